@@ -0,0 +1,95 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Freeing objects that were passed over the FFI as a `Box::into_raw`
+//! pointer, like database connections:
+//!
+//! ```rust,ignore
+//! #[no_mangle]
+//! pub extern "C" fn mylib_connection_new(error: &mut ExternError) -> *mut Connection {
+//!     call_with_result(error, || Ok::<_, MyError>(Box::into_raw(Box::new(Connection::open()?))))
+//! }
+//!
+//! define_box_destructor!(Connection, mylib_connection_destroy);
+//! ```
+//!
+//! Components that can should use a `ConcurrentHandleMap` (and
+//! `define_handle_map_deleter!`) instead, which also catches objects that are
+//! used after they're freed.
+
+use std::panic;
+
+/// Drops a boxed object that was passed over the FFI. This is what the
+/// functions `define_box_destructor!` defines call. Does nothing if `ptr` is
+/// null. If dropping the object panics, the panic is logged and ignored,
+/// since there's no way to report it to the caller.
+///
+/// # Safety
+///
+/// `ptr` must be null, or have come from `Box::into_raw` for a `Box<T>`, and
+/// must not be used after this is called.
+pub unsafe fn destroy_boxed<T>(ptr: *mut T) {
+    if ptr.is_null() {
+        return;
+    }
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(|| drop(Box::from_raw(ptr))));
+    if let Err(e) = res {
+        error!("Caught a panic destroying a boxed object: {:?}", e);
+    }
+}
+
+/// Define an `extern "C"` function that frees a boxed `$T`, for the other
+/// side of the FFI to call when it's done with it. For example,
+/// `define_box_destructor!(PlacesDb, places_connection_destroy);`. It's safe
+/// to call with a null pointer.
+#[macro_export]
+macro_rules! define_box_destructor {
+    ($T:ty, $mylib_destroy_object:ident) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $mylib_destroy_object(obj: *mut $T) {
+            $crate::destroy_boxed(obj)
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    static DROPS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    struct Counted;
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    struct Exploding;
+
+    impl Drop for Exploding {
+        fn drop(&mut self) {
+            panic!("Exploding on drop");
+        }
+    }
+
+    define_box_destructor!(Counted, ffi_support_test_counted_destroy);
+    define_box_destructor!(Exploding, ffi_support_test_exploding_destroy);
+
+    #[test]
+    fn test_box_destructor() {
+        unsafe {
+            ffi_support_test_counted_destroy(Box::into_raw(Box::new(Counted)));
+            assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+            ffi_support_test_counted_destroy(ptr::null_mut());
+            assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+
+            // Shouldn't unwind out of the destructor.
+            ffi_support_test_exploding_destroy(Box::into_raw(Box::new(Exploding)));
+        }
+    }
+}
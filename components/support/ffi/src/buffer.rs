@@ -0,0 +1,219 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Returning arrays of primitives (ids, timestamps, flags, ...) over the FFI
+//! as a pointer and length, rather than serializing them to JSON.
+//!
+//! A callback passed to `call_with_result` can return a `Vec<i32>`,
+//! `Vec<i64>` or `Vec<f64>`, which becomes a `PrimitiveBuffer` of the same
+//! type, or a `Vec<bool>`, which becomes a `PrimitiveBuffer<u8>` with 1 for
+//! true and 0 for false (`bool` isn't guaranteed to be FFI safe). Each
+//! component declares a destructor for the buffers it returns with
+//! `define_primitive_buffer_destructor!`.
+//!
+//! Generic types don't exist in C, so a generated header (see the `build`
+//! module) names each `PrimitiveBuffer` after the alias for it below, like
+//! `PrimitiveBufferI64`. Signatures should use the aliases, since otherwise
+//! cbindgen makes up names like `PrimitiveBuffer_i64`.
+
+use std::{mem, ptr, slice};
+
+use into_ffi::IntoFfi;
+
+/// Types that can be returned in a `PrimitiveBuffer`: they have the same
+/// representation in C, and no invalid bit patterns.
+pub unsafe trait BufferPrimitive: Copy {}
+
+unsafe impl BufferPrimitive for u8 {}
+unsafe impl BufferPrimitive for i32 {}
+unsafe impl BufferPrimitive for i64 {}
+unsafe impl BufferPrimitive for f64 {}
+
+/// An array of primitives allocated by Rust, passed over the FFI by value.
+///
+/// `data` is null when `len` is zero, including when an error occurred, so
+/// callers don't need to special case empty arrays. Non-empty buffers must be
+/// freed with the component's buffer destructor (see
+/// `define_primitive_buffer_destructor!`), which is safe to call with an empty
+/// one too.
+///
+/// In C, this is a struct with an `int64_t len` and a pointer to the element
+/// type named `data`, in that order.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PrimitiveBuffer<T: BufferPrimitive> {
+    len: i64,
+    data: *mut T,
+}
+
+/// A `PrimitiveBuffer` of `u8`s, which is also how `Vec<bool>`s are
+/// returned.
+pub type PrimitiveBufferU8 = PrimitiveBuffer<u8>;
+pub type PrimitiveBufferI32 = PrimitiveBuffer<i32>;
+pub type PrimitiveBufferI64 = PrimitiveBuffer<i64>;
+pub type PrimitiveBufferF64 = PrimitiveBuffer<f64>;
+
+impl<T: BufferPrimitive> PrimitiveBuffer<T> {
+    /// Takes ownership of the contents of `v`.
+    pub fn from_vec(v: Vec<T>) -> Self {
+        if v.is_empty() {
+            return PrimitiveBuffer::empty();
+        }
+        // `into_boxed_slice` drops any excess capacity, so that `destroy` can
+        // rebuild the allocation from the length alone.
+        let mut boxed = v.into_boxed_slice();
+        let len = boxed.len() as i64;
+        let data = boxed.as_mut_ptr();
+        mem::forget(boxed);
+        PrimitiveBuffer { len, data }
+    }
+
+    #[inline]
+    pub fn empty() -> Self {
+        PrimitiveBuffer { len: 0, data: ptr::null_mut() }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The contents of the buffer.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must have been created by `from_vec` or `empty` (and not
+    /// modified by foreign code), and not yet destroyed.
+    pub unsafe fn as_slice(&self) -> &[T] {
+        if self.data.is_null() {
+            &[]
+        } else {
+            slice::from_raw_parts(self.data, self.len())
+        }
+    }
+
+    /// Frees the buffer. This is what the functions defined by
+    /// `define_primitive_buffer_destructor!` call.
+    ///
+    /// # Safety
+    ///
+    /// Same as `as_slice`. The buffer must not be used afterwards.
+    pub unsafe fn destroy(self) {
+        if !self.data.is_null() {
+            let data = slice::from_raw_parts_mut(self.data, self.len()) as *mut [T];
+            drop(Box::from_raw(data));
+        }
+    }
+}
+
+impl<T: BufferPrimitive> Default for PrimitiveBuffer<T> {
+    #[inline]
+    fn default() -> Self {
+        PrimitiveBuffer::empty()
+    }
+}
+
+macro_rules! impl_into_ffi_for_vec {
+    ($($T:ty),+) => {$(
+        unsafe impl IntoFfi for Vec<$T> {
+            type Value = PrimitiveBuffer<$T>;
+            #[inline]
+            fn ffi_default() -> Self::Value {
+                PrimitiveBuffer::empty()
+            }
+            #[inline]
+            fn into_ffi_value(self) -> Self::Value {
+                PrimitiveBuffer::from_vec(self)
+            }
+        }
+    )+}
+}
+
+impl_into_ffi_for_vec![i32, i64, f64];
+
+/// `bool`s are returned as `u8`s, for the same reason as in the `IntoFfi`
+/// impl for `bool`.
+unsafe impl IntoFfi for Vec<bool> {
+    type Value = PrimitiveBuffer<u8>;
+    #[inline]
+    fn ffi_default() -> Self::Value {
+        PrimitiveBuffer::empty()
+    }
+    #[inline]
+    fn into_ffi_value(self) -> Self::Value {
+        PrimitiveBuffer::from_vec(self.into_iter().map(|b| b as u8).collect())
+    }
+}
+
+/// Define a destructor for `PrimitiveBuffer`s of a given type, for the other
+/// side of the FFI to call. For example,
+/// `define_primitive_buffer_destructor!(mylib_destroy_i64_buffer, i64);`.
+///
+/// The destructor takes the buffer by its alias (`PrimitiveBufferI64` in the
+/// example), so that the generated header agrees with the functions that
+/// return it.
+#[macro_export]
+macro_rules! define_primitive_buffer_destructor {
+    ($mylib_destroy_buffer:ident, u8) => {
+        define_primitive_buffer_destructor!(@define $mylib_destroy_buffer, PrimitiveBufferU8);
+    };
+    ($mylib_destroy_buffer:ident, i32) => {
+        define_primitive_buffer_destructor!(@define $mylib_destroy_buffer, PrimitiveBufferI32);
+    };
+    ($mylib_destroy_buffer:ident, i64) => {
+        define_primitive_buffer_destructor!(@define $mylib_destroy_buffer, PrimitiveBufferI64);
+    };
+    ($mylib_destroy_buffer:ident, f64) => {
+        define_primitive_buffer_destructor!(@define $mylib_destroy_buffer, PrimitiveBufferF64);
+    };
+    (@define $mylib_destroy_buffer:ident, $Buffer:ident) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $mylib_destroy_buffer(buffer: $crate::$Buffer) {
+            buffer.destroy()
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffer_roundtrip() {
+        let buffer = PrimitiveBuffer::from_vec(vec![1i64, -2, 1 << 40]);
+        assert_eq!(buffer.len(), 3);
+        unsafe {
+            assert_eq!(buffer.as_slice(), &[1, -2, 1 << 40]);
+            buffer.destroy();
+        }
+    }
+
+    #[test]
+    fn test_empty_buffer() {
+        let buffer = PrimitiveBuffer::<i32>::from_vec(Vec::with_capacity(10));
+        assert!(buffer.is_empty());
+        assert!(buffer.data.is_null());
+        unsafe {
+            assert_eq!(buffer.as_slice(), &[] as &[i32]);
+            buffer.destroy();
+        }
+    }
+
+    #[test]
+    fn test_into_ffi() {
+        let buffer = vec![true, false, true].into_ffi_value();
+        unsafe {
+            assert_eq!(buffer.as_slice(), &[1u8, 0, 1]);
+            buffer.destroy();
+        }
+        let buffer = <Vec<f64> as IntoFfi>::ffi_default();
+        assert!(buffer.data.is_null());
+        assert_eq!(buffer.len(), 0);
+    }
+}
@@ -0,0 +1,109 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Generating a C header for a component's FFI crate as part of its build,
+//! so that the header can't drift from the Rust code it describes.
+//!
+//! Enable the `build_support` feature in the FFI crate's
+//! `[build-dependencies]`, and call `generate_header` from its `build.rs`:
+//!
+//! ```rust,ignore
+//! extern crate ffi_support;
+//!
+//! fn main() {
+//!     let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+//!     ffi_support::build::generate_header(&crate_dir, "places_ffi.h")
+//!         .expect("Failed to generate places_ffi.h");
+//! }
+//! ```
+//!
+//! The types from this crate in the header (`ExternError`, `PooledBuffer`,
+//! `ByteBuffer`, `FfiTagged`, and the `PrimitiveBuffer` aliases) keep the
+//! same names in C, so code on the other side of the FFI can share
+//! declarations between components.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use cbindgen;
+
+/// Generates a C header declaring the `extern "C"` functions (and the types
+/// they use) of the crate in `crate_dir`, and writes it to `header_path`
+/// (relative to `crate_dir`, if it isn't absolute). The file is left alone if
+/// it's already up to date.
+pub fn generate_header<P: AsRef<Path>, Q: AsRef<Path>>(
+    crate_dir: P,
+    header_path: Q,
+) -> Result<(), cbindgen::Error> {
+    let crate_dir = crate_dir.as_ref();
+    let header_path = crate_dir.join(header_path);
+    // Cargo only looks at a directory's own mtime, which doesn't change when
+    // a file in it is edited, so we list every source file instead.
+    // If we can't list them, cbindgen won't be able to read them either, and
+    // will report a better error than we could.
+    if let Ok(paths) = source_files(&crate_dir.join("src")) {
+        for path in paths {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+    let bindings = cbindgen::Builder::new()
+        .with_crate(crate_dir)
+        .with_language(cbindgen::Language::C)
+        .with_include_guard(include_guard(&header_path))
+        .with_sys_include("stdint.h")
+        .with_no_includes()
+        // Our types are defined here, rather than in the FFI crate.
+        .with_parse_deps(true)
+        .with_parse_include(&["ffi-support"])
+        .generate()?;
+    bindings.write_to_file(&header_path);
+    Ok(())
+}
+
+/// Lists the files under `dir`, including those in subdirectories.
+fn source_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(source_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Makes an include guard from the header's file name, like
+/// `PLACES_FFI_H` for `places_ffi.h`.
+fn include_guard(header_path: &Path) -> String {
+    let name = header_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_include_guard() {
+        assert_eq!(include_guard(Path::new("places_ffi.h")), "PLACES_FFI_H");
+        assert_eq!(include_guard(Path::new("/a/b/logins-sql.h")), "LOGINS_SQL_H");
+    }
+
+    #[test]
+    fn test_source_files() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let files = source_files(&src).unwrap();
+        assert!(files.contains(&src.join("lib.rs")));
+        assert!(files.contains(&src.join("build.rs")));
+        assert!(files.iter().all(|path| path.is_file()));
+    }
+}
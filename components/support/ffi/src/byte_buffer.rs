@@ -0,0 +1,309 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Returning arbitrary bytes (encrypted blobs, serialized protobufs, ...)
+//! over the FFI, without encoding them into a string first.
+//!
+//! A callback passed to `call_with_result` can return a `Vec<u8>`, which
+//! becomes a `ByteBuffer`. Each component declares a destructor for them with
+//! `define_bytebuffer_destructor!`:
+//!
+//! ```rust,ignore
+//! #[no_mangle]
+//! pub extern "C" fn mylib_encrypt(
+//!     data: *const u8,
+//!     len: i32,
+//!     error: &mut ExternError,
+//! ) -> ByteBuffer {
+//!     call_with_result(error, || encrypt(unsafe { rust_slice_from_c(data, len) }))
+//! }
+//!
+//! define_bytebuffer_destructor!(mylib_destroy_bytebuffer);
+//! ```
+//!
+//! With the `prost_support` feature, types that implement `prost::Message`
+//! can be returned as `ByteBuffer`s holding the encoded message too, once
+//! they opt in with `implement_into_ffi_by_protobuf!`.
+//!
+//! This is for bytes that are built once and handed over. Responses that are
+//! produced often, and that fit in a reusable buffer, should use a
+//! `BufferPool` instead (see the `pool` module).
+
+use std::{mem, ptr, slice};
+
+use into_ffi::IntoFfi;
+
+/// A byte buffer allocated by Rust, passed over the FFI by value.
+///
+/// `data` is null when `len` is zero, including when an error occurred.
+/// Non-empty buffers must be freed with the component's destructor (see
+/// `define_bytebuffer_destructor!`), which is safe to call with an empty one
+/// too.
+///
+/// In C, this is a struct with an `int64_t len` and a `uint8_t *data`, in
+/// that order.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ByteBuffer {
+    len: i64,
+    data: *mut u8,
+}
+
+impl ByteBuffer {
+    /// Takes ownership of the contents of `v`.
+    pub fn from_vec(v: Vec<u8>) -> Self {
+        if v.is_empty() {
+            return ByteBuffer::empty();
+        }
+        // `into_boxed_slice` drops any excess capacity, so that `destroy` can
+        // rebuild the allocation from the length alone.
+        let mut boxed = v.into_boxed_slice();
+        let len = boxed.len() as i64;
+        let data = boxed.as_mut_ptr();
+        mem::forget(boxed);
+        ByteBuffer { len, data }
+    }
+
+    /// A buffer of `size` zeroed bytes, for code that fills it in place.
+    #[inline]
+    pub fn new_with_size(size: usize) -> Self {
+        ByteBuffer::from_vec(vec![0u8; size])
+    }
+
+    #[inline]
+    pub fn empty() -> Self {
+        ByteBuffer { len: 0, data: ptr::null_mut() }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The contents of the buffer.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must have been created by one of the constructors above
+    /// (and not modified by foreign code), and not yet destroyed.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        if self.data.is_null() {
+            &[]
+        } else {
+            slice::from_raw_parts(self.data, self.len())
+        }
+    }
+
+    /// Like `as_slice`, but mutable, for filling in a buffer from
+    /// `new_with_size`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `as_slice`.
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        if self.data.is_null() {
+            &mut []
+        } else {
+            slice::from_raw_parts_mut(self.data, self.len())
+        }
+    }
+
+    /// Takes back ownership of the bytes, for buffers that were passed back
+    /// to Rust instead of being destroyed.
+    ///
+    /// # Safety
+    ///
+    /// Same as `as_slice`. The buffer must not be used afterwards.
+    pub unsafe fn into_vec(self) -> Vec<u8> {
+        if self.data.is_null() {
+            Vec::new()
+        } else {
+            let data = slice::from_raw_parts_mut(self.data, self.len()) as *mut [u8];
+            Box::from_raw(data).into_vec()
+        }
+    }
+
+    /// Frees the buffer. This is what the functions defined by
+    /// `define_bytebuffer_destructor!` call.
+    ///
+    /// # Safety
+    ///
+    /// Same as `into_vec`.
+    #[inline]
+    pub unsafe fn destroy(self) {
+        drop(self.into_vec())
+    }
+}
+
+impl Default for ByteBuffer {
+    #[inline]
+    fn default() -> Self {
+        ByteBuffer::empty()
+    }
+}
+
+impl From<Vec<u8>> for ByteBuffer {
+    #[inline]
+    fn from(v: Vec<u8>) -> Self {
+        ByteBuffer::from_vec(v)
+    }
+}
+
+unsafe impl IntoFfi for ByteBuffer {
+    type Value = ByteBuffer;
+    #[inline]
+    fn ffi_default() -> Self::Value {
+        ByteBuffer::empty()
+    }
+    #[inline]
+    fn into_ffi_value(self) -> Self::Value {
+        self
+    }
+}
+
+unsafe impl IntoFfi for Vec<u8> {
+    type Value = ByteBuffer;
+    #[inline]
+    fn ffi_default() -> Self::Value {
+        ByteBuffer::empty()
+    }
+    #[inline]
+    fn into_ffi_value(self) -> Self::Value {
+        ByteBuffer::from_vec(self)
+    }
+}
+
+/// Define a destructor for `ByteBuffer`s, for the other side of the FFI to
+/// call. For example, `define_bytebuffer_destructor!(mylib_destroy_bytebuffer);`.
+#[macro_export]
+macro_rules! define_bytebuffer_destructor {
+    ($mylib_destroy_bytebuffer:ident) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $mylib_destroy_bytebuffer(buffer: $crate::ByteBuffer) {
+            buffer.destroy()
+        }
+    };
+}
+
+/// Implements `IntoFfi` for one or more protobuf messages (types that
+/// implement `prost::Message`), so that a callback passed to
+/// `call_with_result` can return them directly. They're encoded into a
+/// `ByteBuffer`, which the other side of the FFI decodes with the same
+/// `.proto` file, and frees with the destructor from
+/// `define_bytebuffer_destructor!`. For example,
+/// `implement_into_ffi_by_protobuf!(msg_types::SearchResultList);`.
+///
+/// Only available with the `prost_support` feature.
+#[cfg(feature = "prost_support")]
+#[macro_export]
+macro_rules! implement_into_ffi_by_protobuf {
+    ($($T:ty),+) => {$(
+        unsafe impl $crate::IntoFfi for $T {
+            type Value = $crate::ByteBuffer;
+            #[inline]
+            fn ffi_default() -> Self::Value {
+                $crate::ByteBuffer::empty()
+            }
+            #[inline]
+            fn into_ffi_value(self) -> Self::Value {
+                use $crate::prost::Message;
+                let mut bytes = Vec::with_capacity(self.encoded_len());
+                // Encoding only fails if the buffer is too small, and a `Vec`
+                // grows as needed.
+                self.encode(&mut bytes)
+                    .expect("Failed to encode protobuf message");
+                $crate::ByteBuffer::from_vec(bytes)
+            }
+        }
+    )+}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use error::{ErrorCode, ExternError};
+    use call_with_result;
+
+    #[test]
+    fn test_bytebuffer_roundtrip() {
+        let buffer = ByteBuffer::from(vec![1u8, 2, 3]);
+        assert_eq!(buffer.len(), 3);
+        unsafe {
+            assert_eq!(buffer.as_slice(), &[1, 2, 3]);
+            assert_eq!(buffer.into_vec(), vec![1, 2, 3]);
+        }
+
+        let mut buffer = ByteBuffer::new_with_size(4);
+        unsafe {
+            buffer.as_mut_slice()[1] = 9;
+            assert_eq!(buffer.as_slice(), &[0, 9, 0, 0]);
+            buffer.destroy();
+        }
+    }
+
+    #[test]
+    fn test_empty_bytebuffer() {
+        let buffer = ByteBuffer::from_vec(Vec::with_capacity(10));
+        assert!(buffer.is_empty());
+        assert!(buffer.data.is_null());
+        unsafe {
+            assert_eq!(buffer.as_slice(), &[] as &[u8]);
+            buffer.destroy();
+        }
+        assert!(ByteBuffer::new_with_size(0).data.is_null());
+    }
+
+    #[test]
+    fn test_bytebuffer_into_ffi() {
+        let mut error = ExternError::success();
+        let buffer = call_with_result(&mut error, || -> Result<Vec<u8>, ExternError> {
+            Ok(b"hello".to_vec())
+        });
+        assert_eq!(error.get_code(), ErrorCode::SUCCESS);
+        unsafe {
+            assert_eq!(buffer.as_slice(), b"hello");
+            buffer.destroy();
+        }
+
+        let buffer = call_with_result(&mut error, || -> Result<Vec<u8>, ExternError> {
+            Err(ExternError::new_error(ErrorCode::new(1), "oops"))
+        });
+        assert!(buffer.data.is_null());
+        assert_eq!(buffer.len(), 0);
+        unsafe { error.manually_release() };
+    }
+
+    #[cfg(feature = "prost_support")]
+    #[derive(Clone, PartialEq, Message)]
+    struct TestMessage {
+        #[prost(string, tag = "1")]
+        url: String,
+        #[prost(int64, repeated, tag = "2")]
+        visits: Vec<i64>,
+    }
+
+    #[cfg(feature = "prost_support")]
+    implement_into_ffi_by_protobuf!(TestMessage);
+
+    #[cfg(feature = "prost_support")]
+    #[test]
+    fn test_protobuf_roundtrip() {
+        use prost::Message;
+        let message = TestMessage {
+            url: "https://www.example.com".into(),
+            visits: vec![1, 2, 3],
+        };
+        let buffer = message.clone().into_ffi_value();
+        assert_eq!(buffer.len(), message.encoded_len());
+        let bytes = unsafe { buffer.into_vec() };
+        assert_eq!(TestMessage::decode(&bytes[..]).unwrap(), message);
+
+        assert!(<TestMessage as IntoFfi>::ffi_default().is_empty());
+    }
+}
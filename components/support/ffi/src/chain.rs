@@ -0,0 +1,134 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Errors often cross several components before reaching the FFI. For
+//! example, a logins sync can fail because sync15 got a 401 from the token
+//! server. Each component only knows its own error codes, so without some
+//! help, the outer component reports whatever it can't classify as
+//! "unexpected", and the useful part is lost.
+//!
+//! An `ErrorChain` keeps a `(domain, code, message)` triple for each layer,
+//! outermost first. Components describe their own errors (and, where they
+//! wrap another component's error, append that component's chain), and the
+//! component that owns the FFI translates the inner codes it knows about into
+//! its own code space with `find`.
+//!
+//! Only the outermost code is reported in `ExternError::code`, since codes
+//! from different domains can't be told apart on the other side of the FFI.
+//! The rest of the chain is included in the message.
+
+use std::fmt;
+use error::{ErrorCode, ExternError};
+
+/// A single layer of an `ErrorChain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorLink {
+    /// The component that reported this error, e.g. "logins" or "sync15".
+    pub domain: &'static str,
+    /// The error code, in `domain`'s code space.
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl fmt::Display for ErrorLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{} {}] {}", self.domain, self.code.code(), self.message)
+    }
+}
+
+/// A list of `ErrorLink`s, from the outermost error to its innermost cause.
+/// There's always at least one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorChain {
+    links: Vec<ErrorLink>,
+}
+
+impl ErrorChain {
+    pub fn new<S: Into<String>>(domain: &'static str, code: ErrorCode, message: S) -> Self {
+        ErrorChain {
+            links: vec![ErrorLink {
+                domain,
+                code,
+                message: message.into(),
+            }],
+        }
+    }
+
+    /// Append the links of `cause` (usually the chain of an error from
+    /// another component that caused this one).
+    pub fn with_cause(mut self, cause: ErrorChain) -> Self {
+        self.links.extend(cause.links);
+        self
+    }
+
+    #[inline]
+    pub fn links(&self) -> &[ErrorLink] {
+        &self.links
+    }
+
+    #[inline]
+    pub fn outermost(&self) -> &ErrorLink {
+        &self.links[0]
+    }
+
+    /// The outermost link from `domain`, if any.
+    pub fn find(&self, domain: &str) -> Option<&ErrorLink> {
+        self.links.iter().find(|link| link.domain == domain)
+    }
+
+    /// Replace the outermost code. This is how a component reports a
+    /// translated inner code, for example:
+    ///
+    /// ```rust,ignore
+    /// let chain = err.error_chain();
+    /// let code = match chain.find("sync15").map(|l| l.code.code()) {
+    ///     Some(sync15::error_codes::AUTH_INVALID) => ErrorCode::new(AUTH_INVALID),
+    ///     _ => chain.outermost().code,
+    /// };
+    /// chain.with_code(code).into()
+    /// ```
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.links[0].code = code;
+        self
+    }
+}
+
+impl fmt::Display for ErrorChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.links[0])?;
+        for link in &self.links[1..] {
+            write!(f, "; caused by {}", link)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ErrorChain> for ExternError {
+    fn from(chain: ErrorChain) -> ExternError {
+        ExternError::new_error(chain.outermost().code, chain.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chain() {
+        let inner = ErrorChain::new("sync15", ErrorCode::new(1), "token server returned 401");
+        let chain = ErrorChain::new("logins", ErrorCode::new(7), "sync failed").with_cause(inner);
+        assert_eq!(chain.links().len(), 2);
+        assert_eq!(chain.outermost().domain, "logins");
+        assert_eq!(chain.find("sync15").unwrap().code, ErrorCode::new(1));
+        assert!(chain.find("places").is_none());
+
+        let mut error: ExternError = chain.with_code(ErrorCode::new(2)).into();
+        assert_eq!(error.get_code(), ErrorCode::new(2));
+        assert_eq!(
+            error.get_message(),
+            Some("[logins 2] sync failed; caused by [sync15 1] token server returned 401")
+        );
+        unsafe { error.manually_release() };
+    }
+}
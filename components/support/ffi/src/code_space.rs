@@ -0,0 +1,148 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Which component an `ErrorCode` belongs to.
+//!
+//! Negative codes are reserved for this crate (see `ErrorCode::PANIC`,
+//! `ErrorCode::UNEXPECTED` and `ErrorCode::INVALID_HANDLE`), and mean the
+//! same thing for every component. Each component declares the positive codes
+//! it uses as an `ErrorCodeSpace`, and registers it when it's initialized:
+//!
+//! ```rust,ignore
+//! pub const ERROR_CODES: ErrorCodeSpace = ErrorCodeSpace {
+//!     component: "mylib",
+//!     start: 1,
+//!     end: 100,
+//! };
+//!
+//! let registered = register_error_code_space(ERROR_CODES);
+//! debug_assert!(registered, "Overlapping error codes");
+//! ```
+//!
+//! Registering fails if the space overlaps another component's, so spaces
+//! are assigned here:
+//!
+//! - logins: 1 to 99
+//! - places: 100 to 199
+//!
+//! The Kotlin and Swift wrappers can then map codes to typed exceptions,
+//! without parsing messages: reserved codes are handled the same way for
+//! every component, and the function defined with
+//! `define_error_code_spaces_query!` lists the others.
+
+use std::sync::Mutex;
+
+use error::ErrorCode;
+use serde_json;
+
+use lock_ignoring_poison;
+
+/// The codes from `start` (inclusive) to `end` (exclusive) that `component`
+/// reports in `ExternError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct ErrorCodeSpace {
+    pub component: &'static str,
+    pub start: i32,
+    pub end: i32,
+}
+
+impl ErrorCodeSpace {
+    #[inline]
+    pub fn contains(&self, code: ErrorCode) -> bool {
+        self.start <= code.code() && code.code() < self.end
+    }
+
+    fn overlaps(&self, other: &ErrorCodeSpace) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+lazy_static! {
+    static ref CODE_SPACES: Mutex<Vec<ErrorCodeSpace>> = Mutex::new(Vec::new());
+}
+
+/// Records that `space.component` reports the codes in `space`. Registering
+/// the same component again replaces its previous space.
+///
+/// Returns false (and logs an error) without registering anything if the
+/// space is empty, includes reserved (zero or negative) codes, or overlaps the
+/// space of another component, since then there'd be no way to tell which
+/// component a code came from.
+pub fn register_error_code_space(space: ErrorCodeSpace) -> bool {
+    if space.start <= 0 || space.end <= space.start {
+        error!("Invalid error code space for {}: {}..{}", space.component, space.start, space.end);
+        return false;
+    }
+    let mut spaces = lock_ignoring_poison(&CODE_SPACES);
+    if let Some(other) = spaces.iter().find(|s| s.component != space.component && s.overlaps(&space)) {
+        error!("Error codes {}..{} for {} overlap those of {} ({}..{})",
+               space.start, space.end, space.component,
+               other.component, other.start, other.end);
+        return false;
+    }
+    spaces.retain(|s| s.component != space.component);
+    spaces.push(space);
+    true
+}
+
+/// Returns the registered space that `code` belongs to, if any. Reserved
+/// codes don't belong to any.
+pub fn error_code_space(code: ErrorCode) -> Option<ErrorCodeSpace> {
+    lock_ignoring_poison(&CODE_SPACES).iter().find(|s| s.contains(code)).cloned()
+}
+
+/// Returns the registered spaces as a JSON array of
+/// `{"component": ..., "start": ..., "end": ...}` objects, where `end` is
+/// exclusive.
+pub fn error_code_spaces_json() -> String {
+    serde_json::to_string(&*lock_ignoring_poison(&CODE_SPACES))
+        .expect("Serializing the error code spaces can't fail")
+}
+
+/// Define an `extern "C"` function that returns `error_code_spaces_json()`,
+/// which must be freed with the library's string destructor (see
+/// `define_string_destructor!`). For example,
+/// `define_error_code_spaces_query!(mylib_error_code_spaces);`.
+#[macro_export]
+macro_rules! define_error_code_spaces_query {
+    ($mylib_error_code_spaces:ident) => {
+        #[no_mangle]
+        pub extern "C" fn $mylib_error_code_spaces() -> *mut ::std::os::raw::c_char {
+            $crate::rust_string_to_c($crate::error_code_spaces_json())
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use string::{destroy_c_string, rust_str_from_c};
+
+    define_error_code_spaces_query!(ffi_support_test_error_code_spaces);
+
+    #[test]
+    fn test_error_code_spaces() {
+        let first = ErrorCodeSpace { component: "code-space-test-1", start: 10000, end: 10100 };
+        let second = ErrorCodeSpace { component: "code-space-test-2", start: 10100, end: 10200 };
+        assert!(register_error_code_space(first));
+        assert!(register_error_code_space(second));
+        // Re-registering is fine, but overlapping another component isn't.
+        assert!(register_error_code_space(first));
+        assert!(!register_error_code_space(ErrorCodeSpace { start: 10050, ..second }));
+        assert!(!register_error_code_space(ErrorCodeSpace { start: -5, ..second }));
+        assert!(!register_error_code_space(ErrorCodeSpace { end: 10100, ..second }));
+
+        assert_eq!(error_code_space(ErrorCode::new(10000)), Some(first));
+        assert_eq!(error_code_space(ErrorCode::new(10100)), Some(second));
+        assert_eq!(error_code_space(ErrorCode::new(10200)), None);
+        assert_eq!(error_code_space(ErrorCode::PANIC), None);
+
+        let json = ffi_support_test_error_code_spaces();
+        unsafe {
+            assert!(rust_str_from_c(json).contains(
+                "{\"component\":\"code-space-test-2\",\"start\":10100,\"end\":10200}"));
+            destroy_c_string(json);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Keeping old FFI functions working after they're replaced, while we wait
+//! for the apps using them to update.
+//!
+//! Declare the old function with `define_deprecated_ffi_fn!`, which forwards
+//! to the new one:
+//!
+//! ```rust,ignore
+//! define_deprecated_ffi_fn! {
+//!     /// Deprecated: use `mylib_frobnicate_v2`.
+//!     fn mylib_frobnicate(thing: *const c_char, error: &mut ExternError) -> *mut c_char
+//!         => mylib_frobnicate_v2;
+//! }
+//! ```
+//!
+//! The first call logs a warning, and records the call, so that the app can
+//! find out which deprecated functions it still uses with `deprecated_calls`
+//! (or a function defined with `define_deprecated_calls_query!`), for example
+//! in its tests or telemetry.
+
+use std::sync::Mutex;
+
+use serde_json;
+
+use lock_ignoring_poison;
+
+/// A deprecated FFI function that was called, and the function that
+/// replaces it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct DeprecatedCall {
+    pub name: &'static str,
+    pub replacement: &'static str,
+}
+
+lazy_static! {
+    static ref DEPRECATED_CALLS: Mutex<Vec<DeprecatedCall>> = Mutex::new(Vec::new());
+}
+
+/// Called by the functions `define_deprecated_ffi_fn!` defines, the first
+/// time they're called.
+#[doc(hidden)]
+pub fn note_deprecated_call(name: &'static str, replacement: &'static str) {
+    warn!("{} is deprecated, and will be removed. Use {} instead.", name, replacement);
+    let mut calls = lock_ignoring_poison(&DEPRECATED_CALLS);
+    if !calls.iter().any(|call| call.name == name) {
+        calls.push(DeprecatedCall { name, replacement });
+    }
+}
+
+/// Returns the deprecated FFI functions that have been called since the
+/// library was loaded, in the order they were first called.
+pub fn deprecated_calls() -> Vec<DeprecatedCall> {
+    lock_ignoring_poison(&DEPRECATED_CALLS).clone()
+}
+
+/// Like `deprecated_calls`, but as a JSON array of
+/// `{"name": ..., "replacement": ...}` objects, to return over the FFI.
+pub fn deprecated_calls_json() -> String {
+    serde_json::to_string(&deprecated_calls())
+        .expect("Serializing the deprecated calls can't fail")
+}
+
+/// Define a deprecated `extern "C"` function, which forwards its arguments to
+/// `$replacement` (which must take the same arguments), and logs a warning
+/// the first time it's called. See the module docs for an example.
+#[macro_export]
+macro_rules! define_deprecated_ffi_fn {
+    ($(#[$attr:meta])* fn $name:ident($($arg:ident: $T:ty),*) -> $R:ty => $replacement:path;) => {
+        $(#[$attr])*
+        #[no_mangle]
+        pub unsafe extern "C" fn $name($($arg: $T),*) -> $R {
+            static WARNED: ::std::sync::atomic::AtomicBool = ::std::sync::atomic::AtomicBool::new(false);
+            if !WARNED.swap(true, ::std::sync::atomic::Ordering::Relaxed) {
+                $crate::note_deprecated_call(stringify!($name), stringify!($replacement));
+            }
+            $replacement($($arg),*)
+        }
+    };
+    ($(#[$attr:meta])* fn $name:ident($($arg:ident: $T:ty),*) => $replacement:path;) => {
+        $(#[$attr])*
+        #[no_mangle]
+        pub unsafe extern "C" fn $name($($arg: $T),*) {
+            static WARNED: ::std::sync::atomic::AtomicBool = ::std::sync::atomic::AtomicBool::new(false);
+            if !WARNED.swap(true, ::std::sync::atomic::Ordering::Relaxed) {
+                $crate::note_deprecated_call(stringify!($name), stringify!($replacement));
+            }
+            $replacement($($arg),*)
+        }
+    };
+}
+
+/// Define an `extern "C"` function that returns `deprecated_calls_json()`,
+/// which must be freed with the string destructor. For example,
+/// `define_deprecated_calls_query!(mylib_deprecated_calls);`.
+#[macro_export]
+macro_rules! define_deprecated_calls_query {
+    ($mylib_deprecated_calls:ident) => {
+        #[no_mangle]
+        pub extern "C" fn $mylib_deprecated_calls() -> *mut ::std::os::raw::c_char {
+            $crate::rust_string_to_c($crate::deprecated_calls_json())
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use string::{destroy_c_string, rust_str_from_c};
+
+    extern "C" fn ffi_support_test_add_v2(a: i32, b: i32) -> i32 {
+        a + b
+    }
+
+    extern "C" fn ffi_support_test_reset_v2() {}
+
+    define_deprecated_ffi_fn! {
+        /// Deprecated: use `ffi_support_test_add_v2`.
+        fn ffi_support_test_add(a: i32, b: i32) -> i32 => ffi_support_test_add_v2;
+    }
+
+    define_deprecated_ffi_fn! {
+        fn ffi_support_test_reset() => ffi_support_test_reset_v2;
+    }
+
+    define_deprecated_calls_query!(ffi_support_test_deprecated_calls);
+
+    #[test]
+    fn test_deprecated_calls() {
+        // Other tests may run first, but none of them call these.
+        let add = DeprecatedCall {
+            name: "ffi_support_test_add",
+            replacement: "ffi_support_test_add_v2",
+        };
+        assert!(!deprecated_calls().contains(&add));
+        unsafe {
+            assert_eq!(ffi_support_test_add(1, 2), 3);
+            assert_eq!(ffi_support_test_add(3, 4), 7);
+            ffi_support_test_reset();
+        }
+        let calls = deprecated_calls();
+        assert_eq!(calls.iter().filter(|&&call| call == add).count(), 1);
+        assert!(calls.iter().any(|call| call.name == "ffi_support_test_reset"));
+
+        let json = ffi_support_test_deprecated_calls();
+        unsafe {
+            assert!(rust_str_from_c(json).contains(
+                "{\"name\":\"ffi_support_test_add\",\"replacement\":\"ffi_support_test_add_v2\"}"));
+            destroy_c_string(json);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::any::Any;
+use std::os::raw::c_char;
+use std::ptr;
+use string::{destroy_c_string, rust_string_to_c};
+
+/// Represents an error that occurred on the rust side. FFI functions that can
+/// fail take a `&mut ExternError` (a `*mut ExternError` on the C side) as their
+/// last argument, which is an out parameter that describes the error (if any).
+///
+/// If `code` is `ErrorCode::SUCCESS` (zero), no error occurred and `message`
+/// is null. Otherwise `message` is a heap allocated string describing the
+/// error, which the consumer must free with the component's string destructor.
+///
+/// While this isn't very ergonomic in Rust, it avoids needing a separate
+/// `Result`-shaped type on the other side of the FFI for every return type.
+///
+/// In C, this is a struct with an `int32_t code` and a `char *message`, in
+/// that order. The fields are private so that Rust code can't break the
+/// invariants above, but the layout won't change.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExternError {
+    code: ErrorCode,
+    message: *mut c_char,
+}
+
+impl ExternError {
+    /// Construct an error with a code and message.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `code` is `ErrorCode::SUCCESS`, or if the message contains a
+    /// nul byte.
+    pub fn new_error<S: Into<String>>(code: ErrorCode, message: S) -> Self {
+        assert!(!code.is_success(), "Attempted to construct an error with ErrorCode::SUCCESS");
+        ExternError {
+            code,
+            message: rust_string_to_c(message),
+        }
+    }
+
+    /// An `ExternError` representing that no error occurred.
+    pub fn success() -> Self {
+        ExternError {
+            code: ErrorCode::SUCCESS,
+            message: ptr::null_mut(),
+        }
+    }
+
+    #[inline]
+    pub fn get_code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// The error message, if any. This is mostly useful for tests, since in
+    /// practice the message is read on the other side of the FFI.
+    pub fn get_message(&self) -> Option<&str> {
+        if self.message.is_null() {
+            None
+        } else {
+            unsafe { ::std::ffi::CStr::from_ptr(self.message) }.to_str().ok()
+        }
+    }
+
+    /// Free the message and reset this to `ExternError::success()`.
+    ///
+    /// # Safety
+    ///
+    /// The message must not have been freed by anybody else (for example, by
+    /// passing it back over the FFI to the string destructor).
+    pub unsafe fn manually_release(&mut self) {
+        let message = ::std::mem::replace(&mut self.message, ptr::null_mut());
+        destroy_c_string(message);
+        self.code = ErrorCode::SUCCESS;
+    }
+}
+
+impl Default for ExternError {
+    #[inline]
+    fn default() -> Self {
+        ExternError::success()
+    }
+}
+
+// This is the `Err` of std::thread::Result, which is what
+// `panic::catch_unwind` returns.
+impl From<Box<Any + Send + 'static>> for ExternError {
+    fn from(e: Box<Any + Send + 'static>) -> Self {
+        // The documentation suggests that it will usually be a str or String.
+        let message = if let Some(s) = e.downcast_ref::<&'static str>() {
+            s.to_string()
+        } else if let Some(s) = e.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "Unknown panic!".to_string()
+        };
+        ExternError::new_error(ErrorCode::PANIC, message)
+    }
+}
+
+/// A wrapper around an `i32` error code, which is what `ExternError::code`
+/// holds on the other side of the FFI.
+///
+/// Zero means success, and negative codes are reserved by this crate for
+/// errors that the application isn't expected to handle (like panics), which
+/// mean the same thing for every component. Each component assigns meanings
+/// to the positive codes in its `ErrorCodeSpace`.
+///
+/// Since it's `#[repr(transparent)]`, it's passed exactly like an `i32`, and
+/// generated headers declare it as an `int32_t`.
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ErrorCode(i32);
+
+impl ErrorCode {
+    /// No error occurred.
+    pub const SUCCESS: ErrorCode = ErrorCode(0);
+
+    /// The rust code hit a `panic!` (or something equivalent, like `assert!`).
+    pub const PANIC: ErrorCode = ErrorCode(-1);
+
+    /// An error that the component doesn't expect the application to handle,
+    /// like a database error. Components may use this instead of assigning
+    /// one of their own codes.
+    pub const UNEXPECTED: ErrorCode = ErrorCode(-2);
+
+    /// A handle passed to a function that uses a `ConcurrentHandleMap` was
+    /// null, already freed, or from a different map.
+    pub const INVALID_HANDLE: ErrorCode = ErrorCode(-1000);
+
+    #[inline]
+    pub fn new(code: i32) -> Self {
+        ErrorCode(code)
+    }
+
+    #[inline]
+    pub fn code(self) -> i32 {
+        self.0
+    }
+
+    #[inline]
+    pub fn is_success(self) -> bool {
+        self.0 == 0
+    }
+
+    /// True for the codes reserved by this crate, like `PANIC`, which don't
+    /// belong to any component's `ErrorCodeSpace`.
+    #[inline]
+    pub fn is_reserved(self) -> bool {
+        self.0 < 0
+    }
+}
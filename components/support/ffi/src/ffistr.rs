@@ -0,0 +1,132 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::os::raw::c_char;
+
+/// A nul-terminated string passed in from C, for use as an argument to FFI
+/// functions in place of a `*const c_char`. It has the same representation,
+/// so the other side of the FFI (and the generated header) still sees a
+/// `const char *`:
+///
+/// ```rust,ignore
+/// #[no_mangle]
+/// pub extern "C" fn mylib_frobnicate(thing: FfiStr, error: &mut ExternError) -> *mut c_char {
+///     call_with_result(error, || frobnicate(thing.as_str()))
+/// }
+/// ```
+///
+/// Unlike converting the pointer by hand, the checks can't be forgotten: a
+/// null pointer or invalid UTF-8 is a panic (and so an `ErrorCode::PANIC`
+/// for the caller, inside `call_with_result`), rather than undefined
+/// behavior, or an empty string. Use `as_opt_str` for arguments that may be
+/// null.
+///
+/// Declaring a function with an `FfiStr` argument is a promise that the
+/// other side of the FFI passes either null, or a pointer to a nul-terminated
+/// string that isn't modified or freed until the function returns. That's
+/// what makes borrowing it safe. Don't keep the `&str` (or the `FfiStr`)
+/// around after the call.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy)]
+pub struct FfiStr<'a> {
+    cstr: *const c_char,
+    _boo: PhantomData<&'a ()>,
+}
+
+impl<'a> FfiStr<'a> {
+    /// Wraps a pointer from C, for code that has one already.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be null, or point to a nul-terminated string that lives for
+    /// `'a`, and isn't modified in that time.
+    #[inline]
+    pub unsafe fn from_raw(ptr: *const c_char) -> Self {
+        FfiStr { cstr: ptr, _boo: PhantomData }
+    }
+
+    /// Wraps a `CStr`, mostly for tests.
+    #[inline]
+    pub fn from_cstr(cstr: &'a CStr) -> Self {
+        FfiStr { cstr: cstr.as_ptr(), _boo: PhantomData }
+    }
+
+    #[inline]
+    pub fn is_null(&self) -> bool {
+        self.cstr.is_null()
+    }
+
+    /// Borrows the string.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pointer is null, or the string isn't valid UTF-8.
+    #[inline]
+    pub fn as_str(&self) -> &'a str {
+        self.as_opt_str().expect("Null pointer passed to rust!")
+    }
+
+    /// Like `as_str`, but returns `None` if the pointer is null.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string isn't valid UTF-8.
+    pub fn as_opt_str(&self) -> Option<&'a str> {
+        if self.cstr.is_null() {
+            return None;
+        }
+        let cstr = unsafe { CStr::from_ptr(self.cstr) };
+        Some(cstr.to_str().expect("Invalid UTF-8 was passed to rust!"))
+    }
+
+    /// Like `as_str`, but copies the string, so that it can outlive the
+    /// call.
+    #[inline]
+    pub fn into_string(self) -> String {
+        self.as_str().to_owned()
+    }
+
+    /// Like `as_opt_str`, but copies the string.
+    #[inline]
+    pub fn into_opt_string(self) -> Option<String> {
+        self.as_opt_str().map(str::to_owned)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr;
+
+    #[test]
+    fn test_ffistr() {
+        let s = CString::new("héllo").unwrap();
+        let ffi_str = FfiStr::from_cstr(&s);
+        assert!(!ffi_str.is_null());
+        assert_eq!(ffi_str.as_str(), "héllo");
+        assert_eq!(ffi_str.as_opt_str(), Some("héllo"));
+        assert_eq!(ffi_str.into_string(), "héllo".to_string());
+
+        let null = unsafe { FfiStr::from_raw(ptr::null()) };
+        assert!(null.is_null());
+        assert_eq!(null.as_opt_str(), None);
+        assert_eq!(null.into_opt_string(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_ffistr_null() {
+        unsafe { FfiStr::from_raw(ptr::null()) }.as_str();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_ffistr_invalid_utf8() {
+        let s = CString::new(vec![0xffu8, 0xfe]).unwrap();
+        FfiStr::from_cstr(&s).as_opt_str();
+    }
+}
@@ -0,0 +1,111 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for fuzzing FFI functions with `cargo fuzz`, available with the
+//! `fuzzing` feature. This is only meant for the fuzz targets in `/fuzz`;
+//! nothing that ships should enable it.
+//!
+//! A fuzz target turns the fuzzer's bytes into the arguments the other side
+//! of the FFI would pass with `FuzzArgs`, and makes the call through
+//! `check_ffi_call`, which fails the run if the function panicked. (A panic
+//! is caught by `call_with_result` and reported as `ErrorCode::PANIC`, so it
+//! wouldn't otherwise crash the fuzzer.) For example:
+//!
+//! ```rust,ignore
+//! fuzz_target!(|data: &[u8]| {
+//!     let mut args = FuzzArgs::new(data);
+//!     let json = args.next_c_string();
+//!     check_ffi_call(|error| unsafe {
+//!         mylib_frobnicate(json.as_ptr(), error)
+//!     });
+//! });
+//! ```
+//!
+//! See `/fuzz/README.md` for adding a target.
+
+use std::ffi::CString;
+
+use error::{ErrorCode, ExternError};
+
+/// Splits the fuzzer's input into arguments. Once the input runs out, every
+/// argument is empty (or zero).
+pub struct FuzzArgs<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> FuzzArgs<'a> {
+    #[inline]
+    pub fn new(data: &'a [u8]) -> Self {
+        FuzzArgs { data }
+    }
+
+    /// Takes the bytes up to the next nul (or the end of the input) as a
+    /// string argument. Strings from Kotlin and Swift are always valid UTF-8,
+    /// and `rust_str_from_c` relies on that, so invalid sequences are
+    /// replaced.
+    pub fn next_c_string(&mut self) -> CString {
+        let len = self.data.iter().position(|&b| b == 0).unwrap_or(self.data.len());
+        let (arg, rest) = self.data.split_at(len);
+        // Skip the nul, if there was one.
+        self.data = if rest.is_empty() { rest } else { &rest[1..] };
+        let s = String::from_utf8_lossy(arg).into_owned();
+        CString::new(s).expect("Arguments never contain a nul")
+    }
+
+    /// Takes the next 4 bytes as a little-endian `u32`.
+    pub fn next_u32(&mut self) -> u32 {
+        let len = self.data.len().min(4);
+        let (arg, rest) = self.data.split_at(len);
+        self.data = rest;
+        arg.iter().rev().fold(0, |n, &b| (n << 8) | u32::from(b))
+    }
+
+    /// Returns whatever input is left, for the last argument.
+    #[inline]
+    pub fn rest(self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// Calls an FFI function, passing it an `ExternError`, and panics (which the
+/// fuzzer reports as a crash) if the function panicked. Returns the error
+/// code, having freed the message.
+pub fn check_ffi_call<F>(call: F) -> ErrorCode
+where
+    F: FnOnce(&mut ExternError),
+{
+    let mut error = ExternError::default();
+    call(&mut error);
+    let code = error.get_code();
+    if code == ErrorCode::PANIC {
+        panic!("FFI call panicked: {}", error.get_message().unwrap_or("(no message)"));
+    }
+    unsafe { error.manually_release() };
+    code
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fuzz_args() {
+        let mut args = FuzzArgs::new(b"abc\0\xff\0\x01\x02");
+        assert_eq!(args.next_c_string().to_str().unwrap(), "abc");
+        assert_eq!(args.next_c_string().to_str().unwrap(), "\u{fffd}");
+        assert_eq!(args.next_u32(), 0x0201);
+        assert_eq!(args.next_c_string().to_str().unwrap(), "");
+        assert!(args.rest().is_empty());
+
+        assert_eq!(check_ffi_call(|_| ()), ErrorCode::SUCCESS);
+        assert_eq!(check_ffi_call(|error| *error = ExternError::new_error(ErrorCode::new(1), "nope")),
+                   ErrorCode::new(1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_check_ffi_call_panics() {
+        check_ffi_call(|error| *error = ExternError::new_error(ErrorCode::PANIC, "boom"));
+    }
+}
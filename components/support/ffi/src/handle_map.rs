@@ -0,0 +1,670 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Passing Rust objects (like database connections) over the FFI as opaque
+//! `u64` handles, instead of raw pointers.
+//!
+//! A pointer from `Box::into_raw` is only safe as long as the other side of
+//! the FFI uses it correctly: passing it to the destructor twice, or using it
+//! after (or while!) it's freed on another thread, is undefined behavior, and
+//! usually a crash somewhere unrelated. A handle is checked on every use
+//! instead, so those mistakes become errors the other side can see.
+//!
+//! Each component keeps its objects in a `ConcurrentHandleMap` in a `static`
+//! (usually with `lazy_static!`), and defines a destructor for them with
+//! `define_handle_map_deleter!`:
+//!
+//! ```rust,ignore
+//! lazy_static! {
+//!     static ref CONNECTIONS: ConcurrentHandleMap<Connection> = ConcurrentHandleMap::new();
+//! }
+//!
+//! #[no_mangle]
+//! pub extern "C" fn mylib_open(path: *const c_char, error: &mut ExternError) -> u64 {
+//!     CONNECTIONS.insert_with_result(error, || Connection::open(rust_str_from_c(path)))
+//! }
+//!
+//! #[no_mangle]
+//! pub extern "C" fn mylib_count(handle: u64, error: &mut ExternError) -> i64 {
+//!     CONNECTIONS.call_with_result(error, handle, |conn| conn.count())
+//! }
+//!
+//! define_handle_map_deleter!(mylib_close, CONNECTIONS);
+//! ```
+//!
+//! Functions like these, which only unpack their arguments and call one
+//! method, can be generated with `define_handle_map_constructor!` and
+//! `define_handle_map_accessor!` instead:
+//!
+//! ```rust,ignore
+//! define_handle_map_constructor! {
+//!     fn mylib_open(CONNECTIONS, path: FfiStr) -> Result<Connection, Error> {
+//!         Connection::open(path.as_str())
+//!     }
+//! }
+//!
+//! define_handle_map_accessor! {
+//!     fn mylib_count(CONNECTIONS, conn) -> Result<i64, Error> {
+//!         conn.count()
+//!     }
+//! }
+//! ```
+//!
+//! Calls for different objects run in parallel, but calls for the same object
+//! are serialized, and closing an object waits for any calls that are using
+//! it to finish.
+
+use std::error;
+use std::fmt;
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use error::{ErrorCode, ExternError};
+use into_ffi::IntoFfi;
+
+/// Why a handle was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleError {
+    /// The handle is zero, which is never valid, and is what we return when
+    /// creating an object fails.
+    NullHandle,
+    /// The handle is from a different map.
+    WrongMap,
+    /// The handle's object was already removed (and maybe replaced by a new
+    /// object in the same slot).
+    StaleHandle,
+    /// The handle was never handed out by this map, and was probably
+    /// corrupted.
+    InvalidHandle,
+}
+
+impl fmt::Display for HandleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandleError::NullHandle => write!(f, "Tried to use a null handle"),
+            HandleError::WrongMap => write!(f, "Tried to use a handle from a different map"),
+            HandleError::StaleHandle => write!(f, "Tried to use a handle after it was freed"),
+            HandleError::InvalidHandle => write!(f, "Tried to use an invalid handle"),
+        }
+    }
+}
+
+impl error::Error for HandleError {}
+
+impl From<HandleError> for ExternError {
+    fn from(e: HandleError) -> ExternError {
+        ExternError::new_error(ErrorCode::INVALID_HANDLE, e.to_string())
+    }
+}
+
+/// A handle to an object in a `HandleMap`. Over the FFI, this is a `u64`:
+/// the index of the object's slot in the low 32 bits, then a version that
+/// changes each time the slot is reused, and an id for the map. Zero is
+/// never a valid handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Handle {
+    map_id: u16,
+    version: u16,
+    index: u32,
+}
+
+impl Handle {
+    #[inline]
+    pub fn into_u64(self) -> u64 {
+        (u64::from(self.map_id) << 48) | (u64::from(self.version) << 32) | u64::from(self.index)
+    }
+
+    /// Splits a `u64` from the other side of the FFI into a handle. This
+    /// only checks for zero; the map checks the rest when it's used.
+    #[inline]
+    pub fn from_u64(v: u64) -> Result<Handle, HandleError> {
+        if v == 0 {
+            return Err(HandleError::NullHandle);
+        }
+        Ok(Handle {
+            map_id: (v >> 48) as u16,
+            version: (v >> 32) as u16,
+            index: v as u32,
+        })
+    }
+}
+
+// Map ids start at 1, so that handles are never zero. They wrap after 65535
+// maps, which is fine, since they're only there to catch mix-ups.
+static NEXT_MAP_ID: AtomicUsize = AtomicUsize::new(1);
+
+fn next_map_id() -> u16 {
+    loop {
+        let id = NEXT_MAP_ID.fetch_add(1, Ordering::Relaxed) as u16;
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+struct Entry<T> {
+    // Zero for slots that have never been used. Bumped each time the slot is
+    // filled, so that handles to the previous object in the slot are stale.
+    // Once it reaches `u16::MAX`, the slot is retired instead of being reused,
+    // so versions never wrap around to one that an old handle still has.
+    version: u16,
+    value: Option<T>,
+}
+
+/// A map from `Handle`s to objects. This is the single-threaded part of
+/// `ConcurrentHandleMap`, which is what FFI code should use.
+pub struct HandleMap<T> {
+    id: u16,
+    entries: Vec<Entry<T>>,
+    // Indices of the empty slots, to reuse before growing `entries`.
+    free: Vec<u32>,
+    // The number of empty slots that are never reused.
+    retired: usize,
+}
+
+impl<T> HandleMap<T> {
+    pub fn new() -> Self {
+        HandleMap {
+            id: next_map_id(),
+            entries: Vec::new(),
+            free: Vec::new(),
+            retired: 0,
+        }
+    }
+
+    /// The number of objects in the map.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len() - self.free.len() - self.retired
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds `value` to the map, and returns a new handle for it.
+    pub fn insert(&mut self, value: T) -> Handle {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                assert!(self.entries.len() < u32::max_value() as usize, "HandleMap is full");
+                self.entries.push(Entry { version: 0, value: None });
+                (self.entries.len() - 1) as u32
+            }
+        };
+        let entry = &mut self.entries[index as usize];
+        // Can't overflow, since we don't reuse slots with the last version.
+        entry.version += 1;
+        entry.value = Some(value);
+        Handle {
+            map_id: self.id,
+            version: entry.version,
+            index,
+        }
+    }
+
+    /// Removes the object for `handle` from the map, and returns it.
+    pub fn remove(&mut self, handle: Handle) -> Result<T, HandleError> {
+        let index = self.check(handle)?;
+        let entry = &mut self.entries[index];
+        let value = entry.value.take().expect("Checked handles have values");
+        if entry.version == u16::max_value() {
+            self.retired += 1;
+        } else {
+            self.free.push(index as u32);
+        }
+        Ok(value)
+    }
+
+    /// Like `remove`, but drops the object instead of returning it.
+    #[inline]
+    pub fn delete(&mut self, handle: Handle) -> Result<(), HandleError> {
+        self.remove(handle).map(drop)
+    }
+
+    pub fn get(&self, handle: Handle) -> Result<&T, HandleError> {
+        let index = self.check(handle)?;
+        Ok(self.entries[index].value.as_ref().expect("Checked handles have values"))
+    }
+
+    pub fn get_mut(&mut self, handle: Handle) -> Result<&mut T, HandleError> {
+        let index = self.check(handle)?;
+        Ok(self.entries[index].value.as_mut().expect("Checked handles have values"))
+    }
+
+    // Returns the index of the slot for `handle`, if it's in use by the
+    // object the handle was handed out for.
+    fn check(&self, handle: Handle) -> Result<usize, HandleError> {
+        if handle.map_id != self.id {
+            return Err(HandleError::WrongMap);
+        }
+        let entry = self
+            .entries
+            .get(handle.index as usize)
+            .ok_or(HandleError::InvalidHandle)?;
+        if handle.version == 0 || handle.version > entry.version {
+            // We never handed out a version that's newer than the slot's.
+            return Err(HandleError::InvalidHandle);
+        }
+        if handle.version != entry.version || entry.value.is_none() {
+            return Err(HandleError::StaleHandle);
+        }
+        Ok(handle.index as usize)
+    }
+}
+
+impl<T> Default for HandleMap<T> {
+    #[inline]
+    fn default() -> Self {
+        HandleMap::new()
+    }
+}
+
+/// A `HandleMap` that's safe to share between threads, for storing objects
+/// that are passed over the FFI. See the module docs for an example.
+///
+/// The map itself is behind a `RwLock`, and each object behind a `Mutex`:
+/// looking up an object only needs a read lock on the map, so different
+/// objects can be used at the same time, while inserting and removing need
+/// the write lock, and so wait until nobody is using any object.
+pub struct ConcurrentHandleMap<T> {
+    map: RwLock<HandleMap<Mutex<T>>>,
+}
+
+impl<T> ConcurrentHandleMap<T> {
+    pub fn new() -> Self {
+        ConcurrentHandleMap {
+            map: RwLock::new(HandleMap::new()),
+        }
+    }
+
+    /// The number of objects in the map.
+    pub fn len(&self) -> usize {
+        self.map.read().unwrap().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds `value` to the map, and returns its handle as a `u64`.
+    pub fn insert(&self, value: T) -> u64 {
+        self.map.write().unwrap().insert(Mutex::new(value)).into_u64()
+    }
+
+    /// Removes the object for `handle`, and returns it.
+    pub fn remove_u64(&self, handle: u64) -> Result<T, HandleError> {
+        let handle = Handle::from_u64(handle)?;
+        let value = self.map.write().unwrap().remove(handle)?;
+        // If a callback panicked while holding the lock, we still want to
+        // free the object.
+        Ok(match value.into_inner() {
+            Ok(value) => value,
+            Err(poisoned) => poisoned.into_inner(),
+        })
+    }
+
+    /// Removes and drops the object for `handle`. This is what the
+    /// destructors defined by `define_handle_map_deleter!` call.
+    #[inline]
+    pub fn delete_u64(&self, handle: u64) -> Result<(), HandleError> {
+        self.remove_u64(handle).map(drop)
+    }
+
+    /// Calls `callback` with the object for `handle`. The object is locked
+    /// for the duration of the call.
+    pub fn get<F, R, E>(&self, handle: u64, callback: F) -> Result<R, E>
+    where
+        F: FnOnce(&T) -> Result<R, E>,
+        E: From<HandleError>,
+    {
+        self.get_mut(handle, |value| callback(value))
+    }
+
+    /// Calls `callback` with a mutable reference to the object for `handle`.
+    pub fn get_mut<F, R, E>(&self, handle: u64, callback: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut T) -> Result<R, E>,
+        E: From<HandleError>,
+    {
+        let handle = Handle::from_u64(handle)?;
+        let map = self.map.read().unwrap();
+        let mutex = map.get(handle)?;
+        // This panics (which `call_with_result` reports as an error) if an
+        // earlier callback for this object panicked, since the object may be
+        // in a bad state.
+        let mut value = mutex.lock().unwrap();
+        callback(&mut *value)
+    }
+
+    /// Like `call_with_result`, but calls `callback` with the object for
+    /// `handle`, and reports a bad handle through `out_error` too.
+    pub fn call_with_result<R, E, F>(&self, out_error: &mut ExternError, handle: u64, callback: F) -> R::Value
+    where
+        F: panic::UnwindSafe + FnOnce(&T) -> Result<R, E>,
+        E: Into<ExternError>,
+        R: IntoFfi,
+    {
+        self.call_with_result_mut(out_error, handle, |value| callback(value))
+    }
+
+    /// Like `call_with_result`, but `callback` gets a mutable reference.
+    pub fn call_with_result_mut<R, E, F>(&self, out_error: &mut ExternError, handle: u64, callback: F) -> R::Value
+    where
+        F: panic::UnwindSafe + FnOnce(&mut T) -> Result<R, E>,
+        E: Into<ExternError>,
+        R: IntoFfi,
+    {
+        // The map is only ever modified with the write lock held, and we
+        // don't hand out references to objects outside of a callback, so
+        // a panic can't leave it in a state that's unsafe to observe.
+        let map = panic::AssertUnwindSafe(self);
+        ::call_with_result(out_error, move || -> Result<R, ExternError> {
+            map.get_mut(handle, |value| callback(value).map_err(Into::into))
+        })
+    }
+
+    /// Calls `constructor`, and adds the object it returns to the map. This
+    /// is for FFI functions that create objects, like opening a connection.
+    /// Returns the new handle, or zero if `constructor` failed.
+    pub fn insert_with_result<E, F>(&self, out_error: &mut ExternError, constructor: F) -> u64
+    where
+        F: panic::UnwindSafe + FnOnce() -> Result<T, E>,
+        E: Into<ExternError>,
+    {
+        let map = panic::AssertUnwindSafe(self);
+        ::call_with_result(out_error, move || -> Result<u64, E> {
+            Ok(map.insert(constructor()?))
+        })
+    }
+}
+
+impl<T> Default for ConcurrentHandleMap<T> {
+    #[inline]
+    fn default() -> Self {
+        ConcurrentHandleMap::new()
+    }
+}
+
+/// Define an `extern "C"` function that removes and drops an object in a
+/// `ConcurrentHandleMap`, for the other side of the FFI to call when it's
+/// done with the object. For example,
+/// `define_handle_map_deleter!(mylib_close, CONNECTIONS);`, where
+/// `CONNECTIONS` is a `ConcurrentHandleMap` (or a `lazy_static!` that derefs
+/// to one). The function takes the handle, and an `ExternError` out
+/// parameter, which is set if the handle is bad (for example, if it was
+/// already deleted).
+#[macro_export]
+macro_rules! define_handle_map_deleter {
+    ($mylib_destroy_object:ident, $map:expr) => {
+        #[no_mangle]
+        pub extern "C" fn $mylib_destroy_object(handle: u64, error: &mut $crate::ExternError) {
+            $crate::call_with_result(error, || $map.delete_u64(handle))
+        }
+    };
+}
+
+/// Define an `extern "C"` function that creates an object, and adds it to a
+/// `ConcurrentHandleMap`. The function takes the arguments listed after the
+/// map, and an `ExternError` out parameter, and returns the new handle, or
+/// zero if the body returned an error. For example:
+///
+/// ```rust,ignore
+/// define_handle_map_constructor! {
+///     fn mylib_open(CONNECTIONS, path: FfiStr) -> Result<Connection, Error> {
+///         Connection::open(path.as_str())
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! define_handle_map_constructor {
+    (fn $mylib_new_object:ident($map:expr $(, $arg:ident: $arg_ty:ty)*) -> Result<$T:ty, $E:ty> $body:block) => {
+        #[no_mangle]
+        pub extern "C" fn $mylib_new_object($($arg: $arg_ty,)* error: &mut $crate::ExternError) -> u64 {
+            $map.insert_with_result(error, move || -> Result<$T, $E> { $body })
+        }
+    };
+}
+
+/// Define an `extern "C"` function that calls the body with (a mutable
+/// reference to) the object for a handle in a `ConcurrentHandleMap`, like
+/// `ConcurrentHandleMap::call_with_result_mut`. The function takes the
+/// handle, the arguments listed after the object's name, and an
+/// `ExternError` out parameter, which is also set if the handle is bad. For
+/// example:
+///
+/// ```rust,ignore
+/// define_handle_map_accessor! {
+///     fn mylib_get_title(CONNECTIONS, conn, id: i64) -> Result<Option<String>, Error> {
+///         conn.get_title(id)
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! define_handle_map_accessor {
+    (fn $mylib_call:ident($map:expr, $obj:ident $(, $arg:ident: $arg_ty:ty)*) -> Result<$R:ty, $E:ty> $body:block) => {
+        #[no_mangle]
+        pub extern "C" fn $mylib_call(
+            handle: u64,
+            $($arg: $arg_ty,)*
+            error: &mut $crate::ExternError
+        ) -> <$R as $crate::IntoFfi>::Value {
+            $map.call_with_result_mut(error, handle, move |$obj| -> Result<$R, $E> { $body })
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq)]
+    struct Counter(u32);
+
+    #[test]
+    fn test_handle_map() {
+        let mut map = HandleMap::new();
+        let a = map.insert(Counter(1));
+        let b = map.insert(Counter(2));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(a), Ok(&Counter(1)));
+        map.get_mut(b).unwrap().0 += 1;
+        assert_eq!(map.get(b), Ok(&Counter(3)));
+
+        assert_eq!(map.remove(a), Ok(Counter(1)));
+        assert_eq!(map.remove(a), Err(HandleError::StaleHandle));
+        assert_eq!(map.get(a), Err(HandleError::StaleHandle));
+
+        // The slot is reused, but the old handle doesn't see the new object.
+        let c = map.insert(Counter(4));
+        assert_eq!(c.index, a.index);
+        assert_ne!(c, a);
+        assert_eq!(map.get(a), Err(HandleError::StaleHandle));
+        assert_eq!(map.get(c), Ok(&Counter(4)));
+        assert_eq!(map.len(), 2);
+
+        // Handles survive the trip through a `u64`, and made-up ones are
+        // rejected.
+        assert_eq!(Handle::from_u64(c.into_u64()), Ok(c));
+        assert_eq!(Handle::from_u64(0), Err(HandleError::NullHandle));
+        let past_end = Handle { index: 10, ..c };
+        assert_eq!(map.get(past_end), Err(HandleError::InvalidHandle));
+        let future = Handle { version: c.version + 1, ..c };
+        assert_eq!(map.get(future), Err(HandleError::InvalidHandle));
+
+        let other: HandleMap<Counter> = HandleMap::new();
+        assert_eq!(other.get(c), Err(HandleError::WrongMap));
+
+        map.delete(b).unwrap();
+        map.delete(c).unwrap();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_retired_slots() {
+        let mut map = HandleMap::new();
+        let a = map.insert(Counter(1));
+        map.delete(a).unwrap();
+        map.entries[a.index as usize].version = u16::max_value() - 1;
+
+        // Once a slot has used its last version, it's never reused, so old
+        // handles can't see new objects.
+        let b = map.insert(Counter(2));
+        assert_eq!(b.index, a.index);
+        assert_eq!(b.version, u16::max_value());
+        map.delete(b).unwrap();
+        assert!(map.is_empty());
+        let c = map.insert(Counter(3));
+        assert_ne!(c.index, b.index);
+        assert_eq!(map.get(a), Err(HandleError::StaleHandle));
+        assert_eq!(map.get(b), Err(HandleError::StaleHandle));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_call_with_result() {
+        let map = ConcurrentHandleMap::new();
+        let handle = map.insert(Counter(0));
+        assert_ne!(handle, 0);
+
+        let mut error = ExternError::success();
+        let v: u32 = map.call_with_result_mut(&mut error, handle, |c| -> Result<u32, ExternError> {
+            c.0 += 5;
+            Ok(c.0)
+        });
+        assert_eq!(v, 5);
+        assert_eq!(error.get_code(), ErrorCode::SUCCESS);
+
+        let v: u32 = map.call_with_result(&mut error, handle, |_| -> Result<u32, ExternError> {
+            Err(ExternError::new_error(ErrorCode::new(2), "nope"))
+        });
+        assert_eq!(v, 0);
+        assert_eq!(error.get_code(), ErrorCode::new(2));
+        unsafe { error.manually_release() };
+
+        assert_eq!(map.remove_u64(handle), Ok(Counter(5)));
+        let v: u32 = map.call_with_result(&mut error, handle, |c| -> Result<u32, ExternError> { Ok(c.0) });
+        assert_eq!(v, 0);
+        assert_eq!(error.get_code(), ErrorCode::INVALID_HANDLE);
+        unsafe { error.manually_release() };
+
+        let handle = map.insert_with_result(&mut error, || -> Result<Counter, ExternError> {
+            Err(ExternError::new_error(ErrorCode::new(3), "can't open"))
+        });
+        assert_eq!(handle, 0);
+        assert_eq!(error.get_code(), ErrorCode::new(3));
+        unsafe { error.manually_release() };
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_panic() {
+        let map = ConcurrentHandleMap::new();
+        let handle = map.insert(Counter(0));
+        let mut error = ExternError::success();
+        let _: u32 = map.call_with_result(&mut error, handle, |_| -> Result<u32, ExternError> {
+            panic!("oh no");
+        });
+        assert_eq!(error.get_code(), ErrorCode::PANIC);
+        unsafe { error.manually_release() };
+
+        // The object might be broken now, so we report an error instead of
+        // using it, but can still free it.
+        let _: u32 = map.call_with_result(&mut error, handle, |c| -> Result<u32, ExternError> { Ok(c.0) });
+        assert_eq!(error.get_code(), ErrorCode::PANIC);
+        unsafe { error.manually_release() };
+        assert_eq!(map.remove_u64(handle), Ok(Counter(0)));
+    }
+
+    lazy_static! {
+        static ref COUNTERS: ConcurrentHandleMap<Counter> = ConcurrentHandleMap::new();
+    }
+
+    define_handle_map_deleter!(test_destroy_counter, COUNTERS);
+
+    #[test]
+    fn test_deleter() {
+        let handle = COUNTERS.insert(Counter(0));
+        let mut error = ExternError::success();
+        test_destroy_counter(handle, &mut error);
+        assert_eq!(error.get_code(), ErrorCode::SUCCESS);
+
+        // Freeing it twice is an error, not a double free.
+        test_destroy_counter(handle, &mut error);
+        assert_eq!(error.get_code(), ErrorCode::INVALID_HANDLE);
+        unsafe { error.manually_release() };
+    }
+
+    define_handle_map_constructor! {
+        fn test_new_counter(COUNTERS, start: u32) -> Result<Counter, ExternError> {
+            if start > 100 {
+                return Err(ExternError::new_error(ErrorCode::new(2), "too big"));
+            }
+            Ok(Counter(start))
+        }
+    }
+
+    define_handle_map_accessor! {
+        fn test_add_to_counter(COUNTERS, counter, amount: u32) -> Result<u32, ExternError> {
+            counter.0 += amount;
+            Ok(counter.0)
+        }
+    }
+
+    #[test]
+    fn test_constructor_and_accessor() {
+        let mut error = ExternError::success();
+        let handle = test_new_counter(5, &mut error);
+        assert_eq!(error.get_code(), ErrorCode::SUCCESS);
+        assert_eq!(test_add_to_counter(handle, 2, &mut error), 7);
+        assert_eq!(error.get_code(), ErrorCode::SUCCESS);
+
+        assert_eq!(test_new_counter(101, &mut error), 0);
+        assert_eq!(error.get_code(), ErrorCode::new(2));
+        unsafe { error.manually_release() };
+
+        test_destroy_counter(handle, &mut error);
+        assert_eq!(test_add_to_counter(handle, 2, &mut error), 0);
+        assert_eq!(error.get_code(), ErrorCode::INVALID_HANDLE);
+        unsafe { error.manually_release() };
+    }
+
+    // Deleting an object while another thread is using it waits for that
+    // thread to finish, instead of freeing the object out from under it.
+    #[test]
+    fn test_delete_while_in_use() {
+        let map = Arc::new(ConcurrentHandleMap::new());
+        let handle = map.insert(Counter(0));
+        let started = Arc::new(AtomicBool::new(false));
+
+        let user = {
+            let map = Arc::clone(&map);
+            let started = Arc::clone(&started);
+            thread::spawn(move || {
+                map.get_mut(handle, |c| -> Result<(), HandleError> {
+                    started.store(true, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    c.0 += 1;
+                    Ok(())
+                }).unwrap();
+            })
+        };
+        while !started.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        assert_eq!(map.remove_u64(handle), Ok(Counter(1)));
+        user.join().unwrap();
+        assert_eq!(
+            map.get(handle, |_| -> Result<(), HandleError> { Ok(()) }),
+            Err(HandleError::StaleHandle)
+        );
+    }
+}
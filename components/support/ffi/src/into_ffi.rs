@@ -0,0 +1,137 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::os::raw::c_char;
+use std::ptr;
+use string::{rust_string_to_c, StaticCStr};
+
+/// Converts the value returned by a callback passed to `call_with_result` into
+/// something that can be returned over the FFI.
+///
+/// `ffi_default` is what we return when an error (or panic) occurs, and is
+/// what the other side of the FFI will see in that case, so it should be
+/// something harmless, like zero or a null pointer.
+pub unsafe trait IntoFfi {
+    /// The type actually returned over the FFI. This must be safe to pass to
+    /// and from C.
+    type Value;
+
+    fn ffi_default() -> Self::Value;
+
+    fn into_ffi_value(self) -> Self::Value;
+}
+
+unsafe impl IntoFfi for () {
+    type Value = ();
+    #[inline]
+    fn ffi_default() {}
+    #[inline]
+    fn into_ffi_value(self) {}
+}
+
+/// Strings are returned as heap allocated, nul-terminated C strings, which must
+/// be freed by the caller with the component's string destructor.
+unsafe impl IntoFfi for String {
+    type Value = *mut c_char;
+
+    #[inline]
+    fn ffi_default() -> Self::Value {
+        ptr::null_mut()
+    }
+
+    #[inline]
+    fn into_ffi_value(self) -> Self::Value {
+        rust_string_to_c(self)
+    }
+}
+
+/// `None` is returned as a null pointer, which means callers can't tell it
+/// apart from an error without checking the `ExternError`.
+unsafe impl IntoFfi for Option<String> {
+    type Value = *mut c_char;
+
+    #[inline]
+    fn ffi_default() -> Self::Value {
+        ptr::null_mut()
+    }
+
+    #[inline]
+    fn into_ffi_value(self) -> Self::Value {
+        match self {
+            Some(s) => rust_string_to_c(s),
+            None => ptr::null_mut(),
+        }
+    }
+}
+
+/// Static strings are returned as pointers to memory Rust owns, which the
+/// caller must not free. See `StaticCStr`.
+unsafe impl IntoFfi for StaticCStr {
+    type Value = *const c_char;
+
+    #[inline]
+    fn ffi_default() -> Self::Value {
+        ptr::null()
+    }
+
+    #[inline]
+    fn into_ffi_value(self) -> Self::Value {
+        self.as_ptr()
+    }
+}
+
+unsafe impl<T> IntoFfi for *mut T {
+    type Value = *mut T;
+    #[inline]
+    fn ffi_default() -> Self::Value {
+        ptr::null_mut()
+    }
+    #[inline]
+    fn into_ffi_value(self) -> Self::Value {
+        self
+    }
+}
+
+unsafe impl<T> IntoFfi for *const T {
+    type Value = *const T;
+    #[inline]
+    fn ffi_default() -> Self::Value {
+        ptr::null()
+    }
+    #[inline]
+    fn into_ffi_value(self) -> Self::Value {
+        self
+    }
+}
+
+/// `bool` isn't guaranteed to be FFI safe, so we return a `u8` instead.
+unsafe impl IntoFfi for bool {
+    type Value = u8;
+    #[inline]
+    fn ffi_default() -> Self::Value {
+        0
+    }
+    #[inline]
+    fn into_ffi_value(self) -> Self::Value {
+        self as u8
+    }
+}
+
+macro_rules! impl_into_ffi_for_primitive {
+    ($($T:ty),+) => {$(
+        unsafe impl IntoFfi for $T {
+            type Value = Self;
+            #[inline]
+            fn ffi_default() -> Self {
+                Default::default()
+            }
+            #[inline]
+            fn into_ffi_value(self) -> Self {
+                self
+            }
+        }
+    )+}
+}
+
+impl_into_ffi_for_primitive![u8, i8, u16, i16, u32, i32, u64, i64, f32, f64];
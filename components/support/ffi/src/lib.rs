@@ -0,0 +1,217 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers shared by the FFI crates of our components.
+//!
+//! Each component's FFI crate used to carry its own copy of the code for
+//! catching panics, reporting errors through an `ExternError` out parameter,
+//! and converting strings between Rust and C. This crate is the one place that
+//! code lives now. The general shape of an FFI function using it is:
+//!
+//! ```rust,ignore
+//! #[no_mangle]
+//! pub extern "C" fn mylib_frobnicate(
+//!     thing: FfiStr,
+//!     error: &mut ExternError,
+//! ) -> *mut c_char {
+//!     call_with_result(error, || {
+//!         frobnicate(thing.as_str()) // Returns a `Result<String, MyError>`.
+//!     })
+//! }
+//! ```
+//!
+//! where `MyError` implements `Into<ExternError>`.
+
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+#[cfg(feature = "build_support")]
+extern crate cbindgen;
+// Public so that `implement_into_ffi_by_protobuf!` can name it.
+#[cfg(feature = "prost_support")]
+#[doc(hidden)]
+pub extern crate prost;
+#[cfg(all(test, feature = "prost_support"))]
+extern crate bytes;
+#[cfg(all(test, feature = "prost_support"))]
+#[macro_use]
+extern crate prost_derive;
+
+mod boxed;
+mod buffer;
+mod byte_buffer;
+mod chain;
+mod code_space;
+mod deprecated;
+mod error;
+mod ffistr;
+mod handle_map;
+mod into_ffi;
+mod log_sink;
+mod pool;
+mod slice;
+mod string;
+mod tagged;
+mod version;
+
+pub use boxed::*;
+pub use buffer::*;
+pub use byte_buffer::*;
+pub use chain::*;
+pub use code_space::*;
+pub use deprecated::*;
+pub use error::*;
+pub use ffistr::*;
+pub use handle_map::*;
+pub use into_ffi::*;
+pub use log_sink::*;
+pub use pool::*;
+pub use slice::*;
+pub use string::*;
+pub use tagged::*;
+pub use version::*;
+
+#[cfg(feature = "build_support")]
+pub mod build;
+
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+
+use std::os::raw::c_char;
+use std::panic;
+use std::sync::{Mutex, MutexGuard};
+
+/// Call a callback that returns a `Result<R, E>`, converting the result to
+/// something that can be returned over the FFI, and writing any error (or
+/// panic) to `out_error`.
+///
+/// On success, `out_error` is set to `ExternError::success()` and the value is
+/// converted with `IntoFfi::into_ffi_value`. On failure, `out_error` holds the
+/// error and `IntoFfi::ffi_default()` is returned.
+///
+/// Panics are caught rather than unwinding across the FFI boundary (which is
+/// undefined behavior), and reported with `ErrorCode::PANIC`.
+pub fn call_with_result<R, E, F>(out_error: &mut ExternError, callback: F) -> R::Value
+where
+    F: panic::UnwindSafe + FnOnce() -> Result<R, E>,
+    E: Into<ExternError>,
+    R: IntoFfi,
+{
+    let res: std::thread::Result<(ExternError, R::Value)> = panic::catch_unwind(|| {
+        match callback() {
+            Ok(v) => (ExternError::success(), v.into_ffi_value()),
+            Err(e) => (e.into(), R::ffi_default()),
+        }
+    });
+    match res {
+        Ok((err, value)) => {
+            *out_error = err;
+            value
+        }
+        Err(e) => {
+            error!("Caught a panic calling rust code: {:?}", e);
+            *out_error = e.into();
+            R::ffi_default()
+        }
+    }
+}
+
+/// Like `call_with_result`, but for callbacks that can't fail, other than by
+/// panicking. `out_error` is still written to, since a panic is reported
+/// there, so the FFI function should take one anyway.
+pub fn call_with_output<R, F>(out_error: &mut ExternError, callback: F) -> R::Value
+where
+    F: panic::UnwindSafe + FnOnce() -> R,
+    R: IntoFfi,
+{
+    call_with_result(out_error, || -> Result<R, ExternError> { Ok(callback()) })
+}
+
+/// Like `call_with_result`, but for callbacks that return anything that can
+/// be converted into a `String` (like a `&'static str`, or a `Cow<str>`),
+/// which is returned as a string that must be freed with the string
+/// destructor.
+pub fn call_with_string_result<R, E, F>(out_error: &mut ExternError, callback: F) -> *mut c_char
+where
+    F: panic::UnwindSafe + FnOnce() -> Result<R, E>,
+    E: Into<ExternError>,
+    R: Into<String>,
+{
+    call_with_result(out_error, || -> Result<String, E> { callback().map(Into::into) })
+}
+
+// Locks `mutex`, even if another thread panicked while holding it. Only for
+// data that a panic can't leave in a bad state, like our registries, which
+// are only changed by single pushes and replacements.
+pub(crate) fn lock_ignoring_poison<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_call_with_result() {
+        let mut error = ExternError::success();
+        let v: u32 = call_with_result(&mut error, || -> Result<u32, ExternError> { Ok(3) });
+        assert_eq!(v, 3);
+        assert_eq!(error.get_code(), ErrorCode::SUCCESS);
+
+        let v: u32 = call_with_result(&mut error, || -> Result<u32, ExternError> {
+            Err(ExternError::new_error(ErrorCode::new(5), "oh no"))
+        });
+        assert_eq!(v, 0);
+        assert_eq!(error.get_code(), ErrorCode::new(5));
+        assert_eq!(error.get_message(), Some("oh no"));
+        unsafe { error.manually_release() };
+
+        let v: u32 = call_with_result(&mut error, || -> Result<u32, ExternError> {
+            panic!("very bad");
+        });
+        assert_eq!(v, 0);
+        assert_eq!(error.get_code(), ErrorCode::PANIC);
+        assert_eq!(error.get_message(), Some("very bad"));
+        unsafe { error.manually_release() };
+    }
+
+    #[test]
+    fn test_call_with_output() {
+        let mut error = ExternError::success();
+        let v: u32 = call_with_output(&mut error, || 3u32);
+        assert_eq!(v, 3);
+        assert_eq!(error.get_code(), ErrorCode::SUCCESS);
+
+        let v: u8 = call_with_output(&mut error, || -> bool { panic!("very bad") });
+        assert_eq!(v, 0);
+        assert_eq!(error.get_code(), ErrorCode::PANIC);
+        unsafe { error.manually_release() };
+    }
+
+    #[test]
+    fn test_call_with_string_result() {
+        let mut error = ExternError::success();
+        let s = call_with_string_result(&mut error, || -> Result<&str, ExternError> { Ok("hello") });
+        assert_eq!(error.get_code(), ErrorCode::SUCCESS);
+        unsafe {
+            assert_eq!(rust_str_from_c(s), "hello");
+            destroy_c_string(s);
+        }
+
+        let s = call_with_string_result(&mut error, || -> Result<&str, ExternError> {
+            Err(ExternError::new_error(ErrorCode::new(2), "nope"))
+        });
+        assert!(s.is_null());
+        assert_eq!(error.get_code(), ErrorCode::new(2));
+        unsafe { error.manually_release() };
+    }
+}
@@ -0,0 +1,263 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Forwarding the logs of our components to the app.
+//!
+//! On Android, a component can use `android_logger` to write to logcat, but
+//! there's nothing like that on iOS, and apps may want to route logs through
+//! their own logging library on Android too. Instead, each FFI crate defines
+//! functions for registering a callback with `define_log_callback_setters!`:
+//!
+//! ```rust,ignore
+//! define_log_callback_setters!(mylib_set_log_callback, mylib_clear_log_callback);
+//! ```
+//!
+//! and the app registers one, which is called with every record logged with
+//! the `log` crate by any component in that library:
+//!
+//! ```c
+//! void on_log(int32_t level, const char *tag, const char *message) {
+//!     // Copy `tag` and `message` if you need them after returning.
+//! }
+//!
+//! if (!mylib_set_log_callback(on_log, 3 /* LogLevel::Debug */)) {
+//!     // Another logger was installed first.
+//! }
+//! ```
+//!
+//! `log` only allows one logger per library, so this fails if another one
+//! (like `android_logger`) was already installed. FFI crates that install
+//! `android_logger` should check `foreign_logger_installed` first, and apps
+//! should register their callback before calling anything else.
+
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::RwLock;
+
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
+
+/// The type of the callback the app registers. `level` is a `LogLevel`,
+/// `tag` is the Rust module the record was logged from, and `message` is
+/// the formatted message. The strings are only valid until the callback
+/// returns. It may be called on any thread, must not unwind, and shouldn't
+/// call back into Rust code that logs.
+pub type LogCallback = extern "C" fn(level: i32, tag: *const c_char, message: *const c_char);
+
+/// The level of a record passed to a `LogCallback`. These have the same
+/// values as Android's log priorities, so Android apps can pass them
+/// straight to `android.util.Log.println`.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Verbose = 2,
+    Debug = 3,
+    Info = 4,
+    Warn = 5,
+    Error = 6,
+}
+
+impl LogLevel {
+    /// Converts a level passed over the FFI to the most verbose level we
+    /// forward. Anything above `Error` turns logging off.
+    pub fn level_filter(level: i32) -> LevelFilter {
+        match level {
+            level if level <= 2 => LevelFilter::Trace,
+            3 => LevelFilter::Debug,
+            4 => LevelFilter::Info,
+            5 => LevelFilter::Warn,
+            6 => LevelFilter::Error,
+            _ => LevelFilter::Off,
+        }
+    }
+}
+
+impl From<Level> for LogLevel {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Trace => LogLevel::Verbose,
+            Level::Debug => LogLevel::Debug,
+            Level::Info => LogLevel::Info,
+            Level::Warn => LogLevel::Warn,
+            Level::Error => LogLevel::Error,
+        }
+    }
+}
+
+lazy_static! {
+    // A callback is only ever called while holding the read lock, so once
+    // `clear_log_callback` (which takes the write lock) returns, it's never
+    // called again, and the app can free whatever it uses.
+    static ref CALLBACK: RwLock<Option<LogCallback>> = RwLock::new(None);
+}
+
+struct ForeignLogger;
+
+static LOGGER: ForeignLogger = ForeignLogger;
+static LOGGER_INSTALLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+impl Log for ForeignLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        // `log` already checked the max level.
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let callback = match CALLBACK.read() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let callback = match *callback {
+            Some(callback) => callback,
+            None => return,
+        };
+        // Formatting the message runs arbitrary `Display` impls, which might
+        // panic, and we don't want that to take down the caller's thread just
+        // because it logged something.
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let tag = to_cstring(record.module_path().unwrap_or_else(|| record.target()));
+            let message = to_cstring(&record.args().to_string());
+            callback(LogLevel::from(record.level()) as i32, tag.as_ptr(), message.as_ptr());
+        }));
+        if res.is_err() {
+            // Don't log this, since it'd probably panic again.
+            eprintln!("ffi-support: Caught a panic forwarding a log record");
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// C strings can't have interior nuls, so replace them rather than dropping the
+// whole record.
+fn to_cstring(s: &str) -> CString {
+    CString::new(s.replace('\0', "\u{fffd}")).expect("Nul bytes were replaced")
+}
+
+/// Forwards records logged with the `log` crate, at `max_level` or less
+/// verbose, to `callback`, replacing any callback that was set before.
+/// Fails if a logger other than ours was already installed.
+pub fn set_log_callback(callback: LogCallback, max_level: LevelFilter) -> Result<(), log::SetLoggerError> {
+    {
+        let mut current = match CALLBACK.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // We can't uninstall our logger, so it stays installed (and does
+        // nothing) after the callback is cleared.
+        if !LOGGER_INSTALLED.load(Ordering::SeqCst) {
+            log::set_logger(&LOGGER)?;
+            LOGGER_INSTALLED.store(true, Ordering::SeqCst);
+        }
+        *current = Some(callback);
+    }
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Returns true if `set_log_callback` installed our logger, in which case
+/// no other logger can be installed, even after the callback is cleared.
+pub fn foreign_logger_installed() -> bool {
+    LOGGER_INSTALLED.load(Ordering::SeqCst)
+}
+
+/// Stops forwarding records to the callback set with `set_log_callback`.
+/// Once this returns, it won't be called again. Does nothing if there's no
+/// callback, or if another logger was installed.
+pub fn clear_log_callback() {
+    let mut current = match CALLBACK.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if current.take().is_some() {
+        log::set_max_level(LevelFilter::Off);
+    }
+}
+
+/// Define `extern "C"` functions for setting and clearing the log callback,
+/// which call `set_log_callback` and `clear_log_callback`. In C, they're
+///
+/// ```c
+/// // Forwards logs at `max_level` (a `LogLevel`) or less verbose to
+/// // `callback`, replacing any callback and level set before. Returns 0 if
+/// // another logger was installed first, and 1 otherwise.
+/// uint8_t mylib_set_log_callback(LogCallback callback, int32_t max_level);
+/// // Once this returns, the callback is never called again, so the app can
+/// // clean up after it. Must not be called from the callback itself.
+/// void mylib_clear_log_callback(void);
+/// ```
+#[macro_export]
+macro_rules! define_log_callback_setters {
+    ($mylib_set_log_callback:ident, $mylib_clear_log_callback:ident) => {
+        #[no_mangle]
+        pub extern "C" fn $mylib_set_log_callback(
+            callback: $crate::LogCallback,
+            max_level: i32,
+        ) -> u8 {
+            match $crate::set_log_callback(callback, $crate::LogLevel::level_filter(max_level)) {
+                Ok(()) => 1,
+                Err(_) => 0,
+            }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn $mylib_clear_log_callback() {
+            $crate::clear_log_callback()
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CStr;
+    use std::fmt;
+    use std::sync::Mutex;
+
+    lazy_static! {
+        static ref RECORDS: Mutex<Vec<(i32, String)>> = Mutex::new(Vec::new());
+    }
+
+    extern "C" fn record(level: i32, tag: *const c_char, message: *const c_char) {
+        let (tag, message) = unsafe {
+            (CStr::from_ptr(tag).to_string_lossy(), CStr::from_ptr(message).to_string_lossy())
+        };
+        // Other tests may log at the same time.
+        if tag == module_path!() {
+            RECORDS.lock().unwrap().push((level, message.into_owned()));
+        }
+    }
+
+    struct Exploding;
+
+    impl fmt::Display for Exploding {
+        fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+            panic!("Exploding while formatting");
+        }
+    }
+
+    define_log_callback_setters!(ffi_support_test_set_log_callback, ffi_support_test_clear_log_callback);
+
+    #[test]
+    fn test_log_callback() {
+        assert_eq!(ffi_support_test_set_log_callback(record, LogLevel::Debug as i32), 1);
+        assert!(foreign_logger_installed());
+        info!("hello {}", "world");
+        debug!("nul\0byte");
+        trace!("too verbose");
+        // Shouldn't unwind out of `log`.
+        warn!("{}", Exploding);
+        error!("bye");
+
+        ffi_support_test_clear_log_callback();
+        error!("not forwarded");
+
+        assert_eq!(*RECORDS.lock().unwrap(), vec![
+            (LogLevel::Info as i32, "hello world".to_string()),
+            (LogLevel::Debug as i32, "nul\u{fffd}byte".to_string()),
+            (LogLevel::Error as i32, "bye".to_string()),
+        ]);
+    }
+}
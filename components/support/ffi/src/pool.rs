@@ -0,0 +1,331 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Reusing the byte buffers we return over the FFI, for functions that are
+//! called often and return a similar amount of data each time, like
+//! autocomplete (which runs on every keystroke).
+//!
+//! Each component that wants this declares a `BufferPool` in a `static`
+//! (usually with `lazy_static!`), and a destructor for its buffers with
+//! `define_pooled_buffer_destructor!`. A function then takes an empty buffer
+//! from the pool with `acquire`, writes its result into it (for example, with
+//! `serde_json::to_writer`), and returns it with `BufferPool::to_ffi`:
+//!
+//! ```rust,ignore
+//! lazy_static! {
+//!     static ref BUFFERS: BufferPool = BufferPool::new(4, 64 * 1024);
+//! }
+//!
+//! #[no_mangle]
+//! pub extern "C" fn mylib_search(query: *const c_char, error: &mut ExternError) -> PooledBuffer {
+//!     call_with_result(error, || {
+//!         let mut buf = BUFFERS.acquire();
+//!         serde_json::to_writer(&mut buf, &search(rust_str_from_c(query))?)?;
+//!         Ok(BUFFERS.to_ffi(buf))
+//!     })
+//! }
+//!
+//! define_pooled_buffer_destructor!(mylib_destroy_pooled_buffer, BUFFERS);
+//! ```
+//!
+//! When the other side of the FFI calls the destructor, the buffer goes back
+//! into the pool instead of being freed, so the next call can write into it
+//! without allocating.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::{mem, ptr, slice};
+
+use into_ffi::IntoFfi;
+use lock_ignoring_poison;
+
+/// A byte buffer allocated by a `BufferPool`, passed over the FFI by value.
+///
+/// The other side of the FFI may read `len` bytes from `data` (which may be
+/// null when `len` is zero), and must pass the buffer back to the destructor
+/// defined with `define_pooled_buffer_destructor!` when it's done, without
+/// changing any of the fields.
+///
+/// In C, this is a struct with an `int64_t len`, a `uint8_t *data` and an
+/// `int64_t capacity`, in that order.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PooledBuffer {
+    len: i64,
+    data: *mut u8,
+    // Needed to rebuild the `Vec` when the buffer is returned. Not for use by
+    // the other side of the FFI.
+    capacity: i64,
+}
+
+impl PooledBuffer {
+    fn from_vec(mut v: Vec<u8>) -> Self {
+        if v.capacity() == 0 {
+            return PooledBuffer::empty();
+        }
+        let buffer = PooledBuffer {
+            len: v.len() as i64,
+            data: v.as_mut_ptr(),
+            capacity: v.capacity() as i64,
+        };
+        mem::forget(v);
+        buffer
+    }
+
+    #[inline]
+    pub fn empty() -> Self {
+        PooledBuffer { len: 0, data: ptr::null_mut(), capacity: 0 }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The contents of the buffer.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must have come from `BufferPool::to_ffi` (or `empty`),
+    /// unmodified, and not have been returned to its pool yet.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        if self.data.is_null() {
+            &[]
+        } else {
+            slice::from_raw_parts(self.data, self.len())
+        }
+    }
+
+    unsafe fn into_vec(self) -> Option<Vec<u8>> {
+        if self.data.is_null() {
+            None
+        } else {
+            Some(Vec::from_raw_parts(self.data, self.len as usize, self.capacity as usize))
+        }
+    }
+}
+
+impl Default for PooledBuffer {
+    #[inline]
+    fn default() -> Self {
+        PooledBuffer::empty()
+    }
+}
+
+unsafe impl IntoFfi for PooledBuffer {
+    type Value = PooledBuffer;
+    #[inline]
+    fn ffi_default() -> Self::Value {
+        PooledBuffer::empty()
+    }
+    #[inline]
+    fn into_ffi_value(self) -> Self::Value {
+        self
+    }
+}
+
+/// Counts of what a `BufferPool` has done, for measuring how much it helps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Buffers handed out by `acquire` that had to be allocated.
+    pub allocated: usize,
+    /// Buffers handed out by `acquire` that were reused from the pool.
+    pub reused: usize,
+    /// Buffers returned to the pool that were freed instead of kept, because
+    /// the pool was full or they were too big.
+    pub discarded: usize,
+}
+
+/// A pool of byte buffers, shared across FFI calls (and threads).
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+    max_capacity: usize,
+    allocated: AtomicUsize,
+    reused: AtomicUsize,
+    discarded: AtomicUsize,
+}
+
+impl BufferPool {
+    /// Creates a pool that keeps up to `max_buffers` buffers for reuse. A
+    /// buffer that grew past `max_capacity` bytes (for an unusually large
+    /// result) is freed when it's returned, rather than holding on to the
+    /// memory forever.
+    ///
+    /// `max_buffers` only needs to be as large as the number of buffers the
+    /// other side of the FFI holds on to at once, plus one.
+    pub fn new(max_buffers: usize, max_capacity: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+            max_capacity,
+            allocated: AtomicUsize::new(0),
+            reused: AtomicUsize::new(0),
+            discarded: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes an empty buffer from the pool, or a new one if the pool is
+    /// empty.
+    pub fn acquire(&self) -> Vec<u8> {
+        match self.lock().pop() {
+            Some(buf) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Puts a buffer back in the pool, if there's room for it. Buffers that
+    /// didn't come from `acquire` are fine too.
+    pub fn recycle(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 {
+            return;
+        }
+        if buf.capacity() <= self.max_capacity {
+            let mut buffers = self.lock();
+            if buffers.len() < self.max_buffers {
+                buf.clear();
+                buffers.push(buf);
+                return;
+            }
+        }
+        self.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Converts a buffer into a `PooledBuffer`, to return over the FFI. It
+    /// comes back to this pool when the other side frees it.
+    #[inline]
+    pub fn to_ffi(&self, buf: Vec<u8>) -> PooledBuffer {
+        PooledBuffer::from_vec(buf)
+    }
+
+    /// Returns a buffer that was passed over the FFI to the pool. This is
+    /// what the destructors defined by `define_pooled_buffer_destructor!`
+    /// call.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must have come from `to_ffi` (of any pool, though it should
+    /// be this one), unmodified, and must not be used afterwards.
+    pub unsafe fn recycle_ffi(&self, buffer: PooledBuffer) {
+        if let Some(buf) = buffer.into_vec() {
+            self.recycle(buf);
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Vec<u8>>> {
+        // The buffers are just memory, so a panic while the lock was held
+        // can't have left them in a bad state.
+        lock_ignoring_poison(&self.buffers)
+    }
+}
+
+/// Define an `extern "C"` function that returns `PooledBuffer`s to the pool
+/// they came from. For example,
+/// `define_pooled_buffer_destructor!(mylib_destroy_pooled_buffer, BUFFERS);`,
+/// where `BUFFERS` is a `BufferPool` (or a `lazy_static!` that derefs to one).
+#[macro_export]
+macro_rules! define_pooled_buffer_destructor {
+    ($mylib_destroy_pooled_buffer:ident, $pool:expr) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $mylib_destroy_pooled_buffer(buffer: $crate::PooledBuffer) {
+            $pool.recycle_ffi(buffer)
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_roundtrip() {
+        let pool = BufferPool::new(2, 1024);
+        let mut buf = pool.acquire();
+        buf.extend_from_slice(b"hello");
+        let buffer = pool.to_ffi(buf);
+        unsafe {
+            assert_eq!(buffer.as_slice(), b"hello");
+            pool.recycle_ffi(buffer);
+        }
+        let buf = pool.acquire();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 5);
+        assert_eq!(pool.stats(), BufferPoolStats { allocated: 1, reused: 1, discarded: 0 });
+
+        // Empty buffers don't allocate, and aren't worth keeping.
+        let buffer = pool.to_ffi(Vec::new());
+        assert!(buffer.data.is_null());
+        unsafe { pool.recycle_ffi(buffer) };
+        unsafe { pool.recycle_ffi(<PooledBuffer as IntoFfi>::ffi_default()) };
+        assert_eq!(pool.stats().discarded, 0);
+    }
+
+    #[test]
+    fn test_limits() {
+        let pool = BufferPool::new(1, 16);
+        pool.recycle(vec![0; 32]);
+        assert_eq!(pool.stats().discarded, 1);
+        pool.recycle(vec![0; 8]);
+        pool.recycle(vec![0; 8]);
+        assert_eq!(pool.stats().discarded, 2);
+        assert_eq!(pool.acquire().capacity(), 8);
+        assert_eq!(pool.acquire().capacity(), 0);
+    }
+
+    // Simulates typing into an address bar hooked up to autocomplete: each
+    // keystroke returns a result of a few KB, which the other side reads and
+    // frees before the next one.
+    #[test]
+    fn test_typing_workload() {
+        const KEYSTROKES: usize = 200;
+        let pool = BufferPool::new(2, 64 * 1024);
+        let mut grew = 0;
+        for keystroke in 0..KEYSTROKES {
+            let mut buf = pool.acquire();
+            let capacity = buf.capacity();
+            write!(buf, "[").unwrap();
+            for result in 0..(keystroke % 10 + 1) {
+                write!(buf, "{{\"url\":\"https://example.com/{}/{}\",\"title\":\"{}\"}},",
+                       keystroke, result, "x".repeat(200)).unwrap();
+            }
+            write!(buf, "]").unwrap();
+            if buf.capacity() != capacity {
+                grew += 1;
+            }
+            let buffer = pool.to_ffi(buf);
+            unsafe {
+                assert_eq!(buffer.as_slice()[0], b'[');
+                pool.recycle_ffi(buffer);
+            }
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.allocated + stats.reused, KEYSTROKES);
+        // Without the pool, every keystroke allocates (and grows) a new
+        // buffer. With it, we only allocate once, and the buffer only grows
+        // until it's big enough for the largest result.
+        assert_eq!(stats.allocated, 1);
+        assert_eq!(stats.discarded, 0);
+        assert!(grew < 10, "Buffer grew {} times", grew);
+    }
+}
@@ -0,0 +1,120 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for receiving byte buffers from C as a `(pointer, length)` pair,
+//! for data that isn't a nul-terminated string (encrypted push payloads,
+//! serialized protobufs, and so on).
+//!
+//! The length is an `i32` rather than a `usize`, since that's what's easiest
+//! to produce from Kotlin (which has no unsigned types) and Swift.
+//!
+//! `std::slice::from_raw_parts` requires a non-null pointer even for an empty
+//! slice, but callers commonly pass null (or whatever pointer an empty array
+//! happens to have) along with a zero length, so we never look at the pointer
+//! when the length is zero. Bytes have no alignment requirements, so any other
+//! non-null pointer is fine.
+
+use std::slice;
+
+fn check_len(len: i32) -> usize {
+    assert!(len >= 0, "Negative buffer length passed to rust: {}", len);
+    len as usize
+}
+
+/// Borrow a `&[u8]` from a pointer and length passed in from C.
+///
+/// # Panics
+///
+/// Panics if `len` is negative, or if `data` is null and `len` isn't zero.
+///
+/// # Safety
+///
+/// Unless `len` is zero, `data` must point to at least `len` readable bytes
+/// that aren't modified while the returned slice is alive. In practice, that
+/// means for the duration of the FFI call.
+pub unsafe fn rust_slice_from_c<'a>(data: *const u8, len: i32) -> &'a [u8] {
+    let len = check_len(len);
+    if len == 0 {
+        return &[];
+    }
+    assert!(!data.is_null(), "Null pointer passed to rust with a non-zero length!");
+    slice::from_raw_parts(data, len)
+}
+
+/// Like `rust_slice_from_c`, but returns `None` if `data` is null (whatever
+/// the length). This is for arguments where a missing buffer means something
+/// different from an empty one.
+///
+/// # Safety
+///
+/// Same as `rust_slice_from_c`.
+pub unsafe fn opt_rust_slice_from_c<'a>(data: *const u8, len: i32) -> Option<&'a [u8]> {
+    if data.is_null() {
+        // Still check the length, to catch callers that pass garbage.
+        check_len(len);
+        None
+    } else {
+        Some(rust_slice_from_c(data, len))
+    }
+}
+
+/// Like `rust_slice_from_c`, but copies the data into a `Vec<u8>`, so that it
+/// can outlive the FFI call.
+///
+/// # Safety
+///
+/// Same as `rust_slice_from_c`, except that the result may outlive `data`.
+pub unsafe fn rust_vec_from_c(data: *const u8, len: i32) -> Vec<u8> {
+    rust_slice_from_c(data, len).to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ptr;
+
+    #[test]
+    fn test_slice_from_c() {
+        let bytes = vec![1u8, 2, 3, 4, 5];
+        unsafe {
+            assert_eq!(rust_slice_from_c(bytes.as_ptr(), 5), &[1, 2, 3, 4, 5]);
+            assert_eq!(rust_slice_from_c(bytes.as_ptr().offset(1), 3), &[2, 3, 4]);
+            assert_eq!(rust_vec_from_c(bytes.as_ptr(), 2), vec![1, 2]);
+        }
+    }
+
+    #[test]
+    fn test_empty_slice_from_c() {
+        unsafe {
+            assert_eq!(rust_slice_from_c(ptr::null(), 0), &[] as &[u8]);
+            // Empty arrays often come with a dangling pointer, which we should
+            // never read from.
+            assert_eq!(rust_slice_from_c(1 as *const u8, 0), &[] as &[u8]);
+            assert_eq!(rust_vec_from_c(ptr::null(), 0), Vec::<u8>::new());
+        }
+    }
+
+    #[test]
+    fn test_opt_slice_from_c() {
+        let bytes = [7u8, 8];
+        unsafe {
+            assert_eq!(opt_rust_slice_from_c(ptr::null(), 0), None);
+            assert_eq!(opt_rust_slice_from_c(bytes.as_ptr(), 0), Some(&[] as &[u8]));
+            assert_eq!(opt_rust_slice_from_c(bytes.as_ptr(), 2), Some(&bytes[..]));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_null_slice_with_len() {
+        unsafe { rust_slice_from_c(ptr::null(), 3) };
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_negative_len() {
+        let bytes = [1u8];
+        unsafe { rust_slice_from_c(bytes.as_ptr(), -1) };
+    }
+}
@@ -0,0 +1,180 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+
+/// Convert a rust string into a nul-terminated, heap allocated C string. The
+/// result must be freed with `destroy_c_string` (usually by way of a string
+/// destructor defined with `define_string_destructor!`).
+///
+/// # Panics
+///
+/// Panics if the string contains a nul byte.
+pub fn rust_string_to_c<S: Into<String>>(rust_string: S) -> *mut c_char {
+    CString::new(rust_string.into())
+        .expect("Error: Rust string contained an interior null byte.")
+        .into_raw()
+}
+
+/// Like `rust_string_to_c`, but returns null for `None`.
+pub fn opt_rust_string_to_c<S: Into<String>>(opt_rust_string: Option<S>) -> *mut c_char {
+    match opt_rust_string {
+        Some(s) => rust_string_to_c(s),
+        None => ::std::ptr::null_mut(),
+    }
+}
+
+/// Free a string created by `rust_string_to_c`. Does nothing if `cstring` is
+/// null.
+///
+/// # Safety
+///
+/// `cstring` must have been returned by `rust_string_to_c` (or
+/// `CString::into_raw`), and must not be used after this call.
+pub unsafe fn destroy_c_string(cstring: *mut c_char) {
+    if !cstring.is_null() {
+        drop(CString::from_raw(cstring))
+    }
+}
+
+/// Borrow a `&str` from a nul-terminated C string.
+///
+/// # Panics
+///
+/// Panics if `cstr` is null or isn't valid UTF-8.
+///
+/// # Safety
+///
+/// `cstr` must point to a nul-terminated string that outlives the returned
+/// reference. In practice, that means for the duration of the FFI call.
+pub unsafe fn rust_str_from_c<'a>(cstr: *const c_char) -> &'a str {
+    opt_rust_str_from_c(cstr).expect("Null pointer passed to rust!")
+}
+
+/// Like `rust_str_from_c`, but returns `None` if `cstr` is null.
+///
+/// # Safety
+///
+/// Same as `rust_str_from_c`.
+pub unsafe fn opt_rust_str_from_c<'a>(cstr: *const c_char) -> Option<&'a str> {
+    if cstr.is_null() {
+        return None;
+    }
+    Some(CStr::from_ptr(cstr).to_str().expect("Invalid UTF-8 was passed to rust!"))
+}
+
+/// Like `rust_str_from_c`, but copies the string into a `String`.
+///
+/// # Safety
+///
+/// Same as `rust_str_from_c`, except that the result may outlive `cstr`.
+pub unsafe fn rust_string_from_c(cstr: *const c_char) -> String {
+    rust_str_from_c(cstr).to_owned()
+}
+
+/// Define an `extern "C"` function that frees strings we've returned over the
+/// FFI (including `ExternError` messages). Every component's FFI crate should
+/// use this exactly once.
+///
+/// ```rust,ignore
+/// define_string_destructor!(mylib_destroy_string);
+/// ```
+#[macro_export]
+macro_rules! define_string_destructor {
+    ($mylib_destroy_string:ident) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $mylib_destroy_string(s: *mut ::std::os::raw::c_char) {
+            if !s.is_null() {
+                $crate::destroy_c_string(s)
+            }
+        }
+    };
+}
+
+/// A nul-terminated string that lives for the whole program, which can be
+/// returned over the FFI without allocating. This is meant for fixed strings,
+/// like version numbers or schemas. Create these with `static_cstr!`.
+///
+/// Unlike the strings returned for a `String`, these are owned by Rust and
+/// must **not** be freed by the caller: passing one to the string destructor
+/// is undefined behavior. Functions that return one should say so in their
+/// documentation. The pointer is valid for as long as the library is loaded,
+/// so callers may keep it around without copying it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StaticCStr(&'static str);
+
+impl StaticCStr {
+    /// Wraps a string that already has a trailing nul. Usually you want
+    /// `static_cstr!`, which adds it for you.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `s` doesn't end with a nul byte, or contains any others.
+    pub fn from_nul_terminated(s: &'static str) -> StaticCStr {
+        assert!(s.ends_with('\0') && !s[..s.len() - 1].contains('\0'),
+                "Static C strings must have exactly one nul byte, at the end");
+        StaticCStr(s)
+    }
+
+    /// The string, without the trailing nul.
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        &self.0[..self.0.len() - 1]
+    }
+
+    #[inline]
+    pub fn as_ptr(&self) -> *const c_char {
+        self.0.as_ptr() as *const c_char
+    }
+}
+
+/// Create a `StaticCStr` from a string literal, or a macro that expands to
+/// one, like `env!` or `include_str!`.
+///
+/// ```rust,ignore
+/// #[no_mangle]
+/// pub extern "C" fn mylib_version(error: &mut ExternError) -> *const c_char {
+///     // Don't free the result!
+///     call_with_result(error, || -> Result<_, ExternError> {
+///         Ok(static_cstr!(env!("CARGO_PKG_VERSION")))
+///     })
+/// }
+/// ```
+#[macro_export]
+macro_rules! static_cstr {
+    ($s:expr) => {
+        $crate::StaticCStr::from_nul_terminated(concat!($s, "\0"))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_string_roundtrip() {
+        let c = rust_string_to_c("hello");
+        unsafe {
+            assert_eq!(rust_str_from_c(c), "hello");
+            assert_eq!(rust_string_from_c(c), "hello".to_string());
+            destroy_c_string(c);
+            assert_eq!(opt_rust_str_from_c(::std::ptr::null()), None);
+        }
+        assert!(opt_rust_string_to_c(None::<String>).is_null());
+    }
+
+    #[test]
+    fn test_static_cstr() {
+        let s = static_cstr!("1.2.3");
+        assert_eq!(s.as_str(), "1.2.3");
+        assert_eq!(unsafe { rust_str_from_c(s.as_ptr()) }, "1.2.3");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_static_cstr_interior_nul() {
+        static_cstr!("a\0b");
+    }
+}
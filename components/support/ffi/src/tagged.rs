@@ -0,0 +1,168 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Returning "one of several outcomes" over the FFI, for functions that can
+//! succeed in more than one way (for example, a sync that either ran or was
+//! skipped because of a rate limit). These aren't errors, so reporting them
+//! through `ExternError` would be wrong, and a bare integer can't carry any
+//! details about the outcome.
+//!
+//! Instead, the function returns an `FfiTagged` by value: a tag saying which
+//! outcome it was, and an optional payload with the details, as a JSON
+//! string. A Rust enum can be returned from a `call_with_result` callback
+//! once it implements `IntoFfiTagged` and `implement_into_ffi_by_tag!` has
+//! been used on it. Each component declares a destructor for the values it
+//! returns with `define_tagged_destructor!`.
+
+use std::os::raw::c_char;
+use std::ptr;
+
+use string::{destroy_c_string, opt_rust_string_to_c};
+
+/// A tag, and a payload which is either null or a JSON string, passed over
+/// the FFI by value.
+///
+/// Tags are chosen by the type being returned, and must not be negative.
+/// When an error occurred, the tag is `FfiTagged::ERROR_TAG` and the payload
+/// is null, although callers should check the `ExternError` first anyway.
+///
+/// In C, this is a struct with an `int32_t tag` and a `char *payload`, in
+/// that order.
+#[repr(C)]
+#[derive(Debug)]
+pub struct FfiTagged {
+    pub tag: i32,
+    pub payload: *mut c_char,
+}
+
+impl FfiTagged {
+    /// The tag of the value returned when an error occurred.
+    pub const ERROR_TAG: i32 = -1;
+
+    /// Create a value with the given tag and payload. The payload should be
+    /// JSON, since that's what the other side of the FFI will expect.
+    pub fn new(tag: i32, payload: Option<String>) -> Self {
+        debug_assert!(tag >= 0, "Negative tags are reserved");
+        FfiTagged {
+            tag,
+            payload: opt_rust_string_to_c(payload),
+        }
+    }
+
+    /// Create a value with the given tag, and no payload.
+    #[inline]
+    pub fn from_tag(tag: i32) -> Self {
+        FfiTagged::new(tag, None)
+    }
+
+    /// Frees the payload. This is what the functions defined by
+    /// `define_tagged_destructor!` call.
+    ///
+    /// # Safety
+    ///
+    /// The value must have been created by Rust, and its payload not already
+    /// freed.
+    pub unsafe fn destroy(self) {
+        if !self.payload.is_null() {
+            destroy_c_string(self.payload)
+        }
+    }
+}
+
+impl Default for FfiTagged {
+    #[inline]
+    fn default() -> Self {
+        FfiTagged {
+            tag: FfiTagged::ERROR_TAG,
+            payload: ptr::null_mut(),
+        }
+    }
+}
+
+/// Types (usually enums) that can be returned over the FFI as an
+/// `FfiTagged`. Implementing this doesn't implement `IntoFfi`, since a
+/// blanket impl would overlap with the others; use
+/// `implement_into_ffi_by_tag!` for that.
+pub trait IntoFfiTagged {
+    /// Returns the tag for this value, and its payload, if it has one.
+    fn into_ffi_tagged(self) -> FfiTagged;
+}
+
+/// Implements `IntoFfi` for a type that implements `IntoFfiTagged`, so that
+/// it can be returned from a `call_with_result` callback. For example,
+/// `implement_into_ffi_by_tag!(SyncOutcome);`.
+#[macro_export]
+macro_rules! implement_into_ffi_by_tag {
+    ($T:ty) => {
+        unsafe impl $crate::IntoFfi for $T {
+            type Value = $crate::FfiTagged;
+            #[inline]
+            fn ffi_default() -> Self::Value {
+                Default::default()
+            }
+            #[inline]
+            fn into_ffi_value(self) -> Self::Value {
+                $crate::IntoFfiTagged::into_ffi_tagged(self)
+            }
+        }
+    };
+}
+
+/// Define a destructor for the `FfiTagged` values a component returns, for
+/// the other side of the FFI to call. For example,
+/// `define_tagged_destructor!(mylib_destroy_tagged);`. It's safe to call with
+/// a value that has no payload.
+#[macro_export]
+macro_rules! define_tagged_destructor {
+    ($mylib_destroy_tagged:ident) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $mylib_destroy_tagged(value: $crate::FfiTagged) {
+            value.destroy()
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use into_ffi::IntoFfi;
+    use std::ffi::CStr;
+
+    enum Outcome {
+        Done,
+        Postponed { seconds: u32 },
+    }
+
+    impl IntoFfiTagged for Outcome {
+        fn into_ffi_tagged(self) -> FfiTagged {
+            match self {
+                Outcome::Done => FfiTagged::from_tag(0),
+                Outcome::Postponed { seconds } => {
+                    FfiTagged::new(1, Some(format!("{{\"seconds\":{}}}", seconds)))
+                }
+            }
+        }
+    }
+
+    implement_into_ffi_by_tag!(Outcome);
+
+    #[test]
+    fn test_into_ffi() {
+        let done = Outcome::Done.into_ffi_value();
+        assert_eq!(done.tag, 0);
+        assert!(done.payload.is_null());
+        unsafe { done.destroy() };
+
+        let postponed = Outcome::Postponed { seconds: 30 }.into_ffi_value();
+        assert_eq!(postponed.tag, 1);
+        unsafe {
+            assert_eq!(CStr::from_ptr(postponed.payload).to_str().unwrap(), "{\"seconds\":30}");
+            postponed.destroy();
+        }
+
+        let default = <Outcome as IntoFfi>::ffi_default();
+        assert_eq!(default.tag, FfiTagged::ERROR_TAG);
+        assert!(default.payload.is_null());
+    }
+}
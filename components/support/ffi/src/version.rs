@@ -0,0 +1,153 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Recording which components (and which versions of them) an app embeds,
+//! for its diagnostics screens and bug reports.
+//!
+//! Each component's FFI crate registers itself from its initialization
+//! function, with `register_component_version!`:
+//!
+//! ```rust,ignore
+//! #[no_mangle]
+//! pub extern "C" fn mylib_state_new(error: &mut ExternError) -> u64 {
+//!     register_component_version!();
+//!     // ...
+//! }
+//! ```
+//!
+//! and defines a function that returns them as a JSON array, with
+//! `define_component_versions_query!`:
+//!
+//! ```rust,ignore
+//! define_component_versions_query!(mylib_component_versions);
+//! ```
+//!
+//! Each library that embeds this crate has its own registry, so an app that
+//! loads several (as on Android, where each component is its own `.so`) calls
+//! the query of each, and gets back the components built into that library.
+//!
+//! The name and version come from the FFI crate's `Cargo.toml`. The git
+//! commit is whatever the `APPSERVICES_GIT_HASH` environment variable was
+//! set to when the crate was built, if anything; release builds should set
+//! it.
+
+use std::sync::Mutex;
+
+use serde_json;
+
+use lock_ignoring_poison;
+
+/// The name, version, and (if known) git commit of a component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ComponentVersion {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_hash: Option<&'static str>,
+}
+
+lazy_static! {
+    static ref COMPONENT_VERSIONS: Mutex<Vec<ComponentVersion>> = Mutex::new(Vec::new());
+}
+
+/// Records `component`. Usually you want `register_component_version!`,
+/// which fills it in for the calling crate. Registering a component again
+/// replaces the previous entry with the same name, so this is fine to call
+/// every time a component is initialized.
+pub fn register_component(component: ComponentVersion) {
+    let mut versions = lock_ignoring_poison(&COMPONENT_VERSIONS);
+    if let Some(existing) = versions.iter_mut().find(|v| v.name == component.name) {
+        *existing = component;
+        return;
+    }
+    debug!("Registered component {} {}", component.name, component.version);
+    versions.push(component);
+}
+
+/// Returns the components that have been registered since the library was
+/// loaded, in the order they were first registered.
+pub fn component_versions() -> Vec<ComponentVersion> {
+    lock_ignoring_poison(&COMPONENT_VERSIONS).clone()
+}
+
+/// Like `component_versions`, but as a JSON array of
+/// `{"name": ..., "version": ..., "gitHash": ...}` objects, where `gitHash`
+/// is null if it isn't known.
+pub fn component_versions_json() -> String {
+    serde_json::to_string(&component_versions())
+        .expect("Serializing the component versions can't fail")
+}
+
+/// Define an `extern "C"` function that returns `component_versions_json()`,
+/// which must be freed with the library's string destructor (see
+/// `define_string_destructor!`). The calling crate is registered first, so
+/// it's always included. See the module docs for an example.
+#[macro_export]
+macro_rules! define_component_versions_query {
+    ($mylib_component_versions:ident) => {
+        #[no_mangle]
+        pub extern "C" fn $mylib_component_versions() -> *mut ::std::os::raw::c_char {
+            $crate::register_component($crate::ComponentVersion {
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+                git_hash: option_env!("APPSERVICES_GIT_HASH"),
+            });
+            $crate::rust_string_to_c($crate::component_versions_json())
+        }
+    };
+}
+
+/// Registers the calling crate's name and version (and git commit, if
+/// `APPSERVICES_GIT_HASH` was set when it was built). See the module docs.
+#[macro_export]
+macro_rules! register_component_version {
+    () => {
+        $crate::register_component($crate::ComponentVersion {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("APPSERVICES_GIT_HASH"),
+        })
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use string::{destroy_c_string, rust_str_from_c};
+
+    #[test]
+    fn test_component_versions() {
+        let test_component = ComponentVersion {
+            name: "ffi-support-test",
+            version: "1.2.3",
+            git_hash: Some("abc\"123"),
+        };
+        register_component(test_component);
+        register_component_version!();
+        register_component_version!();
+
+        // Other tests may register components too, but not these.
+        let versions = component_versions();
+        assert!(versions.contains(&test_component));
+        assert_eq!(versions.iter().filter(|v| v.name == "ffi-support").count(), 1);
+
+        register_component(ComponentVersion { git_hash: None, ..test_component });
+        let json = component_versions_json();
+        assert!(json.starts_with('[') && json.ends_with(']'));
+        assert!(json.contains(
+            "{\"name\":\"ffi-support-test\",\"version\":\"1.2.3\",\"gitHash\":null}"));
+    }
+
+    define_component_versions_query!(ffi_support_test_component_versions);
+
+    #[test]
+    fn test_component_versions_query() {
+        let json = ffi_support_test_component_versions();
+        unsafe {
+            assert!(rust_str_from_c(json).contains(
+                &format!("{{\"name\":\"ffi-support\",\"version\":\"{}\"", env!("CARGO_PKG_VERSION"))));
+            destroy_c_string(json);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Guid construction, cloning and hashing benchmarks, for the different
+//! kinds of ids sync sees.
+//!
+//! Run with `cargo bench -p sync-guid`. Ids that are stored inline (12-char
+//! places guids and short ASCII ids like `"menu"`) should be much cheaper to
+//! create and clone than the long ones, which need a heap allocation. To
+//! compare with `String`, see the `string` variant of each benchmark.
+
+#[macro_use]
+extern crate criterion;
+extern crate sync_guid;
+
+use criterion::{black_box, Criterion, Fun};
+use std::collections::HashMap;
+use sync_guid::Guid;
+
+const IDS: &[(&str, &str)] = &[
+    ("places", "aaaabbbbcccc"),
+    ("short", "menu"),
+    ("uuid", "{5e8ea4a4-6d38-4a1e-a0bd-7b0eb7e1b8e8}"),
+];
+
+fn bench_new(c: &mut Criterion) {
+    for &(name, id) in IDS {
+        let guid = Fun::new("guid", |b, id: &&str| b.iter(|| Guid::new(black_box(id))));
+        let string = Fun::new("string", |b, id: &&str| b.iter(|| black_box(id).to_owned()));
+        c.bench_functions(&format!("new/{}", name), vec![guid, string], id);
+    }
+}
+
+fn bench_clone(c: &mut Criterion) {
+    for &(name, id) in IDS {
+        let guid = Fun::new("guid", |b, id: &&str| {
+            let guid = Guid::new(id);
+            b.iter(|| black_box(&guid).clone())
+        });
+        let string = Fun::new("string", |b, id: &&str| {
+            let s = id.to_string();
+            b.iter(|| black_box(&s).clone())
+        });
+        c.bench_functions(&format!("clone/{}", name), vec![guid, string], id);
+    }
+}
+
+// Reconciliation looks records up in maps keyed by guid, so this measures a
+// lookup in a map of 1000 ids of the same kind.
+fn bench_lookup(c: &mut Criterion) {
+    for &(name, id) in IDS {
+        let ids = (0..1000).map(|i| format!("{}{:03}", &id[..id.len() - 3], i)).collect::<Vec<_>>();
+        let guid = Fun::new("guid", {
+            let ids = ids.clone();
+            move |b, _: &&str| {
+                let map = ids.iter().map(|id| (Guid::new(id), ())).collect::<HashMap<_, _>>();
+                let key = Guid::new(&ids[500]);
+                b.iter(|| map.contains_key(black_box(&key)))
+            }
+        });
+        let string = Fun::new("string", move |b, _: &&str| {
+            let map = ids.iter().map(|id| (id.clone(), ())).collect::<HashMap<_, _>>();
+            let key = ids[500].clone();
+            b.iter(|| map.contains_key(black_box(&key)))
+        });
+        c.bench_functions(&format!("lookup/{}", name), vec![guid, string], id);
+    }
+}
+
+criterion_group!(benches, bench_new, bench_clone, bench_lookup);
+criterion_main!(benches);
@@ -0,0 +1,425 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An archive of guids that can be read in place, without copying them out,
+//! for snapshots and large on-disk indexes.
+//!
+//! The archive is laid out like the `Guid` reprs, so reading a guid back
+//! gives it the same repr it was written with:
+//!
+//! - A 4 byte magic number, and the number of guids, as a little-endian
+//!   `u32`.
+//! - A 24 byte entry for each guid: a tag for the repr, the length, and then
+//!   `MAX_INLINE_GUID_LEN` bytes. Fast and short guids are stored in the
+//!   entry itself, padded with zeros. For slow guids, the entry holds the
+//!   offset and length of the guid in the string table, as little-endian
+//!   `u32`s.
+//! - The string table: the UTF-8 bytes of every slow guid, one after
+//!   another.
+//!
+//! Everything is stored as bytes, so archives don't need to be aligned, and
+//! `GuidArchive::new` checks every entry up front. Reading a guid after that
+//! is just slicing the buffer.
+
+use std::{fmt, str};
+
+use {check_sync_server_guid, is_base64url_byte, Guid, GuidError, GuidRef, Repr, FAST_GUID_LEN,
+     MAX_INLINE_GUID_LEN};
+
+const MAGIC: [u8; 4] = *b"SGA1";
+const HEADER_LEN: usize = MAGIC.len() + 4;
+const ENTRY_LEN: usize = 2 + MAX_INLINE_GUID_LEN;
+
+const TAG_FAST: u8 = 0;
+const TAG_SHORT: u8 = 1;
+const TAG_SLOW: u8 = 2;
+
+/// Why `GuidArchive::new` rejected an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArchiveError {
+    /// The archive doesn't start with the magic number, so it's either not
+    /// an archive, or one in a format we don't know.
+    BadMagic,
+    /// The archive is shorter than its header says it should be.
+    Truncated,
+    /// The entry at this index is corrupt.
+    InvalidEntry(usize),
+    /// With the `strict` feature, the guid at this index isn't one the sync
+    /// server would accept.
+    InvalidGuid(usize, GuidError),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::BadMagic => write!(f, "Not a guid archive"),
+            ArchiveError::Truncated => write!(f, "Guid archive is truncated"),
+            ArchiveError::InvalidEntry(index) => write!(f, "Guid archive entry {} is corrupt", index),
+            ArchiveError::InvalidGuid(index, e) => write!(f, "Guid archive entry {} is invalid: {}", index, e),
+        }
+    }
+}
+
+impl ::std::error::Error for ArchiveError {}
+
+/// Writes `guids` to a new archive, which can be read with `GuidArchive`.
+///
+/// Panics if the guids don't fit in an archive, which needs more than 4GB
+/// of slow guids (or more than `u32::MAX` guids).
+pub fn archive_guids<'g, I>(guids: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'g Guid>,
+{
+    let mut entries = Vec::new();
+    let mut strings = Vec::new();
+    let mut count = 0usize;
+    for guid in guids {
+        let mut entry = [0u8; ENTRY_LEN];
+        match &guid.0 {
+            Repr::Fast(rep) => {
+                entry[0] = TAG_FAST;
+                entry[1] = FAST_GUID_LEN as u8;
+                entry[2..2 + FAST_GUID_LEN].copy_from_slice(&rep[..]);
+            }
+            Repr::Short(len, rep) => {
+                entry[0] = TAG_SHORT;
+                entry[1] = *len;
+                entry[2..].copy_from_slice(&rep[..]);
+            }
+            Repr::Slow(rep) => {
+                entry[0] = TAG_SLOW;
+                entry[2..6].copy_from_slice(&to_u32_bytes(strings.len()));
+                entry[6..10].copy_from_slice(&to_u32_bytes(rep.len()));
+                strings.extend_from_slice(rep.as_bytes());
+            }
+        }
+        entries.extend_from_slice(&entry);
+        count += 1;
+    }
+    let mut archive = Vec::with_capacity(HEADER_LEN + entries.len() + strings.len());
+    archive.extend_from_slice(&MAGIC);
+    archive.extend_from_slice(&to_u32_bytes(count));
+    archive.extend_from_slice(&entries);
+    archive.extend_from_slice(&strings);
+    archive
+}
+
+/// A view of an archive written by `archive_guids`, which borrows the guids
+/// from the archive instead of copying them.
+#[derive(Clone, Copy)]
+pub struct GuidArchive<'a> {
+    entries: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> GuidArchive<'a> {
+    /// Checks that `bytes` is a valid archive, and returns a view of it.
+    /// With the `strict` feature, this also rejects archives with guids the
+    /// sync server wouldn't accept.
+    pub fn new(bytes: &'a [u8]) -> Result<GuidArchive<'a>, ArchiveError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(if bytes.starts_with(&MAGIC[..bytes.len().min(MAGIC.len())]) {
+                ArchiveError::Truncated
+            } else {
+                ArchiveError::BadMagic
+            });
+        }
+        if bytes[..MAGIC.len()] != MAGIC {
+            return Err(ArchiveError::BadMagic);
+        }
+        let count = from_u32_bytes(&bytes[MAGIC.len()..HEADER_LEN]);
+        let entries_len = match count.checked_mul(ENTRY_LEN) {
+            Some(len) if len <= bytes.len() - HEADER_LEN => len,
+            _ => return Err(ArchiveError::Truncated),
+        };
+        let archive = GuidArchive {
+            entries: &bytes[HEADER_LEN..HEADER_LEN + entries_len],
+            strings: &bytes[HEADER_LEN + entries_len..],
+        };
+        for index in 0..count {
+            let s = archive.check_entry(index).ok_or(ArchiveError::InvalidEntry(index))?;
+            if cfg!(feature = "strict") {
+                check_sync_server_guid(s).map_err(|e| ArchiveError::InvalidGuid(index, e))?;
+            }
+        }
+        Ok(archive)
+    }
+
+    /// The number of guids in the archive.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_LEN
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the guid at `index`, borrowed from the archive.
+    pub fn get(&self, index: usize) -> Option<GuidRef<'a>> {
+        if index >= self.len() {
+            return None;
+        }
+        let entry = self.entry(index);
+        let bytes = match entry[0] {
+            TAG_SLOW => self.slow_bytes(entry)?,
+            _ => &entry[2..2 + entry[1] as usize],
+        };
+        // We checked all the entries in `new`.
+        Some(GuidRef::new(unsafe { str::from_utf8_unchecked(bytes) }))
+    }
+
+    /// Returns the guid at `index` as a `Guid`, with the same repr it had
+    /// when it was archived. Fast and short guids are copied straight out of
+    /// their entries, so this only allocates for slow guids.
+    pub fn get_guid(&self, index: usize) -> Option<Guid> {
+        if index >= self.len() {
+            return None;
+        }
+        let entry = self.entry(index);
+        Some(match entry[0] {
+            TAG_FAST => {
+                let mut rep = [0u8; FAST_GUID_LEN];
+                rep.copy_from_slice(&entry[2..2 + FAST_GUID_LEN]);
+                Guid(Repr::Fast(rep))
+            }
+            TAG_SHORT => {
+                let mut rep = [0u8; MAX_INLINE_GUID_LEN];
+                rep.copy_from_slice(&entry[2..]);
+                Guid(Repr::Short(entry[1], rep))
+            }
+            _ => self.get(index)?.to_guid(),
+        })
+    }
+
+    /// Returns an iterator over the guids in the archive, borrowed from it.
+    #[inline]
+    pub fn iter(&self) -> Iter<'a> {
+        Iter {
+            archive: *self,
+            index: 0,
+        }
+    }
+
+    #[inline]
+    fn entry(&self, index: usize) -> &'a [u8] {
+        &self.entries[index * ENTRY_LEN..(index + 1) * ENTRY_LEN]
+    }
+
+    fn slow_bytes(&self, entry: &[u8]) -> Option<&'a [u8]> {
+        let offset = from_u32_bytes(&entry[2..6]);
+        let len = from_u32_bytes(&entry[6..10]);
+        let end = offset.checked_add(len)?;
+        self.strings.get(offset..end)
+    }
+
+    // Returns the guid for the entry at `index`, if the entry is valid. An
+    // entry is only valid if its tag is the repr `Guid::new` would choose
+    // for the guid, so that reading it back doesn't change how it's stored,
+    // and the padding is all zeros, so that archives of the same guids are
+    // always byte-for-byte the same.
+    fn check_entry(&self, index: usize) -> Option<&'a str> {
+        let entry = self.entry(index);
+        let len = entry[1] as usize;
+        match entry[0] {
+            TAG_FAST => {
+                let bytes = &entry[2..2 + FAST_GUID_LEN];
+                if len != FAST_GUID_LEN
+                    || !bytes.iter().all(|&b| is_base64url_byte(b))
+                    || entry[2 + FAST_GUID_LEN..].iter().any(|&b| b != 0)
+                {
+                    return None;
+                }
+                str::from_utf8(bytes).ok()
+            }
+            TAG_SHORT => {
+                if len > MAX_INLINE_GUID_LEN || entry[2 + len..].iter().any(|&b| b != 0) {
+                    return None;
+                }
+                let bytes = &entry[2..2 + len];
+                let should_be_fast = len == FAST_GUID_LEN && bytes.iter().all(|&b| is_base64url_byte(b));
+                if !bytes.is_ascii() || should_be_fast {
+                    return None;
+                }
+                str::from_utf8(bytes).ok()
+            }
+            TAG_SLOW => {
+                if len != 0 || entry[10..].iter().any(|&b| b != 0) {
+                    return None;
+                }
+                let s = str::from_utf8(self.slow_bytes(entry)?).ok()?;
+                if s.len() <= MAX_INLINE_GUID_LEN && s.is_ascii() {
+                    return None;
+                }
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'a> fmt::Debug for GuidArchive<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a> IntoIterator for GuidArchive<'a> {
+    type Item = GuidRef<'a>;
+    type IntoIter = Iter<'a>;
+
+    #[inline]
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over the guids in a `GuidArchive`.
+#[derive(Clone, Debug)]
+pub struct Iter<'a> {
+    archive: GuidArchive<'a>,
+    index: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = GuidRef<'a>;
+
+    fn next(&mut self) -> Option<GuidRef<'a>> {
+        let guid = self.archive.get(self.index)?;
+        self.index += 1;
+        Some(guid)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.archive.len() - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+#[inline]
+fn to_u32_bytes(n: usize) -> [u8; 4] {
+    assert!(n <= u32::max_value() as usize, "Too many guids to archive");
+    let n = n as u32;
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+#[inline]
+fn from_u32_bytes(b: &[u8]) -> usize {
+    (u32::from(b[0]) | (u32::from(b[1]) << 8) | (u32::from(b[2]) << 16) | (u32::from(b[3]) << 24))
+        as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn guids() -> Vec<Guid> {
+        vec![
+            Guid::new("aaaabbbbcccc"),
+            Guid::new("menu"),
+            Guid::new("{5e8ea4a4-6d38-4a1e-a0bd-7b0eb7e1b8e8}"),
+            Guid::new("toolbar_____"),
+            Guid::new("x".repeat(22).as_str()),
+            Guid::new("x".repeat(23).as_str()),
+        ]
+    }
+
+    fn repr_tag(guid: &Guid) -> u8 {
+        match guid.0 {
+            Repr::Fast(_) => TAG_FAST,
+            Repr::Short(..) => TAG_SHORT,
+            Repr::Slow(_) => TAG_SLOW,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let guids = guids();
+        let bytes = archive_guids(&guids);
+        assert_eq!(bytes.len(), HEADER_LEN + guids.len() * ENTRY_LEN + 38 + 23);
+
+        let archive = GuidArchive::new(&bytes).unwrap();
+        assert_eq!(archive.len(), guids.len());
+        for (index, guid) in guids.iter().enumerate() {
+            assert_eq!(archive.get(index).unwrap(), *guid);
+            let read = archive.get_guid(index).unwrap();
+            assert_eq!(read, *guid);
+            assert_eq!(repr_tag(&read), repr_tag(guid), "{:?}", guid);
+        }
+        assert_eq!(archive.get(guids.len()), None);
+        assert_eq!(archive.get_guid(guids.len()), None);
+        assert_eq!(archive.iter().len(), guids.len());
+        assert!(archive.iter().eq(guids.iter().map(Guid::as_guid_ref)));
+
+        // The archive borrows slow guids from the buffer, too.
+        let slow = archive.get(2).unwrap().as_str();
+        let start = bytes.as_ptr() as usize;
+        assert!(slow.as_ptr() as usize >= start && (slow.as_ptr() as usize) < start + bytes.len());
+
+        // Archiving the same guids again gives the same bytes.
+        assert_eq!(archive_guids(&archive.iter().map(|g| g.to_guid()).collect::<Vec<_>>()), bytes);
+
+        let empty = archive_guids(&[]);
+        let archive = GuidArchive::new(&empty).unwrap();
+        assert!(archive.is_empty());
+        assert_eq!(archive.iter().next(), None);
+    }
+
+    #[test]
+    fn test_unaligned() {
+        let guids = guids();
+        let bytes = archive_guids(&guids);
+        // Archives can be read at any offset, like after a header in a
+        // larger file.
+        for offset in 0..8 {
+            let mut buf = vec![0xaau8; offset];
+            buf.extend_from_slice(&bytes);
+            let archive = GuidArchive::new(&buf[offset..]).unwrap();
+            assert!(archive.iter().eq(guids.iter().map(Guid::as_guid_ref)));
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let bytes = archive_guids(&guids());
+        assert_eq!(GuidArchive::new(b"").unwrap_err(), ArchiveError::Truncated);
+        assert_eq!(GuidArchive::new(b"SGA1\x01").unwrap_err(), ArchiveError::Truncated);
+        assert_eq!(GuidArchive::new(b"nope\0\0\0\0").unwrap_err(), ArchiveError::BadMagic);
+        assert_eq!(GuidArchive::new(&bytes[..HEADER_LEN + ENTRY_LEN]).unwrap_err(), ArchiveError::Truncated);
+        // The string table is cut short.
+        assert_eq!(GuidArchive::new(&bytes[..bytes.len() - 1]).unwrap_err(), ArchiveError::InvalidEntry(5));
+
+        let corrupt = |index: usize, byte: usize, value: u8| {
+            let mut bytes = bytes.clone();
+            bytes[HEADER_LEN + index * ENTRY_LEN + byte] = value;
+            GuidArchive::new(&bytes).unwrap_err()
+        };
+        // Bad tag.
+        assert_eq!(corrupt(0, 0, 3), ArchiveError::InvalidEntry(0));
+        // A fast guid that isn't base64url.
+        assert_eq!(corrupt(0, 2, b'!'), ArchiveError::InvalidEntry(0));
+        // A short guid that should be fast.
+        assert_eq!(corrupt(0, 0, TAG_SHORT), ArchiveError::InvalidEntry(0));
+        // A short guid with junk after it, or that's too long.
+        assert_eq!(corrupt(1, 10, b'x'), ArchiveError::InvalidEntry(1));
+        assert_eq!(corrupt(1, 1, 23), ArchiveError::InvalidEntry(1));
+        // A slow guid that should be short.
+        assert_eq!(corrupt(2, 6, 4), ArchiveError::InvalidEntry(2));
+    }
+
+    #[test]
+    fn test_strict() {
+        let bytes = archive_guids(&[Guid::new("menu"), Guid::new("a,b")]);
+        let result = GuidArchive::new(&bytes);
+        if cfg!(feature = "strict") {
+            assert_eq!(result.unwrap_err(), ArchiveError::InvalidGuid(1, GuidError::InvalidChar(',')));
+        } else {
+            assert_eq!(result.unwrap().get(1).unwrap(), "a,b");
+        }
+    }
+}
@@ -0,0 +1,38 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::{error, fmt};
+
+/// Why an id was rejected by one of `Guid`'s strict constructors (like
+/// `new_checked`, `try_from_slice`, and the `FromStr` impl). These are the
+/// rules `Guid::is_valid_for_sync_server` checks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuidError {
+    /// The id is empty.
+    Empty,
+    /// The id is longer than the sync server allows. Holds the length.
+    TooLong(usize),
+    /// The id contains a character that isn't printable ASCII, or a comma.
+    InvalidChar(char),
+    /// The id isn't valid UTF-8.
+    InvalidUtf8,
+}
+
+impl fmt::Display for GuidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GuidError::Empty => write!(f, "Guid is empty"),
+            GuidError::TooLong(len) => write!(f, "Guid is too long ({} bytes)", len),
+            GuidError::InvalidChar(c) => write!(f, "Guid contains an invalid character: {:?}", c),
+            GuidError::InvalidUtf8 => write!(f, "Guid isn't valid UTF-8"),
+        }
+    }
+}
+
+impl error::Error for GuidError {}
+
+/// The error from parsing a `Guid` with `str::parse` (or `FromStr`), which is
+/// the same as the one from `try_from_slice`. `InvalidUtf8` never happens for
+/// a `str`.
+pub type GuidParseError = GuidError;
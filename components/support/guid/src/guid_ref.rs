@@ -0,0 +1,221 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::{fmt, ops};
+
+use {
+    check_sync_server_guid, is_valid_places_guid, is_valid_sync_server_guid, Guid, GuidError,
+    BUILT_IN_ROOTS,
+};
+
+/// A borrowed guid: a `&str` with the same validation and comparison API as
+/// `Guid`. It's `Copy`, and making one never allocates, so functions that
+/// only look ids up can take an `impl Into<GuidRef<'a>>`, which accepts a
+/// `&Guid`, a `&str` or a `&String`, and only build a `Guid` (with
+/// `to_guid`) if they need to keep it.
+///
+/// Like `Guid::new`, making a `GuidRef` never fails; use `try_new` for ids
+/// that must be valid for the sync server.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GuidRef<'a>(&'a str);
+
+impl<'a> GuidRef<'a> {
+    #[inline]
+    pub fn new(s: &'a str) -> Self {
+        GuidRef(s)
+    }
+
+    /// Like `new`, but fails if `s` isn't an id the sync server would accept,
+    /// like `Guid`'s `FromStr` impl.
+    #[inline]
+    pub fn try_new(s: &'a str) -> Result<Self, GuidError> {
+        check_sync_server_guid(s)?;
+        Ok(GuidRef(s))
+    }
+
+    /// Returns the `&str` this borrows, which lives as long as the original,
+    /// unlike the one `Deref` returns.
+    #[inline]
+    pub fn as_str(&self) -> &'a str {
+        self.0
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0.as_bytes()
+    }
+
+    /// Makes an owned `Guid` with the same value.
+    #[inline]
+    pub fn to_guid(&self) -> Guid {
+        Guid::new(self.0)
+    }
+
+    /// See `Guid::is_valid_for_sync_server`.
+    #[inline]
+    pub fn is_valid_for_sync_server(&self) -> bool {
+        is_valid_sync_server_guid(self.as_bytes())
+    }
+
+    /// See `Guid::is_valid_for_places`.
+    #[inline]
+    pub fn is_valid_for_places(&self) -> bool {
+        is_valid_places_guid(self.as_bytes())
+    }
+
+    /// See `Guid::is_built_in_root`.
+    pub fn is_built_in_root(&self) -> bool {
+        BUILT_IN_ROOTS.iter().any(|root| root.as_str() == self.0)
+    }
+}
+
+impl Guid {
+    /// Borrows this guid as a `GuidRef`.
+    #[inline]
+    pub fn as_guid_ref(&self) -> GuidRef<'_> {
+        GuidRef(self.as_str())
+    }
+}
+
+impl<'a> From<&'a str> for GuidRef<'a> {
+    #[inline]
+    fn from(s: &'a str) -> Self {
+        GuidRef(s)
+    }
+}
+
+impl<'a> From<&'a String> for GuidRef<'a> {
+    #[inline]
+    fn from(s: &'a String) -> Self {
+        GuidRef(s.as_str())
+    }
+}
+
+impl<'a> From<&'a Guid> for GuidRef<'a> {
+    #[inline]
+    fn from(guid: &'a Guid) -> Self {
+        guid.as_guid_ref()
+    }
+}
+
+impl<'a> From<GuidRef<'a>> for Guid {
+    #[inline]
+    fn from(guid: GuidRef<'a>) -> Self {
+        guid.to_guid()
+    }
+}
+
+impl<'a> AsRef<str> for GuidRef<'a> {
+    #[inline]
+    fn as_ref(&self) -> &str {
+        self.0
+    }
+}
+
+impl<'a> AsRef<[u8]> for GuidRef<'a> {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<'a> ops::Deref for GuidRef<'a> {
+    type Target = str;
+    #[inline]
+    fn deref(&self) -> &str {
+        self.0
+    }
+}
+
+impl<'a> PartialEq<Guid> for GuidRef<'a> {
+    #[inline]
+    fn eq(&self, other: &Guid) -> bool {
+        self.0 == other.as_str()
+    }
+}
+
+impl<'a> PartialEq<GuidRef<'a>> for Guid {
+    #[inline]
+    fn eq(&self, other: &GuidRef<'a>) -> bool {
+        self.as_str() == other.0
+    }
+}
+
+impl<'a> PartialEq<str> for GuidRef<'a> {
+    #[inline]
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl<'a, 'b> PartialEq<&'b str> for GuidRef<'a> {
+    #[inline]
+    fn eq(&self, other: &&'b str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl<'a> fmt::Debug for GuidRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if cfg!(feature = "redact_debug") {
+            write!(f, "GuidRef({})", self.redacted())
+        } else {
+            write!(f, "GuidRef({:?})", self.0)
+        }
+    }
+}
+
+impl<'a> fmt::Display for GuidRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn lookup<'a, G: Into<GuidRef<'a>>>(map: &HashMap<Guid, u32>, guid: G) -> Option<u32> {
+        map.get(guid.into().as_str()).cloned()
+    }
+
+    #[test]
+    fn test_guid_ref() {
+        let guid = Guid::new("aaaabbbbcccc");
+        let guid_ref = guid.as_guid_ref();
+        assert_eq!(guid_ref, guid);
+        assert_eq!(guid, guid_ref);
+        assert_eq!(guid_ref, "aaaabbbbcccc");
+        assert_eq!(guid_ref.to_guid(), guid);
+        assert_eq!(Guid::from(GuidRef::new("menu")), "menu");
+        if !cfg!(feature = "redact_debug") {
+            assert_eq!(format!("{:?}", GuidRef::new("menu")), "GuidRef(\"menu\")");
+        }
+        assert_eq!(GuidRef::new("menu").to_string(), "menu");
+        assert!(GuidRef::new("a") < GuidRef::new("b"));
+
+        let mut map = HashMap::new();
+        map.insert(guid.clone(), 1);
+        assert_eq!(lookup(&map, &guid), Some(1));
+        assert_eq!(lookup(&map, "aaaabbbbcccc"), Some(1));
+        assert_eq!(lookup(&map, &"aaaabbbbcccc".to_string()), Some(1));
+        assert_eq!(lookup(&map, guid_ref), Some(1));
+        assert_eq!(lookup(&map, "menu"), None);
+    }
+
+    #[test]
+    fn test_validation() {
+        for id in &["aaaabbbbcccc", "menu", "", "a,b", "émile", "menu________"] {
+            let guid = Guid::new(id);
+            let guid_ref = GuidRef::new(id);
+            assert_eq!(guid_ref.is_valid_for_sync_server(), guid.is_valid_for_sync_server());
+            assert_eq!(guid_ref.is_valid_for_places(), guid.is_valid_for_places());
+            assert_eq!(guid_ref.is_built_in_root(), guid.is_built_in_root());
+            let parsed = id.parse::<Guid>().ok();
+            assert_eq!(GuidRef::try_new(id).ok(), parsed.as_ref().map(Guid::as_guid_ref));
+        }
+        assert_eq!(GuidRef::try_new(""), Err(GuidError::Empty));
+    }
+}
@@ -0,0 +1,322 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Sharing one copy of each guid in a large batch of records.
+//!
+//! Incoming bookmarks mention the same parent (and, for folders, the same
+//! children) over and over, and keeping a separate `Guid` for every mention
+//! adds up when a batch has thousands of records. A `GuidInterner` keeps one
+//! copy of each guid it sees, and hands out `InternedGuid`s, which are cheap
+//! to clone, and which compare equal without looking at the bytes if they
+//! came from the same interner.
+//!
+//! With the `serde_support` feature, `&mut GuidInterner` is also a
+//! `DeserializeSeed`, for interning guids as they're deserialized:
+//!
+//! ```rust,ignore
+//! let parent = (&mut interner).deserialize(&mut deserializer)?;
+//! ```
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops;
+use std::sync::Arc;
+
+use Guid;
+
+/// Deduplicates guids, handing out shared `InternedGuid`s. Dropping the
+/// interner doesn't invalidate the guids it handed out.
+#[derive(Default)]
+pub struct GuidInterner {
+    guids: HashSet<InternedGuid>,
+}
+
+impl GuidInterner {
+    #[inline]
+    pub fn new() -> Self {
+        GuidInterner::default()
+    }
+
+    #[inline]
+    pub fn with_capacity(capacity: usize) -> Self {
+        GuidInterner {
+            guids: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Returns the interned copy of `s`, adding it if we haven't seen it
+    /// before.
+    pub fn intern(&mut self, s: &str) -> InternedGuid {
+        if let Some(interned) = self.guids.get(s) {
+            return interned.clone();
+        }
+        self.insert(Guid::new(s))
+    }
+
+    /// Like `intern`, but takes an owned `Guid`, which is kept if we haven't
+    /// seen it before.
+    pub fn intern_guid(&mut self, guid: Guid) -> InternedGuid {
+        if let Some(interned) = self.guids.get(&guid) {
+            return interned.clone();
+        }
+        self.insert(guid)
+    }
+
+    fn insert(&mut self, guid: Guid) -> InternedGuid {
+        let interned = InternedGuid(Arc::new(guid));
+        self.guids.insert(interned.clone());
+        interned
+    }
+
+    /// The number of distinct guids interned.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.guids.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.guids.is_empty()
+    }
+
+    /// Forgets every guid interned so far. Guids handed out before this
+    /// still work, but won't share storage with guids handed out after it.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.guids.clear()
+    }
+}
+
+impl fmt::Debug for GuidInterner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GuidInterner")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// A guid handed out by a `GuidInterner`. Cloning one just bumps a
+/// reference count, and it derefs to the shared `Guid`. It hashes, compares
+/// and orders the same way that `Guid` does, so it can be looked up in a
+/// map by `&str` or `&Guid`.
+#[derive(Clone)]
+pub struct InternedGuid(Arc<Guid>);
+
+impl InternedGuid {
+    #[inline]
+    pub fn as_guid(&self) -> &Guid {
+        &self.0
+    }
+
+    /// Makes an owned `Guid` with the same value.
+    #[inline]
+    pub fn to_guid(&self) -> Guid {
+        (*self.0).clone()
+    }
+
+    /// Returns true if `a` and `b` are the same interned copy. Guids from
+    /// different interners (or from before a `clear`) can be equal without
+    /// being the same copy.
+    #[inline]
+    pub fn ptr_eq(a: &InternedGuid, b: &InternedGuid) -> bool {
+        Arc::ptr_eq(&a.0, &b.0)
+    }
+}
+
+impl ops::Deref for InternedGuid {
+    type Target = Guid;
+    #[inline]
+    fn deref(&self) -> &Guid {
+        &self.0
+    }
+}
+
+impl AsRef<Guid> for InternedGuid {
+    #[inline]
+    fn as_ref(&self) -> &Guid {
+        &self.0
+    }
+}
+
+impl AsRef<str> for InternedGuid {
+    #[inline]
+    fn as_ref(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl Borrow<Guid> for InternedGuid {
+    #[inline]
+    fn borrow(&self) -> &Guid {
+        &self.0
+    }
+}
+
+impl Borrow<str> for InternedGuid {
+    #[inline]
+    fn borrow(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl From<InternedGuid> for Guid {
+    #[inline]
+    fn from(guid: InternedGuid) -> Guid {
+        Arc::try_unwrap(guid.0).unwrap_or_else(|shared| (*shared).clone())
+    }
+}
+
+impl PartialEq for InternedGuid {
+    #[inline]
+    fn eq(&self, other: &InternedGuid) -> bool {
+        InternedGuid::ptr_eq(self, other) || self.0 == other.0
+    }
+}
+
+impl Eq for InternedGuid {}
+
+impl PartialEq<Guid> for InternedGuid {
+    #[inline]
+    fn eq(&self, other: &Guid) -> bool {
+        *self.0 == *other
+    }
+}
+
+impl PartialEq<InternedGuid> for Guid {
+    #[inline]
+    fn eq(&self, other: &InternedGuid) -> bool {
+        *self == *other.0
+    }
+}
+
+impl PartialEq<str> for InternedGuid {
+    #[inline]
+    fn eq(&self, other: &str) -> bool {
+        self.0.as_str() == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for InternedGuid {
+    #[inline]
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0.as_str() == *other
+    }
+}
+
+impl PartialOrd for InternedGuid {
+    #[inline]
+    fn partial_cmp(&self, other: &InternedGuid) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InternedGuid {
+    #[inline]
+    fn cmp(&self, other: &InternedGuid) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+// Must match `Guid`'s (and so `str`'s), for the `Borrow` impls.
+impl Hash for InternedGuid {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl fmt::Debug for InternedGuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for InternedGuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+#[cfg(feature = "serde_support")]
+mod serde_impls {
+    use serde::de::{Deserialize, DeserializeSeed, Deserializer};
+    use serde::ser::{Serialize, Serializer};
+
+    use super::{GuidInterner, InternedGuid};
+    use Guid;
+
+    impl<'de, 'a> DeserializeSeed<'de> for &'a mut GuidInterner {
+        type Value = InternedGuid;
+        fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<InternedGuid, D::Error> {
+            // Guids that can be stored inline don't allocate, so it's fine to
+            // make one before checking whether we've seen it.
+            Guid::deserialize(deserializer).map(|guid| self.intern_guid(guid))
+        }
+    }
+
+    impl Serialize for InternedGuid {
+        #[inline]
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_intern() {
+        let mut interner = GuidInterner::new();
+        let long = "a".repeat(100);
+        let a = interner.intern(&long);
+        let b = interner.intern_guid(Guid::new(&long));
+        let menu = interner.intern("menu________");
+        assert_eq!(interner.len(), 2);
+        assert!(InternedGuid::ptr_eq(&a, &b));
+        assert_eq!(a, b);
+        assert_ne!(a, menu);
+        assert_eq!(menu, Guid::MENU);
+        assert_eq!(Guid::MENU, menu);
+        assert_eq!(menu, "menu________");
+        assert!(menu.is_built_in_root());
+        assert_eq!(format!("{:?}", menu), format!("{:?}", Guid::MENU));
+
+        // Guids from different interners are equal, but not shared.
+        let other = GuidInterner::new().intern(&long);
+        assert_eq!(a, other);
+        assert!(!InternedGuid::ptr_eq(&a, &other));
+
+        let mut map = HashMap::new();
+        map.insert(menu.clone(), 1);
+        assert_eq!(map.get("menu________"), Some(&1));
+        assert_eq!(map.get(&Guid::MENU), Some(&1));
+
+        drop(interner);
+        assert_eq!(Guid::from(a), Guid::new(&long));
+        assert_eq!(Guid::from(menu), Guid::MENU);
+    }
+
+    #[cfg(feature = "serde_support")]
+    #[test]
+    fn test_deserialize_seed() {
+        use serde::de::DeserializeSeed;
+        use serde_json;
+
+        let mut interner = GuidInterner::new();
+        let mut parents = Vec::new();
+        for json in &[r#""toolbar_____""#, r#""toolbar_____""#, r#""menu________""#] {
+            let mut de = serde_json::Deserializer::from_str(json);
+            parents.push((&mut interner).deserialize(&mut de).unwrap());
+        }
+        assert_eq!(interner.len(), 2);
+        assert!(InternedGuid::ptr_eq(&parents[0], &parents[1]));
+        assert_eq!(serde_json::to_string(&parents[2]).unwrap(), r#""menu________""#);
+    }
+}
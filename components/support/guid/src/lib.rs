@@ -0,0 +1,833 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A type for the record IDs ("guids") used by sync, and the validation
+//! rules that apply to them.
+
+extern crate ring;
+
+#[cfg(feature = "serde_support")]
+extern crate serde;
+
+#[cfg(all(test, feature = "serde_support"))]
+extern crate serde_json;
+
+#[cfg(all(test, feature = "serde_support"))]
+extern crate serde_test;
+
+#[cfg(feature = "serde_support")]
+mod serde_support;
+
+#[cfg(feature = "uuid_support")]
+extern crate uuid;
+
+#[cfg(feature = "uuid_support")]
+mod uuid_support;
+
+#[cfg(feature = "random")]
+extern crate rand;
+
+#[cfg(feature = "random")]
+mod random;
+
+#[cfg(feature = "rusqlite_support")]
+extern crate rusqlite;
+
+#[cfg(feature = "rusqlite_support")]
+mod rusqlite_support;
+
+#[cfg(feature = "rusqlite_support")]
+pub use rusqlite_support::GuidBlob;
+
+#[cfg(feature = "interner")]
+mod interner;
+
+#[cfg(feature = "interner")]
+pub use interner::{GuidInterner, InternedGuid};
+
+#[cfg(feature = "proptest_support")]
+#[cfg_attr(test, macro_use)]
+extern crate proptest;
+
+#[cfg(feature = "proptest_support")]
+mod proptest_support;
+
+#[cfg(feature = "proptest_support")]
+pub use proptest_support::{edge_case_guid, long_guid, places_guid, short_guid};
+
+mod error;
+pub use error::{GuidError, GuidParseError};
+
+mod validation;
+pub use validation::{ValidationReport, MAX_REPORTED_INVALID};
+
+mod guid_ref;
+pub use guid_ref::GuidRef;
+
+mod normalize;
+mod raw;
+
+mod redact;
+pub use redact::RedactedGuid;
+
+#[cfg(feature = "archive")]
+mod archive;
+
+#[cfg(feature = "archive")]
+pub use archive::{archive_guids, ArchiveError, GuidArchive};
+
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    ops, str,
+};
+
+/// This is a type intended to be used to represent the guids used by sync. It
+/// has several benefits over using a `String`:
+///
+/// 1. It's more explicit about what is being stored, and could prevent bugs
+///    where a Guid is passed to a function expecting text.
+///
+/// 2. Guids are guaranteed to be immutable.
+///
+/// 3. It's optimized for the guids commonly used by sync. In particular, guids
+///    that meet `PlacesUtils.isValidGuid` (12 base64url characters), and other
+///    ASCII ids of up to `MAX_INLINE_GUID_LEN` bytes (like `"menu"`), are
+///    stored inline, and don't require a heap allocation.
+///
+/// Note that constructing a `Guid` with `new` (or `From`) never fails: sync has
+/// to cope with ids that other clients uploaded, however odd they are. Use
+/// `is_valid_for_sync_server` and `is_valid_for_places` to check whether an id
+/// is one we'd be willing to create ourselves, or `new_checked` (and
+/// `try_from_slice`, `try_from_vec` and the `FromStr` impl), which reject
+/// anything the sync server would, with a `GuidError` saying why.
+///
+/// With the `strict` feature, the constructors that can already fail also
+/// reject ids the sync server would: deserializing a guid with serde, reading
+/// one from SQLite, and `from_raw_bytes`. This is for consumers that would
+/// rather find out about a bad id when they read it than when they try to
+/// upload it. `new` and the `From` impls can't fail, so they're unaffected;
+/// code that wants to be strict should use `new_checked` instead.
+///
+/// With the `redact_debug` feature, `Debug` only prints a salted hash of the
+/// guid (see `redacted`), so that record ids don't leak into logs. `Display`
+/// and `as_str` always give the whole guid.
+#[derive(Clone)]
+pub struct Guid(Repr);
+
+/// The length of a guid that meets `PlacesUtils.isValidGuid`.
+const FAST_GUID_LEN: usize = 12;
+
+/// Places guids encode this many bytes, which is exactly `FAST_GUID_LEN`
+/// characters of base64url, without any padding.
+const PLACES_GUID_BYTES: usize = 9;
+
+const BASE64URL_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// The sync server rejects ids longer than this.
+const MAX_SYNC_SERVER_GUID_LEN: usize = 64;
+
+/// ASCII ids up to this many bytes long are stored inline. On 64-bit
+/// targets, `Repr::Short` (with its length byte) then fits in the space
+/// `Repr::Slow` and the tag need anyway, so `Guid` is no bigger than a
+/// `String` and a `usize`. It's bigger than that on 32-bit targets, but this
+/// can't depend on the target, since it's part of the `archive` format.
+pub const MAX_INLINE_GUID_LEN: usize = 22;
+
+#[derive(Clone)]
+enum Repr {
+    // A guid that meets `is_valid_for_places`. We only ever store valid
+    // base64url bytes here, which are always ASCII (and so valid UTF-8).
+    Fast([u8; FAST_GUID_LEN]),
+    // Any other ASCII id of up to `MAX_INLINE_GUID_LEN` bytes: the length,
+    // followed by the bytes, and then zeros. Only the first `len` bytes are
+    // part of the guid.
+    Short(u8, [u8; MAX_INLINE_GUID_LEN]),
+    // Anything else.
+    Slow(String),
+}
+
+impl Guid {
+    /// The guid of the root of the bookmarks tree. The other roots are its
+    /// children.
+    pub const ROOT: Guid = Guid(Repr::Fast(*b"root________"));
+    /// The guid of the bookmarks menu.
+    pub const MENU: Guid = Guid(Repr::Fast(*b"menu________"));
+    /// The guid of the bookmarks toolbar.
+    pub const TOOLBAR: Guid = Guid(Repr::Fast(*b"toolbar_____"));
+    /// The guid of the "Other Bookmarks" folder.
+    pub const UNFILED: Guid = Guid(Repr::Fast(*b"unfiled_____"));
+    /// The guid of the "Mobile Bookmarks" folder.
+    pub const MOBILE: Guid = Guid(Repr::Fast(*b"mobile______"));
+    /// The guid of the tags folder. Desktop stores tags as bookmarks in
+    /// folders under it, but never syncs it.
+    pub const TAGS: Guid = Guid(Repr::Fast(*b"tags________"));
+
+    /// Returns the empty guid. It's never valid for the sync server (or
+    /// places), but some callers use it as a placeholder for "no id yet", like
+    /// logins added by the application, which are assigned a random guid when
+    /// they're inserted. It's also what `Guid::default()` returns.
+    ///
+    /// The empty guid is stored inline, so this doesn't allocate.
+    #[inline]
+    pub fn empty() -> Self {
+        Guid(Repr::Short(0, [0u8; MAX_INLINE_GUID_LEN]))
+    }
+
+    /// Returns true if this is the empty guid.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        match &self.0 {
+            Repr::Short(len, _) => *len == 0,
+            Repr::Fast(_) => false,
+            Repr::Slow(s) => s.is_empty(),
+        }
+    }
+
+    /// Returns `None` for the empty guid, and `Some(self)` for any other. This
+    /// is handy for binding a guid that might be a placeholder as `NULL`, or
+    /// for checking whether a caller provided one:
+    ///
+    /// ```rust,ignore
+    /// let guid = login.guid.non_empty().cloned().unwrap_or_else(Guid::random);
+    /// ```
+    #[inline]
+    pub fn non_empty(&self) -> Option<&Guid> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self)
+        }
+    }
+
+    /// Create a guid from a `str`.
+    #[inline]
+    pub fn new(s: &str) -> Self {
+        if let Some(inline) = Guid::try_make_inline(s.as_bytes()) {
+            inline
+        } else {
+            Guid(Repr::Slow(s.to_owned()))
+        }
+    }
+
+    /// Create a guid from a `String`.
+    ///
+    /// Reuses the allocation of `s` if it can't be stored inline.
+    #[inline]
+    pub fn from_string(s: String) -> Self {
+        if let Some(inline) = Guid::try_make_inline(s.as_bytes()) {
+            inline
+        } else {
+            Guid(Repr::Slow(s))
+        }
+    }
+
+    /// Create a guid from a `str`, if it's an id the sync server would
+    /// accept. See `is_valid_for_sync_server`.
+    #[inline]
+    pub fn new_checked(s: &str) -> Result<Self, GuidError> {
+        check_sync_server_guid(s)?;
+        Ok(Guid::new(s))
+    }
+
+    /// Like `new_checked`, but reuses the allocation of `s` if the guid can't
+    /// be stored inline.
+    #[inline]
+    pub fn from_string_checked(s: String) -> Result<Self, GuidError> {
+        check_sync_server_guid(&s)?;
+        Ok(Guid::from_string(s))
+    }
+
+    /// Creates a guid for an id we've read from elsewhere, like a serialized
+    /// record or the database. With the `strict` feature, this rejects ids
+    /// the sync server would. Otherwise, it accepts anything, like `new`.
+    #[inline]
+    pub(crate) fn from_stored_str(s: &str) -> Result<Self, GuidError> {
+        if cfg!(feature = "strict") {
+            Guid::new_checked(s)
+        } else {
+            Ok(Guid::new(s))
+        }
+    }
+
+    /// Like `from_stored_str`, but for a `String`.
+    #[cfg(feature = "serde_support")]
+    #[inline]
+    pub(crate) fn from_stored_string(s: String) -> Result<Self, GuidError> {
+        if cfg!(feature = "strict") {
+            Guid::from_string_checked(s)
+        } else {
+            Ok(Guid::from_string(s))
+        }
+    }
+
+    /// Create a guid from a `Vec<u8>`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `v` isn't valid UTF-8.
+    pub fn from_vec(v: Vec<u8>) -> Self {
+        if let Some(inline) = Guid::try_make_inline(&v) {
+            inline
+        } else {
+            Guid(Repr::Slow(String::from_utf8(v).expect("Guid::from_vec: invalid UTF-8")))
+        }
+    }
+
+    /// Create a guid from a byte slice.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `b` isn't valid UTF-8. Use `try_from_bytes` if that's
+    /// possible.
+    #[inline]
+    pub fn from_bytes(b: &[u8]) -> Self {
+        Guid::try_from_bytes(b).expect("Guid::from_bytes: invalid UTF-8")
+    }
+
+    /// Like `from_bytes`, but returns `None` if `b` isn't valid UTF-8.
+    pub fn try_from_bytes(b: &[u8]) -> Option<Self> {
+        if let Some(inline) = Guid::try_make_inline(b) {
+            Some(inline)
+        } else {
+            str::from_utf8(b).ok().map(|s| Guid(Repr::Slow(s.to_owned())))
+        }
+    }
+
+    /// Create a guid from `b`, if it's an id the sync server would accept.
+    pub fn try_from_slice(b: &[u8]) -> Result<Self, GuidError> {
+        let s = str::from_utf8(b).map_err(|_| GuidError::InvalidUtf8)?;
+        Guid::new_checked(s)
+    }
+
+    /// Like `try_from_slice`, but reuses the allocation of `v` if the guid
+    /// can't be stored inline.
+    pub fn try_from_vec(v: Vec<u8>) -> Result<Self, GuidError> {
+        let s = String::from_utf8(v).map_err(|_| GuidError::InvalidUtf8)?;
+        Guid::from_string_checked(s)
+    }
+
+    /// Returns a guid for `bytes` if it can be stored inline.
+    #[inline]
+    fn try_make_inline(bytes: &[u8]) -> Option<Self> {
+        if is_valid_places_guid(bytes) {
+            let mut fast = [0u8; FAST_GUID_LEN];
+            fast.copy_from_slice(bytes);
+            Some(Guid(Repr::Fast(fast)))
+        } else if bytes.len() <= MAX_INLINE_GUID_LEN && bytes.is_ascii() {
+            let mut short = [0u8; MAX_INLINE_GUID_LEN];
+            short[..bytes.len()].copy_from_slice(bytes);
+            Some(Guid(Repr::Short(bytes.len() as u8, short)))
+        } else {
+            None
+        }
+    }
+
+    // Makes a places guid by base64url-encoding `bytes`.
+    fn from_places_guid_bytes(bytes: &[u8; PLACES_GUID_BYTES]) -> Self {
+        let mut fast = [0u8; FAST_GUID_LEN];
+        for (chunk, out) in bytes.chunks(3).zip(fast.chunks_mut(4)) {
+            let group = (u32::from(chunk[0]) << 16) | (u32::from(chunk[1]) << 8) | u32::from(chunk[2]);
+            for (i, c) in out.iter_mut().enumerate() {
+                *c = BASE64URL_ALPHABET[((group >> (18 - 6 * i)) & 0x3f) as usize];
+            }
+        }
+        Guid(Repr::Fast(fast))
+    }
+
+    /// Get the data backing this `Guid` as a `&[u8]`.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Fast(rep) => &rep[..],
+            Repr::Short(len, rep) => &rep[..*len as usize],
+            Repr::Slow(rep) => rep.as_bytes(),
+        }
+    }
+
+    /// Get the data backing this `Guid` as a `&str`.
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        match &self.0 {
+            // Safe, since we only ever store valid base64url (and thus ASCII)
+            // bytes in the fast repr, and ASCII in the short one.
+            Repr::Fast(rep) => unsafe { str::from_utf8_unchecked(&rep[..]) },
+            Repr::Short(len, rep) => unsafe { str::from_utf8_unchecked(&rep[..*len as usize]) },
+            Repr::Slow(rep) => rep,
+        }
+    }
+
+    /// Convert this `Guid` into a `String`, consuming it in the process.
+    #[inline]
+    pub fn into_string(self) -> String {
+        match self.0 {
+            // Safe for the same reason as in `as_str`.
+            Repr::Fast(rep) => unsafe { String::from_utf8_unchecked(rep.to_vec()) },
+            Repr::Short(len, rep) => unsafe {
+                String::from_utf8_unchecked(rep[..len as usize].to_vec())
+            },
+            Repr::Slow(s) => s,
+        }
+    }
+
+    /// Returns true if this guid would be accepted as a record id by the sync
+    /// server: it must be between 1 and 64 characters long, and consist only
+    /// of printable ASCII (`' '..='~'`), excluding commas.
+    ///
+    /// The server is actually a little more lenient than this, but ids that
+    /// fail this check can't round-trip through all of its APIs (for example,
+    /// `?ids=` takes a comma separated list), so other clients treat them as
+    /// invalid, and so do we.
+    #[inline]
+    pub fn is_valid_for_sync_server(&self) -> bool {
+        is_valid_sync_server_guid(self.as_bytes())
+    }
+
+    /// Returns true for guids that are 12 base64url characters long, which is
+    /// what places (and `PlacesUtils.isValidGuid` on desktop) requires. These
+    /// are always valid for the sync server as well.
+    #[inline]
+    pub fn is_valid_for_places(&self) -> bool {
+        match self.0 {
+            Repr::Fast(_) => true,
+            Repr::Short(..) | Repr::Slow(_) => false,
+        }
+    }
+
+    /// Returns true if this is the guid of one of the bookmark roots every
+    /// profile has (`ROOT`, `MENU`, `TOOLBAR`, `UNFILED`, `MOBILE` or `TAGS`),
+    /// which can't be moved or deleted.
+    pub fn is_built_in_root(&self) -> bool {
+        BUILT_IN_ROOTS.iter().any(|root| root == self)
+    }
+}
+
+static BUILT_IN_ROOTS: [Guid; 6] = [
+    Guid::ROOT,
+    Guid::MENU,
+    Guid::TOOLBAR,
+    Guid::UNFILED,
+    Guid::MOBILE,
+    Guid::TAGS,
+];
+
+fn is_valid_places_guid(bytes: &[u8]) -> bool {
+    bytes.len() == FAST_GUID_LEN && bytes.iter().all(|&b| is_base64url_byte(b))
+}
+
+fn is_valid_sync_server_guid(bytes: &[u8]) -> bool {
+    !bytes.is_empty()
+        && bytes.len() <= MAX_SYNC_SERVER_GUID_LEN
+        && bytes.iter().all(|&b| is_sync_server_byte(b))
+}
+
+#[inline]
+fn is_sync_server_byte(b: u8) -> bool {
+    b >= b' ' && b <= b'~' && b != b','
+}
+
+/// Like `is_valid_sync_server_guid`, but says what's wrong.
+fn check_sync_server_guid(s: &str) -> Result<(), GuidError> {
+    if s.is_empty() {
+        return Err(GuidError::Empty);
+    }
+    if s.len() > MAX_SYNC_SERVER_GUID_LEN {
+        return Err(GuidError::TooLong(s.len()));
+    }
+    match s.chars().find(|&c| !c.is_ascii() || !is_sync_server_byte(c as u8)) {
+        Some(c) => Err(GuidError::InvalidChar(c)),
+        None => Ok(()),
+    }
+}
+
+#[inline]
+fn is_base64url_byte(b: u8) -> bool {
+    (b'a' <= b && b <= b'z')
+        || (b'A' <= b && b <= b'Z')
+        || (b'0' <= b && b <= b'9')
+        || b == b'-'
+        || b == b'_'
+}
+
+impl Default for Guid {
+    /// The empty guid. See `Guid::empty`.
+    #[inline]
+    fn default() -> Self {
+        Guid::empty()
+    }
+}
+
+impl<'a> From<&'a str> for Guid {
+    #[inline]
+    fn from(s: &'a str) -> Guid {
+        Guid::new(s)
+    }
+}
+
+impl<'a> From<&'a String> for Guid {
+    #[inline]
+    fn from(s: &'a String) -> Guid {
+        Guid::new(s)
+    }
+}
+
+impl From<String> for Guid {
+    #[inline]
+    fn from(s: String) -> Guid {
+        Guid::from_string(s)
+    }
+}
+
+impl str::FromStr for Guid {
+    type Err = GuidParseError;
+
+    /// Creates a guid from `s`, if it's an id the sync server would accept,
+    /// like `new_checked`.
+    fn from_str(s: &str) -> Result<Guid, GuidParseError> {
+        Guid::new_checked(s)
+    }
+}
+
+impl From<Guid> for String {
+    #[inline]
+    fn from(guid: Guid) -> String {
+        guid.into_string()
+    }
+}
+
+impl AsRef<str> for Guid {
+    #[inline]
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<[u8]> for Guid {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+// This lets maps keyed by `Guid` be queried with a `&str`, without building
+// a `Guid` for the lookup. It's why `Hash` (and `Eq` and `Ord`) must agree with
+// `str`'s.
+//
+// There's deliberately no `Borrow<[u8]>`: `[u8]` and `str` hash differently,
+// so we can only be consistent with one of them, and ids are strings.
+impl Borrow<str> for Guid {
+    #[inline]
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl ops::Deref for Guid {
+    type Target = str;
+    #[inline]
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+// The derived impls would compare the representations, so we implement these
+// by hand in terms of the string value instead.
+
+impl Ord for Guid {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl PartialOrd for Guid {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Guid {
+    #[inline]
+    fn eq(&self, other: &Guid) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for Guid {}
+
+impl Hash for Guid {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Must hash the same as `str`, for `Borrow<str>`.
+        self.as_str().hash(state)
+    }
+}
+
+macro_rules! impl_guid_eq {
+    ($($other: ty),+) => {$(
+        impl<'a> PartialEq<$other> for Guid {
+            #[inline]
+            fn eq(&self, other: &$other) -> bool {
+                PartialEq::eq(AsRef::<[u8]>::as_ref(self), AsRef::<[u8]>::as_ref(other))
+            }
+        }
+
+        impl<'a> PartialEq<Guid> for $other {
+            #[inline]
+            fn eq(&self, other: &Guid) -> bool {
+                PartialEq::eq(AsRef::<[u8]>::as_ref(self), AsRef::<[u8]>::as_ref(other))
+            }
+        }
+    )+}
+}
+
+// Implement direct comparison with some common types from the stdlib.
+impl_guid_eq![str, &'a str, String, [u8], &'a [u8], Vec<u8>];
+
+// With `redact_debug`, this only shows enough of the guid to match up log
+// lines. See `RedactedGuid`.
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if cfg!(feature = "redact_debug") {
+            write!(f, "Guid({})", self.redacted())
+        } else {
+            write!(f, "Guid({:?})", self.as_str())
+        }
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn test_base64url_bytes() {
+        let mut expect = 0;
+        for b in 0..=255u8 {
+            if is_base64url_byte(b) {
+                expect += 1;
+            }
+        }
+        assert_eq!(expect, 64);
+    }
+
+    #[test]
+    fn test_valid_for_places() {
+        assert!(Guid::new("aaaabbbbcccc").is_valid_for_places());
+        assert!(Guid::from_bytes(b"09_az-AZ_09-").is_valid_for_places());
+        assert!(!Guid::new("aaaabbbbccccd").is_valid_for_places()); // too long
+        assert!(!Guid::new("aaaabbbbccc").is_valid_for_places()); // too short
+        assert!(!Guid::new("aaaabbbbccc=").is_valid_for_places()); // right length, bad character
+        assert!(!Guid::new("").is_valid_for_places());
+    }
+
+    #[test]
+    fn test_roots() {
+        assert_eq!(Guid::ROOT, "root________");
+        assert_eq!(Guid::MOBILE, "mobile______");
+        for root in &BUILT_IN_ROOTS {
+            assert!(root.is_valid_for_places());
+            assert!(root.is_built_in_root());
+            assert!(Guid::new(root.as_str()).is_built_in_root());
+        }
+        assert!(!Guid::new("aaaabbbbcccc").is_built_in_root());
+        assert!(!Guid::new("menu").is_built_in_root());
+    }
+
+    #[test]
+    fn test_valid_for_sync_server() {
+        assert!(Guid::new("aaaabbbbcccc").is_valid_for_sync_server());
+        assert!(Guid::new("{5e8ea4a4-6d38-4a1e-a0bd-7b0eb7e1b8e8}").is_valid_for_sync_server());
+        assert!(Guid::new("menu").is_valid_for_sync_server());
+        assert!(Guid::new(" ~ ").is_valid_for_sync_server());
+        assert!(Guid::new(&"x".repeat(64)).is_valid_for_sync_server());
+
+        assert!(!Guid::new("").is_valid_for_sync_server());
+        assert!(!Guid::new(&"x".repeat(65)).is_valid_for_sync_server());
+        assert!(!Guid::new("a,b").is_valid_for_sync_server());
+        assert!(!Guid::new("tab\there").is_valid_for_sync_server());
+        assert!(!Guid::new("nul\0").is_valid_for_sync_server());
+        assert!(!Guid::new("émile").is_valid_for_sync_server());
+    }
+
+    #[test]
+    fn test_try_from_slice() {
+        assert_eq!(Guid::try_from_slice(b"aaaabbbbcccc").unwrap(), "aaaabbbbcccc");
+        assert_eq!(Guid::try_from_slice(b"menu").unwrap(), "menu");
+        assert_eq!(Guid::try_from_vec(b" ~ ".to_vec()).unwrap(), " ~ ");
+        let long = "x".repeat(64);
+        assert_eq!(Guid::try_from_slice(long.as_bytes()).unwrap(), long);
+
+        assert_eq!(Guid::try_from_slice(b""), Err(GuidError::Empty));
+        assert_eq!(Guid::try_from_vec("x".repeat(65).into_bytes()), Err(GuidError::TooLong(65)));
+        assert_eq!(Guid::try_from_slice(b"a,b"), Err(GuidError::InvalidChar(',')));
+        assert_eq!(Guid::try_from_slice(b"tab\there"), Err(GuidError::InvalidChar('\t')));
+        assert_eq!(Guid::try_from_slice("émile".as_bytes()), Err(GuidError::InvalidChar('é')));
+        assert_eq!(Guid::try_from_vec(vec![0xff, 0xfe]), Err(GuidError::InvalidUtf8));
+
+        // Anything these accept is valid for the server, and vice versa.
+        for s in &["", "aaaabbbbcccc", "a,b", "nul\0", "{5e8ea4a4-6d38-4a1e-a0bd-7b0eb7e1b8e8}"] {
+            assert_eq!(Guid::try_from_slice(s.as_bytes()).is_ok(), Guid::new(s).is_valid_for_sync_server());
+        }
+    }
+
+    #[test]
+    fn test_new_checked() {
+        assert_eq!(Guid::new_checked("aaaabbbbcccc").unwrap(), "aaaabbbbcccc");
+        assert_eq!(Guid::from_string_checked("menu".to_string()).unwrap(), "menu");
+        assert_eq!(Guid::new_checked(""), Err(GuidError::Empty));
+        assert_eq!(Guid::from_string_checked("a,b".to_string()), Err(GuidError::InvalidChar(',')));
+
+        let bad = "x".repeat(65);
+        assert_eq!(Guid::from_stored_str(&bad).is_ok(), !cfg!(feature = "strict"));
+        assert!(Guid::from_stored_str("menu").is_ok());
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("aaaabbbbcccc".parse::<Guid>().unwrap(), "aaaabbbbcccc");
+        assert_eq!("menu".parse::<Guid>().unwrap(), Guid::from("menu"));
+        assert_eq!("x".repeat(64).parse::<Guid>().unwrap().len(), 64);
+
+        assert_eq!("".parse::<Guid>(), Err(GuidParseError::Empty));
+        assert_eq!("x".repeat(65).parse::<Guid>(), Err(GuidParseError::TooLong(65)));
+        assert_eq!("a,b".parse::<Guid>(), Err(GuidParseError::InvalidChar(',')));
+        assert_eq!("émile".parse::<Guid>(), Err(GuidParseError::InvalidChar('é')));
+
+        // It works in generic code, like other `FromStr` types.
+        fn parse_all<T: str::FromStr>(ids: &[&str]) -> Result<Vec<T>, T::Err> {
+            ids.iter().map(|id| id.parse()).collect()
+        }
+        assert_eq!(parse_all::<Guid>(&["menu", "aaaabbbbcccc"]).unwrap(), vec!["menu", "aaaabbbbcccc"]);
+        assert!(parse_all::<Guid>(&["menu", ""]).is_err());
+    }
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!(Guid::from("abcdabcdabcd"), "abcdabcdabcd");
+        assert_eq!(Guid::from("abcdabcdabcd".to_string()), "abcdabcdabcd");
+        assert_eq!("abcdabcdabcd", Guid::from("abcdabcdabcd"));
+        assert_eq!(Guid::from("abcdabcdabcd"), &b"abcdabcdabcd"[..]);
+        assert_eq!(Guid::from("abcd"), "abcd");
+        assert_eq!(Guid::from("abcd".to_string()), String::from("abcd"));
+        assert_ne!(Guid::from("abcdabcdabcd"), Guid::from("abcd"));
+
+        let mut guids = vec![
+            Guid::from("zzzzzzzzzzzz"),
+            Guid::from("aaaa"),
+            Guid::from("bbbbbbbbbbbb"),
+            Guid::from("bbbb"),
+        ];
+        guids.sort();
+        assert_eq!(guids, vec!["aaaa", "bbbb", "bbbbbbbbbbbb", "zzzzzzzzzzzz"]);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let fast = Guid::from_string("abcdabcdabcd".to_string());
+        assert!(fast.is_valid_for_places());
+        assert_eq!(fast.as_str(), "abcdabcdabcd");
+        assert_eq!(fast.len(), 12);
+        assert_eq!(String::from(fast), "abcdabcdabcd");
+
+        let slow = Guid::from_vec(b"not a places guid".to_vec());
+        assert!(!slow.is_valid_for_places());
+        assert_eq!(slow.to_string(), "not a places guid");
+        assert_eq!(slow.into_string(), "not a places guid");
+
+        assert!(Guid::try_from_bytes(b"\xff\xfe").is_none());
+    }
+
+    #[test]
+    fn test_inline() {
+        fn is_inline(guid: &Guid) -> bool {
+            match guid.0 {
+                Repr::Fast(_) | Repr::Short(..) => true,
+                Repr::Slow(_) => false,
+            }
+        }
+        // Storing short ids inline doesn't make guids any bigger, on 64-bit
+        // targets.
+        if cfg!(target_pointer_width = "64") {
+            assert!(mem::size_of::<Guid>() <= mem::size_of::<String>() + mem::size_of::<usize>());
+        }
+
+        for s in &["", "menu", "places", "aaaabbbbccc=", "{5e8ea4a4-6d38-4a1e}", "with\0nul"] {
+            let guid = Guid::new(s);
+            assert!(is_inline(&guid), "{:?} should be inline", s);
+            assert!(!guid.is_valid_for_places());
+            assert_eq!(guid, *s);
+            assert_eq!(guid.len(), s.len());
+            assert_eq!(guid.clone().into_string(), *s);
+        }
+        assert!(is_inline(&Guid::new(&"x".repeat(MAX_INLINE_GUID_LEN))));
+        assert!(!is_inline(&Guid::new(&"x".repeat(MAX_INLINE_GUID_LEN + 1))));
+        assert!(!is_inline(&Guid::new("émile")));
+        assert!(is_inline(&Guid::from_vec(b"places".to_vec())));
+
+        // Inline guids compare and hash like any other.
+        assert_eq!(Guid::new("menu"), Guid(Repr::Slow("menu".into())));
+        assert!(Guid::new("menu") < Guid::new("menu________"));
+    }
+
+    #[test]
+    fn test_empty() {
+        let empty = Guid::empty();
+        assert!(empty.is_empty());
+        assert_eq!(empty, "");
+        assert_eq!(Guid::default(), empty);
+        assert_eq!(Guid::new(""), empty);
+        assert!(Guid(Repr::Slow(String::new())).is_empty());
+        assert!(!empty.is_valid_for_sync_server());
+        assert_eq!(empty.non_empty(), None);
+
+        for s in &["abcdabcdabcd", "menu", "{5e8ea4a4-6d38-4a1e-a0bd-7b0eb7e1b8e8}"] {
+            let guid = Guid::new(s);
+            assert!(!guid.is_empty());
+            assert_eq!(guid.non_empty(), Some(&guid));
+        }
+    }
+
+    #[test]
+    fn test_borrow() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::{BTreeMap, HashMap};
+
+        fn hash_of<T: Hash + ?Sized>(t: &T) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            t.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let ids = ["aaaabbbbcccc", "menu", "{5e8ea4a4-6d38-4a1e-a0bd-7b0eb7e1b8e8}", ""];
+        for id in &ids {
+            assert_eq!(hash_of(&Guid::new(id)), hash_of(*id));
+        }
+
+        let hash_map = ids.iter().map(|&id| (Guid::new(id), id.len())).collect::<HashMap<_, _>>();
+        let btree_map = ids.iter().map(|&id| (Guid::new(id), id.len())).collect::<BTreeMap<_, _>>();
+        for id in &ids {
+            assert_eq!(hash_map.get(*id), Some(&id.len()));
+            assert_eq!(btree_map.get(*id), Some(&id.len()));
+        }
+        assert_eq!(hash_map.get("bbbbccccdddd"), None);
+        assert_eq!(btree_map.get("bbbbccccdddd"), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_from_bytes_invalid_utf8() {
+        Guid::from_bytes(b"\xff\xfe");
+    }
+}
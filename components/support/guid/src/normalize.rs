@@ -0,0 +1,55 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Replacing invalid incoming ids with valid ones that every client agrees
+//! on.
+
+use ring::digest;
+use {is_valid_sync_server_guid, Guid, PLACES_GUID_BYTES};
+
+impl Guid {
+    /// Returns a guid for `s` that's valid for the sync server: `s` itself if
+    /// it already is, and otherwise a places guid derived from it. Engines
+    /// can use this to repair records with ids the server would reject.
+    ///
+    /// The derived guid is the first 9 bytes of the SHA-256 hash of `s`'s
+    /// UTF-8 bytes, base64url-encoded (which makes it 12 characters long).
+    /// It's deterministic, so every client using this crate repairs the same
+    /// id to the same guid. (Other clients don't necessarily use this scheme,
+    /// so a record they repaired may still end up with a different id.) It's
+    /// lossy, since the original id can't be recovered from it.
+    pub fn normalize_lossy(s: &str) -> Guid {
+        if is_valid_sync_server_guid(s.as_bytes()) {
+            return Guid::new(s);
+        }
+        let digest = digest::digest(&digest::SHA256, s.as_bytes());
+        let mut bytes = [0u8; PLACES_GUID_BYTES];
+        bytes.copy_from_slice(&digest.as_ref()[..PLACES_GUID_BYTES]);
+        Guid::from_places_guid_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_lossy() {
+        for valid in &["aaaabbbbcccc", "menu", "{punctuation and spaces are fine}"] {
+            assert_eq!(Guid::normalize_lossy(valid), *valid);
+        }
+        let long = "x".repeat(65);
+        let cases = &[
+            ("bad,id", "hK6tFiCPoduD"),
+            ("", "47DEQpj8HBSa"),
+            (long.as_str(), "lTfF_fEgSC99"),
+            ("émile", "pWs2PKI92dub"),
+        ];
+        for &(invalid, expected) in cases {
+            let normalized = Guid::normalize_lossy(invalid);
+            assert_eq!(normalized, expected);
+            assert!(normalized.is_valid_for_places());
+        }
+    }
+}
@@ -0,0 +1,126 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Generating guids for property tests, with `proptest`.
+//!
+//! `any::<Guid>()` generates a mix of the ids sync actually sees: mostly
+//! 12-character places guids, some short and long ids from other clients, and
+//! some edge cases (invalid ids, the built-in roots, and ids right at the
+//! length limits). The strategies for each kind are also exported, for tests
+//! that only want one of them:
+//!
+//! ```rust,ignore
+//! proptest! {
+//!     #[test]
+//!     fn test_merge(local in any::<Guid>(), remote in sync_guid::places_guid()) {
+//!         ...
+//!     }
+//! }
+//! ```
+
+use proptest::prelude::*;
+use proptest::sample;
+use proptest::string::string_regex;
+
+use {Guid, BUILT_IN_ROOTS, MAX_INLINE_GUID_LEN, MAX_SYNC_SERVER_GUID_LEN};
+
+// Everything the sync server allows: printable ASCII, except `,`.
+const SYNC_SERVER_CHARS: &str = "[ -+\\--~]";
+
+// Guids matching `pattern`, which is a regex.
+fn matching(pattern: &str) -> impl Strategy<Value = Guid> {
+    string_regex(pattern)
+        .expect("Bad regex for guids")
+        .prop_map(Guid::from_string)
+}
+
+// Guids of between `min` and `max` characters the sync server allows.
+fn sync_server_chars(min: usize, max: usize) -> impl Strategy<Value = Guid> {
+    matching(&format!("{}{{{},{}}}", SYNC_SERVER_CHARS, min, max))
+}
+
+impl Arbitrary for Guid {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Guid>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            6 => places_guid(),
+            1 => short_guid(),
+            1 => long_guid(),
+            2 => edge_case_guid()
+        ].boxed()
+    }
+}
+
+/// Guids that are valid for places, like the ones we create.
+pub fn places_guid() -> impl Strategy<Value = Guid> {
+    matching("[A-Za-z0-9_-]{12}")
+}
+
+/// Ids that are valid for the sync server, and short enough to be stored
+/// inline, like the `"menu"` and `"toolbar"` ids older clients use for the
+/// roots. Most aren't valid for places.
+pub fn short_guid() -> impl Strategy<Value = Guid> {
+    sync_server_chars(1, MAX_INLINE_GUID_LEN)
+}
+
+/// Ids that are valid for the sync server, but too long to be stored inline,
+/// like the `{uuid}`s some clients use.
+pub fn long_guid() -> impl Strategy<Value = Guid> {
+    prop_oneof![
+        matching("\\{[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}\\}"),
+        sync_server_chars(MAX_INLINE_GUID_LEN + 1, MAX_SYNC_SERVER_GUID_LEN)
+    ]
+}
+
+/// Ids that are invalid, or only just valid: empty ids, ids with characters
+/// the sync server rejects, ids at (and past) the length limits, and the
+/// built-in roots.
+pub fn edge_case_guid() -> impl Strategy<Value = Guid> {
+    prop_oneof![
+        Just(Guid::new("")),
+        sample::select(BUILT_IN_ROOTS.to_vec()),
+        // At, and just past, `MAX_INLINE_GUID_LEN` and the sync server's
+        // limit.
+        sync_server_chars(MAX_INLINE_GUID_LEN, MAX_INLINE_GUID_LEN + 1),
+        sync_server_chars(MAX_SYNC_SERVER_GUID_LEN, MAX_SYNC_SERVER_GUID_LEN + 1),
+        // Places guids, except for one character.
+        matching("[A-Za-z0-9_-]{11}[ ,.+/=\u{e9}]"),
+        matching("[a-z]{0,8},[a-z]{0,8}"),
+        any::<String>().prop_map(Guid::from_string)
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn test_places_guid(guid in places_guid()) {
+            prop_assert!(guid.is_valid_for_places());
+        }
+
+        #[test]
+        fn test_short_guid(guid in short_guid()) {
+            prop_assert!(guid.is_valid_for_sync_server());
+            prop_assert!(guid.len() <= MAX_INLINE_GUID_LEN);
+        }
+
+        #[test]
+        fn test_long_guid(guid in long_guid()) {
+            prop_assert!(guid.is_valid_for_sync_server());
+            prop_assert!(guid.len() > MAX_INLINE_GUID_LEN);
+        }
+
+        #[test]
+        fn test_any_guid(guid in any::<Guid>()) {
+            prop_assert_eq!(Guid::new(guid.as_str()), guid.clone());
+            if guid.is_valid_for_places() {
+                prop_assert!(guid.is_valid_for_sync_server());
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Generating new guids, like `PlacesUtils.history.makeGuid` on desktop.
+
+use rand::{self, RngCore};
+
+use {Guid, PLACES_GUID_BYTES};
+
+impl Guid {
+    /// Create a new random guid, which is valid for places (and so for the
+    /// sync server as well). This uses `rand::thread_rng`, which is
+    /// cryptographically secure, so the guids are unpredictable as well as
+    /// (for all practical purposes) unique.
+    pub fn random() -> Self {
+        Guid::random_with(&mut rand::thread_rng())
+    }
+
+    /// Like `random`, but uses `rng`, so that tests can use a seeded RNG to
+    /// get the same guids every time. Code that needs unpredictable guids
+    /// should stick to `random`.
+    pub fn random_with<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        let mut bytes = [0u8; PLACES_GUID_BYTES];
+        rng.fill_bytes(&mut bytes);
+        Guid::from_places_guid_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_random() {
+        let guids = (0..1000).map(|_| Guid::random()).collect::<HashSet<_>>();
+        assert_eq!(guids.len(), 1000);
+        for guid in &guids {
+            assert!(guid.is_valid_for_places());
+            assert!(guid.is_valid_for_sync_server());
+            assert_eq!(Guid::new(guid.as_str()), *guid);
+        }
+    }
+
+    #[test]
+    fn test_random_with() {
+        let sequence = |seed: u8| {
+            let mut rng = StdRng::from_seed([seed; 32]);
+            (0..10).map(|_| Guid::random_with(&mut rng)).collect::<Vec<_>>()
+        };
+        let guids = sequence(1);
+        assert_eq!(guids, sequence(1));
+        assert_ne!(guids, sequence(2));
+        assert!(guids.iter().all(Guid::is_valid_for_places));
+        assert_eq!(guids.iter().collect::<HashSet<_>>().len(), 10);
+
+        // Trait objects work too.
+        let mut rng = StdRng::from_seed([1; 32]);
+        assert_eq!(Guid::random_with(&mut rng as &mut RngCore), guids[0]);
+    }
+}
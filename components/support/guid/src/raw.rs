@@ -0,0 +1,121 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A compact binary form for guids, for binary formats and on-disk caches.
+//!
+//! Places guids are 12 base64url characters, which decode to 9 bytes, so
+//! that's what we store for them. Any other guid is stored as its UTF-8
+//! bytes. The only ambiguity is with other guids that happen to be 9 bytes
+//! long, which we prefix with a `0xff` byte (which never appears in UTF-8).
+//!
+//! So, reading the raw form of a guid:
+//!
+//! - 9 bytes are a places guid.
+//! - A `0xff` byte, followed by 9 bytes, is a 9 byte guid that isn't a
+//!   places guid.
+//! - Anything else is the UTF-8 bytes of the guid.
+//!
+//! This means that a guid stored as a plain string is read back correctly,
+//! unless it's 9 bytes long.
+
+use std::borrow::Cow;
+use std::str;
+
+use {Guid, GuidError, Repr, BASE64URL_ALPHABET, PLACES_GUID_BYTES};
+
+/// Marks a 9 byte guid that isn't a places guid.
+const STRING_MARKER: u8 = 0xff;
+
+impl Guid {
+    /// Returns the compact binary form of this guid. See `from_raw_bytes`.
+    pub fn to_raw_bytes(&self) -> Cow<'_, [u8]> {
+        match &self.0 {
+            Repr::Fast(rep) => {
+                let mut raw = [0u8; PLACES_GUID_BYTES];
+                for (chunk, out) in rep.chunks(4).zip(raw.chunks_mut(3)) {
+                    let group = chunk
+                        .iter()
+                        .fold(0u32, |group, &c| (group << 6) | u32::from(base64url_value(c)));
+                    out[0] = (group >> 16) as u8;
+                    out[1] = (group >> 8) as u8;
+                    out[2] = group as u8;
+                }
+                Cow::Owned(raw.to_vec())
+            }
+            _ => {
+                let bytes = self.as_bytes();
+                if bytes.len() == PLACES_GUID_BYTES {
+                    let mut marked = Vec::with_capacity(bytes.len() + 1);
+                    marked.push(STRING_MARKER);
+                    marked.extend_from_slice(bytes);
+                    Cow::Owned(marked)
+                } else {
+                    Cow::Borrowed(bytes)
+                }
+            }
+        }
+    }
+
+    /// Reads a guid in the form returned by `to_raw_bytes`. This only fails
+    /// if `raw` is meant to be a string, but isn't valid UTF-8, or, with the
+    /// `strict` feature, isn't an id the sync server would accept.
+    pub fn from_raw_bytes(raw: &[u8]) -> Result<Guid, GuidError> {
+        if raw.len() == PLACES_GUID_BYTES {
+            let mut bytes = [0u8; PLACES_GUID_BYTES];
+            bytes.copy_from_slice(raw);
+            return Ok(Guid::from_places_guid_bytes(&bytes));
+        }
+        let bytes = match raw.split_first() {
+            Some((&STRING_MARKER, rest)) if rest.len() == PLACES_GUID_BYTES => rest,
+            _ => raw,
+        };
+        let s = str::from_utf8(bytes).map_err(|_| GuidError::InvalidUtf8)?;
+        Guid::from_stored_str(s)
+    }
+}
+
+// Only called with bytes from the fast repr, which are all base64url.
+#[inline]
+fn base64url_value(c: u8) -> u8 {
+    BASE64URL_ALPHABET
+        .iter()
+        .position(|&a| a == c)
+        .expect("Not a base64url byte") as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_raw_round_trip() {
+        let cases: &[(&str, &[u8])] = &[
+            ("AAAAAAAAAAAA", &[0; 9]),
+            ("____________", &[0xff; 9]),
+            ("menu________", &[0x99, 0xe9, 0xee, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            ("menu", b"menu"),
+            ("", b""),
+            ("ninechars", b"\xffninechars"),
+            ("{5e8ea4a4-6d38-4a1e}", b"{5e8ea4a4-6d38-4a1e}"),
+            ("émile", "émile".as_bytes()),
+        ];
+        for &(s, raw) in cases {
+            let guid = Guid::new(s);
+            assert_eq!(&*guid.to_raw_bytes(), raw, "{:?}", s);
+            let read = Guid::from_raw_bytes(raw);
+            if cfg!(feature = "strict") && !guid.is_valid_for_sync_server() {
+                assert!(read.is_err(), "{:?}", s);
+                continue;
+            }
+            let read = read.unwrap();
+            assert_eq!(read, guid);
+            assert_eq!(read.is_valid_for_places(), guid.is_valid_for_places());
+        }
+
+        // Strings are read as is, unless they're 9 bytes long.
+        assert_eq!(Guid::from_raw_bytes(b"abcdabcdabcd").unwrap(), "abcdabcdabcd");
+        assert_eq!(Guid::from_raw_bytes(b"\xffmenu").unwrap_err(), GuidError::InvalidUtf8);
+        assert_eq!(Guid::from_raw_bytes(b"\xfe\xff").unwrap_err(), GuidError::InvalidUtf8);
+    }
+}
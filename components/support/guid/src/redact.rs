@@ -0,0 +1,143 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A form of guids that's safe to log.
+//!
+//! Record ids can identify the user's data (a history guid and a server
+//! dump together say which pages they visited, for example), so we
+//! shouldn't write them to logs that get attached to bug reports. A
+//! redacted guid is just a hash of the guid, which is enough to match up
+//! log lines. The hash is salted with a random value chosen once per
+//! process, so it can't be matched against the ids in a server dump (or
+//! the logs of another process), and it doesn't keep any of the guid
+//! itself.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::{Once, ONCE_INIT};
+
+use ring::digest::{self, Digest};
+use {Guid, GuidRef};
+
+/// How many bytes of the hash we show, in hex.
+const HASH_BYTES: usize = 4;
+
+static SALT_INIT: Once = ONCE_INIT;
+// At least 16 bytes, even with a 32-bit `usize`.
+static SALT: [AtomicUsize; 4] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+];
+
+fn salted_hash(s: &str) -> Digest {
+    // `RandomState`s are keyed from the OS's RNG, which saves us depending
+    // on `rand` just for this. Each one gets different keys, so hashing
+    // nothing gives us unpredictable bits.
+    SALT_INIT.call_once(|| {
+        for word in &SALT {
+            word.store(RandomState::new().build_hasher().finish() as usize, Ordering::Relaxed);
+        }
+    });
+    let mut input = Vec::with_capacity(SALT.len() * mem::size_of::<usize>() + s.len());
+    for word in &SALT {
+        let word = word.load(Ordering::Relaxed);
+        for i in 0..mem::size_of::<usize>() {
+            input.push((word >> (8 * i)) as u8);
+        }
+    }
+    input.extend_from_slice(s.as_bytes());
+    digest::digest(&digest::SHA256, &input)
+}
+
+/// A guid that `Display`s and `Debug`s as a salted hash of the guid, like
+/// `"#3f2a9c1b"`. The same guid always gives the same hash in one process,
+/// and a different one in the next. Returned by `Guid::redacted` and
+/// `GuidRef::redacted`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RedactedGuid<'a>(&'a str);
+
+impl<'a> fmt::Display for RedactedGuid<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#")?;
+        for byte in &salted_hash(self.0).as_ref()[..HASH_BYTES] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Debug for RedactedGuid<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Guid {
+    /// Returns a form of this guid that's safe to log: see `RedactedGuid`.
+    /// With the `redact_debug` feature, this is also what `Debug` prints;
+    /// use `as_str` when you really need the whole guid.
+    #[inline]
+    pub fn redacted(&self) -> RedactedGuid<'_> {
+        RedactedGuid(self.as_str())
+    }
+}
+
+impl<'a> GuidRef<'a> {
+    /// Returns a form of this guid that's safe to log. See
+    /// `Guid::redacted`.
+    #[inline]
+    pub fn redacted(&self) -> RedactedGuid<'a> {
+        RedactedGuid(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_redacted() {
+        let guid = Guid::new("aaaabbbbcccc");
+        let redacted = guid.redacted().to_string();
+        assert_eq!(redacted.len(), 1 + HASH_BYTES * 2);
+        assert!(redacted.starts_with('#'), "{}", redacted);
+        assert!(!redacted.contains("aaaa"));
+
+        // The hash tells apart guids with the same prefix, and is stable
+        // within a process.
+        assert_ne!(redacted, Guid::new("aaaabbbbdddd").redacted().to_string());
+        assert_eq!(redacted, Guid::new("aaaabbbbcccc").redacted().to_string());
+        assert_eq!(redacted, GuidRef::new("aaaabbbbcccc").redacted().to_string());
+
+        // It's salted, so it isn't the hash of the guid someone could compute
+        // from a server dump.
+        let unsalted = digest::digest(&digest::SHA256, b"aaaabbbbcccc");
+        assert_ne!(&redacted[1..], hex(&unsalted.as_ref()[..HASH_BYTES]));
+        assert!(SALT.iter().any(|word| word.load(Ordering::Relaxed) != 0));
+
+        for s in &["menu", "x", "émile", ""] {
+            assert_eq!(Guid::new(s).redacted().to_string().len(), 1 + HASH_BYTES * 2);
+        }
+    }
+
+    #[test]
+    fn test_debug() {
+        let guid = Guid::new("aaaabbbbcccc");
+        let debug = format!("{:?}", guid);
+        if cfg!(feature = "redact_debug") {
+            assert_eq!(debug, format!("Guid({})", guid.redacted()));
+            assert_eq!(format!("{:?}", GuidRef::new("aaaabbbbcccc")), format!("GuidRef({})", guid.redacted()));
+        } else {
+            assert_eq!(debug, "Guid(\"aaaabbbbcccc\")");
+        }
+        // `Display` is for building strings, so it's never redacted.
+        assert_eq!(guid.to_string(), "aaaabbbbcccc");
+    }
+}
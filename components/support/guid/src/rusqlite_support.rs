@@ -0,0 +1,144 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Storing guids in SQLite, with `rusqlite`.
+//!
+//! Guids are bound as `TEXT`, which is how we store them in our own schemas.
+//! Some older schemas (like Fennec's, and iOS's) store them as `BLOB`s
+//! instead, so reading a guid accepts either, and `Guid::to_blob` binds one
+//! as a `BLOB`, for queries against those tables:
+//!
+//! ```rust,ignore
+//! conn.execute("DELETE FROM bookmarks WHERE guid = ?", &[&guid.to_blob()])?;
+//! ```
+//!
+//! A blob is read as the UTF-8 bytes of the guid, and it's an error if it
+//! isn't valid UTF-8. With the `strict` feature, it's also an error to read
+//! an id the sync server would reject, whether it's stored as `TEXT` or as a
+//! `BLOB`.
+//!
+//! The empty guid is bound as an empty string, not `NULL`, and reading a
+//! `NULL` as a `Guid` is an error. Nullable columns should be read as an
+//! `Option<Guid>`, and a placeholder guid can be bound as `NULL` with
+//! `guid.non_empty().cloned()`.
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::Result;
+use std::str;
+
+use {Guid, GuidError};
+
+impl ToSql for Guid {
+    #[inline]
+    fn to_sql(&self) -> Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Borrowed(ValueRef::Text(self.as_str())))
+    }
+}
+
+impl FromSql for Guid {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        let s = match value {
+            ValueRef::Text(s) => s,
+            ValueRef::Blob(b) => str::from_utf8(b)
+                .map_err(|_| FromSqlError::Other(Box::new(GuidError::InvalidUtf8)))?,
+            _ => return Err(FromSqlError::InvalidType),
+        };
+        Guid::from_stored_str(s).map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+/// A guid bound as a `BLOB`, instead of `TEXT`. Returned by `Guid::to_blob`.
+#[derive(Debug, Clone, Copy)]
+pub struct GuidBlob<'a>(&'a Guid);
+
+impl Guid {
+    /// Returns something that binds this guid as a `BLOB` of its UTF-8
+    /// bytes, for schemas that store guids that way. SQLite never considers
+    /// a `BLOB` equal to `TEXT`, so comparing a guid bound as `TEXT` with a
+    /// guid stored as a `BLOB` won't match.
+    #[inline]
+    pub fn to_blob(&self) -> GuidBlob<'_> {
+        GuidBlob(self)
+    }
+}
+
+impl<'a> ToSql for GuidBlob<'a> {
+    #[inline]
+    fn to_sql(&self) -> Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Borrowed(ValueRef::Blob(self.0.as_bytes())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rusqlite::Connection;
+
+    fn conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE guids(guid);
+             INSERT INTO guids(guid) VALUES('abcdabcdabcd'), (X'6d656e75'), (1), (X'fffe')",
+        ).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_read_text_or_blob() {
+        let conn = conn();
+        let read = |rowid: i64| {
+            conn.query_row("SELECT guid FROM guids WHERE rowid = ?", &[&rowid as &ToSql], |row| {
+                row.get_checked::<_, Guid>(0)
+            }).unwrap()
+        };
+        assert_eq!(read(1).unwrap(), "abcdabcdabcd");
+        assert_eq!(read(2).unwrap(), "menu");
+        assert!(read(3).is_err());
+        assert!(read(4).is_err());
+    }
+
+    #[cfg(feature = "strict")]
+    #[test]
+    fn test_read_strict() {
+        let conn = conn();
+        for sql in &["SELECT 'a,b'", "SELECT X'612c62'", "SELECT ''"] {
+            assert!(conn.query_row(sql, &[], |row| row.get_checked::<_, Guid>(0)).unwrap().is_err());
+        }
+    }
+
+    #[test]
+    fn test_bind_text_or_blob() {
+        let conn = conn();
+        let typeof_and_rowid = |value: &ToSql| -> (String, i64) {
+            conn.query_row(
+                "SELECT typeof(?1), rowid FROM guids WHERE guid = ?1",
+                &[value],
+                |row| (row.get(0), row.get(1)),
+            ).unwrap()
+        };
+        let places = Guid::new("abcdabcdabcd");
+        assert_eq!(typeof_and_rowid(&places), ("text".to_string(), 1));
+        let menu = Guid::new("menu");
+        assert_eq!(typeof_and_rowid(&menu.to_blob()), ("blob".to_string(), 2));
+
+        // The empty guid is bound as an empty string, unless it's bound with
+        // `non_empty`.
+        let typeof_value = |value: &ToSql| -> String {
+            conn.query_row("SELECT typeof(?)", &[value], |row| row.get(0)).unwrap()
+        };
+        let empty = Guid::empty();
+        assert_eq!(typeof_value(&empty), "text");
+        assert_eq!(typeof_value(&empty.non_empty().cloned()), "null");
+        assert_eq!(typeof_value(&menu.non_empty().cloned()), "text");
+        let null: Option<Guid> = conn.query_row("SELECT NULL", &[], |row| row.get(0)).unwrap();
+        assert_eq!(null, None);
+        assert!(conn.query_row("SELECT NULL", &[], |row| row.get_checked::<_, Guid>(0)).unwrap().is_err());
+
+        // Round trips either way.
+        for value in &[&menu as &ToSql, &menu.to_blob()] {
+            let guid: Guid = conn.query_row("SELECT ?", &[*value], |row| row.get(0)).unwrap();
+            assert_eq!(guid, menu);
+        }
+    }
+}
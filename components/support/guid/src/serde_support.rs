@@ -0,0 +1,223 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Guids are serialized as strings. The empty guid is serialized as `""`,
+//! like any other, and `null` isn't a guid; use an `Option<Guid>` for fields
+//! that can be `null`.
+//!
+//! Formats that aren't human readable (like bincode) get the compact form
+//! from `Guid::to_raw_bytes` instead, which is 9 bytes for a places guid.
+//!
+//! With the `strict` feature, deserializing an id the sync server would
+//! reject (including the empty guid) is an error.
+
+use std::{fmt, str};
+
+use serde::{
+    de::{self, Deserialize, Deserializer, Unexpected, Visitor},
+    ser::{Serialize, Serializer},
+};
+
+use {Guid, GuidError};
+
+struct GuidVisitor;
+impl<'de> Visitor<'de> for GuidVisitor {
+    type Value = Guid;
+    #[inline]
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a sync guid")
+    }
+    // Guids that can be stored inline (including every places guid) are
+    // copied straight out of the deserializer's buffer, without allocating.
+    #[inline]
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        Guid::from_stored_str(s).map_err(E::custom)
+    }
+    #[inline]
+    fn visit_borrowed_str<E: de::Error>(self, s: &'de str) -> Result<Self::Value, E> {
+        self.visit_str(s)
+    }
+    #[inline]
+    fn visit_string<E: de::Error>(self, s: String) -> Result<Self::Value, E> {
+        Guid::from_stored_string(s).map_err(E::custom)
+    }
+    // Formats without a string type (or that don't check strings are UTF-8)
+    // hand us bytes instead.
+    #[inline]
+    fn visit_bytes<E: de::Error>(self, b: &[u8]) -> Result<Self::Value, E> {
+        match str::from_utf8(b) {
+            Ok(s) => self.visit_str(s),
+            Err(_) => Err(E::invalid_value(Unexpected::Bytes(b), &self)),
+        }
+    }
+    #[inline]
+    fn visit_borrowed_bytes<E: de::Error>(self, b: &'de [u8]) -> Result<Self::Value, E> {
+        self.visit_bytes(b)
+    }
+    #[inline]
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        match String::from_utf8(v) {
+            Ok(s) => self.visit_string(s),
+            Err(e) => Err(E::invalid_value(Unexpected::Bytes(e.as_bytes()), &self)),
+        }
+    }
+}
+
+// Reads the form from `Guid::to_raw_bytes`, for formats that aren't human
+// readable. Those can still hand us a string if that's all they have.
+struct RawGuidVisitor;
+impl<'de> Visitor<'de> for RawGuidVisitor {
+    type Value = Guid;
+    #[inline]
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a sync guid in its raw form")
+    }
+    #[inline]
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        GuidVisitor.visit_str(s)
+    }
+    #[inline]
+    fn visit_bytes<E: de::Error>(self, b: &[u8]) -> Result<Self::Value, E> {
+        Guid::from_raw_bytes(b).map_err(|e| match e {
+            GuidError::InvalidUtf8 => E::invalid_value(Unexpected::Bytes(b), &self),
+            e => E::custom(e),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Guid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(GuidVisitor)
+        } else {
+            deserializer.deserialize_bytes(RawGuidVisitor)
+        }
+    }
+}
+
+impl Serialize for Guid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(self.as_str())
+        } else {
+            serializer.serialize_bytes(&self.to_raw_bytes())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json;
+    use serde_test::{
+        assert_de_tokens, assert_de_tokens_error, assert_ser_tokens, assert_tokens, Compact, Configure, Token,
+    };
+    use Repr;
+
+    #[test]
+    fn test_round_trip() {
+        for id in &["abcdabcdabcd", "{5e8ea4a4-6d38-4a1e-a0bd-7b0eb7e1b8e8}"] {
+            let guid = Guid::new(id);
+            let json = serde_json::to_string(&guid).unwrap();
+            assert_eq!(json, format!("{:?}", id));
+            let parsed: Guid = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, guid);
+            assert_eq!(parsed.is_valid_for_places(), guid.is_valid_for_places());
+        }
+        assert!(serde_json::from_str::<Guid>("123").is_err());
+    }
+
+    const PLACES_GUID: &str = "abcdabcdabcd";
+    const SHORT_GUID: &str = "menu";
+    const SLOW_GUID: &str = "{5e8ea4a4-6d38-4a1e-a0bd-7b0eb7e1b8e8}";
+
+    #[test]
+    fn test_tokens() {
+        for &id in &[PLACES_GUID, SHORT_GUID, SLOW_GUID] {
+            let guid = Guid::new(id);
+            assert_tokens(&guid, &[Token::Str(id)]);
+            assert_de_tokens(&guid, &[Token::BorrowedStr(id)]);
+            assert_de_tokens(&guid, &[Token::String(id)]);
+            assert_de_tokens(&guid, &[Token::Bytes(id.as_bytes())]);
+            assert_de_tokens(&guid, &[Token::BorrowedBytes(id.as_bytes())]);
+            assert_de_tokens(&guid, &[Token::ByteBuf(id.as_bytes())]);
+        }
+        assert_de_tokens_error::<Guid>(
+            &[Token::Bytes(b"\xff\xfe")],
+            "invalid value: byte array, expected a sync guid",
+        );
+        assert_de_tokens_error::<Guid>(
+            &[Token::ByteBuf(b"\xff\xfe")],
+            "invalid value: byte array, expected a sync guid",
+        );
+        assert_de_tokens_error::<Guid>(
+            &[Token::I32(123)],
+            "invalid type: integer `123`, expected a sync guid",
+        );
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_ser_tokens(&Guid::empty(), &[Token::Str("")]);
+        if cfg!(feature = "strict") {
+            assert!(serde_json::from_str::<Guid>("\"\"").is_err());
+        } else {
+            assert_de_tokens(&Guid::empty(), &[Token::Str("")]);
+            assert_eq!(serde_json::from_str::<Guid>("\"\"").unwrap(), Guid::empty());
+        }
+        assert!(serde_json::from_str::<Guid>("null").is_err());
+        assert_eq!(serde_json::from_str::<Option<Guid>>("null").unwrap(), None);
+    }
+
+    #[test]
+    fn test_compact() {
+        assert_tokens(&Guid::new("AAAAAAAAAAAA").compact(), &[Token::Bytes(&[0; 9])]);
+        for &id in &[SHORT_GUID, SLOW_GUID] {
+            assert_tokens(&Guid::new(id).compact(), &[Token::Bytes(id.as_bytes())]);
+            assert_de_tokens(&Guid::new(id).compact(), &[Token::Str(id)]);
+        }
+        assert_tokens(&Guid::new("ninechars").compact(), &[Token::Bytes(b"\xffninechars")]);
+        assert_de_tokens_error::<Compact<Guid>>(
+            &[Token::Bytes(b"\xff\xfe")],
+            "invalid value: byte array, expected a sync guid in its raw form",
+        );
+        // The human readable form doesn't change.
+        assert_tokens(&Guid::new(PLACES_GUID).readable(), &[Token::Str(PLACES_GUID)]);
+    }
+
+    #[cfg(feature = "strict")]
+    #[test]
+    fn test_strict() {
+        assert_de_tokens_error::<Guid>(&[Token::Str("a,b")], "Guid contains an invalid character: ','");
+        assert_de_tokens_error::<Guid>(&[Token::Bytes(b"")], "Guid is empty");
+        assert_de_tokens_error::<Compact<Guid>>(&[Token::Bytes(b"a,b")], "Guid contains an invalid character: ','");
+        assert!(serde_json::from_str::<Guid>(&format!("{:?}", "x".repeat(65))).is_err());
+        // Places guids are always valid.
+        assert_de_tokens(&Guid::new("AAAAAAAAAAAA").compact(), &[Token::Bytes(&[0; 9])]);
+    }
+
+    #[test]
+    fn test_representations() {
+        // Deserializing keeps the representation we'd pick for the string, so
+        // places guids don't allocate.
+        let json = format!("[{:?},{:?},{:?}]", PLACES_GUID, SHORT_GUID, SLOW_GUID);
+        let guids: Vec<Guid> = serde_json::from_str(&json).unwrap();
+        match guids[0].0 {
+            Repr::Fast(_) => {}
+            _ => panic!("{:?} should use the fast representation", guids[0]),
+        }
+        match guids[1].0 {
+            Repr::Short(..) => {}
+            _ => panic!("{:?} should be stored inline", guids[1]),
+        }
+        match guids[2].0 {
+            Repr::Slow(_) => {}
+            _ => panic!("{:?} should be on the heap", guids[2]),
+        }
+        // Including when serde_json has to unescape the string.
+        let escaped: Guid = serde_json::from_str(r#""abcd\u0061bcdabcd""#).unwrap();
+        assert_eq!(escaped, PLACES_GUID);
+        assert!(escaped.is_valid_for_places());
+    }
+}
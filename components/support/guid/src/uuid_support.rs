@@ -0,0 +1,185 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Conversions between `Guid` and `uuid::Uuid`, for embedders that store ids
+//! as platform UUIDs (`java.util.UUID`, `NSUUID`, ...).
+//!
+//! A UUID doesn't fit in a 12 character places guid, so by default we encode
+//! its 16 bytes as 22 characters of base64url, without padding. This is short
+//! enough for the sync server, and uses the same alphabet as places guids.
+//! The usual 36 character hyphenated form is also available, for embedders
+//! that want ids other clients will recognize as UUIDs. Both are lossless,
+//! and `Guid::to_uuid` understands either. The base64url form is also short
+//! enough to store inline, so converting a UUID to it doesn't allocate.
+
+use uuid::Uuid;
+
+use {Guid, Repr, BASE64URL_ALPHABET, MAX_INLINE_GUID_LEN};
+
+const UUID_LEN: usize = 16;
+
+/// The length of a UUID encoded as base64url, without padding.
+const BASE64URL_UUID_LEN: usize = 22;
+
+/// The length of a UUID in the hyphenated form, like
+/// `"67e55044-10b1-426f-9247-bb680e5fe0c8"`.
+const HYPHENATED_UUID_LEN: usize = 36;
+
+impl Guid {
+    /// Create a guid from a UUID, encoded as 22 characters of base64url.
+    pub fn from_uuid(uuid: &Uuid) -> Self {
+        let mut encoded = [0u8; MAX_INLINE_GUID_LEN];
+        encode_base64url(uuid.as_bytes(), &mut encoded[..BASE64URL_UUID_LEN]);
+        Guid(Repr::Short(BASE64URL_UUID_LEN as u8, encoded))
+    }
+
+    /// Create a guid from a UUID in the 36 character hyphenated form.
+    pub fn from_uuid_hyphenated(uuid: &Uuid) -> Self {
+        Guid::from_string(uuid.to_hyphenated().to_string())
+    }
+
+    /// Returns the UUID this guid represents, if it's either the 22 character
+    /// base64url encoding of one (as produced by `from_uuid`) or in the 36
+    /// character hyphenated form. Other guids, including all places guids,
+    /// return `None`.
+    pub fn to_uuid(&self) -> Option<Uuid> {
+        match self.len() {
+            BASE64URL_UUID_LEN => decode_base64url(self.as_bytes()).map(Uuid::from_bytes),
+            HYPHENATED_UUID_LEN => Uuid::parse_str(self.as_str()).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl From<Uuid> for Guid {
+    #[inline]
+    fn from(uuid: Uuid) -> Guid {
+        Guid::from_uuid(&uuid)
+    }
+}
+
+impl<'a> From<&'a Uuid> for Guid {
+    #[inline]
+    fn from(uuid: &'a Uuid) -> Guid {
+        Guid::from_uuid(uuid)
+    }
+}
+
+fn encode_base64url(bytes: &[u8; UUID_LEN], encoded: &mut [u8]) {
+    let mut out = encoded.iter_mut();
+    for chunk in bytes.chunks(3) {
+        let mut group = 0u32;
+        for (i, &b) in chunk.iter().enumerate() {
+            group |= (b as u32) << (16 - 8 * i);
+        }
+        // Without padding, `n` bytes take `n + 1` characters.
+        for i in 0..=chunk.len() {
+            let index = (group >> (18 - 6 * i)) & 0x3f;
+            *out.next().unwrap() = BASE64URL_ALPHABET[index as usize];
+        }
+    }
+}
+
+fn decode_base64url(encoded: &[u8]) -> Option<[u8; UUID_LEN]> {
+    if encoded.len() != BASE64URL_UUID_LEN {
+        return None;
+    }
+    let mut bytes = [0u8; UUID_LEN];
+    let mut len = 0;
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    for &c in encoded {
+        bits = (bits << 6) | base64url_value(c)? as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes[len] = (bits >> bit_count) as u8;
+            len += 1;
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    // 22 characters hold 132 bits, so there are 4 left over. They must be
+    // zero, otherwise several encodings would decode to the same UUID.
+    if bits != 0 {
+        return None;
+    }
+    Some(bytes)
+}
+
+#[inline]
+fn base64url_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'-' => Some(62),
+        b'_' => Some(63),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const UUIDS: &[&str] = &[
+        "00000000-0000-0000-0000-000000000000",
+        "ffffffff-ffff-ffff-ffff-ffffffffffff",
+        "67e55044-10b1-426f-9247-bb680e5fe0c8",
+        "936da01f-9abd-4d9d-80c7-02af85c822a8",
+    ];
+
+    #[test]
+    fn test_base64url_round_trip() {
+        for s in UUIDS {
+            let uuid = Uuid::parse_str(s).unwrap();
+            let guid = Guid::from_uuid(&uuid);
+            assert_eq!(guid.len(), BASE64URL_UUID_LEN);
+            assert!(guid.is_valid_for_sync_server());
+            assert!(guid.bytes().all(|b| BASE64URL_ALPHABET.contains(&b)));
+            assert_eq!(guid.to_uuid(), Some(uuid));
+            assert_eq!(Guid::from(uuid), guid);
+            // It's stored inline, like it would be if we'd parsed it.
+            match guid.0 {
+                Repr::Short(..) => {}
+                _ => panic!("{:?} should be stored inline", guid),
+            }
+            assert_eq!(Guid::new(guid.as_str()), guid);
+        }
+    }
+
+    #[test]
+    fn test_base64url_encoding() {
+        // Checked against `base64.urlsafe_b64encode` in Python.
+        let uuid = Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        assert_eq!(Guid::from_uuid(&uuid), "Z-VQRBCxQm-SR7toDl_gyA");
+        let zero = Uuid::parse_str(UUIDS[0]).unwrap();
+        assert_eq!(Guid::from_uuid(&zero), "AAAAAAAAAAAAAAAAAAAAAA");
+    }
+
+    #[test]
+    fn test_hyphenated_round_trip() {
+        for s in UUIDS {
+            let uuid = Uuid::parse_str(s).unwrap();
+            let guid = Guid::from_uuid_hyphenated(&uuid);
+            assert_eq!(guid, *s);
+            assert_eq!(guid.to_uuid(), Some(uuid));
+        }
+        // Other clients may upload upper case UUIDs.
+        let upper = Guid::new("67E55044-10B1-426F-9247-BB680E5FE0C8");
+        assert_eq!(upper.to_uuid(), Some(Uuid::parse_str(UUIDS[2]).unwrap()));
+    }
+
+    #[test]
+    fn test_not_uuids() {
+        assert_eq!(Guid::new("aaaabbbbcccc").to_uuid(), None);
+        assert_eq!(Guid::new("").to_uuid(), None);
+        // Right length, but with a character outside the alphabet.
+        assert_eq!(Guid::new("Z-VQRBCxQm-SR7toDl_gy=").to_uuid(), None);
+        // The last character has non-zero padding bits.
+        assert_eq!(Guid::new("Z-VQRBCxQm-SR7toDl_gyB").to_uuid(), None);
+        // Right length for the hyphenated form, but not a UUID.
+        assert_eq!(Guid::new(&"x".repeat(HYPHENATED_UUID_LEN)).to_uuid(), None);
+    }
+}
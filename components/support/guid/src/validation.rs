@@ -0,0 +1,112 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use {is_valid_places_guid, is_valid_sync_server_guid, Guid};
+
+/// The maximum number of invalid ids we record in a `ValidationReport`. Past
+/// this, we only count them, so that validating a large batch of garbage
+/// doesn't copy all of it.
+pub const MAX_REPORTED_INVALID: usize = 20;
+
+/// The result of `Guid::validate_all`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// The number of ids we checked.
+    pub total: usize,
+
+    /// The number of ids that failed `Guid::is_valid_for_sync_server`.
+    pub invalid_count: usize,
+
+    /// The number of ids that are valid for places. Every one of these is
+    /// also valid for the sync server.
+    pub places_valid_count: usize,
+
+    /// The index and value of the first `MAX_REPORTED_INVALID` invalid ids.
+    pub invalid: Vec<(usize, String)>,
+}
+
+impl ValidationReport {
+    /// Returns true if every id we checked is valid for the sync server.
+    #[inline]
+    pub fn all_valid(&self) -> bool {
+        self.invalid_count == 0
+    }
+
+    /// Returns true if there were more invalid ids than we recorded in
+    /// `invalid`.
+    #[inline]
+    pub fn is_truncated(&self) -> bool {
+        self.invalid_count > self.invalid.len()
+    }
+}
+
+impl Guid {
+    /// Check a batch of ids against the sync server's rules (see
+    /// `is_valid_for_sync_server`), without constructing a `Guid` for each.
+    pub fn validate_all<'a>(ids: impl Iterator<Item = &'a str>) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        for (i, id) in ids.enumerate() {
+            report.total += 1;
+            let bytes = id.as_bytes();
+            if is_valid_places_guid(bytes) {
+                report.places_valid_count += 1;
+            } else if !is_valid_sync_server_guid(bytes) {
+                report.invalid_count += 1;
+                if report.invalid.len() < MAX_REPORTED_INVALID {
+                    report.invalid.push((i, id.to_owned()));
+                }
+            }
+        }
+        report
+    }
+
+    /// Like `validate_all(ids).all_valid()`, but stops at the first invalid
+    /// id, and doesn't allocate.
+    #[inline]
+    pub fn all_valid_for_sync_server<'a>(mut ids: impl Iterator<Item = &'a str>) -> bool {
+        ids.all(|id| is_valid_sync_server_guid(id.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_all() {
+        let ids = ["aaaabbbbcccc", "short", "", "has,comma", "bbbbccccdddd", "\u{1F600}"];
+        let report = Guid::validate_all(ids.iter().cloned());
+        assert_eq!(report.total, 6);
+        assert_eq!(report.places_valid_count, 2);
+        assert_eq!(report.invalid_count, 3);
+        assert_eq!(
+            report.invalid,
+            vec![
+                (2, "".to_owned()),
+                (3, "has,comma".to_owned()),
+                (5, "\u{1F600}".to_owned()),
+            ]
+        );
+        assert!(!report.all_valid());
+        assert!(!report.is_truncated());
+
+        assert!(!Guid::all_valid_for_sync_server(ids.iter().cloned()));
+        assert!(Guid::all_valid_for_sync_server(ids[..2].iter().cloned()));
+        assert!(Guid::validate_all(ids[..2].iter().cloned()).all_valid());
+
+        let empty = Guid::validate_all(Vec::<&str>::new().into_iter());
+        assert_eq!(empty, ValidationReport::default());
+        assert!(empty.all_valid());
+    }
+
+    #[test]
+    fn test_validate_all_truncated() {
+        let bad = vec!["a,b"; MAX_REPORTED_INVALID + 5];
+        let report = Guid::validate_all(bad.into_iter());
+        assert_eq!(report.invalid_count, MAX_REPORTED_INVALID + 5);
+        assert_eq!(report.invalid.len(), MAX_REPORTED_INVALID);
+        assert_eq!(report.invalid[MAX_REPORTED_INVALID - 1].0, MAX_REPORTED_INVALID - 1);
+        assert!(report.is_truncated());
+    }
+}
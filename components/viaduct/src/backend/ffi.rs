@@ -0,0 +1,177 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A backend which hands every request to a callback registered by the host
+//! application.
+//!
+//! Requests and responses cross the FFI as JSON strings. The request string is
+//! owned by Rust and only valid for the duration of the callback. The response
+//! string returned by the callback must have been produced by passing the
+//! host's JSON to `viaduct_alloc_string`, which copies it into memory Rust
+//! owns (and will free).
+
+use super::{set_backend, Backend};
+use error::*;
+use serde_json;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+use url::Url;
+use {Headers, Request, Response};
+
+pub type FetchCallback = unsafe extern "C" fn(request: *const c_char) -> *mut c_char;
+
+#[derive(Serialize)]
+struct FfiRequest<'a> {
+    method: &'static str,
+    url: &'a str,
+    headers: &'a Headers,
+    body: Option<&'a [u8]>,
+}
+
+#[derive(Deserialize)]
+struct FfiResponse {
+    /// Set by the host if the request could not be performed at all (as
+    /// opposed to the server returning an error status).
+    #[serde(default)]
+    exception_message: Option<String>,
+    #[serde(default)]
+    url: Option<String>,
+    /// Required unless `exception_message` is set.
+    #[serde(default)]
+    status: Option<u16>,
+    #[serde(default)]
+    headers: Headers,
+    #[serde(default)]
+    body: Vec<u8>,
+}
+
+pub struct FfiBackend {
+    callback: FetchCallback,
+}
+
+impl FfiBackend {
+    pub fn new(callback: FetchCallback) -> FfiBackend {
+        FfiBackend { callback }
+    }
+}
+
+impl Backend for FfiBackend {
+    fn send(&self, request: Request) -> Result<Response> {
+        let json = serde_json::to_string(&FfiRequest {
+            method: request.method.as_str(),
+            url: request.url.as_str(),
+            headers: &request.headers,
+            body: request.body.as_ref().map(|b| b.as_slice()),
+        })?;
+        let json = CString::new(json).map_err(|e| ErrorKind::BackendError(e.to_string()))?;
+        let result = unsafe { (self.callback)(json.as_ptr()) };
+        if result.is_null() {
+            return Err(ErrorKind::BackendError("Callback returned a null response".into()).into());
+        }
+        let result = unsafe { CString::from_raw(result) };
+        let resp: FfiResponse = serde_json::from_slice(result.as_bytes())?;
+        if let Some(message) = resp.exception_message {
+            return Err(ErrorKind::NetworkError(message).into());
+        }
+        let status = match resp.status {
+            Some(status) => status,
+            None => return Err(ErrorKind::BackendError("Callback returned a response without a status".into()).into()),
+        };
+        let url = match resp.url {
+            Some(u) => Url::parse(&u)?,
+            None => request.url,
+        };
+        Ok(Response {
+            request_method: request.method,
+            url,
+            status,
+            headers: resp
+                .headers
+                .into_iter()
+                .map(|(k, v)| (k.to_ascii_lowercase(), v))
+                .collect(),
+            body: resp.body,
+        })
+    }
+}
+
+/// Route all requests made by the components through `callback`. Must be
+/// called before any requests are made. Returns 1 on success and 0 if a
+/// backend was already installed (in which case the callback is ignored).
+#[no_mangle]
+pub extern "C" fn viaduct_initialize(callback: FetchCallback) -> u8 {
+    match set_backend(Box::new(FfiBackend::new(callback))) {
+        Ok(()) => 1,
+        Err(e) => {
+            warn!("viaduct_initialize failed: {}", e);
+            0
+        }
+    }
+}
+
+/// Copy a host-allocated, nul-terminated string into memory owned by Rust.
+/// The fetch callback must return its response through this function.
+#[no_mangle]
+pub unsafe extern "C" fn viaduct_alloc_string(s: *const c_char) -> *mut c_char {
+    if s.is_null() {
+        return ptr::null_mut();
+    }
+    CStr::from_ptr(s).to_owned().into_raw()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Method;
+
+    unsafe fn respond(json: &'static [u8]) -> *mut c_char {
+        viaduct_alloc_string(json.as_ptr() as *const c_char)
+    }
+
+    unsafe extern "C" fn ok_callback(_request: *const c_char) -> *mut c_char {
+        respond(b"{\"status\": 201, \"headers\": {\"ETag\": \"abc\"}, \"body\": [104, 105]}\0")
+    }
+
+    unsafe extern "C" fn exception_callback(_request: *const c_char) -> *mut c_char {
+        respond(b"{\"exception_message\": \"Offline\"}\0")
+    }
+
+    unsafe extern "C" fn no_status_callback(_request: *const c_char) -> *mut c_char {
+        respond(b"{\"body\": []}\0")
+    }
+
+    unsafe extern "C" fn null_callback(_request: *const c_char) -> *mut c_char {
+        ptr::null_mut()
+    }
+
+    fn send(callback: FetchCallback) -> Result<Response> {
+        let url = Url::parse("https://www.example.com/path").unwrap();
+        FfiBackend::new(callback).send(Request::put(url).body("hi"))
+    }
+
+    #[test]
+    fn test_ffi_response() {
+        let resp = send(ok_callback).unwrap();
+        assert_eq!(resp.request_method, Method::Put);
+        assert_eq!(resp.url.as_str(), "https://www.example.com/path");
+        assert_eq!(resp.status, 201);
+        assert_eq!(resp.header("etag"), Some("abc"));
+        assert_eq!(resp.body, b"hi");
+    }
+
+    #[test]
+    fn test_ffi_errors() {
+        match *send(exception_callback).unwrap_err().kind() {
+            ErrorKind::NetworkError(ref message) => assert_eq!(message, "Offline"),
+            ref kind => panic!("Unexpected error {:?}", kind),
+        }
+        for &callback in &[no_status_callback as FetchCallback, null_callback] {
+            match *send(callback).unwrap_err().kind() {
+                ErrorKind::BackendError(_) => {}
+                ref kind => panic!("Unexpected error {:?}", kind),
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use error::*;
+use std::sync::{Arc, RwLock};
+use {Request, Response};
+
+pub mod ffi;
+pub mod native;
+
+/// Something that can actually perform an HTTP request.
+///
+/// There is exactly one backend per process. If none has been installed by
+/// the time the first request is sent, we fall back to `native::ReqwestBackend`.
+pub trait Backend: Send + Sync + 'static {
+    fn send(&self, request: Request) -> Result<Response>;
+}
+
+lazy_static! {
+    // The lock is only held long enough to clone the backend out, never while
+    // sending, so backends can be slow (or send requests of their own) without
+    // blocking anyone else.
+    static ref BACKEND: RwLock<Option<Arc<Backend>>> = RwLock::new(None);
+}
+
+/// Install the backend used for all requests in this process. This may only be
+/// done once, and should be done before any component makes a request -- it's
+/// an error to call this after the default backend has been installed.
+pub fn set_backend(backend: Box<Backend>) -> Result<()> {
+    let mut guard = BACKEND.write().unwrap();
+    if guard.is_some() {
+        return Err(ErrorKind::BackendAlreadyInitialized.into());
+    }
+    *guard = Some(Arc::from(backend));
+    Ok(())
+}
+
+fn get_backend() -> Arc<Backend> {
+    if let Some(ref backend) = *BACKEND.read().unwrap() {
+        return backend.clone();
+    }
+    // Nobody registered anything, so install the native backend. Someone else
+    // may have beaten us to it between dropping the read lock and taking the
+    // write lock, so we only fill it in if it's still empty.
+    let mut guard = BACKEND.write().unwrap();
+    if guard.is_none() {
+        info!("No HTTP backend registered, using the native backend");
+        *guard = Some(Arc::new(native::ReqwestBackend::new()));
+    }
+    guard.as_ref().unwrap().clone()
+}
+
+pub(crate) fn send(request: Request) -> Result<Response> {
+    get_backend().send(request)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Url;
+
+    // Echoes the request back, after sending the same request to `/inner`
+    // if it was for `/outer`.
+    struct MockBackend;
+
+    impl Backend for MockBackend {
+        fn send(&self, request: Request) -> Result<Response> {
+            if request.url.path() == "/outer" {
+                let inner = request.url.join("/inner").unwrap();
+                Request::get(inner).send()?;
+            }
+            Ok(Response {
+                request_method: request.method,
+                url: request.url,
+                status: 200,
+                headers: request.headers,
+                body: request.body.unwrap_or_default(),
+            })
+        }
+    }
+
+    // This is the only test that installs a backend, since there can only be
+    // one per process. The others call their backends directly.
+    #[test]
+    fn test_set_backend() {
+        set_backend(Box::new(MockBackend)).unwrap();
+        match set_backend(Box::new(MockBackend)) {
+            Err(ref e) => match e.kind() {
+                ErrorKind::BackendAlreadyInitialized => {}
+                kind => panic!("Unexpected error {:?}", kind),
+            },
+            Ok(()) => panic!("Installed a second backend"),
+        }
+
+        let url = Url::parse("https://www.example.com/outer").unwrap();
+        let resp = Request::post(url.clone()).body("hi").send().unwrap();
+        assert_eq!(resp.url, url);
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body, b"hi");
+    }
+}
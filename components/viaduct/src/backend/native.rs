@@ -0,0 +1,84 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use super::Backend;
+use error::*;
+use reqwest;
+use std::time::Duration;
+use {Headers, Method, Request, Response};
+
+/// How long we wait for a request before giving up on it. The host's network
+/// stack applies its own timeouts when the FFI backend is in use.
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// The default backend, which performs requests in-process using reqwest.
+pub struct ReqwestBackend {
+    client: reqwest::Client,
+}
+
+impl ReqwestBackend {
+    pub fn new() -> ReqwestBackend {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+            .build()
+            .expect("Failed to initialize the reqwest client");
+        ReqwestBackend { client }
+    }
+}
+
+impl Default for ReqwestBackend {
+    fn default() -> ReqwestBackend {
+        ReqwestBackend::new()
+    }
+}
+
+fn to_reqwest_method(method: Method) -> reqwest::Method {
+    match method {
+        Method::Get => reqwest::Method::GET,
+        Method::Head => reqwest::Method::HEAD,
+        Method::Post => reqwest::Method::POST,
+        Method::Put => reqwest::Method::PUT,
+        Method::Delete => reqwest::Method::DELETE,
+        Method::Patch => reqwest::Method::PATCH,
+    }
+}
+
+impl Backend for ReqwestBackend {
+    fn send(&self, request: Request) -> Result<Response> {
+        let request_method = request.method;
+        let mut builder = self
+            .client
+            .request(to_reqwest_method(request_method), request.url.clone());
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        if let Some(body) = request.body {
+            builder = builder.body(body);
+        }
+        let mut resp = builder
+            .send()
+            .map_err(|e| ErrorKind::NetworkError(e.to_string()))?;
+
+        let mut headers = Headers::new();
+        for (name, value) in resp.headers() {
+            match value.to_str() {
+                Ok(v) => {
+                    headers.insert(name.as_str().to_ascii_lowercase(), v.to_string());
+                }
+                Err(_) => warn!("Dropping non-ASCII response header {}", name),
+            }
+        }
+        let mut body = Vec::new();
+        resp.copy_to(&mut body)
+            .map_err(|e| ErrorKind::NetworkError(e.to_string()))?;
+
+        Ok(Response {
+            request_method,
+            url: resp.url().clone(),
+            status: resp.status().as_u16(),
+            headers,
+            body,
+        })
+    }
+}
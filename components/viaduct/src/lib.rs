@@ -0,0 +1,216 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Viaduct is the HTTP layer shared by the components in this repository.
+//!
+//! Components build a [`Request`] and call [`Request::send`]; where that
+//! request actually goes is decided once per process by the installed
+//! [`Backend`]. By default we use a native backend built on reqwest, but a
+//! host application can instead register a callback over the FFI (see
+//! `viaduct_initialize`) so that every request made by every component is
+//! routed through the host's own network stack, picking up its proxy
+//! configuration, DNS-over-HTTPS settings, instrumentation, and so on.
+
+extern crate failure;
+#[macro_use]
+extern crate failure_derive;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+extern crate reqwest;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate url;
+
+use std::collections::BTreeMap;
+
+pub use url::Url;
+
+pub mod backend;
+pub mod error;
+pub mod status_codes;
+
+pub use backend::{set_backend, Backend};
+pub use error::*;
+
+/// Header names are stored lowercased, since HTTP header names are case
+/// insensitive.
+pub type Headers = BTreeMap<String, String>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    pub url: Url,
+    pub headers: Headers,
+    pub body: Option<Vec<u8>>,
+}
+
+impl Request {
+    pub fn new(method: Method, url: Url) -> Request {
+        Request {
+            method,
+            url,
+            headers: Headers::new(),
+            body: None,
+        }
+    }
+
+    pub fn get(url: Url) -> Request {
+        Request::new(Method::Get, url)
+    }
+
+    pub fn post(url: Url) -> Request {
+        Request::new(Method::Post, url)
+    }
+
+    pub fn put(url: Url) -> Request {
+        Request::new(Method::Put, url)
+    }
+
+    pub fn delete(url: Url) -> Request {
+        Request::new(Method::Delete, url)
+    }
+
+    /// Add a header to the request, replacing any existing header with the
+    /// same (case-insensitive) name. Header names and values may not contain
+    /// control characters.
+    pub fn header<N, V>(mut self, name: N, value: V) -> Result<Request>
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        let name = name.into().to_ascii_lowercase();
+        let value = value.into();
+        if name.is_empty() || name.chars().chain(value.chars()).any(|c| c.is_control()) {
+            return Err(ErrorKind::RequestHeaderError(name).into());
+        }
+        self.headers.insert(name, value);
+        Ok(self)
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Request {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Set the body to the JSON serialization of `v`, and set the
+    /// `content-type` header accordingly.
+    pub fn json<T: ?Sized + serde::Serialize>(mut self, v: &T) -> Result<Request> {
+        self.body = Some(serde_json::to_vec(v)?);
+        self.headers
+            .insert("content-type".into(), "application/json".into());
+        Ok(self)
+    }
+
+    /// Send the request using the currently installed backend.
+    pub fn send(self) -> Result<Response> {
+        backend::send(self)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub request_method: Method,
+    pub url: Url,
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    #[inline]
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    #[inline]
+    pub fn is_client_error(&self) -> bool {
+        self.status >= 400 && self.status < 500
+    }
+
+    #[inline]
+    pub fn is_server_error(&self) -> bool {
+        self.status >= 500 && self.status < 600
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(|s| s.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json<'a, T>(&'a self) -> Result<T>
+    where
+        T: serde::Deserialize<'a>,
+    {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_headers() {
+        let url = Url::parse("https://www.example.com").unwrap();
+        let req = Request::get(url)
+            .header("X-Foo", "bar")
+            .unwrap()
+            .header("x-foo", "baz")
+            .unwrap();
+        assert_eq!(req.headers.len(), 1);
+        assert_eq!(req.headers["x-foo"], "baz");
+        let url = Url::parse("https://www.example.com").unwrap();
+        assert!(Request::get(url).header("X-Foo", "bar\r\nX-Evil: 1").is_err());
+    }
+
+    #[test]
+    fn test_response_helpers() {
+        let mut headers = Headers::new();
+        headers.insert("content-type".into(), "application/json".into());
+        let resp = Response {
+            request_method: Method::Get,
+            url: Url::parse("https://www.example.com").unwrap(),
+            status: 404,
+            headers,
+            body: b"{\"a\": 1}".to_vec(),
+        };
+        assert!(!resp.is_success());
+        assert!(resp.is_client_error());
+        assert_eq!(resp.header("Content-Type"), Some("application/json"));
+        let v: serde_json::Value = resp.json().unwrap();
+        assert_eq!(v["a"], 1);
+    }
+}
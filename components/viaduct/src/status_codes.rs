@@ -0,0 +1,25 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Named constants for the HTTP status codes the components care about, for
+//! comparing against `Response::status`.
+
+pub const OK: u16 = 200;
+pub const CREATED: u16 = 201;
+pub const ACCEPTED: u16 = 202;
+pub const NO_CONTENT: u16 = 204;
+
+pub const NOT_MODIFIED: u16 = 304;
+
+pub const BAD_REQUEST: u16 = 400;
+pub const UNAUTHORIZED: u16 = 401;
+pub const FORBIDDEN: u16 = 403;
+pub const NOT_FOUND: u16 = 404;
+pub const CONFLICT: u16 = 409;
+pub const GONE: u16 = 410;
+pub const PRECONDITION_FAILED: u16 = 412;
+pub const TOO_MANY_REQUESTS: u16 = 429;
+
+pub const INTERNAL_SERVER_ERROR: u16 = 500;
+pub const SERVICE_UNAVAILABLE: u16 = 503;
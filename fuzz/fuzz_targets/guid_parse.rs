@@ -0,0 +1,25 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate serde_json;
+extern crate sync_guid;
+
+use sync_guid::Guid;
+
+fuzz_target!(|data: &[u8]| {
+    // Guids from other clients arrive as bytes from the database, or as JSON
+    // strings in records.
+    if let Some(guid) = Guid::try_from_bytes(data) {
+        assert_eq!(guid.as_bytes(), data);
+        assert_eq!(Guid::new(guid.as_str()), guid);
+        if guid.is_valid_for_places() {
+            assert!(guid.is_valid_for_sync_server());
+        }
+        let json = serde_json::to_string(&guid).expect("Guids should serialize");
+        assert_eq!(serde_json::from_str::<Guid>(&json).expect("Guids should roundtrip"), guid);
+    }
+    let _ = serde_json::from_slice::<Guid>(data);
+});
@@ -0,0 +1,30 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate ffi_support;
+extern crate places;
+extern crate places_ffi;
+
+use std::cell::RefCell;
+
+use ffi_support::fuzzing::{check_ffi_call, FuzzArgs};
+use places::PlacesDb;
+
+thread_local! {
+    // Setting up the schema is slow, so every run shares a connection.
+    static CONN: RefCell<PlacesDb> = RefCell::new(
+        PlacesDb::open_in_memory(None).expect("Should open a database"));
+}
+
+fuzz_target!(|data: &[u8]| {
+    let json = FuzzArgs::new(data).next_c_string();
+    CONN.with(|conn| {
+        let conn: *mut PlacesDb = &mut *conn.borrow_mut();
+        check_ffi_call(|error| unsafe {
+            places_ffi::places_note_observation(conn, json.as_ptr(), error)
+        });
+    });
+});
@@ -2,7 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use fxa_client::Profile;
+use fxa_client::{AccountState, Profile};
 use fxa_client::{OAuthInfo, SyncKeys};
 use fxa_str_free;
 use libc::c_char;
@@ -34,14 +34,12 @@ impl From<SyncKeys> for SyncKeysC {
 #[repr(C)]
 pub struct OAuthInfoC {
     pub access_token: *mut c_char,
-    pub keys: *mut c_char,
     pub scope: *mut c_char,
 }
 
 impl Drop for OAuthInfoC {
     fn drop(&mut self) {
         fxa_str_free(self.access_token);
-        fxa_str_free(self.keys);
         fxa_str_free(self.scope);
     }
 }
@@ -51,10 +49,6 @@ impl From<OAuthInfo> for OAuthInfoC {
         let scopes = info.scopes.join(" ");
         OAuthInfoC {
             access_token: string_to_c_char(info.access_token),
-            keys: match info.keys {
-                Some(keys) => string_to_c_char(keys),
-                None => std::ptr::null_mut(),
-            },
             scope: string_to_c_char(scopes),
         }
     }
@@ -89,3 +83,23 @@ impl From<Profile> for ProfileC {
         }
     }
 }
+
+/// An `AccountState`, for [fxa_get_account_state] and
+/// [fxa_retry_authentication].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccountStateC {
+    Disconnected = 0,
+    Connected = 1,
+    AuthProblems = 2,
+}
+
+impl From<AccountState> for AccountStateC {
+    fn from(state: AccountState) -> Self {
+        match state {
+            AccountState::Disconnected => AccountStateC::Disconnected,
+            AccountState::Connected => AccountStateC::Connected,
+            AccountState::AuthProblems => AccountStateC::AuthProblems,
+        }
+    }
+}
@@ -2,22 +2,32 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+#[macro_use]
+extern crate ffi_support;
 extern crate fxa_client;
 extern crate libc;
+extern crate serde_json;
+extern crate viaduct;
 
 mod ctypes;
 mod util;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::panic::AssertUnwindSafe;
 use std::ptr;
 
 use ctypes::*;
 use fxa_client::errors::Error as InternalError;
 use fxa_client::errors::ErrorKind as InternalErrorKind;
-use fxa_client::{Config, FirefoxAccount, PersistCallback, WebChannelResponse};
+use fxa_client::{
+    Capability, CommandDataCallback, Config, FirefoxAccount, PersistCallback, WebChannelResponse,
+};
 use libc::c_char;
 use util::*;
 
+// Re-exported so that they're exported from this library, letting the host
+// route our requests through its own network stack.
+pub use viaduct::backend::ffi::{viaduct_alloc_string, viaduct_initialize};
+
 #[repr(C)]
 #[derive(Debug)]
 pub enum ErrorCode {
@@ -25,6 +35,12 @@ pub enum ErrorCode {
     Other = 1,
     AuthenticationError = 2,
     InternalPanic = 3,
+    /// The persisted account is for another FxA environment. See
+    /// [fxa_from_json_for_config].
+    EnvironmentMismatch = 4,
+    /// The account needs to be reauthenticated. See
+    /// [fxa_get_account_state].
+    AuthProblems = 5,
 }
 
 /// An error struct containing an error code and a description string. Callers
@@ -58,6 +74,14 @@ impl From<InternalError> for ExternError {
                 code: ErrorCode::AuthenticationError,
                 message: string_to_c_char(err.to_string()),
             },
+            InternalErrorKind::AuthProblems => ExternError {
+                code: ErrorCode::AuthProblems,
+                message: string_to_c_char(err.to_string()),
+            },
+            InternalErrorKind::EnvironmentMismatch { .. } => ExternError {
+                code: ErrorCode::EnvironmentMismatch,
+                message: string_to_c_char(err.to_string()),
+            },
             err => ExternError {
                 code: ErrorCode::Other,
                 message: string_to_c_char(err.to_string()),
@@ -126,6 +150,24 @@ where
     try_call_with_result(out_error, callback).unwrap_or(default)
 }
 
+/// Like `call_with_result`, but for callbacks that can't fail, other than by
+/// panicking. The FFI function should still take an `ExternError`, since a
+/// panic is reported there.
+unsafe fn call_with_output<R, F>(out_error: *mut ExternError, callback: F) -> *mut R
+where
+    F: std::panic::UnwindSafe + FnOnce() -> R,
+{
+    call_with_result(out_error, || Ok(callback()))
+}
+
+/// `call_with_output` for `call_with_result_by_value`.
+unsafe fn call_with_output_by_value<R, F>(out_error: *mut ExternError, default: R, callback: F) -> R
+where
+    F: std::panic::UnwindSafe + FnOnce() -> R,
+{
+    call_with_result_by_value(out_error, default, || Ok(callback()))
+}
+
 /// Helper for the fairly common case where we want to return a string to C.
 unsafe fn call_with_string_result<R, F>(out_error: *mut ExternError, callback: F) -> *mut c_char
 where
@@ -251,12 +293,12 @@ pub unsafe extern "C" fn fxa_new(
     redirect_uri: *const c_char,
     err: *mut ExternError,
 ) -> *mut FirefoxAccount {
-    call_with_result(err, || {
+    call_with_output(err, || {
         assert!(!config.is_null());
         let client_id = c_char_to_string(client_id);
         let redirect_uri = c_char_to_string(redirect_uri);
         let config = Box::from_raw(config);
-        Ok(FirefoxAccount::new(*config, client_id, redirect_uri))
+        FirefoxAccount::new(*config, client_id, redirect_uri)
     })
 }
 
@@ -274,6 +316,54 @@ pub unsafe extern "C" fn fxa_from_json(
     call_with_result(err, || FirefoxAccount::from_json(c_char_to_string(json)))
 }
 
+/// Like [fxa_from_json], but fails with `ErrorCode::EnvironmentMismatch` if the account was
+/// persisted with a config for a different FxA environment than `config` (stage instead of
+/// production, for example).
+///
+/// Note: Unlike the functions that take ownership of a `Config`, this only borrows it, so the
+/// caller must still free it.
+///
+/// # Safety
+///
+/// A destructor [fxa_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_from_json_for_config(
+    json: *const c_char,
+    config: *const Config,
+    err: *mut ExternError,
+) -> *mut FirefoxAccount {
+    call_with_result(err, || {
+        assert!(!config.is_null());
+        FirefoxAccount::from_json_for_config(c_char_to_string(json), &*config)
+    })
+}
+
+/// Switches a [FirefoxAccount] to another FxA environment at runtime. If the account is signed in,
+/// this fails unless `wipe` is true, in which case its tokens (and session) are forgotten first.
+///
+/// Note: This takes ownership of `Config`, even if it fails.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_switch_config(
+    fxa: *mut FirefoxAccount,
+    config: *mut Config,
+    wipe: bool,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        assert!(!config.is_null());
+        let fxa = &mut *fxa;
+        let config = Box::from_raw(config);
+        if wipe {
+            fxa.wipe_and_switch_config(*config);
+            Ok(())
+        } else {
+            fxa.switch_config(*config)
+        }
+    });
+}
+
 /// Serializes the state of a [FirefoxAccount] instance. It can be restored later with [fxa_from_json].
 ///
 /// It is the responsability of the caller to persist that serialized state regularly (after operations that mutate [FirefoxAccount])
@@ -295,6 +385,29 @@ pub unsafe extern "C" fn fxa_to_json(
     })
 }
 
+/// Serializes the state of a [FirefoxAccount] instance like [fxa_to_json], but without any key
+/// material, for handing the account to a less trusted process (for example, an Android service
+/// that only needs access tokens). An instance restored from this with [fxa_from_json] can get and
+/// refresh access tokens, but they won't include scoped keys.
+///
+/// The restored instance's state should never be persisted over the original's.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_to_json_scrubbed(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        fxa.to_json_scrubbed()
+    })
+}
+
 /// Registers a callback that gets called every time the FirefoxAccount internal state
 /// changed and therefore need to be persisted.
 #[no_mangle]
@@ -304,7 +417,7 @@ pub unsafe extern "C" fn fxa_register_persist_callback(
     error: *mut ExternError,
 ) {
     AssertUnwindSafe(callback);
-    call_with_result(error, || {
+    call_with_output(error, || {
         assert!(!fxa.is_null());
         let fxa = &mut *fxa;
         fxa.register_persist_callback(PersistCallback::new(move |json| {
@@ -312,7 +425,6 @@ pub unsafe extern "C" fn fxa_register_persist_callback(
             callback(s);
             drop(CString::from_raw(s));
         }));
-        Ok(()) // call_with_result needs a result
     });
 }
 
@@ -322,11 +434,79 @@ pub unsafe extern "C" fn fxa_unregister_persist_callback(
     fxa: *mut FirefoxAccount,
     error: *mut ExternError,
 ) {
-    call_with_result(error, || {
+    call_with_output(error, || {
         assert!(!fxa.is_null());
         let fxa = &mut *fxa;
         fxa.unregister_persist_callback();
-        Ok(()) // call_with_result needs a result
+    });
+}
+
+/// Registers a callback that makes the data other devices need to send us
+/// the command for a capability. It's called with the capability's name
+/// (for example `"SendTab"`) and the sync key as JSON, and returns the data,
+/// or null to leave the capability out. The returned string must be
+/// allocated with `malloc` (for example with `strdup`); Rust frees it.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_register_command_data_callback(
+    fxa: *mut FirefoxAccount,
+    callback: extern "C" fn(capability: *const c_char, sync_key_json: *const c_char)
+        -> *mut c_char,
+    error: *mut ExternError,
+) {
+    call_with_output(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        fxa.register_command_data_callback(CommandDataCallback::new(
+            move |capability, sync_key| {
+                let capability = match serde_json::to_value(capability) {
+                    Ok(serde_json::Value::String(name)) => name,
+                    _ => return None,
+                };
+                let sync_key_json = serde_json::to_string(sync_key).ok()?;
+                let capability = string_to_c_char(capability);
+                let sync_key_json = string_to_c_char(sync_key_json);
+                let data = callback(capability, sync_key_json);
+                drop(CString::from_raw(capability));
+                drop(CString::from_raw(sync_key_json));
+                if data.is_null() {
+                    return None;
+                }
+                let result = CStr::from_ptr(data).to_str().map(String::from).ok();
+                libc::free(data as *mut libc::c_void);
+                result
+            },
+        ));
+    });
+}
+
+/// Unregisters a previous registered command data callback.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_unregister_command_data_callback(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) {
+    call_with_output(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        fxa.unregister_command_data_callback();
+    });
+}
+
+/// Sets the capabilities to register for this device, as a JSON array (for
+/// example `["SendTab"]`), and registers them if they changed. Passing `[]`
+/// unregisters them. A command data callback must be registered first.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_ensure_capabilities(
+    fxa: *mut FirefoxAccount,
+    capabilities_json: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let capabilities_json = c_char_to_string(capabilities_json);
+        let capabilities: Vec<Capability> = serde_json::from_str(capabilities_json)?;
+        fxa.ensure_capabilities(&capabilities)
     });
 }
 
@@ -370,6 +550,48 @@ pub unsafe extern "C" fn fxa_get_token_server_endpoint_url(
     })
 }
 
+/// Get the URL of the account settings page, with `entrypoint` saying where
+/// in the app the user came from. Needs a token with the `profile` scope.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_get_manage_account_url(
+    fxa: *mut FirefoxAccount,
+    entrypoint: *const c_char,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let entrypoint = c_char_to_string(entrypoint);
+        fxa.get_manage_account_url(entrypoint)
+    })
+}
+
+/// Like [fxa_get_manage_account_url], for the page listing the account's
+/// devices.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_get_manage_devices_url(
+    fxa: *mut FirefoxAccount,
+    entrypoint: *const c_char,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let entrypoint = c_char_to_string(entrypoint);
+        fxa.get_manage_devices_url(entrypoint)
+    })
+}
+
 /// Generate an assertion for a specified audience. Requires to be in a `Married` state.
 /// Note that new clients don't use assertions and use Oauth flows instead.
 ///
@@ -470,7 +692,8 @@ pub unsafe extern "C" fn fxa_begin_oauth_flow(
     })
 }
 
-/// Finish an OAuth flow initiated by [fxa_begin_oauth_flow] and returns token/keys.
+/// Finish an OAuth flow initiated by [fxa_begin_oauth_flow] and returns the token. Use
+/// [fxa_get_access_token] to get the keys, if the flow requested them.
 ///
 /// This resulting token might not have all the `scopes` the caller have requested (e.g. the user
 /// might have denied some of them): it is the responsibility of the caller to accomodate that.
@@ -496,8 +719,150 @@ pub unsafe extern "C" fn fxa_complete_oauth_flow(
     })
 }
 
+/// Try to get an access token for a single `scope`.
+///
+/// If the token is expired, the system will try to refresh it automatically using
+/// a `refresh_token` or `session_token`.
+///
+/// If the system can't find a suitable token but has a `session_token`, it will generate a new one on the go.
+///
+/// If not, this returns null, and the caller must start an OAuth flow with
+/// [fxa_begin_oauth_flow].
+///
+/// Returns a JSON object like `{"scope": "...", "token": "...", "key": {...},
+/// "expires_at": 1234}`, where `key` is a JWK (with `kty`, `scope`, `k` and
+/// `kid` members), or null if the token was obtained without keys.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_get_access_token(
+    fxa: *mut FirefoxAccount,
+    scope: *const c_char,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_result_by_value(error, ptr::null_mut(), || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let scope = c_char_to_string(scope);
+        Ok(match fxa.get_access_token(scope)? {
+            Some(info) => string_to_c_char(serde_json::to_string(&info)?),
+            None => ptr::null_mut(),
+        })
+    })
+}
+
+/// Whether the account can be used: `Disconnected` (0), `Connected` (1), or
+/// `AuthProblems` (2), in which case the user needs to sign in again, and
+/// anything that needs a token fails with `ErrorCode::AuthProblems`. The
+/// state is persisted with the account.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_get_account_state(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) -> AccountStateC {
+    call_with_output_by_value(error, AccountStateC::Disconnected, || {
+        assert!(!fxa.is_null());
+        let fxa = &*fxa;
+        fxa.account_state().into()
+    })
+}
+
+/// Try to recover from `AuthProblems` without making the user sign in again,
+/// and return the new state, like [fxa_get_account_state]. If it's still
+/// `AuthProblems`, the app should start an OAuth flow with
+/// [fxa_begin_oauth_flow].
+#[no_mangle]
+pub unsafe extern "C" fn fxa_retry_authentication(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) -> AccountStateC {
+    call_with_result_by_value(error, AccountStateC::AuthProblems, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        Ok(fxa.retry_authentication()?.into())
+    })
+}
+
+/// Register another OAuth client (for example, a second FxA-gated service
+/// embedded in the same app) with its own `client_id` and `redirect_uri`.
+/// It shares the account's login state, but has separate tokens, which are
+/// obtained with [fxa_begin_oauth_flow_for_client] and
+/// [fxa_get_access_token_for_client].
+#[no_mangle]
+pub unsafe extern "C" fn fxa_add_client(
+    fxa: *mut FirefoxAccount,
+    client_id: *const c_char,
+    redirect_uri: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_output(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let client_id = c_char_to_string(client_id);
+        let redirect_uri = c_char_to_string(redirect_uri);
+        fxa.add_client(client_id, redirect_uri);
+    });
+}
+
+/// Like [fxa_begin_oauth_flow], for a client registered with
+/// [fxa_add_client]. The flow is completed with [fxa_complete_oauth_flow].
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_begin_oauth_flow_for_client(
+    fxa: *mut FirefoxAccount,
+    client_id: *const c_char,
+    scope: *const c_char,
+    wants_keys: bool,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let client_id = c_char_to_string(client_id);
+        let scope = c_char_to_string(scope);
+        let scopes: Vec<&str> = scope.split(" ").collect();
+        fxa.begin_oauth_flow_for_client(client_id, &scopes, wants_keys)
+    })
+}
+
+/// Like [fxa_get_access_token], for a client registered with
+/// [fxa_add_client].
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_get_access_token_for_client(
+    fxa: *mut FirefoxAccount,
+    client_id: *const c_char,
+    scope: *const c_char,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_result_by_value(error, ptr::null_mut(), || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let client_id = c_char_to_string(client_id);
+        let scope = c_char_to_string(scope);
+        Ok(match fxa.get_access_token_for_client(client_id, scope)? {
+            Some(info) => string_to_c_char(serde_json::to_string(&info)?),
+            None => ptr::null_mut(),
+        })
+    })
+}
+
 /// Try to get a previously obtained cached token.
 ///
+/// Deprecated: use [fxa_get_access_token], which also returns the scope's key,
+/// instead.
+///
 /// If the token is expired, the system will try to refresh it automatically using
 /// a `refresh_token` or `session_token`.
 ///
@@ -510,6 +875,7 @@ pub unsafe extern "C" fn fxa_complete_oauth_flow(
 /// A destructor [fxa_oauth_info_free] is provided for releasing the memory for this
 /// pointer type.
 #[no_mangle]
+#[allow(deprecated)]
 pub unsafe extern "C" fn fxa_get_oauth_token(
     fxa: *mut FirefoxAccount,
     scope: *const c_char,
@@ -527,6 +893,100 @@ pub unsafe extern "C" fn fxa_get_oauth_token(
     })
 }
 
+/// Handle a push message received for the account. `json` is the decrypted
+/// push payload.
+///
+/// Returns a JSON array of the account events contained in the message (for
+/// example `[{"type":"PasswordChanged"}]`), which may be empty. Events which
+/// invalidate our tokens (such as a password change) are applied to the
+/// account state before this returns.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_handle_push_message(
+    fxa: *mut FirefoxAccount,
+    json: *const c_char,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let json = c_char_to_string(json);
+        let events = fxa.handle_push_message(json)?;
+        serde_json::to_string(&events).map_err(|e| e.into())
+    })
+}
+
+/// Fetch the commands other devices sent to this device since we last
+/// looked, such as tabs sent with Send Tab.
+///
+/// Returns a JSON array of account events, like `fxa_handle_push_message`
+/// (for example `[{"type":"TabReceived","sender":"...","payload":{...}}]`),
+/// which may be empty. Each command is only returned once.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_poll_device_commands(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let events = fxa.poll_device_commands()?;
+        serde_json::to_string(&events).map_err(|e| e.into())
+    })
+}
+
+/// Fetch the devices connected to the account, including this one.
+///
+/// Returns a JSON array of devices (for example
+/// `[{"id":"...","display_name":"...","device_type":"mobile","is_current_device":false,
+/// "last_access_time":1234,"capabilities":["SendTab"],"available_commands":{...}}]`).
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_get_devices(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let devices = fxa.get_devices()?;
+        serde_json::to_string(&devices).map_err(|e| e.into())
+    })
+}
+
+/// Like `fxa_get_devices`, but only returns the other devices that can
+/// receive tabs.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_get_send_tab_targets(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let devices = fxa.get_send_tab_targets()?;
+        serde_json::to_string(&devices).map_err(|e| e.into())
+    })
+}
+
 /// Free a Rust-created string.
 #[no_mangle]
 pub extern "C" fn fxa_str_free(s: *mut c_char) {
@@ -538,6 +998,13 @@ pub extern "C" fn fxa_str_free(s: *mut c_char) {
     };
 }
 
+// Returns the components built into this library as a JSON array, for
+// diagnostics. The result must be freed with [fxa_str_free].
+define_component_versions_query!(fxa_component_versions);
+
+// Forwards this library's logs to a callback; see `ffi_support::log_sink`.
+define_log_callback_setters!(fxa_set_log_callback, fxa_clear_log_callback);
+
 /// Creates a function with a given `$name` that releases the memory for a type `$t`.
 macro_rules! define_destructor (
      ($name:ident, $t:ty) => (
@@ -8,6 +8,9 @@ public enum FxAError: Error {
     case Unauthorized(message: String)
     case Unspecified(message: String)
     case Panic(message: String)
+    case EnvironmentMismatch(message: String)
+    /// The user needs to sign in again. See `FirefoxAccount.getAccountState()`.
+    case AuthProblems(message: String)
 
     // The name is attempting to indicate that we free fxaError.message if it
     // existed, and that it's a very bad idea to touch it after you call this
@@ -23,6 +26,10 @@ public enum FxAError: Error {
             return .Unspecified(message: String(freeingFxaString: message!))
         case InternalPanic:
             return .Panic(message: String(freeingFxaString: message!))
+        case EnvironmentMismatch:
+            return .EnvironmentMismatch(message: String(freeingFxaString: message!))
+        case AuthProblems:
+            return .AuthProblems(message: String(freeingFxaString: message!))
         default:
             return .Unspecified(message: String(freeingFxaString: message!))
         }
@@ -52,8 +52,42 @@ public protocol PersistCallback {
     func persist(json: String)
 }
 
+public protocol CommandDataCallback {
+    /// Makes the data other devices need to send us the command for `capability` (for
+    /// example `"SendTab"`), given the sync key as JSON. Returning nil leaves the
+    /// capability out. This is called on the FxA queue, and must not call back into
+    /// the `FirefoxAccount`.
+    func commandData(capability: String, syncKey: String) -> String?
+}
+
+/// The levels of the records passed to a `LogCallback`, from most to least verbose.
+public enum LogLevel: Int32 {
+    case verbose = 2
+    case debug = 3
+    case info = 4
+    case warn = 5
+    case error = 6
+}
+
+public protocol LogCallback {
+    /// Called with every record the FxA library logs. This may be called on any thread, and
+    /// must not call back into the FxA library.
+    func log(level: LogLevel, tag: String, message: String)
+}
+
+public protocol FetchCallback {
+    /// Performs the HTTP request described by `request`, and returns the response, both as
+    /// JSON in the format described in `components/viaduct/README.md`. This is called
+    /// synchronously on the thread making the request, and must not call back into the FxA
+    /// library.
+    func fetch(request: String) -> String
+}
+
 open class FirefoxAccount: RustOpaquePointer {
     fileprivate static var persistCallback: PersistCallback?
+    fileprivate static var commandDataCallback: CommandDataCallback?
+    fileprivate static var logCallback: LogCallback?
+    fileprivate static var fetchCallback: FetchCallback?
 
     #if BROWSERID_FEATURES
     /// Creates a `FirefoxAccount` instance from credentials obtained with the onepw FxA login flow.
@@ -79,6 +113,60 @@ open class FirefoxAccount: RustOpaquePointer {
         })
     }
 
+    /// Returns the components built into the FxA library, as a JSON array of
+    /// `{"name": ..., "version": ..., "gitHash": ...}` objects, where `gitHash` may be null.
+    /// Meant for diagnostics screens and bug reports.
+    open class func componentVersions() -> String {
+        return String(freeingFxaString: fxa_component_versions())
+    }
+
+    /// Forwards the FxA library's logs, at `maxLevel` or less verbose, to `cb`, replacing any
+    /// callback set before. Call this before anything else: it returns false (and does nothing)
+    /// if another logger was installed first.
+    @discardableResult
+    open class func setLogCallback(_ cb: LogCallback, maxLevel: LogLevel) -> Bool {
+        FirefoxAccount.logCallback = cb
+        if fxa_set_log_callback(logCallbackFunction, maxLevel.rawValue) == 0 {
+            FirefoxAccount.logCallback = nil
+            return false
+        }
+        return true
+    }
+
+    /// Stops forwarding logs to the callback passed to `setLogCallback`.
+    open class func clearLogCallback() {
+        fxa_clear_log_callback()
+        FirefoxAccount.logCallback = nil
+    }
+
+    /// Sends all of the FxA library's HTTP requests through `cb`, instead of its own network
+    /// stack. Call this before anything else: it returns false (and does nothing) if it was
+    /// already called, or if a request was already made.
+    @discardableResult
+    open class func setFetchCallback(_ cb: FetchCallback) -> Bool {
+        if FirefoxAccount.fetchCallback != nil {
+            return false
+        }
+        FirefoxAccount.fetchCallback = cb
+        if viaduct_initialize(fetchCallbackFunction) == 0 {
+            FirefoxAccount.fetchCallback = nil
+            return false
+        }
+        return true
+    }
+
+    /// Like `fromJSON(state:)`, but throws `FxAError.EnvironmentMismatch` if the state was saved
+    /// for a different FxA environment than `config` (stage instead of production, for example).
+    /// Unlike most functions taking an `FxAConfig`, this does not consume it.
+    open class func fromJSON(state: String, config: FxAConfig) throws -> FirefoxAccount {
+        return try queue.sync(execute: {
+            let pointer = try FxAError.unwrap({ err in
+                fxa_from_json_for_config(state, try config.validPointer(), err)
+            })
+            return FirefoxAccount(raw: pointer)
+        })
+    }
+
     /// Create a `FirefoxAccount` from scratch. This is suitable for callers using the
     /// OAuth Flow.
     /// Please note that the `FxAConfig` provided will be consumed and therefore
@@ -108,6 +196,56 @@ open class FirefoxAccount: RustOpaquePointer {
         })
     }
 
+    /// Like `toJSON()`, but without any key material, for handing the account to a less trusted process.
+    /// An instance restored from this can get access tokens, but they won't include scoped keys.
+    open func toJSONScrubbed() throws -> String {
+        return try queue.sync(execute: {
+            return String(freeingFxaString: try FxAError.unwrap({err in
+                fxa_to_json_scrubbed(self.raw, err)
+            }))
+        })
+    }
+
+    /// Switches this account to another FxA environment. Throws if the account is signed in,
+    /// unless `wipe` is true, in which case the account is signed out first.
+    /// Please note that the `FxAConfig` provided will be consumed and therefore
+    /// should not be re-used.
+    open func switchConfig(config: FxAConfig, wipe: Bool) throws {
+        try queue.sync(execute: {
+            try FxAError.unwrap({err in
+                fxa_switch_config(self.raw, try config.movePointer(), wipe, err)
+            })
+        })
+    }
+
+    /// Whether the account can be used. In `.authProblems`, anything that needs a token throws
+    /// `FxAError.AuthProblems` until the user signs in again (or `retryAuthentication(...)`
+    /// recovers).
+    open func getAccountState() throws -> AccountState {
+        return try queue.sync(execute: {
+            var err = FxAErrorC(code: NoError, message: nil)
+            let state = fxa_get_account_state(self.raw, &err)
+            if let fxaErr = FxAError.fromConsuming(err) {
+                throw fxaErr
+            }
+            return AccountState(state)
+        })
+    }
+
+    /// Try to get out of `.authProblems` without making the user sign in again. If the
+    /// new state is still `.authProblems`, start an OAuth flow with `beginOAuthFlow(...)`.
+    open func retryAuthentication(completionHandler: @escaping (AccountState?, Error?) -> Void) {
+        queue.async {
+            var err = FxAErrorC(code: NoError, message: nil)
+            let state = fxa_retry_authentication(self.raw, &err)
+            if let fxaErr = FxAError.fromConsuming(err) {
+                DispatchQueue.main.async { completionHandler(nil, fxaErr) }
+            } else {
+                DispatchQueue.main.async { completionHandler(AccountState(state), nil) }
+            }
+        }
+    }
+
     /// Registers a persistance callback. The callback will get called everytime
     /// the `FirefoxAccount` state needs to be saved. The callback must
     /// persist the passed string in a secure location (like the keychain).
@@ -126,6 +264,43 @@ open class FirefoxAccount: RustOpaquePointer {
         })
     }
 
+    /// Registers the callback that makes the data for our capabilities. This must
+    /// be done before calling `ensureCapabilities`.
+    public func registerCommandDataCallback(_ cb: CommandDataCallback) throws {
+        try queue.sync(execute: {
+            FirefoxAccount.commandDataCallback = cb
+            try FxAError.unwrap({err in
+                fxa_register_command_data_callback(self.raw, commandDataCallbackFunction, err)
+            })
+        })
+    }
+
+    /// Unregisters the command data callback.
+    public func unregisterCommandDataCallback() throws {
+        try queue.sync(execute: {
+            try FxAError.unwrap({err in
+                fxa_unregister_command_data_callback(self.raw, err)
+            })
+            FirefoxAccount.commandDataCallback = nil
+        })
+    }
+
+    /// Registers `capabilities` (for example `["SendTab"]`) for this device, if
+    /// they changed. Passing an empty array unregisters them.
+    open func ensureCapabilities(_ capabilities: [String], completionHandler: @escaping (Error?) -> Void) {
+        queue.async {
+            do {
+                let json = String(data: try JSONEncoder().encode(capabilities), encoding: .utf8)!
+                try FxAError.unwrap({err in
+                    fxa_ensure_capabilities(self.raw, json, err)
+                })
+                DispatchQueue.main.async { completionHandler(nil) }
+            } catch {
+                DispatchQueue.main.async { completionHandler(error) }
+            }
+        }
+    }
+
     /// Gets the logged-in user profile.
     /// Throws FxAError.Unauthorized we couldn't find any suitable access token
     /// to make that call. The caller should then start the OAuth Flow again with
@@ -161,6 +336,25 @@ open class FirefoxAccount: RustOpaquePointer {
         })
     }
 
+    /// Get the URL of the account settings page. `entrypoint` says where in the
+    /// app the user came from, for metrics.
+    open func getManageAccountURL(entrypoint: String) throws -> URL {
+        return try queue.sync(execute: {
+            return URL(string: String(freeingFxaString: try FxAError.unwrap({err in
+                fxa_get_manage_account_url(self.raw, entrypoint, err)
+            })))!
+        })
+    }
+
+    /// Get the URL of the page listing the devices connected to the account.
+    open func getManageDevicesURL(entrypoint: String) throws -> URL {
+        return try queue.sync(execute: {
+            return URL(string: String(freeingFxaString: try FxAError.unwrap({err in
+                fxa_get_manage_devices_url(self.raw, entrypoint, err)
+            })))!
+        })
+    }
+
     /// Request a OAuth token by starting a new OAuth flow.
     ///
     /// This function returns a URL string that the caller should open in a webview.
@@ -184,7 +378,8 @@ open class FirefoxAccount: RustOpaquePointer {
         }
     }
 
-    /// Finish an OAuth flow initiated by `beginOAuthFlow(...)` and returns token/keys.
+    /// Finish an OAuth flow initiated by `beginOAuthFlow(...)` and returns the token. Use
+    /// `getAccessToken(...)` to get the keys, if the flow requested them.
     ///
     /// This resulting token might not have all the `scopes` the caller have requested (e.g. the user
     /// might have denied some of them): it is the responsibility of the caller to accomodate that.
@@ -201,6 +396,79 @@ open class FirefoxAccount: RustOpaquePointer {
         }
     }
 
+    /// Try to get an access token for `scope`, along with the scope's key if the token
+    /// was obtained by a flow that requested keys.
+    ///
+    /// If the token is expired, the system will try to refresh it automatically using
+    /// a `refresh_token` or `session_token`.
+    ///
+    /// If the system can't find a suitable token but has a `session_token`, it will generate a new one on the go.
+    ///
+    /// If not, `completionHandler` gets `nil` for both arguments, and the caller must start an
+    /// OAuth flow with `beginOAuthFlow(...)`.
+    open func getAccessToken(scope: String, completionHandler: @escaping (AccessTokenInfo?, Error?) -> Void) {
+        queue.async {
+            do {
+                let json = try FxAError.tryUnwrap({err in
+                    fxa_get_access_token(self.raw, scope, err)
+                })
+                guard let ptr = json else {
+                    DispatchQueue.main.async { completionHandler(nil, nil) }
+                    return
+                }
+                let data = String(freeingFxaString: ptr).data(using: .utf8)!
+                let info = try JSONDecoder().decode(AccessTokenInfo.self, from: data)
+                DispatchQueue.main.async { completionHandler(info, nil) }
+            } catch {
+                DispatchQueue.main.async { completionHandler(nil, error) }
+            }
+        }
+    }
+
+    /// Registers another OAuth client (for example a second FxA-gated service in the same
+    /// app), which shares this account's login state but has its own tokens.
+    public func addClient(clientId: String, redirectUri: String) throws {
+        try FxAError.unwrap({err in
+            fxa_add_client(self.raw, clientId, redirectUri, err)
+        })
+    }
+
+    /// Like `beginOAuthFlow(...)`, for a client registered with `addClient(...)`.
+    /// The flow is completed with `completeOAuthFlow(...)`.
+    open func beginOAuthFlow(clientId: String, scopes: [String], wantsKeys: Bool, completionHandler: @escaping (URL?, Error?) -> Void) {
+        queue.async {
+            do {
+                let scope = scopes.joined(separator: " ")
+                let url = URL(string: String(freeingFxaString: try FxAError.unwrap({err in
+                    fxa_begin_oauth_flow_for_client(self.raw, clientId, scope, wantsKeys, err)
+                })))!
+                DispatchQueue.main.async { completionHandler(url, nil) }
+            } catch {
+                DispatchQueue.main.async { completionHandler(nil, error) }
+            }
+        }
+    }
+
+    /// Like `getAccessToken(...)`, for a client registered with `addClient(...)`.
+    open func getAccessToken(clientId: String, scope: String, completionHandler: @escaping (AccessTokenInfo?, Error?) -> Void) {
+        queue.async {
+            do {
+                let json = try FxAError.tryUnwrap({err in
+                    fxa_get_access_token_for_client(self.raw, clientId, scope, err)
+                })
+                guard let ptr = json else {
+                    DispatchQueue.main.async { completionHandler(nil, nil) }
+                    return
+                }
+                let data = String(freeingFxaString: ptr).data(using: .utf8)!
+                let info = try JSONDecoder().decode(AccessTokenInfo.self, from: data)
+                DispatchQueue.main.async { completionHandler(info, nil) }
+            } catch {
+                DispatchQueue.main.async { completionHandler(nil, error) }
+            }
+        }
+    }
+
     /// Try to get a previously obtained cached token.
     ///
     /// If the token is expired, the system will try to refresh it automatically using
@@ -209,6 +477,7 @@ open class FirefoxAccount: RustOpaquePointer {
     /// If the system can't find a suitable token but has a `session_token`, it will generate a new one on the go.
     ///
     /// If not, the caller must start an OAuth flow with `beginOAuthFlow(...)`.
+    @available(*, deprecated, message: "Use getAccessToken(scope:completionHandler:), which returns typed keys, instead")
     open func getOAuthToken(scopes: [String], completionHandler: @escaping (OAuthInfo?, Error?) -> Void) {
         queue.async {
             do {
@@ -252,6 +521,69 @@ private func persistCallbackFunction(json: UnsafePointer<CChar>) {
     }
 }
 
+private func commandDataCallbackFunction(capability: UnsafePointer<CChar>, syncKey: UnsafePointer<CChar>) -> UnsafeMutablePointer<CChar>? {
+    guard let cb = FirefoxAccount.commandDataCallback,
+        let data = cb.commandData(capability: String(cString: capability), syncKey: String(cString: syncKey)) else {
+        return nil
+    }
+    // Rust frees this with `free`.
+    return strdup(data)
+}
+
+private func logCallbackFunction(level: Int32, tag: UnsafePointer<CChar>, message: UnsafePointer<CChar>) {
+    if let cb = FirefoxAccount.logCallback {
+        cb.log(level: LogLevel(rawValue: level) ?? .error, tag: String(cString: tag), message: String(cString: message))
+    }
+}
+
+private func fetchCallbackFunction(request: UnsafePointer<CChar>) -> UnsafeMutablePointer<CChar>? {
+    guard let cb = FirefoxAccount.fetchCallback else {
+        return nil
+    }
+    let response = cb.fetch(request: String(cString: request))
+    // Rust frees the copy made by `viaduct_alloc_string`.
+    return viaduct_alloc_string(response)
+}
+
+public enum AccountState {
+    case disconnected
+    case connected
+    case authProblems
+
+    init(_ state: AccountStateC) {
+        switch state {
+        case AccountStateConnected:
+            self = .connected
+        case AccountStateAuthProblems:
+            self = .authProblems
+        default:
+            self = .disconnected
+        }
+    }
+}
+
+public struct ScopedKey: Decodable {
+    public let kty: String
+    public let scope: String
+    public let k: String
+    public let kid: String
+}
+
+public struct AccessTokenInfo: Decodable {
+    public let scope: String
+    public let token: String
+    public let key: ScopedKey?
+    /// Seconds since the epoch.
+    public let expiresAt: UInt64
+
+    enum CodingKeys: String, CodingKey {
+        case scope
+        case token
+        case key
+        case expiresAt = "expires_at"
+    }
+}
+
 open class OAuthInfo: RustStructPointer<OAuthInfoC> {
     public var scopes: [String] {
         get {
@@ -265,15 +597,6 @@ open class OAuthInfo: RustStructPointer<OAuthInfoC> {
         }
     }
 
-    open var keys: String? {
-        get {
-            guard let pointer = raw.pointee.keys else {
-                return nil
-            }
-            return String(cString: pointer)
-        }
-    }
-
     override func cleanup(pointer: UnsafeMutablePointer<OAuthInfoC>) {
         queue.sync {
             fxa_oauth_info_free(self.raw)
@@ -24,8 +24,19 @@ typedef enum ErrorCode {
     Other = 1,
     AuthenticationError = 2,
     InternalPanic = 3,
+    EnvironmentMismatch = 4,
+    AuthProblems = 5,
 } ErrorCode;
 
+/*
+ A mapping of the AccountStateC repr(C) Rust enum.
+ */
+typedef enum AccountStateC {
+    AccountStateDisconnected = 0,
+    AccountStateConnected = 1,
+    AccountStateAuthProblems = 2,
+} AccountStateC;
+
 /*
  A mapping of the ExternError repr(C) Rust struct.
  */
@@ -36,7 +47,6 @@ typedef struct FxAErrorC {
 
 typedef struct OAuthInfoC {
     const char *const _Nonnull access_token;
-    const char *const _Nullable keys;
     const char *const _Nonnull scope;
 } OAuthInfoC;
 
@@ -74,12 +84,50 @@ OAuthInfoC *_Nullable fxa_get_oauth_token(FirefoxAccount *_Nonnull fxa,
                                           const char *_Nonnull scope,
                                           FxAErrorC *_Nonnull out);
 
+char *_Nullable fxa_get_access_token(FirefoxAccount *_Nonnull fxa,
+                                    const char *_Nonnull scope,
+                                    FxAErrorC *_Nonnull out);
+
+void fxa_add_client(FirefoxAccount *_Nonnull fxa,
+                    const char *_Nonnull client_id,
+                    const char *_Nonnull redirect_uri,
+                    FxAErrorC *_Nonnull out);
+
+char *_Nonnull fxa_begin_oauth_flow_for_client(FirefoxAccount *_Nonnull fxa,
+                                               const char *_Nonnull client_id,
+                                               const char *_Nonnull scopes,
+                                               bool wants_keys,
+                                               FxAErrorC *_Nonnull out);
+
+char *_Nullable fxa_get_access_token_for_client(FirefoxAccount *_Nonnull fxa,
+                                               const char *_Nonnull client_id,
+                                               const char *_Nonnull scope,
+                                               FxAErrorC *_Nonnull out);
+
 FirefoxAccount *_Nullable fxa_from_json(const char *_Nonnull json,
                                         FxAErrorC *_Nonnull out);
 
+FirefoxAccount *_Nullable fxa_from_json_for_config(const char *_Nonnull json,
+                                                   const Config *_Nonnull config,
+                                                   FxAErrorC *_Nonnull out);
+
+void fxa_switch_config(FirefoxAccount *_Nonnull fxa,
+                       Config *_Nonnull config,
+                       bool wipe,
+                       FxAErrorC *_Nonnull out);
+
+AccountStateC fxa_get_account_state(FirefoxAccount *_Nonnull fxa,
+                                    FxAErrorC *_Nonnull out);
+
+AccountStateC fxa_retry_authentication(FirefoxAccount *_Nonnull fxa,
+                                       FxAErrorC *_Nonnull out);
+
 char *_Nullable fxa_to_json(FirefoxAccount *_Nonnull fxa,
                             FxAErrorC *_Nonnull out);
 
+char *_Nullable fxa_to_json_scrubbed(FirefoxAccount *_Nonnull fxa,
+                                     FxAErrorC *_Nonnull out);
+
 void fxa_register_persist_callback(FirefoxAccount *_Nonnull fxa,
                                    void (*_Nonnull callback_fn)(const char* _Nonnull json),
                                    FxAErrorC *_Nonnull out);
@@ -87,6 +135,31 @@ void fxa_register_persist_callback(FirefoxAccount *_Nonnull fxa,
 void fxa_unregister_persist_callback(FirefoxAccount *_Nonnull fxa,
                                      FxAErrorC *_Nonnull out);
 
+void fxa_register_command_data_callback(FirefoxAccount *_Nonnull fxa,
+                                        char *_Nullable (*_Nonnull callback_fn)(const char* _Nonnull capability,
+                                                                                const char* _Nonnull sync_key_json),
+                                        FxAErrorC *_Nonnull out);
+
+void fxa_unregister_command_data_callback(FirefoxAccount *_Nonnull fxa,
+                                          FxAErrorC *_Nonnull out);
+
+void fxa_ensure_capabilities(FirefoxAccount *_Nonnull fxa,
+                             const char *_Nonnull capabilities_json,
+                             FxAErrorC *_Nonnull out);
+
+char *_Nullable fxa_get_devices(FirefoxAccount *_Nonnull fxa,
+                                FxAErrorC *_Nonnull out);
+
+char *_Nullable fxa_get_send_tab_targets(FirefoxAccount *_Nonnull fxa,
+                                         FxAErrorC *_Nonnull out);
+
+char *_Nullable fxa_handle_push_message(FirefoxAccount *_Nonnull fxa,
+                                        const char *_Nonnull json,
+                                        FxAErrorC *_Nonnull out);
+
+char *_Nullable fxa_poll_device_commands(FirefoxAccount *_Nonnull fxa,
+                                         FxAErrorC *_Nonnull out);
+
 FirefoxAccount *_Nullable fxa_new(Config *_Nonnull config,
                                   const char *_Nonnull client_id,
                                   const char *_Nonnull redirect_uri,
@@ -109,9 +182,36 @@ char *_Nullable fxa_assertion_new(FirefoxAccount *_Nonnull fxa,
 char *_Nullable fxa_get_token_server_endpoint_url(FirefoxAccount *_Nonnull fxa,
                                                   FxAErrorC *_Nonnull out);
 
+char *_Nullable fxa_get_manage_account_url(FirefoxAccount *_Nonnull fxa,
+                                           const char *_Nonnull entrypoint,
+                                           FxAErrorC *_Nonnull out);
+
+char *_Nullable fxa_get_manage_devices_url(FirefoxAccount *_Nonnull fxa,
+                                           const char *_Nonnull entrypoint,
+                                           FxAErrorC *_Nonnull out);
+
 SyncKeysC *_Nullable fxa_get_sync_keys(FirefoxAccount *_Nonnull fxa,
                                        FxAErrorC *_Nonnull out);
 
+char *_Nonnull fxa_component_versions(void);
+
+uint8_t fxa_set_log_callback(void (*_Nonnull callback_fn)(int32_t level,
+                                                          const char* _Nonnull tag,
+                                                          const char* _Nonnull message),
+                             int32_t max_level);
+
+void fxa_clear_log_callback(void);
+
+/*
+ Routes every HTTP request made by this library through `callback_fn`, which is given the
+ request as JSON and must return the response as JSON (see components/viaduct/README.md),
+ copied with `viaduct_alloc_string`. Returns 1 on success, or 0 if requests were already
+ being sent some other way.
+ */
+uint8_t viaduct_initialize(char *_Nullable (*_Nonnull callback_fn)(const char *_Nonnull request));
+
+char *_Nullable viaduct_alloc_string(const char *_Nullable s);
+
 void fxa_str_free(char* _Nullable ptr);
 void fxa_free(FirefoxAccount* _Nullable ptr);
 void fxa_oauth_info_free(OAuthInfoC* _Nullable ptr);
@@ -0,0 +1,74 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use errors::*;
+
+/// Whether an account can be used, as returned by
+/// `FirefoxAccount::account_state`.
+///
+/// ```text
+///                  complete_oauth_flow
+///   Disconnected ----------------------> Connected
+///        ^                                 |    ^
+///        | account destroyed,              |    | retry_authentication,
+///        | wipe_and_switch_config          |    | complete_oauth_flow
+///        |                                 v    |
+///        +------------------------------ AuthProblems
+///                     (credentials rejected, password changed)
+/// ```
+///
+/// `AuthProblems` is sticky: it's persisted with the account, and lasts
+/// until the account is reauthenticated, so that apps can show a "Sign in
+/// again" prompt instead of failing at random whenever they need a token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountState {
+    /// We have no tokens, and no session.
+    Disconnected,
+    /// Signed in, as far as we know.
+    Connected,
+    /// The server rejected our credentials, or the password was changed, so
+    /// the user needs to sign in again (unless `retry_authentication` can
+    /// recover). Everything that needs a token fails with
+    /// `ErrorKind::AuthProblems` until then.
+    AuthProblems,
+}
+
+/// True if `error` means the server no longer accepts our refresh token (or
+/// session token): it was revoked, or the password was reset. Other errors,
+/// like network errors or server outages, don't mean anything about our
+/// credentials.
+pub(crate) fn is_auth_error(error: &Error) -> bool {
+    match error.kind() {
+        // 108 is the OAuth server's "invalid token", and 110 is the auth
+        // server's "invalid authentication token".
+        ErrorKind::RemoteError { code: 401, .. }
+        | ErrorKind::RemoteError { errno: 108, .. }
+        | ErrorKind::RemoteError { errno: 110, .. } => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote_error(code: u64, errno: u64) -> Error {
+        ErrorKind::RemoteError {
+            code,
+            errno,
+            error: String::new(),
+            message: String::new(),
+            info: String::new(),
+        }.into()
+    }
+
+    #[test]
+    fn test_is_auth_error() {
+        assert!(is_auth_error(&remote_error(401, 110)));
+        assert!(is_auth_error(&remote_error(400, 108)));
+        assert!(!is_auth_error(&remote_error(500, 999)));
+        assert!(!is_auth_error(&remote_error(400, 109)));
+        assert!(!is_auth_error(&ErrorKind::NoRefreshToken.into()));
+    }
+}
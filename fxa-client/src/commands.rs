@@ -0,0 +1,275 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Commands sent to this device by the other devices on the account, like
+//! Send Tab. The FxA server queues them for us, numbered with an increasing
+//! index, and tells us about new ones with a push message; we fetch the ones
+//! after the last index we handled.
+
+use std::cmp;
+use std::collections::VecDeque;
+
+use config::Config;
+use errors::*;
+use hex;
+use http_client::{FxAClient, PendingCommand};
+use push::AccountEvent;
+use ring::digest;
+use serde_json;
+
+/// The command other devices send us tabs with.
+pub const SEND_TAB_COMMAND: &str = "https://identity.mozilla.com/cmd/open-uri";
+
+// How many commands to ask the server for at a time.
+const PAGE_SIZE: u64 = 50;
+
+// How many handled commands to remember in `CommandsStateV1::recent`.
+const MAX_RECENT_COMMANDS: usize = 20;
+
+/// Where we're up to in the account's command queue. This is persisted with
+/// the rest of the account state.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CommandsStateV1 {
+    /// The index of the last command we handled, if any.
+    last_index: Option<u64>,
+    /// Fingerprints of the last few commands we handled. The server's
+    /// indexes start over if its queue is reset, so this stops us from
+    /// handling a command twice if it shows up again with a new index.
+    #[serde(default)]
+    recent: VecDeque<String>,
+}
+
+impl CommandsStateV1 {
+    /// Fetches the commands we haven't handled yet, and returns events for
+    /// them, in order.
+    ///
+    /// If the server's latest index is before the last one we handled, its
+    /// queue was reset, so we start again from the beginning. If commands
+    /// are missing between the last one we handled and the first one we got,
+    /// they expired before we fetched them, and are lost.
+    pub(crate) fn poll(
+        &mut self,
+        client: &FxAClient,
+        config: &Config,
+        refresh_token: &str,
+    ) -> Result<Vec<AccountEvent>> {
+        let mut events = Vec::new();
+        let mut from = self.next_index();
+        let mut first_page = true;
+        loop {
+            let resp = client.pending_commands(config, refresh_token, from, PAGE_SIZE)?;
+            if first_page {
+                first_page = false;
+                if let Some(last_index) = self.last_index {
+                    if resp.index < last_index {
+                        warn!(
+                            "Command index went back from {} to {}, refetching all commands",
+                            last_index, resp.index
+                        );
+                        self.last_index = None;
+                        from = 0;
+                        continue;
+                    }
+                    if let Some(first) = resp.messages.first() {
+                        if first.index > from {
+                            warn!("Missed commands {} to {}", from, first.index - 1);
+                        }
+                    }
+                }
+            }
+            let done = resp.last.unwrap_or(true) || resp.messages.is_empty();
+            for message in resp.messages {
+                from = cmp::max(from, message.index + 1);
+                if let Some(event) = self.handle(message) {
+                    events.push(event);
+                }
+            }
+            if done {
+                break;
+            }
+        }
+        Ok(events)
+    }
+
+    fn next_index(&self) -> u64 {
+        self.last_index.map_or(0, |index| index + 1)
+    }
+
+    fn handle(&mut self, message: PendingCommand) -> Option<AccountEvent> {
+        if self.last_index.map_or(false, |last_index| message.index <= last_index) {
+            return None;
+        }
+        self.last_index = Some(message.index);
+        let fingerprint = fingerprint(&message);
+        if self.recent.contains(&fingerprint) {
+            info!("Skipping command {}, which we already handled", message.index);
+            return None;
+        }
+        if self.recent.len() >= MAX_RECENT_COMMANDS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(fingerprint);
+        let data = message.data;
+        Some(if data.command == SEND_TAB_COMMAND {
+            AccountEvent::TabReceived {
+                sender: data.sender,
+                payload: data.payload,
+            }
+        } else {
+            AccountEvent::CommandReceived {
+                command: data.command,
+                sender: data.sender,
+                payload: data.payload,
+            }
+        })
+    }
+}
+
+// Payloads are encrypted with a random nonce, so two commands with the same
+// sender and payload are the same command.
+fn fingerprint(message: &PendingCommand) -> String {
+    let data = &message.data;
+    let input = format!(
+        "{}\n{}\n{}",
+        data.sender.as_ref().map(String::as_str).unwrap_or(""),
+        data.command,
+        serde_json::to_string(&data.payload).unwrap_or_default()
+    );
+    hex::encode(&digest::digest(&digest::SHA256, input.as_bytes()).as_ref()[0..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::*;
+
+    const COMMANDS_1_TO_2: &str = r#"{"index": 2, "last": true, "messages": [
+        {"index": 1, "data": {"command": "https://identity.mozilla.com/cmd/open-uri",
+                              "payload": {"encrypted": "abc"}, "sender": "device1"}},
+        {"index": 2, "data": {"command": "https://example.com/cmd/ring",
+                              "payload": {}, "sender": "device2"}}
+    ]}"#;
+
+    const COMMAND_3_PAGE: &str = r#"{"index": 4, "last": false, "messages": [
+        {"index": 3, "data": {"command": "https://identity.mozilla.com/cmd/open-uri",
+                              "payload": {"encrypted": "def"}}}
+    ]}"#;
+
+    const COMMAND_4: &str = r#"{"index": 4, "last": true, "messages": [
+        {"index": 4, "data": {"command": "https://identity.mozilla.com/cmd/open-uri",
+                              "payload": {"encrypted": "ghi"}, "sender": "device1"}}
+    ]}"#;
+
+    const NO_COMMANDS: &str = r#"{"index": 4, "last": true, "messages": []}"#;
+
+    // The server's queue was reset, and has command 1 (which we've seen
+    // before) and a new command 2.
+    const RESET: &str = r#"{"index": 2, "messages": []}"#;
+    const AFTER_RESET: &str = r#"{"index": 2, "messages": [
+        {"index": 1, "data": {"command": "https://identity.mozilla.com/cmd/open-uri",
+                              "payload": {"encrypted": "ghi"}, "sender": "device1"}},
+        {"index": 2, "data": {"command": "https://identity.mozilla.com/cmd/open-uri",
+                              "payload": {"encrypted": "jkl"}, "sender": "device1"}}
+    ]}"#;
+
+    fn tab(sender: Option<&str>, encrypted: &str) -> AccountEvent {
+        AccountEvent::TabReceived {
+            sender: sender.map(str::to_owned),
+            payload: json!({ "encrypted": encrypted }),
+        }
+    }
+
+    fn requested_indexes(requests: &[FakeRequest]) -> Vec<u64> {
+        requests
+            .iter()
+            .map(|request| match request {
+                FakeRequest::PendingCommands { index, .. } => *index,
+                _ => panic!("Unexpected request {:?}", request),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_poll() {
+        let (client, requests) = FakeClient::new(vec![
+            COMMANDS_1_TO_2,
+            COMMAND_3_PAGE,
+            COMMAND_4,
+            NO_COMMANDS,
+        ]);
+        let config = Config::stable_dev_fixture();
+        let mut state = CommandsStateV1::default();
+
+        let events = state.poll(&client, &config, "refresh").unwrap();
+        assert_eq!(
+            events,
+            vec![
+                tab(Some("device1"), "abc"),
+                AccountEvent::CommandReceived {
+                    command: "https://example.com/cmd/ring".to_string(),
+                    sender: Some("device2".to_string()),
+                    payload: json!({}),
+                },
+            ]
+        );
+        assert_eq!(state.last_index, Some(2));
+
+        // Two pages.
+        let events = state.poll(&client, &config, "refresh").unwrap();
+        assert_eq!(events, vec![tab(None, "def"), tab(Some("device1"), "ghi")]);
+        assert_eq!(state.last_index, Some(4));
+
+        assert!(state.poll(&client, &config, "refresh").unwrap().is_empty());
+        assert_eq!(state.last_index, Some(4));
+        assert_eq!(requested_indexes(&requests.lock().unwrap()), vec![0, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_poll_skips_handled_commands() {
+        let (client, _) = FakeClient::new(vec![COMMANDS_1_TO_2]);
+        let mut state = CommandsStateV1 {
+            last_index: Some(1),
+            recent: VecDeque::new(),
+        };
+        // The server sent command 1 again, even though we asked for 2.
+        let events = state.poll(&client, &Config::stable_dev_fixture(), "refresh").unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(state.last_index, Some(2));
+    }
+
+    #[test]
+    fn test_poll_after_reset() {
+        let (client, requests) = FakeClient::new(vec![COMMAND_4, RESET, AFTER_RESET]);
+        let config = Config::stable_dev_fixture();
+        let mut state = CommandsStateV1 {
+            last_index: Some(3),
+            recent: VecDeque::new(),
+        };
+        assert_eq!(
+            state.poll(&client, &config, "refresh").unwrap(),
+            vec![tab(Some("device1"), "ghi")]
+        );
+
+        // We refetch from the start, and only handle the new command.
+        assert_eq!(
+            state.poll(&client, &config, "refresh").unwrap(),
+            vec![tab(Some("device1"), "jkl")]
+        );
+        assert_eq!(state.last_index, Some(2));
+        assert_eq!(requested_indexes(&requests.lock().unwrap()), vec![4, 5, 0]);
+    }
+
+    #[test]
+    fn test_recent_is_bounded() {
+        let mut state = CommandsStateV1::default();
+        for i in 0..(MAX_RECENT_COMMANDS as u64 + 5) {
+            let message: PendingCommand = serde_json::from_value(json!({
+                "index": i,
+                "data": {"command": SEND_TAB_COMMAND, "payload": {"encrypted": i}},
+            })).unwrap();
+            assert!(state.handle(message).is_some());
+        }
+        assert_eq!(state.recent.len(), MAX_RECENT_COMMANDS);
+    }
+}
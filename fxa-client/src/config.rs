@@ -3,8 +3,8 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use super::errors::*;
-use reqwest;
 use url::Url;
+use viaduct::Request;
 
 #[derive(Deserialize)]
 struct ClientConfigurationResponse {
@@ -50,12 +50,18 @@ impl Config {
         Config::import_from("https://accounts.stage.mozaws.net")
     }
 
+    /// The production servers for China, which are separate from the
+    /// `release` ones: accounts on one don't exist on the other.
+    pub fn china() -> Result<Config> {
+        Config::import_from("https://accounts.firefox.com.cn")
+    }
+
     pub fn import_from(content_url: &str) -> Result<Config> {
         let config_url = Url::parse(content_url)?.join(".well-known/fxa-client-configuration")?;
-        let resp: ClientConfigurationResponse = reqwest::get(config_url)?.json()?;
+        let resp: ClientConfigurationResponse = Request::get(config_url).send()?.json()?;
 
         let openid_config_url = Url::parse(content_url)?.join(".well-known/openid-configuration")?;
-        let openid_resp: OpenIdConfigurationResponse = reqwest::get(openid_config_url)?.json()?;
+        let openid_resp: OpenIdConfigurationResponse = Request::get(openid_config_url).send()?.json()?;
 
         Ok(Config {
             content_url: content_url.to_string(),
@@ -71,6 +77,18 @@ impl Config {
         })
     }
 
+    /// The content server this config was imported from, which identifies
+    /// the FxA environment (production, stage, China, ...).
+    pub fn environment(&self) -> &str {
+        &self.content_url
+    }
+
+    /// Returns true if `other` is for the same FxA environment. Accounts (and
+    /// their tokens) only work with the environment they were created on.
+    pub fn is_same_environment(&self, other: &Config) -> bool {
+        self.content_url.trim_right_matches('/') == other.content_url.trim_right_matches('/')
+    }
+
     pub fn content_url(&self) -> Result<Url> {
         Url::parse(&self.content_url).map_err(|e| e.into())
     }
@@ -128,6 +146,43 @@ impl Config {
     }
 }
 
+/// The stable dev configuration, without fetching it from the server.
+#[cfg(test)]
+impl Config {
+    pub(crate) fn stable_dev_fixture() -> Config {
+        Config {
+            content_url: "https://stable.dev.lcip.org/".to_string(),
+            auth_url: "https://stable.dev.lcip.org/auth/".to_string(),
+            oauth_url: "https://oauth-stable.dev.lcip.org/".to_string(),
+            profile_url: "https://stable.dev.lcip.org/profile/".to_string(),
+            token_server_endpoint_url: "https://stable.dev.lcip.org/syncserver/token/1.0/sync/1.5"
+                .to_string(),
+            authorization_endpoint: "https://oauth-stable.dev.lcip.org/v1/authorization"
+                .to_string(),
+            issuer: "https://dev.lcip.org/".to_string(),
+            jwks_uri: "https://oauth-stable.dev.lcip.org/v1/jwks".to_string(),
+            token_endpoint: "https://oauth-stable.dev.lcip.org/v1/token".to_string(),
+            userinfo_endpoint: "https://stable.dev.lcip.org/profile/v1/profile".to_string(),
+        }
+    }
+
+    /// The stage configuration, without fetching it from the server.
+    pub(crate) fn stage_dev_fixture() -> Config {
+        Config {
+            content_url: "https://accounts.stage.mozaws.net".to_string(),
+            auth_url: "https://api-accounts.stage.mozaws.net/".to_string(),
+            oauth_url: "https://oauth.stage.mozaws.net/".to_string(),
+            profile_url: "https://profile.stage.mozaws.net/".to_string(),
+            token_server_endpoint_url: "https://token.stage.mozaws.net/1.0/sync/1.5".to_string(),
+            authorization_endpoint: "https://accounts.stage.mozaws.net/authorization".to_string(),
+            issuer: "https://accounts.stage.mozaws.net".to_string(),
+            jwks_uri: "https://oauth.stage.mozaws.net/v1/jwks".to_string(),
+            token_endpoint: "https://oauth.stage.mozaws.net/v1/token".to_string(),
+            userinfo_endpoint: "https://profile.stage.mozaws.net/v1/profile".to_string(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -169,4 +224,17 @@ mod tests {
             "https://stable.dev.lcip.org/syncserver/token/1.0/sync/1.5"
         );
     }
+
+    #[test]
+    fn test_same_environment() {
+        let stable = Config::stable_dev_fixture();
+        let stage = Config::stage_dev_fixture();
+        assert!(stable.is_same_environment(&stable.clone()));
+        assert!(!stable.is_same_environment(&stage));
+        // `import_from` keeps the URL it was given, with or without a slash.
+        let mut unslashed = stable.clone();
+        unslashed.content_url = "https://stable.dev.lcip.org".to_string();
+        assert!(unslashed.is_same_environment(&stable));
+        assert_eq!(stage.environment(), "https://accounts.stage.mozaws.net");
+    }
 }
@@ -0,0 +1,276 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The devices connected to the account, and which commands they accept.
+//!
+//! Each device registers the commands it can handle with the FxA server, as
+//! a map from the command's URL to some data the sender needs (for Send Tab,
+//! the keys to encrypt the tab with). We call the commands we know about
+//! `Capability`s, so that apps can tell, for example, which devices they can
+//! offer to send a tab to.
+//!
+//! The data for our own commands is derived from the account's sync key, so
+//! it's out of date once that changes. We remember which key we registered
+//! it with, and register it again when we get a token with a different one.
+
+use std::collections::HashMap;
+use std::panic::RefUnwindSafe;
+
+use commands::SEND_TAB_COMMAND;
+use http_client::DeviceResponse;
+use scoped_keys::ScopedKey;
+
+/// The scope of the sync key, which the command data is derived from.
+pub(crate) const OLDSYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
+
+/// A command, from the ones we know about, that a device can accept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Capability {
+    /// The device can receive tabs, sent with `SEND_TAB_COMMAND`.
+    SendTab,
+}
+
+impl Capability {
+    /// The URL of the command for this capability.
+    pub fn command(self) -> &'static str {
+        match self {
+            Capability::SendTab => SEND_TAB_COMMAND,
+        }
+    }
+
+    fn from_command(command: &str) -> Option<Capability> {
+        match command {
+            SEND_TAB_COMMAND => Some(Capability::SendTab),
+            _ => None,
+        }
+    }
+}
+
+/// A device connected to the account, as returned by
+/// `FirefoxAccount::get_devices`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Device {
+    pub id: String,
+    pub display_name: String,
+    /// `"desktop"`, `"mobile"`, and so on. Devices choose this themselves,
+    /// so it can be anything.
+    pub device_type: String,
+    /// True if this is us.
+    pub is_current_device: bool,
+    /// Milliseconds since the epoch.
+    pub last_access_time: Option<u64>,
+    /// The commands we know about that the device accepts, in no particular
+    /// order.
+    pub capabilities: Vec<Capability>,
+    /// Every command the device accepts, including ones we don't know
+    /// about, mapped to the data for sending it.
+    pub available_commands: HashMap<String, String>,
+}
+
+impl Device {
+    #[inline]
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+impl From<DeviceResponse> for Device {
+    fn from(response: DeviceResponse) -> Device {
+        let capabilities = response
+            .available_commands
+            .keys()
+            .filter_map(|command| Capability::from_command(command))
+            .collect();
+        Device {
+            id: response.id,
+            display_name: response.name,
+            device_type: response.device_type,
+            is_current_device: response.is_current_device,
+            last_access_time: response.last_access_time,
+            capabilities,
+            available_commands: response.available_commands,
+        }
+    }
+}
+
+/// Makes the data other devices need to send us the command for a
+/// capability, given the sync key. The data is registered with the server
+/// as is, so it's up to the app to encrypt anything that needs it (like
+/// the Send Tab keys) with the sync key, and to include the key's `kid`.
+/// Returning `None` leaves the capability out.
+pub struct CommandDataCallback {
+    callback_fn: Box<Fn(Capability, &ScopedKey) -> Option<String> + Send + RefUnwindSafe>,
+}
+
+impl CommandDataCallback {
+    pub fn new<F>(callback_fn: F) -> CommandDataCallback
+    where
+        F: Fn(Capability, &ScopedKey) -> Option<String> + 'static + Send + RefUnwindSafe,
+    {
+        CommandDataCallback {
+            callback_fn: Box::new(callback_fn),
+        }
+    }
+
+    pub fn call(&self, capability: Capability, sync_key: &ScopedKey) -> Option<String> {
+        (*self.callback_fn)(capability, sync_key)
+    }
+}
+
+/// The capabilities we register for our own device. This is persisted with
+/// the rest of the account state.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct DeviceStateV1 {
+    /// The capabilities the app asked for, with
+    /// `FirefoxAccount::ensure_capabilities`.
+    capabilities: Vec<Capability>,
+    /// The capabilities we last registered, which may be more than the app
+    /// asks for now, if it stopped asking for some.
+    #[serde(default)]
+    registered_capabilities: Vec<Capability>,
+    /// The `kid` of the sync key the registered command data was made with,
+    /// or `None` if we haven't registered any.
+    registered_kid: Option<String>,
+}
+
+impl DeviceStateV1 {
+    /// Sets the capabilities to register, returning true if they changed.
+    pub(crate) fn set_capabilities(&mut self, capabilities: &[Capability]) -> bool {
+        let mut deduped = Vec::with_capacity(capabilities.len());
+        for &capability in capabilities {
+            if !deduped.contains(&capability) {
+                deduped.push(capability);
+            }
+        }
+        if deduped == self.capabilities {
+            return false;
+        }
+        self.capabilities = deduped;
+        true
+    }
+
+    /// Returns true if the capabilities need to be registered (again) with
+    /// the command data for the sync key with `kid`. This includes when the
+    /// app stopped asking for all of them, so that we unregister them.
+    pub(crate) fn needs_registration(&self, kid: &str) -> bool {
+        if self.capabilities != self.registered_capabilities {
+            return true;
+        }
+        !self.capabilities.is_empty() && self.registered_kid.as_ref().map(String::as_str) != Some(kid)
+    }
+
+    /// Returns the available commands to register for our capabilities.
+    pub(crate) fn available_commands(
+        &self,
+        sync_key: &ScopedKey,
+        callback: &CommandDataCallback,
+    ) -> HashMap<String, String> {
+        let mut commands = HashMap::new();
+        for &capability in &self.capabilities {
+            match callback.call(capability, sync_key) {
+                Some(data) => {
+                    commands.insert(capability.command().to_string(), data);
+                }
+                None => warn!("No command data for {:?}, not registering it", capability),
+            }
+        }
+        commands
+    }
+
+    /// Records that we registered the capabilities with the command data for
+    /// the sync key with `kid`.
+    pub(crate) fn registered(&mut self, kid: &str) {
+        self.registered_capabilities = self.capabilities.clone();
+        self.registered_kid = Some(kid.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn sync_key(kid: &str) -> ScopedKey {
+        ScopedKey {
+            kty: "oct".to_string(),
+            scope: OLDSYNC_SCOPE.to_string(),
+            k: "key".to_string(),
+            kid: kid.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_device_from_response() {
+        let response: DeviceResponse = serde_json::from_value(json!({
+            "id": "device1",
+            "name": "Bob's phone",
+            "type": "mobile",
+            "isCurrentDevice": false,
+            "lastAccessTime": 1234,
+            "availableCommands": {
+                "https://identity.mozilla.com/cmd/open-uri": "send tab data",
+                "https://example.com/cmd/ring": ""
+            }
+        })).unwrap();
+        let device = Device::from(response);
+        assert_eq!(device.display_name, "Bob's phone");
+        assert_eq!(device.capabilities, vec![Capability::SendTab]);
+        assert!(device.supports(Capability::SendTab));
+        assert_eq!(device.available_commands.len(), 2);
+
+        // Devices that haven't registered any commands leave them out.
+        let response: DeviceResponse = serde_json::from_value(json!({
+            "id": "device2",
+            "name": "Desktop",
+            "type": "desktop",
+            "isCurrentDevice": true
+        })).unwrap();
+        let device = Device::from(response);
+        assert!(!device.supports(Capability::SendTab));
+        assert_eq!(device.last_access_time, None);
+    }
+
+    #[test]
+    fn test_registration() {
+        let mut state = DeviceStateV1::default();
+        assert!(!state.needs_registration("kid1"));
+
+        assert!(state.set_capabilities(&[Capability::SendTab, Capability::SendTab]));
+        assert!(state.needs_registration("kid1"));
+        let callback = CommandDataCallback::new(|capability, key| {
+            Some(format!("{:?} for {}", capability, key.kid))
+        });
+        let commands = state.available_commands(&sync_key("kid1"), &callback);
+        assert_eq!(commands[SEND_TAB_COMMAND], "SendTab for kid1");
+        state.registered("kid1");
+        assert!(!state.needs_registration("kid1"));
+
+        // Asking for the same capabilities again doesn't register them again,
+        // but a new key does.
+        assert!(!state.set_capabilities(&[Capability::SendTab]));
+        assert!(state.needs_registration("kid2"));
+
+        let callback = CommandDataCallback::new(|_, _| None);
+        assert!(state.available_commands(&sync_key("kid2"), &callback).is_empty());
+        state.registered("kid2");
+
+        // Clearing the capabilities unregisters them, but only once.
+        assert!(state.set_capabilities(&[]));
+        assert!(state.needs_registration("kid2"));
+        assert!(state.available_commands(&sync_key("kid2"), &callback).is_empty());
+        state.registered("kid2");
+        assert!(!state.needs_registration("kid2"));
+        assert!(!state.needs_registration("kid3"));
+    }
+
+    #[test]
+    fn test_persisted_state_without_registered_capabilities() {
+        let state: DeviceStateV1 = serde_json::from_value(json!({
+            "capabilities": ["SendTab"],
+            "registered_kid": "kid1"
+        })).unwrap();
+        // We don't know what we registered, so we register again.
+        assert!(state.needs_registration("kid1"));
+    }
+}
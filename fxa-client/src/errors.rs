@@ -12,8 +12,9 @@ use hawk;
 use hex;
 #[cfg(feature = "browserid")]
 use openssl;
-use reqwest;
 use serde_json;
+use url;
+use viaduct;
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -65,15 +66,27 @@ pub enum ErrorKind {
     #[fail(display = "Unknown OAuth State")]
     UnknownOAuthState,
 
+    #[fail(display = "Unknown client id {}", _0)]
+    UnknownClient(String),
+
     #[fail(display = "The client requested keys alongside the token but they were not included")]
     TokenWithoutKeys,
 
     #[fail(display = "Login state needs to be Married for the current operation")]
     NotMarried,
 
+    #[fail(display = "The account needs to be reauthenticated")]
+    AuthProblems,
+
     #[fail(display = "No cached token for scope {}", _0)]
     NoCachedToken(&'static str),
 
+    #[fail(display = "No refresh token, which is needed to talk to the device endpoints")]
+    NoRefreshToken,
+
+    #[fail(display = "No command data callback, which is needed to register capabilities")]
+    NoCommandDataCallback,
+
     #[fail(display = "Unrecoverable server error")]
     UnrecoverableServerError,
 
@@ -95,6 +108,12 @@ pub enum ErrorKind {
     #[fail(display = "Origin mismatch")]
     OriginMismatch,
 
+    #[fail(display = "Can't switch FxA environments while signed in")]
+    SwitchConfigWhileSignedIn,
+
+    #[fail(display = "The account is for the FxA environment at {}, not {}", found, expected)]
+    EnvironmentMismatch { expected: String, found: String },
+
     #[fail(display = "JWT signature validation failed")]
     JWTSignatureValidationFailed,
 
@@ -130,6 +149,9 @@ pub enum ErrorKind {
         info: String,
     },
 
+    #[fail(display = "Unexpected HTTP status {} without an error body", _0)]
+    UnexpectedStatus(u16),
+
     // Basically reimplement error_chain's foreign_links. (Ugh, this sucks)
     #[fail(display = "Hex decode error: {}", _0)]
     HexDecodeError(#[fail(cause)] hex::FromHexError),
@@ -147,17 +169,11 @@ pub enum ErrorKind {
     #[fail(display = "UTF8 decode error: {}", _0)]
     UTF8DecodeError(#[fail(cause)] string::FromUtf8Error),
 
-    #[fail(display = "Network error: {}", _0)]
-    RequestError(#[fail(cause)] reqwest::Error),
+    #[fail(display = "Error sending request: {}", _0)]
+    ViaductError(#[fail(cause)] viaduct::Error),
 
     #[fail(display = "Malformed URL error: {}", _0)]
-    MalformedUrl(#[fail(cause)] reqwest::UrlError),
-
-    #[fail(display = "Header parsing error: {}", _0)]
-    HeaderParseError(#[fail(cause)] reqwest::header::ToStrError),
-
-    #[fail(display = "Malformed header error: {}", _0)]
-    MalformedHeader(#[fail(cause)] reqwest::header::InvalidHeaderValue),
+    MalformedUrl(#[fail(cause)] url::ParseError),
 
     #[cfg(feature = "browserid")]
     #[fail(display = "HAWK error: {}", _0)]
@@ -187,10 +203,8 @@ impl_from_error! {
     (Base64Decode, ::base64::DecodeError),
     (JsonError, ::serde_json::Error),
     (UTF8DecodeError, ::std::string::FromUtf8Error),
-    (RequestError, ::reqwest::Error),
-    (MalformedUrl, ::reqwest::UrlError),
-    (HeaderParseError, ::reqwest::header::ToStrError),
-    (MalformedHeader, ::reqwest::header::InvalidHeaderValue)
+    (ViaductError, ::viaduct::Error),
+    (MalformedUrl, ::url::ParseError)
 }
 
 #[cfg(feature = "browserid")]
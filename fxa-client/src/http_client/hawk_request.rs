@@ -4,9 +4,9 @@
 
 use hawk::{Credentials, Key, PayloadHasher, RequestBuilder, SHA256};
 use hex;
-use reqwest::{header::{self, HeaderValue}, Client, Method, Request};
 use serde_json;
 use url::Url;
+use viaduct::{Method, Request};
 
 use errors::*;
 
@@ -41,8 +41,7 @@ impl<'a> HAWKRequestBuilder<'a> {
         {
             // Make sure we de-allocate the hash after hawk_request_builder.
             let hash;
-            let method = format!("{}", self.method);
-            let mut hawk_request_builder = RequestBuilder::from_url(method.as_str(), &self.url)?;
+            let mut hawk_request_builder = RequestBuilder::from_url(self.method.as_str(), &self.url)?;
             if let Some(ref body) = self.body {
                 hash = PayloadHasher::hash("application/json", &SHA256, &body);
                 hawk_request_builder = hawk_request_builder.hash(&hash[..]);
@@ -58,14 +57,12 @@ impl<'a> HAWKRequestBuilder<'a> {
             hawk_header = format!("Hawk {}", header);
         }
 
-        let mut request_builder = Client::new().request(self.method, self.url);
-        request_builder = request_builder.header(header::AUTHORIZATION, HeaderValue::from_str(&hawk_header)?);
+        let mut request = Request::new(self.method, self.url).header("authorization", hawk_header)?;
 
         if let Some(body) = self.body {
-            request_builder = request_builder.header(header::CONTENT_TYPE, "application/json");
-            request_builder = request_builder.body(body);
+            request = request.header("content-type", "application/json")?.body(body);
         }
 
-        request_builder.build().map_err(|e| e.into())
+        Ok(request)
     }
 }
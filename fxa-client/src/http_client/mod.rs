@@ -3,12 +3,13 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use hex;
-use reqwest;
-use reqwest::{header, Client as ReqwestClient, Method, Request, Response, StatusCode};
 use ring::{digest, hkdf, hmac};
 use serde_json;
-use std;
+use std::collections::HashMap;
 use util::Xorable;
+#[cfg(feature = "browserid")]
+use viaduct::Method;
+use viaduct::{status_codes, Request, Response};
 
 #[cfg(feature = "browserid")]
 use self::browser_id::rsa::RSABrowserIDKeyPair;
@@ -28,6 +29,107 @@ const HKDF_SALT: [u8; 32] = [0b0; 32];
 const KEY_LENGTH: usize = 32;
 const SIGN_DURATION_MS: u64 = 24 * 60 * 60 * 1000;
 
+/// The requests made by `FirefoxAccount`'s OAuth flows. `HttpClient` sends
+/// them to the servers in the `Config`, and tests replace it with one that
+/// returns canned responses (see `test_support::FakeClient`).
+pub(crate) trait FxAClient: Send {
+    fn oauth_token_with_code(
+        &self,
+        config: &Config,
+        code: &str,
+        code_verifier: &str,
+        client_id: &str,
+    ) -> Result<OAuthTokenResponse>;
+
+    fn oauth_token_with_refresh_token(
+        &self,
+        config: &Config,
+        client_id: &str,
+        refresh_token: &str,
+        scopes: &[&str],
+    ) -> Result<OAuthTokenResponse>;
+
+    fn profile(
+        &self,
+        config: &Config,
+        profile_access_token: &str,
+        etag: Option<String>,
+    ) -> Result<Option<ResponseAndETag<ProfileResponse>>>;
+
+    fn pending_commands(
+        &self,
+        config: &Config,
+        refresh_token: &str,
+        index: u64,
+        limit: u64,
+    ) -> Result<PendingCommandsResponse>;
+
+    fn devices(&self, config: &Config, refresh_token: &str) -> Result<Vec<DeviceResponse>>;
+
+    fn update_device(
+        &self,
+        config: &Config,
+        refresh_token: &str,
+        available_commands: &HashMap<String, String>,
+    ) -> Result<()>;
+}
+
+pub(crate) struct HttpClient;
+
+impl FxAClient for HttpClient {
+    fn oauth_token_with_code(
+        &self,
+        config: &Config,
+        code: &str,
+        code_verifier: &str,
+        client_id: &str,
+    ) -> Result<OAuthTokenResponse> {
+        Client::new(config).oauth_token_with_code(code, code_verifier, client_id)
+    }
+
+    fn oauth_token_with_refresh_token(
+        &self,
+        config: &Config,
+        client_id: &str,
+        refresh_token: &str,
+        scopes: &[&str],
+    ) -> Result<OAuthTokenResponse> {
+        Client::new(config).oauth_token_with_refresh_token(client_id, refresh_token, scopes)
+    }
+
+    fn profile(
+        &self,
+        config: &Config,
+        profile_access_token: &str,
+        etag: Option<String>,
+    ) -> Result<Option<ResponseAndETag<ProfileResponse>>> {
+        Client::new(config).profile(profile_access_token, etag)
+    }
+
+    fn pending_commands(
+        &self,
+        config: &Config,
+        refresh_token: &str,
+        index: u64,
+        limit: u64,
+    ) -> Result<PendingCommandsResponse> {
+        Client::new(config).pending_commands(refresh_token, index, limit)
+    }
+
+    fn devices(&self, config: &Config, refresh_token: &str) -> Result<Vec<DeviceResponse>> {
+        Client::new(config).devices(refresh_token)
+    }
+
+    fn update_device(
+        &self,
+        config: &Config,
+        refresh_token: &str,
+        available_commands: &HashMap<String, String>,
+    ) -> Result<()> {
+        Client::new(config).update_device(refresh_token, available_commands)
+    }
+}
+
 pub struct Client<'a> {
     config: &'a Config,
 }
@@ -71,24 +173,20 @@ impl<'a> Client<'a> {
 
     #[cfg(feature = "browserid")]
     pub fn login(&self, email: &str, auth_pwd: &str, get_keys: bool) -> Result<LoginResponse> {
-        let url = self.config.auth_url_path("v1/account/login")?;
+        let mut url = self.config.auth_url_path("v1/account/login")?;
+        url.query_pairs_mut().append_pair("keys", &get_keys.to_string());
         let parameters = json!({
           "email": email,
           "authPW": auth_pwd
         });
-        let client = ReqwestClient::new();
-        let request = client
-            .request(Method::POST, url)
-            .query(&[("keys", get_keys)])
-            .body(parameters.to_string())
-            .build()?;
+        let request = Request::post(url).json(&parameters)?;
         Client::make_request(request)?.json().map_err(|e| e.into())
     }
 
     pub fn account_status(&self, uid: &String) -> Result<AccountStatusResponse> {
-        let url = self.config.auth_url_path("v1/account/status")?;
-        let client = ReqwestClient::new();
-        let request = client.get(url).query(&[("uid", uid)]).build()?;
+        let mut url = self.config.auth_url_path("v1/account/status")?;
+        url.query_pairs_mut().append_pair("uid", uid);
+        let request = Request::get(url);
         Client::make_request(request)?.json().map_err(|e| e.into())
     }
 
@@ -103,7 +201,7 @@ impl<'a> Client<'a> {
             KEY_LENGTH * 3,
         );
         let key_request_key = &key[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)];
-        let request = HAWKRequestBuilder::new(Method::GET, url, &key).build()?;
+        let request = HAWKRequestBuilder::new(Method::Get, url, &key).build()?;
         let json: serde_json::Value = Client::make_request(request)?.json()?;
         let bundle = match json["bundle"].as_str() {
             Some(bundle) => bundle,
@@ -140,7 +238,7 @@ impl<'a> Client<'a> {
     ) -> Result<RecoveryEmailStatusResponse> {
         let url = self.config.auth_url_path("v1/recovery_email/status")?;
         let key = Client::derive_key_from_session_token(session_token)?;
-        let request = HAWKRequestBuilder::new(Method::GET, url, &key).build()?;
+        let request = HAWKRequestBuilder::new(Method::Get, url, &key).build()?;
         Client::make_request(request)?.json().map_err(|e| e.into())
     }
 
@@ -150,24 +248,62 @@ impl<'a> Client<'a> {
         etag: Option<String>,
     ) -> Result<Option<ResponseAndETag<ProfileResponse>>> {
         let url = self.config.userinfo_endpoint()?;
-        let client = ReqwestClient::new();
-        let mut builder = client.request(Method::GET, url)
-            .header(header::AUTHORIZATION, format!("Bearer {}", profile_access_token));
+        let mut request = Request::get(url)
+            .header("authorization", format!("Bearer {}", profile_access_token))?;
         if let Some(etag) = etag {
-            builder = builder.header(header::IF_NONE_MATCH, format!("\"{}\"", etag));
+            request = request.header("if-none-match", format!("\"{}\"", etag))?;
         }
-        let request = builder.build()?;
-        let mut resp = Client::make_request(request)?;
-        if resp.status() == StatusCode::NOT_MODIFIED {
+        let resp = Client::make_request(request)?;
+        if resp.status == status_codes::NOT_MODIFIED {
             return Ok(None);
         }
-        let etag = resp.headers().get(header::ETAG).and_then(|v| v.to_str().ok()).map(|s| s.to_owned());
+        let etag = resp.header("etag").map(|s| s.to_owned());
         Ok(Some(ResponseAndETag {
             etag,
             response: resp.json()?,
         }))
     }
 
+    /// Fetches up to `limit` of the commands queued for this device, starting
+    /// at `index`.
+    pub fn pending_commands(
+        &self,
+        refresh_token: &str,
+        index: u64,
+        limit: u64,
+    ) -> Result<PendingCommandsResponse> {
+        let mut url = self.config.auth_url_path("v1/account/device/commands")?;
+        url.query_pairs_mut()
+            .append_pair("index", &index.to_string())
+            .append_pair("limit", &limit.to_string());
+        let request = Request::get(url)
+            .header("authorization", format!("Bearer {}", refresh_token))?;
+        Client::make_request(request)?.json().map_err(|e| e.into())
+    }
+
+    /// Fetches the devices connected to the account.
+    pub fn devices(&self, refresh_token: &str) -> Result<Vec<DeviceResponse>> {
+        let url = self.config.auth_url_path("v1/account/devices")?;
+        let request = Request::get(url)
+            .header("authorization", format!("Bearer {}", refresh_token))?;
+        Client::make_request(request)?.json().map_err(|e| e.into())
+    }
+
+    /// Replaces the commands this device accepts.
+    pub fn update_device(
+        &self,
+        refresh_token: &str,
+        available_commands: &HashMap<String, String>,
+    ) -> Result<()> {
+        let url = self.config.auth_url_path("v1/account/device")?;
+        let body = json!({ "availableCommands": available_commands });
+        let request = Request::post(url)
+            .header("authorization", format!("Bearer {}", refresh_token))?
+            .json(&body)?;
+        Client::make_request(request)?;
+        Ok(())
+    }
+
     #[cfg(feature = "browserid")]
     pub fn oauth_token_with_session_token(
         &self,
@@ -187,7 +323,7 @@ impl<'a> Client<'a> {
         });
         let key = Client::derive_key_from_session_token(session_token)?;
         let url = self.config.authorization_endpoint()?;
-        let request = HAWKRequestBuilder::new(Method::POST, url, &key)
+        let request = HAWKRequestBuilder::new(Method::Post, url, &key)
             .body(parameters)
             .build()?;
         Client::make_request(request)?.json().map_err(|e| e.into())
@@ -224,12 +360,7 @@ impl<'a> Client<'a> {
 
     fn make_oauth_token_request(&self, body: serde_json::Value) -> Result<OAuthTokenResponse> {
         let url = self.config.token_endpoint()?;
-        let client = ReqwestClient::new();
-        let request = client
-            .request(Method::POST, url)
-            .header(header::CONTENT_TYPE, "application/json")
-            .body(body.to_string())
-            .build()?;
+        let request = Request::post(url).json(&body)?;
         Client::make_request(request)?.json().map_err(|e| e.into())
     }
 
@@ -242,7 +373,7 @@ impl<'a> Client<'a> {
         });
         let key = Client::derive_key_from_session_token(session_token)?;
         let url = self.config.auth_url_path("v1/certificate/sign")?;
-        let request = HAWKRequestBuilder::new(Method::POST, url, &key)
+        let request = HAWKRequestBuilder::new(Method::Post, url, &key)
             .body(parameters)
             .build()?;
         Client::make_request(request)?.json().map_err(|e| e.into())
@@ -277,14 +408,12 @@ impl<'a> Client<'a> {
     }
 
     fn make_request(request: Request) -> Result<Response> {
-        let client = ReqwestClient::new();
-        let mut resp = client.execute(request)?;
-        let status = resp.status();
+        let resp = request.send()?;
 
-        if status.is_success() || status == StatusCode::NOT_MODIFIED {
+        if resp.is_success() || resp.status == status_codes::NOT_MODIFIED {
             Ok(resp)
         } else {
-            let json: std::result::Result<serde_json::Value, reqwest::Error> = resp.json();
+            let json: ::viaduct::Result<serde_json::Value> = resp.json();
             match json {
                 Ok(json) => Err(ErrorKind::RemoteError {
                     code: json["code"].as_u64().unwrap_or(0),
@@ -293,7 +422,7 @@ impl<'a> Client<'a> {
                     message: json["message"].as_str().unwrap_or("").to_string(),
                     info: json["info"].as_str().unwrap_or("").to_string(),
                 }.into()),
-                Err(_) => Err(resp.error_for_status().unwrap_err().into()),
+                Err(_) => Err(ErrorKind::UnexpectedStatus(resp.status).into()),
             }
         }
     }
@@ -360,6 +489,46 @@ pub struct ProfileResponse {
     pub two_factor_authentication: bool,
 }
 
+#[derive(Deserialize)]
+pub struct PendingCommandsResponse {
+    /// The index of the latest command in the queue.
+    pub index: u64,
+    /// False if there are more commands after these.
+    pub last: Option<bool>,
+    pub messages: Vec<PendingCommand>,
+}
+
+#[derive(Deserialize)]
+pub struct PendingCommand {
+    pub index: u64,
+    pub data: CommandData,
+}
+
+#[derive(Deserialize)]
+pub struct CommandData {
+    pub command: String,
+    #[serde(default)]
+    pub payload: serde_json::Value,
+    /// The id of the device that sent the command.
+    pub sender: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeviceResponse {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    #[serde(rename = "isCurrentDevice")]
+    pub is_current_device: bool,
+    #[serde(rename = "lastAccessTime")]
+    pub last_access_time: Option<u64>,
+    /// Maps the URLs of the commands the device accepts to the data needed
+    /// to send them. Missing for devices that never registered any.
+    #[serde(rename = "availableCommands", default)]
+    pub available_commands: HashMap<String, String>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
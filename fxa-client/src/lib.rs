@@ -17,7 +17,6 @@ extern crate log;
 #[cfg(feature = "browserid")]
 extern crate openssl;
 extern crate regex;
-extern crate reqwest;
 extern crate ring;
 extern crate serde;
 #[macro_use]
@@ -26,37 +25,56 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate untrusted;
 extern crate url;
+extern crate viaduct;
 
 use std::collections::HashMap;
 use std::mem;
 use std::panic::RefUnwindSafe;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use account_state::is_auth_error;
 #[cfg(feature = "browserid")]
 use self::login_sm::LoginState::*;
 #[cfg(feature = "browserid")]
 use self::login_sm::*;
+use commands::CommandsStateV1;
+use device::{DeviceStateV1, OLDSYNC_SCOPE};
 use errors::*;
 #[cfg(feature = "browserid")]
 use http_client::browser_id::jwt_utils;
-use http_client::{Client, OAuthTokenResponse, ProfileResponse};
+#[cfg(feature = "browserid")]
+use http_client::Client;
+use http_client::{FxAClient, HttpClient, OAuthTokenResponse, ProfileResponse};
+use push::PushMessage;
+use random::{RandomSource, SystemRandomSource};
 use ring::digest;
-use ring::rand::{SecureRandom, SystemRandom};
 use scoped_keys::ScopedKeysFlow;
 use url::Url;
 use util::now;
 
+mod account_state;
+mod commands;
 mod config;
+mod device;
 pub mod errors;
 mod http_client;
 #[cfg(feature = "browserid")]
 mod login_sm;
 mod oauth;
+mod push;
+mod random;
 mod scoped_keys;
+#[cfg(test)]
+mod test_support;
 mod util;
 
+pub use account_state::AccountState;
+pub use commands::SEND_TAB_COMMAND;
 pub use config::Config;
+pub use device::{Capability, CommandDataCallback, Device};
 pub use http_client::ProfileResponse as Profile;
+pub use push::AccountEvent;
+pub use scoped_keys::ScopedKey;
 
 // If a cached token has less than `OAUTH_MIN_TIME_LEFT` seconds left to live,
 // it will be considered already expired.
@@ -64,10 +82,6 @@ const OAUTH_MIN_TIME_LEFT: u64 = 60;
 // A cached profile response is considered fresh for `PROFILE_FRESHNESS_THRESHOLD` ms.
 const PROFILE_FRESHNESS_THRESHOLD: u64 = 120000; // 2 minutes
 
-lazy_static! {
-    static ref RNG: SystemRandom = SystemRandom::new();
-}
-
 #[derive(Clone, Serialize, Deserialize)]
 struct StateV1 {
     client_id: String,
@@ -76,6 +90,27 @@ struct StateV1 {
     #[cfg(feature = "browserid")]
     login_state: LoginState,
     oauth_cache: HashMap<String, OAuthInfo>,
+    /// Clients added with `add_client`, keyed by client id. The account
+    /// itself (the config and login state) is shared, but each client has its
+    /// own tokens, since tokens and refresh tokens are bound to the client
+    /// that requested them.
+    #[serde(default)]
+    additional_clients: HashMap<String, ClientStateV1>,
+    /// Where we're up to in the queue of commands sent to this device.
+    #[serde(default)]
+    commands: CommandsStateV1,
+    /// The capabilities we register for this device.
+    #[serde(default)]
+    device: DeviceStateV1,
+    /// True if we're in `AccountState::AuthProblems`.
+    #[serde(default)]
+    auth_problems: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ClientStateV1 {
+    redirect_uri: String,
+    oauth_cache: HashMap<String, OAuthInfo>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -113,7 +148,10 @@ pub struct FirefoxAccount {
     state: StateV1,
     flow_store: HashMap<String, OAuthFlow>,
     persist_callback: Option<PersistCallback>,
+    command_data_callback: Option<CommandDataCallback>,
     profile_cache: Option<CachedResponse<ProfileResponse>>,
+    client: Box<FxAClient>,
+    random: Box<RandomSource>,
 }
 
 pub type SyncKeys = (String, String);
@@ -143,10 +181,22 @@ impl FirefoxAccount {
             state,
             flow_store: HashMap::new(),
             persist_callback: None,
+            command_data_callback: None,
             profile_cache: None,
+            client: Box::new(HttpClient),
+            random: Box::new(SystemRandomSource),
         }
     }
 
+    /// Replace the server requests and randomness used by the OAuth flows,
+    /// so that tests can run them against recorded responses.
+    #[cfg(test)]
+    fn with_seams(mut self, client: Box<FxAClient>, random: Box<RandomSource>) -> Self {
+        self.client = client;
+        self.random = random;
+        self
+    }
+
     pub fn new(config: Config, client_id: &str, redirect_uri: &str) -> FirefoxAccount {
         FirefoxAccount::from_state(StateV1 {
             client_id: client_id.to_string(),
@@ -155,6 +205,10 @@ impl FirefoxAccount {
             #[cfg(feature = "browserid")]
             login_state: Unknown,
             oauth_cache: HashMap::new(),
+            additional_clients: HashMap::new(),
+            commands: CommandsStateV1::default(),
+            device: DeviceStateV1::default(),
+            auth_problems: false,
         })
     }
 
@@ -189,6 +243,10 @@ impl FirefoxAccount {
             config,
             login_state,
             oauth_cache: HashMap::new(),
+            additional_clients: HashMap::new(),
+            commands: CommandsStateV1::default(),
+            device: DeviceStateV1::default(),
+            auth_problems: false,
         }))
     }
 
@@ -199,11 +257,221 @@ impl FirefoxAccount {
         }
     }
 
+    /// Serializes everything needed to restore this account with `from_json`,
+    /// including refresh tokens and scoped keys, so the result should be
+    /// stored as securely as the keys themselves.
     pub fn to_json(&self) -> Result<String> {
         let state = State::V1(self.state.clone());
         serde_json::to_string(&state).map_err(|e| e.into())
     }
 
+    /// Like `to_json`, but without any key material, for handing the account
+    /// to a less trusted process. An account restored from this can still
+    /// get (and refresh) access tokens, but they won't have keys.
+    ///
+    /// The scoped keys are removed from the OAuth token cache, and with the
+    /// `browserid` feature, the session state (which includes the session
+    /// token and kB) is reset to `Unknown`. Don't persist the state of an
+    /// account restored from this over the original.
+    pub fn to_json_scrubbed(&self) -> Result<String> {
+        let mut state = self.state.clone();
+        for info in state.oauth_cache.values_mut() {
+            info.scoped_keys.clear();
+        }
+        for client in state.additional_clients.values_mut() {
+            for info in client.oauth_cache.values_mut() {
+                info.scoped_keys.clear();
+            }
+        }
+        #[cfg(feature = "browserid")]
+        {
+            state.login_state = Unknown;
+        }
+        serde_json::to_string(&State::V1(state)).map_err(|e| e.into())
+    }
+
+    /// Like `from_json`, but fails with `ErrorKind::EnvironmentMismatch` if
+    /// the account was persisted with a config for a different FxA
+    /// environment than `config`. Apps that can switch environments at
+    /// runtime (see `switch_config`) should restore accounts with this, so
+    /// that one made on stage is never used with the production servers, or
+    /// the other way around.
+    pub fn from_json_for_config(data: &str, config: &Config) -> Result<FirefoxAccount> {
+        let fxa = FirefoxAccount::from_json(data)?;
+        if !fxa.state.config.is_same_environment(config) {
+            return Err(ErrorKind::EnvironmentMismatch {
+                expected: config.environment().to_string(),
+                found: fxa.state.config.environment().to_string(),
+            }.into());
+        }
+        Ok(fxa)
+    }
+
+    /// Returns true if we have any tokens for the account, for any client,
+    /// or (with the `browserid` feature) a session, or if the account is in
+    /// `AccountState::AuthProblems`.
+    pub fn is_signed_in(&self) -> bool {
+        if self.state.auth_problems
+            || !self.state.oauth_cache.is_empty()
+            || self.state.additional_clients.values().any(|client| !client.oauth_cache.is_empty())
+        {
+            return true;
+        }
+        #[cfg(feature = "browserid")]
+        {
+            match self.state.login_state {
+                Unknown | Separated(_) => {}
+                _ => return true,
+            }
+        }
+        false
+    }
+
+    /// Switch to another FxA environment (for example, from production to
+    /// stage), keeping the registered clients. The new config is persisted
+    /// along with the rest of the state. This fails with
+    /// `ErrorKind::SwitchConfigWhileSignedIn` if `is_signed_in`, since the
+    /// account doesn't exist on the other environment: sign out first, or use
+    /// `wipe_and_switch_config`.
+    pub fn switch_config(&mut self, config: Config) -> Result<()> {
+        if self.is_signed_in() {
+            return Err(ErrorKind::SwitchConfigWhileSignedIn.into());
+        }
+        self.set_config(config);
+        Ok(())
+    }
+
+    /// Like `switch_config`, but signs out first by forgetting every token
+    /// (without revoking them) and, with the `browserid` feature, the session.
+    pub fn wipe_and_switch_config(&mut self, config: Config) {
+        self.state.oauth_cache.clear();
+        for client in self.state.additional_clients.values_mut() {
+            client.oauth_cache.clear();
+        }
+        #[cfg(feature = "browserid")]
+        {
+            self.state.login_state = Unknown;
+        }
+        self.state.auth_problems = false;
+        self.state.commands = CommandsStateV1::default();
+        self.state.device = DeviceStateV1::default();
+        self.set_config(config);
+    }
+
+    fn set_config(&mut self, config: Config) {
+        info!("Switching FxA environment to {}", config.environment());
+        self.state.config = config;
+        // Flows in progress, and the cached profile, are for the old one.
+        self.flow_store.clear();
+        self.profile_cache = None;
+        self.maybe_call_persist_callback();
+    }
+
+    /// Whether the account can be used. See `AccountState` for how it
+    /// changes.
+    pub fn account_state(&self) -> AccountState {
+        if self.state.auth_problems {
+            AccountState::AuthProblems
+        } else if self.is_signed_in() {
+            AccountState::Connected
+        } else {
+            AccountState::Disconnected
+        }
+    }
+
+    /// Try to get out of `AccountState::AuthProblems` without making the
+    /// user sign in again, by refreshing one of our tokens. This recovers if
+    /// the server rejected our credentials because of a temporary problem on
+    /// its side, but not if the password was changed, or our refresh token
+    /// was revoked: then this returns `AuthProblems` again, and the app
+    /// should ask the user to sign in with `begin_oauth_flow`.
+    ///
+    /// Returns the new state. Fails (and stays in `AuthProblems`) if we
+    /// can't reach the server.
+    pub fn retry_authentication(&mut self) -> Result<AccountState> {
+        if !self.state.auth_problems {
+            return Ok(self.account_state());
+        }
+        let (client_id, previous) = match self.find_refreshable_token() {
+            Some(found) => found,
+            None => {
+                info!("No refresh token to retry authentication with");
+                return Ok(AccountState::AuthProblems);
+            }
+        };
+        let result = {
+            let refresh_token = previous.refresh_token.as_ref().map(String::as_str).unwrap_or("");
+            let scopes: Vec<&str> = previous.scopes.iter().map(String::as_str).collect();
+            self.client.oauth_token_with_refresh_token(
+                &self.state.config,
+                &client_id,
+                refresh_token,
+                &scopes,
+            )
+        };
+        match result {
+            Ok(resp) => {
+                // This leaves `AuthProblems`.
+                self.handle_oauth_token_response(&client_id, resp, None, Some(previous))?;
+                info!("Retrying authentication succeeded");
+                Ok(self.account_state())
+            }
+            Err(ref e) if is_auth_error(e) => {
+                info!("Retrying authentication failed: {}", e);
+                Ok(AccountState::AuthProblems)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn find_refreshable_token(&self) -> Option<(String, OAuthInfo)> {
+        let main_client = Some((&self.state.client_id, &self.state.oauth_cache));
+        let other_clients = self
+            .state
+            .additional_clients
+            .iter()
+            .map(|(client_id, client)| (client_id, &client.oauth_cache));
+        for (client_id, oauth_cache) in main_client.into_iter().chain(other_clients) {
+            if let Some(info) = oauth_cache.values().find(|info| info.refresh_token.is_some()) {
+                return Some((client_id.clone(), info.clone()));
+            }
+        }
+        None
+    }
+
+    /// Fails with `ErrorKind::AuthProblems` if we're in that state, for the
+    /// methods that need a token (or session).
+    fn check_no_auth_problems(&self) -> Result<()> {
+        if self.state.auth_problems {
+            return Err(ErrorKind::AuthProblems.into());
+        }
+        Ok(())
+    }
+
+    /// Enters `AccountState::AuthProblems` (and fails with
+    /// `ErrorKind::AuthProblems`) if `result` is an error that means the
+    /// server rejected our credentials.
+    fn check_auth_result<T>(&mut self, result: Result<T>) -> Result<T> {
+        match result {
+            Err(ref e) if is_auth_error(e) => {
+                warn!("The server rejected our credentials: {}", e);
+                self.enter_auth_problems();
+                Err(ErrorKind::AuthProblems.into())
+            }
+            result => result,
+        }
+    }
+
+    fn enter_auth_problems(&mut self) {
+        if self.state.auth_problems {
+            return;
+        }
+        info!("Account needs reauthentication");
+        self.state.auth_problems = true;
+        self.profile_cache = None;
+        self.maybe_call_persist_callback();
+    }
+
     #[cfg(feature = "browserid")]
     fn to_married(&mut self) -> Option<&MarriedState> {
         self.advance();
@@ -221,10 +489,76 @@ impl FirefoxAccount {
         self.state.login_state = state_machine.advance(state);
     }
 
+    /// Register another OAuth client for this account, for apps that embed
+    /// more than one FxA relying service, each with its own client id (and
+    /// scopes). The new client shares the account's login state, but gets
+    /// its own tokens: use `begin_oauth_flow_for_client` and
+    /// `get_access_token_for_client` to get them. Adding a client that's
+    /// already registered only updates its redirect URI.
+    pub fn add_client(&mut self, client_id: &str, redirect_uri: &str) {
+        if client_id == self.state.client_id {
+            self.state.redirect_uri = redirect_uri.to_string();
+        } else {
+            self.state
+                .additional_clients
+                .entry(client_id.to_string())
+                .or_insert_with(|| ClientStateV1 {
+                    redirect_uri: String::new(),
+                    oauth_cache: HashMap::new(),
+                })
+                .redirect_uri = redirect_uri.to_string();
+        }
+        self.maybe_call_persist_callback();
+    }
+
+    /// Forget a client added with `add_client`, along with its tokens.
+    /// Returns false if there was no such client. The account's own client
+    /// can't be removed.
+    pub fn remove_client(&mut self, client_id: &str) -> bool {
+        let removed = self.state.additional_clients.remove(client_id).is_some();
+        if removed {
+            self.flow_store.retain(|_, flow| flow.client_id != client_id);
+            self.maybe_call_persist_callback();
+        }
+        removed
+    }
+
+    fn redirect_uri_for_client(&self, client_id: &str) -> Result<&str> {
+        if client_id == self.state.client_id {
+            return Ok(self.state.redirect_uri.as_str());
+        }
+        match self.state.additional_clients.get(client_id) {
+            Some(client) => Ok(client.redirect_uri.as_str()),
+            None => Err(ErrorKind::UnknownClient(client_id.to_string()).into()),
+        }
+    }
+
+    fn oauth_cache_for_client(&self, client_id: &str) -> Option<&HashMap<String, OAuthInfo>> {
+        if client_id == self.state.client_id {
+            Some(&self.state.oauth_cache)
+        } else {
+            self.state.additional_clients.get(client_id).map(|client| &client.oauth_cache)
+        }
+    }
+
     fn oauth_cache_store(&mut self, info: &OAuthInfo) {
+        let client_id = self.state.client_id.clone();
+        self.oauth_cache_store_for_client(&client_id, info);
+    }
+
+    fn oauth_cache_store_for_client(&mut self, client_id: &str, info: &OAuthInfo) {
         let info = info.clone();
         let scope_key = info.scopes.join(" ");
-        self.state.oauth_cache.insert(scope_key, info);
+        let cache = if client_id == self.state.client_id {
+            &mut self.state.oauth_cache
+        } else {
+            match self.state.additional_clients.get_mut(client_id) {
+                Some(client) => &mut client.oauth_cache,
+                // The client was removed while a flow was in progress.
+                None => return,
+            }
+        };
+        cache.insert(scope_key, info);
     }
 
     fn scope_implies_scopes(scope: &str, match_scopes: &[&str]) -> Result<bool> {
@@ -239,11 +573,23 @@ impl FirefoxAccount {
     }
 
     fn oauth_cache_find(&self, requested_scopes: &[&str]) -> Option<&OAuthInfo> {
+        self.oauth_cache_find_for_client(&self.state.client_id, requested_scopes)
+    }
+
+    fn oauth_cache_find_for_client(
+        &self,
+        client_id: &str,
+        requested_scopes: &[&str],
+    ) -> Option<&OAuthInfo> {
+        let oauth_cache = match self.oauth_cache_for_client(client_id) {
+            Some(oauth_cache) => oauth_cache,
+            None => return None,
+        };
         // First we try to get the exact same scope.
-        if let Some(info) = self.state.oauth_cache.get(&requested_scopes.join(" ")) {
+        if let Some(info) = oauth_cache.get(&requested_scopes.join(" ")) {
             return Some(info);
         }
-        for (scope_key, info) in self.state.oauth_cache.iter() {
+        for (scope_key, info) in oauth_cache.iter() {
             if FirefoxAccount::scope_implies_scopes(scope_key, requested_scopes).unwrap_or(false) {
                 return Some(info);
             }
@@ -251,24 +597,67 @@ impl FirefoxAccount {
         None
     }
 
+    /// Get an access token for `scope`, along with the key for that scope if
+    /// the token was obtained by a flow that requested keys.
+    ///
+    /// Cached tokens are reused (and refreshed if they're about to expire),
+    /// including tokens granted for a broader set of scopes. If we don't have
+    /// a suitable token and can't get one, this returns `None`, and the caller
+    /// must start an OAuth flow with `begin_oauth_flow`.
+    ///
+    /// Fails with `ErrorKind::AuthProblems` if the account needs to be
+    /// reauthenticated (see `AccountState`), including when the server
+    /// rejects our refresh token.
+    pub fn get_access_token(&mut self, scope: &str) -> Result<Option<AccessTokenInfo>> {
+        let client_id = self.state.client_id.clone();
+        self.get_access_token_for_client(&client_id, scope)
+    }
+
+    /// Like `get_access_token`, but for a client added with `add_client`.
+    /// Tokens are never shared between clients, even if one client has a
+    /// token for the requested scope.
+    pub fn get_access_token_for_client(
+        &mut self,
+        client_id: &str,
+        scope: &str,
+    ) -> Result<Option<AccessTokenInfo>> {
+        match self.fetch_oauth_info(client_id, &[scope])? {
+            Some(info) => Ok(Some(info.to_access_token_info(scope))),
+            None => Ok(None),
+        }
+    }
+
+    #[deprecated(note = "Use `get_access_token`, which returns typed keys, instead")]
     pub fn get_oauth_token(&mut self, scopes: &[&str]) -> Result<Option<OAuthInfo>> {
-        let mut refresh_token = None;
-        if let Some(cached_oauth_info) = self.oauth_cache_find(scopes) {
+        let client_id = self.state.client_id.clone();
+        self.fetch_oauth_info(&client_id, scopes)
+    }
+
+    /// Try to get a previously obtained cached token, refreshing it with the
+    /// `refresh_token` (or `session_token`) if it's about to expire.
+    fn fetch_oauth_info(&mut self, client_id: &str, scopes: &[&str]) -> Result<Option<OAuthInfo>> {
+        if self.oauth_cache_for_client(client_id).is_none() {
+            return Err(ErrorKind::UnknownClient(client_id.to_string()).into());
+        }
+        self.check_no_auth_problems()?;
+        let mut previous = None;
+        if let Some(cached_oauth_info) = self.oauth_cache_find_for_client(client_id, scopes) {
             if cached_oauth_info.expires_at > util::now_secs() + OAUTH_MIN_TIME_LEFT {
                 return Ok(Some(cached_oauth_info.clone()));
             }
-            refresh_token = cached_oauth_info.refresh_token.clone();
+            previous = Some(cached_oauth_info.clone());
         }
+        let refresh_token = previous.as_ref().and_then(|info| info.refresh_token.clone());
         // This is a bit awkward, borrow checker weirdness.
-        let resp;
+        let result;
         {
             if let Some(refresh_token) = refresh_token {
-                let client = Client::new(&self.state.config);
-                resp = client.oauth_token_with_refresh_token(
-                    &self.state.client_id,
+                result = self.client.oauth_token_with_refresh_token(
+                    &self.state.config,
+                    client_id,
                     &refresh_token,
                     &scopes,
-                )?;
+                );
             } else {
                 #[cfg(feature = "browserid")]
                 {
@@ -276,11 +665,11 @@ impl FirefoxAccount {
                         FirefoxAccount::session_token_from_state(&self.state.login_state)
                     {
                         let client = Client::new(&self.state.config);
-                        resp = client.oauth_token_with_session_token(
-                            &self.state.client_id,
+                        result = client.oauth_token_with_session_token(
+                            client_id,
                             session_token,
                             &scopes,
-                        )?;
+                        );
                     } else {
                         return Ok(None);
                     }
@@ -291,7 +680,8 @@ impl FirefoxAccount {
                 }
             }
         }
-        Ok(Some(self.handle_oauth_token_response(resp, None)?))
+        let resp = self.check_auth_result(result)?;
+        Ok(Some(self.handle_oauth_token_response(client_id, resp, None, previous)?))
     }
 
     pub fn begin_pairing_flow(&mut self, pairing_url: &str, scopes: &[&str]) -> Result<String> {
@@ -306,22 +696,47 @@ impl FirefoxAccount {
     }
 
     pub fn begin_oauth_flow(&mut self, scopes: &[&str], wants_keys: bool) -> Result<String> {
+        let client_id = self.state.client_id.clone();
+        self.begin_oauth_flow_for_client(&client_id, scopes, wants_keys)
+    }
+
+    /// Like `begin_oauth_flow`, but for a client added with `add_client`.
+    /// The flow is completed with `complete_oauth_flow` as usual, and the
+    /// resulting token is stored with that client's tokens.
+    pub fn begin_oauth_flow_for_client(
+        &mut self,
+        client_id: &str,
+        scopes: &[&str],
+        wants_keys: bool,
+    ) -> Result<String> {
         let mut url = self.state.config.authorization_endpoint()?;
         url.query_pairs_mut()
             .append_pair("action", "email")
             .append_pair("response_type", "code");
 
-        self.oauth_flow(url, scopes, wants_keys)
+        self.oauth_flow_for_client(client_id, url, scopes, wants_keys)
     }
 
-    pub fn oauth_flow(&mut self, mut url: Url, scopes: &[&str], wants_keys: bool) -> Result<String> {
-        let state = FirefoxAccount::random_base64_url_string(16)?;
-        let code_verifier = FirefoxAccount::random_base64_url_string(43)?;
+    pub fn oauth_flow(&mut self, url: Url, scopes: &[&str], wants_keys: bool) -> Result<String> {
+        let client_id = self.state.client_id.clone();
+        self.oauth_flow_for_client(&client_id, url, scopes, wants_keys)
+    }
+
+    fn oauth_flow_for_client(
+        &mut self,
+        client_id: &str,
+        mut url: Url,
+        scopes: &[&str],
+        wants_keys: bool,
+    ) -> Result<String> {
+        let redirect_uri = self.redirect_uri_for_client(client_id)?.to_string();
+        let state = self.random_base64_url_string(16)?;
+        let code_verifier = self.random_base64_url_string(43)?;
         let code_challenge = digest::digest(&digest::SHA256, &code_verifier.as_bytes());
         let code_challenge = base64::encode_config(&code_challenge, base64::URL_SAFE_NO_PAD);
         url.query_pairs_mut()
-            .append_pair("client_id", &self.state.client_id)
-            .append_pair("redirect_uri", &self.state.redirect_uri)
+            .append_pair("client_id", client_id)
+            .append_pair("redirect_uri", &redirect_uri)
             .append_pair("scope", &scopes.join(" "))
             .append_pair("state", &state)
             .append_pair("code_challenge_method", "S256")
@@ -329,7 +744,7 @@ impl FirefoxAccount {
             .append_pair("access_type", "offline");
         let scoped_keys_flow = match wants_keys {
             true => {
-                let flow = ScopedKeysFlow::with_random_key(&*RNG)?;
+                let flow = self.random.scoped_keys_flow()?;
                 let jwk_json = flow.generate_keys_jwk()?;
                 let keys_jwk = base64::encode_config(&jwk_json, base64::URL_SAFE_NO_PAD);
                 url.query_pairs_mut().append_pair("keys_jwk", &keys_jwk);
@@ -340,6 +755,7 @@ impl FirefoxAccount {
         self.flow_store.insert(
             state.clone(), // Since state is supposed to be unique, we use it to key our flows.
             OAuthFlow {
+                client_id: client_id.to_string(),
                 scoped_keys_flow,
                 code_verifier,
             },
@@ -355,36 +771,54 @@ impl FirefoxAccount {
                 Some(flow) => flow,
                 None => return Err(ErrorKind::UnknownOAuthState.into()),
             };
-            let client = Client::new(&self.state.config);
-            resp = client.oauth_token_with_code(&code, &flow.code_verifier, &self.state.client_id)?;
+            resp = self.client.oauth_token_with_code(
+                &self.state.config,
+                &code,
+                &flow.code_verifier,
+                &flow.client_id,
+            )?;
         }
         let oauth_flow = match self.flow_store.remove(state) {
             Some(oauth_flow) => oauth_flow,
             None => return Err(ErrorKind::UnknownOAuthState.into()),
         };
-        self.handle_oauth_token_response(resp, oauth_flow.scoped_keys_flow)
+        self.handle_oauth_token_response(
+            &oauth_flow.client_id,
+            resp,
+            oauth_flow.scoped_keys_flow,
+            None,
+        )
     }
 
+    /// `previous` is the token we refreshed, if any. Refresh responses don't
+    /// include a refresh token or keys, so we keep the ones we had; otherwise
+    /// the next refresh would be impossible, and the keys would be lost.
     fn handle_oauth_token_response(
         &mut self,
+        client_id: &str,
         resp: OAuthTokenResponse,
         scoped_keys_flow: Option<ScopedKeysFlow>,
+        previous: Option<OAuthInfo>,
     ) -> Result<OAuthInfo> {
         let granted_scopes = resp.scope.split(" ").map(|s| s.to_string()).collect();
         // This assumes that if the server returns keys_jwe, the jwk argument is Some.
-        let keys = match resp.keys_jwe {
+        let scoped_keys = match resp.keys_jwe {
             Some(jwe) => {
                 let scoped_keys_flow = scoped_keys_flow.expect(
                     "Insane state! If we are getting back a JWE this means we should have a JWK private key.",
                 );
-                Some(scoped_keys_flow.decrypt_keys_jwe(&jwe)?)
+                let keys = scoped_keys_flow.decrypt_keys_jwe(&jwe)?;
+                serde_json::from_str(&keys)?
             }
             None => {
                 if scoped_keys_flow.is_some() {
                     error!("Expected to get keys back alongside the token but the server didn't send them.");
                     return Err(ErrorKind::TokenWithoutKeys.into());
                 } else {
-                    None
+                    previous
+                        .as_ref()
+                        .map(|info| info.scoped_keys.clone())
+                        .unwrap_or_default()
                 }
             }
         };
@@ -394,19 +828,33 @@ impl FirefoxAccount {
         let expires_at = since_epoch.as_secs() + resp.expires_in;
         let oauth_info = OAuthInfo {
             access_token: resp.access_token,
-            keys,
-            refresh_token: resp.refresh_token,
+            scoped_keys,
+            refresh_token: resp
+                .refresh_token
+                .or_else(|| previous.and_then(|info| info.refresh_token)),
             expires_at,
             scopes: granted_scopes,
         };
-        self.oauth_cache_store(&oauth_info);
+        self.oauth_cache_store_for_client(client_id, &oauth_info);
+        // The server accepted our credentials (or new ones, from signing in
+        // again), so any auth problems are over.
+        if self.state.auth_problems {
+            info!("Account reauthenticated");
+            self.state.auth_problems = false;
+        }
         self.maybe_call_persist_callback();
+        // The token may come with a new sync key, which our registered
+        // command data needs to be remade with. Failing to do that shouldn't
+        // fail the request for the token, and we'll try again next time.
+        if let Err(e) = self.register_capabilities_if_needed() {
+            warn!("Failed to register device capabilities: {}", e);
+        }
         Ok(oauth_info)
     }
 
-    fn random_base64_url_string(len: usize) -> Result<String> {
+    fn random_base64_url_string(&self, len: usize) -> Result<String> {
         let mut out = vec![0u8; len];
-        RNG.fill(&mut out).map_err(|_| ErrorKind::RngFailure)?;
+        self.random.fill(&mut out)?;
         Ok(base64::encode_config(&out, base64::URL_SAFE_NO_PAD))
     }
 
@@ -427,6 +875,7 @@ impl FirefoxAccount {
 
     #[cfg(feature = "browserid")]
     pub fn generate_assertion(&mut self, audience: &str) -> Result<String> {
+        self.check_no_auth_problems()?;
         let married = match self.to_married() {
             Some(married) => married,
             None => return Err(ErrorKind::NotMarried.into()),
@@ -441,8 +890,8 @@ impl FirefoxAccount {
     }
 
     pub fn get_profile(&mut self, ignore_cache: bool) -> Result<ProfileResponse> {
-        let profile_access_token = match self.get_oauth_token(&["profile"])? {
-            Some(token) => token.access_token,
+        let profile_access_token = match self.get_access_token("profile")? {
+            Some(info) => info.token,
             None => return Err(ErrorKind::NoCachedToken("profile").into()),
         };
         let mut etag = None;
@@ -452,8 +901,7 @@ impl FirefoxAccount {
             }
             etag = Some(cached_profile.etag.clone());
         }
-        let client = Client::new(&self.state.config);
-        match client.profile(&profile_access_token, etag)? {
+        match self.client.profile(&self.state.config, &profile_access_token, etag)? {
             Some(response_and_etag) => {
                 if let Some(etag) = response_and_etag.etag {
                     self.profile_cache = Some(CachedResponse {
@@ -476,6 +924,7 @@ impl FirefoxAccount {
 
     #[cfg(feature = "browserid")]
     pub fn get_sync_keys(&mut self) -> Result<SyncKeys> {
+        self.check_no_auth_problems()?;
         let married = match self.to_married() {
             Some(married) => married,
             None => return Err(ErrorKind::NotMarried.into()),
@@ -488,16 +937,196 @@ impl FirefoxAccount {
         self.state.config.token_server_endpoint_url()
     }
 
-    pub fn handle_push_message(&self) {
-        panic!("Not implemented yet!")
+    /// The URL of the account settings page, for a "Manage account" menu
+    /// item. `entrypoint` says where in the app the user came from (for
+    /// example `"menu"` or `"preferences"`), and is passed along for the FxA
+    /// metrics.
+    ///
+    /// The URL identifies the account (with its uid and email), so that the
+    /// page can tell whether the user is signed in to the same account on the
+    /// web. This needs the profile, and so a token with the `profile` scope.
+    pub fn get_manage_account_url(&mut self, entrypoint: &str) -> Result<String> {
+        let url = self.state.config.content_url_path("settings")?;
+        self.settings_url(url, entrypoint)
     }
 
-    pub fn register_device(&self) {
-        panic!("Not implemented yet!")
+    /// Like `get_manage_account_url`, for the page listing the devices
+    /// connected to the account.
+    pub fn get_manage_devices_url(&mut self, entrypoint: &str) -> Result<String> {
+        let url = self.state.config.content_url_path("settings/clients")?;
+        self.settings_url(url, entrypoint)
     }
 
-    pub fn get_devices_list(&self) {
-        panic!("Not implemented yet!")
+    fn settings_url(&mut self, mut url: Url, entrypoint: &str) -> Result<String> {
+        let profile = self.get_profile(false)?;
+        url.query_pairs_mut()
+            .append_pair("uid", &profile.uid)
+            .append_pair("email", &profile.email)
+            .append_pair("entrypoint", entrypoint)
+            .append_pair("utm_source", entrypoint)
+            .append_pair("utm_medium", "fxa-client");
+        Ok(url.to_string())
+    }
+
+    /// Handle a (decrypted) push message sent by the FxA servers, updating
+    /// our state as needed, and returning the events the application should
+    /// react to. If the message says a command was sent to this device, this
+    /// fetches it (and any others we haven't seen) with
+    /// `poll_device_commands`.
+    pub fn handle_push_message(&mut self, payload: &str) -> Result<Vec<AccountEvent>> {
+        let event = match push::parse_push_message(payload)? {
+            Some(PushMessage::Event(event)) => event,
+            Some(PushMessage::CommandReceived { index }) => {
+                // Poll even if we've already handled `index`, since the
+                // server's queue may have been reset.
+                info!("Command {} received, polling", index);
+                return self.poll_device_commands();
+            }
+            None => return Ok(vec![]),
+        };
+        match event {
+            AccountEvent::PasswordChanged => {
+                // Any tokens we have are now useless, but the account still
+                // exists, so the user needs to sign in again.
+                let was_signed_in = self.is_signed_in();
+                self.clear_tokens();
+                self.state.auth_problems = was_signed_in;
+                self.maybe_call_persist_callback();
+            }
+            AccountEvent::AccountDestroyed => {
+                self.clear_tokens();
+                self.state.auth_problems = false;
+                self.maybe_call_persist_callback();
+            }
+            AccountEvent::ProfileUpdated => {
+                self.profile_cache = None;
+            }
+            AccountEvent::DeviceConnected { .. }
+            | AccountEvent::DeviceDisconnected { .. }
+            | AccountEvent::TabReceived { .. }
+            | AccountEvent::CommandReceived { .. } => {}
+        }
+        Ok(vec![event])
+    }
+
+    fn clear_tokens(&mut self) {
+        self.state.oauth_cache.clear();
+        for client in self.state.additional_clients.values_mut() {
+            client.oauth_cache.clear();
+        }
+        self.profile_cache = None;
+    }
+
+    /// Fetch the commands other devices sent to this device since we last
+    /// looked, and return them as `TabReceived` and `CommandReceived`
+    /// events, oldest first. Apps should call this when they start, and
+    /// whenever push is unavailable; `handle_push_message` calls it when a
+    /// command arrives.
+    ///
+    /// The index of the last command handled is persisted with the account,
+    /// so each command is only returned once, even if the server sends it
+    /// again, or its queue is reset (in which case we fetch it all again).
+    /// This needs a refresh token, and fails with
+    /// `ErrorKind::NoRefreshToken` without one, or `ErrorKind::AuthProblems`
+    /// if the account needs to be reauthenticated.
+    pub fn poll_device_commands(&mut self) -> Result<Vec<AccountEvent>> {
+        let refresh_token = self.refresh_token()?;
+        // Only update our state once we have all the commands, so that none
+        // are lost if a request fails.
+        let mut commands = self.state.commands.clone();
+        let result = commands.poll(&*self.client, &self.state.config, &refresh_token);
+        let events = self.check_auth_result(result)?;
+        if commands != self.state.commands {
+            self.state.commands = commands;
+            self.maybe_call_persist_callback();
+        }
+        Ok(events)
+    }
+
+    // The device endpoints take any of our refresh tokens.
+    fn refresh_token(&self) -> Result<String> {
+        self.check_no_auth_problems()?;
+        match self
+            .state
+            .oauth_cache
+            .values()
+            .filter_map(|info| info.refresh_token.as_ref())
+            .next()
+        {
+            Some(refresh_token) => Ok(refresh_token.clone()),
+            None => Err(ErrorKind::NoRefreshToken.into()),
+        }
+    }
+
+    /// Fetch the devices connected to the account, including this one,
+    /// with the capabilities each of them registered. This needs a refresh
+    /// token, like `poll_device_commands`.
+    pub fn get_devices(&mut self) -> Result<Vec<Device>> {
+        let refresh_token = self.refresh_token()?;
+        let result = self.client.devices(&self.state.config, &refresh_token);
+        let devices = self.check_auth_result(result)?;
+        Ok(devices.into_iter().map(Device::from).collect())
+    }
+
+    /// Like `get_devices`, but only returns the other devices that can
+    /// receive tabs, for a "Send to device" menu.
+    pub fn get_send_tab_targets(&mut self) -> Result<Vec<Device>> {
+        Ok(self
+            .get_devices()?
+            .into_iter()
+            .filter(|device| !device.is_current_device && device.supports(Capability::SendTab))
+            .collect())
+    }
+
+    /// Register `capabilities` for this device, replacing any registered
+    /// before, so that other devices can send it the matching commands. The
+    /// data for each command is made by the callback registered with
+    /// `register_command_data_callback`, which must be registered first.
+    ///
+    /// The capabilities are persisted, and registered again whenever we get
+    /// a new sync key. If we don't have a sync key yet, they're registered
+    /// once we do. Apps should call this each time they start; it doesn't
+    /// talk to the server if the capabilities are already registered.
+    pub fn ensure_capabilities(&mut self, capabilities: &[Capability]) -> Result<()> {
+        if self.command_data_callback.is_none() {
+            return Err(ErrorKind::NoCommandDataCallback.into());
+        }
+        if self.state.device.set_capabilities(capabilities) {
+            self.maybe_call_persist_callback();
+        }
+        self.register_capabilities_if_needed()
+    }
+
+    fn register_capabilities_if_needed(&mut self) -> Result<()> {
+        let sync_key = match self.sync_key() {
+            Some(sync_key) => sync_key,
+            None => return Ok(()),
+        };
+        if !self.state.device.needs_registration(&sync_key.kid) {
+            return Ok(());
+        }
+        let available_commands = match self.command_data_callback {
+            Some(ref callback) => self.state.device.available_commands(&sync_key, callback),
+            None => return Err(ErrorKind::NoCommandDataCallback.into()),
+        };
+        let refresh_token = self.refresh_token()?;
+        let result = self
+            .client
+            .update_device(&self.state.config, &refresh_token, &available_commands);
+        self.check_auth_result(result)?;
+        self.state.device.registered(&sync_key.kid);
+        self.maybe_call_persist_callback();
+        Ok(())
+    }
+
+    /// The sync key from our cached tokens, if any of them came with one.
+    fn sync_key(&self) -> Option<ScopedKey> {
+        self.state
+            .oauth_cache
+            .values()
+            .filter_map(|info| info.scoped_keys.get(OLDSYNC_SCOPE))
+            .next()
+            .cloned()
     }
 
     pub fn send_message(&self) {
@@ -516,6 +1145,16 @@ impl FirefoxAccount {
         self.persist_callback = None;
     }
 
+    /// Registers the callback that `ensure_capabilities` uses to make the
+    /// data for our commands.
+    pub fn register_command_data_callback(&mut self, callback: CommandDataCallback) {
+        self.command_data_callback = Some(callback);
+    }
+
+    pub fn unregister_command_data_callback(&mut self) {
+        self.command_data_callback = None;
+    }
+
     fn maybe_call_persist_callback(&self) {
         if let Some(ref cb) = self.persist_callback {
             let json = match self.to_json() {
@@ -541,6 +1180,17 @@ impl FirefoxAccount {
 mod tests {
     use super::*;
     use std::borrow::Cow;
+    use std::sync::{Arc, Mutex};
+    use test_support::*;
+
+    const OLDSYNC: &str = "https://identity.mozilla.com/apps/oldsync";
+
+    fn fixture_account(responses: Vec<&'static str>) -> (FirefoxAccount, Arc<Mutex<Vec<FakeRequest>>>) {
+        let (client, requests) = FakeClient::new(responses);
+        let fxa = FirefoxAccount::new(Config::stable_dev_fixture(), "12345678", "https://foo.bar")
+            .with_seams(Box::new(client), Box::new(FakeRandomSource));
+        (fxa, requests)
+    }
 
     #[test]
     fn test_fxa_is_send() {
@@ -656,13 +1306,575 @@ mod tests {
         assert_eq!(format!("{:?}", url), "Err(Error(\n\nOrigin mismatch))")
     }
 
+    #[test]
+    fn test_handle_push_message_password_changed() {
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".to_string(),
+            scoped_keys: HashMap::new(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: 1,
+            scopes: vec!["profile".to_string()],
+        });
+        let events = fxa
+            .handle_push_message(r#"{"version":1,"command":"fxaccounts:password_changed"}"#)
+            .unwrap();
+        assert_eq!(events, vec![AccountEvent::PasswordChanged]);
+        assert!(fxa.oauth_cache_find(&["profile"]).is_none());
+        assert_eq!(fxa.account_state(), AccountState::AuthProblems);
+        assert_auth_problems(fxa.get_access_token("profile"));
+
+        fxa.handle_push_message(r#"{"version":1,"command":"fxaccounts:account_destroyed"}"#)
+            .unwrap();
+        assert_eq!(fxa.account_state(), AccountState::Disconnected);
+        assert!(fxa.get_access_token("profile").unwrap().is_none());
+    }
+
+    fn assert_auth_problems<T>(result: Result<T>) {
+        match result {
+            Err(e) => match *e.kind() {
+                ErrorKind::AuthProblems => {}
+                ref kind => panic!("Unexpected error: {}", kind),
+            },
+            Ok(_) => panic!("Expected an AuthProblems error"),
+        }
+    }
+
+    #[test]
+    fn test_auth_problems() {
+        let (mut fxa, requests) = fixture_account(vec![
+            OAUTH_INVALID_TOKEN,
+            OAUTH_INVALID_TOKEN,
+            OAUTH_TOKEN_REFRESHED,
+        ]);
+        assert_eq!(fxa.account_state(), AccountState::Disconnected);
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "expired".to_string(),
+            scoped_keys: HashMap::new(),
+            refresh_token: Some("fixture-refresh-token".to_string()),
+            expires_at: 1,
+            scopes: vec!["profile".to_string()],
+        });
+        assert_eq!(fxa.account_state(), AccountState::Connected);
+
+        // The refresh token was revoked.
+        assert_auth_problems(fxa.get_access_token("profile"));
+        assert_eq!(fxa.account_state(), AccountState::AuthProblems);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Until we reauthenticate, we don't even try.
+        assert_auth_problems(fxa.get_access_token("profile"));
+        assert_auth_problems(fxa.get_devices());
+        assert_auth_problems(fxa.poll_device_commands());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // The state is persisted.
+        let restored = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        assert_eq!(restored.account_state(), AccountState::AuthProblems);
+        assert!(restored.is_signed_in());
+
+        // The server still doesn't accept our refresh token...
+        assert_eq!(fxa.retry_authentication().unwrap(), AccountState::AuthProblems);
+        // ...until it does.
+        assert_eq!(fxa.retry_authentication().unwrap(), AccountState::Connected);
+        let token = fxa.get_access_token("profile").unwrap().unwrap();
+        assert_eq!(token.token, "fixture-access-token-2");
+        let expected_request = FakeRequest::TokenWithRefreshToken {
+            client_id: "12345678".to_string(),
+            refresh_token: "fixture-refresh-token".to_string(),
+            scopes: vec!["profile".to_string()],
+        };
+        assert_eq!(*requests.lock().unwrap(), vec![expected_request; 3]);
+
+        // Retrying when there's nothing to recover from does nothing.
+        assert_eq!(fxa.retry_authentication().unwrap(), AccountState::Connected);
+    }
+
+    #[test]
+    fn test_sign_in_again_after_auth_problems() {
+        let (mut fxa, _) = fixture_account(vec![OAUTH_TOKEN_WITH_KEYS]);
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".to_string(),
+            scoped_keys: HashMap::new(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: 1,
+            scopes: vec!["profile".to_string()],
+        });
+        fxa.handle_push_message(r#"{"version":1,"command":"fxaccounts:password_reset"}"#)
+            .unwrap();
+        // We don't have a refresh token any more, so only signing in again
+        // helps.
+        assert_eq!(fxa.retry_authentication().unwrap(), AccountState::AuthProblems);
+
+        let url = Url::parse(&fxa.begin_oauth_flow(&["profile", OLDSYNC], true).unwrap()).unwrap();
+        let state = url.query_pairs().find(|(k, _)| k == "state").unwrap().1.into_owned();
+        fxa.complete_oauth_flow("fixture-code", &state).unwrap();
+        assert_eq!(fxa.account_state(), AccountState::Connected);
+        let token = fxa.get_access_token("profile").unwrap().unwrap();
+        assert_eq!(token.token, "fixture-access-token-1");
+    }
+
+    #[test]
+    fn test_get_access_token_from_cache() {
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        let expires_at = util::now_secs() + 3600;
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".to_string(),
+            scoped_keys: serde_json::from_value(json!({
+                "https://identity.mozilla.com/apps/oldsync": {
+                    "kty": "oct",
+                    "scope": "https://identity.mozilla.com/apps/oldsync",
+                    "k": "bXlrZXk",
+                    "kid": "1234-abcd",
+                },
+            })).unwrap(),
+            refresh_token: None,
+            expires_at,
+            scopes: vec![
+                "profile".to_string(),
+                "https://identity.mozilla.com/apps/oldsync".to_string(),
+            ],
+        });
+
+        let info = fxa
+            .get_access_token("https://identity.mozilla.com/apps/oldsync")
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.token, "abcdef");
+        assert_eq!(info.expires_at, expires_at);
+        let key = info.key.unwrap();
+        assert_eq!(key.k, "bXlrZXk");
+        assert_eq!(key.kid, "1234-abcd");
+
+        // The same token is good for the profile scope, but there's no key.
+        let info = fxa.get_access_token("profile").unwrap().unwrap();
+        assert_eq!(info.scope, "profile");
+        assert_eq!(info.key, None);
+    }
+
+    #[test]
+    fn test_fixture_oauth_flow_with_keys() {
+        let (mut fxa, requests) = fixture_account(vec![OAUTH_TOKEN_WITH_KEYS]);
+        let url = fxa.begin_oauth_flow(&["profile", OLDSYNC], true).unwrap();
+        let url = Url::parse(&url).unwrap();
+        let state = url.query_pairs().find(|(k, _)| k == "state").unwrap().1.into_owned();
+        assert_eq!(state, "AAECAwQFBgcICQoLDA0ODw");
+
+        let info = fxa.complete_oauth_flow("fixture-code", &state).unwrap();
+        assert_eq!(info.access_token, "fixture-access-token-1");
+        assert_eq!(info.refresh_token, Some("fixture-refresh-token".to_string()));
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![FakeRequest::TokenWithCode {
+                code: "fixture-code".to_string(),
+                code_verifier: "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8gISIjJCUmJygpKg".to_string(),
+                client_id: "12345678".to_string(),
+            }]
+        );
+
+        // The keys were decrypted with the flow's ephemeral key.
+        let token = fxa.get_access_token(OLDSYNC).unwrap().unwrap();
+        assert_eq!(token.token, "fixture-access-token-1");
+        let key = token.key.unwrap();
+        assert_eq!(key.kid, "1526414944666-zgTjf5oXmPmBjxwXWFsDWg");
+        assert_eq!(
+            key.k,
+            "8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA"
+        );
+
+        // Each flow can only be completed once.
+        assert!(fxa.complete_oauth_flow("fixture-code", &state).is_err());
+    }
+
+    #[test]
+    fn test_fixture_refresh_token() {
+        let (mut fxa, requests) = fixture_account(vec![OAUTH_TOKEN_REFRESHED]);
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "expired".to_string(),
+            scoped_keys: HashMap::new(),
+            refresh_token: Some("fixture-refresh-token".to_string()),
+            expires_at: 1,
+            scopes: vec!["profile".to_string()],
+        });
+
+        let token = fxa.get_access_token("profile").unwrap().unwrap();
+        assert_eq!(token.token, "fixture-access-token-2");
+        assert!(token.expires_at > util::now_secs() + 3600);
+        // The response doesn't include a refresh token, so we keep ours.
+        let info = fxa.oauth_cache_find(&["profile"]).unwrap();
+        assert_eq!(info.refresh_token, Some("fixture-refresh-token".to_string()));
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![FakeRequest::TokenWithRefreshToken {
+                client_id: "12345678".to_string(),
+                refresh_token: "fixture-refresh-token".to_string(),
+                scopes: vec!["profile".to_string()],
+            }]
+        );
+
+        // The new token is cached, so we don't ask again (`FakeClient` would
+        // panic if we did).
+        let token = fxa.get_access_token("profile").unwrap().unwrap();
+        assert_eq!(token.token, "fixture-access-token-2");
+    }
+
+    #[test]
+    fn test_to_json_keeps_tokens_and_keys() {
+        let (mut fxa, _) = fixture_account(vec![OAUTH_TOKEN_WITH_KEYS]);
+        let url = Url::parse(&fxa.begin_oauth_flow(&["profile", OLDSYNC], true).unwrap()).unwrap();
+        let state = url.query_pairs().find(|(k, _)| k == "state").unwrap().1.into_owned();
+        fxa.complete_oauth_flow("fixture-code", &state).unwrap();
+
+        let mut restored = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        let token = restored.get_access_token(OLDSYNC).unwrap().unwrap();
+        assert_eq!(token.token, "fixture-access-token-1");
+        assert_eq!(token.key.unwrap().kid, "1526414944666-zgTjf5oXmPmBjxwXWFsDWg");
+        let info = restored.oauth_cache_find(&[OLDSYNC]).unwrap();
+        assert_eq!(info.refresh_token, Some("fixture-refresh-token".to_string()));
+
+        let scrubbed = fxa.to_json_scrubbed().unwrap();
+        assert!(!scrubbed.contains("8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw"));
+        let mut restored = FirefoxAccount::from_json(&scrubbed).unwrap();
+        let token = restored.get_access_token(OLDSYNC).unwrap().unwrap();
+        assert_eq!(token.token, "fixture-access-token-1");
+        assert!(token.key.is_none());
+        let info = restored.oauth_cache_find(&[OLDSYNC]).unwrap();
+        assert_eq!(info.refresh_token, Some("fixture-refresh-token".to_string()));
+    }
+
+    #[test]
+    fn test_oauth_info_reads_keys_persisted_as_a_string() {
+        let keys = json!({ OLDSYNC: {"kty": "oct", "scope": OLDSYNC, "k": "bXlrZXk", "kid": "1234-abcd"} });
+        let legacy = json!({
+            "access_token": "abcdef",
+            "keys": keys.to_string(),
+            "refresh_token": null,
+            "expires_at": 1,
+            "scopes": [OLDSYNC],
+        });
+        let info: OAuthInfo = serde_json::from_value(legacy.clone()).unwrap();
+        assert_eq!(info.scoped_keys[OLDSYNC].kid, "1234-abcd");
+
+        // New state stores the map itself.
+        let info: OAuthInfo = serde_json::from_str(&serde_json::to_string(&info).unwrap()).unwrap();
+        assert_eq!(info.scoped_keys[OLDSYNC].k, "bXlrZXk");
+
+        let mut without_keys = legacy;
+        without_keys["keys"] = json!(null);
+        let info: OAuthInfo = serde_json::from_value(without_keys).unwrap();
+        assert!(info.scoped_keys.is_empty());
+    }
+
+    #[test]
+    fn test_additional_client() {
+        let (mut fxa, requests) = fixture_account(vec![OAUTH_TOKEN_WITH_KEYS]);
+        assert!(fxa.begin_oauth_flow_for_client("abcdef", &["profile"], false).is_err());
+        assert!(fxa.get_access_token_for_client("abcdef", "profile").is_err());
+
+        fxa.add_client("abcdef", "https://other.bar");
+        let url = fxa.begin_oauth_flow_for_client("abcdef", &["profile", OLDSYNC], true).unwrap();
+        let url = Url::parse(&url).unwrap();
+        let pairs: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(pairs["client_id"], "abcdef");
+        assert_eq!(pairs["redirect_uri"], "https://other.bar");
+        fxa.complete_oauth_flow("fixture-code", &pairs["state"]).unwrap();
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![FakeRequest::TokenWithCode {
+                code: "fixture-code".to_string(),
+                code_verifier: "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8gISIjJCUmJygpKg".to_string(),
+                client_id: "abcdef".to_string(),
+            }]
+        );
+
+        // The token only belongs to the client that asked for it.
+        let token = fxa.get_access_token_for_client("abcdef", OLDSYNC).unwrap().unwrap();
+        assert_eq!(token.token, "fixture-access-token-1");
+        assert!(fxa.oauth_cache_find(&["profile"]).is_none());
+
+        // Clients and their tokens survive serialization.
+        let mut restored = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        let info = restored.oauth_cache_find_for_client("abcdef", &[OLDSYNC]).unwrap();
+        assert_eq!(info.refresh_token, Some("fixture-refresh-token".to_string()));
+        let scrubbed = fxa.to_json_scrubbed().unwrap();
+        assert!(!scrubbed.contains("8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw"));
+
+        fxa.handle_push_message(r#"{"version":1,"command":"fxaccounts:password_changed"}"#)
+            .unwrap();
+        assert!(fxa.oauth_cache_find_for_client("abcdef", &[OLDSYNC]).is_none());
+
+        assert!(restored.remove_client("abcdef"));
+        assert!(!restored.remove_client("abcdef"));
+        assert!(!restored.remove_client("12345678"));
+        assert!(restored.get_access_token_for_client("abcdef", OLDSYNC).is_err());
+    }
+
+    #[test]
+    fn test_switch_config() {
+        let (mut fxa, _) = fixture_account(vec![]);
+        assert!(!fxa.is_signed_in());
+        fxa.begin_oauth_flow(&["profile"], false).unwrap();
+        fxa.switch_config(Config::stage_dev_fixture()).unwrap();
+        assert_eq!(fxa.state.config.environment(), "https://accounts.stage.mozaws.net");
+        assert!(fxa.flow_store.is_empty());
+
+        fxa.add_client("abcdef", "https://other.bar");
+        fxa.oauth_cache_store_for_client("abcdef", &OAuthInfo {
+            access_token: "abcdef".to_string(),
+            scoped_keys: HashMap::new(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: 1,
+            scopes: vec!["profile".to_string()],
+        });
+        assert!(fxa.is_signed_in());
+        match fxa.switch_config(Config::stable_dev_fixture()).unwrap_err().kind() {
+            ErrorKind::SwitchConfigWhileSignedIn => {}
+            kind => panic!("Unexpected error {:?}", kind),
+        }
+        assert!(fxa.state.config.is_same_environment(&Config::stage_dev_fixture()));
+
+        fxa.wipe_and_switch_config(Config::stable_dev_fixture());
+        assert!(!fxa.is_signed_in());
+        assert!(fxa.state.config.is_same_environment(&Config::stable_dev_fixture()));
+        // The client is still registered.
+        assert!(fxa.redirect_uri_for_client("abcdef").is_ok());
+    }
+
+    #[test]
+    fn test_from_json_for_config() {
+        let fxa = FirefoxAccount::new(Config::stage_dev_fixture(), "12345678", "https://foo.bar");
+        let json = fxa.to_json().unwrap();
+        assert!(FirefoxAccount::from_json_for_config(&json, &Config::stage_dev_fixture()).is_ok());
+        match FirefoxAccount::from_json_for_config(&json, &Config::stable_dev_fixture()) {
+            Err(e) => match e.kind() {
+                ErrorKind::EnvironmentMismatch { expected, found } => {
+                    assert_eq!(expected, "https://stable.dev.lcip.org/");
+                    assert_eq!(found, "https://accounts.stage.mozaws.net");
+                }
+                kind => panic!("Unexpected error {:?}", kind),
+            },
+            Ok(_) => panic!("Restored an account from another environment"),
+        }
+    }
+
+    #[test]
+    fn test_from_json_without_additional_clients() {
+        let fxa = FirefoxAccount::new(Config::stable_dev_fixture(), "12345678", "https://foo.bar");
+        let mut json: serde_json::Value = serde_json::from_str(&fxa.to_json().unwrap()).unwrap();
+        json.as_object_mut().unwrap().remove("additional_clients");
+        let restored = FirefoxAccount::from_json(&json.to_string()).unwrap();
+        assert!(restored.state.additional_clients.is_empty());
+    }
+
+    #[test]
+    fn test_manage_urls() {
+        let (mut fxa, requests) = fixture_account(vec![PROFILE]);
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "profile-token".to_string(),
+            scoped_keys: HashMap::new(),
+            refresh_token: None,
+            expires_at: util::now_secs() + 3600,
+            scopes: vec!["profile".to_string()],
+        });
+
+        let url = Url::parse(&fxa.get_manage_account_url("menu").unwrap()).unwrap();
+        assert_eq!(url.host_str(), Some("stable.dev.lcip.org"));
+        assert_eq!(url.path(), "/settings");
+        let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("uid".to_string(), "fixture-uid".to_string()),
+                ("email".to_string(), "foo@example.com".to_string()),
+                ("entrypoint".to_string(), "menu".to_string()),
+                ("utm_source".to_string(), "menu".to_string()),
+                ("utm_medium".to_string(), "fxa-client".to_string()),
+            ]
+        );
+
+        let url = Url::parse(&fxa.get_manage_devices_url("preferences").unwrap()).unwrap();
+        assert_eq!(url.path(), "/settings/clients");
+        assert!(url.query_pairs().any(|(k, v)| k == "entrypoint" && v == "preferences"));
+        // The second URL uses the cached profile.
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_fixture_profile() {
+        let (mut fxa, requests) = fixture_account(vec![PROFILE]);
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "profile-token".to_string(),
+            scoped_keys: HashMap::new(),
+            refresh_token: None,
+            expires_at: util::now_secs() + 3600,
+            scopes: vec!["profile".to_string()],
+        });
+
+        let profile = fxa.get_profile(false).unwrap();
+        assert_eq!(profile.email, "foo@example.com");
+        assert_eq!(profile.display_name, Some("Foo".to_string()));
+        // Fresh enough to come from the cache.
+        assert_eq!(fxa.get_profile(false).unwrap().uid, "fixture-uid");
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![FakeRequest::Profile {
+                access_token: "profile-token".to_string(),
+                etag: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_poll_device_commands() {
+        const COMMAND: &str = r#"{"index": 7, "last": true, "messages": [
+            {"index": 7, "data": {"command": "https://identity.mozilla.com/cmd/open-uri",
+                                  "payload": {"encrypted": "abc"}, "sender": "device1"}}
+        ]}"#;
+        const NO_COMMANDS: &str = r#"{"index": 7, "last": true, "messages": []}"#;
+        let (mut fxa, requests) = fixture_account(vec![COMMAND, NO_COMMANDS]);
+        match fxa.poll_device_commands().unwrap_err().kind() {
+            ErrorKind::NoRefreshToken => {}
+            kind => panic!("Unexpected error {:?}", kind),
+        }
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".to_string(),
+            scoped_keys: HashMap::new(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: util::now_secs() + 3600,
+            scopes: vec!["profile".to_string()],
+        });
+        let persisted = Arc::new(Mutex::new(None));
+        let persisted_in_callback = persisted.clone();
+        fxa.register_persist_callback(PersistCallback::new(move |json| {
+            *persisted_in_callback.lock().unwrap() = Some(json.to_string());
+        }));
+
+        let push = r#"{"version":1,"command":"fxaccounts:command_received","data":{"index":7}}"#;
+        assert_eq!(
+            fxa.handle_push_message(push).unwrap(),
+            vec![AccountEvent::TabReceived {
+                sender: Some("device1".to_string()),
+                payload: json!({"encrypted": "abc"}),
+            }]
+        );
+
+        // The index is persisted, so a restored account picks up after it.
+        let json = persisted.lock().unwrap().take().unwrap();
+        let restored = FirefoxAccount::from_json(&json).unwrap();
+        assert_eq!(restored.state.commands, fxa.state.commands);
+        assert!(fxa.poll_device_commands().unwrap().is_empty());
+        assert!(persisted.lock().unwrap().is_none());
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                FakeRequest::PendingCommands {
+                    refresh_token: "refresh".to_string(),
+                    index: 0,
+                    limit: 50,
+                },
+                FakeRequest::PendingCommands {
+                    refresh_token: "refresh".to_string(),
+                    index: 8,
+                    limit: 50,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_send_tab_targets() {
+        const DEVICES: &str = r#"[
+            {"id": "us", "name": "This phone", "type": "mobile", "isCurrentDevice": true,
+             "availableCommands": {"https://identity.mozilla.com/cmd/open-uri": "data"}},
+            {"id": "desktop", "name": "Desktop", "type": "desktop", "isCurrentDevice": false,
+             "lastAccessTime": 1234,
+             "availableCommands": {"https://identity.mozilla.com/cmd/open-uri": "data"}},
+            {"id": "old", "name": "Old phone", "type": "mobile", "isCurrentDevice": false}
+        ]"#;
+        let (mut fxa, requests) = fixture_account(vec![DEVICES, DEVICES]);
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".to_string(),
+            scoped_keys: HashMap::new(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: util::now_secs() + 3600,
+            scopes: vec!["profile".to_string()],
+        });
+        let devices = fxa.get_devices().unwrap();
+        assert_eq!(devices.len(), 3);
+        assert!(devices[0].is_current_device);
+        assert_eq!(devices[2].capabilities, vec![]);
+
+        let targets = fxa.get_send_tab_targets().unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].id, "desktop");
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            FakeRequest::Devices {
+                refresh_token: "refresh".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_ensure_capabilities() {
+        let (mut fxa, requests) = fixture_account(vec![OAUTH_TOKEN_WITH_KEYS, "{}", "{}"]);
+        match fxa.ensure_capabilities(&[Capability::SendTab]).unwrap_err().kind() {
+            ErrorKind::NoCommandDataCallback => {}
+            kind => panic!("Unexpected error {:?}", kind),
+        }
+        fxa.register_command_data_callback(CommandDataCallback::new(|capability, sync_key| {
+            Some(format!("{:?} for {}", capability, sync_key.kid))
+        }));
+
+        // Without a sync key, there's nothing to register yet...
+        fxa.ensure_capabilities(&[Capability::SendTab]).unwrap();
+        assert!(requests.lock().unwrap().is_empty());
+
+        // ...until we get a token with one.
+        let url = fxa.begin_oauth_flow(&["profile", OLDSYNC], true).unwrap();
+        let url = Url::parse(&url).unwrap();
+        let state = url.query_pairs().find(|(k, _)| k == "state").unwrap().1.into_owned();
+        fxa.complete_oauth_flow("fixture-code", &state).unwrap();
+        let update_device = |kid: &str| {
+            let mut available_commands = HashMap::new();
+            available_commands.insert(SEND_TAB_COMMAND.to_string(), format!("SendTab for {}", kid));
+            FakeRequest::UpdateDevice {
+                refresh_token: "fixture-refresh-token".to_string(),
+                available_commands,
+            }
+        };
+        assert_eq!(
+            requests.lock().unwrap()[1],
+            update_device("1526414944666-zgTjf5oXmPmBjxwXWFsDWg")
+        );
+
+        // Already registered with that key.
+        fxa.ensure_capabilities(&[Capability::SendTab]).unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        // The key changed.
+        let mut info = fxa.oauth_cache_find(&[OLDSYNC]).unwrap().clone();
+        info.scoped_keys = serde_json::from_value(
+            json!({ OLDSYNC: {"kty": "oct", "scope": OLDSYNC, "k": "bmV3a2V5", "kid": "5678-efgh"} }),
+        ).unwrap();
+        fxa.oauth_cache_store(&info);
+        fxa.ensure_capabilities(&[Capability::SendTab]).unwrap();
+        assert_eq!(requests.lock().unwrap()[2], update_device("5678-efgh"));
+
+        // The registration is persisted.
+        let restored = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        assert_eq!(restored.state.device, fxa.state.device);
+    }
+
     #[test]
     fn test_oauth_cache_store_and_find() {
         let mut fxa =
             FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
         let oauth_info = OAuthInfo {
             access_token: "abcdef".to_string(),
-            keys: None,
+            scoped_keys: HashMap::new(),
             refresh_token: None,
             expires_at: 1,
             scopes: vec![
@@ -676,6 +1888,7 @@ mod tests {
 }
 
 pub struct OAuthFlow {
+    pub client_id: String,
     pub scoped_keys_flow: Option<ScopedKeysFlow>,
     pub code_verifier: String,
 }
@@ -683,8 +1896,58 @@ pub struct OAuthFlow {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct OAuthInfo {
     pub access_token: String,
-    pub keys: Option<String>,
+    /// The keys for the granted scopes that have one, by scope. Older
+    /// versions persisted these as a JSON string, which
+    /// `deserialize_scoped_keys` still accepts.
+    #[serde(
+        rename = "keys",
+        default,
+        deserialize_with = "deserialize_scoped_keys"
+    )]
+    pub scoped_keys: HashMap<String, ScopedKey>,
     pub refresh_token: Option<String>,
     pub expires_at: u64, // seconds since epoch
     pub scopes: Vec<String>,
 }
+
+impl OAuthInfo {
+    fn to_access_token_info(&self, scope: &str) -> AccessTokenInfo {
+        AccessTokenInfo {
+            scope: scope.to_string(),
+            token: self.access_token.clone(),
+            key: self.scoped_keys.get(scope).cloned(),
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+fn deserialize_scoped_keys<'de, D>(
+    deserializer: D,
+) -> std::result::Result<HashMap<String, ScopedKey>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Keys {
+        Json(String),
+        Map(HashMap<String, ScopedKey>),
+    }
+    let keys: Option<Keys> = serde::Deserialize::deserialize(deserializer)?;
+    match keys {
+        None => Ok(HashMap::new()),
+        Some(Keys::Map(keys)) => Ok(keys),
+        Some(Keys::Json(json)) => serde_json::from_str(&json).map_err(serde::de::Error::custom),
+    }
+}
+
+/// An OAuth access token for a single scope, and that scope's key, if the
+/// token was obtained through a flow that requested keys.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessTokenInfo {
+    pub scope: String,
+    pub token: String,
+    pub key: Option<ScopedKey>,
+    /// Seconds since the epoch.
+    pub expires_at: u64,
+}
@@ -0,0 +1,143 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use errors::*;
+use serde_json;
+
+/// An account-level event, as delivered to us by the FxA push service.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum AccountEvent {
+    /// Another device was connected to the account.
+    DeviceConnected { device_name: String },
+    /// A device was disconnected from the account. `is_local_device` is set
+    /// if that device is us, in which case the app should sign out.
+    DeviceDisconnected {
+        device_id: String,
+        is_local_device: bool,
+    },
+    /// The user's profile changed, and any cached copy is stale.
+    ProfileUpdated,
+    /// The account was deleted.
+    AccountDestroyed,
+    /// The password was changed or reset: all of our tokens are now invalid
+    /// and the user needs to sign in again.
+    PasswordChanged,
+    /// Another device sent us a tab. `payload` is the send tab command's
+    /// payload, which is encrypted with this device's send tab keys.
+    TabReceived {
+        sender: Option<String>,
+        payload: serde_json::Value,
+    },
+    /// Another device sent us a command we don't know about.
+    CommandReceived {
+        command: String,
+        sender: Option<String>,
+        payload: serde_json::Value,
+    },
+}
+
+/// A push message from the FxA servers.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum PushMessage {
+    Event(AccountEvent),
+    /// A command was sent to this device, and should be fetched with
+    /// `FirefoxAccount::poll_device_commands`.
+    CommandReceived { index: u64 },
+}
+
+#[derive(Deserialize)]
+struct PushPayload {
+    command: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct DeviceConnectedData {
+    #[serde(rename = "deviceName")]
+    device_name: String,
+}
+
+#[derive(Deserialize)]
+struct DeviceDisconnectedData {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct CommandReceivedData {
+    index: u64,
+}
+
+/// Parse a decrypted push payload. Returns `None` for commands we don't know
+/// about, which are expected as the server adds new ones.
+pub(crate) fn parse_push_message(payload: &str) -> Result<Option<PushMessage>> {
+    let payload: PushPayload = serde_json::from_str(payload)?;
+    Ok(Some(PushMessage::Event(match payload.command.as_str() {
+        "fxaccounts:device_connected" => {
+            let data: DeviceConnectedData = serde_json::from_value(payload.data)?;
+            AccountEvent::DeviceConnected {
+                device_name: data.device_name,
+            }
+        }
+        "fxaccounts:device_disconnected" => {
+            let data: DeviceDisconnectedData = serde_json::from_value(payload.data)?;
+            // We don't track our own device id yet, so this is never us.
+            AccountEvent::DeviceDisconnected {
+                device_id: data.id,
+                is_local_device: false,
+            }
+        }
+        "fxaccounts:profile_updated" => AccountEvent::ProfileUpdated,
+        "fxaccounts:account_destroyed" => AccountEvent::AccountDestroyed,
+        "fxaccounts:password_changed" | "fxaccounts:password_reset" => {
+            AccountEvent::PasswordChanged
+        }
+        "fxaccounts:command_received" => {
+            let data: CommandReceivedData = serde_json::from_value(payload.data)?;
+            return Ok(Some(PushMessage::CommandReceived { index: data.index }));
+        }
+        other => {
+            warn!("Unknown push command {}", other);
+            return Ok(None);
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_push_message() {
+        let msg = r#"{"version":1,"command":"fxaccounts:device_connected","data":{"deviceName":"Bob's phone"}}"#;
+        assert_eq!(
+            parse_push_message(msg).unwrap(),
+            Some(PushMessage::Event(AccountEvent::DeviceConnected {
+                device_name: "Bob's phone".to_string()
+            }))
+        );
+        let msg = r#"{"version":1,"command":"fxaccounts:device_disconnected","data":{"id":"abcd"}}"#;
+        assert_eq!(
+            parse_push_message(msg).unwrap(),
+            Some(PushMessage::Event(AccountEvent::DeviceDisconnected {
+                device_id: "abcd".to_string(),
+                is_local_device: false,
+            }))
+        );
+        let msg = r#"{"version":1,"command":"fxaccounts:password_reset"}"#;
+        assert_eq!(
+            parse_push_message(msg).unwrap(),
+            Some(PushMessage::Event(AccountEvent::PasswordChanged))
+        );
+        let msg = r#"{"version":1,"command":"fxaccounts:command_received","data":{"command":"https://identity.mozilla.com/cmd/open-uri","index":42,"sender":"abcd","url":"https://example.com"}}"#;
+        assert_eq!(
+            parse_push_message(msg).unwrap(),
+            Some(PushMessage::CommandReceived { index: 42 })
+        );
+        let msg = r#"{"version":1,"command":"fxaccounts:something_new","data":{}}"#;
+        assert_eq!(parse_push_message(msg).unwrap(), None);
+        assert!(parse_push_message("not json").is_err());
+    }
+}
@@ -0,0 +1,32 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use errors::*;
+use ring::rand::{SecureRandom, SystemRandom};
+use scoped_keys::ScopedKeysFlow;
+
+lazy_static! {
+    static ref RNG: SystemRandom = SystemRandom::new();
+}
+
+/// Where the OAuth flows get their randomness: the `state` and PKCE code
+/// verifier, and the ephemeral key used to receive scoped keys. This is only
+/// a trait so that tests can make flows deterministic.
+pub(crate) trait RandomSource: Send {
+    fn fill(&self, dest: &mut [u8]) -> Result<()>;
+
+    fn scoped_keys_flow(&self) -> Result<ScopedKeysFlow>;
+}
+
+pub(crate) struct SystemRandomSource;
+
+impl RandomSource for SystemRandomSource {
+    fn fill(&self, dest: &mut [u8]) -> Result<()> {
+        RNG.fill(dest).map_err(|_| ErrorKind::RngFailure.into())
+    }
+
+    fn scoped_keys_flow(&self) -> Result<ScopedKeysFlow> {
+        ScopedKeysFlow::with_random_key(&*RNG)
+    }
+}
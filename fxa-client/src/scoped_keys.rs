@@ -8,6 +8,17 @@ use ring::{aead, agreement, digest};
 use serde_json;
 use untrusted::Input;
 
+/// A key for a single scope, as returned by the server alongside an OAuth
+/// token (see `FirefoxAccount::get_access_token`). This is a JWK.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScopedKey {
+    pub kty: String,
+    pub scope: String,
+    /// The key material, base64url encoded.
+    pub k: String,
+    pub kid: String,
+}
+
 pub struct ScopedKeysFlow {
     private_key: EphemeralPrivateKey,
 }
@@ -0,0 +1,208 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Fakes for the seams in `FirefoxAccount`, so that OAuth flows can be tested
+//! without talking to real servers. The canned server responses live in the
+//! `fixtures` directory.
+
+use config::Config;
+use errors::*;
+use http_client::{
+    DeviceResponse, FxAClient, OAuthTokenResponse, PendingCommandsResponse, ProfileResponse,
+    ResponseAndETag,
+};
+use random::RandomSource;
+use ring::test::rand::FixedSliceRandom;
+use scoped_keys::ScopedKeysFlow;
+use serde::de::DeserializeOwned;
+use serde_json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// A token response with scoped keys, encrypted to `SCOPED_KEYS_PRIVATE_KEY`.
+pub const OAUTH_TOKEN_WITH_KEYS: &str = include_str!("../fixtures/oauth_token_with_keys.json");
+
+/// A token response to a refresh token request.
+pub const OAUTH_TOKEN_REFRESHED: &str = include_str!("../fixtures/oauth_token_refreshed.json");
+
+pub const PROFILE: &str = include_str!("../fixtures/profile.json");
+
+/// The error the OAuth server returns for a revoked refresh token.
+pub const OAUTH_INVALID_TOKEN: &str = include_str!("../fixtures/oauth_invalid_token.json");
+
+/// The ephemeral private key `FakeRandomSource` uses for scoped keys flows.
+pub const SCOPED_KEYS_PRIVATE_KEY: &[u8] = &[
+    81, 172, 131, 226, 73, 255, 225, 1, 239, 46, 242, 203, 73, 38, 128, 53, 240, 212, 167, 208,
+    28, 66, 119, 80, 187, 244, 232, 133, 2, 168, 202, 127,
+];
+
+/// A request made to a `FakeClient`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FakeRequest {
+    TokenWithCode {
+        code: String,
+        code_verifier: String,
+        client_id: String,
+    },
+    TokenWithRefreshToken {
+        client_id: String,
+        refresh_token: String,
+        scopes: Vec<String>,
+    },
+    Profile {
+        access_token: String,
+        etag: Option<String>,
+    },
+    PendingCommands {
+        refresh_token: String,
+        index: u64,
+        limit: u64,
+    },
+    Devices {
+        refresh_token: String,
+    },
+    UpdateDevice {
+        refresh_token: String,
+        available_commands: HashMap<String, String>,
+    },
+}
+
+/// Returns the given responses in order, whatever the request was, and
+/// records the requests so that tests can check them. Responses with an
+/// `errno` are returned as `ErrorKind::RemoteError`s, like the real client
+/// does for error responses. Panics if it runs out of responses.
+pub struct FakeClient {
+    responses: Mutex<VecDeque<&'static str>>,
+    requests: Arc<Mutex<Vec<FakeRequest>>>,
+}
+
+impl FakeClient {
+    pub fn new(responses: Vec<&'static str>) -> (Self, Arc<Mutex<Vec<FakeRequest>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let client = FakeClient {
+            responses: Mutex::new(responses.into_iter().collect()),
+            requests: requests.clone(),
+        };
+        (client, requests)
+    }
+
+    fn respond<T: DeserializeOwned>(&self, request: FakeRequest) -> Result<T> {
+        let response = self
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| panic!("No response for {:?}", request));
+        self.requests.lock().unwrap().push(request);
+        let json: serde_json::Value = serde_json::from_str(response)?;
+        if let Some(errno) = json["errno"].as_u64() {
+            return Err(ErrorKind::RemoteError {
+                code: json["code"].as_u64().unwrap_or(0),
+                errno,
+                error: json["error"].as_str().unwrap_or("").to_string(),
+                message: json["message"].as_str().unwrap_or("").to_string(),
+                info: json["info"].as_str().unwrap_or("").to_string(),
+            }.into());
+        }
+        Ok(serde_json::from_value(json)?)
+    }
+}
+
+impl FxAClient for FakeClient {
+    fn oauth_token_with_code(
+        &self,
+        _config: &Config,
+        code: &str,
+        code_verifier: &str,
+        client_id: &str,
+    ) -> Result<OAuthTokenResponse> {
+        self.respond(FakeRequest::TokenWithCode {
+            code: code.to_string(),
+            code_verifier: code_verifier.to_string(),
+            client_id: client_id.to_string(),
+        })
+    }
+
+    fn oauth_token_with_refresh_token(
+        &self,
+        _config: &Config,
+        client_id: &str,
+        refresh_token: &str,
+        scopes: &[&str],
+    ) -> Result<OAuthTokenResponse> {
+        self.respond(FakeRequest::TokenWithRefreshToken {
+            client_id: client_id.to_string(),
+            refresh_token: refresh_token.to_string(),
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+        })
+    }
+
+    fn profile(
+        &self,
+        _config: &Config,
+        profile_access_token: &str,
+        etag: Option<String>,
+    ) -> Result<Option<ResponseAndETag<ProfileResponse>>> {
+        let response = self.respond(FakeRequest::Profile {
+            access_token: profile_access_token.to_string(),
+            etag,
+        })?;
+        Ok(Some(ResponseAndETag {
+            response,
+            etag: Some("fixture-etag".to_string()),
+        }))
+    }
+
+    fn pending_commands(
+        &self,
+        _config: &Config,
+        refresh_token: &str,
+        index: u64,
+        limit: u64,
+    ) -> Result<PendingCommandsResponse> {
+        self.respond(FakeRequest::PendingCommands {
+            refresh_token: refresh_token.to_string(),
+            index,
+            limit,
+        })
+    }
+
+    fn devices(&self, _config: &Config, refresh_token: &str) -> Result<Vec<DeviceResponse>> {
+        self.respond(FakeRequest::Devices {
+            refresh_token: refresh_token.to_string(),
+        })
+    }
+
+    fn update_device(
+        &self,
+        _config: &Config,
+        refresh_token: &str,
+        available_commands: &HashMap<String, String>,
+    ) -> Result<()> {
+        let _: serde_json::Value = self.respond(FakeRequest::UpdateDevice {
+            refresh_token: refresh_token.to_string(),
+            available_commands: available_commands.clone(),
+        })?;
+        Ok(())
+    }
+}
+
+/// Fills buffers with 0, 1, 2, ..., and uses `SCOPED_KEYS_PRIVATE_KEY` for
+/// scoped keys flows.
+pub struct FakeRandomSource;
+
+impl RandomSource for FakeRandomSource {
+    fn fill(&self, dest: &mut [u8]) -> Result<()> {
+        for (i, b) in dest.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        Ok(())
+    }
+
+    fn scoped_keys_flow(&self) -> Result<ScopedKeysFlow> {
+        ScopedKeysFlow::with_random_key(&FixedSliceRandom {
+            bytes: SCOPED_KEYS_PRIVATE_KEY,
+        })
+    }
+}
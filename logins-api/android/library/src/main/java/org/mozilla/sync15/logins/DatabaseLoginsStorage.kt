@@ -12,6 +12,8 @@ import android.util.Log
 import com.sun.jna.Pointer
 import kotlinx.coroutines.experimental.launch
 import org.mozilla.sync15.logins.rust.PasswordSyncAdapter
+import org.mozilla.sync15.logins.rust.RawFetchCallback
+import org.mozilla.sync15.logins.rust.RawLogCallback
 import org.mozilla.sync15.logins.rust.RawLoginSyncState
 import org.mozilla.sync15.logins.rust.RustError
 import java.io.Closeable
@@ -65,16 +67,42 @@ class DatabaseLoginsStorage(private val dbPath: String) : Closeable, LoginsStora
         }
     }
 
-    override fun sync(syncInfo: SyncUnlockInfo): SyncResult<Unit> {
+    override fun sync(
+            syncInfo: SyncUnlockInfo,
+            userInitiated: Boolean,
+            usernameMatch: UsernameMatch
+    ): SyncResult<SyncOutcome> {
         return safeAsync { error ->
             Log.d("LoginsAPI", "sync")
             checkUnlocked()
-            PasswordSyncAdapter.INSTANCE.sync15_passwords_sync(this.raw!!,
+            val result = PasswordSyncAdapter.INSTANCE.sync15_passwords_sync(this.raw!!,
                     syncInfo.kid,
                     syncInfo.fxaAccessToken,
                     syncInfo.syncKey,
                     syncInfo.tokenserverURL,
+                    (if (userInitiated) 1 else 0).toByte(),
+                    usernameMatch.toFlags(),
                     error)
+            try {
+                if (error.isFailure()) {
+                    throw error.intoException()
+                }
+                SyncOutcome.fromTagged(result.tag, result.getPayload())
+            } finally {
+                PasswordSyncAdapter.INSTANCE.sync15_passwords_destroy_sync_result(result)
+            }
+        }
+    }
+
+    /**
+     * Skip syncs that aren't user-initiated until `seconds` have passed since the last
+     * successful sync. Zero disables this, which is the default. The interval is stored
+     * in the database, so it only needs to be set again to change it.
+     */
+    fun setSyncMinInterval(seconds: Long): SyncResult<Unit> {
+        return safeAsync { error ->
+            checkUnlocked()
+            PasswordSyncAdapter.INSTANCE.sync15_passwords_set_sync_min_interval(this.raw!!, seconds, error)
         }
     }
 
@@ -118,6 +146,42 @@ class DatabaseLoginsStorage(private val dbPath: String) : Closeable, LoginsStora
         }
     }
 
+    /**
+     * Fetch the logins for `hostname`, restricted to those for `username` if it isn't null.
+     * Usernames are compared according to `usernameMatch`.
+     */
+    fun getByHostname(
+            hostname: String,
+            username: String? = null,
+            usernameMatch: UsernameMatch = UsernameMatch.EXACT
+    ): SyncResult<List<ServerPassword>> {
+        return safeAsyncString { error ->
+            checkUnlocked()
+            PasswordSyncAdapter.INSTANCE.sync15_passwords_get_by_hostname(this.raw!!,
+                    hostname, username, usernameMatch.toFlags(), error)
+        }.then { json ->
+            SyncResult.fromValue(ServerPassword.fromJSONArray(json!!))
+        }
+    }
+
+    /**
+     * Like `getByHostname`, but fetches the logins for `baseDomain` and all of its
+     * subdomains, on any scheme or port.
+     */
+    fun getByBaseDomain(
+            baseDomain: String,
+            username: String? = null,
+            usernameMatch: UsernameMatch = UsernameMatch.EXACT
+    ): SyncResult<List<ServerPassword>> {
+        return safeAsyncString { error ->
+            checkUnlocked()
+            PasswordSyncAdapter.INSTANCE.sync15_passwords_get_by_base_domain(this.raw!!,
+                    baseDomain, username, usernameMatch.toFlags(), error)
+        }.then { json ->
+            SyncResult.fromValue(ServerPassword.fromJSONArray(json!!))
+        }
+    }
+
     override fun touch(id: String): SyncResult<Unit> {
         return safeAsync { error ->
             Log.d("LoginsAPI", "touch by id")
@@ -174,6 +238,79 @@ class DatabaseLoginsStorage(private val dbPath: String) : Closeable, LoginsStora
 
     companion object {
 
+        /**
+         * Returns the components built into the logins library, as a JSON array of
+         * `{"name": ..., "version": ..., "gitHash": ...}` objects, where `gitHash` may be
+         * null. Meant for diagnostics screens and bug reports.
+         */
+        fun componentVersions(): String {
+            return getAndConsumeString(PasswordSyncAdapter.INSTANCE.sync15_passwords_component_versions())!!
+        }
+
+        // JNA doesn't keep the callback alive, so we have to.
+        private var logCallback: RawLogCallback? = null
+
+        /**
+         * Forwards logs from the logins library, at `maxLevel` (an `android.util.Log` priority)
+         * or less verbose, to `onLog` instead of logcat, replacing any callback set before.
+         * `onLog` may be called on any thread, and must not call back into this library.
+         *
+         * This must be called before any `DatabaseLoginsStorage` is unlocked, and returns false
+         * (and does nothing) otherwise.
+         */
+        @Synchronized
+        fun setLogCallback(maxLevel: Int, onLog: (level: Int, tag: String, message: String) -> Unit): Boolean {
+            val callback = object : RawLogCallback {
+                override fun invoke(level: Int, tag: String, message: String) {
+                    onLog(level, tag, message)
+                }
+            }
+            if (PasswordSyncAdapter.INSTANCE.sync15_passwords_set_log_callback(callback, maxLevel).toInt() == 0) {
+                return false
+            }
+            logCallback = callback
+            return true
+        }
+
+        /**
+         * Stops forwarding logs to the callback passed to `setLogCallback`. Logs don't go to
+         * logcat after this either.
+         */
+        @Synchronized
+        fun clearLogCallback() {
+            PasswordSyncAdapter.INSTANCE.sync15_passwords_clear_log_callback()
+            logCallback = null
+        }
+
+        // Kept alive for the same reason as `logCallback`.
+        private var fetchCallback: RawFetchCallback? = null
+
+        /**
+         * Sends the HTTP requests the logins library makes while syncing through `onFetch`,
+         * instead of its own network stack. `onFetch` gets the request and returns the response,
+         * both as JSON in the format described in `components/viaduct/README.md`. It's called
+         * synchronously on the syncing thread, and must not call back into this library.
+         *
+         * This must be called before the first sync, and returns false (and does nothing)
+         * otherwise, or if it was already called.
+         */
+        @Synchronized
+        fun setFetchCallback(onFetch: (request: String) -> String): Boolean {
+            if (fetchCallback != null) {
+                return false
+            }
+            val callback = object : RawFetchCallback {
+                override fun invoke(request: String): Pointer? {
+                    return PasswordSyncAdapter.INSTANCE.viaduct_alloc_string(onFetch(request))
+                }
+            }
+            if (PasswordSyncAdapter.INSTANCE.viaduct_initialize(callback).toInt() == 0) {
+                return false
+            }
+            fetchCallback = callback
+            return true
+        }
+
         internal fun getAndConsumeString(p: Pointer?): String? {
             if (p == null) {
                 return null;
@@ -27,8 +27,18 @@ interface LoginsStorage : Closeable {
 
     /**
      * Synchronize the logins storage layer with a remote layer.
+     *
+     * Pass false for `userInitiated` for scheduled or background syncs, which may be skipped
+     * if the storage rate limits them. The result says whether we synced.
+     *
+     * Incoming logins are merged into local ones for the same site whose usernames match
+     * according to `usernameMatch`, instead of being added alongside them.
      */
-    fun sync(syncInfo: SyncUnlockInfo): SyncResult<Unit>
+    fun sync(
+            syncInfo: SyncUnlockInfo,
+            userInitiated: Boolean = true,
+            usernameMatch: UsernameMatch = UsernameMatch.EXACT
+    ): SyncResult<SyncOutcome>
 
     /**
      * Delete all locally stored login sync metadata.
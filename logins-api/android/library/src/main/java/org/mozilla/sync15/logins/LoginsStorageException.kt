@@ -56,4 +56,10 @@ class InvalidKeyException(msg: String): LoginsStorageException(msg)
  */
 class RequestFailedException(msg: String): LoginsStorageException(msg)
 
+/**
+ * This error is emitted if the account is over its storage quota, so the
+ * server refused our uploads. The user should be asked to free up space.
+ */
+class SyncOverQuotaException(msg: String): LoginsStorageException(msg)
+
 
@@ -62,11 +62,15 @@ class MemoryLoginsStorage(private var list: List<ServerPassword>) : Closeable, L
         }
     }
 
-    override fun sync(syncInfo: SyncUnlockInfo): SyncResult<Unit> {
+    override fun sync(
+            syncInfo: SyncUnlockInfo,
+            userInitiated: Boolean,
+            usernameMatch: UsernameMatch
+    ): SyncResult<SyncOutcome> {
         return asyncResult {
             checkUnlocked()
             Log.w("MemoryLoginsStorage", "Not syncing because this implementation can not sync")
-            Unit
+            SyncOutcome.Synced(null, null, null)
         }
     }
 
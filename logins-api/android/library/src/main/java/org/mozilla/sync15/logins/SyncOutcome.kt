@@ -0,0 +1,48 @@
+/* Copyright 2018 Mozilla
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+package org.mozilla.sync15.logins
+
+import org.json.JSONObject
+
+/**
+ * What a sync did, when it didn't fail.
+ */
+sealed class SyncOutcome {
+
+    /**
+     * We synced. The account's storage usage and quota are from the last time the server
+     * reported them, and are null if it never has, or if the account has no quota.
+     */
+    data class Synced(
+            val usageKb: Double?,
+            val quotaKb: Double?,
+            val quotaRemainingKb: Double?
+    ) : SyncOutcome()
+
+    /**
+     * The sync wasn't user-initiated, and we synced less than the minimum interval ago (see
+     * `DatabaseLoginsStorage.setSyncMinInterval`), so we didn't sync. `nextAllowed` is in
+     * milliseconds since the epoch.
+     */
+    data class SkippedRateLimited(val nextAllowed: Long) : SyncOutcome()
+
+    companion object {
+        internal fun fromTagged(tag: Int, payload: String?): SyncOutcome {
+            val o = JSONObject(payload!!)
+            fun doubleOrNull(key: String): Double? {
+                return if (o.isNull(key)) null else o.getDouble(key)
+            }
+            return when (tag) {
+                0 -> Synced(doubleOrNull("usageKb"), doubleOrNull("quotaKb"), doubleOrNull("quotaRemainingKb"))
+                1 -> SkippedRateLimited(o.getLong("nextAllowed"))
+                else -> throw LoginsStorageException("Unknown sync outcome: $tag")
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+/* Copyright 2018 Mozilla
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+package org.mozilla.sync15.logins
+
+/**
+ * How usernames are compared when looking for local duplicates of incoming logins during a
+ * sync, and when looking up logins by username. Only ASCII letters are compared ignoring
+ * case, so "ÉMILE" and "émile" are still different usernames.
+ */
+data class UsernameMatch(
+        val ignoreCase: Boolean = false,
+        val trimWhitespace: Boolean = false
+) {
+    // The `USERNAME_MATCH_*` flags in `logins_sql::ffi`.
+    internal fun toFlags(): Byte {
+        var flags = 0
+        if (ignoreCase) {
+            flags = flags or 1
+        }
+        if (trimWhitespace) {
+            flags = flags or 2
+        }
+        return flags.toByte()
+    }
+
+    companion object {
+        val EXACT = UsernameMatch()
+    }
+}
@@ -7,6 +7,7 @@
  * CONDITIONS OF ANY KIND, either express or implied. See the License for the
  * specific language governing permissions and limitations under the License. */
 package org.mozilla.sync15.logins.rust
+import com.sun.jna.Callback
 import com.sun.jna.Library
 import com.sun.jna.Native
 import com.sun.jna.Pointer
@@ -32,6 +33,15 @@ internal interface PasswordSyncAdapter : Library {
 
     fun sync15_passwords_state_destroy(p: RawLoginSyncState)
 
+    // Copies the database at `db_path` to a new plain SQLite database at `dest_path`, with
+    // usernames and passwords encrypted using `field_key` (32 bytes). Fails if `dest_path` exists.
+    fun sync15_passwords_export_to_plaintext(db_path: String,
+                                             encryption_key: String,
+                                             dest_path: String,
+                                             field_key: ByteArray,
+                                             field_key_len: Int,
+                                             error: RustError.ByReference)
+
     // Important: strings returned from rust as *char must be Pointers on this end, returning a
     // String will work but either force us to leak them, or cause us to corrupt the heap (when we
     // free them).
@@ -42,17 +52,45 @@ internal interface PasswordSyncAdapter : Library {
     // return json array
     fun sync15_passwords_get_all(state: RawLoginSyncState, error: RustError.ByReference): Pointer
 
+    // Return json arrays. A null `username` matches any; `username_match` holds
+    // UsernameMatch's flags.
+    fun sync15_passwords_get_by_hostname(state: RawLoginSyncState,
+                                         hostname: String,
+                                         username: String?,
+                                         username_match: Byte,
+                                         error: RustError.ByReference): Pointer
+
+    fun sync15_passwords_get_by_base_domain(state: RawLoginSyncState,
+                                            base_domain: String,
+                                            username: String?,
+                                            username_match: Byte,
+                                            error: RustError.ByReference): Pointer
+
+    // Tag 0 means we synced, with `{"usageKb": ..., "quotaKb": ..., "quotaRemainingKb": ...}`
+    // (each possibly null) as the payload, and tag 1 means the sync was skipped due to the rate
+    // limit, with `{"nextAllowed": <ms since the epoch>}` as the payload. Must be freed with
+    // sync15_passwords_destroy_sync_result.
     fun sync15_passwords_sync(state: RawLoginSyncState,
                               key_id: String,
                               access_token: String,
                               sync_key: String,
                               token_server_url: String,
-                              error: RustError.ByReference)
+                              user_initiated: Byte,
+                              username_match: Byte,
+                              error: RustError.ByReference): RustTagged.ByValue
+
+    fun sync15_passwords_set_sync_min_interval(state: RawLoginSyncState,
+                                               min_interval_secs: Long,
+                                               error: RustError.ByReference)
 
     fun sync15_passwords_wipe(state: RawLoginSyncState, error: RustError.ByReference)
     fun sync15_passwords_reset(state: RawLoginSyncState, error: RustError.ByReference)
 
     fun sync15_passwords_touch(state: RawLoginSyncState, id: String, error: RustError.ByReference)
+    // Non-zero `excluded` keeps the login on this device only; zero includes it in sync again.
+    fun sync15_passwords_set_sync_excluded(state: RawLoginSyncState, id: String, excluded: Byte, error: RustError.ByReference)
+    // 1 if the login is excluded from sync, 0 otherwise.
+    fun sync15_passwords_is_sync_excluded(state: RawLoginSyncState, id: String, error: RustError.ByReference): Byte
     // This is 1 for true and 0 for false, it would be a boolean but we need to return a value with
     // a known size.
     fun sync15_passwords_delete(state: RawLoginSyncState, id: String, error: RustError.ByReference): Byte
@@ -60,7 +98,35 @@ internal interface PasswordSyncAdapter : Library {
     fun sync15_passwords_add(state: RawLoginSyncState, new_login_json: String, error: RustError.ByReference): Pointer
     fun sync15_passwords_update(state: RawLoginSyncState, existing_login_json: String, error: RustError.ByReference)
 
+    // Zero disables the trace (the default), and discards anything traced so far.
+    fun sync15_passwords_set_reconcile_trace_capacity(capacity: Int)
+    // Returns a json array of reconciliation decisions, oldest first.
+    fun sync15_passwords_get_reconcile_trace(error: RustError.ByReference): Pointer
+
+    // Returns a json array of the components built into this library, for diagnostics.
+    fun sync15_passwords_component_versions(): Pointer
+
+    // Returns 1 if the callback was set, or 0 if another logger (like logcat) was installed first.
+    fun sync15_passwords_set_log_callback(callback: RawLogCallback, max_level: Int): Byte
+    fun sync15_passwords_clear_log_callback()
+
+    // Sends every HTTP request this library makes through `callback` (see
+    // components/viaduct/README.md for the JSON it gets and returns). Returns 1 on success, or 0
+    // if requests were already being sent some other way.
+    fun viaduct_initialize(callback: RawFetchCallback): Byte
+    // The fetch callback must return its response through this, so that Rust can free it.
+    fun viaduct_alloc_string(s: String): Pointer?
+
     fun sync15_passwords_destroy_string(p: Pointer)
+    fun sync15_passwords_destroy_sync_result(r: RustTagged.ByValue)
 }
 
 class RawLoginSyncState : PointerType()
+
+internal interface RawLogCallback : Callback {
+    fun invoke(level: Int, tag: String, message: String)
+}
+
+internal interface RawFetchCallback : Callback {
+    fun invoke(request: String): Pointer?
+}
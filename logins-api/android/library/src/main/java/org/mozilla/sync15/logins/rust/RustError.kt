@@ -55,6 +55,7 @@ open class RustError : Structure() {
             4 -> return InvalidRecordException(message)
             5 -> return InvalidKeyException(message)
             6 -> return RequestFailedException(message)
+            7 -> return SyncOverQuotaException(message)
             else -> return LoginsStorageException(message)
         }
     }
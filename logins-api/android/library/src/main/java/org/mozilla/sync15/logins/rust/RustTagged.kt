@@ -0,0 +1,38 @@
+/* Copyright 2018 Mozilla
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+package org.mozilla.sync15.logins.rust
+
+import com.sun.jna.Pointer
+import com.sun.jna.Structure
+import java.util.Arrays
+
+/**
+ * A tag, and a payload which is either null or JSON, returned by value from
+ * rust functions that can succeed in more than one way.
+ *
+ * This should be considered private, but it needs to be public for JNA.
+ */
+open class RustTagged : Structure() {
+
+    class ByValue : RustTagged(), Structure.ByValue
+
+    @JvmField var tag: Int = 0
+    @JvmField var payload: Pointer? = null
+
+    /**
+     * Get the payload, or null if there is none.
+     */
+    fun getPayload(): String? {
+        return this.payload?.getString(0, "utf8")
+    }
+
+    override fun getFieldOrder(): List<String> {
+        return Arrays.asList("tag", "payload")
+    }
+}
@@ -258,13 +258,13 @@ abstract class LoginsStorageTest {
         assertEquals(toUpdate.passwordField, record.passwordField)
         assertEquals(toUpdate.usernameField, record.usernameField)
         assertEquals(toUpdate.formSubmitURL, record.formSubmitURL)
-        assertEquals(toUpdate.timesUsed + 1, record.timesUsed)
+        // Updating a login isn't using it, so only `timePasswordChanged`
+        // moves (since the password changed).
+        assertEquals(toUpdate.timesUsed, record.timesUsed)
         assertEquals(toUpdate.timeCreated, record.timeCreated)
+        assertEquals(toUpdate.timeLastUsed, record.timeLastUsed)
 
-        assert(toUpdate.timeLastUsed < record.timeLastUsed)
-
-        assert(toUpdate.timeLastUsed < record.timeLastUsed)
-        assert(toUpdate.timeLastUsed < record.timePasswordChanged)
+        assert(toUpdate.timePasswordChanged < record.timePasswordChanged)
 
         val specificID = waitForResult(test.add(ServerPassword(
                 id = "123412341234",
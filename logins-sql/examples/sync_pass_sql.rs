@@ -31,9 +31,9 @@ use failure::Fail;
 
 use std::{fs, io::{self, Read, Write}};
 use std::collections::HashMap;
-use fxa_client::{FirefoxAccount, Config, OAuthInfo};
+use fxa_client::{FirefoxAccount, Config, AccessTokenInfo};
 use sync::{Sync15StorageClientInit, KeyBundle};
-use logins_sql::{PasswordEngine, Login};
+use logins_sql::{PasswordEngine, Login, UsernameMatch};
 
 const CLIENT_ID: &str = "98adfa37698f255b";
 const REDIRECT_URI: &str = "https://lockbox.firefox.com/fxa/ios-redirect.html";
@@ -49,14 +49,6 @@ const SCOPES: &[&str] = &[
 // I'm completely punting on good error handling here.
 type Result<T> = std::result::Result<T, failure::Error>;
 
-#[derive(Debug, Deserialize)]
-struct ScopedKeyData {
-    k: String,
-    kty: String,
-    kid: String,
-    scope: String,
-}
-
 fn load_fxa_creds(path: &str) -> Result<FirefoxAccount> {
     let mut file = fs::File::open(path)?;
     let mut s = String::new();
@@ -128,8 +120,8 @@ fn read_login() -> Login {
     let form_submit_url = prompt_string("form_submit_url");
     let hostname = prompt_string("hostname").unwrap_or_default();
     let http_realm = prompt_string("http_realm");
-    let username_field = prompt_string("username_field").unwrap_or_default();
-    let password_field = prompt_string("password_field").unwrap_or_default();
+    let username_field = prompt_string("username_field");
+    let password_field = prompt_string("password_field");
     let record = Login {
         id: sync::util::random_guid().unwrap().into(),
         username,
@@ -171,8 +163,13 @@ fn update_login(record: &mut Login) {
     update_string("password", &mut record.password, ", leave blank to keep");
     update_string("hostname", &mut record.hostname, ", leave blank to keep");
 
-    update_string("username_field", &mut record.username_field, ", leave blank to keep");
-    update_string("password_field", &mut record.password_field, ", leave blank to keep");
+    if prompt_bool(&format!("edit username_field? (now {}) [yN]", string_opt_or(&record.username_field, "(none)"))).unwrap_or(false) {
+        record.username_field = prompt_string("username_field");
+    }
+
+    if prompt_bool(&format!("edit password_field? (now {}) [yN]", string_opt_or(&record.password_field, "(none)"))).unwrap_or(false) {
+        record.password_field = prompt_string("password_field");
+    }
 
     if prompt_bool(&format!("edit form_submit_url? (now {}) [yN]", string_opt_or(&record.form_submit_url, "(none)"))).unwrap_or(false) {
         record.form_submit_url = prompt_string("form_submit_url");
@@ -277,8 +274,8 @@ fn show_all(engine: &PasswordEngine) -> Result<Vec<String>> {
             string_opt_or(&rec.form_submit_url, ""),
             string_opt_or(&rec.http_realm, ""),
 
-            &rec.username_field,
-            &rec.password_field,
+            string_opt_or(&rec.username_field, ""),
+            string_opt_or(&rec.password_field, ""),
 
             rec.times_used,
             timestamp_to_string(rec.time_created),
@@ -362,24 +359,22 @@ fn main() -> Result<()> {
 
     // TODO: we should probably set a persist callback on acct?
     let mut acct = load_or_create_fxa_creds(cred_file, cfg.clone())?;
-    let token: OAuthInfo;
-    match acct.get_oauth_token(SCOPES)? {
+    let token: AccessTokenInfo;
+    match acct.get_access_token(SYNC_SCOPE)? {
         Some(t) => token = t,
         None => {
             // The cached credentials did not have appropriate scope, sign in again.
             warn!("Credentials do not have appropriate scope, launching OAuth flow.");
             acct = create_fxa_creds(cred_file, cfg.clone())?;
-            token = acct.get_oauth_token(SCOPES)?.unwrap();
+            token = acct.get_access_token(SYNC_SCOPE)?.unwrap();
         }
     }
 
-    let keys: HashMap<String, ScopedKeyData> = serde_json::from_str(&token.keys.unwrap())?;
-
-    let key = keys.get(SYNC_SCOPE).unwrap();
+    let key = token.key.expect("Sync token should come with a key");
 
     let client_init = Sync15StorageClientInit {
         key_id: key.kid.clone(),
-        access_token: token.access_token.clone(),
+        access_token: token.token.clone(),
         tokenserver_url,
     };
     let root_sync_key = KeyBundle::from_ksync_base64(&key.k)?;
@@ -455,11 +450,12 @@ fn main() -> Result<()> {
             }
             'S' | 's' => {
                 info!("Syncing!");
-                if let Err(e) = engine.sync(&client_init, &root_sync_key) {
-                    warn!("Sync failed! {}", e);
-                    warn!("BT: {:?}", e.backtrace());
-                } else {
-                    info!("Sync was successful!");
+                match engine.sync(&client_init, &root_sync_key, true, UsernameMatch::EXACT) {
+                    Ok(result) => info!("Sync was successful! {:?}", result),
+                    Err(e) => {
+                        warn!("Sync failed! {}", e);
+                        warn!("BT: {:?}", e.backtrace());
+                    }
                 }
             }
             'V' | 'v' => {
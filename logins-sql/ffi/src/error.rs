@@ -13,8 +13,18 @@ use logins_sql::{
     ErrorKind,
 };
 
-use sync15_adapter::{
-    ErrorKind as Sync15ErrorKind
+use sync15_adapter::ffi::{error_codes as sync15_codes};
+use ffi_support::{ErrorChain, ErrorCode, ErrorCodeSpace, IntoFfi};
+
+/// The domain of our links in an `ErrorChain`.
+const ERROR_DOMAIN: &str = "logins";
+
+/// The positive codes in `ExternErrorCode`, and the ones we'll add to it. This
+/// is the range assigned to us in `ffi_support`'s `code_space` module.
+pub const ERROR_CODES: ErrorCodeSpace = ErrorCodeSpace {
+    component: ERROR_DOMAIN,
+    start: 1,
+    end: 100,
 };
 
 #[inline]
@@ -48,6 +58,19 @@ where
     try_call_with_result(error, callback).unwrap_or_default()
 }
 
+/// For types returned by value through `IntoFfi`, like the `FfiTagged`
+/// values made by `implement_into_ffi_by_tag!`.
+pub unsafe fn with_translated_ffi_result<F, T>(error: *mut ExternError, callback: F) -> T::Value
+where
+    F: FnOnce() -> Result<T>,
+    T: IntoFfi,
+{
+    match try_call_with_result(error, callback) {
+        Some(v) => v.into_ffi_value(),
+        None => T::ffi_default(),
+    }
+}
+
 pub unsafe fn with_translated_string_result<F>(error: *mut ExternError, callback: F) -> *mut c_char
 where F: FnOnce() -> Result<String> {
     if let Some(s) = try_call_with_result(error, callback) {
@@ -109,7 +132,8 @@ where F: FnOnce() -> Result<R> {
 /// C-compatible Error code. Negative codes are not expected to be handled by
 /// the application, a code of zero indicates that no error occurred, and a
 /// positive error code indicates an error that will likely need to be handled
-/// by the application
+/// by the application. The negative codes are the ones reserved by
+/// `ffi_support::ErrorCode`, and the positive ones are in `ERROR_CODES`.
 #[repr(i32)]
 #[derive(Clone, Copy, Debug)]
 pub enum ExternErrorCode {
@@ -143,6 +167,10 @@ pub enum ExternErrorCode {
 
     /// A request to the sync server failed.
     NetworkError = 6,
+
+    /// The account is over its storage quota, so the server refused our
+    /// uploads. Apps should ask the user to free up space.
+    OverQuotaError = 7,
 }
 
 /// Represents an error that occurred on the rust side. Many rust FFI functions take a
@@ -190,13 +218,10 @@ fn get_code(err: &Error) -> ExternErrorCode {
     match err.kind() {
         ErrorKind::SyncAdapterError(e) => {
             error!("Sync error {:?}", e);
-            match e.kind() {
-                Sync15ErrorKind::TokenserverHttpError(401) => {
-                    ExternErrorCode::AuthInvalidError
-                },
-                Sync15ErrorKind::RequestError(_) => {
-                    ExternErrorCode::NetworkError
-                }
+            match e.error_chain().outermost().code.code() {
+                sync15_codes::AUTH_INVALID => ExternErrorCode::AuthInvalidError,
+                sync15_codes::NETWORK => ExternErrorCode::NetworkError,
+                sync15_codes::OVER_QUOTA => ExternErrorCode::OverQuotaError,
                 _ => ExternErrorCode::OtherError,
             }
         }
@@ -219,6 +244,10 @@ fn get_code(err: &Error) -> ExternErrorCode {
             error!("Not a database / invalid key error");
             ExternErrorCode::InvalidKeyError
         }
+        ErrorKind::InvalidFieldKey | ErrorKind::BadEncryptedField => {
+            error!("Invalid field encryption key");
+            ExternErrorCode::InvalidKeyError
+        }
         err => {
             error!("Unexpected error: {:?}", err);
             ExternErrorCode::OtherError
@@ -226,10 +255,24 @@ fn get_code(err: &Error) -> ExternErrorCode {
     }
 }
 
+// Errors from sync15 keep their own domain and code in the message, so that
+// they aren't lost when we report them as `OtherError`.
+fn error_chain(err: &Error, code: ExternErrorCode) -> ErrorChain {
+    let code = ErrorCode::new(code as i32);
+    match err.kind() {
+        // Our `Display` for these includes sync15's message, which is already
+        // in its own link.
+        ErrorKind::SyncAdapterError(e) => {
+            ErrorChain::new(ERROR_DOMAIN, code, "Error synchronizing").with_cause(e.error_chain())
+        }
+        _ => ErrorChain::new(ERROR_DOMAIN, code, err.to_string()),
+    }
+}
+
 impl From<Error> for ExternError {
     fn from(e: Error) -> ExternError {
         let code = get_code(&e);
-        let message = string_to_c_char(e.to_string());
+        let message = string_to_c_char(error_chain(&e, code).to_string());
         ExternError { message, code }
     }
 }
@@ -255,3 +298,71 @@ impl From<Box<std::any::Any + Send + 'static>> for ExternError {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use logins_sql::{check_golden, InvalidLogin};
+    use serde_json::Value as JsonValue;
+    use std::ffi::CString;
+
+    // Our codes and messages aren't JSON, but the wrappers depend on them
+    // just as much, so we check them against the golden files for the JSON
+    // schema too. See `logins_sql::JSON_SCHEMA_VERSION` for how to update
+    // them. The codes reserved by `ffi_support` are written by name, since
+    // their values are its to define (`test_reserved_codes` checks that ours
+    // match).
+    fn to_json(err: ExternError) -> JsonValue {
+        let message = unsafe { CString::from_raw(err.message) };
+        let code = ErrorCode::new(err.code as i32);
+        let code = if code == ErrorCode::UNEXPECTED {
+            json!("UNEXPECTED")
+        } else if code == ErrorCode::PANIC {
+            json!("PANIC")
+        } else {
+            json!(code.code())
+        };
+        json!({
+            "code": code,
+            "message": message.to_str().unwrap(),
+        })
+    }
+
+    #[test]
+    fn test_reserved_codes() {
+        assert_eq!(ErrorCode::new(ExternErrorCode::OtherError as i32), ErrorCode::UNEXPECTED);
+        assert_eq!(ErrorCode::new(ExternErrorCode::UnexpectedPanic as i32), ErrorCode::PANIC);
+        assert_eq!(ErrorCode::new(ExternErrorCode::NoError as i32), ErrorCode::SUCCESS);
+    }
+
+    #[test]
+    fn test_golden_errors() {
+        let errors: Vec<Error> = vec![
+            ErrorKind::NoSuchRecord("aaaabbbbcccc".into()).into(),
+            ErrorKind::DuplicateGuid("aaaabbbbcccc".into()).into(),
+            ErrorKind::InvalidLogin(InvalidLogin::EmptyPassword).into(),
+            ErrorKind::InvalidFieldKey.into(),
+            ErrorKind::FieldEncryptedDatabase.into(),
+        ];
+        let mut actual = errors.into_iter()
+            .map(|e| to_json(e.into()))
+            .collect::<Vec<_>>();
+        let panic: Box<std::any::Any + Send + 'static> = Box::new("Something went wrong");
+        actual.push(to_json(panic.into()));
+        check_golden("errors.json", &actual);
+    }
+
+    #[test]
+    fn test_sync_error_chain() {
+        let err: sync15_adapter::Error = sync15_adapter::ErrorKind::TokenserverHttpError(401).into();
+        let err: Error = err.into();
+        assert_eq!(
+            to_json(err.into()),
+            json!({
+                "code": ExternErrorCode::AuthInvalidError as i32,
+                "message": "[logins 1] Error synchronizing; caused by [sync15 2] \
+                            HTTP status 401 when requesting a token from the tokenserver",
+            })
+        );
+    }
+}
@@ -2,12 +2,14 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-extern crate serde_json;
+#[macro_use] extern crate serde_json;
 extern crate rusqlite;
 extern crate logins_sql;
 extern crate sync15_adapter;
+#[macro_use] extern crate ffi_support;
 extern crate url;
 #[macro_use] extern crate log;
+extern crate viaduct;
 
 #[cfg(target_os = "android")]
 extern crate android_logger;
@@ -15,11 +17,15 @@ extern crate android_logger;
 pub mod error;
 
 use std::os::raw::c_char;
-use std::ffi::{CString, CStr};
+use std::ffi::CString;
+use std::time::Duration;
+
+use ffi_support::{FfiStr, FfiTagged};
 
 use error::{
     ExternError,
     with_translated_result,
+    with_translated_ffi_result,
     with_translated_value_result,
     with_translated_void_result,
     with_translated_string_result,
@@ -29,99 +35,406 @@ use error::{
 use logins_sql::{
     Login,
     PasswordEngine,
+    UsernameMatch,
 };
 
-#[inline]
-unsafe fn c_str_to_str<'a>(cstr: *const c_char) -> &'a str {
-    CStr::from_ptr(cstr).to_str().unwrap_or_default()
-}
+// Re-exported so that they're exported from this library, letting the host
+// route sync's requests through its own network stack.
+pub use viaduct::backend::ffi::{viaduct_alloc_string, viaduct_initialize};
 
 fn logging_init() {
+    register_component_version!();
+    let registered = ffi_support::register_error_code_space(error::ERROR_CODES);
+    debug_assert!(registered, "Our error codes overlap another component's");
+    // If the app registered a log callback, our logs already go there.
     #[cfg(target_os = "android")]
     {
-        android_logger::init_once(
-            android_logger::Filter::default().with_min_level(log::Level::Trace),
-            Some("libloginsapi_ffi"));
-        debug!("Android logging should be hooked up!")
+        if !ffi_support::foreign_logger_installed() {
+            android_logger::init_once(
+                android_logger::Filter::default().with_min_level(log::Level::Trace),
+                Some("libloginsapi_ffi"));
+            debug!("Android logging should be hooked up!")
+        }
     }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_state_new(
-    db_path: *const c_char,
-    encryption_key: *const c_char,
+    db_path: FfiStr,
+    encryption_key: FfiStr,
     error: *mut ExternError
 ) -> *mut PasswordEngine {
     logging_init();
     trace!("sync15_passwords_state_new");
     with_translated_result(error, || {
-        let path = c_str_to_str(db_path);
-        let key = c_str_to_str(encryption_key);
+        let path = db_path.as_str();
+        let key = encryption_key.as_str();
         let state = PasswordEngine::new(path, Some(key))?;
         Ok(state)
     })
 }
 
+/// Exports the sqlcipher database at `db_path` to a new plain SQLite
+/// database at `dest_path`, with usernames and passwords encrypted using
+/// the `field_key_len` (which must be 32) bytes at `field_key`. See
+/// `logins_sql::export_to_plaintext` for the format. This doesn't need (and
+/// shouldn't be called with) an open `PasswordEngine` for `db_path`.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_export_to_plaintext(
+    db_path: FfiStr,
+    encryption_key: FfiStr,
+    dest_path: FfiStr,
+    field_key: *const u8,
+    field_key_len: i32,
+    error: *mut ExternError
+) {
+    logging_init();
+    trace!("sync15_passwords_export_to_plaintext");
+    with_translated_void_result(error, || {
+        assert!(!field_key.is_null(), "Null key passed to sync15_passwords_export_to_plaintext");
+        assert!(field_key_len >= 0, "Negative key length passed to sync15_passwords_export_to_plaintext");
+        let field_key = std::slice::from_raw_parts(field_key, field_key_len as usize);
+        logins_sql::export_to_plaintext(db_path.as_str(),
+                                        encryption_key.as_str(),
+                                        dest_path.as_str(),
+                                        field_key)
+    })
+}
+
 // indirection to help `?` figure out the target error type
 fn parse_url(url: &str) -> sync15_adapter::Result<url::Url> {
     Ok(url::Url::parse(url)?)
 }
 
+/// Sync passwords. Returns whether we synced, or skipped the sync because of
+/// the rate limit (see `sync15_passwords_set_sync_min_interval`), as
+/// described in `logins_sql::ffi`. The result must be freed with
+/// `sync15_passwords_destroy_sync_result`. Pass a non-zero `user_initiated`
+/// to ignore the rate limit. `username_match` holds the
+/// `logins_sql::ffi::USERNAME_MATCH_*` flags for comparing usernames when
+/// looking for local dupes of incoming logins; zero compares them exactly.
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_sync(
     state: *mut PasswordEngine,
-    key_id: *const c_char,
-    access_token: *const c_char,
-    sync_key: *const c_char,
-    tokenserver_url: *const c_char,
+    key_id: FfiStr,
+    access_token: FfiStr,
+    sync_key: FfiStr,
+    tokenserver_url: FfiStr,
+    user_initiated: u8,
+    username_match: u8,
     error: *mut ExternError
-) {
+) -> FfiTagged {
     trace!("sync15_passwords_sync");
-    with_translated_void_result(error, || {
+    with_translated_ffi_result(error, || {
         assert!(!state.is_null(), "Null state passed to sync15_passwords_sync");
         let state = &mut *state;
         state.sync(
             &sync15_adapter::Sync15StorageClientInit {
-                key_id: c_str_to_str(key_id).into(),
-                access_token: c_str_to_str(access_token).into(),
-                tokenserver_url: parse_url(c_str_to_str(tokenserver_url))?,
+                key_id: key_id.as_str().into(),
+                access_token: access_token.as_str().into(),
+                tokenserver_url: parse_url(tokenserver_url.as_str())?,
             },
             &sync15_adapter::KeyBundle::from_ksync_base64(
-                c_str_to_str(sync_key).into()
-            )?
+                sync_key.as_str().into()
+            )?,
+            user_initiated != 0,
+            UsernameMatch::from_ffi_flags(username_match)
         )
     })
 }
 
+/// Compares the logins stored locally with the ones on the server, and
+/// returns the differences as JSON (see `sync15_adapter::ValidationReport`).
+/// This takes the same arguments as `sync15_passwords_sync`, and is meant to
+/// be called right after a successful sync, when the app wants to report
+/// validation telemetry. It doesn't change any logins.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_validate(
+    state: *mut PasswordEngine,
+    key_id: FfiStr,
+    access_token: FfiStr,
+    sync_key: FfiStr,
+    tokenserver_url: FfiStr,
+    error: *mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_validate");
+    with_translated_string_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_validate");
+        let state = &mut *state;
+        let report = state.validate(
+            &sync15_adapter::Sync15StorageClientInit {
+                key_id: key_id.as_str().into(),
+                access_token: access_token.as_str().into(),
+                tokenserver_url: parse_url(tokenserver_url.as_str())?,
+            },
+            &sync15_adapter::KeyBundle::from_ksync_base64(
+                sync_key.as_str().into()
+            )?,
+        )?;
+        let result = serde_json::to_string(&report)?;
+        Ok(result)
+    })
+}
+
+/// Skip syncs that aren't user-initiated until `min_interval_secs` seconds
+/// have passed since the last successful sync. Zero or less disables this.
+/// The interval is stored in the database, so it only needs to be set again
+/// to change it.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_set_sync_min_interval(
+    state: *mut PasswordEngine,
+    min_interval_secs: i64,
+    error: *mut ExternError
+) {
+    trace!("sync15_passwords_set_sync_min_interval");
+    with_translated_void_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_set_sync_min_interval");
+        let state = &mut *state;
+        state.set_sync_min_interval(if min_interval_secs > 0 {
+            Some(Duration::from_secs(min_interval_secs as u64))
+        } else {
+            None
+        })
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_touch(
     state: *const PasswordEngine,
-    id: *const c_char,
+    id: FfiStr,
     error: *mut ExternError
 ) {
     trace!("sync15_passwords_touch");
     with_translated_void_result(error, || {
         assert!(!state.is_null(), "Null state passed to sync15_passwords_touch");
         let state = &*state;
-        state.touch(c_str_to_str(id))
+        state.touch(id.as_str())
+    })
+}
+
+/// Like `sync15_passwords_touch`, but also records that the login was used
+/// for `origin` in `context`, the app it was filled into (for example, an
+/// Android package name). `context` may be null when the login was used in
+/// the browser itself. This is local-only, and is used to rank the results of
+/// `sync15_passwords_get_for_usage_context`.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_touch_in_context(
+    state: *const PasswordEngine,
+    id: FfiStr,
+    origin: FfiStr,
+    context: FfiStr,
+    error: *mut ExternError
+) {
+    trace!("sync15_passwords_touch_in_context");
+    with_translated_void_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_touch_in_context");
+        let state = &*state;
+        state.touch_in_context(id.as_str(), origin.as_str(), context.as_opt_str())
+    })
+}
+
+/// Returns a JSON array of the logins to offer for `origin` in `context` (or
+/// in the browser, if `context` is null), best match first. This includes
+/// logins saved for other hostnames that were used for `origin`. See
+/// `LoginDb::get_for_usage_context` for how they're ranked.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_get_for_usage_context(
+    state: *const PasswordEngine,
+    origin: FfiStr,
+    context: FfiStr,
+    error: *mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_get_for_usage_context");
+    with_translated_string_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_get_for_usage_context");
+        let state = &*state;
+        let logins = state.get_for_usage_context(origin.as_str(), context.as_opt_str())?;
+        let result = serde_json::to_string(&logins)?;
+        Ok(result)
+    })
+}
+
+/// Exclude the login with the given id from sync (if `excluded` is non-zero),
+/// or include it again. Excluded logins are never uploaded, and changes to
+/// them from other devices are never applied locally. Including a login again
+/// uploads the local copy, overwriting any changes made elsewhere in the
+/// meantime. See `LoginDb::set_sync_excluded` for the details.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_set_sync_excluded(
+    state: *const PasswordEngine,
+    id: FfiStr,
+    excluded: u8,
+    error: *mut ExternError
+) {
+    trace!("sync15_passwords_set_sync_excluded");
+    with_translated_void_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_set_sync_excluded");
+        let state = &*state;
+        state.set_sync_excluded(id.as_str(), excluded != 0)
+    })
+}
+
+/// Returns 1 if the login with the given id is excluded from sync, and 0
+/// otherwise.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_is_sync_excluded(
+    state: *const PasswordEngine,
+    id: FfiStr,
+    error: *mut ExternError
+) -> u8 {
+    trace!("sync15_passwords_is_sync_excluded");
+    with_translated_value_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_is_sync_excluded");
+        let state = &*state;
+        let excluded = state.is_sync_excluded(id.as_str())?;
+        Ok(if excluded { 1 } else { 0 })
     })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_delete(
     state: *const PasswordEngine,
-    id: *const c_char,
+    id: FfiStr,
     error: *mut ExternError
 ) -> u8 {
     trace!("sync15_passwords_delete");
     with_translated_value_result(error, || {
         assert!(!state.is_null(), "Null state passed to sync15_passwords_delete");
         let state = &*state;
-        let deleted = state.delete(c_str_to_str(id))?;
+        let deleted = state.delete(id.as_str())?;
+        Ok(if deleted { 1 } else { 0 })
+    })
+}
+
+/// Delete the logins whose ids are in `ids_json`, a JSON array of strings,
+/// recording tombstones for the ones that were synced. Either all of them
+/// are deleted, or none are. Returns the number of logins that existed.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_delete_many(
+    state: *const PasswordEngine,
+    ids_json: FfiStr,
+    error: *mut ExternError
+) -> i64 {
+    trace!("sync15_passwords_delete_many");
+    with_translated_value_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_delete_many");
+        let state = &*state;
+        let ids: Vec<String> = serde_json::from_str(ids_json.as_str())?;
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let deleted = state.delete_many(&ids)?;
+        Ok(deleted as i64)
+    })
+}
+
+/// Returns a JSON array of the logins that haven't been used since
+/// `time_ms` (in milliseconds since the epoch), least recently used first,
+/// for a "clean up unused logins" UI. Logins that were never used count as
+/// last used when they were created.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_get_unused_since(
+    state: *const PasswordEngine,
+    time_ms: i64,
+    error: *mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_get_unused_since");
+    with_translated_string_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_get_unused_since");
+        let state = &*state;
+        let unused = state.get_unused_since(time_ms)?;
+        let result = serde_json::to_string(&unused)?;
+        Ok(result)
+    })
+}
+
+/// Returns a JSON array of the local versions of logins that were overwritten
+/// by incoming changes during sync, most recently recovered first. Each one
+/// has an `id` (for `sync15_passwords_restore_recovered` and
+/// `sync15_passwords_delete_recovered`), the `login`, and when it was
+/// recovered, as `recoveredAt` in milliseconds since the epoch.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_get_recovered(
+    state: *const PasswordEngine,
+    error: *mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_get_recovered");
+    with_translated_string_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_get_recovered");
+        let state = &*state;
+        let recovered = state.get_recovered()?;
+        let result = serde_json::to_string(&recovered)?;
+        Ok(result)
+    })
+}
+
+/// Restores the recovered login with the given id, and returns the id of the
+/// login it was restored to. That's the login it was recovered from, unless
+/// that's been deleted since, in which case it's added again with a new id.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_restore_recovered(
+    state: *const PasswordEngine,
+    id: i64,
+    error: *mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_restore_recovered");
+    with_translated_string_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_restore_recovered");
+        let state = &*state;
+        state.restore_recovered(id)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_delete_recovered(
+    state: *const PasswordEngine,
+    id: i64,
+    error: *mut ExternError
+) -> u8 {
+    trace!("sync15_passwords_delete_recovered");
+    with_translated_value_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_delete_recovered");
+        let state = &*state;
+        let deleted = state.delete_recovered(id)?;
         Ok(if deleted { 1 } else { 0 })
     })
 }
 
+/// Deletes the logins recovered before `time_ms` (in milliseconds since the
+/// epoch), and returns how many there were.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_purge_recovered(
+    state: *const PasswordEngine,
+    time_ms: i64,
+    error: *mut ExternError
+) -> i64 {
+    trace!("sync15_passwords_purge_recovered");
+    with_translated_value_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_purge_recovered");
+        let state = &*state;
+        let purged = state.purge_recovered(time_ms)?;
+        Ok(purged as i64)
+    })
+}
+
+/// Does housekeeping on the database, and returns what it did as JSON (see
+/// `logins_sql::MaintenanceReport`). Intended to be called when the
+/// application is idle. `allow_vacuum` should be nonzero if it's okay to
+/// vacuum the database, which can take a while.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_run_maintenance(
+    state: *const PasswordEngine,
+    allow_vacuum: u8,
+    error: *mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_run_maintenance");
+    with_translated_string_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_run_maintenance");
+        let state = &*state;
+        let report = state.run_maintenance(allow_vacuum != 0)?;
+        let result = serde_json::to_string(&report)?;
+        Ok(result)
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_wipe(
     state: *const PasswordEngine,
@@ -166,14 +479,14 @@ pub unsafe extern "C" fn sync15_passwords_get_all(
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_get_by_id(
     state: *const PasswordEngine,
-    id: *const c_char,
+    id: FfiStr,
     error: *mut ExternError
 ) -> *mut c_char {
     trace!("sync15_passwords_get_by_id");
     with_translated_opt_string_result(error, || {
         assert!(!state.is_null(), "Null state passed to sync15_passwords_get_by_id");
         let state = &*state;
-        if let Some(password) = state.get(c_str_to_str(id))? {
+        if let Some(password) = state.get(id.as_str())? {
             Ok(Some(serde_json::to_string(&password)?))
         } else {
             Ok(None)
@@ -181,17 +494,62 @@ pub unsafe extern "C" fn sync15_passwords_get_by_id(
     })
 }
 
+/// Returns a JSON array of the logins for `hostname`, restricted to those for
+/// `username` unless it's null. `username_match` holds the
+/// `logins_sql::ffi::USERNAME_MATCH_*` flags for comparing usernames.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_get_by_hostname(
+    state: *const PasswordEngine,
+    hostname: FfiStr,
+    username: FfiStr,
+    username_match: u8,
+    error: *mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_get_by_hostname");
+    with_translated_string_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_get_by_hostname");
+        let state = &*state;
+        let logins = state.get_by_hostname(hostname.as_str(),
+                                           username.as_opt_str(),
+                                           UsernameMatch::from_ffi_flags(username_match))?;
+        let result = serde_json::to_string(&logins)?;
+        Ok(result)
+    })
+}
+
+/// Like `sync15_passwords_get_by_hostname`, but returns the logins for
+/// `base_domain` and all of its subdomains, on any scheme or port.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_get_by_base_domain(
+    state: *const PasswordEngine,
+    base_domain: FfiStr,
+    username: FfiStr,
+    username_match: u8,
+    error: *mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_get_by_base_domain");
+    with_translated_string_result(error, || {
+        assert!(!state.is_null(), "Null state passed to sync15_passwords_get_by_base_domain");
+        let state = &*state;
+        let logins = state.get_by_base_domain(base_domain.as_str(),
+                                              username.as_opt_str(),
+                                              UsernameMatch::from_ffi_flags(username_match))?;
+        let result = serde_json::to_string(&logins)?;
+        Ok(result)
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_add(
     state: *const PasswordEngine,
-    record_json: *const c_char,
+    record_json: FfiStr,
     error: *mut ExternError
 ) -> *mut c_char {
     trace!("sync15_passwords_add");
     with_translated_string_result(error, || {
         assert!(!state.is_null(), "Null state passed to sync15_passwords_add");
         let state = &*state;
-        let mut parsed: serde_json::Value = serde_json::from_str(c_str_to_str(record_json))?;
+        let mut parsed: serde_json::Value = serde_json::from_str(record_json.as_str())?;
         if parsed.get("id").is_none() {
             // Note: we replace this with a real guid in `db.rs`.
             parsed["id"] = serde_json::Value::String(String::default());
@@ -204,28 +562,55 @@ pub unsafe extern "C" fn sync15_passwords_add(
 #[no_mangle]
 pub unsafe extern "C" fn sync15_passwords_update(
     state: *const PasswordEngine,
-    record_json: *const c_char,
+    record_json: FfiStr,
     error: *mut ExternError
 ) {
     trace!("sync15_passwords_update");
     with_translated_void_result(error, || {
         assert!(!state.is_null(), "Null state passed to sync15_passwords_update");
         let state = &*state;
-        let parsed: Login = serde_json::from_str(c_str_to_str(record_json))?;
+        let parsed: Login = serde_json::from_str(record_json.as_str())?;
         state.update(parsed)
     });
 }
 
+/// Start tracing the decisions we make when reconciling incoming records,
+/// keeping the most recent `capacity` of them in memory (see
+/// `sync15_passwords_get_reconcile_trace`). Zero or less stops tracing and
+/// discards the trace. This is off by default, and meant for debugging.
 #[no_mangle]
-pub unsafe extern "C" fn sync15_passwords_destroy_string(s: *mut c_char) {
-    if !s.is_null() {
-        drop(CString::from_raw(s));
+pub extern "C" fn sync15_passwords_set_reconcile_trace_capacity(capacity: i32) {
+    trace!("sync15_passwords_set_reconcile_trace_capacity");
+    if capacity > 0 {
+        sync15_adapter::trace::enable_reconcile_trace(capacity as usize);
+    } else {
+        sync15_adapter::trace::disable_reconcile_trace();
     }
 }
 
+/// Returns the traced reconciliation decisions as a JSON array, oldest first.
+/// See `sync15_adapter::trace::ReconcileDecision` for the shape of each item.
+/// Record ids are hashed, and record contents are never traced, so this is
+/// safe to attach to bug reports.
 #[no_mangle]
-pub unsafe extern "C" fn sync15_passwords_state_destroy(obj: *mut PasswordEngine) {
-    if !obj.is_null() {
-        drop(Box::from_raw(obj));
+pub unsafe extern "C" fn sync15_passwords_get_reconcile_trace(
+    error: *mut ExternError
+) -> *mut c_char {
+    trace!("sync15_passwords_get_reconcile_trace");
+    with_translated_string_result(error, || {
+        Ok(serde_json::to_string(&sync15_adapter::trace::reconcile_trace())?)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_destroy_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
     }
 }
+
+define_tagged_destructor!(sync15_passwords_destroy_sync_result);
+define_box_destructor!(PasswordEngine, sync15_passwords_state_destroy);
+define_component_versions_query!(sync15_passwords_component_versions);
+define_log_callback_setters!(sync15_passwords_set_log_callback, sync15_passwords_clear_log_callback);
+define_error_code_spaces_query!(sync15_passwords_error_code_spaces);
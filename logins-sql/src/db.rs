@@ -2,25 +2,79 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use rusqlite::{Connection, types::{ToSql, FromSql}};
-use std::time::SystemTime;
+use rusqlite::{Connection, TransactionBehavior, types::{ToSql, FromSql}};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::path::Path;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use error::*;
 use schema;
-use login::{LocalLogin, MirrorLogin, Login, SyncStatus, SyncLoginData};
-use sync::{self, ServerTimestamp, IncomingChangeset, Store, OutgoingChangeset, Payload};
+use login::{add_unknown_fields, take_unknown_fields, LocalLogin, MirrorLogin, Login, RecoveredLogin, SyncStatus, SyncLoginData};
+use sync::{self, CollectionName, ServerTimestamp, IncomingChangeset, Store, StoreCommand, OutgoingChangeset, Payload};
+use sync::{trace_reconcile, ReconcileWinner};
+use sync::validation::{LocalRecords, ValidatableStore};
+use sync::sync_lock::{self, SyncLock};
+use sync_guid::{Guid, GuidRef};
+use telemetry::IncomingTelemetry;
 use update_plan::UpdatePlan;
 use sql_support::{self, ConnExt};
 use util;
+use paths::LoginStorePaths;
 use std::ops::Deref;
 
 pub struct LoginDb {
     pub db: Connection,
+    incoming_telemetry: Option<IncomingTelemetry>,
+    // Identifies us in the sync lock.
+    instance_id: String,
+    // Whether we took the sync lock with `begin_sync`, and so need to renew
+    // it before writing anything that's synced.
+    holds_sync_lock: bool,
+}
+
+/// Controls how usernames are compared when looking for duplicate logins
+/// during a sync (see `LoginStore`), and when looking up logins by username.
+/// Each of these takes one, so different callers can compare differently.
+///
+/// The comparison is done entirely in SQL. Note that SQLite's `NOCASE`
+/// collation only folds ASCII characters, so e.g. "ÉMILE" and "émile" are
+/// still considered different usernames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UsernameMatch {
+    /// Compare usernames using `COLLATE NOCASE`.
+    pub ignore_case: bool,
+    /// Ignore leading and trailing whitespace.
+    pub trim_whitespace: bool,
+}
+
+impl UsernameMatch {
+    pub const EXACT: UsernameMatch = UsernameMatch {
+        ignore_case: false,
+        trim_whitespace: false,
+    };
+
+    /// Returns an SQL expression comparing the `username` column against the
+    /// `param` named parameter using these options.
+    fn sql_condition(&self, param: &str) -> String {
+        let (col, param) = if self.trim_whitespace {
+            ("trim(username)".to_owned(), format!("trim({})", param))
+        } else {
+            ("username".to_owned(), param.to_owned())
+        };
+        if self.ignore_case {
+            format!("{} IS {} COLLATE NOCASE", col, param)
+        } else {
+            format!("{} IS {}", col, param)
+        }
+    }
 }
 
 impl LoginDb {
     pub fn with_connection(db: Connection, encryption_key: Option<&str>) -> Result<Self> {
+        Self::configure(&db, encryption_key)?;
+        Self::init(db)
+    }
+
+    fn configure(db: &Connection, encryption_key: Option<&str>) -> Result<()> {
         #[cfg(test)] {
             util::init_test_logging();
         }
@@ -46,9 +100,22 @@ impl LoginDb {
         ", encryption_pragmas);
 
         db.execute_batch(&initial_pragmas)?;
+        Ok(())
+    }
 
-        let mut logins = Self { db };
+    fn init(db: Connection) -> Result<Self> {
+        let mut logins = Self {
+            db,
+            incoming_telemetry: None,
+            instance_id: sync_lock::new_instance_id()?,
+            holds_sync_lock: false,
+        };
         schema::init(&mut logins)?;
+        // Syncing a database exported by `export_to_plaintext` would upload
+        // its encrypted usernames and passwords.
+        if logins.get_meta::<String>(schema::FIELD_ENCRYPTION_META_KEY)?.is_some() {
+            throw!(ErrorKind::FieldEncryptedDatabase);
+        }
         Ok(logins)
     }
 
@@ -56,6 +123,41 @@ impl LoginDb {
         Ok(Self::with_connection(Connection::open(path)?, encryption_key)?)
     }
 
+    /// Opens the database in a profile directory. Unlike `open`, this backs
+    /// up the database before migrating it to a newer schema, and puts the
+    /// backup back if the migration fails, or if we find that a previous
+    /// migration was interrupted.
+    pub fn open_in_profile(paths: &LoginStorePaths, encryption_key: Option<&str>) -> Result<Self> {
+        if paths.restore_migration_backup()? {
+            warn!("The last logins schema migration was interrupted, and has been rolled back");
+        }
+        let db = Connection::open(paths.database())?;
+        Self::configure(&db, encryption_key)?;
+        let user_version = db.query_one::<i64>("PRAGMA user_version")?;
+        // Creating the schema in a new database doesn't need a backup.
+        let needs_backup = user_version > 0 && user_version < schema::VERSION;
+        if needs_backup {
+            paths.backup_for_migration(schema::VERSION)?;
+        }
+        match Self::init(db) {
+            Ok(logins) => {
+                if needs_backup {
+                    paths.finish_migration(schema::VERSION)?;
+                }
+                Ok(logins)
+            }
+            Err(e) => {
+                // `init` closed the connection when it failed, so it's safe to
+                // replace the file.
+                if needs_backup {
+                    error!("Failed to migrate logins database: {}", e);
+                    paths.restore_migration_backup()?;
+                }
+                Err(e)
+            }
+        }
+    }
+
     pub fn open_in_memory(encryption_key: Option<&str>) -> Result<Self> {
         Ok(Self::with_connection(Connection::open_in_memory()?, encryption_key)?)
     }
@@ -80,57 +182,158 @@ impl Deref for LoginDb {
 // login specific stuff.
 
 impl LoginDb {
+    /// Returns the telemetry for the records we downloaded during the most
+    /// recent sync, or None if we haven't applied any incoming records since
+    /// opening the database.
+    pub fn incoming_telemetry(&self) -> Option<&IncomingTelemetry> {
+        self.incoming_telemetry.as_ref()
+    }
 
-    fn mark_as_synchronized(&mut self, guids: &[&str], ts: ServerTimestamp) -> Result<()> {
-        sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
-            self.db.execute(
-                &format!("DELETE FROM loginsM WHERE guid IN ({vars})",
-                         vars = sql_support::repeat_sql_vars(chunk.len())),
-                chunk
-            )?;
-
-            self.db.execute(
-                &format!("
-                    INSERT OR IGNORE INTO loginsM (
-                        {common_cols}, is_overridden, server_modified
-                    )
-                    SELECT {common_cols}, 0, {modified_ms_i64}
-                    FROM loginsL
-                    WHERE is_deleted = 0 AND guid IN ({vars})",
-                    common_cols = schema::COMMON_COLS,
-                    modified_ms_i64 = ts.as_millis() as i64,
-                    vars = sql_support::repeat_sql_vars(chunk.len())),
-                chunk
-            )?;
-
-            self.db.execute(
-                &format!("DELETE FROM loginsL WHERE guid IN ({vars})",
-                         vars = sql_support::repeat_sql_vars(chunk.len())),
-                chunk
-            )?;
-            Ok(())
-        })?;
-        self.set_last_sync(ts)?;
-        Ok(())
+    fn mark_as_synchronized(&self, guids: &[&str], ts: ServerTimestamp) -> Result<()> {
+        self.in_sync_transaction(|db| {
+            sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
+                // We replace the mirror rows with the local ones below, so hang on
+                // to the fields from the server that we didn't understand.
+                let unknown_fields: Vec<(String, String)> = {
+                    let mut stmt = db.db.prepare(&format!("
+                        SELECT guid, unknownFields FROM loginsM
+                        WHERE unknownFields IS NOT NULL AND guid IN ({vars})",
+                        vars = sql_support::repeat_sql_vars(chunk.len())))?;
+                    let rows = stmt.query_and_then(chunk, |row| -> Result<(String, String)> {
+                        Ok((row.get_checked(0)?, row.get_checked(1)?))
+                    })?;
+                    rows.collect::<Result<_>>()?
+                };
+
+                db.db.execute(
+                    &format!("DELETE FROM loginsM WHERE guid IN ({vars})",
+                             vars = sql_support::repeat_sql_vars(chunk.len())),
+                    chunk
+                )?;
+
+                db.db.execute(
+                    &format!("
+                        INSERT OR IGNORE INTO loginsM (
+                            {common_cols}, is_overridden, server_modified
+                        )
+                        SELECT {common_cols}, 0, {modified_ms_i64}
+                        FROM loginsL
+                        WHERE is_deleted = 0 AND guid IN ({vars})",
+                        common_cols = schema::COMMON_COLS,
+                        modified_ms_i64 = ts.as_millis() as i64,
+                        vars = sql_support::repeat_sql_vars(chunk.len())),
+                    chunk
+                )?;
+
+                for (guid, fields) in &unknown_fields {
+                    db.db.execute_named_cached(
+                        "UPDATE loginsM SET unknownFields = :unknown_fields WHERE guid = :guid",
+                        &[(":unknown_fields", fields as &ToSql), (":guid", guid as &ToSql)]
+                    )?;
+                }
+
+                db.db.execute(
+                    &format!("DELETE FROM loginsL WHERE guid IN ({vars})",
+                             vars = sql_support::repeat_sql_vars(chunk.len())),
+                    chunk
+                )?;
+                Ok(())
+            })?;
+            db.set_last_sync(ts)
+        })
+    }
+
+    /// Prepares downloaded records for `fetch_login_data`. Ids that the sync
+    /// server would reject are replaced with local guids (see the
+    /// `loginsIdMap` table in the schema docs), and if an id occurs more than
+    /// once, we only keep the most recently modified record.
+    fn normalize_incoming(
+        &self,
+        records: Vec<(Payload, ServerTimestamp)>,
+        telemetry: &mut IncomingTelemetry,
+    ) -> Result<Vec<(Payload, ServerTimestamp)>> {
+        let mut result: Vec<(Payload, ServerTimestamp)> = Vec::with_capacity(records.len());
+        let mut index_by_id: HashMap<String, usize> = HashMap::with_capacity(records.len());
+        let report = Guid::validate_all(records.iter().map(|r| r.0.id.as_str()));
+        if !report.all_valid() {
+            warn!("{} of {} incoming ids are invalid", report.invalid_count, report.total);
+        }
+        for (mut payload, ts) in records {
+            if !report.all_valid() && !Guid::new(&payload.id).is_valid_for_sync_server() {
+                let local_guid = match self.get_mapped_guid(&payload.id)? {
+                    Some(guid) => guid,
+                    None if payload.is_tombstone() => {
+                        debug!("Ignoring tombstone for unknown invalid id {}", GuidRef::new(&payload.id).redacted());
+                        telemetry.invalid_ignored += 1;
+                        continue;
+                    }
+                    None => self.add_id_mapping(&payload.id)?,
+                };
+                debug!("Mapping invalid id {} to local guid {}",
+                       GuidRef::new(&payload.id).redacted(), GuidRef::new(&local_guid).redacted());
+                telemetry.remapped += 1;
+                payload.id = local_guid;
+            }
+            // Note that we check for duplicates after mapping, since once we
+            // upload a change to a remapped record, the server has it under
+            // both ids.
+            if let Some(&idx) = index_by_id.get(&payload.id) {
+                debug!("Incoming id {} appears more than once, keeping the newest", payload.id);
+                telemetry.duplicate_ids += 1;
+                if ts >= result[idx].1 {
+                    result[idx] = (payload, ts);
+                }
+                continue;
+            }
+            index_by_id.insert(payload.id.clone(), result.len());
+            result.push((payload, ts));
+        }
+        Ok(result)
+    }
+
+    fn get_mapped_guid(&self, remote_id: &str) -> Result<Option<String>> {
+        self.try_query_row(
+            "SELECT local_guid FROM loginsIdMap WHERE remote_id = :remote_id",
+            &[(":remote_id", &remote_id as &ToSql)],
+            |row| Ok::<_, Error>(row.get_checked(0)?),
+            true
+        )
+    }
+
+    fn add_id_mapping(&self, remote_id: &str) -> Result<String> {
+        let local_guid = Guid::random().into_string();
+        self.execute_named_cached(
+            "INSERT INTO loginsIdMap (remote_id, local_guid) VALUES (:remote_id, :local_guid)",
+            &[(":remote_id", &remote_id as &ToSql), (":local_guid", &local_guid as &ToSql)]
+        )?;
+        Ok(local_guid)
     }
 
     // Fetch all the data for the provided IDs.
     // TODO: Might be better taking a fn instead of returning all of it... But that func will likely
     // want to insert stuff while we're doing this so ugh.
-    fn fetch_login_data(&self, records: &[(sync::Payload, ServerTimestamp)]) -> Result<Vec<SyncLoginData>> {
-        let mut sync_data = Vec::with_capacity(records.len());
+    //
+    // This takes ownership of the payloads, so that the strings sync15 already
+    // parsed out of the cleartext are moved into the `Login`s, rather than
+    // copied (see `SyncLoginData::from_payload`).
+    fn fetch_login_data(&self, records: Vec<(sync::Payload, ServerTimestamp)>) -> Result<Vec<SyncLoginData>> {
         {
-            let mut seen_ids: HashSet<String> = HashSet::with_capacity(records.len());
-            for incoming in records.iter() {
-                if seen_ids.contains(&incoming.0.id) {
-                    throw!(ErrorKind::DuplicateGuid(incoming.0.id.to_string()))
+            let mut seen_ids: HashSet<&str> = HashSet::with_capacity(records.len());
+            for &(ref payload, _) in &records {
+                if !seen_ids.insert(&payload.id) {
+                    throw!(ErrorKind::DuplicateGuid(payload.id.clone()))
                 }
-                seen_ids.insert(incoming.0.id.clone());
-                sync_data.push(SyncLoginData::from_payload(incoming.0.clone(), incoming.1)?);
             }
         }
-
-        sql_support::each_chunk_mapped(&records, |r| &r.0.id as &ToSql, |chunk, offset| -> Result<()> {
+        let mut sync_data = records.into_iter()
+            .map(|(payload, ts)| SyncLoginData::from_payload(payload, ts))
+            .collect::<Result<Vec<_>>>()?;
+
+        // We can't borrow `sync_data` for the guids while we fill it in, so
+        // collect what we fetch, and fill it in afterwards.
+        let mut mirrors: Vec<(usize, MirrorLogin)> = Vec::new();
+        let mut locals: Vec<(usize, LocalLogin)> = Vec::new();
+        sql_support::each_chunk_mapped(&sync_data, |d| &d.guid as &ToSql, |chunk, offset| -> Result<()> {
             // pairs the bound parameter for the guid with an integer index.
             let values_with_idx = sql_support::repeat_display(chunk.len(), ",", |i, f| write!(f, "({},?)", i + offset));
             let query = format!("
@@ -142,6 +345,7 @@ impl LoginDb {
                     NULL as local_modified,
                     NULL as is_deleted,
                     NULL as sync_status,
+                    NULL as sync_excluded,
                     1 as is_mirror,
                     to_fetch.guid_idx as guid_idx
                 FROM loginsM
@@ -157,6 +361,7 @@ impl LoginDb {
                     local_modified,
                     is_deleted,
                     sync_status,
+                    sync_excluded,
                     0 as is_mirror,
                     to_fetch.guid_idx as guid_idx
                 FROM loginsL
@@ -177,9 +382,9 @@ impl LoginDb {
                 let guid_idx = guid_idx_i as usize;
                 let is_mirror: bool = row.get("is_mirror");
                 if is_mirror {
-                    sync_data[guid_idx].set_mirror(MirrorLogin::from_row(row)?)?;
+                    mirrors.push((guid_idx, MirrorLogin::from_row(row)?));
                 } else {
-                    sync_data[guid_idx].set_local(LocalLogin::from_row(row)?)?;
+                    locals.push((guid_idx, LocalLogin::from_row(row)?));
                 }
                 Ok(())
             })?;
@@ -187,12 +392,21 @@ impl LoginDb {
             rows.collect::<Result<_>>()?;
             Ok(())
         })?;
+        for (guid_idx, mirror) in mirrors {
+            sync_data[guid_idx].set_mirror(mirror)?;
+        }
+        for (guid_idx, local) in locals {
+            sync_data[guid_idx].set_local(local)?;
+        }
         Ok(sync_data)
     }
 
     // It would be nice if this were a batch-ish api (e.g. takes a slice of records and finds dupes
     // for each one if they exist)... I can't think of how to write that query, though.
-    fn find_dupe(&self, l: &Login) -> Result<Option<Login>> {
+    //
+    // Logins excluded from sync are never considered dupes, since merging an incoming record into
+    // one would tie it to the server copy.
+    fn find_dupe(&self, l: &Login, username_match: UsernameMatch) -> Result<Option<Login>> {
         let form_submit_host_port = l.form_submit_url.as_ref().and_then(|s| util::url_host_port(&s));
         let args = &[
             (":hostname", &l.hostname as &ToSql),
@@ -203,10 +417,12 @@ impl LoginDb {
         let mut query = format!("
             SELECT {common}
             FROM loginsL
-            WHERE hostname IS :hostname
+            WHERE sync_excluded = 0
+              AND hostname IS :hostname
               AND httpRealm IS :http_realm
-              AND username IS :username",
+              AND {username_cond}",
             common = schema::COMMON_COLS,
+            username_cond = username_match.sql_condition(":username"),
         );
         if form_submit_host_port.is_some() {
             // Stolen from iOS
@@ -230,6 +446,80 @@ impl LoginDb {
                            true)
     }
 
+    /// Get all (non-deleted) logins for `hostname`, optionally restricted to
+    /// those with a matching username. Usernames are compared according to
+    /// `username_match`, which is ignored if `username` is None.
+    pub fn get_by_hostname(&self,
+                           hostname: &str,
+                           username: Option<&str>,
+                           username_match: UsernameMatch) -> Result<Vec<Login>> {
+        self.get_by_hostname_condition("hostname = :hostname",
+                                       (":hostname", &hostname as &ToSql),
+                                       username,
+                                       username_match)
+    }
+
+    /// Like `get_by_hostname`, but returns the logins for `base_domain` and
+    /// all of its subdomains, on any scheme or port. For example, a base
+    /// domain of "example.com" matches "https://www.example.com" and
+    /// "http://example.com:8080", but not "https://notexample.com".
+    pub fn get_by_base_domain(&self,
+                              base_domain: &str,
+                              username: Option<&str>,
+                              username_match: UsernameMatch) -> Result<Vec<Login>> {
+        // Hosts in parsed URLs are lowercase.
+        let base_domain = base_domain.to_ascii_lowercase();
+        let subdomain_suffix = format!(".{}", base_domain);
+        // The SQL condition only narrows things down: "notexample.com"
+        // contains "example.com", so we check the host of each match below.
+        let logins = self.get_by_hostname_condition("instr(lower(hostname), :base_domain) > 0",
+                                                    (":base_domain", &base_domain as &ToSql),
+                                                    username,
+                                                    username_match)?;
+        Ok(logins.into_iter().filter(|login| {
+            match util::url_host(&login.hostname) {
+                Some(host) => host == base_domain || host.ends_with(&subdomain_suffix),
+                None => false,
+            }
+        }).collect())
+    }
+
+    fn get_by_hostname_condition(&self,
+                                 hostname_cond: &str,
+                                 hostname_param: (&str, &ToSql),
+                                 username: Option<&str>,
+                                 username_match: UsernameMatch) -> Result<Vec<Login>> {
+        let username_cond = if username.is_some() {
+            format!("AND {}", username_match.sql_condition(":username"))
+        } else {
+            String::new()
+        };
+        let sql = format!("
+            SELECT {common_cols} FROM loginsL
+            WHERE is_deleted = 0
+              AND {hostname_cond}
+              {username_cond}
+
+            UNION ALL
+
+            SELECT {common_cols} FROM loginsM
+            WHERE is_overridden = 0
+              AND {hostname_cond}
+              {username_cond}",
+            common_cols = schema::COMMON_COLS,
+            hostname_cond = hostname_cond,
+            username_cond = username_cond,
+        );
+        let mut params = vec![hostname_param];
+        if let Some(ref username) = username {
+            params.push((":username", username as &ToSql));
+        }
+        let mut stmt = self.db.prepare_cached(&sql)?;
+        let rows: Vec<Login> = stmt.query_and_then_named(&params, Login::from_row)?
+                                   .collect::<Result<_>>()?;
+        Ok(rows)
+    }
+
     pub fn touch(&self, id: &str) -> Result<()> {
         self.ensure_local_overlay_exists(id)?;
         self.mark_mirror_overridden(id)?;
@@ -249,7 +539,66 @@ impl LoginDb {
         Ok(())
     }
 
+    /// Like `touch`, but also records that the login was used for `origin`
+    /// in `context`, which is the app it was filled into (for example, an
+    /// Android package name), or `None` for the browser itself. This is
+    /// what `get_for_usage_context` ranks by, and is never synced.
+    pub fn touch_in_context(&self, id: &str, origin: &str, context: Option<&str>) -> Result<()> {
+        if !self.exists(id)? {
+            throw!(ErrorKind::NoSuchRecord(id.to_owned()));
+        }
+        self.db.execute_batch("BEGIN")?;
+        let result = self.do_touch_in_context(id, origin, context);
+        if result.is_ok() {
+            self.db.execute_batch("COMMIT")?;
+        } else if let Err(rollback_err) = self.db.execute_batch("ROLLBACK") {
+            error!("Failed to roll back recording login usage: {}", rollback_err);
+        }
+        result
+    }
+
+    fn do_touch_in_context(&self, id: &str, origin: &str, context: Option<&str>) -> Result<()> {
+        self.touch(id)?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        self.execute_named_cached("
+            INSERT OR IGNORE INTO loginsUsage (guid, origin, context, timesUsed, timeLastUsed)
+            VALUES (:guid, :origin, :context, 0, :now_millis)",
+            &[(":guid", &id as &ToSql),
+              (":origin", &origin as &ToSql),
+              (":context", &context.unwrap_or("") as &ToSql),
+              (":now_millis", &now_ms as &ToSql)]
+        )?;
+        self.execute_named_cached("
+            UPDATE loginsUsage
+               SET timesUsed = timesUsed + 1,
+                   timeLastUsed = :now_millis
+             WHERE guid = :guid
+               AND origin = :origin
+               AND context = :context",
+            &[(":guid", &id as &ToSql),
+              (":origin", &origin as &ToSql),
+              (":context", &context.unwrap_or("") as &ToSql),
+              (":now_millis", &now_ms as &ToSql)]
+        )?;
+        Ok(())
+    }
+
+    /// Get the (non-deleted) logins to offer for `origin` in `context` (see
+    /// `touch_in_context`), best match first. These are the logins saved for
+    /// `origin`, along with any saved elsewhere that were used for it. Logins
+    /// used most often in `context` come first, then the ones used most often
+    /// for `origin` in any context, and then the most recently used.
+    pub fn get_for_usage_context(&self, origin: &str, context: Option<&str>) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_FOR_USAGE_CONTEXT_SQL)?;
+        let rows = stmt.query_and_then_named(&[
+            (":origin", &origin as &ToSql),
+            (":context", &context.unwrap_or("") as &ToSql),
+        ], Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
     pub fn add(&self, mut login: Login) -> Result<Login> {
+        login.fixup();
         login.check_valid()?;
 
         let now_ms = util::system_time_ms_i64(SystemTime::now());
@@ -258,21 +607,22 @@ impl LoginDb {
         // one. (Note that the FFI, does not require that the `id` field be
         // present in the JSON, and replaces it with an empty string if missing).
         if login.id.is_empty() {
-            // Our FFI handles panics so this is fine. In practice there's not
-            // much we can do here. Using a CSPRNG for this is probably
-            // unnecessary, so we likely could fall back to something less
-            // fallible eventually, but it's unlikely very much else will work
-            // if this fails, so it doesn't matter much.
-            login.id = sync::util::random_guid()
-                .expect("Failed to generate failed to generate random bytes for GUID");
-        }
-
-        // Fill in default metadata.
-        // TODO: allow this to be provided for testing?
-        login.time_created = now_ms;
-        login.time_password_changed = now_ms;
-        login.time_last_used = now_ms;
-        login.times_used = 1;
+            login.id = Guid::random().into_string();
+        }
+
+        // Fill in any metadata the caller didn't provide.
+        if login.time_created <= 0 {
+            login.time_created = now_ms;
+        }
+        if login.time_password_changed <= 0 {
+            login.time_password_changed = now_ms;
+        }
+        if login.time_last_used <= 0 {
+            login.time_last_used = now_ms;
+        }
+        if login.times_used <= 0 {
+            login.times_used = 1;
+        }
 
         let sql = format!("
             INSERT OR IGNORE INTO loginsL (
@@ -332,7 +682,8 @@ impl LoginDb {
         Ok(login)
     }
 
-    pub fn update(&self, login: Login) -> Result<()> {
+    pub fn update(&self, mut login: Login) -> Result<()> {
+        login.fixup();
         login.check_valid()?;
         // Note: These fail with DuplicateGuid if the record doesn't exist.
         self.ensure_local_overlay_exists(login.guid_str())?;
@@ -343,8 +694,9 @@ impl LoginDb {
         let sql = format!("
             UPDATE loginsL
             SET local_modified      = :now_millis,
-                timeLastUsed        = :now_millis,
                 -- Only update timePasswordChanged if, well, the password changed.
+                -- Editing a login doesn't count as using it, so we leave
+                -- timeLastUsed and timesUsed alone (see `touch`).
                 timePasswordChanged = (CASE
                     WHEN password = :password
                     THEN timePasswordChanged
@@ -354,7 +706,6 @@ impl LoginDb {
                 formSubmitURL       = :form_submit_url,
                 usernameField       = :username_field,
                 passwordField       = :password_field,
-                timesUsed           = timesUsed + 1,
                 username            = :username,
                 password            = :password,
                 hostname            = :hostname,
@@ -424,6 +775,9 @@ impl LoginDb {
         self.execute_named("UPDATE loginsM SET is_overridden = 1 WHERE guid = :guid",
                      &[(":guid", &id as &ToSql)])?;
 
+        self.execute_named("DELETE FROM loginsUsage WHERE guid = :guid",
+                           &[(":guid", &id as &ToSql)])?;
+
         // If we don't have a local record for this ID, but do have it in the mirror
         // insert a tombstone.
         self.execute_named(&format!("
@@ -439,6 +793,154 @@ impl LoginDb {
         Ok(exists)
     }
 
+    /// Deletes the records with the given ids, like `delete`, recording
+    /// tombstones for the ones that were synced. Either all of them are
+    /// deleted, or (if this fails) none of them are. Returns the number of
+    /// records that existed.
+    pub fn delete_many(&self, ids: &[&str]) -> Result<usize> {
+        self.db.execute_batch("BEGIN")?;
+        let mut deleted = 0;
+        for id in ids {
+            match self.delete(id) {
+                Ok(true) => deleted += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    if let Err(rollback_err) = self.db.execute_batch("ROLLBACK") {
+                        error!("Failed to roll back deleting logins: {}", rollback_err);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.db.execute_batch("COMMIT")?;
+        Ok(deleted)
+    }
+
+    /// Get the (non-deleted) logins that haven't been used since `time_ms`
+    /// (in milliseconds since the epoch), least recently used first, so that
+    /// the user can be offered to clean them up. Logins that were never
+    /// used count as last used when they were created.
+    pub fn get_unused_since(&self, time_ms: i64) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_UNUSED_SINCE_SQL)?;
+        let rows = stmt.query_and_then_named(&[(":time_ms", &time_ms as &ToSql)], Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Get the local versions of logins that were overwritten by incoming
+    /// changes during sync, most recently recovered first. We keep one
+    /// whenever a merge replaces a password or username that was changed
+    /// locally, so that the user can get it back. These are never synced,
+    /// and are kept until they're restored or deleted.
+    pub fn get_recovered(&self) -> Result<Vec<RecoveredLogin>> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT * FROM loginsRecovered ORDER BY recoveredAt DESC, id DESC")?;
+        let rows = stmt.query_and_then(&[], RecoveredLogin::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Restores the recovered login with the given id (see `get_recovered`),
+    /// and deletes the recovered copy. If the login it was recovered from
+    /// still exists, it's updated (like `update`), and so uploaded on the
+    /// next sync. Otherwise, it's added again, with a new id. Returns the id
+    /// of the restored login.
+    pub fn restore_recovered(&self, id: i64) -> Result<String> {
+        let recovered = self.try_query_row(
+            "SELECT * FROM loginsRecovered WHERE id = :id",
+            &[(":id", &id as &ToSql)],
+            RecoveredLogin::from_row,
+            false
+        )?.ok_or_else(|| ErrorKind::NoSuchRecord(id.to_string()))?;
+
+        self.db.execute_batch("BEGIN")?;
+        let result = self.do_restore_recovered(recovered);
+        if result.is_ok() {
+            self.db.execute_batch("COMMIT")?;
+        } else if let Err(rollback_err) = self.db.execute_batch("ROLLBACK") {
+            error!("Failed to roll back restoring a login: {}", rollback_err);
+        }
+        result
+    }
+
+    fn do_restore_recovered(&self, recovered: RecoveredLogin) -> Result<String> {
+        let mut login = recovered.login;
+        let guid = if self.exists(&login.id)? {
+            let guid = login.id.clone();
+            self.update(login)?;
+            guid
+        } else {
+            login.id = String::new();
+            self.add(login)?.id
+        };
+        self.delete_recovered(recovered.id)?;
+        Ok(guid)
+    }
+
+    /// Deletes the recovered login with the given id, returning true if it
+    /// existed.
+    pub fn delete_recovered(&self, id: i64) -> Result<bool> {
+        let deleted = self.execute_named_cached(
+            "DELETE FROM loginsRecovered WHERE id = :id",
+            &[(":id", &id as &ToSql)])?;
+        Ok(deleted > 0)
+    }
+
+    /// Deletes the recovered logins that were recovered before `time_ms` (in
+    /// milliseconds since the epoch), returning how many there were. Passing
+    /// `i64::max_value()` deletes all of them.
+    pub fn purge_recovered(&self, time_ms: i64) -> Result<usize> {
+        Ok(self.execute_named_cached(
+            "DELETE FROM loginsRecovered WHERE recoveredAt < :time_ms",
+            &[(":time_ms", &time_ms as &ToSql)])?)
+    }
+
+    /// Excludes the login with the given id from sync, or includes it again.
+    ///
+    /// While a login is excluded, we never upload it, even if it's changed
+    /// or deleted locally. Incoming changes to it (including deletions) are
+    /// stored in the mirror, but never applied to the local copy, which is
+    /// what `get_by_id` and friends keep returning. Each one is counted in
+    /// `IncomingTelemetry::excluded_conflicts`.
+    ///
+    /// Including it again marks the local copy as changed, so the next sync
+    /// uploads it, replacing whatever the server has. Changes made on other
+    /// devices while it was excluded are lost.
+    ///
+    /// Excluding a login that was never synced keeps it off the server
+    /// entirely. Note that other clients don't know about exclusions, so an
+    /// excluded login that's already on the server stays there.
+    pub fn set_sync_excluded(&self, id: &str, excluded: bool) -> Result<()> {
+        if !self.exists(id)? {
+            throw!(ErrorKind::NoSuchRecord(id.to_owned()));
+        }
+        if excluded {
+            self.ensure_local_overlay_exists(id)?;
+            self.mark_mirror_overridden(id)?;
+            self.execute_named_cached(
+                "UPDATE loginsL SET sync_excluded = 1 WHERE guid = :guid",
+                &[(":guid", &id as &ToSql)])?;
+        } else {
+            self.execute_named_cached(&format!("
+                UPDATE loginsL
+                SET sync_excluded = 0,
+                    sync_status = max(sync_status, {changed})
+                WHERE guid = :guid
+                  AND sync_excluded = 1",
+                changed = SyncStatus::Changed as u8),
+                &[(":guid", &id as &ToSql)])?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the login with the given id is excluded from sync
+    /// (see `set_sync_excluded`).
+    pub fn is_sync_excluded(&self, id: &str) -> Result<bool> {
+        Ok(self.db.query_row_named(
+            "SELECT EXISTS(SELECT 1 FROM loginsL WHERE guid = :guid AND sync_excluded = 1)",
+            &[(":guid", &id as &ToSql)],
+            |row| row.get(0)
+        )?)
+    }
+
     fn mark_mirror_overridden(&self, guid: &str) -> Result<()> {
         self.execute_named_cached("
             UPDATE loginsM SET
@@ -459,10 +961,10 @@ impl LoginDb {
             return Ok(())
         }
 
-        debug!("No overlay; cloning one for {:?}.", guid);
+        debug!("No overlay; cloning one for {}.", GuidRef::new(guid).redacted());
         let changed = self.clone_mirror_to_overlay(guid)?;
         if changed == 0 {
-            error!("Failed to create local overlay for GUID {:?}.", guid);
+            error!("Failed to create local overlay for GUID {}.", GuidRef::new(guid).redacted());
             throw!(ErrorKind::NoSuchRecord(guid.to_owned()));
         }
         Ok(())
@@ -477,14 +979,37 @@ impl LoginDb {
 
     pub fn reset(&self) -> Result<()> {
         info!("Executing reset on password store!");
-        self.execute_all(&[
-            &*CLONE_ENTIRE_MIRROR_SQL,
-            "DELETE FROM loginsM",
-            &format!("UPDATE loginsL SET sync_status = {}", SyncStatus::New as u8),
-        ])?;
-        self.set_last_sync(ServerTimestamp(0.0))?;
-        // TODO: Should we clear global_state?
-        Ok(())
+        self.in_sync_transaction(|db| {
+            db.execute_all(&[
+                &*CLONE_ENTIRE_MIRROR_SQL,
+                "DELETE FROM loginsM",
+                &format!("UPDATE loginsL SET sync_status = {}", SyncStatus::New as u8),
+            ])?;
+            db.set_last_sync(ServerTimestamp(0.0))?;
+            // We need to sync again after a reset, so don't let the rate limit
+            // get in the way.
+            db.delete_meta(schema::LAST_LOCAL_SYNC_META_KEY)?;
+            // TODO: Should we clear global_state?
+            Ok(())
+        })
+    }
+
+    /// Deletes all logins locally (including recovered ones), without
+    /// uploading tombstones, for when another client asks us to wipe the
+    /// engine. The next sync downloads everything from the server again.
+    pub fn wipe_local(&self) -> Result<()> {
+        info!("Executing wipe_local on password store!");
+        self.in_sync_transaction(|db| {
+            db.execute_all(&[
+                "DELETE FROM loginsL",
+                "DELETE FROM loginsM",
+                "DELETE FROM loginsRecovered",
+                "DELETE FROM loginsUsage",
+            ])?;
+            db.set_last_sync(ServerTimestamp(0.0))?;
+            db.delete_meta(schema::LAST_LOCAL_SYNC_META_KEY)?;
+            Ok(())
+        })
     }
 
     pub fn wipe(&self) -> Result<()> {
@@ -492,6 +1017,8 @@ impl LoginDb {
         let now_ms = util::system_time_ms_i64(SystemTime::now());
 
         self.execute(&format!("DELETE FROM loginsL WHERE sync_status = {new}", new = SyncStatus::New as u8), &[])?;
+        self.execute("DELETE FROM loginsRecovered", &[])?;
+        self.execute("DELETE FROM loginsUsage", &[])?;
         self.execute_named(
             &format!("
                 UPDATE loginsL
@@ -519,39 +1046,81 @@ impl LoginDb {
         Ok(())
     }
 
-    fn reconcile(&self, records: Vec<SyncLoginData>, server_now: ServerTimestamp) -> Result<UpdatePlan> {
+    fn reconcile(
+        &self,
+        records: Vec<SyncLoginData>,
+        server_now: ServerTimestamp,
+        telemetry: &mut IncomingTelemetry,
+        username_match: UsernameMatch,
+    ) -> Result<UpdatePlan> {
         let mut plan = UpdatePlan::default();
 
         for mut record in records {
             debug!("Processing remote change {}", record.guid());
+            let excluded = record.local.as_ref().map_or(false, |local| local.sync_excluded);
+            let local_modified = record.local.as_ref().map(|local| local.local_modified);
+            let upstream_time = record.inbound.1;
+            // The closure needs its own copy of the guid, since `record` is
+            // taken apart below.
+            let guid = record.guid.clone();
+            let trace = |winner, reason| {
+                trace_reconcile("passwords", &guid, winner, reason,
+                                local_modified, Some(upstream_time));
+            };
             let upstream = if let Some(inbound) = record.inbound.0.take() {
                 inbound
+            } else if excluded {
+                debug!("Processing inbound deletion of a record excluded from sync, keeping local");
+                trace(ReconcileWinner::Local, "excluded_deletion");
+                telemetry.excluded_conflicts += 1;
+                plan.plan_mirror_delete(record.guid.clone());
+                continue;
             } else {
                 debug!("Processing inbound deletion (always prefer)");
+                trace(ReconcileWinner::Remote, "remote_deletion");
                 plan.plan_delete(record.guid.clone());
                 continue;
             };
-            let upstream_time = record.inbound.1;
+            // Whichever way we merge, the mirror ends up with the server's
+            // copy, so it gets the server's unknown fields too.
+            plan.plan_mirror_unknown_fields(record.guid.clone(), record.inbound_unknown_fields.take());
+            if excluded {
+                // The local record stays as it is, and hides the mirror.
+                debug!("  Record is excluded from sync, only updating the mirror");
+                trace(ReconcileWinner::Local, "excluded");
+                telemetry.excluded_conflicts += 1;
+                if record.mirror.is_some() {
+                    plan.plan_mirror_update(upstream, upstream_time);
+                } else {
+                    plan.plan_mirror_insert(upstream, upstream_time, true);
+                }
+                continue;
+            }
             match (record.mirror.take(), record.local.take()) {
                 (Some(mirror), Some(local)) => {
                     debug!("  Conflict between remote and local, Resolving with 3WM");
+                    trace(ReconcileWinner::Merged, "three_way_merge");
                     plan.plan_three_way_merge(
                         local, mirror, upstream, upstream_time, server_now);
                 }
                 (Some(_mirror), None) => {
                     debug!("  Forwarding mirror to remote");
+                    trace(ReconcileWinner::Remote, "unchanged_locally");
                     plan.plan_mirror_update(upstream, upstream_time);
                 }
                 (None, Some(local)) => {
                     debug!("  Conflicting record without shared parent, using newer");
-                    plan.plan_two_way_merge(&local.login, (upstream, upstream_time));
+                    let kept_local = plan.plan_two_way_merge(&local.login, (upstream, upstream_time));
+                    trace(two_way_winner(kept_local), "two_way_merge");
                 }
                 (None, None) => {
-                    if let Some(dupe) = self.find_dupe(&upstream)? {
+                    if let Some(dupe) = self.find_dupe(&upstream, username_match)? {
                         debug!("  Incoming record {} was is a dupe of local record {}", upstream.id, dupe.id);
-                        plan.plan_two_way_merge(&dupe, (upstream, upstream_time));
+                        let kept_local = plan.plan_two_way_merge(&dupe, (upstream, upstream_time));
+                        trace(two_way_winner(kept_local), "dupe_two_way_merge");
                     } else {
                         debug!("  No dupe found, inserting into mirror");
+                        trace(ReconcileWinner::Remote, "new_remote");
                         plan.plan_mirror_insert(upstream, upstream_time, false);
                     }
                 }
@@ -561,7 +1130,11 @@ impl LoginDb {
     }
 
     fn execute_plan(&mut self, plan: UpdatePlan) -> Result<()> {
-        let mut tx = self.db.transaction()?;
+        let owner = &self.instance_id;
+        let mut tx = self.db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if self.holds_sync_lock {
+            update_sync_lock(&tx, |current| sync_lock::renew(current, owner, SystemTime::now()))?;
+        }
         plan.execute(&mut tx)?;
         tx.commit()?;
         Ok(())
@@ -570,8 +1143,12 @@ impl LoginDb {
     pub fn fetch_outgoing(&self, st: ServerTimestamp) -> Result<OutgoingChangeset> {
         let mut outgoing = OutgoingChangeset::new("passwords".into(), st);
         let mut stmt = self.db.prepare_cached(&format!("
-            SELECT * FROM loginsL
-            WHERE sync_status IS NOT {synced}",
+            SELECT loginsL.*,
+                   (SELECT unknownFields FROM loginsM
+                    WHERE loginsM.guid = loginsL.guid) AS mirrorUnknownFields
+            FROM loginsL
+            WHERE sync_status IS NOT {synced}
+              AND sync_excluded = 0",
             synced = SyncStatus::Synced as u8
         ))?;
         let rows = stmt.query_and_then(&[], |row| {
@@ -579,7 +1156,11 @@ impl LoginDb {
                 Payload::new_tombstone(row.get_checked::<_, String>("guid")?)
             } else {
                 let login = Login::from_row(row)?;
-                Payload::from_record(login)?
+                let mut payload = Payload::from_record(login)?;
+                if let Some(fields) = row.get_checked::<_, Option<String>>("mirrorUnknownFields")? {
+                    add_unknown_fields(&mut payload, &fields);
+                }
+                payload
             })
         })?;
         outgoing.changes = rows.collect::<Result<_>>()?;
@@ -589,29 +1170,33 @@ impl LoginDb {
 
     fn do_apply_incoming(
         &mut self,
-        inbound: IncomingChangeset
+        inbound: IncomingChangeset,
+        username_match: UsernameMatch,
     ) -> Result<OutgoingChangeset> {
-        let data = self.fetch_login_data(&inbound.changes)?;
-        let plan = self.reconcile(data, inbound.timestamp)?;
+        let mut telemetry = IncomingTelemetry::default();
+        let records = self.normalize_incoming(inbound.changes, &mut telemetry)?;
+        let data = dedupe_incoming(self.fetch_login_data(records)?, &mut telemetry);
+        telemetry.applied = data.len() as u32;
+        // The collection timestamp is when the newest record was written, not
+        // the time now, so use our estimate of the server's clock to age the
+        // incoming records.
+        let plan = self.reconcile(data, ServerTimestamp::now_estimate(), &mut telemetry, username_match)?;
         self.execute_plan(plan)?;
+        info!("Applied incoming records: {:?}", telemetry);
+        self.incoming_telemetry = Some(telemetry);
         Ok(self.fetch_outgoing(inbound.timestamp)?)
     }
 
     fn put_meta(&self, key: &str, value: &ToSql) -> Result<()> {
-        self.execute_named_cached(
-            "REPLACE INTO loginsSyncMeta (key, value) VALUES (:key, :value)",
-            &[(":key", &key as &ToSql), (":value", value)]
-        )?;
-        Ok(())
+        put_meta(&self.db, key, value)
+    }
+
+    fn delete_meta(&self, key: &str) -> Result<()> {
+        delete_meta(&self.db, key)
     }
 
     fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
-        Ok(self.try_query_row(
-            "SELECT value FROM loginsSyncMeta WHERE key = :key",
-            &[(":key", &key as &ToSql)],
-            |row| Ok::<_, Error>(row.get_checked(0)?),
-            true
-        )?)
+        get_meta(&self.db, key)
     }
 
     pub fn set_last_sync(&self, last_sync: ServerTimestamp) -> Result<()> {
@@ -621,7 +1206,7 @@ impl LoginDb {
     }
 
     pub fn set_global_state(&self, global_state: &str) -> Result<()> {
-        self.put_meta(schema::GLOBAL_STATE_META_KEY, &global_state)
+        self.in_sync_transaction(|db| db.put_meta(schema::GLOBAL_STATE_META_KEY, &global_state))
     }
 
     pub fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
@@ -632,8 +1217,193 @@ impl LoginDb {
     pub fn get_global_state(&self) -> Result<Option<String>> {
         self.get_meta::<String>(schema::GLOBAL_STATE_META_KEY)
     }
+
+    /// Records the (local) time of the last successful sync, for rate
+    /// limiting. Unlike the last sync timestamp, this isn't the server's time.
+    pub fn set_last_local_sync_time(&self, time: SystemTime) -> Result<()> {
+        self.put_meta(schema::LAST_LOCAL_SYNC_META_KEY, &util::system_time_ms_i64(time))
+    }
+
+    pub fn get_last_local_sync_time(&self) -> Result<Option<SystemTime>> {
+        Ok(self.get_meta::<i64>(schema::LAST_LOCAL_SYNC_META_KEY)?
+            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)))
+    }
+
+    /// Stores the minimum interval between syncs that aren't user-initiated,
+    /// or removes it if `None`.
+    pub fn set_sync_min_interval(&self, min_interval: Option<Duration>) -> Result<()> {
+        match min_interval {
+            Some(d) => {
+                let millis = d.as_secs() * 1000 + u64::from(d.subsec_millis());
+                self.put_meta(schema::SYNC_MIN_INTERVAL_META_KEY, &(millis as i64))
+            }
+            None => self.delete_meta(schema::SYNC_MIN_INTERVAL_META_KEY),
+        }
+    }
+
+    pub fn get_sync_min_interval(&self) -> Result<Option<Duration>> {
+        Ok(self.get_meta::<i64>(schema::SYNC_MIN_INTERVAL_META_KEY)?
+            .filter(|&millis| millis > 0)
+            .map(|millis| Duration::from_millis(millis as u64)))
+    }
+
+    /// Takes the sync lock, failing with `sync15_adapter::ErrorKind::AlreadySyncing`
+    /// if another instance (say, a background worker with its own connection)
+    /// is syncing this database. Until `end_sync`, syncing renews the lock
+    /// in the same transaction as each write, and fails with `SyncLockLost`
+    /// (rolling the write back) if the other instance took it over after we
+    /// stopped renewing it for too long.
+    ///
+    /// `PasswordEngine::sync` does this for us; callers syncing us with
+    /// `sync15_adapter::sync_multiple` should do it themselves.
+    pub fn begin_sync(&mut self) -> Result<()> {
+        self.in_immediate_transaction(|db| {
+            update_sync_lock(&db.db, |current| sync_lock::acquire(current, &db.instance_id, SystemTime::now()))
+        })?;
+        self.holds_sync_lock = true;
+        Ok(())
+    }
+
+    /// Renews the sync lock, if we took it with `begin_sync`. Writes made
+    /// while syncing renew it themselves; this is for checking that we
+    /// still hold it before doing something slow.
+    pub fn renew_sync_lock(&self) -> Result<()> {
+        self.in_sync_transaction(|_| Ok(()))
+    }
+
+    /// Releases the sync lock, unless another instance took it over.
+    pub fn end_sync(&mut self) -> Result<()> {
+        if !self.holds_sync_lock {
+            return Ok(());
+        }
+        self.holds_sync_lock = false;
+        self.in_immediate_transaction(|db| {
+            if get_sync_lock(&db.db)?.map_or(false, |lock| lock.owner == db.instance_id) {
+                db.delete_meta(schema::SYNC_LOCK_META_KEY)?;
+            }
+            Ok(())
+        })
+    }
+
+    // Runs `f` in a write transaction that also renews the sync lock, if we
+    // hold it, so that we never write after another instance took it over.
+    fn in_sync_transaction<F>(&self, f: F) -> Result<()>
+    where F: FnOnce(&Self) -> Result<()> {
+        self.in_immediate_transaction(|db| {
+            if db.holds_sync_lock {
+                update_sync_lock(&db.db, |current| sync_lock::renew(current, &db.instance_id, SystemTime::now()))?;
+            }
+            f(db)
+        })
+    }
+
+    // Takes the write lock up front, so that two connections checking the
+    // sync lock can't both decide that it's free.
+    fn in_immediate_transaction<F>(&self, f: F) -> Result<()>
+    where F: FnOnce(&Self) -> Result<()> {
+        self.db.execute_batch("BEGIN IMMEDIATE")?;
+        match f(self) {
+            Ok(()) => {
+                self.db.execute_batch("COMMIT")?;
+                Ok(())
+            }
+            Err(e) => {
+                if let Err(rollback_err) = self.db.execute_batch("ROLLBACK") {
+                    error!("Failed to roll back transaction: {}", rollback_err);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+fn put_meta(conn: &Connection, key: &str, value: &ToSql) -> Result<()> {
+    conn.execute_named_cached(
+        "REPLACE INTO loginsSyncMeta (key, value) VALUES (:key, :value)",
+        &[(":key", &key as &ToSql), (":value", value)]
+    )?;
+    Ok(())
+}
+
+fn delete_meta(conn: &Connection, key: &str) -> Result<()> {
+    conn.execute_named_cached(
+        "DELETE FROM loginsSyncMeta WHERE key = :key",
+        &[(":key", &key as &ToSql)]
+    )?;
+    Ok(())
+}
+
+fn get_meta<T: FromSql>(conn: &Connection, key: &str) -> Result<Option<T>> {
+    Ok(conn.try_query_row(
+        "SELECT value FROM loginsSyncMeta WHERE key = :key",
+        &[(":key", &key as &ToSql)],
+        |row| Ok::<_, Error>(row.get_checked(0)?),
+        true
+    )?)
+}
+
+fn get_sync_lock(conn: &Connection) -> Result<Option<SyncLock>> {
+    Ok(get_meta::<String>(conn, schema::SYNC_LOCK_META_KEY)?.and_then(|json| {
+        serde_json::from_str(&json).map_err(|e| {
+            warn!("Ignoring malformed sync lock: {}", e);
+        }).ok()
+    }))
+}
+
+// Must be called in a write transaction, which should also contain any
+// writes that depend on us holding the lock.
+fn update_sync_lock<F>(conn: &Connection, f: F) -> Result<()>
+where F: FnOnce(Option<&SyncLock>) -> sync::Result<SyncLock> {
+    let lock = f(get_sync_lock(conn)?.as_ref())?;
+    put_meta(conn, schema::SYNC_LOCK_META_KEY, &serde_json::to_string(&lock)?)
+}
+
+fn two_way_winner(kept_local: bool) -> ReconcileWinner {
+    if kept_local {
+        ReconcileWinner::Local
+    } else {
+        ReconcileWinner::Remote
+    }
+}
+
+/// Drops new records that have the same contents as another incoming record.
+/// Records we already have local or mirror data for are always kept, since
+/// otherwise that data would never be updated.
+fn dedupe_incoming(
+    records: Vec<SyncLoginData>,
+    telemetry: &mut IncomingTelemetry,
+) -> Vec<SyncLoginData> {
+    let is_known = |r: &SyncLoginData| r.local.is_some() || r.mirror.is_some();
+    let keep: Vec<bool> = {
+        let mut seen = HashSet::new();
+        for record in records.iter().filter(|r| is_known(*r)) {
+            if let Some(login) = &record.inbound.0 {
+                seen.insert(login.content_key());
+            }
+        }
+        records.iter().map(|record| {
+            match &record.inbound.0 {
+                Some(login) if !is_known(record) => {
+                    if seen.insert(login.content_key()) {
+                        true
+                    } else {
+                        debug!("Dropping incoming record {}, which duplicates another", record.guid());
+                        telemetry.deduped += 1;
+                        false
+                    }
+                }
+                _ => true,
+            }
+        }).collect()
+    };
+    records.into_iter()
+        .zip(keep)
+        .filter_map(|(record, keep)| if keep { Some(record) } else { None })
+        .collect()
 }
 
+// Syncing the database directly compares usernames exactly when looking for
+// dupes; use a `LoginStore` to compare them differently.
 impl Store for LoginDb {
     type Error = Error;
 
@@ -641,7 +1411,7 @@ impl Store for LoginDb {
         &mut self,
         inbound: IncomingChangeset
     ) -> Result<OutgoingChangeset> {
-        self.do_apply_incoming(inbound)
+        self.do_apply_incoming(inbound, UsernameMatch::EXACT)
     }
 
     fn sync_finished(
@@ -654,6 +1424,692 @@ impl Store for LoginDb {
             new_timestamp
         )
     }
+
+    fn sync_dependencies(&self) -> Vec<CollectionName> {
+        vec![CollectionName::CLIENTS]
+    }
+
+    fn handle_command(&mut self, command: StoreCommand) -> Result<()> {
+        match command {
+            StoreCommand::Wipe => self.wipe_local(),
+            StoreCommand::Reset => self.reset(),
+        }
+    }
+}
+
+/// A `LoginDb` to sync, which compares usernames according to
+/// `username_match` when looking for local dupes of incoming records. For
+/// example, with `ignore_case`, an incoming login for "Alice@Example.com" is
+/// merged into a local one for "alice@example.com" on the same site.
+pub(crate) struct LoginStore<'a> {
+    pub db: &'a mut LoginDb,
+    pub username_match: UsernameMatch,
+}
+
+impl<'a> Store for LoginStore<'a> {
+    type Error = Error;
+
+    fn apply_incoming(
+        &mut self,
+        inbound: IncomingChangeset
+    ) -> Result<OutgoingChangeset> {
+        self.db.do_apply_incoming(inbound, self.username_match)
+    }
+
+    fn sync_finished(
+        &mut self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> Result<()> {
+        self.db.sync_finished(new_timestamp, records_synced)
+    }
+
+    fn sync_dependencies(&self) -> Vec<CollectionName> {
+        self.db.sync_dependencies()
+    }
+
+    fn handle_command(&mut self, command: StoreCommand) -> Result<()> {
+        self.db.handle_command(command)
+    }
+}
+
+impl ValidatableStore for LoginDb {
+    type Error = Error;
+
+    // Logins that are changed locally, or excluded from sync, are expected to
+    // differ from the server. So are records with ids we had to remap, since
+    // the server copy keeps its original id.
+    fn local_records_for_validation(&self) -> Result<LocalRecords> {
+        let mut stmt = self.db.prepare(&*GET_ALL_FOR_VALIDATION_SQL)?;
+        let rows = stmt.query_and_then(&[], |row| -> Result<Payload> {
+            let login = Login::from_row(row)?;
+            let mut payload = Payload::from_record(login)?;
+            if let Some(fields) = row.get_checked::<_, Option<String>>("unknownFields")? {
+                add_unknown_fields(&mut payload, &fields);
+            }
+            remove_usage_fields(&mut payload);
+            Ok(payload)
+        })?;
+        let records = rows.collect::<Result<_>>()?;
+
+        let mut stmt = self.db.prepare(&format!("
+            SELECT guid FROM loginsL
+            WHERE sync_status <> {synced} OR sync_excluded = 1
+            UNION
+            SELECT remote_id FROM loginsIdMap",
+            synced = SyncStatus::Synced as u8))?;
+        let skip_ids = stmt.query_and_then(&[], |row| row.get_checked::<_, String>(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(LocalRecords { records, skip_ids })
+    }
+
+    // Fixes up server records the same way as when we apply them.
+    fn normalize_for_validation(&self, mut payload: Payload) -> Result<Payload> {
+        let unknown_fields = take_unknown_fields(&mut payload);
+        let mut login: Login = payload.into_record()?;
+        login.fixup();
+        let mut payload = Payload::from_record(login)?;
+        if let Some(fields) = unknown_fields {
+            add_unknown_fields(&mut payload, &fields);
+        }
+        remove_usage_fields(&mut payload);
+        Ok(payload)
+    }
+}
+
+// `touch` doesn't mark logins as changed, so the usage fields of a login
+// that was used since it was last uploaded differ from the server's. We leave
+// them out of validation, like desktop.
+fn remove_usage_fields(payload: &mut Payload) {
+    payload.data.remove("timeLastUsed");
+    payload.data.remove("timesUsed");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use tempfile::tempdir;
+
+    fn login(id: &str, username: &str) -> Login {
+        Login {
+            id: id.into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com/login".into()),
+            username: username.into(),
+            password: "p4ssw0rd".into(),
+            .. Login::default()
+        }
+    }
+
+    #[test]
+    fn test_username_match() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        db.add(login("aaaaaaaaaaaa", "Alice@Example.com ")).unwrap();
+        let ignore_case = UsernameMatch { ignore_case: true, trim_whitespace: false };
+        let ignore_case_and_whitespace = UsernameMatch { ignore_case: true, trim_whitespace: true };
+
+        let incoming = login("bbbbbbbbbbbb", "alice@example.com");
+        assert!(db.find_dupe(&incoming, UsernameMatch::EXACT).unwrap().is_none());
+        assert!(db.find_dupe(&incoming, ignore_case).unwrap().is_none());
+        let dupe = db.find_dupe(&incoming, ignore_case_and_whitespace).unwrap().expect("should find dupe");
+        assert_eq!(dupe.id, "aaaaaaaaaaaa");
+
+        let hostname = "https://www.example.com";
+        assert!(db.get_by_hostname(hostname, Some("alice@example.com"), UsernameMatch::EXACT)
+                  .unwrap().is_empty());
+        let found = db.get_by_hostname(hostname, Some("alice@example.com"), ignore_case_and_whitespace)
+                      .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(db.get_by_hostname(hostname, None, UsernameMatch::EXACT).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_sync_username_match() {
+        let changes = || vec![
+            (Payload::from_record(login("bbbbbbbbbbbb", "alice@example.com")).unwrap(), 100.0),
+        ];
+
+        // Syncing the database directly compares usernames exactly.
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        db.add(login("aaaaaaaaaaaa", "Alice@Example.com")).unwrap();
+        db.apply_incoming(incoming(changes())).unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 2);
+
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        db.add(login("aaaaaaaaaaaa", "Alice@Example.com")).unwrap();
+        LoginStore {
+            db: &mut db,
+            username_match: UsernameMatch { ignore_case: true, trim_whitespace: false },
+        }.apply_incoming(incoming(changes())).unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_get_by_base_domain() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        for &(id, hostname) in &[("aaaaaaaaaaaa", "https://www.example.com"),
+                                 ("bbbbbbbbbbbb", "http://example.com:8080"),
+                                 ("cccccccccccc", "https://notexample.com"),
+                                 ("dddddddddddd", "https://example.com.evil.org")] {
+            db.add(Login {
+                hostname: hostname.into(),
+                form_submit_url: Some(format!("{}/login", hostname)),
+                .. login(id, "alice")
+            }).unwrap();
+        }
+
+        let mut ids: Vec<String> = db.get_by_base_domain("Example.com", None, UsernameMatch::EXACT).unwrap()
+                                     .into_iter().map(|login| login.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+
+        assert_eq!(db.get_by_base_domain("www.example.com", Some("alice"), UsernameMatch::EXACT)
+                     .unwrap().len(), 1);
+        assert!(db.get_by_base_domain("example.com", Some("ALICE"), UsernameMatch::EXACT)
+                  .unwrap().is_empty());
+        let ignore_case = UsernameMatch { ignore_case: true, trim_whitespace: false };
+        assert_eq!(db.get_by_base_domain("example.com", Some("ALICE"), ignore_case).unwrap().len(), 2);
+        assert!(db.get_by_base_domain("example.com", Some("bob"), ignore_case).unwrap().is_empty());
+    }
+
+    fn incoming(changes: Vec<(Payload, f64)>) -> IncomingChangeset {
+        let mut changeset = IncomingChangeset::new("passwords".into(), ServerTimestamp(1000.0));
+        changeset.changes = changes.into_iter().map(|(p, ts)| (p, ServerTimestamp(ts))).collect();
+        changeset
+    }
+
+    #[test]
+    fn test_incoming_bad_ids() {
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        let bad_id = "bad,id";
+        let changes = vec![
+            (Payload::from_record(login(bad_id, "bad")).unwrap(), 100.0),
+            // The newer of these should win.
+            (Payload::from_record(login("dupedupedupe", "old")).unwrap(), 200.0),
+            (Payload::from_record(login("dupedupedupe", "new")).unwrap(), 300.0),
+            // Only one of these should be kept.
+            (Payload::from_record(login("sameaaaaaaaa", "same")).unwrap(), 100.0),
+            (Payload::from_record(login("samebbbbbbbb", "same")).unwrap(), 100.0),
+            // A tombstone for an invalid id we've never seen.
+            (Payload::new_tombstone("unknown,id".to_string()), 100.0),
+        ];
+        db.apply_incoming(incoming(changes)).unwrap();
+
+        assert_eq!(db.incoming_telemetry(), Some(&IncomingTelemetry {
+            applied: 3,
+            remapped: 1,
+            invalid_ignored: 1,
+            duplicate_ids: 1,
+            deduped: 1,
+            excluded_conflicts: 0,
+        }));
+        assert_eq!(db.get_all().unwrap().len(), 3);
+
+        let local_guid = db.get_mapped_guid(bad_id).unwrap().expect("should map the bad id");
+        assert!(Guid::new(&local_guid).is_valid_for_sync_server());
+        assert_eq!(db.get_by_id(&local_guid).unwrap().unwrap().username, "bad");
+        assert_eq!(db.get_by_id("dupedupedupe").unwrap().unwrap().username, "new");
+        assert!(db.get_by_id("sameaaaaaaaa").unwrap().is_some());
+        assert!(db.get_by_id("samebbbbbbbb").unwrap().is_none());
+
+        // Later changes to the record with the bad id should apply to the same
+        // local login.
+        let changes = vec![(Payload::from_record(login(bad_id, "changed")).unwrap(), 400.0)];
+        db.apply_incoming(incoming(changes)).unwrap();
+        assert_eq!(db.get_mapped_guid(bad_id).unwrap(), Some(local_guid.clone()));
+        assert_eq!(db.get_by_id(&local_guid).unwrap().unwrap().username, "changed");
+
+        db.apply_incoming(incoming(vec![(Payload::new_tombstone(bad_id.to_string()), 500.0)])).unwrap();
+        assert!(db.get_by_id(&local_guid).unwrap().is_none());
+        assert_eq!(db.incoming_telemetry().unwrap().remapped, 1);
+    }
+
+    // Not a real test: run with `cargo test -p logins-sql --release --
+    // --ignored --nocapture test_apply_incoming_throughput` to measure how
+    // quickly we apply a large batch of new records.
+    #[test]
+    #[ignore]
+    fn test_apply_incoming_throughput() {
+        const COUNT: usize = 10_000;
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        let changes = (0..COUNT).map(|i| {
+            let mut login = login(&format!("{:012}", i), &format!("user{}", i));
+            login.hostname = format!("https://site{}.example.com", i);
+            (Payload::from_record(login).unwrap(), 100.0)
+        }).collect();
+        let start = Instant::now();
+        db.apply_incoming(incoming(changes)).unwrap();
+        let elapsed = start.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        println!("Applied {} records in {:.3}s ({:.0} records/s)", COUNT, secs, COUNT as f64 / secs);
+        assert_eq!(db.get_all().unwrap().len(), COUNT);
+    }
+
+    #[test]
+    fn test_open_in_profile_migrates() {
+        let dir = tempdir().unwrap();
+        let paths = LoginStorePaths::new(dir.path());
+        paths.prepare().unwrap();
+        {
+            let db = LoginDb::open_in_profile(&paths, None).unwrap();
+            db.add(login("aaaaaaaaaaaa", "alice")).unwrap();
+            // Turn this into a v6 database.
+            db.execute_batch("
+                ALTER TABLE loginsM RENAME TO loginsM_v7;
+                CREATE TABLE loginsM AS
+                SELECT id, hostname, httpRealm, formSubmitURL, usernameField,
+                       passwordField, timesUsed, timeCreated, timeLastUsed,
+                       timePasswordChanged, username, password, guid,
+                       server_modified, is_overridden
+                FROM loginsM_v7;
+                DROP TABLE loginsM_v7;
+                PRAGMA user_version = 6;
+            ").unwrap();
+        }
+        let db = LoginDb::open_in_profile(&paths, None).unwrap();
+        assert_eq!(db.query_one::<i64>("PRAGMA user_version").unwrap(), schema::VERSION);
+        assert_eq!(db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap().username, "alice");
+        assert!(paths.backup(&format!("pre-v{}", schema::VERSION)).exists());
+        assert!(!paths.migration_marker().exists());
+    }
+
+    #[test]
+    fn test_open_in_profile_restores_failed_migration() {
+        let dir = tempdir().unwrap();
+        let paths = LoginStorePaths::new(dir.path());
+        paths.prepare().unwrap();
+        {
+            let db = LoginDb::open_in_profile(&paths, None).unwrap();
+            db.add(login("aaaaaaaaaaaa", "alice")).unwrap();
+            // The `unknownFields` column already exists, so migrating from v6
+            // fails when it tries to add it.
+            db.execute_batch("PRAGMA user_version = 6").unwrap();
+        }
+        assert!(LoginDb::open_in_profile(&paths, None).is_err());
+        assert!(!paths.migration_marker().exists());
+
+        let conn = Connection::open(paths.database()).unwrap();
+        assert_eq!(conn.query_one::<i64>("PRAGMA user_version").unwrap(), 6);
+        assert_eq!(conn.query_one::<i64>("SELECT count(*) FROM loginsL").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_handle_commands() {
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        db.apply_incoming(incoming(vec![
+            (Payload::from_record(login("aaaaaaaaaaaa", "alice")).unwrap(), 100.0),
+        ])).unwrap();
+        db.sync_finished(ServerTimestamp(100.0), &[]).unwrap();
+        db.add(login("bbbbbbbbbbbb", "bob")).unwrap();
+
+        db.handle_command(StoreCommand::Reset).unwrap();
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(0.0)));
+        assert_eq!(db.get_all().unwrap().len(), 2);
+
+        db.handle_command(StoreCommand::Wipe).unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 0);
+        // Nothing to upload, since the wipe is local only.
+        assert_eq!(db.fetch_outgoing(ServerTimestamp(0.0)).unwrap().changes.len(), 0);
+    }
+
+    #[test]
+    fn test_validation_records() {
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        let mut server = Payload::from_record(Login {
+            time_created: 1000,
+            time_password_changed: 1000,
+            .. login("aaaaaaaaaaaa", "alice")
+        }).unwrap();
+        server.data.insert("newField".into(), "x".into());
+        db.apply_incoming(incoming(vec![
+            (server.clone(), 100.0),
+            (Payload::from_record(login("cccccccccccc", "carol")).unwrap(), 100.0),
+        ])).unwrap();
+        db.sync_finished(ServerTimestamp(100.0), &[]).unwrap();
+        db.add(login("bbbbbbbbbbbb", "bob")).unwrap();
+        db.delete("cccccccccccc").unwrap();
+        // Using a login doesn't mark it as changed, so its usage fields are
+        // left out, rather than showing up as differences.
+        db.touch("aaaaaaaaaaaa").unwrap();
+
+        let local = db.local_records_for_validation().unwrap();
+        assert_eq!(local.records.iter().map(|p| p.id.as_str()).collect::<Vec<_>>(),
+                   vec!["aaaaaaaaaaaa"]);
+        let mut skip_ids = local.skip_ids.into_iter().collect::<Vec<_>>();
+        skip_ids.sort();
+        assert_eq!(skip_ids, vec!["bbbbbbbbbbbb", "cccccccccccc"]);
+
+        // The server record's form submit URL is fixed up on the way in, so
+        // it should match ours once it's normalized, unknown fields and all.
+        assert_eq!(local.records[0].data["formSubmitURL"], "https://www.example.com");
+        assert!(!local.records[0].data.contains_key("timesUsed"));
+        assert_eq!(db.normalize_for_validation(server).unwrap(), local.records[0]);
+    }
+
+    #[test]
+    fn test_sync_excluded() {
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        db.apply_incoming(incoming(vec![
+            (Payload::from_record(login("aaaaaaaaaaaa", "alice")).unwrap(), 100.0),
+        ])).unwrap();
+        db.sync_finished(ServerTimestamp(100.0), &[]).unwrap();
+        db.add(login("bbbbbbbbbbbb", "bob")).unwrap();
+
+        db.set_sync_excluded("aaaaaaaaaaaa", true).unwrap();
+        db.set_sync_excluded("bbbbbbbbbbbb", true).unwrap();
+        assert!(db.is_sync_excluded("aaaaaaaaaaaa").unwrap());
+        assert!(db.set_sync_excluded("cccccccccccc", true).is_err());
+        db.touch("aaaaaaaaaaaa").unwrap();
+        assert_eq!(db.fetch_outgoing(ServerTimestamp(100.0)).unwrap().changes.len(), 0);
+
+        // Incoming changes only go to the mirror.
+        db.apply_incoming(incoming(vec![
+            (Payload::from_record(login("aaaaaaaaaaaa", "alice2")).unwrap(), 200.0),
+        ])).unwrap();
+        assert_eq!(db.incoming_telemetry().unwrap().excluded_conflicts, 1);
+        assert_eq!(db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap().username, "alice");
+
+        // As do deletions.
+        db.apply_incoming(incoming(vec![
+            (Payload::new_tombstone("aaaaaaaaaaaa".to_string()), 300.0),
+        ])).unwrap();
+        assert_eq!(db.incoming_telemetry().unwrap().excluded_conflicts, 1);
+        assert!(db.get_by_id("aaaaaaaaaaaa").unwrap().is_some());
+        assert_eq!(db.get_all().unwrap().len(), 2);
+
+        // Including them again uploads the local copies.
+        db.set_sync_excluded("aaaaaaaaaaaa", false).unwrap();
+        db.set_sync_excluded("bbbbbbbbbbbb", false).unwrap();
+        assert!(!db.is_sync_excluded("aaaaaaaaaaaa").unwrap());
+        let mut ids: Vec<String> = db.fetch_outgoing(ServerTimestamp(300.0)).unwrap()
+            .changes.into_iter().map(|p| p.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["aaaaaaaaaaaa".to_string(), "bbbbbbbbbbbb".to_string()]);
+    }
+
+    #[test]
+    fn test_recovered() {
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        let with_password = |id: &str, password: &str, time_password_changed: i64| Login {
+            password: password.into(),
+            time_password_changed,
+            .. login(id, "alice")
+        };
+        db.add(with_password("aaaaaaaaaaaa", "local", 1000)).unwrap();
+        db.add(with_password("bbbbbbbbbbbb", "local", 1000)).unwrap();
+        db.add(with_password("cccccccccccc", "local", 3000)).unwrap();
+        // The incoming changes to the first two are newer, so they overwrite
+        // the local passwords. The last one wins, so nothing's lost.
+        db.apply_incoming(incoming(vec![
+            (Payload::from_record(with_password("aaaaaaaaaaaa", "remote", 2000)).unwrap(), 100.0),
+            (Payload::from_record(with_password("bbbbbbbbbbbb", "remote", 2000)).unwrap(), 100.0),
+            (Payload::from_record(with_password("cccccccccccc", "remote", 2000)).unwrap(), 100.0),
+        ])).unwrap();
+        db.sync_finished(ServerTimestamp(100.0), &[]).unwrap();
+        assert_eq!(db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap().password, "remote");
+        assert_eq!(db.get_by_id("cccccccccccc").unwrap().unwrap().password, "local");
+
+        let recovered = db.get_recovered().unwrap();
+        let mut guids: Vec<&str> = recovered.iter().map(|r| r.login.guid_str()).collect();
+        guids.sort();
+        assert_eq!(guids, vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+        assert!(recovered.iter().all(|r| r.login.password == "local"));
+
+        // Restoring updates the login, and uploads it.
+        let a = recovered.iter().find(|r| r.login.id == "aaaaaaaaaaaa").unwrap();
+        assert_eq!(db.restore_recovered(a.id).unwrap(), "aaaaaaaaaaaa");
+        assert_eq!(db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap().password, "local");
+        let outgoing = db.fetch_outgoing(ServerTimestamp(100.0)).unwrap().changes;
+        assert!(outgoing.iter().any(|p| p.id == "aaaaaaaaaaaa"));
+        assert!(db.restore_recovered(a.id).is_err());
+
+        // If the login's gone, it's added back with a new id.
+        db.delete("bbbbbbbbbbbb").unwrap();
+        let b = recovered.iter().find(|r| r.login.id == "bbbbbbbbbbbb").unwrap();
+        let restored = db.restore_recovered(b.id).unwrap();
+        assert_ne!(restored, "bbbbbbbbbbbb");
+        assert_eq!(db.get_by_id(&restored).unwrap().unwrap().password, "local");
+        assert!(db.get_recovered().unwrap().is_empty());
+        assert!(!db.delete_recovered(b.id).unwrap());
+
+        // Purging only deletes the ones recovered before the given time.
+        db.apply_incoming(incoming(vec![
+            (Payload::from_record(with_password(&restored, "remote", i64::max_value())).unwrap(), 200.0),
+        ])).unwrap();
+        let recovered = db.get_recovered().unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(db.purge_recovered(recovered[0].recovered_at).unwrap(), 0);
+        assert_eq!(db.purge_recovered(i64::max_value()).unwrap(), 1);
+        assert!(db.get_recovered().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unused_since() {
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        let used = |id: &str, time_created: i64, time_last_used: i64| Login {
+            time_created,
+            time_last_used,
+            .. login(id, id)
+        };
+        db.apply_incoming(incoming(vec![
+            (Payload::from_record(used("aaaaaaaaaaaa", 100, 500)).unwrap(), 100.0),
+        ])).unwrap();
+        db.sync_finished(ServerTimestamp(100.0), &[]).unwrap();
+        db.add(used("bbbbbbbbbbbb", 100, 300)).unwrap();
+        db.add(used("cccccccccccc", 100, 2000)).unwrap();
+        // Never used, so this counts as used when it was created.
+        db.add(used("dddddddddddd", 400, 0)).unwrap();
+        db.execute("UPDATE loginsL SET timeLastUsed = 0 WHERE guid = 'dddddddddddd'", &[]).unwrap();
+
+        let ids = |logins: Vec<Login>| logins.into_iter().map(|l| l.id).collect::<Vec<_>>();
+        assert_eq!(ids(db.get_unused_since(1000).unwrap()),
+                   vec!["bbbbbbbbbbbb", "dddddddddddd", "aaaaaaaaaaaa"]);
+        assert_eq!(ids(db.get_unused_since(450).unwrap()), vec!["bbbbbbbbbbbb", "dddddddddddd"]);
+        assert!(db.get_unused_since(100).unwrap().is_empty());
+
+        assert_eq!(db.delete_many(&["aaaaaaaaaaaa", "bbbbbbbbbbbb", "eeeeeeeeeeee"]).unwrap(), 2);
+        assert_eq!(ids(db.get_unused_since(1000).unwrap()), vec!["dddddddddddd"]);
+        // The synced login gets a tombstone, and the other is just gone.
+        let outgoing = db.fetch_outgoing(ServerTimestamp(100.0)).unwrap().changes;
+        let tombstones = outgoing.iter()
+            .filter(|p| p.is_tombstone())
+            .map(|p| p.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(tombstones, vec!["aaaaaaaaaaaa"]);
+    }
+
+    #[test]
+    fn test_usage_context() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let app = Some("com.example.app");
+        db.add(login("aaaaaaaaaaaa", "web")).unwrap();
+        db.add(Login {
+            hostname: "https://other.example.com".into(),
+            form_submit_url: Some("https://other.example.com/login".into()),
+            .. login("bbbbbbbbbbbb", "app")
+        }).unwrap();
+        db.add(login("cccccccccccc", "unused")).unwrap();
+        db.add(Login {
+            hostname: "https://unrelated.example.com".into(),
+            .. login("dddddddddddd", "unrelated")
+        }).unwrap();
+
+        db.touch_in_context("aaaaaaaaaaaa", "https://www.example.com", None).unwrap();
+        db.touch_in_context("aaaaaaaaaaaa", "https://www.example.com", None).unwrap();
+        db.touch_in_context("bbbbbbbbbbbb", "https://www.example.com", app).unwrap();
+        assert!(db.touch_in_context("eeeeeeeeeeee", "https://www.example.com", app).is_err());
+        // Overall usage is tracked as for `touch`.
+        assert_eq!(db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap().times_used, 3);
+
+        let ids = |logins: Vec<Login>| logins.into_iter().map(|l| l.id).collect::<Vec<_>>();
+        assert_eq!(ids(db.get_for_usage_context("https://www.example.com", app).unwrap()),
+                   vec!["bbbbbbbbbbbb", "aaaaaaaaaaaa", "cccccccccccc"]);
+        assert_eq!(ids(db.get_for_usage_context("https://www.example.com", None).unwrap()),
+                   vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"]);
+        assert_eq!(ids(db.get_for_usage_context("https://other.example.com", app).unwrap()),
+                   vec!["bbbbbbbbbbbb"]);
+
+        db.delete("bbbbbbbbbbbb").unwrap();
+        assert_eq!(ids(db.get_for_usage_context("https://www.example.com", app).unwrap()),
+                   vec!["aaaaaaaaaaaa", "cccccccccccc"]);
+        assert_eq!(db.query_one::<i64>("SELECT count(*) FROM loginsUsage").unwrap(), 1);
+
+        db.wipe_local().unwrap();
+        assert_eq!(db.query_one::<i64>("SELECT count(*) FROM loginsUsage").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_usage_context_remote_deletion() {
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        db.apply_incoming(incoming(vec![
+            (Payload::from_record(login("aaaaaaaaaaaa", "alice")).unwrap(), 100.0),
+            (Payload::from_record(login("bbbbbbbbbbbb", "bob")).unwrap(), 100.0),
+        ])).unwrap();
+        db.sync_finished(ServerTimestamp(100.0), &[]).unwrap();
+        db.touch_in_context("aaaaaaaaaaaa", "https://www.example.com", None).unwrap();
+        db.touch_in_context("bbbbbbbbbbbb", "https://www.example.com", None).unwrap();
+
+        // A newer incoming change to a login we used keeps its usage.
+        db.apply_incoming(incoming(vec![
+            (Payload::from_record(login("bbbbbbbbbbbb", "bob2")).unwrap(), 200.0),
+        ])).unwrap();
+        assert_eq!(db.query_one::<i64>("SELECT count(*) FROM loginsUsage").unwrap(), 2);
+
+        db.apply_incoming(incoming(vec![
+            (Payload::new_tombstone("aaaaaaaaaaaa".to_string()), 300.0),
+        ])).unwrap();
+        assert!(db.get_by_id("aaaaaaaaaaaa").unwrap().is_none());
+        assert_eq!(db.query_one::<String>("SELECT guid FROM loginsUsage").unwrap(), "bbbbbbbbbbbb");
+    }
+
+    // Checks the timestamp semantics described on `Login` for local changes.
+    // Merging is covered in `update_plan`.
+    #[test]
+    fn test_local_timestamps() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let before = util::system_time_ms_i64(SystemTime::now());
+
+        // Adding fills in what the caller didn't provide...
+        db.add(login("aaaaaaaaaaaa", "alice")).unwrap();
+        let added = db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap();
+        assert_ge!(added.time_created, before);
+        assert_eq!(added.time_password_changed, added.time_created);
+        assert_eq!(added.time_last_used, added.time_created);
+        assert_eq!(added.times_used, 1);
+
+        // ...and keeps what it did, for imports.
+        db.add(Login {
+            time_created: 100,
+            time_password_changed: 200,
+            time_last_used: 300,
+            times_used: 7,
+            .. login("bbbbbbbbbbbb", "bob")
+        }).unwrap();
+        let imported = db.get_by_id("bbbbbbbbbbbb").unwrap().unwrap();
+        assert_eq!((imported.time_created, imported.time_password_changed,
+                    imported.time_last_used, imported.times_used), (100, 200, 300, 7));
+
+        // Editing anything but the password doesn't change any of them, even
+        // if the caller passes different ones.
+        db.update(Login {
+            username: "bobby".into(),
+            time_created: 1,
+            time_password_changed: 1,
+            time_last_used: 1,
+            times_used: 1,
+            .. imported.clone()
+        }).unwrap();
+        let edited = db.get_by_id("bbbbbbbbbbbb").unwrap().unwrap();
+        assert_eq!(edited.username, "bobby");
+        assert_eq!((edited.time_created, edited.time_password_changed,
+                    edited.time_last_used, edited.times_used), (100, 200, 300, 7));
+
+        // Changing the password only changes `time_password_changed`.
+        db.update(Login { password: "n3wp4ssw0rd".into(), .. edited }).unwrap();
+        let changed = db.get_by_id("bbbbbbbbbbbb").unwrap().unwrap();
+        assert_ge!(changed.time_password_changed, before);
+        assert_eq!((changed.time_created, changed.time_last_used, changed.times_used), (100, 300, 7));
+
+        // Using it only changes the use count and time.
+        db.touch("bbbbbbbbbbbb").unwrap();
+        let used = db.get_by_id("bbbbbbbbbbbb").unwrap().unwrap();
+        assert_ge!(used.time_last_used, before);
+        assert_eq!(used.times_used, 8);
+        assert_eq!((used.time_created, used.time_password_changed),
+                   (100, changed.time_password_changed));
+    }
+
+    #[test]
+    fn test_unknown_fields_round_trip() {
+        let mut db = LoginDb::open_in_memory(None).unwrap();
+        let mut payload = Payload::from_record(login("aaaaaaaaaaaa", "alice")).unwrap();
+        payload.data.insert("futureField".into(), "keep me".into());
+        db.apply_incoming(incoming(vec![(payload, 100.0)])).unwrap();
+        // They aren't exposed to consumers.
+        assert_eq!(db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap().username, "alice");
+
+        let mut changed = db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap();
+        changed.password = "n3wp4ssw0rd".into();
+        db.update(changed).unwrap();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(100.0)).unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].data["futureField"], "keep me");
+        assert_eq!(outgoing.changes[0].data["password"], "n3wp4ssw0rd");
+
+        // After uploading, the mirror is replaced with our local copy, which
+        // shouldn't lose them either.
+        db.sync_finished(ServerTimestamp(200.0), &["aaaaaaaaaaaa".to_string()]).unwrap();
+        let mut changed = db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap();
+        changed.username = "bob".into();
+        db.update(changed).unwrap();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(200.0)).unwrap();
+        assert_eq!(outgoing.changes[0].data["futureField"], "keep me");
+        assert_eq!(outgoing.changes[0].data["username"], "bob");
+    }
+
+    // Returns the owner for `AlreadySyncing`, or None for `SyncLockLost`.
+    fn sync_lock_error(result: Result<()>) -> Option<String> {
+        match result.unwrap_err().kind() {
+            ErrorKind::SyncAdapterError(e) => match e.kind() {
+                sync::ErrorKind::AlreadySyncing(owner) => Some(owner.clone()),
+                sync::ErrorKind::SyncLockLost => None,
+                kind => panic!("Unexpected sync error {:?}", kind),
+            },
+            kind => panic!("Unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_sync_lock() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("logins.sqlite");
+        let mut app = LoginDb::open(&path, None).unwrap();
+        let mut worker = LoginDb::open(&path, None).unwrap();
+
+        app.begin_sync().unwrap();
+        assert_eq!(sync_lock_error(worker.begin_sync()), Some(app.instance_id.clone()));
+        app.renew_sync_lock().unwrap();
+        app.end_sync().unwrap();
+
+        // The worker stops renewing its lock for too long, and the app takes
+        // over. The worker notices before it writes anything else.
+        worker.begin_sync().unwrap();
+        let stale = SyncLock { owner: worker.instance_id.clone(), heartbeat: 0 };
+        worker.put_meta(schema::SYNC_LOCK_META_KEY, &serde_json::to_string(&stale).unwrap()).unwrap();
+        app.begin_sync().unwrap();
+        let changes = vec![(Payload::from_record(login("aaaaaaaaaaaa", "alice")).unwrap(), 100.0)];
+        assert_eq!(sync_lock_error(worker.apply_incoming(incoming(changes)).map(|_| ())), None);
+        assert!(worker.get_by_id("aaaaaaaaaaaa").unwrap().is_none());
+        assert_eq!(sync_lock_error(worker.sync_finished(ServerTimestamp(100.0), &[])), None);
+        assert_eq!(worker.get_last_sync().unwrap(), None);
+
+        // Finishing doesn't release the app's lock.
+        worker.end_sync().unwrap();
+        assert!(worker.begin_sync().is_err());
+        app.end_sync().unwrap();
+        worker.begin_sync().unwrap();
+    }
 }
 
 lazy_static! {
@@ -666,6 +2122,63 @@ lazy_static! {
         common_cols = schema::COMMON_COLS,
     );
 
+    // Like `GET_ALL_SQL`, but only logins that are synced and unchanged,
+    // along with the fields from the server we didn't understand.
+    static ref GET_ALL_FOR_VALIDATION_SQL: String = format!("
+        SELECT {common_cols},
+               (SELECT unknownFields FROM loginsM
+                WHERE loginsM.guid = loginsL.guid) AS unknownFields
+        FROM loginsL
+        WHERE is_deleted = 0
+          AND sync_status = {synced}
+          AND sync_excluded = 0
+        UNION ALL
+        SELECT {common_cols}, unknownFields FROM loginsM WHERE is_overridden = 0
+    ",
+        common_cols = schema::COMMON_COLS,
+        synced = SyncStatus::Synced as u8,
+    );
+
+    // `timeLastUsed` is 0 (or NULL, for logins migrated from older schemas)
+    // if the login was never used.
+    static ref GET_UNUSED_SINCE_SQL: String = format!("
+        SELECT * FROM (
+            SELECT {common_cols} FROM loginsL WHERE is_deleted = 0
+            UNION ALL
+            SELECT {common_cols} FROM loginsM WHERE is_overridden = 0
+        )
+        WHERE max(ifnull(timeLastUsed, 0), timeCreated) < :time_ms
+        ORDER BY max(ifnull(timeLastUsed, 0), timeCreated) ASC, guid ASC
+    ",
+        common_cols = schema::COMMON_COLS,
+    );
+
+    // Logins used for the origin in some context are candidates even if they
+    // were saved for a different hostname, since that's the case this is for.
+    static ref GET_FOR_USAGE_CONTEXT_SQL: String = format!("
+        SELECT l.* FROM (
+            SELECT {common_cols} FROM loginsL WHERE is_deleted = 0
+            UNION ALL
+            SELECT {common_cols} FROM loginsM WHERE is_overridden = 0
+        ) l
+        LEFT JOIN loginsUsage c
+               ON c.guid = l.guid AND c.origin = :origin AND c.context = :context
+        LEFT JOIN (
+            SELECT guid, sum(timesUsed) AS timesUsed
+            FROM loginsUsage
+            WHERE origin = :origin
+            GROUP BY guid
+        ) o ON o.guid = l.guid
+        WHERE l.hostname = :origin
+           OR o.guid IS NOT NULL
+        ORDER BY ifnull(c.timesUsed, 0) DESC,
+                 ifnull(o.timesUsed, 0) DESC,
+                 ifnull(l.timeLastUsed, 0) DESC,
+                 l.guid ASC
+    ",
+        common_cols = schema::COMMON_COLS,
+    );
+
     static ref GET_BY_GUID_SQL: String = format!("
         SELECT {common_cols}
         FROM loginsL
@@ -1,11 +1,16 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
-use login::Login;
+use login::{Login, RecoveredLogin};
 use error::*;
-use sync::{self, Sync15StorageClient, Sync15StorageClientInit, GlobalState, KeyBundle};
-use db::LoginDb;
+use sync::{self, Sync15StorageClient, Sync15StorageClientInit, GlobalState, KeyBundle, ValidationReport};
+use sync::request::InfoQuota;
+use db::{LoginDb, LoginStore, UsernameMatch};
+use telemetry::IncomingTelemetry;
+use maintenance::MaintenanceReport;
+use paths::LoginStorePaths;
 use std::path::Path;
+use std::time::{Duration, SystemTime};
 use serde_json;
 use rusqlite;
 
@@ -17,6 +22,26 @@ pub(crate) struct SyncInfo {
     pub last_client_init: Sync15StorageClientInit,
 }
 
+/// What `PasswordEngine::sync` did, when it didn't fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncResult {
+    Synced {
+        /// The account's storage usage and quota, from the last time we
+        /// fetched them (see `GlobalState::quota`), if we ever have.
+        quota: Option<InfoQuota>,
+        /// The kilobytes left in the account's quota, if the server told us
+        /// during this sync (see `Sync15StorageClient::quota_remaining_kb`).
+        quota_remaining_kb: Option<f64>,
+    },
+    /// The sync wasn't user-initiated, and the last successful sync was less
+    /// than the minimum interval ago (see `set_sync_min_interval`), so we
+    /// didn't sync.
+    SkippedRateLimited {
+        /// When the minimum interval will have passed.
+        next_allowed: SystemTime,
+    },
+}
+
 // This isn't really an engine in the firefox sync15 desktop sense -- it's
 // really a bundle of state that contains the sync storage client, the sync
 // state, and the login DB.
@@ -32,6 +57,16 @@ impl PasswordEngine {
         Ok(Self { db, sync: None })
     }
 
+    /// Open the store in the standard location inside a profile directory,
+    /// creating the directories it needs and cleaning up after previous runs.
+    /// The database is backed up before schema migrations (see
+    /// `LoginDb::open_in_profile`).
+    pub fn open_in_profile(paths: &LoginStorePaths, encryption_key: Option<&str>) -> Result<Self> {
+        paths.prepare()?;
+        let db = LoginDb::open_in_profile(paths, encryption_key)?;
+        Ok(Self { db, sync: None })
+    }
+
     pub fn new_in_memory(encryption_key: Option<&str>) -> Result<Self> {
         let db = LoginDb::open_in_memory(encryption_key)?;
         Ok(Self { db, sync: None })
@@ -49,10 +84,63 @@ impl PasswordEngine {
         self.db.touch(id)
     }
 
+    /// See `LoginDb::touch_in_context`.
+    pub fn touch_in_context(&self, id: &str, origin: &str, context: Option<&str>) -> Result<()> {
+        self.db.touch_in_context(id, origin, context)
+    }
+
+    /// See `LoginDb::get_for_usage_context`.
+    pub fn get_for_usage_context(&self, origin: &str, context: Option<&str>) -> Result<Vec<Login>> {
+        self.db.get_for_usage_context(origin, context)
+    }
+
+    /// See `LoginDb::set_sync_excluded` for what this means.
+    pub fn set_sync_excluded(&self, id: &str, excluded: bool) -> Result<()> {
+        self.db.set_sync_excluded(id, excluded)
+    }
+
+    pub fn is_sync_excluded(&self, id: &str) -> Result<bool> {
+        self.db.is_sync_excluded(id)
+    }
+
     pub fn delete(&self, id: &str) -> Result<bool> {
         self.db.delete(id)
     }
 
+    /// See `LoginDb::delete_many`.
+    pub fn delete_many(&self, ids: &[&str]) -> Result<usize> {
+        self.db.delete_many(ids)
+    }
+
+    /// See `LoginDb::get_unused_since`.
+    pub fn get_unused_since(&self, time_ms: i64) -> Result<Vec<Login>> {
+        self.db.get_unused_since(time_ms)
+    }
+
+    /// See `LoginDb::get_recovered`.
+    pub fn get_recovered(&self) -> Result<Vec<RecoveredLogin>> {
+        self.db.get_recovered()
+    }
+
+    /// See `LoginDb::restore_recovered`.
+    pub fn restore_recovered(&self, id: i64) -> Result<String> {
+        self.db.restore_recovered(id)
+    }
+
+    pub fn delete_recovered(&self, id: i64) -> Result<bool> {
+        self.db.delete_recovered(id)
+    }
+
+    /// See `LoginDb::purge_recovered`.
+    pub fn purge_recovered(&self, time_ms: i64) -> Result<usize> {
+        self.db.purge_recovered(time_ms)
+    }
+
+    /// See `LoginDb::run_maintenance`.
+    pub fn run_maintenance(&self, allow_vacuum: bool) -> Result<MaintenanceReport> {
+        self.db.run_maintenance(allow_vacuum)
+    }
+
     pub fn wipe(&self) -> Result<()> {
         self.db.wipe()
     }
@@ -61,6 +149,29 @@ impl PasswordEngine {
         self.db.reset()
     }
 
+    /// See `LoginDb::get_by_hostname`.
+    pub fn get_by_hostname(&self,
+                           hostname: &str,
+                           username: Option<&str>,
+                           username_match: UsernameMatch) -> Result<Vec<Login>> {
+        self.db.get_by_hostname(hostname, username, username_match)
+    }
+
+    /// See `LoginDb::get_by_base_domain`.
+    pub fn get_by_base_domain(&self,
+                              base_domain: &str,
+                              username: Option<&str>,
+                              username_match: UsernameMatch) -> Result<Vec<Login>> {
+        self.db.get_by_base_domain(base_domain, username, username_match)
+    }
+
+    /// Counts describing how the records downloaded during the last sync
+    /// were handled, including any we had to repair or drop. None if we
+    /// haven't synced since the engine was created.
+    pub fn incoming_telemetry(&self) -> Option<&IncomingTelemetry> {
+        self.db.incoming_telemetry()
+    }
+
     pub fn update(&self, login: Login) -> Result<()> {
         self.db.update(login)
     }
@@ -76,12 +187,148 @@ impl PasswordEngine {
         &self.db.db
     }
 
+    /// Rate limit syncs that aren't user-initiated, so that apps which sync
+    /// on every foreground event don't hammer the server. When set, `sync`
+    /// skips syncing (returning `SyncResult::SkippedRateLimited`) until
+    /// `min_interval` has passed since the last successful sync. The interval
+    /// and the time of that sync are stored in the database, so this applies
+    /// across restarts. None (the default) disables the limit.
+    pub fn set_sync_min_interval(&self, min_interval: Option<Duration>) -> Result<()> {
+        self.db.set_sync_min_interval(min_interval)
+    }
+
+    /// Returns when the next sync that isn't user-initiated will be allowed,
+    /// or None if one is allowed now.
+    fn rate_limited_until(&self, now: SystemTime) -> Result<Option<SystemTime>> {
+        let min_interval = match self.db.get_sync_min_interval()? {
+            Some(min_interval) => min_interval,
+            None => return Ok(None),
+        };
+        Ok(match self.db.get_last_local_sync_time()? {
+            // If the last sync seems to be in the future, the clock has
+            // probably been changed. Let this sync through, rather than
+            // waiting for an unknown amount of time.
+            Some(last_sync) if last_sync <= now => {
+                let next_allowed = last_sync + min_interval;
+                if now < next_allowed { Some(next_allowed) } else { None }
+            }
+            _ => None,
+        })
+    }
+
+    /// Syncs passwords. User-initiated syncs (for example, from a "Sync now"
+    /// button) always run, but others are subject to the rate limit set with
+    /// `set_sync_min_interval`. Incoming logins are merged into local ones
+    /// for the same site whose usernames match according to
+    /// `username_match`, instead of being added alongside them.
     pub fn sync(
         &mut self,
         storage_init: &Sync15StorageClientInit,
-        root_sync_key: &KeyBundle
+        root_sync_key: &KeyBundle,
+        user_initiated: bool,
+        username_match: UsernameMatch,
+    ) -> Result<SyncResult> {
+        if !user_initiated {
+            if let Some(next_allowed) = self.rate_limited_until(SystemTime::now())? {
+                info!("Skipping sync, since we synced recently");
+                return Ok(SyncResult::SkippedRateLimited { next_allowed });
+            }
+        }
+
+        // Fails if another instance is syncing the same database.
+        self.db.begin_sync()?;
+        let result = self.sync_locked(storage_init, root_sync_key, username_match);
+        // Release the lock even if the sync failed, so that the next one
+        // doesn't have to wait for it to go stale.
+        if let Err(e) = self.db.end_sync() {
+            warn!("Failed to release the sync lock: {}", e);
+        }
+        result?;
+        let sync_info = self.sync.as_ref().expect("Set by a successful sync");
+        Ok(SyncResult::Synced {
+            quota: sync_info.state.quota,
+            quota_remaining_kb: sync_info.client.quota_remaining_kb(),
+        })
+    }
+
+    fn sync_locked(
+        &mut self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+        username_match: UsernameMatch,
     ) -> Result<()> {
+        let sync_info = self.prepare_sync(storage_init, root_sync_key)?;
+
+        info!("Syncing passwords engine!");
+
+        let ts = self.db.get_last_sync()?.unwrap_or_default();
 
+        // We don't use `?` here so that we can restore the value of of
+        // `self.sync` even if sync fails.
+        let result = sync::synchronize(
+            &sync_info.client,
+            &sync_info.state,
+            &mut LoginStore { db: &mut self.db, username_match },
+            sync::CollectionName::PASSWORDS,
+            ts,
+            true
+        );
+
+        match &result {
+            Ok(()) => info!("Sync was successful!"),
+            Err(e) => warn!("Sync failed! {:?}", e),
+        }
+
+        // Restore our value of `sync_info` even if the sync failed.
+        self.sync = Some(sync_info);
+
+        result?;
+        self.db.set_last_local_sync_time(SystemTime::now())?;
+        Ok(())
+    }
+
+    /// Compares the logins stored locally with the ones on the server, and
+    /// returns what differs, for sync telemetry. Logins with local changes
+    /// that haven't been uploaded yet aren't compared, so this is most
+    /// meaningful right after a successful sync. It doesn't change any logins
+    /// on either side, but does take the sync lock, and set up the sync
+    /// state like `sync` does.
+    pub fn validate(
+        &mut self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+    ) -> Result<ValidationReport> {
+        self.db.begin_sync()?;
+        let result = self.validate_locked(storage_init, root_sync_key);
+        if let Err(e) = self.db.end_sync() {
+            warn!("Failed to release the sync lock: {}", e);
+        }
+        result
+    }
+
+    fn validate_locked(
+        &mut self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+    ) -> Result<ValidationReport> {
+        let sync_info = self.prepare_sync(storage_init, root_sync_key)?;
+        let result = sync::validate(
+            &sync_info.client,
+            &sync_info.state,
+            &self.db,
+            sync::CollectionName::PASSWORDS,
+        );
+        self.sync = Some(sync_info);
+        result
+    }
+
+    // Gets the client and global state ready for talking to the server,
+    // reusing the ones from the last sync where we can.
+    fn prepare_sync(
+        &mut self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+    ) -> Result<SyncInfo> {
         // Note: If `to_ready` (or anything else with a ?) fails below, this
         // `take()` means we end up with `state.sync.is_none()`, which means the
         // next sync will redownload meta/global, crypto/keys, etc. without
@@ -134,6 +381,10 @@ impl PasswordEngine {
             sync_info.last_client_init = storage_init.clone();
         }
 
+        // The client outlives a single sync, so its token may be about to
+        // expire.
+        sync_info.client.refresh_token_if_expiring()?;
+
         // Advance the state machine to the point where it can perform a full
         // sync. This may involve uploading meta/global, crypto/keys etc.
         {
@@ -145,8 +396,12 @@ impl PasswordEngine {
             sync_info.state = next_sync_state;
         }
 
+        // Setting up can take a while, so make sure nobody took over from
+        // us in the meantime.
+        self.db.renew_sync_lock()?;
+
         // Reset our local state if necessary.
-        if sync_info.state.engines_that_need_local_reset().contains("passwords") {
+        if sync_info.state.engines_that_need_local_reset().contains(&sync::CollectionName::PASSWORDS) {
             info!("Passwords sync ID changed; engine needs local reset");
             self.db.reset()?;
         }
@@ -156,30 +411,7 @@ impl PasswordEngine {
         let s = sync_info.state.to_persistable_string();
         self.db.set_global_state(&s)?;
 
-        info!("Syncing passwords engine!");
-
-        let ts = self.db.get_last_sync()?.unwrap_or_default();
-
-        // We don't use `?` here so that we can restore the value of of
-        // `self.sync` even if sync fails.
-        let result = sync::synchronize(
-            &sync_info.client,
-            &sync_info.state,
-            &mut self.db,
-            "passwords".into(),
-            ts,
-            true
-        );
-
-        match &result {
-            Ok(()) => info!("Sync was successful!"),
-            Err(e) => warn!("Sync failed! {:?}", e),
-        }
-
-        // Restore our value of `sync_info` even if the sync failed.
-        self.sync = Some(sync_info);
-
-        Ok(result?)
+        Ok(sync_info)
     }
 }
 
@@ -187,6 +419,7 @@ impl PasswordEngine {
 mod test {
     use super::*;
     use std::time::SystemTime;
+    use url::Url;
     use util;
     // Doesn't check metadata fields
     fn assert_logins_equiv(a: &Login, b: &Login) {
@@ -213,8 +446,8 @@ mod test {
             form_submit_url: Some("https://www.example.com/login".into()),
             username: "coolperson21".into(),
             password: "p4ssw0rd".into(),
-            username_field: "user_input".into(),
-            password_field: "pass_input".into(),
+            username_field: Some("user_input".into()),
+            password_field: Some("pass_input".into()),
             .. Login::default()
         };
 
@@ -224,8 +457,9 @@ mod test {
             http_realm: Some("Some String Here".into()),
             username: "asdf".into(),
             password: "fdsa".into(),
-            username_field: "input_user".into(),
-            password_field: "input_pass".into(),
+            // HTTP auth logins have no form fields.
+            username_field: None,
+            password_field: None,
             .. Login::default()
         };
 
@@ -240,7 +474,11 @@ mod test {
             .expect("Not to error getting a")
             .expect("a to exist");
 
-        assert_logins_equiv(&a, &a_from_db);
+        // The form submit URL is canonicalized to an origin on the way in.
+        let mut a_fixed = a.clone();
+        a_fixed.fixup();
+        assert_eq!(a_fixed.form_submit_url.as_ref().unwrap(), "https://www.example.com");
+        assert_logins_equiv(&a_fixed, &a_from_db);
         assert_ge!(a_from_db.time_created, start_us);
         assert_ge!(a_from_db.time_password_changed, start_us);
         assert_ge!(a_from_db.time_last_used, start_us);
@@ -289,8 +527,45 @@ mod test {
         assert_ge!(b_after_update.time_created, start_us);
         assert_le!(b_after_update.time_created, now_us);
         assert_ge!(b_after_update.time_password_changed, now_us);
-        assert_ge!(b_after_update.time_last_used, now_us);
-        // Should be two even though we updated twice
-        assert_eq!(b_after_update.times_used, 2);
+        // Editing a login isn't using it.
+        assert_eq!(b_after_update.time_last_used, b_from_db.time_last_used);
+        assert_eq!(b_after_update.times_used, 1);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut engine = PasswordEngine::new_in_memory(None).unwrap();
+        let start = SystemTime::now();
+        let secs = |n: u64| start + Duration::from_secs(n);
+
+        // No limit by default, and nothing to limit until we've synced.
+        engine.db.set_last_local_sync_time(start).unwrap();
+        assert_eq!(engine.rate_limited_until(secs(1)).unwrap(), None);
+        engine.set_sync_min_interval(Some(Duration::from_secs(60))).unwrap();
+        engine.db.reset().unwrap();
+        assert_eq!(engine.rate_limited_until(secs(1)).unwrap(), None);
+        // The interval is a setting, so it's kept on reset.
+        assert_eq!(engine.db.get_sync_min_interval().unwrap(), Some(Duration::from_secs(60)));
+
+        engine.db.set_last_local_sync_time(start).unwrap();
+        assert_eq!(engine.rate_limited_until(secs(30)).unwrap(), Some(secs(60)));
+        assert_eq!(engine.rate_limited_until(secs(60)).unwrap(), None);
+        // A last sync in the future means the clock changed.
+        assert_eq!(engine.rate_limited_until(start - Duration::from_secs(1)).unwrap(), None);
+
+        // Scheduled syncs are skipped without touching the network, but
+        // user-initiated ones aren't, and so fail here.
+        engine.db.set_last_local_sync_time(SystemTime::now()).unwrap();
+        let init = Sync15StorageClientInit {
+            key_id: "key".into(),
+            access_token: "token".into(),
+            tokenserver_url: Url::parse("http://127.0.0.1:1/").unwrap(),
+        };
+        let key = KeyBundle::new_random().unwrap();
+        match engine.sync(&init, &key, false, UsernameMatch::EXACT).unwrap() {
+            SyncResult::SkippedRateLimited { .. } => {}
+            other => panic!("Expected the sync to be skipped, got {:?}", other),
+        }
+        assert!(engine.sync(&init, &key, true, UsernameMatch::EXACT).is_err());
     }
 }
@@ -3,8 +3,9 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use failure::{Fail, Context, Backtrace};
-use std::{self, fmt};
+use std::{self, fmt, io};
 use std::boxed::Box;
+use openssl;
 use rusqlite;
 use serde_json;
 use sync;
@@ -90,6 +91,24 @@ pub enum ErrorKind {
 
     #[fail(display = "Error parsing URL: {}", _0)]
     UrlParseError(#[fail(cause)] url::ParseError),
+
+    #[fail(display = "IO error: {}", _0)]
+    IoError(#[fail(cause)] io::Error),
+
+    #[fail(display = "Crypto error: {}", _0)]
+    CryptoError(#[fail(cause)] openssl::error::ErrorStack),
+
+    #[fail(display = "Field encryption keys must be 32 bytes")]
+    InvalidFieldKey,
+
+    #[fail(display = "Can't copy logins to {}, since it already exists", _0)]
+    ExportDestinationExists(String),
+
+    #[fail(display = "Encrypted field is malformed, or was encrypted with a different key")]
+    BadEncryptedField,
+
+    #[fail(display = "The database has encrypted fields, and must be opened by the application's crypto layer")]
+    FieldEncryptedDatabase,
 }
 
 macro_rules! impl_from_error {
@@ -115,6 +134,8 @@ impl_from_error! {
     (JsonError, serde_json::Error),
     (UrlParseError, url::ParseError),
     (SqlError, rusqlite::Error),
+    (IoError, io::Error),
+    (CryptoError, openssl::error::ErrorStack),
     (InvalidLogin, InvalidLogin)
 }
 
@@ -0,0 +1,337 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Migrating a database encrypted with SQLCipher to plain SQLite, for
+//! platforms that would rather keep a key in the OS keystore and encrypt
+//! sensitive fields themselves than encrypt the whole file.
+//!
+//! `export_to_plaintext` copies everything (guids, timestamps, the mirror,
+//! and the sync metadata) into a new database, then encrypts the `username`
+//! and `password` columns of `loginsL`, `loginsM` and `loginsRecovered` with
+//! the caller's key.
+//! Each value is encrypted with AES-256-GCM, using a random nonce and the
+//! record's guid and the column name as associated data (so values can't be
+//! moved between records or columns without detection), and stored as the
+//! base64 of the nonce, ciphertext and tag. A NULL username stays NULL.
+//!
+//! The copy is written to a temporary file next to the destination, with
+//! `secure_delete` on and its journal kept in memory, and vacuumed once the
+//! fields are encrypted, so that no plaintext is left in its free pages. It's
+//! only renamed to the destination once it's complete, and removed if the
+//! export fails.
+//!
+//! The exported database is marked as field-encrypted in `loginsSyncMeta`,
+//! and `LoginDb` refuses to open it, since syncing it would upload the
+//! encrypted values. Reading it is up to the application's own crypto layer,
+//! which can use `decrypt_field` (or reimplement the format above), or
+//! `import_from_plaintext` to turn it back into a database `LoginDb` opens.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use base64;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use rusqlite::{Connection, Transaction};
+use rusqlite::types::ToSql;
+
+use db::LoginDb;
+use error::*;
+use schema;
+
+/// The length of the key `export_to_plaintext` and `decrypt_field` take.
+pub const FIELD_KEY_LEN: usize = 32;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+const FIELD_TABLES: &[&str] = &["loginsL", "loginsM", "loginsRecovered"];
+
+/// Stored under `schema::FIELD_ENCRYPTION_META_KEY` in exported databases.
+pub const FIELD_ENCRYPTION_SCHEME: &str = "aes-256-gcm-v1";
+
+/// Exports the SQLCipher database at `source`, encrypted with `source_key`,
+/// to a new plain database at `dest`, encrypting usernames and passwords with
+/// `field_key`, which must be `FIELD_KEY_LEN` bytes. `source` is migrated to
+/// the current schema first, and left otherwise untouched, so callers should
+/// only delete it once they've safely stored `field_key`.
+///
+/// Fails if `dest` already exists. If the export fails, `dest` isn't created.
+pub fn export_to_plaintext(
+    source: impl AsRef<Path>,
+    source_key: &str,
+    dest: impl AsRef<Path>,
+    field_key: &[u8],
+) -> Result<()> {
+    let dest = dest.as_ref();
+    check_copy_args(dest, field_key)?;
+    let mut db = LoginDb::open(source, Some(source_key))?;
+    copy_database(&mut db.db, dest, "", |tx| {
+        for table in FIELD_TABLES {
+            map_fields(tx, table, |guid, column, value| encrypt_field(field_key, guid, column, value))?;
+        }
+        tx.execute_named(
+            "INSERT OR REPLACE INTO dest.loginsSyncMeta (key, value) VALUES (:key, :value)",
+            &[(":key", &schema::FIELD_ENCRYPTION_META_KEY as &ToSql),
+              (":value", &FIELD_ENCRYPTION_SCHEME as &ToSql)])?;
+        Ok(())
+    })?;
+    info!("Exported logins database to plaintext");
+    Ok(())
+}
+
+/// Reverses `export_to_plaintext`: copies the plain database at `source`,
+/// whose fields were encrypted with `field_key`, to a new SQLCipher database
+/// at `dest`, encrypted with `dest_key`, decrypting its usernames and
+/// passwords. `LoginDb` can open the result, and sync it again.
+///
+/// Fails if `dest` already exists, or if `source` wasn't exported by
+/// `export_to_plaintext`. If the import fails, `dest` isn't created.
+pub fn import_from_plaintext(
+    source: impl AsRef<Path>,
+    field_key: &[u8],
+    dest: impl AsRef<Path>,
+    dest_key: &str,
+) -> Result<()> {
+    let dest = dest.as_ref();
+    check_copy_args(dest, field_key)?;
+    let mut conn = Connection::open(source)?;
+    let scheme: Option<String> = conn.query_row_named(
+        "SELECT value FROM loginsSyncMeta WHERE key = :key",
+        &[(":key", &schema::FIELD_ENCRYPTION_META_KEY as &ToSql)],
+        |row| row.get(0)).ok();
+    if scheme.as_ref().map(String::as_str) != Some(FIELD_ENCRYPTION_SCHEME) {
+        throw!(ErrorKind::BadEncryptedField);
+    }
+    copy_database(&mut conn, dest, dest_key, |tx| {
+        for table in FIELD_TABLES {
+            map_fields(tx, table, |guid, column, value| decrypt_field(field_key, guid, column, value))?;
+        }
+        tx.execute_named(
+            "DELETE FROM dest.loginsSyncMeta WHERE key = :key",
+            &[(":key", &schema::FIELD_ENCRYPTION_META_KEY as &ToSql)])?;
+        Ok(())
+    })?;
+    info!("Imported logins database from plaintext");
+    Ok(())
+}
+
+fn check_copy_args(dest: &Path, field_key: &[u8]) -> Result<()> {
+    if field_key.len() != FIELD_KEY_LEN {
+        throw!(ErrorKind::InvalidFieldKey);
+    }
+    if dest.exists() {
+        throw!(ErrorKind::ExportDestinationExists(dest.display().to_string()));
+    }
+    Ok(())
+}
+
+// Copies the database open on `conn` to `dest`, encrypted with the SQLCipher
+// `key` (an empty key makes it plain SQLite), and calls `f` to change the
+// copy, which is attached as `dest`, before moving it into place.
+fn copy_database<F>(conn: &mut Connection, dest: &Path, key: &str, f: F) -> Result<()>
+where F: FnOnce(&Transaction) -> Result<()> {
+    let temp = temp_path(dest)?;
+    if temp.exists() {
+        // Left over from an earlier copy that crashed.
+        warn!("Removing incomplete copy of logins database");
+        fs::remove_file(&temp)?;
+    }
+    let result = fill_copy(conn, &temp, key, f)
+        .and_then(|()| Ok(fs::rename(&temp, dest)?));
+    if let Err(e) = result {
+        error!("Failed to copy logins database: {}", e);
+        if let Err(e) = conn.execute_batch("DETACH DATABASE dest") {
+            // Expected if we failed after detaching it.
+            debug!("Failed to detach the copy: {}", e);
+        }
+        if temp.exists() {
+            if let Err(e) = fs::remove_file(&temp) {
+                warn!("Failed to remove incomplete copy of logins database: {}", e);
+            }
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn fill_copy<F>(conn: &mut Connection, temp: &Path, key: &str, f: F) -> Result<()>
+where F: FnOnce(&Transaction) -> Result<()> {
+    let temp = temp.to_str().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Path isn't valid UTF-8")
+    })?;
+    conn.execute("ATTACH DATABASE ? AS dest KEY ?", &[&temp, &key])?;
+    // Overwrite anything we delete or replace, and don't let the journal
+    // hold on to pages from before `f` ran. We throw the file away if we
+    // crash, so we don't need a journal on disk to recover it.
+    conn.execute_batch("PRAGMA dest.secure_delete = ON;
+                        PRAGMA dest.journal_mode = MEMORY;")?;
+    conn.query_row("SELECT sqlcipher_export('dest')", &[], |_| ())?;
+    // `sqlcipher_export` doesn't copy the schema version.
+    conn.execute_batch(&format!("PRAGMA dest.user_version = {}", schema::VERSION))?;
+    {
+        let tx = conn.transaction()?;
+        f(&tx)?;
+        tx.commit()?;
+    }
+    // Rebuild the file, so that none of the values `f` replaced are left
+    // in unused pages.
+    conn.execute_batch("VACUUM dest")?;
+    conn.execute_batch("DETACH DATABASE dest")?;
+    Ok(())
+}
+
+fn temp_path(dest: &Path) -> Result<PathBuf> {
+    let name = dest.file_name().and_then(|name| name.to_str()).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Path isn't a valid file name")
+    })?;
+    Ok(dest.with_file_name(format!("{}.tmp", name)))
+}
+
+// Replaces the `username` and `password` of each row in `dest.{table}` with
+// `f(guid, column, value)`.
+fn map_fields<F>(conn: &Connection, table: &str, f: F) -> Result<()>
+where F: Fn(&str, &str, &str) -> Result<String> {
+    let rows: Vec<(i64, String, Option<String>, String)> = {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, guid, username, password FROM dest.{}", table))?;
+        let rows = stmt.query_and_then(&[], |row| -> Result<_> {
+            Ok((row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?, row.get_checked(3)?))
+        })?;
+        rows.collect::<Result<_>>()?
+    };
+    let mut stmt = conn.prepare(&format!(
+        "UPDATE dest.{} SET username = :username, password = :password WHERE id = :id",
+        table))?;
+    for (id, guid, username, password) in rows {
+        let username = match username {
+            Some(username) => Some(f(&guid, "username", &username)?),
+            None => None,
+        };
+        let password = f(&guid, "password", &password)?;
+        stmt.execute_named(&[(":username", &username as &ToSql),
+                             (":password", &password as &ToSql),
+                             (":id", &id as &ToSql)])?;
+    }
+    Ok(())
+}
+
+fn associated_data(guid: &str, column: &str) -> Vec<u8> {
+    format!("{}:{}", guid, column).into_bytes()
+}
+
+fn encrypt_field(field_key: &[u8], guid: &str, column: &str, value: &str) -> Result<String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce)?;
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), field_key, Some(&nonce),
+                                  &associated_data(guid, column), value.as_bytes(), &mut tag)?;
+    let mut encrypted = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    encrypted.extend_from_slice(&tag);
+    Ok(base64::encode(&encrypted))
+}
+
+/// Decrypts a `column` (`"username"` or `"password"`) value of the record
+/// with the given `guid`, from a database exported by `export_to_plaintext`.
+pub fn decrypt_field(field_key: &[u8], guid: &str, column: &str, value: &str) -> Result<String> {
+    if field_key.len() != FIELD_KEY_LEN {
+        throw!(ErrorKind::InvalidFieldKey);
+    }
+    let encrypted = base64::decode(value).map_err(|_| ErrorKind::BadEncryptedField)?;
+    if encrypted.len() < NONCE_LEN + TAG_LEN {
+        throw!(ErrorKind::BadEncryptedField);
+    }
+    let (nonce, rest) = encrypted.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let plaintext = decrypt_aead(Cipher::aes_256_gcm(), field_key, Some(nonce),
+                                 &associated_data(guid, column), ciphertext, tag)
+        .map_err(|_| ErrorKind::BadEncryptedField)?;
+    Ok(String::from_utf8(plaintext).map_err(|_| ErrorKind::BadEncryptedField)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rusqlite::Connection;
+    use login::Login;
+    use sync::ServerTimestamp;
+    use tempfile::tempdir;
+
+    const FIELD_KEY: [u8; FIELD_KEY_LEN] = [7; FIELD_KEY_LEN];
+
+    #[test]
+    fn test_export_to_plaintext() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("logins.sqlite");
+        let dest = dir.path().join("logins-plain.sqlite");
+        {
+            let db = LoginDb::open(&source, Some("secret")).unwrap();
+            db.add(Login {
+                id: "aaaaaaaaaaaa".into(),
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("https://www.example.com".into()),
+                username: "alice".into(),
+                password: "hunter2".into(),
+                time_created: 1234,
+                ..Login::default()
+            }).unwrap();
+            db.set_last_sync(ServerTimestamp(100.0)).unwrap();
+        }
+
+        assert!(export_to_plaintext(&source, "secret", &dest, &[1, 2, 3]).is_err());
+        export_to_plaintext(&source, "secret", &dest, &FIELD_KEY).unwrap();
+        assert!(export_to_plaintext(&source, "secret", &dest, &FIELD_KEY).is_err());
+
+        let plain = Connection::open(&dest).unwrap();
+        let (guid, username, password, time_created): (String, String, String, i64) = plain.query_row(
+            "SELECT guid, username, password, timeCreated FROM loginsL", &[],
+            |row| (row.get(0), row.get(1), row.get(2), row.get(3))).unwrap();
+        assert_eq!(guid, "aaaaaaaaaaaa");
+        assert_eq!(time_created, 1234);
+        assert_ne!(password, "hunter2");
+        assert_eq!(decrypt_field(&FIELD_KEY, &guid, "username", &username).unwrap(), "alice");
+        assert_eq!(decrypt_field(&FIELD_KEY, &guid, "password", &password).unwrap(), "hunter2");
+        // The associated data ties each value to its record and column.
+        assert!(decrypt_field(&FIELD_KEY, &guid, "password", &username).is_err());
+        assert!(decrypt_field(&FIELD_KEY, "bbbbbbbbbbbb", "password", &password).is_err());
+        assert!(decrypt_field(&[8; FIELD_KEY_LEN], &guid, "password", &password).is_err());
+
+        let last_sync: i64 = plain.query_row(
+            "SELECT value FROM loginsSyncMeta WHERE key = ?", &[&schema::LAST_SYNC_META_KEY],
+            |row| row.get(0)).unwrap();
+        assert_eq!(last_sync, 100_000);
+        let version: i64 = plain.query_row("PRAGMA user_version", &[], |row| row.get(0)).unwrap();
+        assert_eq!(version, schema::VERSION);
+        drop(plain);
+
+        // We won't open it ourselves, since syncing it would upload
+        // encrypted values.
+        assert!(LoginDb::open(&dest, None).is_err());
+        assert!(!dir.path().join("logins-plain.sqlite.tmp").exists());
+
+        // Importing it with the wrong key fails with the decryption error,
+        // and doesn't leave anything behind.
+        let imported = dir.path().join("logins-imported.sqlite");
+        match import_from_plaintext(&dest, &[8; FIELD_KEY_LEN], &imported, "secret2").unwrap_err().kind() {
+            ErrorKind::BadEncryptedField => {}
+            kind => panic!("Unexpected error {:?}", kind),
+        }
+        assert!(!imported.exists());
+        assert!(!dir.path().join("logins-imported.sqlite.tmp").exists());
+
+        // But with the right key, we can open and sync it again.
+        import_from_plaintext(&dest, &FIELD_KEY, &imported, "secret2").unwrap();
+        let db = LoginDb::open(&imported, Some("secret2")).unwrap();
+        let login = db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap();
+        assert_eq!(login.username, "alice");
+        assert_eq!(login.password, "hunter2");
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(100.0)));
+
+        // We only import databases we exported.
+        assert!(import_from_plaintext(&imported, &FIELD_KEY, dir.path().join("again.sqlite"), "").is_err());
+    }
+}
@@ -0,0 +1,90 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Support for the FFI, which lives in the `loginsql_ffi` crate. The
+// conversions for our types need to be here, since neither they nor the
+// `ffi_support` traits are defined in that crate.
+
+use std::time::UNIX_EPOCH;
+
+use db::UsernameMatch;
+use engine::SyncResult;
+use ffi_support::{FfiTagged, IntoFfiTagged};
+
+/// Compare usernames ignoring (ASCII) case. See `UsernameMatch::from_ffi_flags`.
+pub const USERNAME_MATCH_IGNORE_CASE: u8 = 1;
+/// Ignore leading and trailing whitespace in usernames.
+pub const USERNAME_MATCH_TRIM_WHITESPACE: u8 = 2;
+
+impl UsernameMatch {
+    /// Makes a `UsernameMatch` from the `USERNAME_MATCH_*` flags that the
+    /// FFI functions take. Zero means an exact match.
+    pub fn from_ffi_flags(flags: u8) -> Self {
+        UsernameMatch {
+            ignore_case: flags & USERNAME_MATCH_IGNORE_CASE != 0,
+            trim_whitespace: flags & USERNAME_MATCH_TRIM_WHITESPACE != 0,
+        }
+    }
+}
+
+/// Tag 0 means we synced, and the payload is
+/// `{"usageKb": ..., "quotaKb": ..., "quotaRemainingKb": ...}`, where each
+/// is null if we don't know it. Tag 1 means the sync was skipped due to the
+/// rate limit, and the payload is `{"nextAllowed": <ms since the epoch>}`.
+impl IntoFfiTagged for SyncResult {
+    fn into_ffi_tagged(self) -> FfiTagged {
+        match self {
+            SyncResult::Synced { quota, quota_remaining_kb } => {
+                FfiTagged::new(0, Some(json!({
+                    "usageKb": quota.map(|q| q.usage_kb),
+                    "quotaKb": quota.and_then(|q| q.quota_kb),
+                    "quotaRemainingKb": quota_remaining_kb,
+                }).to_string()))
+            }
+            SyncResult::SkippedRateLimited { next_allowed } => {
+                let ms = next_allowed.duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+                    .unwrap_or(0);
+                FfiTagged::new(1, Some(json!({ "nextAllowed": ms }).to_string()))
+            }
+        }
+    }
+}
+
+implement_into_ffi_by_tag!(SyncResult);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ffi_support::IntoFfi;
+    use std::ffi::CStr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sync_result_into_ffi() {
+        let skipped = SyncResult::SkippedRateLimited {
+            next_allowed: UNIX_EPOCH + Duration::from_millis(1500),
+        }.into_ffi_value();
+        assert_eq!(skipped.tag, 1);
+        unsafe {
+            assert_eq!(CStr::from_ptr(skipped.payload).to_str().unwrap(), "{\"nextAllowed\":1500}");
+            skipped.destroy();
+        }
+
+        let synced = SyncResult::Synced { quota: None, quota_remaining_kb: None }.into_ffi_value();
+        assert_eq!(synced.tag, 0);
+        unsafe { synced.destroy() };
+
+        assert_eq!(<SyncResult as IntoFfi>::ffi_default().tag, FfiTagged::ERROR_TAG);
+    }
+
+    #[test]
+    fn test_username_match_from_ffi_flags() {
+        assert_eq!(UsernameMatch::from_ffi_flags(0), UsernameMatch::EXACT);
+        assert_eq!(UsernameMatch::from_ffi_flags(USERNAME_MATCH_IGNORE_CASE),
+                   UsernameMatch { ignore_case: true, trim_whitespace: false });
+        assert_eq!(UsernameMatch::from_ffi_flags(USERNAME_MATCH_IGNORE_CASE | USERNAME_MATCH_TRIM_WHITESPACE),
+                   UsernameMatch { ignore_case: true, trim_whitespace: true });
+    }
+}
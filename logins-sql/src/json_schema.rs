@@ -0,0 +1,127 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The JSON we pass over the FFI (`Login`s, `IncomingTelemetry`, and the
+//! error codes and messages in the FFI crate) is parsed by the Kotlin and
+//! Swift wrappers, so its shape is an API, even though nothing declares it.
+//!
+//! To keep it from changing by accident, the tests here (and in the FFI
+//! crate) compare what we serialize against the golden files in
+//! `fixtures/json/v{JSON_SCHEMA_VERSION}`. Changing the shape on purpose means
+//! bumping `JSON_SCHEMA_VERSION`, and running the tests with
+//! `UPDATE_GOLDEN_FILES=1`, which writes the golden files for the new
+//! version. It never overwrites existing ones: golden files for a version
+//! that's been released must not change. We also check that `Login`s in the
+//! golden files for every earlier version still parse, since the wrappers
+//! may hand us JSON they persisted a long time ago.
+
+use serde::Serialize;
+use serde_json::{self, Value as JsonValue};
+use std::{env, fs, path::PathBuf};
+
+/// The version of the JSON shapes we pass over the FFI. Bump this whenever
+/// they change (see the module docs).
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+fn fixtures_dir(version: u32) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join("json")
+        .join(format!("v{}", version))
+}
+
+/// Panics if `value` doesn't serialize to the JSON in the golden file `name`
+/// for `JSON_SCHEMA_VERSION`, or writes the file if it doesn't exist and
+/// `UPDATE_GOLDEN_FILES` is set. This is only for tests, including the FFI
+/// crate's.
+#[doc(hidden)]
+pub fn check_golden<T: Serialize>(name: &str, value: &T) {
+    let path = fixtures_dir(JSON_SCHEMA_VERSION).join(name);
+    let actual = serde_json::to_value(value).unwrap();
+    if env::var_os("UPDATE_GOLDEN_FILES").is_some() && !path.exists() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, serde_json::to_string_pretty(&actual).unwrap() + "\n").unwrap();
+        return;
+    }
+    let golden = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Can't read golden file {}: {}", path.display(), e));
+    let expected: JsonValue = serde_json::from_str(&golden).unwrap();
+    assert_eq!(
+        actual,
+        expected,
+        "The JSON for {} doesn't match {}. If that's intended, bump \
+         JSON_SCHEMA_VERSION and run the tests with UPDATE_GOLDEN_FILES=1",
+        name,
+        path.display()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use login::Login;
+    use telemetry::IncomingTelemetry;
+
+    fn form_login() -> Login {
+        Login {
+            id: "aaaabbbbcccc".into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "alice".into(),
+            password: "hunter2".into(),
+            username_field: Some("user".into()),
+            password_field: Some("pass".into()),
+            time_created: 1_500_000_000_000,
+            time_password_changed: 1_500_000_001_000,
+            time_last_used: 1_500_000_002_000,
+            times_used: 3,
+            ..Login::default()
+        }
+    }
+
+    // No username or form fields, and the timestamps are unknown.
+    fn http_realm_login() -> Login {
+        Login {
+            id: "ddddeeeeffff".into(),
+            hostname: "https://intranet.example.com".into(),
+            http_realm: Some("Intranet".into()),
+            password: "correct horse".into(),
+            ..Login::default()
+        }
+    }
+
+    #[test]
+    fn test_golden_logins() {
+        check_golden("login_form.json", &form_login());
+        check_golden("login_http_realm.json", &http_realm_login());
+        check_golden("logins.json", &vec![form_login(), http_realm_login()]);
+    }
+
+    #[test]
+    fn test_golden_telemetry() {
+        check_golden("incoming_telemetry.json", &IncomingTelemetry {
+            applied: 10,
+            remapped: 1,
+            invalid_ignored: 2,
+            duplicate_ids: 3,
+            deduped: 4,
+            excluded_conflicts: 5,
+        });
+    }
+
+    #[test]
+    fn test_old_logins_parse() {
+        for version in 1..=JSON_SCHEMA_VERSION {
+            let dir = fixtures_dir(version);
+            for name in &["login_form.json", "login_http_realm.json"] {
+                let path = dir.join(name);
+                let golden = fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("Can't read golden file {}: {}", path.display(), e));
+                if let Err(e) = serde_json::from_str::<Login>(&golden) {
+                    panic!("Can't parse {} as a Login: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
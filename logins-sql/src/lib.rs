@@ -27,12 +27,22 @@ extern crate url;
 extern crate rusqlite;
 
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
 
 #[macro_use]
 extern crate serde_derive;
 
 extern crate sql_support;
+extern crate sync_guid;
+#[macro_use]
+extern crate ffi_support;
+
+extern crate openssl;
+extern crate base64;
+
+#[cfg(test)]
+extern crate tempfile;
 
 #[macro_use]
 mod error;
@@ -43,10 +53,24 @@ mod util;
 mod db;
 mod engine;
 mod update_plan;
+mod telemetry;
+mod paths;
+mod export;
+mod json_schema;
+mod maintenance;
+pub mod ffi;
 
 pub use error::*;
 pub use login::*;
 pub use engine::*;
+pub use db::UsernameMatch;
+pub use telemetry::IncomingTelemetry;
+pub use json_schema::JSON_SCHEMA_VERSION;
+#[doc(hidden)]
+pub use json_schema::check_golden;
+pub use paths::LoginStorePaths;
+pub use maintenance::MaintenanceReport;
+pub use export::{export_to_plaintext, import_from_plaintext, decrypt_field, FIELD_KEY_LEN, FIELD_ENCRYPTION_SCHEME};
 
 
 
@@ -4,6 +4,7 @@
 
 use sync::{self, ServerTimestamp};
 use rusqlite::Row;
+use serde_json::{self, Map, Value as JsonValue};
 use util;
 use std::time::{self, SystemTime};
 use error::*;
@@ -17,8 +18,10 @@ pub struct Login {
     pub hostname: String,
 
     // rename_all = "camelCase" by default will do formSubmitUrl, but we can just
-    // override this one field.
-    #[serde(rename = "formSubmitURL")]
+    // override this one field. Newer desktop versions call this
+    // `formActionOrigin`, so we accept that too, but keep writing
+    // `formSubmitURL` since that's what older clients understand.
+    #[serde(rename = "formSubmitURL", alias = "formActionOrigin")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub form_submit_url: Option<String>,
 
@@ -31,14 +34,32 @@ pub struct Login {
 
     pub password: String,
 
+    // HTTP auth logins don't have form fields, so these are `None` for them
+    // (and for form logins where we don't know the field names). We never
+    // store or send empty strings for these.
     #[serde(default)]
-    #[serde(skip_serializing_if = "String::is_empty")]
-    pub username_field: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username_field: Option<String>,
 
     #[serde(default)]
-    #[serde(skip_serializing_if = "String::is_empty")]
-    pub password_field: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_field: Option<String>,
 
+    // The timestamps are in milliseconds since the epoch, and follow the
+    // semantics desktop uses:
+    //
+    // - `add` keeps any of these that the caller provides (for example, when
+    //   importing), and defaults the others to now, and `times_used` to 1.
+    // - `update` only changes `time_password_changed`, to now, and only if the
+    //   password changed. Editing a login isn't using it.
+    // - `touch` sets `time_last_used` to now, and increments `times_used`.
+    // - When merging changes from sync, `time_created` is the earliest of the
+    //   two, `time_last_used` is the latest, `times_used` adds up the uses
+    //   on both sides, and `time_password_changed` comes from whichever side
+    //   the merged password came from. If that side didn't change it (older
+    //   clients don't always), it's set to when that side changed the login.
+    //
+    // Zero means unknown, and is never preferred over a known time.
     #[serde(default)]
     pub time_created: i64,
 
@@ -52,10 +73,72 @@ pub struct Login {
     pub times_used: i64,
 }
 
+/// The payload fields we understand (see the serde attributes on `Login`).
+/// Payloads may have others, added by newer clients. We keep those in the
+/// mirror's `unknownFields` column, and add them back when uploading, so that
+/// syncing with us doesn't lose them.
+const KNOWN_PAYLOAD_FIELDS: &[&str] = &[
+    "hostname",
+    "formSubmitURL",
+    "formActionOrigin",
+    "httpRealm",
+    "username",
+    "password",
+    "usernameField",
+    "passwordField",
+    "timeCreated",
+    "timePasswordChanged",
+    "timeLastUsed",
+    "timesUsed",
+];
+
+/// Removes the fields we don't understand from `payload`, and returns them as
+/// a JSON object, or None if there weren't any.
+pub(crate) fn take_unknown_fields(payload: &mut sync::Payload) -> Option<String> {
+    let unknown_keys: Vec<String> = payload.data.keys()
+        .filter(|key| !KNOWN_PAYLOAD_FIELDS.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    if unknown_keys.is_empty() {
+        return None;
+    }
+    let mut unknown = Map::new();
+    for key in unknown_keys {
+        if let Some(value) = payload.data.remove(&key) {
+            unknown.insert(key, value);
+        }
+    }
+    Some(JsonValue::Object(unknown).to_string())
+}
+
+/// Adds the fields from `take_unknown_fields` back to `payload`. Fields that
+/// are known, or that the payload already has, are left alone, so that old
+/// unknown fields never overwrite our data.
+pub(crate) fn add_unknown_fields(payload: &mut sync::Payload, unknown_fields: &str) {
+    let unknown = match serde_json::from_str::<JsonValue>(unknown_fields) {
+        Ok(JsonValue::Object(unknown)) => unknown,
+        Ok(_) | Err(_) => {
+            warn!("Ignoring malformed unknown fields for {}", payload.id);
+            return;
+        }
+    };
+    for (key, value) in unknown {
+        if KNOWN_PAYLOAD_FIELDS.contains(&key.as_str()) || payload.data.contains_key(&key) {
+            continue;
+        }
+        payload.data.insert(key, value);
+    }
+}
+
 fn string_or_default(row: &Row, col: &str) -> Result<String> {
     Ok(row.get_checked::<_, Option<String>>(col)?.unwrap_or_default())
 }
 
+/// See `Login::content_key`.
+pub(crate) type LoginContentKey<'a> = (
+    &'a str, Option<&'a str>, Option<&'a str>, &'a str, &'a str, Option<&'a str>, Option<&'a str>
+);
+
 impl Login {
     #[inline]
     pub fn guid(&self) -> &String {
@@ -86,6 +169,61 @@ impl Login {
         Ok(())
     }
 
+    /// Canonicalize `hostname` and `form_submit_url` the way desktop does:
+    /// both should be origins (`scheme://host[:port]`) rather than full URLs.
+    ///
+    /// Values we can't interpret are left alone, as are the special
+    /// `form_submit_url` values: an empty string (which matches any form) and
+    /// `javascript:` (for forms submitted by script).
+    ///
+    /// This also normalizes the form fields: empty `username_field` and
+    /// `password_field` values become `None`, and HTTP auth logins lose their
+    /// form fields entirely, including an empty `form_submit_url` that some
+    /// older clients write instead of null.
+    pub fn fixup(&mut self) {
+        if self.username_field.as_ref().map_or(false, String::is_empty) {
+            self.username_field = None;
+        }
+        if self.password_field.as_ref().map_or(false, String::is_empty) {
+            self.password_field = None;
+        }
+        if self.http_realm.is_some() {
+            self.username_field = None;
+            self.password_field = None;
+            if self.form_submit_url.as_ref().map_or(false, String::is_empty) {
+                self.form_submit_url = None;
+            }
+        }
+
+        if let Some(origin) = util::url_origin(&self.hostname) {
+            self.hostname = origin;
+        }
+        if let Some(action) = self.form_submit_url.take() {
+            self.form_submit_url = Some(if action.is_empty() {
+                action
+            } else if action.starts_with("javascript:") {
+                "javascript:".into()
+            } else {
+                util::url_origin(&action).unwrap_or(action)
+            });
+        }
+    }
+
+    /// Returns everything about this login except its id and its timestamp
+    /// and usage metadata. Two records with equal keys describe the same saved
+    /// login, even if they have different ids.
+    pub(crate) fn content_key(&self) -> LoginContentKey {
+        (
+            self.hostname.as_str(),
+            self.http_realm.as_ref().map(String::as_str),
+            self.form_submit_url.as_ref().map(String::as_str),
+            self.username.as_str(),
+            self.password.as_str(),
+            self.username_field.as_ref().map(String::as_str),
+            self.password_field.as_ref().map(String::as_str),
+        )
+    }
+
     pub(crate) fn from_row(row: &Row) -> Result<Login> {
         Ok(Login {
             id: row.get_checked("guid")?,
@@ -97,8 +235,8 @@ impl Login {
 
             form_submit_url: row.get_checked("formSubmitURL")?,
 
-            username_field: string_or_default(row, "usernameField")?,
-            password_field: string_or_default(row, "passwordField")?,
+            username_field: row.get_checked("usernameField")?,
+            password_field: row.get_checked("passwordField")?,
 
             time_created:   row.get_checked("timeCreated")?,
             // Might be null
@@ -110,6 +248,30 @@ impl Login {
     }
 }
 
+/// A local version of a login that was overwritten by an incoming change
+/// during sync, kept so that the user can get it back. See
+/// `LoginDb::get_recovered`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveredLogin {
+    /// Identifies this copy, for `restore_recovered` and `delete_recovered`.
+    /// This isn't the login's id, which is in `login`.
+    pub id: i64,
+    pub login: Login,
+    /// When we saved the copy, in milliseconds since the epoch.
+    pub recovered_at: i64,
+}
+
+impl RecoveredLogin {
+    pub(crate) fn from_row(row: &Row) -> Result<RecoveredLogin> {
+        Ok(RecoveredLogin {
+            id: row.get_checked("id")?,
+            login: Login::from_row(row)?,
+            recovered_at: row.get_checked("recoveredAt")?,
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct MirrorLogin {
     pub login: Login,
@@ -159,6 +321,7 @@ pub(crate) struct LocalLogin {
     pub login: Login,
     pub sync_status: SyncStatus,
     pub is_deleted: bool,
+    pub sync_excluded: bool,
     pub local_modified: SystemTime,
 }
 
@@ -173,6 +336,7 @@ impl LocalLogin {
             login: Login::from_row(row)?,
             sync_status: SyncStatus::from_u8(row.get_checked("sync_status")?)?,
             is_deleted: row.get_checked("is_deleted")?,
+            sync_excluded: row.get_checked("sync_excluded")?,
             local_modified: util::system_time_millis_from_row(row, "local_modified")?
         })
     }
@@ -213,6 +377,7 @@ macro_rules! impl_login {
 impl_login!(LocalLogin {
     sync_status: SyncStatus::New,
     is_deleted: false,
+    sync_excluded: false,
     local_modified: time::UNIX_EPOCH
 });
 
@@ -228,6 +393,8 @@ pub(crate) struct SyncLoginData {
     pub mirror: Option<MirrorLogin>,
     // None means it's a deletion
     pub inbound: (Option<Login>, ServerTimestamp),
+    /// The fields of the inbound record we don't understand, as a JSON object.
+    pub inbound_unknown_fields: Option<String>,
 }
 
 impl SyncLoginData {
@@ -241,17 +408,24 @@ impl SyncLoginData {
         &self.guid
     }
 
-    #[inline]
-    pub fn from_payload(payload: sync::Payload, ts: ServerTimestamp) -> Result<Self> {
+    /// Takes the login out of `payload`. sync15 has already parsed the
+    /// decrypted cleartext into JSON values by the time we get it, so rather
+    /// than borrowing from it, we move those values' strings into the `Login`
+    /// (`into_record` deserializes from an owned value), and only allocate for
+    /// the guid and the unknown fields.
+    pub fn from_payload(mut payload: sync::Payload, ts: ServerTimestamp) -> Result<Self> {
         let guid = payload.id.clone();
+        let mut inbound_unknown_fields = None;
         let login: Option<Login> =
             if payload.is_tombstone() {
                 None
             } else {
-                let record: Login = payload.into_record()?;
+                inbound_unknown_fields = take_unknown_fields(&mut payload);
+                let mut record: Login = payload.into_record()?;
+                record.fixup();
                 Some(record)
             };
-        Ok(Self { guid, local: None, mirror: None, inbound: (login, ts) })
+        Ok(Self { guid, local: None, mirror: None, inbound: (login, ts), inbound_unknown_fields })
     }
 }
 
@@ -322,18 +496,50 @@ macro_rules! merge_field {
     };
 }
 
+// Like `merge_field!`, but resolves collisions with `$pick` (`min` or `max`)
+// instead of preferring a side.
+macro_rules! merge_time {
+    ($merged:ident, $b:ident, $pick:ident, $field:ident) => {
+        if let Some($field) = $b.$field.take() {
+            $merged.$field = Some(match $merged.$field {
+                Some(existing) => existing.$pick($field),
+                None => $field,
+            });
+        }
+    };
+}
+
 impl LoginDelta {
     pub fn merge(self, mut b: LoginDelta, b_is_newer: bool) -> LoginDelta {
         let mut merged = self;
+        // Which side changed the password decides whose
+        // `time_password_changed` we keep, so check before merging it.
+        let a_changed_password = merged.password.is_some();
+        let b_changed_password = b.password.is_some();
+
         merge_field!(merged, b, b_is_newer, hostname);
         merge_field!(merged, b, b_is_newer, password);
         merge_field!(merged, b, b_is_newer, username);
         merge_field!(merged, b, b_is_newer, http_realm);
         merge_field!(merged, b, b_is_newer, form_submit_url);
 
-        merge_field!(merged, b, b_is_newer, time_created);
-        merge_field!(merged, b, b_is_newer, time_last_used);
-        merge_field!(merged, b, b_is_newer, time_password_changed);
+        merge_time!(merged, b, min, time_created);
+        merge_time!(merged, b, max, time_last_used);
+        match (a_changed_password, b_changed_password) {
+            (true, false) => {
+                b.time_password_changed = None;
+            }
+            (false, true) => {
+                merged.time_password_changed = b.time_password_changed.take();
+            }
+            // The timestamp follows the password that won.
+            (true, true) => {
+                merge_field!(merged, b, b_is_newer, time_password_changed);
+            }
+            (false, false) => {
+                merge_time!(merged, b, max, time_password_changed);
+            }
+        }
 
         merge_field!(merged, b, b_is_newer, password_field);
         merge_field!(merged, b, b_is_newer, username_field);
@@ -362,14 +568,25 @@ impl Login {
         apply_field!(self, delta, password);
         apply_field!(self, delta, username);
 
-        apply_field!(self, delta, time_created);
-        apply_field!(self, delta, time_last_used);
+        if let Some(time_created) = delta.time_created.take() {
+            if self.time_created <= 0 || time_created < self.time_created {
+                self.time_created = time_created;
+            }
+        }
+        if let Some(time_last_used) = delta.time_last_used.take() {
+            self.time_last_used = self.time_last_used.max(time_last_used);
+        }
         apply_field!(self, delta, time_password_changed);
 
-        apply_field!(self, delta, password_field);
-        apply_field!(self, delta, username_field);
-
         // Use Some("") to indicate that it should be changed to be None (hacky...)
+        if let Some(field) = delta.password_field.take() {
+            self.password_field = if field.is_empty() { None } else { Some(field) };
+        }
+
+        if let Some(field) = delta.username_field.take() {
+            self.username_field = if field.is_empty() { None } else { Some(field) };
+        }
+
         if let Some(realm) = delta.http_realm.take() {
             self.http_realm = if realm.is_empty() { None } else { Some(realm) };
         }
@@ -402,21 +619,16 @@ impl Login {
             delta.password = Some(self.password.clone());
         }
         if self.password_field != older.password_field {
-            delta.password_field = Some(self.password_field.clone());
+            delta.password_field = Some(self.password_field.clone().unwrap_or_default());
         }
         if self.username_field != older.username_field {
-            delta.username_field = Some(self.username_field.clone());
+            delta.username_field = Some(self.username_field.clone().unwrap_or_default());
         }
 
         // We discard zero (and negative numbers) for timestamps so that a
         // record that doesn't contain this information (these are
-        // `#[serde(default)]`) doesn't skew our records.
-        //
-        // Arguably, we should also also ignore values later than our
-        // `time_created`, or earlier than our `time_last_used` or
-        // `time_password_changed`. Doing this properly would probably require
-        // a scheme analogous to Desktop's weak-reupload system, so I'm punting
-        // on it for now.
+        // `#[serde(default)]`) doesn't skew our records. Later creation times
+        // and earlier last use times are discarded when the delta is applied.
         if self.time_created > 0 && self.time_created != older.time_created {
             delta.time_created = Some(self.time_created);
         }
@@ -434,3 +646,150 @@ impl Login {
         delta
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_fixup() {
+        let mut login = Login {
+            hostname: "https://www.example.com:8080/some/path?q=1".into(),
+            form_submit_url: Some("https://accounts.example.com/login".into()),
+            .. Login::default()
+        };
+        login.fixup();
+        assert_eq!(login.hostname, "https://www.example.com:8080");
+        assert_eq!(login.form_submit_url.unwrap(), "https://accounts.example.com");
+
+        for &(action, expected) in &[("", ""),
+                                     ("javascript:void(0)", "javascript:"),
+                                     ("not a url", "not a url")] {
+            let mut login = Login {
+                hostname: "not a url either".into(),
+                form_submit_url: Some(action.into()),
+                .. Login::default()
+            };
+            login.fixup();
+            assert_eq!(login.hostname, "not a url either");
+            assert_eq!(login.form_submit_url.unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_fixup_form_fields() {
+        let mut login = Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username_field: Some("".into()),
+            password_field: Some("pass".into()),
+            .. Login::default()
+        };
+        login.fixup();
+        assert_eq!(login.username_field, None);
+        assert_eq!(login.password_field, Some("pass".into()));
+
+        let mut login = Login {
+            hostname: "https://www.example.com".into(),
+            http_realm: Some("Restricted".into()),
+            form_submit_url: Some("".into()),
+            username_field: Some("user".into()),
+            password_field: Some("pass".into()),
+            .. Login::default()
+        };
+        login.fixup();
+        assert_eq!(login.form_submit_url, None);
+        assert_eq!(login.username_field, None);
+        assert_eq!(login.password_field, None);
+        assert_eq!(login.http_realm, Some("Restricted".into()));
+    }
+
+    #[test]
+    fn test_null_form_fields() {
+        let login: Login = serde_json::from_str(r#"{
+            "id": "aaaaaaaaaaaa",
+            "hostname": "https://www.example.com",
+            "httpRealm": "Restricted",
+            "formSubmitURL": null,
+            "usernameField": null,
+            "password": "hunter2"
+        }"#).unwrap();
+        assert_eq!(login.form_submit_url, None);
+        assert_eq!(login.username_field, None);
+        assert_eq!(login.password_field, None);
+        let json = serde_json::to_value(&login).unwrap();
+        assert!(json.get("formSubmitURL").is_none());
+        assert!(json.get("usernameField").is_none());
+        assert!(json.get("passwordField").is_none());
+    }
+
+    #[test]
+    fn test_delta_clears_form_fields() {
+        let old = Login {
+            username_field: Some("user".into()),
+            password_field: Some("pass".into()),
+            .. Login::default()
+        };
+        let new = Login {
+            username_field: None,
+            password_field: Some("pass".into()),
+            .. Login::default()
+        };
+        let mut applied = old.clone();
+        applied.apply_delta(new.delta(&old));
+        assert_eq!(applied.username_field, None);
+        assert_eq!(applied.password_field, Some("pass".into()));
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let mut payload: sync::Payload = serde_json::from_str(r#"{
+            "id": "aaaaaaaaaaaa",
+            "hostname": "https://www.example.com",
+            "formActionOrigin": "https://www.example.com",
+            "password": "hunter2",
+            "futureField": {"nested": [1, 2, 3]},
+            "otherFutureField": "hello"
+        }"#).unwrap();
+        let unknown = take_unknown_fields(&mut payload).unwrap();
+        assert!(payload.data.get("futureField").is_none());
+        let login: Login = payload.into_record().unwrap();
+        assert_eq!(login.password, "hunter2");
+
+        let mut outgoing = sync::Payload::from_record(Login {
+            username: "changed".into(),
+            .. login
+        }).unwrap();
+        add_unknown_fields(&mut outgoing, &unknown);
+        // Known fields are never taken from the unknown fields.
+        add_unknown_fields(&mut outgoing, r#"{"username": "stale", "password": "stale"}"#);
+        let json = serde_json::to_value(&outgoing).unwrap();
+        assert_eq!(json["futureField"]["nested"][2], 3);
+        assert_eq!(json["otherFutureField"], "hello");
+        assert_eq!(json["username"], "changed");
+        assert_eq!(json["password"], "hunter2");
+        assert_eq!(json["formSubmitURL"], "https://www.example.com");
+        assert!(json.get("formActionOrigin").is_none());
+
+        let mut payload = sync::Payload::new_tombstone("bbbbbbbbbbbb".into());
+        assert_eq!(take_unknown_fields(&mut payload), None);
+        add_unknown_fields(&mut payload, "not json");
+        assert!(payload.data.is_empty());
+    }
+
+    #[test]
+    fn test_form_action_origin_alias() {
+        let login: Login = serde_json::from_str(r#"{
+            "id": "aaaaaaaaaaaa",
+            "hostname": "https://www.example.com",
+            "formActionOrigin": "https://www.example.com",
+            "password": "hunter2"
+        }"#).unwrap();
+        assert_eq!(login.form_submit_url.as_ref().unwrap(), "https://www.example.com");
+        // We still write the name older clients understand.
+        let json = serde_json::to_value(&login).unwrap();
+        assert_eq!(json["formSubmitURL"], "https://www.example.com");
+        assert!(json.get("formActionOrigin").is_none());
+    }
+}
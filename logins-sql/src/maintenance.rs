@@ -0,0 +1,222 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Periodic housekeeping, intended to be run by the application when it's
+// idle, like places' `run_maintenance`.
+
+use sql_support::ConnExt;
+
+use db::LoginDb;
+use error::*;
+use login::SyncStatus;
+
+// The most problems we ask `PRAGMA quick_check` for.
+const MAX_INTEGRITY_PROBLEMS: u32 = 10;
+
+// We only vacuum when at least this many pages, and at least a quarter of the
+// database, are free. Vacuuming rewrites the whole file, so it isn't worth
+// doing to get a few pages back.
+const VACUUM_MIN_FREE_PAGES: i64 = 256;
+const VACUUM_MIN_FREE_FRACTION_INVERSE: i64 = 4;
+
+/// What `LoginDb::run_maintenance` did.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceReport {
+    /// The number of tombstones we deleted. We only delete ones the server
+    /// already knows about.
+    pub tombstones_pruned: usize,
+    /// True if the database is in WAL mode, and we checkpointed (and
+    /// truncated) the log.
+    pub wal_checkpointed: bool,
+    /// True if we vacuumed the database.
+    pub vacuumed: bool,
+    /// The problems `PRAGMA quick_check` found, if any. When there are some,
+    /// we don't do anything else, since changing a corrupt database could
+    /// make things worse.
+    pub integrity_problems: Vec<String>,
+}
+
+impl LoginDb {
+    /// Does housekeeping that doesn't need to happen right away: checks the
+    /// database for corruption, deletes tombstones that have already been
+    /// synced, checkpoints the WAL, and (if `allow_vacuum` is true, and
+    /// there's enough free space to be worth it) vacuums the database.
+    ///
+    /// Vacuuming can take a while on big databases, and blocks other users
+    /// of the connection, so this is best called when the application is
+    /// idle. It's safe to call while a sync is in progress on another
+    /// connection, but not on this one.
+    pub fn run_maintenance(&self, allow_vacuum: bool) -> Result<MaintenanceReport> {
+        let mut report = MaintenanceReport::default();
+        report.integrity_problems = self.quick_check()?;
+        if !report.integrity_problems.is_empty() {
+            warn!("Skipping maintenance: quick_check found {} problem(s)",
+                  report.integrity_problems.len());
+            return Ok(report);
+        }
+        report.tombstones_pruned = self.prune_synced_tombstones()?;
+        report.wal_checkpointed = self.checkpoint_wal()?;
+        if allow_vacuum {
+            report.vacuumed = self.vacuum_if_worthwhile()?;
+        }
+        info!("Ran maintenance on password store: {:?}", report);
+        Ok(report)
+    }
+
+    fn quick_check(&self) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare(&format!("PRAGMA quick_check({})", MAX_INTEGRITY_PROBLEMS))?;
+        let rows = stmt.query_and_then(&[], |row| row.get_checked::<_, String>(0))?;
+        let mut problems = Vec::new();
+        for row in rows {
+            let message = row?;
+            if message != "ok" {
+                problems.push(message);
+            }
+        }
+        Ok(problems)
+    }
+
+    // Tombstones normally only live in the local table until they're
+    // uploaded (see `mark_as_synchronized`), but a tombstone that's marked as
+    // synced has nothing left to tell the server. We delete its mirror row
+    // too, since dropping the tombstone would otherwise make the mirror copy
+    // visible again.
+    fn prune_synced_tombstones(&self) -> Result<usize> {
+        let synced_tombstones = format!("
+            SELECT guid FROM loginsL
+            WHERE is_deleted = 1
+              AND sync_status = {synced}
+              AND sync_excluded = 0",
+            synced = SyncStatus::Synced as u8);
+        self.db.execute_batch("BEGIN")?;
+        let result = (|| -> Result<usize> {
+            self.db.execute(&format!(
+                "DELETE FROM loginsM WHERE guid IN ({})", synced_tombstones), &[])?;
+            Ok(self.db.execute(&format!(
+                "DELETE FROM loginsL WHERE guid IN ({})", synced_tombstones), &[])?)
+        })();
+        match result {
+            Ok(pruned) => {
+                self.db.execute_batch("COMMIT")?;
+                Ok(pruned)
+            }
+            Err(e) => {
+                if let Err(rollback_err) = self.db.execute_batch("ROLLBACK") {
+                    error!("Failed to roll back pruning tombstones: {}", rollback_err);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Returns false if the database isn't in WAL mode, in which case there's
+    // nothing to checkpoint.
+    fn checkpoint_wal(&self) -> Result<bool> {
+        let journal_mode: String = self.query_one("PRAGMA journal_mode")?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            return Ok(false);
+        }
+        // Returns (busy, log pages, checkpointed pages). If another
+        // connection is reading, we can't truncate the log this time.
+        let busy: i64 = self.query_one("PRAGMA wal_checkpoint(TRUNCATE)")?;
+        Ok(busy == 0)
+    }
+
+    fn vacuum_if_worthwhile(&self) -> Result<bool> {
+        let free_pages: i64 = self.query_one("PRAGMA freelist_count")?;
+        let total_pages: i64 = self.query_one("PRAGMA page_count")?;
+        if free_pages < VACUUM_MIN_FREE_PAGES
+            || free_pages * VACUUM_MIN_FREE_FRACTION_INVERSE < total_pages {
+            return Ok(false);
+        }
+        debug!("Vacuuming password store ({} of {} pages free)", free_pages, total_pages);
+        self.execute_all(&["VACUUM"])?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use login::Login;
+    use rusqlite::types::ToSql;
+
+    fn login(id: &str, password: &str) -> Login {
+        Login {
+            id: id.into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com/login".into()),
+            username: "alice".into(),
+            password: password.into(),
+            .. Login::default()
+        }
+    }
+
+    // Makes `guid` look like it was synced, so that deleting it leaves a
+    // tombstone.
+    fn pretend_synced(db: &LoginDb, guid: &str) {
+        db.execute_named(&format!("
+            INSERT INTO loginsM ({common_cols}, is_overridden, server_modified)
+            SELECT {common_cols}, 0, 1000 FROM loginsL WHERE guid = :guid",
+            common_cols = ::schema::COMMON_COLS),
+            &[(":guid", &guid as &ToSql)]).unwrap();
+        db.execute_named(
+            "UPDATE loginsL SET sync_status = 0 WHERE guid = :guid",
+            &[(":guid", &guid as &ToSql)]).unwrap();
+    }
+
+    fn count(db: &LoginDb, sql: &str) -> i64 {
+        db.query_one(sql).unwrap()
+    }
+
+    #[test]
+    fn test_prune_tombstones() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        for id in &["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"] {
+            db.add(login(id, "p4ssw0rd")).unwrap();
+            pretend_synced(&db, id);
+            db.delete(id).unwrap();
+        }
+        // Two of the tombstones have been uploaded, but the third hasn't.
+        db.execute_all(&[
+            "UPDATE loginsL SET sync_status = 0 WHERE guid IN ('aaaaaaaaaaaa', 'bbbbbbbbbbbb')",
+        ]).unwrap();
+
+        let report = db.run_maintenance(false).unwrap();
+        assert_eq!(report, MaintenanceReport {
+            tombstones_pruned: 2,
+            .. MaintenanceReport::default()
+        });
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM loginsL"), 1);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM loginsM"), 1);
+        assert!(db.get_by_id("aaaaaaaaaaaa").unwrap().is_none());
+        assert!(db.get_by_id("cccccccccccc").unwrap().is_none());
+
+        // The unsynced tombstone still gets uploaded.
+        let outgoing = db.fetch_outgoing(::sync::ServerTimestamp(0.0)).unwrap();
+        let ids = outgoing.changes.iter().map(|p| p.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["cccccccccccc"]);
+
+        assert_eq!(db.run_maintenance(false).unwrap(), MaintenanceReport::default());
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let big_password = "x".repeat(4000);
+        for i in 0..500 {
+            db.add(login(&format!("{:012}", i), &big_password)).unwrap();
+        }
+        assert!(!db.run_maintenance(true).unwrap().vacuumed);
+
+        db.wipe_local().unwrap();
+        assert!(!db.run_maintenance(false).unwrap().vacuumed);
+        let report = db.run_maintenance(true).unwrap();
+        assert!(report.vacuumed);
+        assert!(report.integrity_problems.is_empty());
+        assert_eq!(count(&db, "PRAGMA freelist_count"), 0);
+        assert!(!db.run_maintenance(true).unwrap().vacuumed);
+    }
+}
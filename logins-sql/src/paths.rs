@@ -0,0 +1,320 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use error::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use util;
+
+const DATABASE_FILENAME: &str = "logins.sqlite";
+const BACKUP_DIRNAME: &str = "logins-backups";
+const TEMP_DIRNAME: &str = "logins-tmp";
+const MIGRATION_MARKER_FILENAME: &str = "migration-in-progress";
+const MIGRATION_BACKUP_PREFIX: &str = "pre-v";
+
+/// Files SQLite may create next to the database, which need to move along
+/// with it.
+const DATABASE_SIDECAR_SUFFIXES: &[&str] = &["-wal", "-shm", "-journal"];
+
+/// Temp files older than this are assumed to be left over from a previous
+/// run that crashed or was killed, and are removed by `prepare`.
+const STALE_TEMP_FILE_AGE_SECS: u64 = 60 * 60;
+
+/// The canonical layout of the files the logins store keeps inside a profile
+/// directory:
+///
+/// - `logins.sqlite`: The database.
+/// - `logins-backups/`: Copies of the database made before risky operations,
+///   and databases we've moved aside because they were corrupt. While a
+///   schema migration is running, this also holds a marker file naming the
+///   backup made before it started.
+/// - `logins-tmp/`: Scratch space (for example, for migrations). Anything in
+///   here may be deleted the next time the store is opened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginStorePaths {
+    profile_dir: PathBuf,
+}
+
+impl LoginStorePaths {
+    pub fn new(profile_dir: impl Into<PathBuf>) -> Self {
+        Self { profile_dir: profile_dir.into() }
+    }
+
+    pub fn profile_dir(&self) -> &Path {
+        &self.profile_dir
+    }
+
+    pub fn database(&self) -> PathBuf {
+        self.profile_dir.join(DATABASE_FILENAME)
+    }
+
+    pub fn backup_dir(&self) -> PathBuf {
+        self.profile_dir.join(BACKUP_DIRNAME)
+    }
+
+    pub fn temp_dir(&self) -> PathBuf {
+        self.profile_dir.join(TEMP_DIRNAME)
+    }
+
+    /// The path for a backup of the database, e.g. `backup("pre-v7")` gives
+    /// `logins-backups/logins.pre-v7.sqlite`.
+    pub fn backup(&self, label: &str) -> PathBuf {
+        self.backup_dir().join(format!("logins.{}.sqlite", label))
+    }
+
+    /// The path for a temp file with the given name.
+    pub fn temp_file(&self, name: &str) -> PathBuf {
+        self.temp_dir().join(name)
+    }
+
+    /// Creates the profile, backup and temp directories if needed, and
+    /// removes stale temp files. Called when opening the store.
+    pub fn prepare(&self) -> Result<()> {
+        fs::create_dir_all(&self.profile_dir)?;
+        fs::create_dir_all(self.backup_dir())?;
+        fs::create_dir_all(self.temp_dir())?;
+        let removed = self.remove_stale_temp_files(SystemTime::now())?;
+        if removed > 0 {
+            info!("Removed {} stale logins temp files", removed);
+        }
+        Ok(())
+    }
+
+    /// Moves the database (along with any journal files) into the backup
+    /// directory, so that the next open starts from an empty database.
+    /// Returns where the database was moved to, or None if there wasn't one.
+    ///
+    /// We never do this automatically: opening an encrypted database with
+    /// the wrong key fails the same way as opening a corrupt one, and in that
+    /// case moving it aside would look a lot like data loss to the user.
+    pub fn move_aside_corrupt(&self) -> Result<Option<PathBuf>> {
+        let database = self.database();
+        if !database.exists() {
+            return Ok(None);
+        }
+        fs::create_dir_all(self.backup_dir())?;
+        let label = format!("corrupt-{}", util::system_time_ms_i64(SystemTime::now()));
+        let dest = self.backup(&label);
+        for suffix in DATABASE_SIDECAR_SUFFIXES {
+            let sidecar = with_suffix(&database, suffix);
+            if sidecar.exists() {
+                fs::rename(&sidecar, with_suffix(&dest, suffix))?;
+            }
+        }
+        fs::rename(&database, &dest)?;
+        warn!("Moved corrupt logins database to {:?}", dest);
+        Ok(Some(dest))
+    }
+
+    /// The file that exists while a schema migration is in progress. If we
+    /// find it when opening the store, the previous migration was interrupted
+    /// (for example, because the app crashed or was killed).
+    pub fn migration_marker(&self) -> PathBuf {
+        self.backup_dir().join(MIGRATION_MARKER_FILENAME)
+    }
+
+    /// Copies the database before migrating it to `to_version`, and writes the
+    /// migration marker. Returns the path of the copy.
+    ///
+    /// The database must not be in the middle of a transaction (or have
+    /// uncheckpointed WAL frames), since we copy the main file only. Once
+    /// we're on an SQLite with `VACUUM INTO`, we should use that instead.
+    pub fn backup_for_migration(&self, to_version: i64) -> Result<PathBuf> {
+        fs::create_dir_all(self.backup_dir())?;
+        fs::create_dir_all(self.temp_dir())?;
+        let backup = self.backup(&format!("{}{}", MIGRATION_BACKUP_PREFIX, to_version));
+        // Copy to a temp file first, so that a crash mid-copy can't leave a
+        // truncated backup that looks complete.
+        let temp = self.temp_file("migration-backup");
+        fs::copy(self.database(), &temp)?;
+        fs::rename(&temp, &backup)?;
+        fs::write(self.migration_marker(), backup.to_string_lossy().as_bytes())?;
+        info!("Backed up logins database to {:?} before migrating", backup);
+        Ok(backup)
+    }
+
+    /// Puts the backup named by the migration marker back in place of the
+    /// database, and removes the marker. Returns false (and does nothing) if
+    /// there's no migration in progress.
+    ///
+    /// This must not be called while the database is open.
+    pub fn restore_migration_backup(&self) -> Result<bool> {
+        let marker = self.migration_marker();
+        if !marker.exists() {
+            return Ok(false);
+        }
+        let backup = PathBuf::from(fs::read_to_string(&marker)?);
+        if backup.exists() {
+            let temp = self.temp_file("migration-restore");
+            fs::copy(&backup, &temp)?;
+            let database = self.database();
+            for suffix in DATABASE_SIDECAR_SUFFIXES {
+                let sidecar = with_suffix(&database, suffix);
+                if sidecar.exists() {
+                    fs::remove_file(&sidecar)?;
+                }
+            }
+            fs::rename(&temp, &database)?;
+            warn!("Restored logins database from {:?} after a failed migration", backup);
+        } else {
+            // We only write the marker once the backup is complete, so this
+            // means someone removed it. There's nothing we can do.
+            error!("Migration backup {:?} is missing, can't restore it", backup);
+        }
+        fs::remove_file(&marker)?;
+        Ok(true)
+    }
+
+    /// Removes the migration marker after a successful migration to
+    /// `to_version`, along with the backups made before migrations to older
+    /// versions, which the new backup supersedes.
+    pub fn finish_migration(&self, to_version: i64) -> Result<()> {
+        let marker = self.migration_marker();
+        if marker.exists() {
+            fs::remove_file(&marker)?;
+        }
+        for entry in fs::read_dir(self.backup_dir())? {
+            let path = entry?.path();
+            let superseded = path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(migration_backup_version)
+                .map_or(false, |version| version < to_version);
+            if superseded {
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("Failed to remove old migration backup {:?}: {}", path, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_stale_temp_files(&self, now: SystemTime) -> Result<usize> {
+        let temp_dir = self.temp_dir();
+        if !temp_dir.exists() {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        for entry in fs::read_dir(&temp_dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            // A modification time in the future means our clock is wrong, not
+            // that the file is fresh, but we can't tell, so leave it alone.
+            let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+            if age < Duration::from_secs(STALE_TEMP_FILE_AGE_SECS) {
+                continue;
+            }
+            match fs::remove_file(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Failed to remove stale temp file {:?}: {}", entry.path(), e),
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Returns the version from the name of a backup made by
+/// `backup_for_migration`, e.g. 7 for `logins.pre-v7.sqlite`.
+fn migration_backup_version(filename: &str) -> Option<i64> {
+    let prefix = format!("logins.{}", MIGRATION_BACKUP_PREFIX);
+    if !filename.starts_with(&prefix) || !filename.ends_with(".sqlite") {
+        return None;
+    }
+    filename[prefix.len()..filename.len() - ".sqlite".len()].parse().ok()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_layout() {
+        let paths = LoginStorePaths::new("/profile");
+        assert_eq!(paths.database(), Path::new("/profile/logins.sqlite"));
+        assert_eq!(paths.backup("pre-v7"), Path::new("/profile/logins-backups/logins.pre-v7.sqlite"));
+        assert_eq!(paths.temp_file("migrate"), Path::new("/profile/logins-tmp/migrate"));
+    }
+
+    #[test]
+    fn test_move_aside_corrupt() {
+        let dir = tempdir().unwrap();
+        let paths = LoginStorePaths::new(dir.path());
+        paths.prepare().unwrap();
+        assert_eq!(paths.move_aside_corrupt().unwrap(), None);
+
+        fs::write(paths.database(), b"not a database").unwrap();
+        fs::write(with_suffix(&paths.database(), "-wal"), b"wal").unwrap();
+        let dest = paths.move_aside_corrupt().unwrap().unwrap();
+
+        assert!(!paths.database().exists());
+        assert!(!with_suffix(&paths.database(), "-wal").exists());
+        assert_eq!(dest.parent().unwrap(), paths.backup_dir());
+        assert_eq!(fs::read(&dest).unwrap(), b"not a database");
+        assert_eq!(fs::read(with_suffix(&dest, "-wal")).unwrap(), b"wal");
+    }
+
+    #[test]
+    fn test_migration_backup() {
+        let dir = tempdir().unwrap();
+        let paths = LoginStorePaths::new(dir.path());
+        paths.prepare().unwrap();
+        assert!(!paths.restore_migration_backup().unwrap());
+
+        fs::write(paths.database(), b"v6").unwrap();
+        fs::write(paths.backup("pre-v5"), b"v4").unwrap();
+        fs::write(paths.backup("corrupt-123"), b"corrupt").unwrap();
+        let backup = paths.backup_for_migration(7).unwrap();
+        assert_eq!(backup, paths.backup("pre-v7"));
+        assert!(paths.migration_marker().exists());
+
+        // A crash mid-migration.
+        fs::write(paths.database(), b"half migrated").unwrap();
+        fs::write(with_suffix(&paths.database(), "-journal"), b"journal").unwrap();
+        assert!(paths.restore_migration_backup().unwrap());
+        assert_eq!(fs::read(paths.database()).unwrap(), b"v6");
+        assert!(!with_suffix(&paths.database(), "-journal").exists());
+        assert!(!paths.migration_marker().exists());
+
+        paths.backup_for_migration(7).unwrap();
+        paths.finish_migration(7).unwrap();
+        assert!(!paths.migration_marker().exists());
+        assert!(paths.backup("pre-v7").exists());
+        assert!(!paths.backup("pre-v5").exists());
+        assert!(paths.backup("corrupt-123").exists());
+    }
+
+    #[test]
+    fn test_migration_backup_version() {
+        assert_eq!(migration_backup_version("logins.pre-v7.sqlite"), Some(7));
+        assert_eq!(migration_backup_version("logins.pre-vx.sqlite"), None);
+        assert_eq!(migration_backup_version("logins.corrupt-1.sqlite"), None);
+    }
+
+    #[test]
+    fn test_remove_stale_temp_files() {
+        let dir = tempdir().unwrap();
+        let paths = LoginStorePaths::new(dir.path());
+        paths.prepare().unwrap();
+        fs::write(paths.temp_file("a"), b"a").unwrap();
+        fs::write(paths.temp_file("b"), b"b").unwrap();
+
+        // Nothing is stale yet.
+        assert_eq!(paths.remove_stale_temp_files(SystemTime::now()).unwrap(), 0);
+
+        let later = SystemTime::now() + Duration::from_secs(STALE_TEMP_FILE_AGE_SECS + 1);
+        assert_eq!(paths.remove_stale_temp_files(later).unwrap(), 2);
+        assert!(!paths.temp_file("a").exists());
+        assert!(!paths.temp_file("b").exists());
+    }
+}
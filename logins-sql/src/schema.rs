@@ -2,15 +2,18 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! Logins Schema v4
-//! ================
+//! Logins Schema v10
+//! =================
 //!
 //! The schema we use is a evolution of the firefox-ios logins database format.
-//! There are three tables:
+//! There are six tables:
 //!
 //! - `loginsL`: The local table.
 //! - `loginsM`: The mirror table.
 //! - `loginsSyncMeta`: The table used to to store various sync metadata.
+//! - `loginsIdMap`: The table mapping invalid server ids to local guids.
+//! - `loginsRecovered`: The local changes that lost to incoming ones.
+//! - `loginsUsage`: How often each login was used, by origin and app context.
 //!
 //! ## `loginsL`
 //!
@@ -18,7 +21,8 @@
 //!
 //! `loginsL` is essentially unchanged from firefox-ios, however note the
 //! semantic change v4 makes to timestamp fields (which is explained in more
-//! detail in the [COMMON_COLS] documentation).
+//! detail in the [COMMON_COLS] documentation), and the change v5 makes to the
+//! form fields (also explained there).
 //!
 //! It is important to note that `loginsL` is not guaranteed to be present for
 //! all records. Synced records may only exist in `loginsM` (although this is
@@ -46,6 +50,12 @@
 //!     - `2` (`SyncStatus::New`): Indicating that the record has never been
 //!       synced, or we have been reset since the last time it synced.
 //!
+//! - `sync_excluded`: A boolean indicating that the user wants this login to
+//!   stay on this device (see `LoginDb::set_sync_excluded`). Excluded records
+//!   are never uploaded, not even as tombstones, and incoming changes to them
+//!   only update the mirror. `sync_status` is still maintained as usual, so
+//!   that clearing the flag uploads any local changes. Added in version 8.
+//!
 //! ## `loginsM`
 //!
 //! This stores server-side login information, also known as the "mirror".
@@ -72,13 +82,18 @@
 //! - `is_overridden`: A boolean indicating whether or not the mirror contents
 //!   are invalid, and that we should defer to the data stored in `loginsL`.
 //!
+//! - `unknownFields`: The fields of the server record that we don't
+//!   understand, as a JSON object, or NULL if there weren't any. Newer clients
+//!   may add fields to login records, and we include these when we upload our
+//!   changes to the record so that we don't drop them. Added in version 7.
+//!
 //! ## `loginsSyncMeta`
 //!
 //! This is a simple key-value table based on the `moz_meta` table in places.
 //! This table was added (by this rust crate) in version 4, and so is not
 //! present in firefox-ios.
 //!
-//! Currently it is used to store two items:
+//! Currently it is used to store these items:
 //!
 //! 1. The last sync timestamp is stored under [LAST_SYNC_META_KEY], a
 //!    `sync15_adapter::ServerTimestamp` stored in integer milliseconds.
@@ -87,14 +102,94 @@
 //!    [GLOBAL_STATE_META_KEY]. This is a `sync15_adapter::GlobalState` stored as
 //!    JSON.
 //!
+//! 3. The local time of the last successful sync is stored under
+//!    [LAST_LOCAL_SYNC_META_KEY], in integer milliseconds since the epoch. This
+//!    is used to rate limit syncs (see `PasswordEngine::set_sync_min_interval`),
+//!    and is cleared on reset.
+//!
+//! 4. Databases exported by `export_to_plaintext` have the name of the scheme
+//!    used to encrypt their usernames and passwords stored under
+//!    [FIELD_ENCRYPTION_META_KEY]. We refuse to open these.
+//!
+//! 5. While a sync is running, the instance syncing stores a
+//!    `sync15_adapter::SyncLock` under [SYNC_LOCK_META_KEY], as JSON, so that
+//!    other instances don't sync at the same time (see `LoginDb::begin_sync`).
+//!
+//! 6. The minimum interval between syncs that aren't user-initiated is stored
+//!    under [SYNC_MIN_INTERVAL_META_KEY], in integer milliseconds (see
+//!    `PasswordEngine::set_sync_min_interval`). Unlike the other items, this
+//!    is a setting, so it's kept on reset.
+//!
+//! ## `loginsIdMap`
+//!
+//! Other clients occasionally upload logins with ids that the sync server (and
+//! thus every other client) would reject, for example because they're too long
+//! or contain commas. We don't fail the sync when we see these. Instead, we
+//! give the record a fresh local guid, and store the mapping here, so that
+//! later changes to the same server record are applied to the same login. This
+//! table was added in version 6.
+//!
+//! ### `loginsIdMap` Columns
+//!
+//! - `remote_id`: The invalid id, as it appears on the server.
+//!
+//! - `local_guid`: The guid we use for the record in `loginsL` and `loginsM`.
+//!
+//! ## `loginsRecovered`
+//!
+//! When we merge an incoming record, and it overwrites a password or username
+//! that was changed locally, we keep a copy of the losing local version here,
+//! so that the user can get it back (see `LoginDb::get_recovered`). These
+//! copies are never synced, and are only removed when the user restores or
+//! purges them, or wipes the store. This table was added in version 9.
+//!
+//! ### `loginsRecovered` Columns
+//!
+//! Contains all fields in [COMMON_COLS], although `guid` isn't unique, since
+//! the same login can lose more than once. It also has:
+//!
+//! - `id`: Identifies the recovered copy, for `LoginDb::restore_recovered`
+//!   and `LoginDb::delete_recovered`.
+//!
+//! - `recoveredAt`: When we saved the copy, in milliseconds since the epoch.
+//!
+//! ## `loginsUsage`
+//!
+//! Android Autofill fills logins into apps as well as web pages, and a login
+//! saved on the web is often used inside an app (or a WebView hosted by one).
+//! To rank the logins it offers, it wants to know how often each login was
+//! used for a given origin in a given context, rather than just the overall
+//! `timesUsed` (see `LoginDb::touch_in_context` and
+//! `LoginDb::get_for_usage_context`). This data is never synced, and rows are
+//! removed along with their login. This table was added in version 10.
+//!
+//! ### `loginsUsage` Columns
+//!
+//! - `guid`: The login that was used.
+//!
+//! - `origin`: The web origin the login was filled for.
+//!
+//! - `context`: The app the login was used in (for example, an Android
+//!   package name), or an empty string when it was used in the browser.
+//!
+//! - `timesUsed`: How often the login was used for this origin and context.
+//!
+//! - `timeLastUsed`: When it was last used for this origin and context, in
+//!   milliseconds since the epoch.
+//!
 
 use error::*;
 use sql_support::ConnExt;
 use db;
 
-/// Note that firefox-ios is currently on version 3. Version 4 is this version,
-/// which adds a metadata table and changes timestamps to be in milliseconds
-pub const VERSION: i64 = 4;
+/// Note that firefox-ios is currently on version 3. Version 4 added a metadata
+/// table and changed timestamps to be in milliseconds. Version 5 stores missing
+/// form fields as NULL rather than empty strings. Version 6 adds the
+/// `loginsIdMap` table. Version 7 adds the `unknownFields` column to
+/// `loginsM`. Version 8 adds the `sync_excluded` column to `loginsL`. Version 9
+/// adds the `loginsRecovered` table. Version 10 is this version, which adds the
+/// `loginsUsage` table.
+pub const VERSION: i64 = 10;
 
 /// Every column shared by both tables except for `id`
 ///
@@ -113,6 +208,12 @@ pub const VERSION: i64 = 4;
 /// (of `loginsM`) are stored as milliseconds as well both on firefox-ios and
 /// here (and so they do not need to be updated with the `timeLastUsed`/
 /// `timePasswordChanged`/`timeCreated` timestamps.
+///
+/// As of v5, `usernameField` and `passwordField` are NULL (never an empty
+/// string) when unknown, and are always NULL for HTTP auth logins (those with
+/// an `httpRealm`), which in turn always have a NULL `formSubmitURL`. Note that
+/// an empty `formSubmitURL` is still meaningful for form logins, since it
+/// matches any form on the page.
 pub const COMMON_COLS: &'static str = "
     guid,
     username,
@@ -154,7 +255,8 @@ lazy_static! {
             local_modified INTEGER,
 
             is_deleted     TINYINT NOT NULL DEFAULT 0,
-            sync_status    TINYINT NOT NULL DEFAULT 0
+            sync_status    TINYINT NOT NULL DEFAULT 0,
+            sync_excluded  TINYINT NOT NULL DEFAULT 0
         )",
         common_sql = COMMON_SQL
     );
@@ -165,7 +267,9 @@ lazy_static! {
             -- Milliseconds (a sync15_adapter::ServerTimestamp multiplied by
             -- 1000 and truncated)
             server_modified INTEGER NOT NULL,
-            is_overridden   TINYINT NOT NULL DEFAULT 0
+            is_overridden   TINYINT NOT NULL DEFAULT 0,
+            -- A JSON object, or NULL.
+            unknownFields   TEXT
         )",
         common_sql = COMMON_SQL
     );
@@ -183,6 +287,59 @@ const CREATE_META_TABLE_SQL: &'static str = "
     )
 ";
 
+const CREATE_ID_MAP_TABLE_SQL: &'static str = "
+    CREATE TABLE IF NOT EXISTS loginsIdMap (
+        remote_id  TEXT PRIMARY KEY,
+        local_guid TEXT NOT NULL UNIQUE
+    )
+";
+
+const CREATE_RECOVERED_TABLE_SQL: &'static str = "
+    CREATE TABLE IF NOT EXISTS loginsRecovered (
+        id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+        hostname            TEXT NOT NULL,
+        httpRealm           TEXT,
+        formSubmitURL       TEXT,
+        usernameField       TEXT,
+        passwordField       TEXT,
+        timesUsed           INTEGER NOT NULL DEFAULT 0,
+        timeCreated         INTEGER NOT NULL,
+        timeLastUsed        INTEGER,
+        timePasswordChanged INTEGER NOT NULL,
+        username            TEXT,
+        password            TEXT NOT NULL,
+        guid                TEXT NOT NULL,
+        -- Milliseconds since the epoch.
+        recoveredAt         INTEGER NOT NULL
+    )
+";
+
+const CREATE_USAGE_TABLE_SQL: &'static str = "
+    CREATE TABLE IF NOT EXISTS loginsUsage (
+        guid         TEXT NOT NULL,
+        origin       TEXT NOT NULL,
+        -- An empty string for the browser itself.
+        context      TEXT NOT NULL DEFAULT '',
+        timesUsed    INTEGER NOT NULL DEFAULT 0,
+        -- Milliseconds since the epoch.
+        timeLastUsed INTEGER NOT NULL,
+        PRIMARY KEY (guid, origin, context)
+    )
+";
+
+const CREATE_USAGE_ORIGIN_INDEX_SQL: &'static str = "
+    CREATE INDEX IF NOT EXISTS idx_loginsUsage_origin_context
+    ON loginsUsage (origin, context)
+";
+
+const ADD_MIRROR_UNKNOWN_FIELDS_SQL: &'static str = "
+    ALTER TABLE loginsM ADD COLUMN unknownFields TEXT
+";
+
+const ADD_LOCAL_SYNC_EXCLUDED_SQL: &'static str = "
+    ALTER TABLE loginsL ADD COLUMN sync_excluded TINYINT NOT NULL DEFAULT 0
+";
+
 const CREATE_OVERRIDE_HOSTNAME_INDEX_SQL: &'static str = "
     CREATE INDEX IF NOT EXISTS idx_loginsM_is_overridden_hostname
     ON loginsM (is_overridden, hostname)
@@ -209,8 +366,42 @@ const UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL: &'static str = "
         timePasswordChanged = timePasswordChanged / 1000
 ";
 
+// In v5 we started storing missing form fields as NULL, and stopped allowing
+// HTTP auth logins to have form fields at all (see `Login::fixup`).
+const NULL_LOCAL_EMPTY_FORM_FIELDS_SQL: &'static str = "
+    UPDATE loginsL
+    SET usernameField = NULLIF(usernameField, ''),
+        passwordField = NULLIF(passwordField, '')
+";
+
+const NULL_MIRROR_EMPTY_FORM_FIELDS_SQL: &'static str = "
+    UPDATE loginsM
+    SET usernameField = NULLIF(usernameField, ''),
+        passwordField = NULLIF(passwordField, '')
+";
+
+const NULL_LOCAL_HTTP_AUTH_FORM_FIELDS_SQL: &'static str = "
+    UPDATE loginsL
+    SET usernameField = NULL,
+        passwordField = NULL,
+        formSubmitURL = NULLIF(formSubmitURL, '')
+    WHERE httpRealm IS NOT NULL
+";
+
+const NULL_MIRROR_HTTP_AUTH_FORM_FIELDS_SQL: &'static str = "
+    UPDATE loginsM
+    SET usernameField = NULL,
+        passwordField = NULL,
+        formSubmitURL = NULLIF(formSubmitURL, '')
+    WHERE httpRealm IS NOT NULL
+";
+
 pub(crate) static LAST_SYNC_META_KEY:    &'static str = "last_sync_time";
 pub(crate) static GLOBAL_STATE_META_KEY: &'static str = "global_state";
+pub(crate) static LAST_LOCAL_SYNC_META_KEY: &'static str = "last_local_sync_time";
+pub(crate) static FIELD_ENCRYPTION_META_KEY: &'static str = "field_encryption";
+pub(crate) static SYNC_LOCK_META_KEY: &'static str = "sync_lock";
+pub(crate) static SYNC_MIN_INTERVAL_META_KEY: &'static str = "sync_min_interval";
 
 pub(crate) fn init(db: &db::LoginDb) -> Result<()> {
     let user_version = db.query_one::<i64>("PRAGMA user_version")?;
@@ -240,7 +431,15 @@ pub(crate) fn init(db: &db::LoginDb) -> Result<()> {
     }
     if user_version != VERSION {
         if user_version < VERSION {
-            upgrade(db, user_version)?;
+            // If any step fails, leave the database as it was.
+            db.execute_batch("BEGIN EXCLUSIVE")?;
+            if let Err(e) = upgrade(db, user_version) {
+                if let Err(rollback_err) = db.execute_batch("ROLLBACK") {
+                    error!("Failed to roll back schema upgrade: {}", rollback_err);
+                }
+                return Err(e);
+            }
+            db.execute_batch("COMMIT")?;
         } else {
             warn!("Loaded future schema version {} (we only understand version {}). \
                    Optimisitically ",
@@ -274,9 +473,35 @@ fn upgrade(db: &db::LoginDb, from: i64) -> Result<()> {
             CREATE_META_TABLE_SQL,
             UPDATE_LOCAL_TIMESTAMPS_TO_MILLIS_SQL,
             UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL,
-            &*SET_VERSION_SQL,
         ])?;
     }
+    if from < 5 {
+        db.execute_all(&[
+            NULL_LOCAL_EMPTY_FORM_FIELDS_SQL,
+            NULL_MIRROR_EMPTY_FORM_FIELDS_SQL,
+            NULL_LOCAL_HTTP_AUTH_FORM_FIELDS_SQL,
+            NULL_MIRROR_HTTP_AUTH_FORM_FIELDS_SQL,
+        ])?;
+    }
+    if from < 6 {
+        db.execute_all(&[CREATE_ID_MAP_TABLE_SQL])?;
+    }
+    if from < 7 {
+        db.execute_all(&[ADD_MIRROR_UNKNOWN_FIELDS_SQL])?;
+    }
+    if from < 8 {
+        db.execute_all(&[ADD_LOCAL_SYNC_EXCLUDED_SQL])?;
+    }
+    if from < 9 {
+        db.execute_all(&[CREATE_RECOVERED_TABLE_SQL])?;
+    }
+    if from < 10 {
+        db.execute_all(&[
+            CREATE_USAGE_TABLE_SQL,
+            CREATE_USAGE_ORIGIN_INDEX_SQL,
+        ])?;
+    }
+    db.execute_all(&[&*SET_VERSION_SQL])?;
     Ok(())
 }
 
@@ -288,6 +513,10 @@ pub(crate) fn create(db: &db::LoginDb) -> Result<()> {
         CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
         CREATE_DELETED_HOSTNAME_INDEX_SQL,
         CREATE_META_TABLE_SQL,
+        CREATE_ID_MAP_TABLE_SQL,
+        CREATE_RECOVERED_TABLE_SQL,
+        CREATE_USAGE_TABLE_SQL,
+        CREATE_USAGE_ORIGIN_INDEX_SQL,
         &*SET_VERSION_SQL,
     ])?;
     Ok(())
@@ -299,6 +528,9 @@ pub(crate) fn drop(db: &db::LoginDb) -> Result<()> {
         "DROP TABLE IF EXISTS loginsM",
         "DROP TABLE IF EXISTS loginsL",
         "DROP TABLE IF EXISTS loginsSyncMeta",
+        "DROP TABLE IF EXISTS loginsIdMap",
+        "DROP TABLE IF EXISTS loginsRecovered",
+        "DROP TABLE IF EXISTS loginsUsage",
         "PRAGMA user_version = 0",
     ])?;
     Ok(())
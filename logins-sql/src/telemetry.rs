@@ -0,0 +1,34 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// Counts describing what happened to the records we downloaded during a
+/// sync. Problems with individual records (bad ids, duplicates) are recorded
+/// here rather than failing the whole sync.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IncomingTelemetry {
+    /// The number of records (including tombstones) we reconciled against
+    /// local data.
+    pub applied: u32,
+
+    /// Records whose id would be rejected by the sync server, and which we
+    /// stored under a local guid (see the `loginsIdMap` table).
+    pub remapped: u32,
+
+    /// Tombstones with an invalid id that we've never seen a record for. There
+    /// is nothing for these to delete, so we ignore them.
+    pub invalid_ignored: u32,
+
+    /// Records whose id appeared more than once in the same download. We only
+    /// keep the most recently modified copy.
+    pub duplicate_ids: u32,
+
+    /// New records with different ids but identical contents to another
+    /// incoming record. We only keep one of them.
+    pub deduped: u32,
+
+    /// Changes to records the user has excluded from sync. These only update
+    /// the mirror, and the local copy wins (see `LoginDb::set_sync_excluded`).
+    pub excluded_conflicts: u32,
+}
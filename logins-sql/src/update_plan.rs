@@ -7,6 +7,7 @@ use std::time::SystemTime;
 use error::*;
 use login::{LocalLogin, MirrorLogin, Login, SyncStatus};
 use sync::ServerTimestamp;
+use sync_guid::GuidRef;
 use sql_support;
 use util;
 
@@ -18,15 +19,25 @@ pub(crate) struct UpdatePlan {
     // the bool is the `is_overridden` flag, the i64 is ServerTimestamp in millis
     pub mirror_inserts: Vec<(Login, i64, bool)>,
     pub mirror_updates: Vec<(Login, i64)>,
+    // Applied after the mirror inserts and updates.
+    pub mirror_unknown_fields: Vec<(String, Option<String>)>,
+    // Local versions that lost to incoming changes, to keep in `loginsRecovered`.
+    pub recovered: Vec<Login>,
 }
 
 impl UpdatePlan {
-    pub fn plan_two_way_merge(&mut self, local: &Login, upstream: (Login, ServerTimestamp)) {
+    /// Returns true if the local record is newer, and so wins. If it loses,
+    /// and had a different password or username, it's recovered.
+    pub fn plan_two_way_merge(&mut self, local: &Login, upstream: (Login, ServerTimestamp)) -> bool {
         let is_override = local.time_password_changed > upstream.0.time_password_changed;
-        self.mirror_inserts.push((upstream.0, upstream.1.as_millis() as i64, is_override));
         if !is_override {
+            if local.password != upstream.0.password || local.username != upstream.0.username {
+                self.recovered.push(local.clone());
+            }
             self.delete_local.push(local.id.to_string());
         }
+        self.mirror_inserts.push((upstream.0, upstream.1.as_millis() as i64, is_override));
+        is_override
     }
 
     pub fn plan_three_way_merge(
@@ -47,9 +58,31 @@ impl UpdatePlan {
 
         // Update mirror to upstream
         self.mirror_updates.push((upstream, upstream_time.as_millis() as i64));
+        let shared_password = shared.login.password.clone();
+        let shared_username = shared.login.username.clone();
+        let shared_time_password_changed = shared.login.time_password_changed;
         let mut new = shared;
 
         new.login.apply_delta(merged_delta);
+        if new.login.password != shared_password
+                && new.login.time_password_changed <= shared_time_password_changed {
+            // The side the password came from changed it without saying when,
+            // so use the time it changed the record.
+            new.login.time_password_changed = if new.login.password == local.login.password {
+                util::system_time_ms_i64(local.local_modified)
+            } else {
+                upstream_time.as_millis() as i64
+            };
+        }
+        // If the upstream side won a conflicting change to the password or
+        // username, keep the local version, so that it isn't lost.
+        let lost_password = local.login.password != shared_password
+            && new.login.password != local.login.password;
+        let lost_username = local.login.username != shared_username
+            && new.login.username != local.login.username;
+        if lost_password || lost_username {
+            self.recovered.push(local.login);
+        }
         new.server_modified = upstream_time;
         self.local_updates.push(new);
     }
@@ -59,10 +92,19 @@ impl UpdatePlan {
         self.delete_mirror.push(id.to_string());
     }
 
+    /// Deletes the mirror copy only, leaving the local record in place.
+    pub fn plan_mirror_delete(&mut self, id: String) {
+        self.delete_mirror.push(id);
+    }
+
     pub fn plan_mirror_update(&mut self, login: Login, time: ServerTimestamp) {
         self.mirror_updates.push((login, time.as_millis() as i64));
     }
 
+    pub fn plan_mirror_unknown_fields(&mut self, id: String, unknown_fields: Option<String>) {
+        self.mirror_unknown_fields.push((id, unknown_fields));
+    }
+
     pub fn plan_mirror_insert(&mut self, login: Login, time: ServerTimestamp, is_override: bool) {
         self.mirror_inserts.push((login, time.as_millis() as i64, is_override));
     }
@@ -104,7 +146,7 @@ impl UpdatePlan {
         ";
         let mut stmt = tx.prepare_cached(sql)?;
         for (login, timestamp) in &self.mirror_updates {
-            trace!("Updating mirror {:?}", login.guid_str());
+            trace!("Updating mirror {}", GuidRef::new(login.guid_str()).redacted());
             stmt.execute_named(&[
                (":server_modified", timestamp as &ToSql),
                (":http_realm",      &login.http_realm as &ToSql),
@@ -168,7 +210,7 @@ impl UpdatePlan {
         let mut stmt = tx.prepare_cached(&sql)?;
 
         for (login, timestamp, is_overridden) in &self.mirror_inserts {
-            trace!("Inserting mirror {:?}", login.guid_str());
+            trace!("Inserting mirror {}", GuidRef::new(login.guid_str()).redacted());
             stmt.execute_named(&[
                 (":is_overridden", is_overridden as &ToSql),
                 (":server_modified", timestamp as &ToSql),
@@ -192,6 +234,18 @@ impl UpdatePlan {
         Ok(())
     }
 
+    fn perform_mirror_unknown_fields_updates(&self, tx: &mut Transaction) -> Result<()> {
+        let mut stmt = tx.prepare_cached(
+            "UPDATE loginsM SET unknownFields = :unknown_fields WHERE guid = :guid")?;
+        for (guid, unknown_fields) in &self.mirror_unknown_fields {
+            stmt.execute_named(&[
+                (":unknown_fields", unknown_fields as &ToSql),
+                (":guid", guid as &ToSql),
+            ])?;
+        }
+        Ok(())
+    }
+
     fn perform_local_updates(&self, tx: &mut Transaction) -> Result<()> {
         let sql = format!("
             UPDATE loginsL
@@ -213,7 +267,7 @@ impl UpdatePlan {
         // XXX OutgoingChangeset should no longer have timestamp.
         let local_ms: i64 = util::system_time_ms_i64(SystemTime::now());
         for l in &self.local_updates {
-            trace!("Updating local {:?}", l.guid_str());
+            trace!("Updating local {}", GuidRef::new(l.guid_str()).redacted());
             stmt.execute_named(&[
                 (":local_modified", &local_ms as &ToSql),
 
@@ -235,6 +289,81 @@ impl UpdatePlan {
         Ok(())
     }
 
+    fn perform_recovered_inserts(&self, tx: &mut Transaction) -> Result<()> {
+        let sql = "
+            INSERT INTO loginsRecovered (
+                httpRealm,
+                formSubmitURL,
+                usernameField,
+                passwordField,
+                password,
+                hostname,
+                username,
+
+                timesUsed,
+                timeLastUsed,
+                timePasswordChanged,
+                timeCreated,
+
+                guid,
+                recoveredAt
+            ) VALUES (
+                :http_realm,
+                :form_submit_url,
+                :username_field,
+                :password_field,
+                :password,
+                :hostname,
+                :username,
+
+                :times_used,
+                :time_last_used,
+                :time_password_changed,
+                :time_created,
+
+                :guid,
+                :recovered_at
+            )";
+        let mut stmt = tx.prepare_cached(sql)?;
+        let now_ms: i64 = util::system_time_ms_i64(SystemTime::now());
+        for login in &self.recovered {
+            debug!("Recovering local version of {}", GuidRef::new(login.guid_str()).redacted());
+            stmt.execute_named(&[
+                (":http_realm",      &login.http_realm as &ToSql),
+                (":form_submit_url", &login.form_submit_url as &ToSql),
+                (":username_field",  &login.username_field as &ToSql),
+                (":password_field",  &login.password_field as &ToSql),
+                (":password",        &login.password as &ToSql),
+                (":hostname",        &login.hostname as &ToSql),
+                (":username",        &login.username as &ToSql),
+
+                (":times_used",            &login.times_used as &ToSql),
+                (":time_last_used",        &login.time_last_used as &ToSql),
+                (":time_password_changed", &login.time_password_changed as &ToSql),
+                (":time_created",          &login.time_created as &ToSql),
+
+                (":guid", &login.guid_str() as &ToSql),
+                (":recovered_at", &now_ms as &ToSql),
+            ])?;
+        }
+        Ok(())
+    }
+
+    // Runs last, since a local record that lost a merge is deleted, but lives
+    // on in the mirror, and its usage should too.
+    fn perform_usage_deletes(&self, tx: &mut Transaction) -> Result<()> {
+        sql_support::each_chunk(&self.delete_local, |chunk, _| -> Result<()> {
+            tx.execute(&format!("
+                DELETE FROM loginsUsage
+                WHERE guid IN ({vars})
+                  AND guid NOT IN (SELECT guid FROM loginsL)
+                  AND guid NOT IN (SELECT guid FROM loginsM)",
+                                vars = sql_support::repeat_sql_vars(chunk.len())),
+                       chunk)?;
+            Ok(())
+        })
+    }
+
     pub fn execute(&self, tx: &mut Transaction) -> Result<()> {
         debug!("UpdatePlan: deleting records...");
         self.perform_deletes(tx)?;
@@ -242,8 +371,122 @@ impl UpdatePlan {
         self.perform_mirror_updates(tx)?;
         debug!("UpdatePlan: Inserting new mirror records...");
         self.perform_mirror_inserts(tx)?;
+        debug!("UpdatePlan: Updating unknown fields in the mirror...");
+        self.perform_mirror_unknown_fields_updates(tx)?;
         debug!("UpdatePlan: Updating reconciled local records...");
         self.perform_local_updates(tx)?;
+        debug!("UpdatePlan: Recovering local records that lost...");
+        self.perform_recovered_inserts(tx)?;
+        debug!("UpdatePlan: Removing usage for deleted records...");
+        self.perform_usage_deletes(tx)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // (password, timeCreated, timePasswordChanged, timeLastUsed, timesUsed)
+    type Times = (&'static str, i64, i64, i64, i64);
+
+    const SHARED: Times = ("shared", 1000, 1000, 2000, 3);
+
+    // Golden cases for merging timestamps, as (description, local, upstream,
+    // expected), with `SHARED` as the common parent. The local changes were
+    // made at 5000, and the upstream ones at 6000, so upstream wins conflicts.
+    const MERGE_CASES: &[(&str, Times, Times, Times)] = &[
+        ("Nothing changed",
+         SHARED, SHARED, SHARED),
+        ("Used locally",
+         ("shared", 1000, 1000, 4000, 5), SHARED, ("shared", 1000, 1000, 4000, 5)),
+        ("Used on both sides, so the uses add up",
+         ("shared", 1000, 1000, 4000, 5), ("shared", 1000, 1000, 4500, 4), ("shared", 1000, 1000, 4500, 6)),
+        ("Last used earlier upstream",
+         ("shared", 1000, 1000, 4000, 4), ("shared", 1000, 1000, 3000, 4), ("shared", 1000, 1000, 4000, 5)),
+        ("Created earlier upstream",
+         SHARED, ("shared", 800, 1000, 2000, 3), ("shared", 800, 1000, 2000, 3)),
+        ("Created later upstream",
+         SHARED, ("shared", 1500, 1000, 2000, 3), SHARED),
+        ("Upstream is missing timestamps",
+         SHARED, ("shared", 0, 0, 0, 0), SHARED),
+        ("Password changed locally",
+         ("local", 1000, 4000, 2000, 3), SHARED, ("local", 1000, 4000, 2000, 3)),
+        ("Password changed upstream",
+         SHARED, ("remote", 1000, 5500, 2000, 3), ("remote", 1000, 5500, 2000, 3)),
+        ("Password changed locally, and only its timestamp upstream",
+         ("local", 1000, 4000, 2000, 3), ("shared", 1000, 4500, 2000, 3), ("local", 1000, 4000, 2000, 3)),
+        ("Password changed on both sides",
+         ("local", 1000, 4000, 2000, 3), ("remote", 1000, 5500, 2000, 3), ("remote", 1000, 5500, 2000, 3)),
+        ("Password changed on both sides, with an older timestamp upstream",
+         ("local", 1000, 4000, 2000, 3), ("remote", 1000, 3000, 2000, 3), ("remote", 1000, 3000, 2000, 3)),
+        ("Password changed upstream without a timestamp",
+         SHARED, ("remote", 1000, 1000, 2000, 3), ("remote", 1000, 6000, 2000, 3)),
+        ("Password changed locally without a timestamp",
+         ("local", 1000, 1000, 2000, 3), SHARED, ("local", 1000, 5000, 2000, 3)),
+    ];
+
+    fn login(times: Times) -> Login {
+        let (password, time_created, time_password_changed, time_last_used, times_used) = times;
+        Login {
+            id: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "alice".into(),
+            password: password.into(),
+            time_created,
+            time_password_changed,
+            time_last_used,
+            times_used,
+            .. Login::default()
+        }
+    }
+
+    fn times(login: &Login) -> (&str, i64, i64, i64, i64) {
+        (&login.password, login.time_created, login.time_password_changed, login.time_last_used, login.times_used)
+    }
+
+    #[test]
+    fn test_merge_timestamps() {
+        for &(description, local, upstream, expected) in MERGE_CASES {
+            let mut plan = UpdatePlan::default();
+            plan.plan_three_way_merge(
+                LocalLogin {
+                    login: login(local),
+                    sync_status: SyncStatus::Changed,
+                    is_deleted: false,
+                    sync_excluded: false,
+                    local_modified: UNIX_EPOCH + Duration::from_millis(5000),
+                },
+                MirrorLogin {
+                    login: login(SHARED),
+                    is_overridden: true,
+                    server_modified: ServerTimestamp(1.0),
+                },
+                login(upstream),
+                ServerTimestamp(6.0),
+                ServerTimestamp(7.0),
+            );
+            assert_eq!(times(&plan.local_updates[0].login), expected, "{}", description);
+            // Only a local password that lost is recovered.
+            let lost = local.0 == "local" && expected.0 != "local";
+            assert_eq!(plan.recovered.len(), if lost { 1 } else { 0 }, "{}", description);
+        }
+    }
+
+    #[test]
+    fn test_two_way_merge_recovers() {
+        let mut plan = UpdatePlan::default();
+        let local = login(("local", 1000, 4000, 2000, 3));
+        assert!(!plan.plan_two_way_merge(&local, (login(("remote", 1000, 5000, 2000, 3)), ServerTimestamp(6.0))));
+        assert_eq!(plan.recovered, vec![local.clone()]);
+
+        // Nothing is lost if the local record wins, or if it loses but has the
+        // same password and username.
+        let mut plan = UpdatePlan::default();
+        assert!(plan.plan_two_way_merge(&local, (login(("remote", 1000, 3000, 2000, 3)), ServerTimestamp(6.0))));
+        assert!(!plan.plan_two_way_merge(&local, (login(("local", 1000, 5000, 2000, 3)), ServerTimestamp(6.0))));
+        assert!(plan.recovered.is_empty());
+    }
+}
@@ -7,6 +7,10 @@ use rusqlite::Row;
 use std::time;
 use url::Url;
 
+pub fn url_host(url_str: &str) -> Option<String> {
+    Some(Url::parse(url_str).ok()?.host_str()?.to_string())
+}
+
 pub fn url_host_port(url_str: &str) -> Option<String> {
     let url = Url::parse(url_str).ok()?;
     let host = url.host_str()?;
@@ -17,6 +21,17 @@ pub fn url_host_port(url_str: &str) -> Option<String> {
     })
 }
 
+/// Returns the ASCII serialization of the origin of `url_str`, or None if
+/// it can't be parsed or has an opaque origin (e.g. `data:` URLs).
+pub fn url_origin(url_str: &str) -> Option<String> {
+    let origin = Url::parse(url_str).ok()?.origin();
+    if origin.is_tuple() {
+        Some(origin.ascii_serialization())
+    } else {
+        None
+    }
+}
+
 pub fn system_time_millis_from_row(row: &Row, col_name: &str) -> Result<time::SystemTime> {
     let time_ms = row.get_checked::<_, Option<i64>>(col_name)?.unwrap_or_default() as u64;
     Ok(time::UNIX_EPOCH + time::Duration::from_millis(time_ms))
@@ -0,0 +1,89 @@
+/* Copyright 2018 Mozilla
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+package org.mozilla.places
+
+import org.mozilla.places.rust.LibPlacesFFI
+import org.mozilla.places.rust.RawPlacesConnection
+import org.mozilla.places.rust.RustError
+import java.io.Closeable
+
+/**
+ * A connection to a places database. These calls block while they query the database, so
+ * don't make them on the main thread.
+ *
+ * @param dbPath The path to the database. It's created if it doesn't exist.
+ * @param encryptionKey The key to encrypt the database with, or null to leave it unencrypted.
+ */
+class PlacesConnection(dbPath: String, encryptionKey: String? = null) : Closeable {
+
+    private var raw: RawPlacesConnection? = rustCall { error ->
+        LibPlacesFFI.INSTANCE.places_connection_new(dbPath, encryptionKey, error)
+    }
+
+    /**
+     * Returns up to `limit` autocomplete matches for `search`, best match first.
+     */
+    @Synchronized
+    fun queryAutocomplete(search: String, limit: Int): List<SearchResult> {
+        val buffer = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_query_autocomplete(this.checkOpen(), search, limit, error)
+        }
+        try {
+            return SearchResult.fromJSONArray(buffer.asString())
+        } finally {
+            LibPlacesFFI.INSTANCE.places_destroy_pooled_buffer(buffer)
+        }
+    }
+
+    /**
+     * If `enabled` is true, words in autocomplete searches can match the path and query of
+     * URLs (like "PROJ-123" in a bug tracker URL), as well as the host. It's off for a new
+     * connection, since matching the host only is faster.
+     */
+    @Synchronized
+    fun setAutocompleteMatchUrlPath(enabled: Boolean) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_set_autocomplete_match_url_path(
+                    this.checkOpen(), (if (enabled) 1 else 0).toByte(), error)
+        }
+    }
+
+    /**
+     * Records that the user picked the match for `url` from the results for `search`, so that
+     * it ranks higher for similar searches.
+     */
+    @Synchronized
+    fun acceptResult(search: String, url: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_accept_result(this.checkOpen(), search, url, error)
+        }
+    }
+
+    @Synchronized
+    override fun close() {
+        val raw = this.raw
+        this.raw = null
+        if (raw != null) {
+            LibPlacesFFI.INSTANCE.places_connection_destroy(raw)
+        }
+    }
+
+    private fun checkOpen(): RawPlacesConnection {
+        return this.raw ?: throw PlacesException("Using a PlacesConnection after closing it")
+    }
+
+    private inline fun <U> rustCall(callback: (RustError.ByReference) -> U): U {
+        val e = RustError.ByReference()
+        val ret: U = callback(e)
+        if (e.isFailure()) {
+            throw e.intoException()
+        }
+        return ret
+    }
+}
@@ -0,0 +1,31 @@
+/* Copyright 2018 Mozilla
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+package org.mozilla.places
+
+open class PlacesException(msg: String): Exception(msg)
+
+/**
+ * A URL passed to places couldn't be parsed.
+ */
+class UrlParseFailed(msg: String): PlacesException(msg)
+
+/**
+ * Other data passed to places was invalid.
+ */
+class InvalidInput(msg: String): PlacesException(msg)
+
+/**
+ * Another connection had the database locked. The call can be retried.
+ */
+class DatabaseBusy(msg: String): PlacesException(msg)
+
+/**
+ * The database is corrupt, or isn't a places database.
+ */
+class DatabaseCorrupt(msg: String): PlacesException(msg)
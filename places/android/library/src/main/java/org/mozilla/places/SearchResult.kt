@@ -0,0 +1,61 @@
+/* Copyright 2018 Mozilla
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+package org.mozilla.places
+
+import org.json.JSONArray
+import org.json.JSONObject
+
+/**
+ * An autocomplete match, as returned by `PlacesConnection.queryAutocomplete`.
+ */
+data class SearchResult(
+        /** The search string this is a match for. */
+        val searchString: String,
+        /** The URL to open when the user picks this match. Pass it to `acceptResult`. */
+        val url: String,
+        /** `url` as it should be shown to the user. */
+        val displayUrl: String,
+        /** The id of the page in history, or null for an origin that isn't in history itself. */
+        val placeId: Long?,
+        /** The title to show for the match. */
+        val title: String,
+        /** The favicon URL, if there is one. */
+        val iconUrl: String?,
+        val frecency: Long,
+        /** Why this matched, for example `"url"` or `"bookmark"`. */
+        val reasons: List<String>
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): SearchResult {
+            fun stringOrNull(key: String): String? {
+                return if (jsonObject.isNull(key)) null else jsonObject.getString(key)
+            }
+            val reasons = jsonObject.getJSONArray("reasons")
+            return SearchResult(
+                    searchString = jsonObject.getString("searchString"),
+                    url = jsonObject.getString("url"),
+                    displayUrl = jsonObject.getString("displayUrl"),
+                    placeId = if (jsonObject.isNull("placeId")) null else jsonObject.getLong("placeId"),
+                    title = jsonObject.getString("title"),
+                    iconUrl = stringOrNull("iconUrl"),
+                    frecency = jsonObject.getLong("frecency"),
+                    // `tags` reasons are objects (`{"tags": "..."}`), the rest are strings.
+                    reasons = (0 until reasons.length()).map {
+                        val reason = reasons.get(it)
+                        if (reason is JSONObject) "tags" else reason.toString()
+                    }
+            )
+        }
+
+        fun fromJSONArray(jsonArrayText: String): List<SearchResult> {
+            val array = JSONArray(jsonArrayText)
+            return (0 until array.length()).map { fromJSON(array.getJSONObject(it)) }
+        }
+    }
+}
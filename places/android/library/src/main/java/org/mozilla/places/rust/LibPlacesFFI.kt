@@ -0,0 +1,63 @@
+/* Copyright 2018 Mozilla
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+package org.mozilla.places.rust
+
+import com.sun.jna.Library
+import com.sun.jna.Native
+import com.sun.jna.Pointer
+import com.sun.jna.PointerType
+
+@Suppress("FunctionNaming", "TooGenericExceptionThrown")
+internal interface LibPlacesFFI : Library {
+    companion object {
+        private const val JNA_LIBRARY_NAME = "places_ffi"
+        internal var INSTANCE: LibPlacesFFI
+
+        init {
+            INSTANCE = Native.loadLibrary(JNA_LIBRARY_NAME, LibPlacesFFI::class.java) as LibPlacesFFI
+        }
+    }
+
+    // `encryption_key` may be null, for an unencrypted database.
+    fun places_connection_new(
+            db_path: String,
+            encryption_key: String?,
+            error: RustError.ByReference
+    ): RawPlacesConnection?
+
+    fun places_connection_destroy(conn: RawPlacesConnection)
+
+    // Returns a JSON array of matches in a pooled buffer, which must be freed with
+    // places_destroy_pooled_buffer.
+    fun places_query_autocomplete(
+            conn: RawPlacesConnection,
+            search: String,
+            limit: Int,
+            error: RustError.ByReference
+    ): PooledBuffer.ByValue
+
+    fun places_set_autocomplete_match_url_path(
+            conn: RawPlacesConnection,
+            enabled: Byte,
+            error: RustError.ByReference
+    )
+
+    fun places_accept_result(
+            conn: RawPlacesConnection,
+            search: String,
+            url: String,
+            error: RustError.ByReference
+    )
+
+    fun places_destroy_string(s: Pointer)
+
+    fun places_destroy_pooled_buffer(buffer: PooledBuffer.ByValue)
+}
+
+class RawPlacesConnection : PointerType()
@@ -0,0 +1,41 @@
+/* Copyright 2018 Mozilla
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+ * this file except in compliance with the License. You may obtain a copy of the
+ * License at http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software distributed
+ * under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+ * CONDITIONS OF ANY KIND, either express or implied. See the License for the
+ * specific language governing permissions and limitations under the License. */
+package org.mozilla.places.rust
+
+import com.sun.jna.Pointer
+import com.sun.jna.Structure
+import java.util.Arrays
+
+/**
+ * A buffer of bytes from a pool in the Rust code. Its contents are only valid until it's
+ * passed back to `places_destroy_pooled_buffer`, which must be done exactly once, without
+ * changing any of the fields.
+ *
+ * This should be considered private, but it needs to be public for JNA.
+ */
+open class PooledBuffer : Structure() {
+
+    class ByValue : PooledBuffer(), Structure.ByValue
+
+    @JvmField var len: Long = 0
+    @JvmField var data: Pointer? = null
+    @JvmField var capacity: Long = 0
+
+    /**
+     * Decode the contents as UTF-8.
+     */
+    fun asString(): String {
+        val bytes = data?.getByteArray(0, len.toInt()) ?: ByteArray(0)
+        return String(bytes, Charsets.UTF_8)
+    }
+
+    override fun getFieldOrder(): List<String> {
+        return Arrays.asList("len", "data", "capacity")
+    }
+}
@@ -0,0 +1,143 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Autocomplete benchmarks against a synthetic profile.
+//!
+//! Run with `cargo bench -p places --bench search`. Criterion keeps the
+//! results of the previous run around, so to measure a change, run this once
+//! on the base revision and once with the change applied, and criterion will
+//! report the difference for each query.
+
+#[macro_use]
+extern crate criterion;
+extern crate places;
+extern crate rand;
+
+use criterion::Criterion;
+use places::api::matcher::{search_frecent, SearchParams};
+use places::PlacesDb;
+use rand::prelude::*;
+use std::rc::Rc;
+
+const PAGE_COUNT: usize = 50_000;
+const HOST_COUNT: usize = 2_000;
+
+const SYLLABLES: &[&str] = &[
+    "ka", "lo", "mi", "ne", "ru", "sa", "to", "vi", "xe", "zu",
+    "bar", "cor", "dex", "fin", "gal", "hop", "jun", "mox", "pel", "tri",
+];
+
+fn word(rng: &mut StdRng) -> String {
+    let len = rng.gen_range(2, 4);
+    (0..len).map(|_| *rng.choose(SYLLABLES).unwrap()).collect()
+}
+
+/// Fills `db` with `PAGE_COUNT` pages spread over `HOST_COUNT` hosts, along
+/// with some bookmarks and input history. The RNG is seeded, so every run
+/// gets the same profile. Returns the hosts, so that we can search for them.
+fn populate(db: &mut PlacesDb) -> Vec<String> {
+    let mut rng = StdRng::seed_from_u64(1466);
+    let hosts: Vec<String> = (0..HOST_COUNT)
+        .map(|i| {
+            let www = if i % 3 == 0 { "www." } else { "" };
+            format!("{}{}{}.com", www, word(&mut rng), i)
+        })
+        .collect();
+
+    let tx = db.db.transaction().unwrap();
+    {
+        let mut insert_page = tx.prepare("
+            INSERT INTO moz_places(url, url_hash, title, guid, frecency,
+                                   visit_count_local, last_visit_date_local)
+            VALUES(:url, hash(:url), :title, :guid, :frecency, :visit_count, :date)
+        ").unwrap();
+        let mut insert_bookmark = tx.prepare("
+            INSERT INTO moz_bookmarks(fk, title, lastModified)
+            VALUES(:fk, :title, :date)
+        ").unwrap();
+        let mut insert_input = tx.prepare("
+            INSERT OR IGNORE INTO moz_inputhistory(place_id, input, use_count)
+            VALUES(:place_id, :input, :use_count)
+        ").unwrap();
+
+        for i in 0..PAGE_COUNT {
+            let host = rng.choose(&hosts).unwrap().clone();
+            let scheme = if rng.gen_bool(0.8) { "https" } else { "http" };
+            let url = format!("{}://{}/{}/{}?id={}", scheme, host, word(&mut rng), word(&mut rng), i);
+            let title = (0..rng.gen_range(1, 6)).map(|_| word(&mut rng)).collect::<Vec<_>>().join(" ");
+            let frecency = if rng.gen_bool(0.05) { -1 } else { rng.gen_range(1i64, 10_000) };
+            let visit_count = rng.gen_range(0i64, 50);
+            let date = 1_500_000_000_000i64 + rng.gen_range(0i64, 100_000_000_000);
+            let guid = format!("page{:08}", i);
+            insert_page.execute_named(&[
+                (":url", &url),
+                (":title", &title),
+                (":guid", &guid),
+                (":frecency", &frecency),
+                (":visit_count", &visit_count),
+                (":date", &date),
+            ]).unwrap();
+            let place_id = tx.last_insert_rowid();
+
+            if rng.gen_bool(0.05) {
+                insert_bookmark.execute_named(&[
+                    (":fk", &place_id),
+                    (":title", &title),
+                    (":date", &date),
+                ]).unwrap();
+            }
+            if rng.gen_bool(0.04) {
+                let input: String = title.chars().take(rng.gen_range(1, 5)).collect();
+                insert_input.execute_named(&[
+                    (":place_id", &place_id),
+                    (":input", &input),
+                    (":use_count", &rng.gen_range(1i64, 10)),
+                ]).unwrap();
+            }
+        }
+    }
+    tx.commit().unwrap();
+    db.db.execute_batch("ANALYZE").unwrap();
+    hosts
+}
+
+fn bench_search(c: &mut Criterion) {
+    let mut db = PlacesDb::open_in_memory(None).unwrap();
+    let hosts = populate(&mut db);
+    let db = Rc::new(db);
+
+    // Each of these exercises a different provider: origins, URLs, adaptive
+    // history and (for everything) title and URL suggestions. The last one
+    // doesn't match anything, which is the worst case for suggestions, since
+    // we have to check every page.
+    let queries = vec![
+        ("origin", "kalo".to_owned()),
+        ("www_origin", "www.mi".to_owned()),
+        ("url", format!("https://{}/", hosts[0])),
+        ("single_word", "bar".to_owned()),
+        ("multiple_words", "bar tri mi".to_owned()),
+        ("no_match", "qqqqqq".to_owned()),
+    ];
+    // Matching URL paths means normalizing every URL we look at, so also
+    // measure the cheaper host-only matching.
+    for (name, query) in queries {
+        for &match_url_path in &[true, false] {
+            let db = db.clone();
+            let query = query.clone();
+            let suffix = if match_url_path { "" } else { "_host_only" };
+            c.bench_function(&format!("search_frecent_{}{}", name, suffix), move |b| {
+                b.iter(|| {
+                    search_frecent(&db, SearchParams {
+                        search_string: query.clone(),
+                        limit: 10,
+                        match_url_path,
+                    }).unwrap()
+                })
+            });
+        }
+    }
+}
+
+criterion_group!(benches, bench_search);
+criterion_main!(benches);
@@ -527,6 +527,7 @@ mod autocomplete {
                             autocompleter.query(SearchParams {
                                 search_string: query_str.clone(),
                                 limit: 10,
+                                match_url_path: true,
                             })?;
                         }
                     }
@@ -609,6 +610,7 @@ mod autocomplete {
                         autocompleter.query(SearchParams {
                             search_string: query_str.clone(),
                             limit: 10,
+                            match_url_path: true,
                         })?;
                     } else {
                         pending_change = true;
@@ -634,6 +636,7 @@ mod autocomplete {
                     autocompleter.query(SearchParams {
                         search_string: query_str.clone(),
                         limit: 10,
+                        match_url_path: true,
                     })?;
                 }
             }
@@ -0,0 +1,448 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+extern crate serde_json;
+extern crate places;
+extern crate url;
+#[macro_use] extern crate log;
+#[macro_use] extern crate ffi_support;
+#[macro_use] extern crate lazy_static;
+
+#[cfg(target_os = "android")]
+extern crate android_logger;
+
+use std::os::raw::c_char;
+use std::panic::AssertUnwindSafe;
+use std::mem;
+use std::ptr;
+use std::slice;
+
+use ffi_support::{
+    call_with_output, call_with_result, rust_slice_from_c, BufferPool, FfiStr,
+    ExternError, PooledBuffer, PrimitiveBufferI64, PrimitiveBufferU8,
+};
+use places::{api, PlacesDb, Timestamp, VisitObservation};
+use places::api::matcher::{accept_result, search_frecent, SearchParams};
+use places::api::history::ContainerFilter;
+use url::Url;
+
+fn logging_init() {
+    register_component_version!();
+    let registered = ffi_support::register_error_code_space(places::ffi::ERROR_CODES);
+    debug_assert!(registered, "Our error codes overlap another component's");
+    // If the app registered a log callback, our logs already go there.
+    #[cfg(target_os = "android")]
+    {
+        if !ffi_support::foreign_logger_installed() {
+            android_logger::init_once(
+                android_logger::Filter::default().with_min_level(log::Level::Trace),
+                Some("libplaces_ffi"));
+            debug!("Android logging should be hooked up!")
+        }
+    }
+}
+
+// Note: The connection isn't `UnwindSafe`, so we need `AssertUnwindSafe` to
+// pass closures using it to `call_with_result`. This is fine since if a call
+// panics, all we do is report the error. (The transaction it was in the middle
+// of is rolled back when it's dropped during the unwind.)
+
+/// Open a connection to the database at `db_path`. `encryption_key` may be
+/// null, in which case the database isn't encrypted. The connection must be
+/// closed with `places_connection_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn places_connection_new(
+    db_path: FfiStr,
+    encryption_key: FfiStr,
+    error: &mut ExternError,
+) -> *mut PlacesDb {
+    logging_init();
+    trace!("places_connection_new");
+    call_with_result(error, || -> places::Result<_> {
+        let path = db_path.as_str();
+        let key = encryption_key.as_opt_str();
+        Ok(Box::into_raw(Box::new(PlacesDb::open(path, key)?)))
+    })
+}
+
+define_box_destructor!(PlacesDb, places_connection_destroy);
+
+/// Returns a number that changes whenever another connection to the same
+/// database in this process writes to it through these functions. Callers can
+/// remember the generation they last read at, and skip refreshing until it
+/// changes. This doesn't query the database.
+#[no_mangle]
+pub unsafe extern "C" fn places_connection_generation(
+    conn: *const PlacesDb,
+    error: &mut ExternError,
+) -> i64 {
+    trace!("places_connection_generation");
+    call_with_output(error, AssertUnwindSafe(|| {
+        assert!(!conn.is_null(), "Null connection passed to places_connection_generation");
+        let conn = &*conn;
+        conn.generation() as i64
+    }))
+}
+
+/// Stops recording local history on this connection if `enabled` is zero,
+/// or starts again if it's nonzero, for private browsing. While it's off,
+/// `places_note_observation` ignores local visits, and
+/// `places_accept_result` and `places_set_thumbnail` do nothing, but reading
+/// history still works. It's on for a new connection.
+#[no_mangle]
+pub unsafe extern "C" fn places_set_history_recording_enabled(
+    conn: *mut PlacesDb,
+    enabled: u8,
+    error: &mut ExternError,
+) {
+    trace!("places_set_history_recording_enabled");
+    call_with_output(error, AssertUnwindSafe(|| {
+        assert!(!conn.is_null(), "Null connection passed to places_set_history_recording_enabled");
+        let conn = &mut *conn;
+        conn.set_history_recording_enabled(enabled != 0);
+    }))
+}
+
+/// Lets `places_query_autocomplete` match words in the search against the
+/// path and query of URLs, as well as the host, if `enabled` is nonzero. It's
+/// off for a new connection, since matching the host only is faster.
+#[no_mangle]
+pub unsafe extern "C" fn places_set_autocomplete_match_url_path(
+    conn: *mut PlacesDb,
+    enabled: u8,
+    error: &mut ExternError,
+) {
+    trace!("places_set_autocomplete_match_url_path");
+    call_with_output(error, AssertUnwindSafe(|| {
+        assert!(!conn.is_null(), "Null connection passed to places_set_autocomplete_match_url_path");
+        let conn = &mut *conn;
+        conn.set_autocomplete_match_url_path(enabled != 0);
+    }))
+}
+
+/// Record a `VisitObservation`, passed as JSON. For example:
+///
+/// ```json
+/// {"url": "https://example.com/search?q=rust", "visitType": 1, "searchTerm": "rust"}
+/// ```
+#[no_mangle]
+pub unsafe extern "C" fn places_note_observation(
+    conn: *mut PlacesDb,
+    json_observation: FfiStr,
+    error: &mut ExternError,
+) {
+    trace!("places_note_observation");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_note_observation");
+        let conn = &mut *conn;
+        let json = json_observation.as_str();
+        let observation = VisitObservation::from_json(json)?;
+        api::apply_observation(conn, observation)?;
+        conn.notify_changes()?;
+        Ok(())
+    }))
+}
+
+lazy_static! {
+    // Autocomplete runs on every keystroke, and returns about the same amount
+    // of JSON each time, so we reuse the buffers. The application only needs
+    // to hold on to one set of results at a time, but might still be reading
+    // the last one when the next comes in.
+    static ref AUTOCOMPLETE_BUFFERS: BufferPool = BufferPool::new(4, 256 * 1024);
+}
+
+/// Returns up to `limit` autocomplete matches for `search` as a UTF-8 JSON
+/// array. See `places::api::matcher::SearchResult` for the shape of each
+/// item. Whether words in the search can match the path and query of URLs
+/// is up to `places_set_autocomplete_match_url_path`.
+///
+/// The result must be freed with `places_destroy_pooled_buffer`, which lets
+/// us reuse it for the next search.
+#[no_mangle]
+pub unsafe extern "C" fn places_query_autocomplete(
+    conn: *const PlacesDb,
+    search: FfiStr,
+    limit: u32,
+    error: &mut ExternError,
+) -> PooledBuffer {
+    trace!("places_query_autocomplete");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_query_autocomplete");
+        let conn = &*conn;
+        let results = search_frecent(conn, SearchParams {
+            search_string: search.as_str().to_owned(),
+            limit,
+            match_url_path: conn.autocomplete_match_url_path(),
+        })?;
+        let mut buf = AUTOCOMPLETE_BUFFERS.acquire();
+        if let Err(e) = serde_json::to_writer(&mut buf, &results) {
+            AUTOCOMPLETE_BUFFERS.recycle(buf);
+            return Err(e.into());
+        }
+        Ok(AUTOCOMPLETE_BUFFERS.to_ffi(buf))
+    }))
+}
+
+/// Records that the user picked the match for `url` from the autocomplete
+/// results for `search`, so that it ranks higher for similar searches. See
+/// `places::api::matcher::accept_result`.
+#[no_mangle]
+pub unsafe extern "C" fn places_accept_result(
+    conn: *const PlacesDb,
+    search: FfiStr,
+    url: FfiStr,
+    error: &mut ExternError,
+) {
+    trace!("places_accept_result");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_accept_result");
+        let conn = &*conn;
+        let search = search.as_str();
+        let url = Url::parse(url.as_str())?;
+        accept_result(conn, search, &url)?;
+        conn.notify_changes()?;
+        Ok(())
+    }))
+}
+
+/// Returns up to `limit` past searches as a JSON array, most recent first.
+/// See `places::api::history::SearchHistoryEntry` for the shape of each item.
+/// The result must be freed with `places_destroy_string`.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_search_history(
+    conn: *const PlacesDb,
+    limit: u32,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_search_history");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_get_search_history");
+        let conn = &*conn;
+        let history = api::history::get_search_history(conn, limit)?;
+        Ok(serde_json::to_string(&history)?)
+    }))
+}
+
+/// Returns up to `limit` of the terms searched for most often in the last
+/// `days` days as a JSON array, most frequent first. See
+/// `places::api::history::SearchTermStats` for the shape of each item. The
+/// result must be freed with `places_destroy_string`.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_top_search_terms(
+    conn: *const PlacesDb,
+    days: u32,
+    limit: u32,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_top_search_terms");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_get_top_search_terms");
+        let conn = &*conn;
+        let stats = api::history::get_top_search_terms(conn, days, limit)?;
+        Ok(serde_json::to_string(&stats)?)
+    }))
+}
+
+/// Forgets `search_term`, or every search term if it's null, removing it from
+/// the search history and search term stats. The pages themselves stay in
+/// history.
+#[no_mangle]
+pub unsafe extern "C" fn places_clear_search_terms(
+    conn: *const PlacesDb,
+    search_term: FfiStr,
+    error: &mut ExternError,
+) {
+    trace!("places_clear_search_terms");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_clear_search_terms");
+        let conn = &*conn;
+        api::history::clear_search_terms(conn, search_term.as_opt_str())?;
+        conn.notify_changes()?;
+        Ok(())
+    }))
+}
+
+/// Returns the visits made between `start_date` and `end_date` (in
+/// milliseconds since the epoch, inclusive) as a JSON array, most recent
+/// first. See `places::api::history::HistoryVisitInfo` for the shape of each
+/// item. If `container_id` is null, visits in any container are returned; if
+/// it's empty, only those made outside of a container; otherwise only those
+/// made in that container. The result must be freed with
+/// `places_destroy_string`.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_visit_infos(
+    conn: *const PlacesDb,
+    start_date: i64,
+    end_date: i64,
+    container_id: FfiStr,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_get_visit_infos");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_get_visit_infos");
+        let conn = &*conn;
+        let container = match container_id.as_opt_str() {
+            None => ContainerFilter::Any,
+            Some("") => ContainerFilter::NoContainer,
+            Some(id) => ContainerFilter::Container(id),
+        };
+        let visits = api::history::get_visit_infos(
+            conn,
+            Timestamp(start_date.max(0) as u64),
+            Timestamp(end_date.max(0) as u64),
+            container,
+        )?;
+        Ok(serde_json::to_string(&visits)?)
+    }))
+}
+
+/// Check whether each URL in `urls_json` (a JSON array of strings) has been
+/// visited. Returns a buffer with one byte per URL, in the same order: 1 if
+/// it's been visited, and 0 if not. The result must be freed with
+/// `places_destroy_u8_buffer`.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_visited(
+    conn: *const PlacesDb,
+    urls_json: FfiStr,
+    error: &mut ExternError,
+) -> PrimitiveBufferU8 {
+    trace!("places_get_visited");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_get_visited");
+        let conn = &*conn;
+        let urls: Vec<Url> = serde_json::from_str(urls_json.as_str())?;
+        api::history::get_visited(conn, &urls)
+    }))
+}
+
+/// Returns the dates of the visits to `url` between `start_date` and
+/// `end_date` (in milliseconds since the epoch, inclusive), oldest first. The
+/// result must be freed with `places_destroy_i64_buffer`.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_visit_dates(
+    conn: *const PlacesDb,
+    url: FfiStr,
+    start_date: i64,
+    end_date: i64,
+    error: &mut ExternError,
+) -> PrimitiveBufferI64 {
+    trace!("places_get_visit_dates");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_get_visit_dates");
+        let conn = &*conn;
+        let url = Url::parse(url.as_str())?;
+        let dates = api::history::get_visit_dates(
+            conn,
+            &url,
+            Timestamp(start_date.max(0) as u64),
+            Timestamp(end_date.max(0) as u64),
+        )?;
+        Ok(dates.into_iter().map(|date| date.0 as i64).collect::<Vec<i64>>())
+    }))
+}
+
+/// Store `data_len` bytes from `data` as the thumbnail for `url`, replacing
+/// any existing one. Thumbnails are never synced.
+#[no_mangle]
+pub unsafe extern "C" fn places_set_thumbnail(
+    conn: *const PlacesDb,
+    url: FfiStr,
+    data: *const u8,
+    data_len: i32,
+    error: &mut ExternError,
+) {
+    trace!("places_set_thumbnail");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_set_thumbnail");
+        let conn = &*conn;
+        let url = Url::parse(url.as_str())?;
+        api::thumbnails::set_thumbnail(conn, &url, rust_slice_from_c(data, data_len))?;
+        conn.notify_changes()?;
+        Ok(())
+    }))
+}
+
+/// Returns the thumbnail for `url`, storing its length in `out_len`, or null
+/// (with `out_len` set to 0) if there isn't one. A non-null result must be
+/// freed with `places_destroy_thumbnail`, passing the same length.
+#[no_mangle]
+pub unsafe extern "C" fn places_get_thumbnail(
+    conn: *const PlacesDb,
+    url: FfiStr,
+    out_len: &mut i32,
+    error: &mut ExternError,
+) -> *mut u8 {
+    trace!("places_get_thumbnail");
+    *out_len = 0;
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_get_thumbnail");
+        let conn = &*conn;
+        let url = Url::parse(url.as_str())?;
+        Ok(match api::thumbnails::get_thumbnail(conn, &url)? {
+            Some(data) => {
+                // `into_boxed_slice` drops any excess capacity, so that the
+                // destructor can rebuild the allocation from the length alone.
+                let mut data = data.into_boxed_slice();
+                *out_len = data.len() as i32;
+                let ptr = data.as_mut_ptr();
+                mem::forget(data);
+                ptr
+            }
+            None => ptr::null_mut(),
+        })
+    }))
+}
+
+/// Imports the bookmarks in a bookmarks.html file (as exported by most
+/// browsers), adding them to the existing bookmarks. Returns what was
+/// imported as JSON (see `places::api::import::ImportMetrics`), which must be
+/// freed with `places_destroy_string`.
+#[no_mangle]
+pub unsafe extern "C" fn places_import_bookmarks_html(
+    conn: *mut PlacesDb,
+    html: FfiStr,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_import_bookmarks_html");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_import_bookmarks_html");
+        let conn = &mut *conn;
+        let metrics = api::import::import_bookmarks_html(conn, html.as_str())?;
+        conn.notify_changes()?;
+        Ok(serde_json::to_string(&metrics)?)
+    }))
+}
+
+/// Like `places_import_bookmarks_html`, but for a bookmarks backup written by
+/// desktop Firefox, which is JSON.
+#[no_mangle]
+pub unsafe extern "C" fn places_import_bookmarks_json(
+    conn: *mut PlacesDb,
+    json: FfiStr,
+    error: &mut ExternError,
+) -> *mut c_char {
+    trace!("places_import_bookmarks_json");
+    call_with_result(error, AssertUnwindSafe(|| -> places::Result<_> {
+        assert!(!conn.is_null(), "Null connection passed to places_import_bookmarks_json");
+        let conn = &mut *conn;
+        let metrics = api::import::import_bookmarks_json(conn, json.as_str())?;
+        conn.notify_changes()?;
+        Ok(serde_json::to_string(&metrics)?)
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn places_destroy_thumbnail(data: *mut u8, len: i32) {
+    if !data.is_null() {
+        drop(Box::from_raw(slice::from_raw_parts_mut(data, len as usize) as *mut [u8]));
+    }
+}
+
+define_string_destructor!(places_destroy_string);
+define_primitive_buffer_destructor!(places_destroy_u8_buffer, u8);
+define_primitive_buffer_destructor!(places_destroy_i64_buffer, i64);
+define_pooled_buffer_destructor!(places_destroy_pooled_buffer, AUTOCOMPLETE_BUFFERS);
+define_component_versions_query!(places_component_versions);
+define_log_callback_setters!(places_set_log_callback, places_clear_log_callback);
+define_error_code_spaces_query!(places_error_code_spaces);
@@ -9,6 +9,9 @@ use types::*;
 use db::PlacesDb;
 use super::apply_observation;
 use observation::{VisitObservation};
+use rusqlite::Row;
+use rusqlite::types::ToSql;
+use sql_support::ConnExt;
 
 // This module can become, roughly: PlacesUtils.history()
 
@@ -57,10 +60,348 @@ pub fn insert(conn: &mut PlacesDb, place: AddablePlaceInfo) -> Result<()> {
     Ok(())
 }
 
+/// A past search, for showing search history separately from other history.
+/// Pages are considered search results pages if they were observed with a
+/// search term (see `VisitObservation::with_search_term`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHistoryEntry {
+    pub search_term: String,
+    /// The most recently visited results page for this search term.
+    pub url: Url,
+    pub title: String,
+    pub last_visit_date: Timestamp,
+}
+
+impl SearchHistoryEntry {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            search_term: row.get_checked("search_term")?,
+            url: Url::parse(&row.get_checked::<_, String>("url")?)?,
+            title: row.get_checked::<_, Option<String>>("title")?.unwrap_or_default(),
+            last_visit_date: row.get_checked("last_visit_date")?,
+        })
+    }
+}
+
+/// Returns up to `limit` past searches, most recent first. Searching for the
+/// same terms more than once only produces one entry.
+pub fn get_search_history(conn: &PlacesDb, limit: u32) -> Result<Vec<SearchHistoryEntry>> {
+    // SQLite takes the bare columns in an aggregate query from the row with
+    // the maximum value, so `url` and `title` are those of the most recently
+    // visited page for each term.
+    let mut stmt = conn.db.prepare_cached("
+        SELECT search_term, url, title, MAX(last_visit_date) AS last_visit_date
+        FROM (
+            SELECT search_term, url, title,
+                   MAX(IFNULL(last_visit_date_local, 0),
+                       IFNULL(last_visit_date_remote, 0)) AS last_visit_date
+            FROM moz_places
+            WHERE search_term NOT NULL
+        )
+        WHERE last_visit_date > 0
+        GROUP BY search_term
+        ORDER BY last_visit_date DESC
+        LIMIT :limit
+    ")?;
+    let rows = stmt.query_and_then_named(&[(":limit", &limit)], SearchHistoryEntry::from_row)?;
+    rows.collect()
+}
+
+/// How often a search term was searched for, for suggesting frequent searches.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchTermStats {
+    pub search_term: String,
+    /// The number of visits to results pages for this term in the period.
+    pub count: u32,
+    pub last_searched: Timestamp,
+}
+
+impl SearchTermStats {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            search_term: row.get_checked("search_term")?,
+            count: row.get_checked::<_, i64>("count")? as u32,
+            last_searched: row.get_checked("last_searched")?,
+        })
+    }
+}
+
+/// Returns up to `limit` of the terms searched for most often in the last
+/// `days` days, most frequent first. Each visit to a results page counts as
+/// a search, so the same term searched with different engines is counted
+/// together.
+pub fn get_top_search_terms(conn: &PlacesDb, days: u32, limit: u32) -> Result<Vec<SearchTermStats>> {
+    let since = Timestamp(u64::from(Timestamp::now()).saturating_sub(u64::from(days) * 24 * 60 * 60 * 1000));
+    let mut stmt = conn.db.prepare_cached("
+        SELECT p.search_term, COUNT(*) AS count, MAX(v.visit_date) AS last_searched
+        FROM moz_historyvisits v
+        JOIN moz_places p ON p.id = v.place_id
+        WHERE p.search_term NOT NULL
+          AND v.visit_date >= :since
+        GROUP BY p.search_term
+        ORDER BY count DESC, last_searched DESC
+        LIMIT :limit
+    ")?;
+    let rows = stmt.query_and_then_named(&[(":since", &since as &ToSql), (":limit", &limit as &ToSql)],
+                                         SearchTermStats::from_row)?;
+    rows.collect()
+}
+
+/// Forgets search terms, so that they no longer show up in search history or
+/// search term stats. The pages and their visits are kept, as regular
+/// history. If `term` is given, only that term is forgotten; otherwise all of
+/// them are.
+pub fn clear_search_terms(conn: &PlacesDb, term: Option<&str>) -> Result<()> {
+    conn.execute_named_cached("
+        UPDATE moz_places SET search_term = NULL
+        WHERE search_term NOT NULL
+          AND (:term IS NULL OR search_term = :term)",
+        &[(":term", &term as &ToSql)])?;
+    Ok(())
+}
+
+/// Which visits to include, by the container (contextual identity) they were
+/// made in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContainerFilter<'a> {
+    /// All visits, whatever their container.
+    Any,
+    /// Only visits made outside of any container.
+    NoContainer,
+    /// Only visits made in the container with this id.
+    Container(&'a str),
+}
+
+impl<'a> Default for ContainerFilter<'a> {
+    #[inline]
+    fn default() -> Self {
+        ContainerFilter::Any
+    }
+}
+
+/// A single visit, as returned by `get_visit_infos`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryVisitInfo {
+    pub url: Url,
+    pub title: String,
+    pub visit_date: Timestamp,
+    pub visit_type: VisitTransition,
+    pub is_local: bool,
+    /// The container the visit was made in, if any. Always None for visits
+    /// from other devices.
+    pub container_id: Option<String>,
+}
+
+impl HistoryVisitInfo {
+    fn from_row(row: &Row) -> Result<Self> {
+        let visit_type = row.get_checked::<_, i64>("visit_type")?;
+        Ok(Self {
+            url: Url::parse(&row.get_checked::<_, String>("url")?)?,
+            title: row.get_checked::<_, Option<String>>("title")?.unwrap_or_default(),
+            visit_date: row.get_checked("visit_date")?,
+            // Other clients may sync transitions we don't know about.
+            visit_type: VisitTransition::from_primitive(visit_type as u32)
+                .unwrap_or(VisitTransition::Link),
+            is_local: row.get_checked("is_local")?,
+            container_id: row.get_checked("container_id")?,
+        })
+    }
+}
+
+/// Returns the visits made between `start` and `end` (inclusive), most recent
+/// first, optionally only those made in a particular container.
+pub fn get_visit_infos(
+    conn: &PlacesDb,
+    start: Timestamp,
+    end: Timestamp,
+    container: ContainerFilter,
+) -> Result<Vec<HistoryVisitInfo>> {
+    let (container_condition, container_id) = match container {
+        ContainerFilter::Any => ("1", None),
+        ContainerFilter::NoContainer => ("v.container_id IS NULL", None),
+        ContainerFilter::Container(id) => ("v.container_id = :container_id", Some(id)),
+    };
+    let sql = format!("
+        SELECT p.url, p.title, v.visit_date, v.visit_type, v.is_local, v.container_id
+        FROM moz_historyvisits v
+        JOIN moz_places p ON p.id = v.place_id
+        WHERE v.visit_date BETWEEN :start AND :end
+          AND {}
+        ORDER BY v.visit_date DESC",
+        container_condition);
+    let mut params: Vec<(&str, &ToSql)> = vec![(":start", &start as &ToSql), (":end", &end as &ToSql)];
+    if let Some(ref id) = container_id {
+        params.push((":container_id", id as &ToSql));
+    }
+    let mut stmt = conn.db.prepare_cached(&sql)?;
+    let rows = stmt.query_and_then_named(&params, HistoryVisitInfo::from_row)?;
+    rows.collect()
+}
+
+/// Returns whether each of `urls` has been visited, in the same order. This
+/// is for link coloring, which needs to check many URLs at once.
+pub fn get_visited(conn: &PlacesDb, urls: &[Url]) -> Result<Vec<bool>> {
+    let mut stmt = conn.db.prepare_cached("
+        SELECT 1 FROM moz_places
+        WHERE url_hash = hash(:url) AND url = :url
+          AND visit_count_local + visit_count_remote > 0")?;
+    let mut visited = Vec::with_capacity(urls.len());
+    for url in urls {
+        let mut rows = stmt.query_named(&[(":url", &url.as_str() as &ToSql)])?;
+        visited.push(match rows.next() {
+            Some(row) => { row?; true }
+            None => false,
+        });
+    }
+    Ok(visited)
+}
+
+/// Returns the dates of the visits to `url` between `start` and `end`
+/// (inclusive), oldest first.
+pub fn get_visit_dates(conn: &PlacesDb, url: &Url, start: Timestamp, end: Timestamp) -> Result<Vec<Timestamp>> {
+    let mut stmt = conn.db.prepare_cached("
+        SELECT v.visit_date
+        FROM moz_historyvisits v
+        JOIN moz_places p ON p.id = v.place_id
+        WHERE p.url_hash = hash(:url) AND p.url = :url
+          AND v.visit_date BETWEEN :start AND :end
+        ORDER BY v.visit_date")?;
+    let rows = stmt.query_and_then_named(&[(":url", &url.as_str() as &ToSql),
+                                           (":start", &start as &ToSql),
+                                           (":end", &end as &ToSql)],
+                                         |row| -> Result<Timestamp> { Ok(row.get_checked(0)?) })?;
+    rows.collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn search(conn: &mut PlacesDb, url: &str, term: Option<&str>, at: u64) {
+        let obs = VisitObservation::new(Url::parse(url).unwrap())
+                  .with_visit_type(VisitTransition::Link)
+                  .with_at(Timestamp(at))
+                  .with_search_term(term.map(str::to_owned));
+        apply_observation(conn, obs).expect("should apply");
+    }
+
+    #[test]
+    fn test_search_history() {
+        let mut c = PlacesDb::open_in_memory(None).expect("should get a connection");
+        search(&mut c, "https://example.com/search?q=rust", Some("rust"), 1000);
+        search(&mut c, "https://example.com/search?q=sqlite", Some(" sqlite "), 2000);
+        search(&mut c, "https://example.com/about", None, 3000);
+        search(&mut c, "https://example.com/search?q=", Some(""), 4000);
+        // Searching again with another engine replaces the earlier entry.
+        search(&mut c, "https://example.org/?search=rust", Some("rust"), 5000);
+
+        let history = get_search_history(&c, 10).expect("should get history");
+        assert_eq!(history.iter().map(|e| e.search_term.as_str()).collect::<Vec<_>>(),
+                   vec!["rust", "sqlite"]);
+        assert_eq!(history[0].url.as_str(), "https://example.org/?search=rust");
+        assert_eq!(history[0].last_visit_date, Timestamp(5000));
+        assert_eq!(history[1].url.as_str(), "https://example.com/search?q=sqlite");
+
+        assert_eq!(get_search_history(&c, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_search_term_stats() {
+        let mut c = PlacesDb::open_in_memory(None).expect("should get a connection");
+        let now = u64::from(Timestamp::now());
+        let day = 24 * 60 * 60 * 1000;
+        search(&mut c, "https://example.com/search?q=rust", Some("rust"), now - 2 * day);
+        search(&mut c, "https://example.com/search?q=rust", Some("rust"), now - day);
+        search(&mut c, "https://example.org/?search=rust", Some("rust"), now - 1000);
+        search(&mut c, "https://example.com/search?q=sqlite", Some("sqlite"), now - 2000);
+        search(&mut c, "https://example.com/search?q=sqlite", Some("sqlite"), now - 10 * day);
+        search(&mut c, "https://example.com/about", None, now);
+
+        let stats = get_top_search_terms(&c, 7, 10).expect("should get stats");
+        assert_eq!(stats.iter().map(|s| (s.search_term.as_str(), s.count)).collect::<Vec<_>>(),
+                   vec![("rust", 3), ("sqlite", 1)]);
+        assert_eq!(stats[0].last_searched, Timestamp(now - 1000));
+        assert_eq!(get_top_search_terms(&c, 7, 1).unwrap().len(), 1);
+        assert_eq!(get_top_search_terms(&c, 30, 10).unwrap()[1].count, 2);
+
+        clear_search_terms(&c, Some("rust")).expect("should clear one term");
+        let stats = get_top_search_terms(&c, 30, 10).unwrap();
+        assert_eq!(stats.iter().map(|s| s.search_term.as_str()).collect::<Vec<_>>(), vec!["sqlite"]);
+
+        clear_search_terms(&c, None).expect("should clear all terms");
+        assert!(get_top_search_terms(&c, 30, 10).unwrap().is_empty());
+        assert!(get_search_history(&c, 10).unwrap().is_empty());
+        // The visits themselves are kept.
+        assert!(get_visited(&c, &[Url::parse("https://example.com/search?q=rust").unwrap()]).unwrap()[0]);
+    }
+
+    #[test]
+    fn test_visit_containers() {
+        let mut c = PlacesDb::open_in_memory(None).expect("should get a connection");
+        let visit = |c: &mut PlacesDb, url: &str, at: u64, container: Option<&str>, is_remote: bool| {
+            let obs = VisitObservation::new(Url::parse(url).unwrap())
+                      .with_visit_type(VisitTransition::Link)
+                      .with_at(Timestamp(at))
+                      .with_is_remote(is_remote)
+                      .with_container_id(container.map(str::to_owned));
+            apply_observation(c, obs).expect("should apply");
+        };
+        visit(&mut c, "https://example.com/personal", 1000, None, false);
+        visit(&mut c, "https://example.com/work", 2000, Some("work"), false);
+        visit(&mut c, "https://example.com/shopping", 3000, Some("shopping"), false);
+        // Containers are local, so remote visits never have one.
+        visit(&mut c, "https://example.com/remote", 4000, Some("work"), true);
+
+        let urls = |filter: ContainerFilter| -> Vec<String> {
+            get_visit_infos(&c, Timestamp(0), Timestamp(5000), filter)
+                .expect("should get visits")
+                .into_iter()
+                .map(|v| v.url.into_string())
+                .collect()
+        };
+        assert_eq!(urls(ContainerFilter::Any), vec![
+            "https://example.com/remote",
+            "https://example.com/shopping",
+            "https://example.com/work",
+            "https://example.com/personal",
+        ]);
+        assert_eq!(urls(ContainerFilter::NoContainer), vec![
+            "https://example.com/remote",
+            "https://example.com/personal",
+        ]);
+        assert_eq!(urls(ContainerFilter::Container("work")), vec!["https://example.com/work"]);
+
+        let visits = get_visit_infos(&c, Timestamp(2000), Timestamp(2000), ContainerFilter::Any).unwrap();
+        assert_eq!(visits.len(), 1);
+        assert_eq!(visits[0].container_id, Some("work".to_string()));
+        assert!(visits[0].is_local);
+    }
+
+    #[test]
+    fn test_get_visited_and_dates() {
+        let mut c = PlacesDb::open_in_memory(None).expect("should get a connection");
+        let url = |s: &str| Url::parse(s).unwrap();
+        for &at in &[3000, 1000, 2000] {
+            search(&mut c, "https://example.com/visited", None, at);
+        }
+        // Bookmarked, but never visited.
+        c.execute_named("INSERT INTO moz_places (guid, url, url_hash) VALUES ('bookmarkguid', :url, hash(:url))",
+                        &[(":url", &"https://example.com/bookmarked")]).unwrap();
+
+        let urls = vec![url("https://example.com/bookmarked"),
+                        url("https://example.com/visited"),
+                        url("https://example.com/never")];
+        assert_eq!(get_visited(&c, &urls).unwrap(), vec![false, true, false]);
+        assert_eq!(get_visited(&c, &[]).unwrap(), Vec::<bool>::new());
+
+        let dates = get_visit_dates(&c, &urls[1], Timestamp(1500), Timestamp(3000)).unwrap();
+        assert_eq!(dates, vec![Timestamp(2000), Timestamp(3000)]);
+        assert!(get_visit_dates(&c, &urls[0], Timestamp(0), Timestamp(5000)).unwrap().is_empty());
+    }
+
     #[test]
     fn test_insert() {
         let mut c = PlacesDb::open_in_memory(None).expect("should get a connection");
@@ -0,0 +1,538 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Parses Netscape bookmark files, which look like this:
+//
+//   <!DOCTYPE NETSCAPE-Bookmark-file-1>
+//   <TITLE>Bookmarks</TITLE>
+//   <H1>Bookmarks Menu</H1>
+//   <DL><p>
+//       <DT><H3 ADD_DATE="1500000000" PERSONAL_TOOLBAR_FOLDER="true">Toolbar</H3>
+//       <DL><p>
+//           <DT><A HREF="https://example.com/" ADD_DATE="1500000000" TAGS="a,b">Example</A>
+//       </DL><p>
+//       <HR>
+//   </DL><p>
+//
+// Every browser writes these a little differently, and none of them write
+// valid HTML (`<DT>` and `<p>` are never closed), so rather than using an HTML
+// parser, we tokenize tags and text, and only pay attention to `DL`, `H3`, `A`
+// and `HR`. Anything we don't understand is ignored.
+
+use std::char;
+
+use super::{split_tags, ImportedBookmark, ImportedBookmarks, ImportedFolder, ImportedItem};
+use types::Timestamp;
+
+/// Parses a Netscape bookmark file. This never fails: the worst that can
+/// happen is that we don't find any bookmarks.
+pub fn parse_html(html: &str) -> ImportedBookmarks {
+    let mut parser = Parser::default();
+    for token in Tokenizer::new(html) {
+        parser.token(token);
+    }
+    parser.finish()
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    /// An opening tag. The name is uppercased, and so are attribute names.
+    Open(String, Vec<(String, String)>),
+    /// A closing tag, with its name uppercased.
+    Close(String),
+    Text(String),
+}
+
+struct Tokenizer<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn new(html: &'a str) -> Self {
+        Tokenizer { rest: html }
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.rest = &self.rest[n..];
+    }
+
+    // Skips past `end`, or to the end of the input if it's missing.
+    fn skip_past(&mut self, end: &str) {
+        let n = self.rest.find(end).map(|i| i + end.len()).unwrap_or(self.rest.len());
+        self.advance(n);
+    }
+
+    fn skip_whitespace(&mut self) {
+        let n = self.rest.find(|c: char| !c.is_whitespace()).unwrap_or(self.rest.len());
+        self.advance(n);
+    }
+
+    // Reads a tag or attribute name, up to whitespace, `=`, `/` or `>`.
+    fn name(&mut self) -> String {
+        let n = self.rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/' || c == '>')
+            .unwrap_or(self.rest.len());
+        let name = self.rest[..n].to_ascii_uppercase();
+        self.advance(n);
+        name
+    }
+
+    fn attr_value(&mut self) -> String {
+        let rest = self.rest;
+        let (value, len) = match rest.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => {
+                let value = &rest[1..];
+                match value.find(quote) {
+                    Some(n) => (&value[..n], n + 2),
+                    None => (value, rest.len()),
+                }
+            }
+            _ => {
+                let n = rest
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .unwrap_or(rest.len());
+                (&rest[..n], n)
+            }
+        };
+        self.advance(len);
+        decode_entities(value)
+    }
+
+    // Reads the rest of an opening tag, after the `<`.
+    fn open_tag(&mut self) -> Token {
+        let name = self.name();
+        let mut attrs = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.rest.is_empty() {
+                break;
+            }
+            if self.rest.starts_with('>') {
+                self.advance(1);
+                break;
+            }
+            if self.rest.starts_with('/') || self.rest.starts_with('=') {
+                self.advance(1);
+                continue;
+            }
+            let attr = self.name();
+            self.skip_whitespace();
+            let value = if self.rest.starts_with('=') {
+                self.advance(1);
+                self.skip_whitespace();
+                self.attr_value()
+            } else {
+                String::new()
+            };
+            attrs.push((attr, value));
+        }
+        Token::Open(name, attrs)
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        loop {
+            if self.rest.is_empty() {
+                return None;
+            }
+            if self.rest.starts_with("<!--") {
+                self.skip_past("-->");
+                continue;
+            }
+            if self.rest.starts_with("<!") || self.rest.starts_with("<?") {
+                self.skip_past(">");
+                continue;
+            }
+            let mut chars = self.rest.chars();
+            chars.next();
+            if self.rest.starts_with('<') {
+                match chars.next() {
+                    Some('/') => {
+                        self.advance(2);
+                        let name = self.name();
+                        self.skip_past(">");
+                        return Some(Token::Close(name));
+                    }
+                    Some(c) if c.is_ascii_alphabetic() => {
+                        self.advance(1);
+                        return Some(self.open_tag());
+                    }
+                    // A stray `<`, which is just text.
+                    _ => {}
+                }
+            }
+            // Text runs up to the next `<`, not counting the first character,
+            // which might be a stray one.
+            let n = self.rest.len() - chars.as_str().len();
+            let n = n + chars.as_str().find('<').unwrap_or(chars.as_str().len());
+            let text = decode_entities(&self.rest[..n]);
+            self.advance(n);
+            return Some(Token::Text(text));
+        }
+    }
+}
+
+// Decodes the character references that appear in bookmark files. Unknown
+// ones are left alone.
+fn decode_entities(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                    u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32)
+                }
+                _ if entity.starts_with('#') => {
+                    entity[1..].parse().ok().and_then(char::from_u32)
+                }
+                _ => None,
+            };
+            c.map(|c| (c, end + 1))
+        });
+        match decoded {
+            Some((c, len)) => {
+                result.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+// Times in bookmark files are in seconds.
+fn parse_date(attrs: &[(String, String)], name: &str) -> Option<Timestamp> {
+    attr(attrs, name)
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .filter(|&secs| secs > 0)
+        .map(|secs| Timestamp(secs.saturating_mul(1000)))
+}
+
+fn attr<'a>(attrs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attrs.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+}
+
+fn normalize_title(title: &str) -> Option<String> {
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    if title.is_empty() {
+        None
+    } else {
+        Some(title)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Root {
+    Toolbar,
+    Unfiled,
+}
+
+// A `DL` we're inside of.
+#[derive(Default)]
+struct Frame {
+    // The `H3` before the `DL`, if there was one.
+    folder: Option<ImportedFolder>,
+    root: Option<Root>,
+    items: Vec<ImportedItem>,
+}
+
+// What the text we're reading is the title of.
+enum Pending {
+    Bookmark(ImportedBookmark, String),
+    Folder(ImportedFolder, Option<Root>, String),
+}
+
+#[derive(Default)]
+struct Parser {
+    // The first frame is the menu, which is also where anything outside a
+    // `DL` goes.
+    stack: Vec<Frame>,
+    pending: Option<Pending>,
+    // A folder whose `H3` we've read, waiting for its `DL`.
+    folder: Option<(ImportedFolder, Option<Root>)>,
+    result: ImportedBookmarks,
+}
+
+impl Parser {
+    fn token(&mut self, token: Token) {
+        match token {
+            Token::Text(text) => match self.pending {
+                Some(Pending::Bookmark(_, ref mut title)) | Some(Pending::Folder(_, _, ref mut title)) => {
+                    title.push_str(&text);
+                }
+                None => {}
+            },
+            Token::Open(name, attrs) => {
+                // Any tag ends an `A` or `H3`, since they're often not closed
+                // properly.
+                self.finish_pending();
+                match name.as_str() {
+                    "DL" => {
+                        let (folder, root) = match self.folder.take() {
+                            Some((folder, root)) => (Some(folder), root),
+                            None => (None, None),
+                        };
+                        self.stack.push(Frame { folder, root, items: Vec::new() });
+                    }
+                    "H3" => {
+                        let root = if attr(&attrs, "PERSONAL_TOOLBAR_FOLDER").is_some() {
+                            Some(Root::Toolbar)
+                        } else if attr(&attrs, "UNFILED_BOOKMARKS_FOLDER").is_some() {
+                            Some(Root::Unfiled)
+                        } else {
+                            None
+                        };
+                        let folder = ImportedFolder {
+                            date_added: parse_date(&attrs, "ADD_DATE"),
+                            last_modified: parse_date(&attrs, "LAST_MODIFIED"),
+                            ..ImportedFolder::default()
+                        };
+                        self.pending = Some(Pending::Folder(folder, root, String::new()));
+                    }
+                    "A" => match attr(&attrs, "HREF") {
+                        Some(href) => {
+                            let bookmark = ImportedBookmark {
+                                url: href.trim().to_owned(),
+                                date_added: parse_date(&attrs, "ADD_DATE"),
+                                last_modified: parse_date(&attrs, "LAST_MODIFIED"),
+                                tags: attr(&attrs, "TAGS").map(split_tags).unwrap_or_default(),
+                                ..ImportedBookmark::default()
+                            };
+                            self.pending = Some(Pending::Bookmark(bookmark, String::new()));
+                        }
+                        None => self.result.skipped += 1,
+                    },
+                    "HR" => self.push(ImportedItem::Separator),
+                    _ => {}
+                }
+            }
+            Token::Close(name) => match name.as_str() {
+                "A" | "H3" => self.finish_pending(),
+                "DL" => {
+                    self.finish_pending();
+                    self.pop();
+                }
+                _ => {}
+            },
+        }
+    }
+
+    fn finish_pending(&mut self) {
+        match self.pending.take() {
+            None => {}
+            Some(Pending::Bookmark(mut bookmark, title)) => {
+                bookmark.title = normalize_title(&title);
+                self.push(ImportedItem::Bookmark(bookmark));
+            }
+            Some(Pending::Folder(mut folder, root, title)) => {
+                folder.title = normalize_title(&title);
+                self.flush_folder();
+                self.folder = Some((folder, root));
+            }
+        }
+    }
+
+    // A folder without a `DL` after it is empty, so once we see anything
+    // else, it's done.
+    fn flush_folder(&mut self) {
+        if let Some((folder, root)) = self.folder.take() {
+            self.push_folder(folder, root);
+        }
+    }
+
+    fn push(&mut self, item: ImportedItem) {
+        self.flush_folder();
+        match self.stack.last_mut() {
+            Some(frame) => frame.items.push(item),
+            None => self.result.menu.push(item),
+        }
+    }
+
+    fn push_folder(&mut self, folder: ImportedFolder, root: Option<Root>) {
+        match root {
+            // The toolbar and unfiled folders are roots, so only their
+            // contents are imported.
+            Some(Root::Toolbar) => self.result.toolbar.extend(folder.children),
+            Some(Root::Unfiled) => self.result.unfiled.extend(folder.children),
+            None => self.push(ImportedItem::Folder(folder)),
+        }
+    }
+
+    fn pop(&mut self) {
+        self.flush_folder();
+        let frame = match self.stack.pop() {
+            Some(frame) => frame,
+            None => return,
+        };
+        match frame.folder {
+            Some(mut folder) => {
+                folder.children = frame.items;
+                self.push_folder(folder, frame.root);
+            }
+            // A `DL` without a heading, like the outermost one, so its
+            // children belong to whatever it's in.
+            None => match self.stack.last_mut() {
+                Some(parent) => parent.items.extend(frame.items),
+                None => self.result.menu.extend(frame.items),
+            },
+        }
+    }
+
+    fn finish(mut self) -> ImportedBookmarks {
+        self.finish_pending();
+        self.flush_folder();
+        // Close any `DL`s that weren't.
+        while !self.stack.is_empty() {
+            self.pop();
+        }
+        self.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::import::{import_bookmarks_html, ImportMetrics};
+    use db::PlacesDb;
+
+    fn bookmark(url: &str, title: Option<&str>) -> ImportedItem {
+        ImportedItem::Bookmark(ImportedBookmark {
+            url: url.into(),
+            title: title.map(str::to_owned),
+            ..ImportedBookmark::default()
+        })
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        let tokens: Vec<_> = Tokenizer::new(
+            "<!DOCTYPE x><!-- <A> --><dt><a href=\"a&amp;b\" ADD_DATE=1 tags='x'>A &lt; &#66;&#x43; &bogus;</A > 1 < 2"
+        ).collect();
+        assert_eq!(tokens, vec![
+            Token::Open("DT".into(), vec![]),
+            Token::Open("A".into(), vec![
+                ("HREF".into(), "a&b".into()),
+                ("ADD_DATE".into(), "1".into()),
+                ("TAGS".into(), "x".into()),
+            ]),
+            Token::Text("A < BC &bogus;".into()),
+            Token::Close("A".into()),
+            Token::Text(" 1 ".into()),
+            Token::Text("< 2".into()),
+        ]);
+    }
+
+    #[test]
+    fn test_parse() {
+        let html = r#"
+            <!DOCTYPE NETSCAPE-Bookmark-file-1>
+            <META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+            <TITLE>Bookmarks</TITLE>
+            <H1>Bookmarks Menu</H1>
+            <DL><p>
+                <DT><A HREF="https://www.mozilla.org/" ADD_DATE="1500000000" LAST_MODIFIED="1500000001" TAGS="moz, browsers">Mozilla
+                    Home</A>
+                <DD>A description, which we ignore.
+                <HR>
+                <DT><H3 ADD_DATE="1500000000">Rust &amp; friends</H3>
+                <DL><p>
+                    <DT><A HREF="https://www.rust-lang.org/">Rust</A>
+                    <DT><H3>Empty</H3>
+                    <DT><A>No URL</A>
+                </DL><p>
+                <DT><H3 PERSONAL_TOOLBAR_FOLDER="true">Bookmarks Toolbar</H3>
+                <DL><p>
+                    <DT><A HREF="https://example.com/"></A>
+                </DL><p>
+                <DT><H3 UNFILED_BOOKMARKS_FOLDER="true">Other Bookmarks</H3>
+                <DL><p>
+                    <DT><A HREF="https://example.org/">Unfiled
+                </DL><p>
+            </DL>
+        "#;
+        assert_eq!(parse_html(html), ImportedBookmarks {
+            menu: vec![
+                ImportedItem::Bookmark(ImportedBookmark {
+                    url: "https://www.mozilla.org/".into(),
+                    title: Some("Mozilla Home".into()),
+                    date_added: Some(Timestamp(1_500_000_000_000)),
+                    last_modified: Some(Timestamp(1_500_000_001_000)),
+                    tags: vec!["moz".into(), "browsers".into()],
+                }),
+                ImportedItem::Separator,
+                ImportedItem::Folder(ImportedFolder {
+                    title: Some("Rust & friends".into()),
+                    date_added: Some(Timestamp(1_500_000_000_000)),
+                    last_modified: None,
+                    children: vec![
+                        bookmark("https://www.rust-lang.org/", Some("Rust")),
+                        ImportedItem::Folder(ImportedFolder {
+                            title: Some("Empty".into()),
+                            ..ImportedFolder::default()
+                        }),
+                    ],
+                }),
+            ],
+            toolbar: vec![bookmark("https://example.com/", None)],
+            unfiled: vec![bookmark("https://example.org/", Some("Unfiled"))],
+            mobile: vec![],
+            skipped: 1,
+        });
+    }
+
+    #[test]
+    fn test_parse_garbage() {
+        assert_eq!(parse_html(""), ImportedBookmarks::default());
+        assert_eq!(parse_html("<DL><DL></DL></DL></DL>"), ImportedBookmarks::default());
+        // Unclosed lists and headings still get imported.
+        assert_eq!(parse_html("<DL><DT><H3>Folder<DL><DT><A HREF=x>X"), ImportedBookmarks {
+            menu: vec![ImportedItem::Folder(ImportedFolder {
+                title: Some("Folder".into()),
+                children: vec![bookmark("x", Some("X"))],
+                ..ImportedFolder::default()
+            })],
+            ..ImportedBookmarks::default()
+        });
+    }
+
+    #[test]
+    fn test_import_html() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let html = r#"
+            <DL><p>
+                <DT><H3 PERSONAL_TOOLBAR_FOLDER="true">Bookmarks Toolbar</H3>
+                <DL><p>
+                    <DT><A HREF="https://example.com/" TAGS="a">Example</A>
+                </DL><p>
+                <DT><A HREF="https://www.mozilla.org/">Mozilla</A>
+            </DL>
+        "#;
+        assert_eq!(import_bookmarks_html(&mut db, html).unwrap(), ImportMetrics {
+            bookmarks_added: 2,
+            tags_added: 1,
+            ..ImportMetrics::default()
+        });
+        assert_eq!(import_bookmarks_html(&mut db, html).unwrap(), ImportMetrics {
+            duplicates_skipped: 2,
+            ..ImportMetrics::default()
+        });
+    }
+}
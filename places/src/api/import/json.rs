@@ -0,0 +1,237 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Parses the bookmark backups desktop writes (to `bookmarkbackups/*.json`,
+// and when the user chooses "Backup..." in the Library), which are a tree
+// of items, like this:
+//
+//   {"guid": "root________", "type": "text/x-moz-place-container",
+//    "root": "placesRoot", "children": [
+//      {"guid": "menu________", "type": "text/x-moz-place-container",
+//       "root": "bookmarksMenuFolder", "children": [
+//         {"type": "text/x-moz-place", "title": "Example",
+//          "uri": "https://example.com/", "tags": "a,b",
+//          "dateAdded": 1500000000000000, "lastModified": 1500000000000000},
+//         {"type": "text/x-moz-place-separator"},
+//       ]},
+//      ...
+//   ]}
+//
+// We only look at the fields we need, so this works with backups from any
+// version of desktop.
+
+use serde_json;
+
+use super::{split_tags, ImportedBookmark, ImportedBookmarks, ImportedFolder, ImportedItem};
+use error::*;
+use types::Timestamp;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BackupItem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    root: Option<String>,
+    // These are in microseconds.
+    #[serde(default)]
+    date_added: Option<u64>,
+    #[serde(default)]
+    last_modified: Option<u64>,
+    #[serde(default)]
+    uri: Option<String>,
+    #[serde(default)]
+    tags: Option<String>,
+    #[serde(default)]
+    children: Vec<BackupItem>,
+}
+
+/// Parses a bookmarks backup written by desktop. Fails if `json` isn't JSON,
+/// or isn't shaped like a backup at all, but skips items it doesn't
+/// understand.
+pub fn parse_json_backup(json: &str) -> Result<ImportedBookmarks> {
+    let item: BackupItem = serde_json::from_str(json)?;
+    let mut result = ImportedBookmarks::default();
+    if item.root.as_ref().map(String::as_str) == Some("placesRoot") {
+        for child in item.children {
+            add_to_root(&mut result, child);
+        }
+    } else {
+        // A backup of a single root or folder.
+        add_to_root(&mut result, item);
+    }
+    Ok(result)
+}
+
+fn add_to_root(result: &mut ImportedBookmarks, item: BackupItem) {
+    let root = match item.root.as_ref().map(String::as_str) {
+        Some("bookmarksMenuFolder") => &mut result.menu,
+        Some("toolbarFolder") => &mut result.toolbar,
+        Some("unfiledBookmarksFolder") => &mut result.unfiled,
+        Some("mobileFolder") => &mut result.mobile,
+        // Tags are imported from the `tags` of each bookmark instead.
+        Some("tagsFolder") => return,
+        // Anything else that isn't in a root goes in the menu.
+        _ => {
+            if let Some(item) = convert(item, &mut result.skipped) {
+                result.menu.push(item);
+            }
+            return;
+        }
+    };
+    let skipped = &mut result.skipped;
+    root.extend(item.children.into_iter().filter_map(|child| convert(child, skipped)));
+}
+
+fn convert(item: BackupItem, skipped: &mut u32) -> Option<ImportedItem> {
+    let date_added = to_timestamp(item.date_added);
+    let last_modified = to_timestamp(item.last_modified);
+    let title = item.title.filter(|title| !title.is_empty());
+    Some(match item.kind.as_str() {
+        "text/x-moz-place" => match item.uri {
+            Some(url) => ImportedItem::Bookmark(ImportedBookmark {
+                url,
+                title,
+                date_added,
+                last_modified,
+                tags: item.tags.as_ref().map(|tags| split_tags(tags)).unwrap_or_default(),
+            }),
+            None => {
+                *skipped += 1;
+                return None;
+            }
+        },
+        "text/x-moz-place-container" => ImportedItem::Folder(ImportedFolder {
+            title,
+            date_added,
+            last_modified,
+            children: item.children.into_iter().filter_map(|child| convert(child, skipped)).collect(),
+        }),
+        "text/x-moz-place-separator" => ImportedItem::Separator,
+        _ => {
+            *skipped += 1;
+            return None;
+        }
+    })
+}
+
+fn to_timestamp(micros: Option<u64>) -> Option<Timestamp> {
+    micros.map(|micros| micros / 1000).filter(|&millis| millis > 0).map(Timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::import::{import_bookmarks_json, ImportMetrics};
+    use db::PlacesDb;
+
+    const BACKUP: &str = r#"{
+        "guid": "root________", "title": "", "index": 0, "id": 1, "typeCode": 2,
+        "type": "text/x-moz-place-container", "root": "placesRoot",
+        "dateAdded": 1500000000000000, "lastModified": 1500000000000000,
+        "children": [
+            {"guid": "menu________", "title": "menu", "type": "text/x-moz-place-container",
+             "root": "bookmarksMenuFolder", "children": [
+                {"guid": "bookmark1___", "title": "Example", "type": "text/x-moz-place",
+                 "uri": "https://example.com/", "tags": "a,b", "keyword": "ex",
+                 "dateAdded": 1500000000000000, "lastModified": 1500000001000000},
+                {"guid": "separator1__", "type": "text/x-moz-place-separator"},
+                {"guid": "folder1_____", "title": "Folder", "type": "text/x-moz-place-container",
+                 "children": [
+                    {"title": "No URL", "type": "text/x-moz-place"},
+                    {"title": "Mozilla", "type": "text/x-moz-place", "uri": "https://www.mozilla.org/"}
+                 ]},
+                {"title": "From the future", "type": "text/x-moz-place-something-new"}
+             ]},
+            {"guid": "toolbar_____", "title": "toolbar", "type": "text/x-moz-place-container",
+             "root": "toolbarFolder", "children": [
+                {"title": "Toolbar", "type": "text/x-moz-place", "uri": "https://example.org/"}
+             ]},
+            {"guid": "tags________", "title": "tags", "type": "text/x-moz-place-container",
+             "root": "tagsFolder", "children": [
+                {"title": "a", "type": "text/x-moz-place-container", "children": [
+                    {"type": "text/x-moz-place", "uri": "https://example.com/"}
+                ]}
+             ]},
+            {"guid": "unfiled_____", "title": "unfiled", "type": "text/x-moz-place-container",
+             "root": "unfiledBookmarksFolder"},
+            {"guid": "mobile______", "title": "mobile", "type": "text/x-moz-place-container",
+             "root": "mobileFolder", "children": [
+                {"title": "", "type": "text/x-moz-place", "uri": "https://m.example.com/"}
+             ]}
+        ]
+    }"#;
+
+    fn bookmark(url: &str, title: Option<&str>) -> ImportedItem {
+        ImportedItem::Bookmark(ImportedBookmark {
+            url: url.into(),
+            title: title.map(str::to_owned),
+            ..ImportedBookmark::default()
+        })
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_json_backup(BACKUP).unwrap(), ImportedBookmarks {
+            menu: vec![
+                ImportedItem::Bookmark(ImportedBookmark {
+                    url: "https://example.com/".into(),
+                    title: Some("Example".into()),
+                    date_added: Some(Timestamp(1_500_000_000_000)),
+                    last_modified: Some(Timestamp(1_500_000_001_000)),
+                    tags: vec!["a".into(), "b".into()],
+                }),
+                ImportedItem::Separator,
+                ImportedItem::Folder(ImportedFolder {
+                    title: Some("Folder".into()),
+                    children: vec![bookmark("https://www.mozilla.org/", Some("Mozilla"))],
+                    ..ImportedFolder::default()
+                }),
+            ],
+            toolbar: vec![bookmark("https://example.org/", Some("Toolbar"))],
+            unfiled: vec![],
+            mobile: vec![bookmark("https://m.example.com/", None)],
+            skipped: 2,
+        });
+    }
+
+    #[test]
+    fn test_parse_folder() {
+        // Backups of something other than the root go in the menu.
+        let json = r#"{"title": "Folder", "type": "text/x-moz-place-container", "children": [
+            {"type": "text/x-moz-place", "uri": "https://example.com/"}
+        ]}"#;
+        assert_eq!(parse_json_backup(json).unwrap(), ImportedBookmarks {
+            menu: vec![ImportedItem::Folder(ImportedFolder {
+                title: Some("Folder".into()),
+                children: vec![bookmark("https://example.com/", None)],
+                ..ImportedFolder::default()
+            })],
+            ..ImportedBookmarks::default()
+        });
+        assert!(parse_json_backup("42").is_err());
+        assert!(parse_json_backup("{").is_err());
+    }
+
+    #[test]
+    fn test_import_json() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        assert_eq!(import_bookmarks_json(&mut db, BACKUP).unwrap(), ImportMetrics {
+            bookmarks_added: 4,
+            folders_added: 1,
+            separators_added: 1,
+            tags_added: 2,
+            invalid_skipped: 2,
+            ..ImportMetrics::default()
+        });
+        assert_eq!(import_bookmarks_json(&mut db, BACKUP).unwrap(), ImportMetrics {
+            folders_merged: 1,
+            duplicates_skipped: 5,
+            invalid_skipped: 2,
+            ..ImportMetrics::default()
+        });
+    }
+}
@@ -0,0 +1,475 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Importing bookmarks, for users migrating from another browser (which can
+// export a bookmarks.html file), or from desktop Firefox (which also keeps
+// JSON backups).
+//
+// Parsing produces an `ImportedBookmarks` tree, which `import_bookmarks` then
+// adds to the bookmarks tree, without replacing what's there. Importing the
+// same file twice shouldn't duplicate everything, so:
+//
+// - A folder is merged into an existing folder with the same title and
+//   parent, rather than added again.
+// - A bookmark is skipped if its parent already has one for the same URL.
+// - A separator is skipped if its parent had any children before the import,
+//   since we can't tell if it's a duplicate.
+//
+// Tags are added to the URL regardless, since tagging is idempotent.
+// Keywords aren't imported, since we don't support them.
+
+use std::collections::HashSet;
+
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+use sql_support::ConnExt;
+use sync::util::random_guid;
+use sync_guid::Guid;
+use url::Url;
+
+use db::PlacesDb;
+use error::*;
+use frecency;
+use storage::{self, RowId};
+use types::{BookmarkType, Timestamp};
+
+mod html;
+mod json;
+
+pub use self::html::parse_html;
+pub use self::json::parse_json_backup;
+
+/// A bookmark, folder or separator read from a bookmarks file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportedItem {
+    Bookmark(ImportedBookmark),
+    Folder(ImportedFolder),
+    Separator,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportedBookmark {
+    /// Not parsed yet, so that one bad URL doesn't fail the whole file.
+    pub url: String,
+    pub title: Option<String>,
+    pub date_added: Option<Timestamp>,
+    pub last_modified: Option<Timestamp>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportedFolder {
+    pub title: Option<String>,
+    pub date_added: Option<Timestamp>,
+    pub last_modified: Option<Timestamp>,
+    pub children: Vec<ImportedItem>,
+}
+
+/// Everything read from a bookmarks file, by the root it goes in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportedBookmarks {
+    pub menu: Vec<ImportedItem>,
+    pub toolbar: Vec<ImportedItem>,
+    pub unfiled: Vec<ImportedItem>,
+    pub mobile: Vec<ImportedItem>,
+    /// The number of items the parser skipped, because they were missing a
+    /// URL, or of a type we don't understand.
+    pub skipped: u32,
+}
+
+/// What `import_bookmarks` did. This is also what the FFI returns, as JSON.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportMetrics {
+    pub bookmarks_added: u32,
+    pub folders_added: u32,
+    pub separators_added: u32,
+    /// The number of tags added to URLs. Tags a URL already had aren't
+    /// counted.
+    pub tags_added: u32,
+    /// Folders merged into an existing folder with the same title.
+    pub folders_merged: u32,
+    /// Bookmarks and separators skipped as duplicates.
+    pub duplicates_skipped: u32,
+    /// Items skipped because they were invalid: either the parser didn't
+    /// understand them, or their URL couldn't be parsed.
+    pub invalid_skipped: u32,
+}
+
+/// Imports the bookmarks in `html`, which is a Netscape bookmark file (as
+/// exported by almost every browser).
+pub fn import_bookmarks_html(db: &mut PlacesDb, html: &str) -> Result<ImportMetrics> {
+    import_bookmarks(db, &parse_html(html))
+}
+
+/// Imports the bookmarks in `json`, which is a bookmarks backup written by
+/// desktop Firefox.
+pub fn import_bookmarks_json(db: &mut PlacesDb, json: &str) -> Result<ImportMetrics> {
+    import_bookmarks(db, &parse_json_backup(json)?)
+}
+
+/// Adds `bookmarks` to the bookmarks tree, in a single transaction.
+pub fn import_bookmarks(db: &mut PlacesDb, bookmarks: &ImportedBookmarks) -> Result<ImportMetrics> {
+    let tx = db.db.transaction()?;
+    let metrics = {
+        let mut importer = Importer {
+            db: &tx,
+            now: Timestamp::now(),
+            tags_root: fetch_root_id(&tx, &Guid::TAGS)?,
+            metrics: ImportMetrics {
+                invalid_skipped: bookmarks.skipped,
+                ..ImportMetrics::default()
+            },
+            bookmarked_pages: HashSet::new(),
+        };
+        for &(ref guid, items) in &[(Guid::MENU, &bookmarks.menu),
+                                    (Guid::TOOLBAR, &bookmarks.toolbar),
+                                    (Guid::UNFILED, &bookmarks.unfiled),
+                                    (Guid::MOBILE, &bookmarks.mobile)] {
+            let root = fetch_root_id(&tx, guid)?;
+            let had_children = importer.has_children(root)?;
+            importer.import_items(root, had_children, items)?;
+        }
+        importer.update_frecencies()?;
+        importer.metrics
+    };
+    tx.commit()?;
+    Ok(metrics)
+}
+
+fn fetch_root_id(db: &Connection, guid: &Guid) -> Result<RowId> {
+    Ok(db.query_row_and_then_named(
+        "SELECT id FROM moz_bookmarks WHERE guid = :guid",
+        &[(":guid", guid as &ToSql)],
+        |row| row.get_checked(0),
+        true)?)
+}
+
+/// Splits a comma separated list of tags, like both formats use.
+fn split_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+struct Importer<'a> {
+    db: &'a Connection,
+    now: Timestamp,
+    tags_root: RowId,
+    metrics: ImportMetrics,
+    // The pages we added bookmarks for, whose frecency needs updating.
+    bookmarked_pages: HashSet<i64>,
+}
+
+impl<'a> Importer<'a> {
+    // `had_children` says whether `parent` had any children before we started
+    // importing into it.
+    fn import_items(&mut self, parent: RowId, had_children: bool, items: &[ImportedItem]) -> Result<()> {
+        for item in items {
+            match item {
+                ImportedItem::Bookmark(bookmark) => self.import_bookmark(parent, bookmark)?,
+                ImportedItem::Folder(folder) => self.import_folder(parent, folder)?,
+                ImportedItem::Separator => {
+                    if had_children {
+                        self.metrics.duplicates_skipped += 1;
+                    } else {
+                        self.insert_item(BookmarkType::Separator, None, parent, None, None, None)?;
+                        self.metrics.separators_added += 1;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn import_bookmark(&mut self, parent: RowId, bookmark: &ImportedBookmark) -> Result<()> {
+        let url = match Url::parse(&bookmark.url) {
+            // `place:` URLs are queries, like desktop's "Most Visited", which
+            // we don't support.
+            Ok(ref url) if url.scheme() == "place" => {
+                self.metrics.invalid_skipped += 1;
+                return Ok(());
+            }
+            Ok(url) => url,
+            Err(e) => {
+                debug!("Skipping bookmark with invalid URL: {}", e);
+                self.metrics.invalid_skipped += 1;
+                return Ok(());
+            }
+        };
+        let page_id = storage::fetch_or_insert_page_id(self.db, &url)?;
+        if self.has_child_for_page(parent, page_id)? {
+            self.metrics.duplicates_skipped += 1;
+        } else {
+            self.insert_item(BookmarkType::Bookmark, Some(page_id), parent,
+                             bookmark.title.as_ref().map(String::as_str),
+                             bookmark.date_added, bookmark.last_modified)?;
+            self.metrics.bookmarks_added += 1;
+        }
+        for tag in &bookmark.tags {
+            self.tag_page(page_id, tag)?;
+        }
+        Ok(())
+    }
+
+    fn import_folder(&mut self, parent: RowId, folder: &ImportedFolder) -> Result<()> {
+        let title = folder.title.as_ref().map(String::as_str).unwrap_or("");
+        let (id, had_children) = match self.fetch_child_folder(parent, title)? {
+            Some(id) => {
+                self.metrics.folders_merged += 1;
+                (id, self.has_children(id)?)
+            }
+            None => {
+                let id = self.insert_item(BookmarkType::Folder, None, parent, folder.title.as_ref().map(String::as_str),
+                                          folder.date_added, folder.last_modified)?;
+                self.metrics.folders_added += 1;
+                (id, false)
+            }
+        };
+        self.import_items(id, had_children, &folder.children)
+    }
+
+    // Tags are stored like desktop does: as a bookmark for the page, in a
+    // folder named after the tag, under the tags root.
+    fn tag_page(&mut self, page_id: RowId, tag: &str) -> Result<()> {
+        let folder = match self.fetch_child_folder(self.tags_root, tag)? {
+            Some(id) => id,
+            None => self.insert_item(BookmarkType::Folder, None, self.tags_root, Some(tag), None, None)?,
+        };
+        if !self.has_child_for_page(folder, page_id)? {
+            self.insert_item(BookmarkType::Bookmark, Some(page_id), folder, None, None, None)?;
+            self.metrics.tags_added += 1;
+        }
+        Ok(())
+    }
+
+    fn insert_item(
+        &mut self,
+        kind: BookmarkType,
+        page_id: Option<RowId>,
+        parent: RowId,
+        title: Option<&str>,
+        date_added: Option<Timestamp>,
+        last_modified: Option<Timestamp>,
+    ) -> Result<RowId> {
+        let guid = random_guid().expect("according to logins-sql, this is fine :)");
+        let date_added = date_added.unwrap_or(self.now);
+        let last_modified = last_modified.unwrap_or(date_added).max(date_added);
+        self.db.execute_named_cached("
+            INSERT INTO moz_bookmarks(type, fk, parent, position, title, dateAdded, lastModified, guid)
+            VALUES (:type, :fk, :parent,
+                    (SELECT IFNULL(MAX(position) + 1, 0) FROM moz_bookmarks
+                     WHERE parent = :parent),
+                    :title, :dateAdded, :lastModified, :guid)",
+            &[(":type", &kind as &ToSql),
+              (":fk", &page_id as &ToSql),
+              (":parent", &parent as &ToSql),
+              (":title", &title as &ToSql),
+              (":dateAdded", &date_added as &ToSql),
+              (":lastModified", &last_modified as &ToSql),
+              (":guid", &guid as &ToSql)])?;
+        let id = RowId(self.db.last_insert_rowid());
+        if let Some(page_id) = page_id {
+            self.db.execute_named_cached("
+                UPDATE moz_places SET foreign_count = foreign_count + 1
+                WHERE id = :page_id",
+                &[(":page_id", &page_id as &ToSql)])?;
+            self.bookmarked_pages.insert(page_id.0);
+        }
+        Ok(id)
+    }
+
+    fn fetch_child_folder(&self, parent: RowId, title: &str) -> Result<Option<RowId>> {
+        Ok(self.db.try_query_row("
+            SELECT id FROM moz_bookmarks
+            WHERE parent = :parent AND type = :type AND IFNULL(title, '') = :title
+            ORDER BY position
+            LIMIT 1",
+            &[(":parent", &parent as &ToSql),
+              (":type", &BookmarkType::Folder as &ToSql),
+              (":title", &title as &ToSql)],
+            |row| row.get_checked(0),
+            true)?)
+    }
+
+    fn has_child_for_page(&self, parent: RowId, page_id: RowId) -> Result<bool> {
+        Ok(self.db.try_query_row("
+            SELECT 1 FROM moz_bookmarks WHERE parent = :parent AND fk = :page_id",
+            &[(":parent", &parent as &ToSql), (":page_id", &page_id as &ToSql)],
+            |row| row.get_checked::<_, i64>(0),
+            true)?.is_some())
+    }
+
+    fn has_children(&self, parent: RowId) -> Result<bool> {
+        Ok(self.db.try_query_row("
+            SELECT 1 FROM moz_bookmarks WHERE parent = :parent",
+            &[(":parent", &parent as &ToSql)],
+            |row| row.get_checked::<_, i64>(0),
+            true)?.is_some())
+    }
+
+    // Bookmarked pages get a frecency boost.
+    fn update_frecencies(&self) -> Result<()> {
+        for &page_id in &self.bookmarked_pages {
+            let frecency = frecency::calculate_frecency(
+                self.db, &frecency::DEFAULT_FRECENCY_SETTINGS, page_id, None)?;
+            self.db.execute_named_cached("
+                UPDATE moz_places SET frecency = :frecency WHERE id = :page_id",
+                &[(":frecency", &frecency as &ToSql), (":page_id", &page_id as &ToSql)])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The children of the folder with `guid`, in order, as `(type, title,
+    // url)`.
+    fn fetch_children(db: &PlacesDb, guid: &str) -> Vec<(BookmarkType, Option<String>, Option<String>)> {
+        let mut stmt = db.prepare("
+            SELECT b.type, b.title, h.url
+            FROM moz_bookmarks b
+            JOIN moz_bookmarks p ON p.id = b.parent
+            LEFT JOIN moz_places h ON h.id = b.fk
+            WHERE p.guid = :guid
+            ORDER BY b.position").unwrap();
+        let rows = stmt.query_map_named(&[(":guid", &guid as &ToSql)], |row| {
+            (row.get(0), row.get(1), row.get(2))
+        }).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    fn fetch_folder_guid(db: &PlacesDb, parent_guid: &str, title: &str) -> String {
+        db.query_row_named("
+            SELECT b.guid FROM moz_bookmarks b
+            JOIN moz_bookmarks p ON p.id = b.parent
+            WHERE p.guid = :parent AND b.title = :title",
+            &[(":parent", &parent_guid as &ToSql), (":title", &title as &ToSql)],
+            |row| row.get(0)).unwrap()
+    }
+
+    fn bookmark(url: &str, title: &str, tags: &[&str]) -> ImportedItem {
+        ImportedItem::Bookmark(ImportedBookmark {
+            url: url.into(),
+            title: Some(title.into()),
+            tags: tags.iter().map(|&tag| tag.to_owned()).collect(),
+            ..ImportedBookmark::default()
+        })
+    }
+
+    fn folder(title: &str, children: Vec<ImportedItem>) -> ImportedItem {
+        ImportedItem::Folder(ImportedFolder {
+            title: Some(title.into()),
+            children,
+            ..ImportedFolder::default()
+        })
+    }
+
+    fn sample() -> ImportedBookmarks {
+        ImportedBookmarks {
+            menu: vec![
+                bookmark("https://www.mozilla.org/", "Mozilla", &["moz"]),
+                ImportedItem::Separator,
+                folder("Rust", vec![
+                    bookmark("https://www.rust-lang.org/", "Rust", &["moz", "lang"]),
+                    bookmark("not a url", "Invalid", &[]),
+                    bookmark("place:sort=8&maxResults=10", "Most Visited", &[]),
+                ]),
+            ],
+            toolbar: vec![bookmark("https://example.com/", "Example", &[])],
+            skipped: 1,
+            ..ImportedBookmarks::default()
+        }
+    }
+
+    #[test]
+    fn test_import() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let metrics = import_bookmarks(&mut db, &sample()).unwrap();
+        assert_eq!(metrics, ImportMetrics {
+            bookmarks_added: 3,
+            folders_added: 1,
+            separators_added: 1,
+            tags_added: 3,
+            folders_merged: 0,
+            duplicates_skipped: 0,
+            invalid_skipped: 3,
+        });
+
+        assert_eq!(fetch_children(&db, Guid::MENU.as_str()), vec![
+            (BookmarkType::Bookmark, Some("Mozilla".into()), Some("https://www.mozilla.org/".into())),
+            (BookmarkType::Separator, None, None),
+            (BookmarkType::Folder, Some("Rust".into()), None),
+        ]);
+        let rust = fetch_folder_guid(&db, Guid::MENU.as_str(), "Rust");
+        assert_eq!(fetch_children(&db, &rust), vec![
+            (BookmarkType::Bookmark, Some("Rust".into()), Some("https://www.rust-lang.org/".into())),
+        ]);
+        assert_eq!(fetch_children(&db, Guid::TOOLBAR.as_str()), vec![
+            (BookmarkType::Bookmark, Some("Example".into()), Some("https://example.com/".into())),
+        ]);
+
+        let tags = fetch_children(&db, Guid::TAGS.as_str());
+        assert_eq!(tags, vec![
+            (BookmarkType::Folder, Some("moz".into()), None),
+            (BookmarkType::Folder, Some("lang".into()), None),
+        ]);
+        let moz = fetch_folder_guid(&db, Guid::TAGS.as_str(), "moz");
+        assert_eq!(fetch_children(&db, &moz).len(), 2);
+
+        // Bookmarked pages count as such for autocomplete and frecency. The
+        // Rust page is bookmarked once, and tagged twice.
+        let (foreign_count, frecency): (i64, i64) = db.query_row_named("
+            SELECT foreign_count, frecency FROM moz_places WHERE url = :url",
+            &[(":url", &"https://www.rust-lang.org/" as &ToSql)],
+            |row| (row.get(0), row.get(1))).unwrap();
+        assert_eq!(foreign_count, 3);
+        assert!(frecency > 0);
+    }
+
+    #[test]
+    fn test_import_twice() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        import_bookmarks(&mut db, &sample()).unwrap();
+        let menu = fetch_children(&db, Guid::MENU.as_str());
+
+        // Nothing is added the second time.
+        let metrics = import_bookmarks(&mut db, &sample()).unwrap();
+        assert_eq!(metrics, ImportMetrics {
+            folders_merged: 1,
+            duplicates_skipped: 4,
+            invalid_skipped: 3,
+            ..ImportMetrics::default()
+        });
+        assert_eq!(fetch_children(&db, Guid::MENU.as_str()), menu);
+
+        // But new items in a merged folder are.
+        let metrics = import_bookmarks(&mut db, &ImportedBookmarks {
+            menu: vec![folder("Rust", vec![
+                ImportedItem::Separator,
+                bookmark("https://crates.io/", "crates.io", &["lang"]),
+            ])],
+            ..ImportedBookmarks::default()
+        }).unwrap();
+        assert_eq!(metrics, ImportMetrics {
+            bookmarks_added: 1,
+            tags_added: 1,
+            folders_merged: 1,
+            duplicates_skipped: 1,
+            ..ImportMetrics::default()
+        });
+        let rust = fetch_folder_guid(&db, Guid::MENU.as_str(), "Rust");
+        assert_eq!(fetch_children(&db, &rust).len(), 2);
+    }
+
+    #[test]
+    fn test_split_tags() {
+        assert_eq!(split_tags("a, b ,,c "), vec!["a", "b", "c"]);
+        assert!(split_tags("").is_empty());
+    }
+}
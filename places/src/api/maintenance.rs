@@ -0,0 +1,299 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Periodic housekeeping, intended to be run by the application when it's
+// idle (roughly what desktop does in PlacesDBUtils on idle-daily).
+
+use rusqlite::types::ToSql;
+use sql_support::ConnExt;
+
+use super::thumbnails;
+use db::PlacesDb;
+use error::*;
+use storage;
+use types::Timestamp;
+
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+// The last time we decayed frecency, in moz_meta.
+pub(crate) const FRECENCY_DECAY_LAST_RUN_META_KEY: &str = "frecency_decay_last_run";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrecencyDecaySettings {
+    /// Pages which haven't been visited for this many days are considered
+    /// stale, and have their frecency decayed.
+    pub stale_after_days: u32,
+    /// Stale pages have their frecency multiplied by this once for every day
+    /// which has passed since the previous decay.
+    pub daily_decay_rate: f64,
+    /// Stale, unbookmarked pages whose frecency falls below this have it set
+    /// to zero, making them candidates for expiration.
+    pub expiration_threshold: i32,
+}
+
+// Same rate as desktop's `places.frecency.decayRate`.
+pub const DEFAULT_FRECENCY_DECAY_SETTINGS: FrecencyDecaySettings = FrecencyDecaySettings {
+    stale_after_days: 1,
+    daily_decay_rate: 0.975,
+    expiration_threshold: 10,
+};
+
+impl Default for FrecencyDecaySettings {
+    #[inline]
+    fn default() -> Self {
+        DEFAULT_FRECENCY_DECAY_SETTINGS
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaintenanceReport {
+    /// The number of pages which had their frecency decayed.
+    pub decayed: usize,
+    /// The number of pages newly marked as expiration candidates.
+    pub expiration_candidates: usize,
+    /// The number of thumbnails evicted to stay under
+    /// `PlacesDb::thumbnail_cache_size`.
+    pub thumbnails_evicted: usize,
+}
+
+pub fn run_maintenance(db: &mut PlacesDb, settings: &FrecencyDecaySettings) -> Result<MaintenanceReport> {
+    run_maintenance_at(db, settings, Timestamp::now())
+}
+
+// Split out so that tests can pretend time has passed.
+pub(crate) fn run_maintenance_at(
+    db: &mut PlacesDb,
+    settings: &FrecencyDecaySettings,
+    now: Timestamp,
+) -> Result<MaintenanceReport> {
+    let thumbnail_cache_size = db.thumbnail_cache_size();
+    let tx = db.db.transaction()?;
+    let mut report = decay_frecencies(&tx, settings, now)?;
+    report.thumbnails_evicted = thumbnails::evict_thumbnails(&tx, thumbnail_cache_size)?;
+    tx.commit()?;
+    Ok(report)
+}
+
+/// Returns what `run_maintenance` would do, without changing anything.
+pub fn run_maintenance_dry_run(db: &PlacesDb, settings: &FrecencyDecaySettings) -> Result<MaintenanceReport> {
+    run_maintenance_dry_run_at(db, settings, Timestamp::now())
+}
+
+pub(crate) fn run_maintenance_dry_run_at(
+    db: &PlacesDb,
+    settings: &FrecencyDecaySettings,
+    now: Timestamp,
+) -> Result<MaintenanceReport> {
+    let mut report = match plan_decay(db, settings, now)? {
+        Some(plan) => {
+            let decayed = db.query_row_and_then_named(
+                &format!("SELECT count(*) FROM moz_places WHERE {}", STALE_PAGES_CONDITION),
+                &[(":cutoff", &plan.cutoff as &ToSql)],
+                |row| row.get_checked::<_, i64>(0),
+                true)?;
+            // Expiration looks at the decayed frecencies, so count as if the
+            // stale pages had already been decayed.
+            let expiration_candidates = db.query_row_and_then_named(
+                &format!("
+                    WITH decayed AS (
+                      SELECT {} AS frecency, foreign_count,
+                             last_visit_date_local, last_visit_date_remote
+                      FROM moz_places WHERE {}
+                    )
+                    SELECT count(*) FROM decayed WHERE {}",
+                    DECAYED_FRECENCY, STALE_PAGES_CONDITION, EXPIRING_PAGES_CONDITION),
+                &[(":factor", &plan.factor as &ToSql),
+                  (":cutoff", &plan.cutoff as &ToSql),
+                  (":threshold", &settings.expiration_threshold as &ToSql)],
+                |row| row.get_checked::<_, i64>(0),
+                true)?;
+            MaintenanceReport {
+                decayed: decayed as usize,
+                expiration_candidates: expiration_candidates as usize,
+                ..MaintenanceReport::default()
+            }
+        }
+        None => MaintenanceReport::default(),
+    };
+    report.thumbnails_evicted = thumbnails::thumbnails_to_evict(db, db.thumbnail_cache_size())?.len();
+    Ok(report)
+}
+
+// Pages which haven't been visited since `:cutoff`, and have their frecency
+// decayed. The decay and its dry run share these, so that they can't
+// disagree.
+const STALE_PAGES_CONDITION: &str = "
+    frecency > 0
+    AND MAX(IFNULL(last_visit_date_local, 0),
+            IFNULL(last_visit_date_remote, 0)) < :cutoff";
+
+const DECAYED_FRECENCY: &str = "CAST(ROUND(frecency * :factor) AS INTEGER)";
+
+// Stale, unbookmarked pages whose (decayed) frecency is below `:threshold`.
+const EXPIRING_PAGES_CONDITION: &str = "
+    frecency > 0
+    AND frecency < :threshold
+    AND foreign_count = 0
+    AND MAX(IFNULL(last_visit_date_local, 0),
+            IFNULL(last_visit_date_remote, 0)) < :cutoff";
+
+struct DecayPlan {
+    days: u64,
+    factor: f64,
+    cutoff: Timestamp,
+}
+
+// Works out how much to decay frecencies by, or returns None if it's too
+// soon since we last did.
+fn plan_decay(db: &impl ConnExt, settings: &FrecencyDecaySettings, now: Timestamp) -> Result<Option<DecayPlan>> {
+    let days = match storage::get_meta::<Timestamp>(db, FRECENCY_DECAY_LAST_RUN_META_KEY)? {
+        Some(last_run) if now > last_run => (now.0 - last_run.0) / MS_PER_DAY,
+        _ => 0,
+    };
+    if days == 0 {
+        return Ok(None);
+    }
+    Ok(Some(DecayPlan {
+        days,
+        factor: settings.daily_decay_rate.powi(days as i32),
+        cutoff: Timestamp(now.0.saturating_sub(settings.stale_after_days as u64 * MS_PER_DAY)),
+    }))
+}
+
+fn decay_frecencies(db: &impl ConnExt, settings: &FrecencyDecaySettings, now: Timestamp) -> Result<MaintenanceReport> {
+    let last_run = storage::get_meta::<Timestamp>(db, FRECENCY_DECAY_LAST_RUN_META_KEY)?;
+    // The first time we run, we just record the time: we don't know how long
+    // it's been since frecencies were last calculated.
+    let last_run = match last_run {
+        Some(last_run) => last_run,
+        None => {
+            storage::put_meta(db, FRECENCY_DECAY_LAST_RUN_META_KEY, &now)?;
+            return Ok(MaintenanceReport::default());
+        }
+    };
+    let DecayPlan { days, factor, cutoff } = match plan_decay(db, settings, now)? {
+        Some(plan) => plan,
+        None => return Ok(MaintenanceReport::default()),
+    };
+    debug!("Decaying frecency of pages not visited since {} by {}", cutoff, factor);
+
+    let decayed = db.execute_named_cached(
+        &format!("UPDATE moz_places SET frecency = {} WHERE {}",
+                 DECAYED_FRECENCY, STALE_PAGES_CONDITION),
+        &[(":factor", &factor as &ToSql), (":cutoff", &cutoff as &ToSql)])?;
+
+    let expiration_candidates = db.execute_named_cached(
+        &format!("UPDATE moz_places SET frecency = 0 WHERE {}", EXPIRING_PAGES_CONDITION),
+        &[(":threshold", &settings.expiration_threshold as &ToSql),
+          (":cutoff", &cutoff as &ToSql)])?;
+
+    // Only advance by whole days, so that running more than once a day
+    // doesn't lose the fractional part.
+    let last_run = Timestamp(last_run.0 + days * MS_PER_DAY);
+    storage::put_meta(db, FRECENCY_DECAY_LAST_RUN_META_KEY, &last_run)?;
+
+    Ok(MaintenanceReport { decayed, expiration_candidates, ..MaintenanceReport::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::history::{insert, AddablePlaceInfo, AddableVisit};
+    use types::VisitTransition;
+    use url::Url;
+
+    fn add_page(db: &mut PlacesDb, url: &str, visited: Timestamp, frecency: i32) {
+        insert(db, AddablePlaceInfo {
+            url: Url::parse(url).unwrap(),
+            title: None,
+            visits: vec![AddableVisit {
+                date: visited,
+                transition: VisitTransition::Link,
+                referrer: None,
+                is_local: true,
+            }],
+        }).unwrap();
+        db.execute_named("UPDATE moz_places SET frecency = :frecency WHERE url = :url",
+                         &[(":frecency", &frecency), (":url", &url)]).unwrap();
+    }
+
+    fn frecency(db: &PlacesDb, url: &str) -> i32 {
+        db.query_row_and_then_named("SELECT frecency FROM moz_places WHERE url = :url",
+                                    &[(":url", &url)],
+                                    |row| row.get_checked(0),
+                                    false).unwrap()
+    }
+
+    #[test]
+    fn test_decay() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let settings = FrecencyDecaySettings::default();
+        let start = Timestamp(1_500_000_000_000);
+        let day = |n: u64| Timestamp(start.0 + n * MS_PER_DAY);
+
+        add_page(&mut db, "https://stale.example.com/", start, 1000);
+        add_page(&mut db, "https://barely.example.com/", start, 11);
+        add_page(&mut db, "https://fresh.example.com/", day(10), 1000);
+
+        // The first run just records the time.
+        assert_eq!(run_maintenance_at(&mut db, &settings, start).unwrap(),
+                   MaintenanceReport::default());
+
+        // Running again later the same day does nothing.
+        let report = run_maintenance_at(&mut db, &settings, Timestamp(start.0 + 1000)).unwrap();
+        assert_eq!(report.decayed, 0);
+
+        let report = run_maintenance_at(&mut db, &settings, day(10)).unwrap();
+        assert_eq!(report, MaintenanceReport { decayed: 2, expiration_candidates: 1, thumbnails_evicted: 0 });
+        let expected = (1000f64 * 0.975f64.powi(10)).round() as i32;
+        assert_eq!(frecency(&db, "https://stale.example.com/"), expected);
+        assert_eq!(frecency(&db, "https://barely.example.com/"), 0);
+        assert_eq!(frecency(&db, "https://fresh.example.com/"), 1000);
+
+        // Ten more days, and the fresh page is now stale too.
+        let report = run_maintenance_at(&mut db, &settings, day(20)).unwrap();
+        assert_eq!(report.decayed, 2);
+        assert_eq!(frecency(&db, "https://fresh.example.com/"), expected);
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        db.set_thumbnail_cache_size(50);
+        let settings = FrecencyDecaySettings::default();
+        let start = Timestamp(1_500_000_000_000);
+        let later = Timestamp(start.0 + 30 * MS_PER_DAY);
+
+        add_page(&mut db, "https://stale.example.com/", start, 1000);
+        add_page(&mut db, "https://barely.example.com/", start, 11);
+        // Decays to zero, so it isn't counted as an expiration candidate.
+        add_page(&mut db, "https://tiny.example.com/", start, 1);
+        for i in 0..2 {
+            let url = Url::parse(&format!("https://example.com/{}", i)).unwrap();
+            thumbnails::set_thumbnail(&db, &url, &[0; 40]).unwrap();
+        }
+
+        // Nothing to decay before the first run.
+        assert_eq!(run_maintenance_dry_run_at(&db, &settings, start).unwrap(),
+                   MaintenanceReport { thumbnails_evicted: 1, ..MaintenanceReport::default() });
+        run_maintenance_at(&mut db, &settings, start).unwrap();
+
+        let preview = run_maintenance_dry_run_at(&db, &settings, later).unwrap();
+        assert_eq!(preview, MaintenanceReport { decayed: 3, expiration_candidates: 1, thumbnails_evicted: 0 });
+        assert_eq!(frecency(&db, "https://stale.example.com/"), 1000);
+        assert_eq!(run_maintenance_at(&mut db, &settings, later).unwrap(), preview);
+    }
+
+    #[test]
+    fn test_evicts_thumbnails() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        db.set_thumbnail_cache_size(100);
+        for i in 0..3 {
+            let url = Url::parse(&format!("https://example.com/{}", i)).unwrap();
+            thumbnails::set_thumbnail(&db, &url, &[0; 40]).unwrap();
+        }
+        let report = run_maintenance(&mut db, &FrecencyDecaySettings::default()).unwrap();
+        assert_eq!(report.thumbnails_evicted, 1);
+    }
+}
@@ -6,15 +6,23 @@ use rusqlite::{
     self,
     types::{FromSql, FromSqlError, FromSqlResult, Null, ToSql, ToSqlOutput, ValueRef},
 };
+use std::collections::HashSet;
+
 use url::Url;
 
 use db::PlacesDb;
 use error::Result;
+use idn;
 
 #[derive(Debug, Clone)]
 pub struct SearchParams {
     pub search_string: String,
     pub limit: u32,
+    /// Whether words in the search string can match the path and query of a
+    /// page's URL (like "PROJ-123" in a bug tracker URL), as well as its
+    /// host, title and tags. Matching only the host is faster, since we don't
+    /// need to normalize the rest of every URL we consider.
+    pub match_url_path: bool,
 }
 
 /// Synchronously queries all providers for autocomplete matches, then filters
@@ -41,25 +49,38 @@ pub fn search_frecent(conn: &PlacesDb, params: SearchParams) -> Result<Vec<Searc
 
     // After the first result, try the queries for adaptive matches and
     // suggestions for bookmarked URLs.
-    let adaptive = Adaptive::new(&params.search_string, conn, params.limit);
+    let adaptive = Adaptive::new(&params.search_string, conn, params.limit, params.match_url_path);
     let adaptive_matches = adaptive.search()?;
     matches.extend(adaptive_matches);
 
-    let suggestions = Suggestions::new(&params.search_string, conn, params.limit);
+    let suggestions = Suggestions::new(&params.search_string, conn, params.limit, params.match_url_path);
     let suggestions_matches = suggestions.search()?;
     matches.extend(suggestions_matches);
 
     // TODO: If we don't have enough results, re-run `Adaptive` and
     // `Suggestions`, this time with `MatchBehavior::Anywhere`.
 
+    // A page can be suggested by more than one provider (a bookmark the user
+    // picked before is both a previous use and a suggestion). Only keep its
+    // first, highest ranked, match.
+    let mut seen_urls = HashSet::new();
+    matches.retain(|result| seen_urls.insert(result.url.clone()));
+
     Ok(matches)
 }
 
-/// Records an accepted autocomplete match, recording the query string,
-/// and chosen URL for subsequent matches.
-pub fn accept_result(conn: &PlacesDb, result: &SearchResult) -> Result<()> {
+/// Records that the user picked `url` from the matches for `search_string`,
+/// so that it ranks higher as a `MatchReason::PreviousUse` the next time they
+/// type that, or anything it starts with. Does nothing if `url` isn't in
+/// history (for example, if it's an origin match for a host we only have
+/// deeper pages for), or if history recording is disabled.
+pub fn accept_result(conn: &PlacesDb, search_string: &str, url: &Url) -> Result<()> {
+    if !conn.history_recording_enabled() {
+        debug!("Ignoring accepted result, since history recording is disabled");
+        return Ok(());
+    }
     // See `nsNavHistory::AutoCompleteFeedback`.
-    let mut stmt = conn.db.prepare("
+    let mut stmt = conn.db.prepare_cached("
         INSERT OR REPLACE INTO moz_inputhistory(place_id, input, use_count)
         SELECT h.id, IFNULL(i.input, :input_text), IFNULL(i.use_count, 0) * .9 + 1
         FROM moz_places h
@@ -67,8 +88,8 @@ pub fn accept_result(conn: &PlacesDb, result: &SearchResult) -> Result<()> {
         WHERE url_hash = hash(:page_url) AND url = :page_url
     ")?;
     let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
-        (":input_text", &result.search_string),
-        (":page_url", &result.url.as_str()),
+        (":input_text", &search_string),
+        (":page_url", &url.as_str()),
     ];
     stmt.execute_named(params)?;
     Ok(())
@@ -112,7 +133,8 @@ fn looks_like_origin(string: &str) -> bool {
 
 /// The match reason specifies why an autocomplete search result matched a
 /// query. This can be used to filter and sort matches.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum MatchReason {
     Keyword,
     Origin,
@@ -122,15 +144,29 @@ pub enum MatchReason {
     Tags(String),
 }
 
-#[derive(Debug, Clone)]
+/// An autocomplete match. These are returned over the FFI as JSON, with
+/// camelCase keys.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct SearchResult {
     /// The search string for this match.
     pub search_string: String,
 
     /// The URL to open when the user confirms a match. This is
-    /// equivalent to `nsIAutoCompleteResult.getFinalCompleteValueAt`.
+    /// equivalent to `nsIAutoCompleteResult.getFinalCompleteValueAt`. Pass
+    /// it to `accept_result` when the user picks this match.
     pub url: Url,
 
+    /// `url` as it should be shown in the UI, with its host in Unicode
+    /// rather than Punycode, unless it looks like a spoof (see
+    /// `idn::display_url`).
+    pub display_url: String,
+
+    /// The id of the page in `moz_places`, which stays the same as long as
+    /// the page is in history, for attributing picks in telemetry. This is
+    /// `None` for origin matches whose URL isn't in history itself.
+    pub place_id: Option<i64>,
+
     /// The title of the autocompleted value, to show in the UI. This can be the
     /// title of the bookmark or page, origin, URL, or URL fragment.
     pub title: String,
@@ -152,7 +188,7 @@ impl SearchResult {
         let mut reasons = vec![MatchReason::PreviousUse];
 
         let search_string = row.get_checked::<_, String>("searchString")?;
-        let _place_id = row.get_checked::<_, i64>("id")?;
+        let place_id = row.get_checked::<_, i64>("id")?;
         let url = row.get_checked::<_, String>("url")?;
         let history_title = row.get_checked::<_, Option<String>>("title")?;
         let bookmarked = row.get_checked::<_, bool>("bookmarked")?;
@@ -169,10 +205,13 @@ impl SearchResult {
             reasons.push(MatchReason::Bookmark);
         }
         let url = Url::parse(&url).expect("Invalid URL in Places");
+        let display_url = idn::display_url(&url);
 
         Ok(Self {
             search_string,
             url,
+            display_url,
+            place_id: Some(place_id),
             title,
             icon_url: None,
             frecency,
@@ -184,6 +223,7 @@ impl SearchResult {
         let mut reasons = vec![MatchReason::Bookmark];
 
         let search_string = row.get_checked::<_, String>("searchString")?;
+        let place_id = row.get_checked::<_, i64>("id")?;
         let url = row.get_checked::<_, String>("url")?;
 
         let history_title = row.get_checked::<_, Option<String>>("title")?;
@@ -195,12 +235,15 @@ impl SearchResult {
             reasons.push(MatchReason::Tags(tags));
         }
         let url = Url::parse(&url).expect("Invalid URL in Places");
+        let display_url = idn::display_url(&url);
 
         let frecency = row.get_checked::<_, i64>("frecency")?;
 
         Ok(Self {
             search_string,
             url,
+            display_url,
+            place_id: Some(place_id),
             title,
             icon_url: None,
             frecency,
@@ -211,15 +254,19 @@ impl SearchResult {
     pub fn from_origin_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
         let search_string = row.get_checked::<_, String>("searchString")?;
         let url = row.get_checked::<_, String>("url")?;
-        let display_url = row.get_checked::<_, String>("displayURL")?;
+        let host = row.get_checked::<_, String>("host")?;
         let frecency = row.get_checked::<_, i64>("frecency")?;
+        let place_id = row.get_checked::<_, Option<i64>>("placeId")?;
 
         let url = Url::parse(&url).expect("Invalid URL in Places");
+        let display_url = idn::display_url(&url);
 
         Ok(Self {
             search_string,
             url,
-            title: display_url,
+            display_url,
+            place_id,
+            title: format!("{}/", idn::host_to_display(&host)),
             icon_url: None,
             frecency,
             reasons: vec![MatchReason::Origin],
@@ -229,9 +276,10 @@ impl SearchResult {
     pub fn from_url_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
         let search_string = row.get_checked::<_, String>("searchString")?;
         let url = row.get_checked::<_, String>("url")?;
-        let display_url = row.get_checked::<_, String>("displayURL")?;
+        let stripped_url = row.get_checked::<_, String>("displayURL")?;
         let frecency = row.get_checked::<_, i64>("frecency")?;
         let bookmarked = row.get_checked::<_, bool>("bookmarked")?;
+        let place_id = row.get_checked::<_, i64>("id")?;
 
         let mut reasons = vec![MatchReason::Url];
         if bookmarked {
@@ -239,11 +287,14 @@ impl SearchResult {
         }
 
         let url = Url::parse(&url).expect("Invalid URL in Places");
+        let display_url = idn::display_url(&url);
 
         Ok(Self {
             search_string,
             url,
-            title: display_url,
+            display_url,
+            place_id: Some(place_id),
+            title: stripped_url,
             icon_url: None,
             frecency,
             reasons,
@@ -288,24 +339,31 @@ impl<'query, 'conn> OriginOrURL<'query, 'conn> {
     pub fn search(&self) -> Result<Vec<SearchResult>> {
         let mut results = Vec::new();
         if looks_like_origin(self.query) {
-            let mut stmt = self.conn.db.prepare("
+            // We store hosts as Punycode, so this only matches a Unicode host
+            // once the label being typed is complete ("münchen", but not
+            // "münch"). `Suggestions` matches partial labels, though.
+            let search_host = idn::host_to_ascii(self.query);
+            let mut stmt = self.conn.db.prepare_cached("
                 SELECT IFNULL(:prefix, prefix) || moz_origins.host || '/' AS url,
-                       moz_origins.host || '/' AS displayURL,
+                       moz_origins.host AS host,
                        frecency,
                        id,
+                       (SELECT h.id FROM moz_places h
+                        WHERE h.url_hash = hash(IFNULL(:prefix, prefix) || moz_origins.host || '/')
+                          AND h.url = IFNULL(:prefix, prefix) || moz_origins.host || '/') AS placeId,
                        :searchString AS searchString
                 FROM (
                   SELECT host,
                          TOTAL(frecency) AS host_frecency
                   FROM moz_origins
-                  WHERE host BETWEEN :searchString AND :searchString || X'FFFF'
+                  WHERE host BETWEEN :searchHost AND :searchHost || X'FFFF'
                   GROUP BY host
                   HAVING host_frecency >= :frecencyThreshold
                   UNION ALL
                   SELECT host,
                          TOTAL(frecency) AS host_frecency
                   FROM moz_origins
-                  WHERE host BETWEEN 'www.' || :searchString AND 'www.' || :searchString || X'FFFF'
+                  WHERE host BETWEEN 'www.' || :searchHost AND 'www.' || :searchHost || X'FFFF'
                   GROUP BY host
                   HAVING host_frecency >= :frecencyThreshold
                 ) AS grouped_hosts
@@ -316,6 +374,7 @@ impl<'query, 'conn> OriginOrURL<'query, 'conn> {
             let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
                 (":prefix", &Null),
                 (":searchString", &self.query),
+                (":searchHost", &search_host),
                 (":frecencyThreshold", &-1i64),
             ];
             for result in stmt.query_and_then_named(params, SearchResult::from_origin_row)? {
@@ -323,7 +382,8 @@ impl<'query, 'conn> OriginOrURL<'query, 'conn> {
             }
         } else if self.query.contains(|c| c == '/' || c == ':' || c == '?') {
             let (host, stripped_url) = split_after_host_and_port(self.query);
-            let mut stmt = self.conn.db.prepare("
+            let host = idn::host_to_ascii(host);
+            let mut stmt = self.conn.db.prepare_cached("
                 SELECT h.url,
                        :strippedURL AS displayURL,
                        h.frecency,
@@ -371,6 +431,7 @@ struct Adaptive<'query, 'conn> {
     conn: &'conn PlacesDb,
     max_results: u32,
     match_behavior: MatchBehavior,
+    match_url_path: bool,
 }
 
 impl<'query, 'conn> Adaptive<'query, 'conn> {
@@ -378,8 +439,9 @@ impl<'query, 'conn> Adaptive<'query, 'conn> {
         query: &'query str,
         conn: &'conn PlacesDb,
         max_results: u32,
+        match_url_path: bool,
     ) -> Adaptive<'query, 'conn> {
-        Adaptive::with_behavior(query, conn, max_results, MatchBehavior::BoundaryAnywhere)
+        Adaptive::with_behavior(query, conn, max_results, MatchBehavior::BoundaryAnywhere, match_url_path)
     }
 
     pub fn with_behavior(
@@ -387,17 +449,19 @@ impl<'query, 'conn> Adaptive<'query, 'conn> {
         conn: &'conn PlacesDb,
         max_results: u32,
         match_behavior: MatchBehavior,
+        match_url_path: bool,
     ) -> Adaptive<'query, 'conn> {
         Adaptive {
             query,
             conn,
             max_results,
             match_behavior,
+            match_url_path,
         }
     }
 
     pub fn search(&self) -> Result<Vec<SearchResult>> {
-        let mut stmt = self.conn.db.prepare("
+        let mut stmt = self.conn.db.prepare_cached("
             SELECT h.url, h.title,
                    EXISTS(SELECT 1 FROM moz_bookmarks
                           WHERE fk = h.id) AS bookmarked,
@@ -421,13 +485,14 @@ impl<'query, 'conn> Adaptive<'query, 'conn> {
             WHERE AUTOCOMPLETE_MATCH(:searchString, h.url,
                                      IFNULL(btitle, h.title), tags,
                                      visit_count, h.typed, bookmarked,
-                                     NULL, :matchBehavior)
+                                     NULL, :matchBehavior, :matchURLPath)
             ORDER BY rank DESC, h.frecency DESC
             LIMIT :maxResults
         ")?;
         let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
             (":searchString", &self.query),
             (":matchBehavior", &self.match_behavior),
+            (":matchURLPath", &self.match_url_path),
             (":maxResults", &self.max_results),
         ];
         let mut results = Vec::new();
@@ -443,6 +508,7 @@ struct Suggestions<'query, 'conn> {
     conn: &'conn PlacesDb,
     max_results: u32,
     match_behavior: MatchBehavior,
+    match_url_path: bool,
 }
 
 impl<'query, 'conn> Suggestions<'query, 'conn> {
@@ -450,8 +516,9 @@ impl<'query, 'conn> Suggestions<'query, 'conn> {
         query: &'query str,
         conn: &'conn PlacesDb,
         max_results: u32,
+        match_url_path: bool,
     ) -> Suggestions<'query, 'conn> {
-        Suggestions::with_behavior(query, conn, max_results, MatchBehavior::BoundaryAnywhere)
+        Suggestions::with_behavior(query, conn, max_results, MatchBehavior::BoundaryAnywhere, match_url_path)
     }
 
     pub fn with_behavior(
@@ -459,17 +526,19 @@ impl<'query, 'conn> Suggestions<'query, 'conn> {
         conn: &'conn PlacesDb,
         max_results: u32,
         match_behavior: MatchBehavior,
+        match_url_path: bool,
     ) -> Suggestions<'query, 'conn> {
         Suggestions {
             query,
             conn,
             max_results,
             match_behavior,
+            match_url_path,
         }
     }
 
     pub fn search(&self) -> Result<Vec<SearchResult>> {
-        let mut stmt = self.conn.db.prepare("
+        let mut stmt = self.conn.db.prepare_cached("
             SELECT h.url, h.title,
                    (SELECT title FROM moz_bookmarks
                     WHERE fk = h.id AND
@@ -485,7 +554,7 @@ impl<'query, 'conn> Suggestions<'query, 'conn> {
                                      IFNULL(btitle, h.title), tags,
                                      visit_count, h.typed,
                                      1, NULL,
-                                     :matchBehavior)
+                                     :matchBehavior, :matchURLPath)
               AND (+h.visit_count_local > 0 OR +h.visit_count_remote > 0)
             ORDER BY h.frecency DESC, h.id DESC
             LIMIT :maxResults
@@ -493,6 +562,7 @@ impl<'query, 'conn> Suggestions<'query, 'conn> {
         let params: &[(&str, &dyn rusqlite::types::ToSql)] = &[
             (":searchString", &self.query),
             (":matchBehavior", &self.match_behavior),
+            (":matchURLPath", &self.match_url_path),
             (":maxResults", &self.max_results),
         ];
         let mut results = Vec::new();
@@ -508,6 +578,7 @@ mod tests {
     use super::*;
     use observation::{VisitObservation};
     use storage::{apply_observation};
+    use sql_support::ConnExt;
     use types::{Timestamp, VisitTransition};
 
     #[test]
@@ -540,27 +611,126 @@ mod tests {
         let by_origin = search_frecent(&conn, SearchParams {
             search_string: "example.com".into(),
             limit: 10,
+            match_url_path: true,
         }).expect("Should search by origin");
         println!("Matches by origin: {:?}", by_origin);
 
         let by_url = search_frecent(&conn, SearchParams {
             search_string: "http://example.com".into(),
             limit: 10,
+            match_url_path: true,
         }).expect("Should search by URL");
         println!("Matches by URL: {:?}", by_url);
 
-        accept_result(&conn, &SearchResult {
-            search_string: "ample".into(),
-            url: url.clone(),
-            title: "Example page 123".into(),
-            icon_url: None,
-            frecency: -1,
-            reasons: vec![],
-        }).expect("Should accept input history match");
+        accept_result(&conn, "ample", &url).expect("Should accept input history match");
         let by_adaptive = search_frecent(&conn, SearchParams {
             search_string: "ample".into(),
             limit: 10,
+            match_url_path: true,
         }).expect("Should search by adaptive input history");
         println!("Matches by adaptive input history: {:?}", by_adaptive);
     }
+
+    #[test]
+    fn search_idn() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+
+        let url = Url::parse("https://münchen.de/stadtplan").unwrap();
+        assert_eq!(url.as_str(), "https://xn--mnchen-3ya.de/stadtplan");
+        let visit = VisitObservation::new(url.clone())
+                   .with_title("Stadtplan".to_string())
+                   .with_visit_type(VisitTransition::Typed)
+                   .with_at(Timestamp::now());
+        apply_observation(&mut conn, visit).expect("Should apply visit");
+
+        let search = |search_string: &str| search_frecent(&conn, SearchParams {
+            search_string: search_string.into(),
+            limit: 10,
+            match_url_path: true,
+        }).expect("Should search");
+
+        for query in &["münchen", "MÜNCHEN.de", "xn--mnchen"] {
+            let results = search(query);
+            let origin = results.iter()
+                .find(|r| match r.reasons[0] { MatchReason::Origin => true, _ => false })
+                .unwrap_or_else(|| panic!("Should match origin for {}", query));
+            assert_eq!(origin.url.as_str(), "https://xn--mnchen-3ya.de/");
+            assert_eq!(origin.display_url, "https://münchen.de/");
+            assert_eq!(origin.title, "münchen.de/");
+        }
+
+        // Partial labels don't match the origin, but still match the page.
+        let results = search("münch");
+        assert_eq!(results.iter().map(|r| r.display_url.as_str()).collect::<Vec<_>>(),
+                   vec!["https://münchen.de/stadtplan"]);
+
+        let results = search("https://münchen.de/stadt");
+        assert_eq!(results[0].url, url);
+        match results[0].reasons[0] {
+            MatchReason::Url => {}
+            ref reason => panic!("Unexpected reason {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn accept_result_ranks_higher() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+
+        let picked = Url::parse("http://example.com/123").unwrap();
+        let other = Url::parse("http://example.com/456").unwrap();
+        for &(url, title) in &[(&picked, "Example page 123"), (&other, "Example page 456")] {
+            let visit = VisitObservation::new(url.clone())
+                       .with_title(title.to_string())
+                       .with_visit_type(VisitTransition::Typed)
+                       .with_at(Timestamp::now());
+            apply_observation(&mut conn, visit).expect("Should apply visit");
+        }
+
+        let params = SearchParams {
+            search_string: "example".into(),
+            limit: 10,
+            match_url_path: true,
+        };
+        let before = search_frecent(&conn, params.clone()).expect("Should search");
+        let place_id = before.iter().find(|r| r.url == picked)
+            .expect("Should suggest the page")
+            .place_id
+            .expect("Suggestions should have a place id");
+
+        accept_result(&conn, "example", &picked).expect("Should accept result");
+        // Pages that aren't in history are ignored.
+        accept_result(&conn, "example", &Url::parse("http://example.org/").unwrap())
+            .expect("Should ignore unknown URL");
+
+        let after = search_frecent(&conn, params).expect("Should search again");
+        let pages = after.iter()
+            .filter(|r| r.url != Url::parse("http://example.com/").unwrap())
+            .collect::<Vec<_>>();
+        // The picked page comes first, as a previous use, and only once.
+        assert_eq!(pages.iter().map(|r| r.url.as_str()).collect::<Vec<_>>(),
+                   vec!["http://example.com/123", "http://example.com/456"]);
+        assert_eq!(pages[0].place_id, Some(place_id));
+        match pages[0].reasons[0] {
+            MatchReason::PreviousUse => {}
+            ref reason => panic!("Unexpected reason {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn accept_result_history_recording_disabled() {
+        let mut conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("http://example.com/123").unwrap();
+        let visit = VisitObservation::new(url.clone())
+                   .with_visit_type(VisitTransition::Typed)
+                   .with_at(Timestamp::now());
+        apply_observation(&mut conn, visit).expect("Should apply visit");
+
+        conn.set_history_recording_enabled(false);
+        accept_result(&conn, "example", &url).expect("Should ignore result");
+        assert_eq!(conn.query_one::<i64>("SELECT COUNT(*) FROM moz_inputhistory").unwrap(), 0);
+
+        conn.set_history_recording_enabled(true);
+        accept_result(&conn, "example", &url).expect("Should accept result");
+        assert_eq!(conn.query_one::<i64>("SELECT COUNT(*) FROM moz_inputhistory").unwrap(), 1);
+    }
 }
@@ -3,7 +3,10 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 pub mod history;
+pub mod import;
+pub mod maintenance;
 pub mod matcher;
+pub mod thumbnails;
 use db::PlacesDb;
 use error::{Result};
 use observation::{VisitObservation};
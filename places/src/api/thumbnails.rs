@@ -0,0 +1,185 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Storage for page thumbnails shown on new-tab tiles. Thumbnails are just a
+// local cache: they aren't synced, and aren't tied to the page's history, so
+// forgetting a page doesn't remove its thumbnail (eviction eventually will).
+// The table is capped at `PlacesDb::thumbnail_cache_size` bytes, with the
+// least recently used thumbnails evicted during maintenance.
+
+use rusqlite::types::ToSql;
+use sql_support::ConnExt;
+use url::Url;
+
+use db::PlacesDb;
+use error::*;
+use types::Timestamp;
+
+/// Stores `data` as the thumbnail for `url`, replacing any existing one. The
+/// data is opaque to us, but is expected to be an encoded image. Does nothing
+/// if history recording is disabled, since a thumbnail shows what was on the
+/// page.
+pub fn set_thumbnail(db: &PlacesDb, url: &Url, data: &[u8]) -> Result<()> {
+    if !db.history_recording_enabled() {
+        debug!("Ignoring thumbnail, since history recording is disabled");
+        return Ok(());
+    }
+    let size = data.len() as i64;
+    db.execute_named_cached("
+        INSERT OR REPLACE INTO moz_thumbnails(url_hash, url, data, size, last_accessed)
+        VALUES (hash(:url), :url, :data, :size, :now)",
+        &[(":url", &url.as_str() as &ToSql),
+          (":data", &data as &ToSql),
+          (":size", &size as &ToSql),
+          (":now", &Timestamp::now() as &ToSql)])?;
+    Ok(())
+}
+
+/// Returns the thumbnail for `url`, if we have one. This counts as a use for
+/// eviction purposes, unless history recording is disabled.
+pub fn get_thumbnail(db: &PlacesDb, url: &Url) -> Result<Option<Vec<u8>>> {
+    let data = db.try_query_row("
+        SELECT data FROM moz_thumbnails
+        WHERE url_hash = hash(:url) AND url = :url",
+        &[(":url", &url.as_str() as &ToSql)],
+        |row| row.get_checked::<_, Vec<u8>>(0),
+        true)?;
+    if data.is_some() && db.history_recording_enabled() {
+        db.execute_named_cached("
+            UPDATE moz_thumbnails SET last_accessed = :now
+            WHERE url_hash = hash(:url) AND url = :url",
+            &[(":url", &url.as_str() as &ToSql),
+              (":now", &Timestamp::now() as &ToSql)])?;
+    }
+    Ok(data)
+}
+
+/// Removes the thumbnail for `url`. Returns false if there wasn't one.
+pub fn remove_thumbnail(db: &PlacesDb, url: &Url) -> Result<bool> {
+    let removed = db.execute_named_cached("
+        DELETE FROM moz_thumbnails
+        WHERE url_hash = hash(:url) AND url = :url",
+        &[(":url", &url.as_str() as &ToSql)])?;
+    Ok(removed > 0)
+}
+
+// The least recently used thumbnails, which need to be evicted for the rest
+// to fit in `max_size` bytes. Shared by eviction and maintenance's dry run.
+pub(crate) fn thumbnails_to_evict(db: &impl ConnExt, max_size: u64) -> Result<Vec<(i64, String)>> {
+    let total = db.query_one::<i64>("SELECT IFNULL(SUM(size), 0) FROM moz_thumbnails")? as u64;
+    if total <= max_size {
+        return Ok(Vec::new());
+    }
+    let mut excess = total - max_size;
+    let mut victims: Vec<(i64, String)> = Vec::new();
+    let mut stmt = db.conn().prepare_cached("
+        SELECT url_hash, url, size FROM moz_thumbnails
+        ORDER BY last_accessed ASC")?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let size = row.get_checked::<_, i64>(2)? as u64;
+        victims.push((row.get_checked(0)?, row.get_checked(1)?));
+        if size >= excess {
+            break;
+        }
+        excess -= size;
+    }
+    Ok(victims)
+}
+
+/// Evicts the least recently used thumbnails until the rest fit in
+/// `max_size` bytes, returning how many were evicted. Called by maintenance.
+pub(crate) fn evict_thumbnails(db: &impl ConnExt, max_size: u64) -> Result<usize> {
+    let victims = thumbnails_to_evict(db, max_size)?;
+    if victims.is_empty() {
+        return Ok(0);
+    }
+    debug!("Evicting {} thumbnails to stay under {} bytes", victims.len(), max_size);
+    for &(url_hash, ref url) in &victims {
+        db.execute_named_cached("
+            DELETE FROM moz_thumbnails
+            WHERE url_hash = :url_hash AND url = :url",
+            &[(":url_hash", &url_hash as &ToSql), (":url", url as &ToSql)])?;
+    }
+    Ok(victims.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn set_last_accessed(db: &PlacesDb, url: &Url, when: u64) {
+        db.execute_named("UPDATE moz_thumbnails SET last_accessed = :when WHERE url = :url",
+                         &[(":when", &Timestamp(when)), (":url", &url.as_str())]).unwrap();
+    }
+
+    #[test]
+    fn test_set_get_remove() {
+        let db = PlacesDb::open_in_memory(None).unwrap();
+        let page = url("https://www.example.com/");
+        assert_eq!(get_thumbnail(&db, &page).unwrap(), None);
+
+        set_thumbnail(&db, &page, &[1, 2, 3]).unwrap();
+        assert_eq!(get_thumbnail(&db, &page).unwrap(), Some(vec![1, 2, 3]));
+
+        set_thumbnail(&db, &page, &[4, 5]).unwrap();
+        assert_eq!(get_thumbnail(&db, &page).unwrap(), Some(vec![4, 5]));
+        assert_eq!(get_thumbnail(&db, &url("https://www.example.com/other")).unwrap(), None);
+
+        assert!(remove_thumbnail(&db, &page).unwrap());
+        assert!(!remove_thumbnail(&db, &page).unwrap());
+        assert_eq!(get_thumbnail(&db, &page).unwrap(), None);
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let db = PlacesDb::open_in_memory(None).unwrap();
+        let a = url("https://a.example.com/");
+        let b = url("https://b.example.com/");
+        let c = url("https://c.example.com/");
+        set_thumbnail(&db, &a, &[0; 40]).unwrap();
+        set_thumbnail(&db, &b, &[0; 40]).unwrap();
+        set_thumbnail(&db, &c, &[0; 40]).unwrap();
+        set_last_accessed(&db, &a, 1000);
+        set_last_accessed(&db, &b, 2000);
+        set_last_accessed(&db, &c, 3000);
+
+        assert_eq!(evict_thumbnails(&db, 120).unwrap(), 0);
+
+        // Reading `a` makes `b` the least recently used.
+        assert!(get_thumbnail(&db, &a).unwrap().is_some());
+        assert_eq!(evict_thumbnails(&db, 100).unwrap(), 1);
+        assert!(get_thumbnail(&db, &b).unwrap().is_none());
+        assert!(get_thumbnail(&db, &c).unwrap().is_some());
+
+        assert_eq!(evict_thumbnails(&db, 0).unwrap(), 2);
+        assert_eq!(db.query_one::<i64>("SELECT COUNT(*) FROM moz_thumbnails").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_history_recording_disabled() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let page = url("https://www.example.com/");
+        set_thumbnail(&db, &page, &[1, 2, 3]).unwrap();
+        set_last_accessed(&db, &page, 1000);
+
+        db.set_history_recording_enabled(false);
+        set_thumbnail(&db, &page, &[4, 5]).unwrap();
+        set_thumbnail(&db, &url("https://www.example.com/private"), &[6]).unwrap();
+        // Existing thumbnails can still be read, but that isn't remembered.
+        assert_eq!(get_thumbnail(&db, &page).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(db.query_one::<Timestamp>("SELECT last_accessed FROM moz_thumbnails").unwrap(),
+                   Timestamp(1000));
+        assert_eq!(db.query_one::<i64>("SELECT COUNT(*) FROM moz_thumbnails").unwrap(), 1);
+
+        db.set_history_recording_enabled(true);
+        set_thumbnail(&db, &page, &[4, 5]).unwrap();
+        assert_eq!(get_thumbnail(&db, &page).unwrap(), Some(vec![4, 5]));
+    }
+}
@@ -0,0 +1,242 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The records in the `bookmarks` collection.
+//!
+//! As with history records, each struct has a flattened `extra` map for the
+//! fields we don't understand, so that re-uploading a record written by a
+//! newer client doesn't drop them. Optional fields are skipped when they're
+//! `None`, so that a record without them round-trips exactly.
+
+use serde_json::{Map, Value as JsonValue};
+use types::SyncGuid;
+
+/// A bookmark item. Records with a `type` we don't support (for example,
+/// livemarks, which desktop no longer creates) fail to deserialize.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum BookmarkItemRecord {
+    Bookmark(BookmarkRecord),
+    Query(QueryRecord),
+    Folder(FolderRecord),
+    Separator(SeparatorRecord),
+}
+
+impl BookmarkItemRecord {
+    pub fn id(&self) -> &SyncGuid {
+        match self {
+            BookmarkItemRecord::Bookmark(b) => &b.id,
+            BookmarkItemRecord::Query(q) => &q.id,
+            BookmarkItemRecord::Folder(f) => &f.id,
+            BookmarkItemRecord::Separator(s) => &s.id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookmarkRecord {
+    pub id: SyncGuid,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<SyncGuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    /// Milliseconds since the epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    #[serde(rename = "bmkUri")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, JsonValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRecord {
+    pub id: SyncGuid,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<SyncGuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    /// A `place:` URL.
+    #[serde(rename = "bmkUri")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    /// The tag this query lists, for tag queries.
+    #[serde(rename = "folderName")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag_folder_name: Option<String>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, JsonValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderRecord {
+    pub id: SyncGuid,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<SyncGuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<SyncGuid>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, JsonValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeparatorRecord {
+    pub id: SyncGuid,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<SyncGuid>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+
+    /// Older clients use this to tell separators apart when deduping.
+    #[serde(rename = "pos")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<i64>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, JsonValue>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use sync::Payload;
+
+    fn assert_round_trips(json: &str) -> BookmarkItemRecord {
+        let json: JsonValue = serde_json::from_str(json).unwrap();
+        let record: BookmarkItemRecord = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&record).unwrap(), json);
+        let payload = Payload::from_record(record.clone()).unwrap();
+        assert_eq!(&payload.id, &record.id().0);
+        assert_eq!(payload.into_record::<BookmarkItemRecord>().unwrap(), record);
+        record
+    }
+
+    #[test]
+    fn test_round_trip_unknown_fields() {
+        let record = assert_round_trips(r#"{
+            "id": "bookmarkAAAA",
+            "type": "bookmark",
+            "parentid": "toolbar",
+            "parentName": "Bookmarks Toolbar",
+            "dateAdded": 1540000000000,
+            "title": "Example",
+            "bmkUri": "https://www.example.com/",
+            "tags": ["a", "b"],
+            "futureField": [1, 2, 3]
+        }"#);
+        match record {
+            BookmarkItemRecord::Bookmark(b) => {
+                assert_eq!(b.url, Some("https://www.example.com/".into()));
+                assert_eq!(b.extra.len(), 1);
+                assert_eq!(b.extra["futureField"][2], 3);
+            }
+            other => panic!("Expected a bookmark, got {:?}", other),
+        }
+
+        let record = assert_round_trips(r#"{
+            "id": "folderAAAAAA",
+            "type": "folder",
+            "parentid": "menu",
+            "title": "Folder",
+            "children": ["bookmarkAAAA", "separatorAA"],
+            "futureFolderField": {"a": null}
+        }"#);
+        match record {
+            BookmarkItemRecord::Folder(f) => {
+                assert_eq!(f.children.len(), 2);
+                assert!(f.extra["futureFolderField"]["a"].is_null());
+            }
+            other => panic!("Expected a folder, got {:?}", other),
+        }
+
+        // An empty folder doesn't gain a `children` field.
+        assert_round_trips(r#"{
+            "id": "folderBBBBBB",
+            "type": "folder",
+            "parentid": "menu",
+            "title": "Empty"
+        }"#);
+
+        assert_round_trips(r#"{
+            "id": "separatorAA",
+            "type": "separator",
+            "parentid": "folderAAAAAA",
+            "pos": 1,
+            "futureSeparatorField": "x"
+        }"#);
+
+        assert_round_trips(r#"{
+            "id": "queryAAAAAAA",
+            "type": "query",
+            "parentid": "menu",
+            "title": "Tagged",
+            "bmkUri": "place:tag=a",
+            "folderName": "a"
+        }"#);
+    }
+
+    #[test]
+    fn test_unsupported_type() {
+        let json: JsonValue = serde_json::from_str(r#"{
+            "id": "livemarkAAAA",
+            "type": "livemark",
+            "feedUri": "https://www.example.com/feed"
+        }"#).unwrap();
+        assert!(serde_json::from_value::<BookmarkItemRecord>(json).is_err());
+    }
+}
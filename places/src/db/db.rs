@@ -6,13 +6,16 @@
 // wip-sync-sql-store branch, but with login specific code removed.
 // We should work out how to split this into a library we can reuse.
 
+use super::generation::ChangeCounter;
 use super::schema;
 use error::*;
 use hash;
+use idn;
 use rusqlite::{self, Connection};
 use sql_support::{self, ConnExt};
 use std::path::Path;
 use std::ops::Deref;
+use std::time::Duration;
 use unicode_segmentation::UnicodeSegmentation;
 use caseless::Caseless;
 
@@ -20,15 +23,55 @@ use api::matcher::{MatchBehavior, split_after_prefix, split_after_host_and_port}
 
 pub const MAX_VARIABLE_NUMBER: usize = 999;
 
+/// Desktop doesn't record a reload of a URL it visited less than 6 minutes ago
+/// at all (see `RECENTLY_VISITED_URIS_MAX_AGE` in History.cpp). We use the
+/// same window, but record the visit, and only leave it out of frecency.
+pub const DEFAULT_VISIT_DEBOUNCE_SECS: u64 = 6 * 60;
+
+/// Enough for a few hundred new-tab tiles at typical thumbnail sizes.
+pub const DEFAULT_THUMBNAIL_CACHE_SIZE: u64 = 10 * 1024 * 1024;
+
 pub struct PlacesDb {
     pub db: Connection,
+    visit_debounce: Option<Duration>,
+    thumbnail_cache_size: u64,
+    history_recording_enabled: bool,
+    autocomplete_match_url_path: bool,
+    changes: ChangeCounter,
 }
 
 fn unicode_normalize(s: &str) -> String {
     use unicode_normalization::UnicodeNormalization;
+    // ASCII is already normalized, and case folds to lowercase, so we can skip
+    // the (comparatively expensive) general case for most URLs and titles.
+    if s.is_ascii() {
+        return s.to_ascii_lowercase();
+    }
     s.chars().nfd().default_case_fold().nfd().collect()
 }
 
+/// The normalized tokens of the last search string passed to
+/// `autocomplete_match`. The search string is the same for every row in a
+/// query, so there's no need to normalize and split it each time.
+#[derive(Default)]
+struct SearchTokens {
+    search_string: String,
+    tokens: Vec<String>,
+}
+
+impl SearchTokens {
+    fn update(&mut self, search_string: String) -> &[String] {
+        if search_string != self.search_string {
+            self.tokens = unicode_normalize(&search_string)
+                .unicode_words()
+                .map(str::to_owned)
+                .collect();
+            self.search_string = search_string;
+        }
+        &self.tokens
+    }
+}
+
 impl PlacesDb {
     pub fn with_connection(db: Connection, encryption_key: Option<&str>) -> Result<Self> {
         #[cfg(test)] {
@@ -58,19 +101,107 @@ impl PlacesDb {
         db.execute_batch(&initial_pragmas)?;
         define_functions(&db)?;
 
-        let mut res = Self { db };
+        let mut res = Self {
+            db,
+            visit_debounce: Some(Duration::from_secs(DEFAULT_VISIT_DEBOUNCE_SECS)),
+            thumbnail_cache_size: DEFAULT_THUMBNAIL_CACHE_SIZE,
+            history_recording_enabled: true,
+            autocomplete_match_url_path: false,
+            changes: ChangeCounter::new(),
+        };
         schema::init(&mut res)?;
+        res.changes.ignore_changes(&res.db)?;
 
         Ok(res)
     }
 
     pub fn open(path: impl AsRef<Path>, encryption_key: Option<&str>) -> Result<Self> {
-        Ok(Self::with_connection(Connection::open(path)?, encryption_key)?)
+        let mut db = Self::with_connection(Connection::open(path.as_ref())?, encryption_key)?;
+        db.changes.share_for_path(path.as_ref());
+        Ok(db)
     }
 
     pub fn open_in_memory(encryption_key: Option<&str>) -> Result<Self> {
         Ok(Self::with_connection(Connection::open_in_memory()?, encryption_key)?)
     }
+
+    /// How close together two local visits to the same URL, with the same
+    /// transition, need to be for the second not to boost the page's frecency.
+    /// The visit is still recorded, as usual. None disables this.
+    #[inline]
+    pub fn visit_debounce(&self) -> Option<Duration> {
+        self.visit_debounce
+    }
+
+    #[inline]
+    pub fn set_visit_debounce(&mut self, debounce: Option<Duration>) {
+        self.visit_debounce = debounce;
+    }
+
+    /// The total size, in bytes, that stored thumbnails may take up before
+    /// maintenance starts evicting the least recently used ones.
+    #[inline]
+    pub fn thumbnail_cache_size(&self) -> u64 {
+        self.thumbnail_cache_size
+    }
+
+    #[inline]
+    pub fn set_thumbnail_cache_size(&mut self, size: u64) {
+        self.thumbnail_cache_size = size;
+    }
+
+    /// Whether local visits are being recorded. See
+    /// `set_history_recording_enabled`.
+    #[inline]
+    pub fn history_recording_enabled(&self) -> bool {
+        self.history_recording_enabled
+    }
+
+    /// Stops recording local history on this connection, or starts again, for
+    /// private browsing. While it's off, `apply_observation` ignores local
+    /// observations (visits, titles, and search terms alike), but still
+    /// applies remote ones, which happened on other devices. Accepted
+    /// autocomplete results and new thumbnails are ignored too, and reading a
+    /// thumbnail doesn't count as using it for eviction. Everything else,
+    /// including reading history, works as usual. This isn't persisted, so
+    /// it's on again for a new connection.
+    #[inline]
+    pub fn set_history_recording_enabled(&mut self, enabled: bool) {
+        self.history_recording_enabled = enabled;
+    }
+
+    /// The `match_url_path` to use for autocomplete searches made through the
+    /// FFI. See `set_autocomplete_match_url_path`.
+    #[inline]
+    pub fn autocomplete_match_url_path(&self) -> bool {
+        self.autocomplete_match_url_path
+    }
+
+    /// Lets autocomplete searches made through the FFI match the path and
+    /// query of URLs, as well as the host (see `SearchParams::match_url_path`).
+    /// It's off for a new connection, since matching the host only is faster.
+    #[inline]
+    pub fn set_autocomplete_match_url_path(&mut self, enabled: bool) {
+        self.autocomplete_match_url_path = enabled;
+    }
+
+    /// A number that changes whenever a connection to this database (opened
+    /// with `open`, in this process) publishes its writes with
+    /// `notify_changes`. A reader can remember the generation its results came
+    /// from, and only query again once it differs. This doesn't touch the
+    /// database, so it's cheap enough to check on every UI refresh.
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.changes.generation()
+    }
+
+    /// Lets the other connections to this database know about this
+    /// connection's writes, by bumping the generation if it's changed anything
+    /// since the last call. Returns the generation afterwards; once a reader
+    /// sees it, it's guaranteed to read those writes.
+    pub fn notify_changes(&self) -> Result<u64> {
+        self.changes.notify_changes(&self.db)
+    }
 }
 
 impl ConnExt for PlacesDb {
@@ -132,7 +263,8 @@ fn define_functions(c: &Connection) -> Result<()> {
         )?;
         Ok(rev_host)
     })?;
-    c.create_scalar_function("autocomplete_match", 9, true, move |ctx| {
+    let mut search_tokens = SearchTokens::default();
+    c.create_scalar_function("autocomplete_match", 10, true, move |ctx| {
         let search_string = ctx.get::<Option<String>>(0)?.unwrap_or_default();
         let url = ctx.get::<Option<String>>(1)?.unwrap_or_default();
         let title = ctx.get::<Option<String>>(2)?.unwrap_or_default();
@@ -142,6 +274,7 @@ fn define_functions(c: &Connection) -> Result<()> {
         let bookmarked = ctx.get::<bool>(6)?;
         let open_page_count = ctx.get::<Option<i64>>(7)?;
         let _match_behavior = ctx.get::<MatchBehavior>(8);
+        let match_url_path = ctx.get::<bool>(9)?;
 
         if !(visit_count > 0
             || bookmarked
@@ -151,18 +284,37 @@ fn define_functions(c: &Connection) -> Result<()> {
             return Ok(false);
         }
 
-        let trimmed_url = slice_up_to_safe(&url, 255);
-        let trimmed_title = slice_up_to_safe(&title, 255);
+        let tokens = search_tokens.update(search_string);
 
-        let norm_url = unicode_normalize(trimmed_url);
-        let norm_title = unicode_normalize(trimmed_title);
-        let norm_search = unicode_normalize(&search_string);
-        let norm_tags = unicode_normalize(&tags.unwrap_or_default());
-        let every_token_matched = norm_search
-            .unicode_words()
-            .all(|token| norm_url.contains(token) ||
-                         norm_tags.contains(token) ||
-                         norm_title.contains(token));
+        // Most tokens match the URL, so only normalize the title and tags if
+        // we need to. If we're not matching the path, we only look at the
+        // host, which is much shorter than most URLs.
+        let (host, _) = split_after_host_and_port(&url);
+        let url = if match_url_path {
+            slice_up_to_safe(&url, 255)
+        } else {
+            host
+        };
+        let norm_url = unicode_normalize(url);
+        let mut norm_display_host = None;
+        let mut norm_title = None;
+        let mut norm_tags = None;
+        let every_token_matched = tokens.iter().all(|token| {
+            norm_url.contains(token.as_str()) ||
+            // We store Punycode hosts, but the user probably typed the
+            // Unicode form.
+            norm_display_host.get_or_insert_with(|| {
+                if host.contains("xn--") {
+                    unicode_normalize(&idn::host_to_display(host))
+                } else {
+                    String::new()
+                }
+            }).contains(token.as_str()) ||
+            norm_title.get_or_insert_with(|| unicode_normalize(slice_up_to_safe(&title, 255)))
+                      .contains(token.as_str()) ||
+            norm_tags.get_or_insert_with(|| unicode_normalize(tags.as_ref().map_or("", String::as_str)))
+                     .contains(token.as_str())
+        });
 
         Ok(every_token_matched)
     })?;
@@ -215,6 +367,42 @@ mod tests {
         assert_eq!(rev_host, ".");
     }
 
+    #[test]
+    fn test_autocomplete_match() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let matches_with = |search: &str, url: &str, title: &str, match_url_path: bool| -> bool {
+            conn.db.query_row(
+                "SELECT autocomplete_match(?, ?, ?, NULL, 1, 0, 0, NULL, 1, ?)",
+                &[&search as &rusqlite::types::ToSql, &url, &title, &match_url_path],
+                |row| row.get(0),
+            ).unwrap()
+        };
+        let matches = |search: &str, url: &str, title: &str| matches_with(search, url, title, true);
+        assert!(matches("example", "https://example.com", ""));
+        assert!(matches("EXAMPLE page", "https://example.com", "Some Page"));
+        assert!(matches("straße", "https://example.com", "STRASSE"));
+        assert!(!matches("example missing", "https://example.com", "Some Page"));
+        // The search string changes between calls, so make sure we don't use
+        // stale tokens.
+        assert!(matches("page", "https://example.com", "Some Page"));
+        assert!(!matches("other", "https://example.com", "Some Page"));
+
+        let bug = "https://jira.example.com/browse/PROJ-123?focusedCommentId=456";
+        assert!(matches("jira PROJ-123", bug, "Some Bug"));
+        assert!(matches("focusedcommentid", bug, "Some Bug"));
+        assert!(!matches_with("jira PROJ-123", bug, "Some Bug", false));
+        assert!(matches_with("jira bug", bug, "Some Bug", false));
+        assert!(!matches_with("browse", bug, "Some Bug", false));
+
+        // Hosts are stored as Punycode, but can be matched in either form.
+        let idn = "https://xn--mnchen-3ya.de/stadt";
+        assert!(matches("münchen", idn, ""));
+        assert!(matches("MÜNCH", idn, ""));
+        assert!(matches("xn--mnchen", idn, ""));
+        assert!(matches_with("münchen.de", idn, "", false));
+        assert!(!matches("münchen stadtplan", idn, ""));
+    }
+
     // not part of the public api, but needs a test.
     #[test]
     fn test_slice_up_to() {
@@ -229,4 +417,34 @@ mod tests {
         assert_eq!(slice_up_to_safe(s, 7), "abcd");
         assert_eq!(slice_up_to_safe(s, 8), s);
     }
+
+    #[test]
+    fn test_generation() {
+        use api::thumbnails::set_thumbnail;
+        use url::Url;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        let writer = PlacesDb::open(&path, None).unwrap();
+        let reader = PlacesDb::open(&path, None).unwrap();
+        let other = PlacesDb::open_in_memory(None).unwrap();
+        let initial = reader.generation();
+
+        // Nothing's changed yet.
+        assert_eq!(writer.notify_changes().unwrap(), initial);
+
+        let url = Url::parse("https://www.example.com/").unwrap();
+        set_thumbnail(&writer, &url, &[1, 2, 3]).unwrap();
+        // Writes aren't visible through the generation until they're published.
+        assert_eq!(reader.generation(), initial);
+        let after_write = writer.notify_changes().unwrap();
+        assert_ne!(after_write, initial);
+        assert_eq!(reader.generation(), after_write);
+        assert_eq!(writer.notify_changes().unwrap(), after_write);
+
+        // Connections to other databases have their own generation.
+        set_thumbnail(&other, &url, &[1, 2, 3]).unwrap();
+        other.notify_changes().unwrap();
+        assert_eq!(reader.generation(), after_write);
+    }
 }
@@ -0,0 +1,97 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// A change counter shared by every connection to the same database file in
+// this process, so that a connection used for reads (and the caches built on
+// top of it, like a UI's list of history items) can cheaply tell whether
+// another connection has written anything since it last looked.
+//
+// SQLite itself never serves stale data: once a write commits, the next
+// statement on any connection sees it. The problem is knowing when to run
+// that statement. Writers publish their changes with
+// `PlacesDb::notify_changes`, which bumps the counter if the connection has
+// changed any rows since it was last called, and readers compare
+// `PlacesDb::generation` against the value they saw last. Reading the counter
+// doesn't touch the database, so it's fine to do on every UI refresh.
+//
+// This only covers connections in the same process. Changes made by other
+// processes aren't counted.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use rusqlite::Connection;
+
+use error::*;
+
+lazy_static! {
+    // Keyed by canonical path, so that different spellings of the same path
+    // share a counter. Entries go away with the last connection to the file.
+    static ref GENERATIONS: Mutex<HashMap<PathBuf, Weak<AtomicUsize>>> = Mutex::new(HashMap::new());
+}
+
+pub(crate) struct ChangeCounter {
+    shared: Arc<AtomicUsize>,
+    // This connection's `total_changes()` when we last bumped the counter.
+    published_changes: Cell<i64>,
+}
+
+impl ChangeCounter {
+    /// A counter that isn't shared with any other connection, which is all an
+    /// in-memory database needs. Connections to a file start with one of
+    /// these too, and call `share_for_path` once they're open.
+    pub fn new() -> ChangeCounter {
+        ChangeCounter {
+            shared: Arc::new(AtomicUsize::new(0)),
+            published_changes: Cell::new(0),
+        }
+    }
+
+    /// Switches to the counter shared by the other connections to the
+    /// database at `path`.
+    pub fn share_for_path(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+        let mut generations = GENERATIONS.lock().unwrap();
+        generations.retain(|_, generation| generation.upgrade().is_some());
+        self.shared = match generations.get(&path).and_then(Weak::upgrade) {
+            Some(shared) => shared,
+            None => {
+                let shared = Arc::new(AtomicUsize::new(0));
+                generations.insert(path, Arc::downgrade(&shared));
+                shared
+            }
+        };
+    }
+
+    /// Forgets about the changes `conn` has made so far, so that setting up
+    /// the schema when it's opened doesn't count as a write.
+    pub fn ignore_changes(&self, conn: &Connection) -> Result<()> {
+        self.published_changes.set(total_changes(conn)?);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.shared.load(Ordering::SeqCst) as u64
+    }
+
+    /// Bumps the shared counter if `conn` has changed anything since the last
+    /// call, and returns the (possibly new) generation.
+    pub fn notify_changes(&self, conn: &Connection) -> Result<u64> {
+        let total = total_changes(conn)?;
+        if total != self.published_changes.get() {
+            self.published_changes.set(total);
+            self.shared.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(self.generation())
+    }
+}
+
+fn total_changes(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("SELECT total_changes()", &[], |row| row.get(0))?)
+}
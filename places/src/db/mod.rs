@@ -6,4 +6,5 @@
 pub mod db;
 pub use db::db::PlacesDb;
 
-mod schema;
+mod generation;
+pub(crate) mod schema;
@@ -8,11 +8,14 @@
 // db.rs.
 
 use db::PlacesDb;
+use rusqlite::types::ToSql;
 use sql_support::ConnExt;
+use sync_guid::Guid;
 
 use error::*;
+use types::{BookmarkType, Timestamp};
 
-const VERSION: i64 = 1;
+const VERSION: i64 = 8;
 
 const CREATE_TABLE_PLACES_SQL: &str =
     "CREATE TABLE IF NOT EXISTS moz_places (
@@ -34,6 +37,11 @@ const CREATE_TABLE_PLACES_SQL: &str =
         description TEXT, -- XXXX - title above?
         preview_image_url TEXT,
         origin_id INTEGER, -- NOT NULL XXXX - not clear if there should always be a moz_origin
+        sync_status TINYINT NOT NULL DEFAULT 1, -- SyncStatus::New
+        sync_change_counter INTEGER NOT NULL DEFAULT 1,
+        -- Set for search engine results pages, to the terms that were searched
+        -- for. Not synced.
+        search_term TEXT,
 
         FOREIGN KEY(origin_id) REFERENCES moz_origins(id) ON DELETE CASCADE
     )";
@@ -48,6 +56,12 @@ const CREATE_TABLE_HISTORYVISITS_SQL: &str =
         visit_date INTEGER,
         visit_type INTEGER,
         -- session INTEGER, -- XXX - what is 'session'? Appears unused.
+        -- The container (contextual identity) the visit was made in, if any.
+        -- Only set for local visits, and never synced.
+        container_id TEXT,
+        -- Set for local visits that repeat a recent visit with the same
+        -- transition, which don't count towards frecency. Never synced.
+        is_debounced INTEGER NOT NULL DEFAULT 0,
 
         FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE,
         FOREIGN KEY(from_visit) REFERENCES moz_historyvisits(id)
@@ -63,24 +77,59 @@ const CREATE_TABLE_INPUTHISTORY_SQL: &str =
         FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE
     )";
 
+// Pages which have been removed locally (ie, "forget this page"), and which
+// the server needs to be told about. Only pages which had already been synced
+// end up here.
+const CREATE_TABLE_PLACES_TOMBSTONES_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_places_tombstones (
+        guid TEXT PRIMARY KEY
+    ) WITHOUT ROWID";
+
+// Individual visits which have been removed locally from a page which still
+// exists. The page itself is uploaded without these visits, and we use this
+// table to make sure that incoming records which still have them don't
+// resurrect them.
+const CREATE_TABLE_HISTORYVISIT_TOMBSTONES_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_historyvisit_tombstones (
+        place_id INTEGER NOT NULL,
+        visit_date INTEGER NOT NULL,
+
+        PRIMARY KEY(place_id, visit_date),
+        FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE
+    ) WITHOUT ROWID";
+
 // XXX - TODO - moz_annos
 // XXX - TODO - moz_anno_attributes
 // XXX - TODO - moz_items_annos
-// XXX - TODO - moz_bookmarks
 // XXX - TODO - moz_bookmarks_deleted
 
-// TODO: This isn't the complete `moz_bookmarks` definition, just enough to
-// test autocomplete.
+// TODO: This still isn't the complete `moz_bookmarks` definition (there's
+// nothing for sync yet), just enough for autocomplete and importing. `type` is
+// a `BookmarkType`, and tags are stored like desktop does: as bookmarks in a
+// folder for each tag, under the tags root.
 const CREATE_TABLE_BOOKMARKS_SQL: &str =
     "CREATE TABLE moz_bookmarks (
         id INTEGER PRIMARY KEY,
         fk INTEGER,
         title TEXT,
         lastModified INTEGER NOT NULL DEFAULT 0,
+        type INTEGER NOT NULL DEFAULT 1,
+        parent INTEGER,
+        position INTEGER NOT NULL DEFAULT 0,
+        dateAdded INTEGER NOT NULL DEFAULT 0,
+        guid TEXT,
 
         FOREIGN KEY(fk) REFERENCES moz_places(id) ON DELETE RESTRICT
     )";
 
+// The children of `Guid::ROOT`, as `(guid, title, position)`.
+static BOOKMARK_ROOTS: [(Guid, &str, i64); 5] = [
+    (Guid::MENU, "menu", 0),
+    (Guid::TOOLBAR, "toolbar", 1),
+    (Guid::TAGS, "tags", 2),
+    (Guid::UNFILED, "unfiled", 3),
+    (Guid::MOBILE, "mobile", 4),
+];
 
 // Note: desktop has/had a 'keywords' table, but we intentionally do not.
 
@@ -115,6 +164,22 @@ const CREATE_TRIGGER_AFTER_INSERT_ON_PLACES: &str = "
 
 // XXX - TODO - lots of favicon related tables - but it's not clear they make sense here yet?
 
+// Page thumbnails for new-tab tiles. These are keyed by URL rather than
+// referencing moz_places, since a tile can outlive the page's history. They're
+// a local cache: never synced, and evicted least recently used first during
+// maintenance once they exceed `PlacesDb::thumbnail_cache_size`. `size` is the
+// length of `data`, kept separately so eviction doesn't need to read blobs.
+const CREATE_TABLE_THUMBNAILS_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS moz_thumbnails (
+        url_hash INTEGER NOT NULL,
+        url TEXT NOT NULL,
+        data BLOB NOT NULL,
+        size INTEGER NOT NULL,
+        last_accessed INTEGER NOT NULL,
+
+        PRIMARY KEY (url_hash, url)
+    ) WITHOUT ROWID";
+
 // This table holds key-value metadata for Places and its consumers. Sync stores
 // the sync IDs for the bookmarks and history collections in this table, and the
 // last sync time for history.
@@ -148,6 +213,30 @@ const CREATE_IDX_MOZ_HISTORYVISITS_VISITDATE: &str = "CREATE INDEX dateindex ON
 
 const CREATE_IDX_MOZ_HISTORYVISITS_ISLOCAL: &str = "CREATE INDEX islocalindex ON moz_historyvisits(is_local)";
 
+// These indices were added in v3 to speed up autocomplete (see the `search`
+// benchmark). Origin matches look up hosts by prefix and sum their frecencies,
+// which `(host, frecency)` covers completely; URL matches look up origins by
+// `rev_host`.
+const CREATE_IDX_MOZ_ORIGINS_HOST_FRECENCY: &str = "CREATE INDEX IF NOT EXISTS originhostfrecencyindex ON moz_origins(host, frecency)";
+const CREATE_IDX_MOZ_ORIGINS_REVHOST: &str = "CREATE INDEX IF NOT EXISTS originrevhostindex ON moz_origins(rev_host)";
+
+// Adaptive matches do a prefix search on `input`, which the primary key can't
+// help with since it starts with `place_id`.
+const CREATE_IDX_MOZ_INPUTHISTORY_INPUT: &str = "CREATE INDEX IF NOT EXISTS inputhistoryinputindex ON moz_inputhistory(input, place_id, use_count)";
+
+// Every match looks up the most recently modified bookmark title for a page.
+const CREATE_IDX_MOZ_BOOKMARKS_ITEMLASTMODIFIED: &str = "CREATE INDEX IF NOT EXISTS itemlastmodifiedindex ON moz_bookmarks(fk, lastModified)";
+
+const CREATE_IDX_MOZ_BOOKMARKS_GUID: &str = "CREATE UNIQUE INDEX IF NOT EXISTS bookmarkguidindex ON moz_bookmarks(guid)";
+const CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION: &str = "CREATE INDEX IF NOT EXISTS parentindex ON moz_bookmarks(parent, position)";
+
+// Only a small fraction of pages are search results pages, so this is a partial
+// index.
+const CREATE_IDX_MOZ_PLACES_SEARCH_TERM: &str = "CREATE INDEX IF NOT EXISTS searchtermindex ON moz_places(search_term) WHERE search_term NOT NULL";
+
+// Eviction walks thumbnails from least to most recently used.
+const CREATE_IDX_MOZ_THUMBNAILS_LASTACCESSED: &str = "CREATE INDEX IF NOT EXISTS thumbnaillastaccessedindex ON moz_thumbnails(last_accessed)";
+
 
 // Keys in the moz_meta table.
 // pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_COUNT: &'static str = "origin_frecency_count";
@@ -173,13 +262,76 @@ pub fn init(db: &PlacesDb) -> Result<()> {
 }
 
 // https://github.com/mozilla-mobile/firefox-ios/blob/master/Storage/SQL/LoginsSchema.swift#L100
-fn upgrade(_db: &PlacesDb, from: i64) -> Result<()> {
+fn upgrade(db: &PlacesDb, from: i64) -> Result<()> {
     debug!("Upgrading schema from {} to {}", from, VERSION);
     if from == VERSION {
         return Ok(());
     }
-    // hrmph - do something here?
-    panic!("sorry, no upgrades yet - delete your db!");
+    if from < 1 {
+        // hrmph - do something here?
+        panic!("sorry, no upgrades yet - delete your db!");
+    }
+    if from < 2 {
+        // Everything that exists at this point was created before we knew
+        // about sync, so is still `New`.
+        db.execute_all(&[
+            "ALTER TABLE moz_places ADD COLUMN sync_status TINYINT NOT NULL DEFAULT 1",
+            "ALTER TABLE moz_places ADD COLUMN sync_change_counter INTEGER NOT NULL DEFAULT 1",
+            CREATE_TABLE_PLACES_TOMBSTONES_SQL,
+            CREATE_TABLE_HISTORYVISIT_TOMBSTONES_SQL,
+        ])?;
+    }
+    if from < 3 {
+        db.execute_all(&[
+            CREATE_IDX_MOZ_ORIGINS_HOST_FRECENCY,
+            CREATE_IDX_MOZ_ORIGINS_REVHOST,
+            CREATE_IDX_MOZ_INPUTHISTORY_INPUT,
+            CREATE_IDX_MOZ_BOOKMARKS_ITEMLASTMODIFIED,
+            "ANALYZE",
+        ])?;
+    }
+    if from < 4 {
+        db.execute_all(&[
+            "ALTER TABLE moz_places ADD COLUMN search_term TEXT",
+            CREATE_IDX_MOZ_PLACES_SEARCH_TERM,
+        ])?;
+    }
+    if from < 5 {
+        db.execute_all(&[
+            CREATE_TABLE_THUMBNAILS_SQL,
+            CREATE_IDX_MOZ_THUMBNAILS_LASTACCESSED,
+        ])?;
+    }
+    if from < 6 {
+        db.execute_all(&[
+            "ALTER TABLE moz_historyvisits ADD COLUMN container_id TEXT",
+        ])?;
+    }
+    if from < 7 {
+        // Bookmarks written before this (which only tests did) are left
+        // without a parent, so they're only visible to autocomplete.
+        db.execute_all(&[
+            "ALTER TABLE moz_bookmarks ADD COLUMN type INTEGER NOT NULL DEFAULT 1",
+            "ALTER TABLE moz_bookmarks ADD COLUMN parent INTEGER",
+            "ALTER TABLE moz_bookmarks ADD COLUMN position INTEGER NOT NULL DEFAULT 0",
+            "ALTER TABLE moz_bookmarks ADD COLUMN dateAdded INTEGER NOT NULL DEFAULT 0",
+            "ALTER TABLE moz_bookmarks ADD COLUMN guid TEXT",
+            CREATE_IDX_MOZ_BOOKMARKS_GUID,
+            CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION,
+        ])?;
+        create_bookmark_roots(db)?;
+    }
+    if from < 8 {
+        // Repeats debounced before this were stored as reloads, so they
+        // already have no frecency bonus.
+        db.execute_all(&[
+            "ALTER TABLE moz_historyvisits ADD COLUMN is_debounced INTEGER NOT NULL DEFAULT 0",
+        ])?;
+    }
+    db.execute_all(&[
+        &format!("PRAGMA user_version = {version}", version = VERSION),
+    ])?;
+    Ok(())
 }
 
 pub fn create(db: &PlacesDb) -> Result<()> {
@@ -191,6 +343,9 @@ pub fn create(db: &PlacesDb) -> Result<()> {
         CREATE_TABLE_BOOKMARKS_SQL,
         CREATE_TABLE_ORIGINS_SQL,
         CREATE_TABLE_META_SQL,
+        CREATE_TABLE_PLACES_TOMBSTONES_SQL,
+        CREATE_TABLE_HISTORYVISIT_TOMBSTONES_SQL,
+        CREATE_TABLE_THUMBNAILS_SQL,
         CREATE_IDX_MOZ_PLACES_URL_HASH,
         CREATE_IDX_MOZ_PLACES_VISITCOUNT_LOCAL,
         CREATE_IDX_MOZ_PLACES_VISITCOUNT_REMOTE,
@@ -203,9 +358,18 @@ pub fn create(db: &PlacesDb) -> Result<()> {
         CREATE_IDX_MOZ_HISTORYVISITS_FROMVISIT,
         CREATE_IDX_MOZ_HISTORYVISITS_VISITDATE,
         CREATE_IDX_MOZ_HISTORYVISITS_ISLOCAL,
+        CREATE_IDX_MOZ_ORIGINS_HOST_FRECENCY,
+        CREATE_IDX_MOZ_ORIGINS_REVHOST,
+        CREATE_IDX_MOZ_INPUTHISTORY_INPUT,
+        CREATE_IDX_MOZ_BOOKMARKS_ITEMLASTMODIFIED,
+        CREATE_IDX_MOZ_BOOKMARKS_GUID,
+        CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION,
+        CREATE_IDX_MOZ_PLACES_SEARCH_TERM,
+        CREATE_IDX_MOZ_THUMBNAILS_LASTACCESSED,
         &format!("PRAGMA user_version = {version}",
                  version = VERSION),
     ])?;
+    create_bookmark_roots(db)?;
 
     debug!("Creating temp tables and triggers");
     db.execute_all(&[
@@ -214,3 +378,26 @@ pub fn create(db: &PlacesDb) -> Result<()> {
 
     Ok(())
 }
+
+fn create_bookmark_roots(db: &PlacesDb) -> Result<()> {
+    let now = Timestamp::now();
+    db.execute_named_cached("
+        INSERT OR IGNORE INTO moz_bookmarks(type, parent, position, title, dateAdded, lastModified, guid)
+        VALUES (:type, NULL, 0, 'root', :now, :now, :guid)",
+        &[(":type", &BookmarkType::Folder as &ToSql),
+          (":now", &now as &ToSql),
+          (":guid", &Guid::ROOT as &ToSql)])?;
+    for &(ref guid, title, position) in BOOKMARK_ROOTS.iter() {
+        db.execute_named_cached("
+            INSERT OR IGNORE INTO moz_bookmarks(type, parent, position, title, dateAdded, lastModified, guid)
+            SELECT :type, id, :position, :title, :now, :now, :guid
+            FROM moz_bookmarks WHERE guid = :rootGuid",
+            &[(":type", &BookmarkType::Folder as &ToSql),
+              (":position", &position as &ToSql),
+              (":title", &title as &ToSql),
+              (":now", &now as &ToSql),
+              (":guid", guid as &ToSql),
+              (":rootGuid", &Guid::ROOT as &ToSql)])?;
+    }
+    Ok(())
+}
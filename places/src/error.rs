@@ -40,6 +40,63 @@ impl Error {
     pub fn kind(&self) -> &ErrorKind {
         &*self.0.get_context()
     }
+
+    /// Returns which `ErrorCategory` this error falls into. Unlike `kind`,
+    /// this is meant for callers to match on.
+    pub fn category(&self) -> ErrorCategory {
+        match self.kind() {
+            ErrorKind::UrlParseError(_) => ErrorCategory::InvalidUrl,
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::NoUrl) => ErrorCategory::InvalidObservation {
+                field: Some("url".into()),
+            },
+            ErrorKind::InvalidObservation(field) => ErrorCategory::InvalidObservation {
+                field: field.clone(),
+            },
+            ErrorKind::JsonError(_) => ErrorCategory::InvalidInput,
+            ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _)) => match err.code {
+                rusqlite::ErrorCode::DatabaseBusy |
+                rusqlite::ErrorCode::DatabaseLocked => ErrorCategory::DatabaseBusy,
+                rusqlite::ErrorCode::DatabaseCorrupt |
+                rusqlite::ErrorCode::NotADatabase => ErrorCategory::Corrupt,
+                _ => ErrorCategory::Unexpected,
+            },
+            _ => ErrorCategory::Unexpected,
+        }
+    }
+}
+
+/// The broad kinds of errors places reports. `ErrorKind` wraps the errors of
+/// the crates we use, and changes as our internals do; these are stable, and
+/// are what callers should use to decide how to handle an error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// A URL couldn't be parsed. Retrying won't help.
+    InvalidUrl,
+
+    /// Another connection holds a lock on the database. The operation can be
+    /// retried later.
+    DatabaseBusy,
+
+    /// The database is corrupt, or isn't a places database at all.
+    Corrupt,
+
+    /// A `VisitObservation` (or its JSON) was invalid. `field` names the
+    /// (camelCase) field at fault, if we know which one it is.
+    InvalidObservation { field: Option<String> },
+
+    /// Other JSON passed to us (like a list of URLs) was invalid.
+    InvalidInput,
+
+    /// Anything else. These are usually bugs, and aren't worth retrying.
+    Unexpected,
+}
+
+impl ErrorCategory {
+    /// Returns true if the operation that failed might succeed if retried.
+    #[inline]
+    pub fn is_transient(&self) -> bool {
+        *self == ErrorCategory::DatabaseBusy
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -62,6 +119,9 @@ pub enum ErrorKind {
     #[fail(display = "Invalid place info: {}", _0)]
     InvalidPlaceInfo(InvalidPlaceInfo),
 
+    #[fail(display = "Invalid observation (field: {:?})", _0)]
+    InvalidObservation(Option<String>),
+
 //    #[fail(display = "The `sync_status` column in DB has an illegal value: {}", _0)]
 //    BadSyncStatus(u8),
 
@@ -116,3 +176,33 @@ pub enum InvalidPlaceInfo {
     NoUrl,
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sqlite_error(code: i32) -> Error {
+        rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(code), None).into()
+    }
+
+    #[test]
+    fn test_categories() {
+        assert_eq!(Error::from(url::Url::parse("not a url").unwrap_err()).category(),
+                   ErrorCategory::InvalidUrl);
+        assert_eq!(Error::from(InvalidPlaceInfo::NoUrl).category(),
+                   ErrorCategory::InvalidObservation { field: Some("url".into()) });
+        assert_eq!(Error::from(serde_json::from_str::<Vec<String>>("[1]").unwrap_err()).category(),
+                   ErrorCategory::InvalidInput);
+
+        let busy = sqlite_error(rusqlite::ffi::SQLITE_BUSY).category();
+        assert_eq!(busy, ErrorCategory::DatabaseBusy);
+        assert!(busy.is_transient());
+        assert_eq!(sqlite_error(rusqlite::ffi::SQLITE_LOCKED).category(), ErrorCategory::DatabaseBusy);
+        assert_eq!(sqlite_error(rusqlite::ffi::SQLITE_CORRUPT).category(), ErrorCategory::Corrupt);
+        assert_eq!(sqlite_error(rusqlite::ffi::SQLITE_NOTADB).category(), ErrorCategory::Corrupt);
+
+        let other = sqlite_error(rusqlite::ffi::SQLITE_CONSTRAINT).category();
+        assert_eq!(other, ErrorCategory::Unexpected);
+        assert!(!other.is_transient());
+    }
+}
@@ -0,0 +1,65 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Support for the FFI, which lives in the `places-ffi` crate. The conversion
+// from our errors needs to be here, since neither `Error` nor `ExternError` are
+// defined in that crate.
+
+use error::{Error, ErrorCategory};
+use ffi_support::{ErrorChain, ErrorCode, ErrorCodeSpace, ExternError};
+
+/// The domain of our links in an `ErrorChain`.
+pub const ERROR_DOMAIN: &str = "places";
+
+/// The codes in `error_codes`, and the ones we'll add to it, which the FFI
+/// registers with `ffi_support::register_error_code_space`. This is the range
+/// assigned to us in `ffi_support`'s `code_space` module.
+pub const ERROR_CODES: ErrorCodeSpace = ErrorCodeSpace {
+    component: ERROR_DOMAIN,
+    start: 100,
+    end: 200,
+};
+
+/// The error codes reported in `ExternError::code` by the places FFI, besides
+/// the ones reserved by `ErrorCode`. Errors we don't expect the application to
+/// handle, like database errors, are reported as `ErrorCode::UNEXPECTED`.
+pub mod error_codes {
+    /// A URL passed over the FFI couldn't be parsed.
+    pub const URL_PARSE_ERROR: i32 = 100;
+
+    /// Data passed over the FFI (such as a JSON observation) was invalid.
+    pub const INVALID_INPUT: i32 = 101;
+
+    /// The database was locked by another connection. The call can be
+    /// retried.
+    pub const DATABASE_BUSY: i32 = 102;
+
+    /// The database is corrupt, or isn't a places database.
+    pub const DATABASE_CORRUPT: i32 = 103;
+}
+
+fn get_code(err: &Error) -> ErrorCode {
+    match err.category() {
+        ErrorCategory::InvalidUrl => ErrorCode::new(error_codes::URL_PARSE_ERROR),
+        ErrorCategory::InvalidObservation { .. } |
+        ErrorCategory::InvalidInput => ErrorCode::new(error_codes::INVALID_INPUT),
+        ErrorCategory::DatabaseBusy => ErrorCode::new(error_codes::DATABASE_BUSY),
+        ErrorCategory::Corrupt => ErrorCode::new(error_codes::DATABASE_CORRUPT),
+        ErrorCategory::Unexpected => ErrorCode::UNEXPECTED,
+    }
+}
+
+impl Error {
+    /// Describe this error for the FFI. None of our errors wrap those of other
+    /// components yet, so this is always a single link.
+    pub fn error_chain(&self) -> ErrorChain {
+        ErrorChain::new(ERROR_DOMAIN, get_code(self), self.to_string())
+    }
+}
+
+impl From<Error> for ExternError {
+    fn from(e: Error) -> ExternError {
+        e.error_chain().into()
+    }
+}
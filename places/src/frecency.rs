@@ -170,6 +170,7 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
             SELECT
                 IFNULL(origin.visit_type, v.visit_type) AS visit_type,
                 target.visit_type AS target_visit_type,
+                v.is_debounced,
                 ROUND((strftime('%s','now','localtime','utc') - v.visit_date/1000000)/86400) AS age_in_days
             FROM moz_historyvisits v
             LEFT JOIN moz_historyvisits origin ON origin.id = v.from_visit
@@ -189,9 +190,11 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
         let row_iter = stmt.query_map_named(&[(":page_id", &self.page_id)], |row| {
             let visit_type = row.get::<_, Option<u32>>("visit_type").unwrap_or(0);
             let target_visit_type = row.get::<_, Option<u32>>("target_visit_type").unwrap_or(0);
+            let is_debounced: bool = row.get("is_debounced");
             let age_in_days: f64 = row.get("age_in_days");
             (VisitTransition::from_primitive(visit_type),
              VisitTransition::from_primitive(target_visit_type),
+             is_debounced,
              age_in_days as i32)
         })?;
 
@@ -199,7 +202,7 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
         let mut points_for_sampled_visits = 0.0f32;
 
         for row_result in row_iter {
-            let (visit_type, target_visit_type, age_in_days) = row_result?;
+            let (visit_type, target_visit_type, is_debounced, age_in_days) = row_result?;
             // When adding a new visit, we should haved passed-in whether we should
             // use the redirect bonus. We can't fetch this information from the
             // database, because we only store redirect targets.
@@ -213,7 +216,13 @@ impl<'db, 's> FrecencyComputation<'db, 's> {
                     self.most_recent_redirect_bonus == RedirectBonus::Redirect
                 };
 
-            let mut bonus = self.settings.get_transition_bonus(visit_type, true, use_redirect_bonus);
+            // Repeats of a recent visit count as visits, but not towards the
+            // bonus; see `apply_observation`.
+            let mut bonus = if is_debounced {
+                0
+            } else {
+                self.settings.get_transition_bonus(visit_type, true, use_redirect_bonus)
+            };
 
             if self.has_bookmark() {
                 bonus += self.settings.get_transition_bonus(Some(VisitTransition::Bookmark), true, false);
@@ -0,0 +1,102 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The records in the `history` collection.
+//!
+//! Newer clients may add fields to these records that we don't know about.
+//! Every record struct (including the visits) keeps those in a flattened
+//! `extra` map, so that when we re-upload a record we've changed, we send them
+//! back unchanged instead of deleting them. Don't put known fields in `extra`,
+//! since they'd be serialized twice.
+
+use serde_json::{Map, Value as JsonValue};
+use types::SyncGuid;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryRecordVisit {
+    /// Microseconds since the epoch.
+    pub date: u64,
+
+    /// A `VisitTransition`, as an integer. We keep the raw value, since
+    /// other clients may record transitions we don't know about.
+    #[serde(rename = "type")]
+    pub transition: u8,
+
+    // Note that visits don't have a container: those are local to the
+    // device, so we never upload them.
+
+    #[serde(flatten)]
+    pub extra: Map<String, JsonValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryRecord {
+    pub id: SyncGuid,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub title: String,
+
+    pub hist_uri: String,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub visits: Vec<HistoryRecordVisit>,
+
+    #[serde(flatten)]
+    pub extra: Map<String, JsonValue>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use sync::Payload;
+
+    #[test]
+    fn test_round_trip_unknown_fields() {
+        let json: JsonValue = serde_json::from_str(r#"{
+            "id": "aaaaaaaaaaaa",
+            "title": "Example",
+            "histUri": "https://www.example.com/",
+            "visits": [
+                {"date": 1540000000000000, "type": 1},
+                {"date": 1540000001000000, "type": 42, "futureVisitField": true}
+            ],
+            "futureField": {"nested": ["a", "b"]}
+        }"#).unwrap();
+
+        let record: HistoryRecord = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(record.id, SyncGuid("aaaaaaaaaaaa".into()));
+        assert_eq!(record.visits[1].transition, 42);
+        assert_eq!(record.visits[0].extra.len(), 0);
+        assert_eq!(record.visits[1].extra["futureVisitField"], true);
+        assert_eq!(record.extra.len(), 1);
+        assert_eq!(serde_json::to_value(&record).unwrap(), json);
+
+        // Changing a known field leaves the unknown ones alone, including
+        // through the sync payload.
+        let mut changed = record.clone();
+        changed.title = "Changed".into();
+        let payload = Payload::from_record(changed).unwrap();
+        assert_eq!(payload.id, "aaaaaaaaaaaa");
+        let round_tripped: HistoryRecord = payload.into_record().unwrap();
+        assert_eq!(round_tripped.title, "Changed");
+        assert_eq!(round_tripped.extra, record.extra);
+        assert_eq!(round_tripped.visits, record.visits);
+    }
+
+    #[test]
+    fn test_missing_fields_stay_missing() {
+        let json: JsonValue = serde_json::from_str(r#"{
+            "id": "aaaaaaaaaaaa",
+            "histUri": "https://www.example.com/"
+        }"#).unwrap();
+        let record: HistoryRecord = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(record.title, "");
+        assert!(record.visits.is_empty());
+        assert_eq!(serde_json::to_value(&record).unwrap(), json);
+    }
+}
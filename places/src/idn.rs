@@ -0,0 +1,165 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Internationalized domain names.
+//!
+//! We always store hosts (in `moz_places.url` and `moz_origins`) in their
+//! ASCII, Punycode form ("xn--mnchen-3ya.de"), since that's what `Url`
+//! gives us. Users type and expect to see the Unicode form ("münchen.de"),
+//! though, so the matcher converts what they type with `host_to_ascii`, and
+//! results are shown with `host_to_display` and `display_url`.
+//!
+//! Unicode hosts can be used to spoof other sites, with characters that look
+//! like ASCII letters ("аррle.com" is Cyrillic), so, like Desktop, we only
+//! show the Unicode form of hosts that look legitimate, and fall back to
+//! Punycode for the rest.
+
+use idna;
+use url::{Position, Url};
+
+// Splits a trailing port off of `host`, which is what
+// `split_after_host_and_port` gives us.
+fn split_port(host: &str) -> (&str, &str) {
+    if let Some(index) = host.rfind(':') {
+        let port = &host[index + 1..];
+        if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) {
+            return (&host[..index], &host[index..]);
+        }
+    }
+    (host, "")
+}
+
+/// Converts `host` (which may have a port) to the ASCII form we store.
+/// Hosts that are already ASCII are only lowercased, and hosts that can't be
+/// converted are returned as they are, since they won't match anything
+/// anyway.
+pub fn host_to_ascii(host: &str) -> String {
+    if host.is_ascii() {
+        return host.to_ascii_lowercase();
+    }
+    let (name, port) = split_port(host);
+    match idna::domain_to_ascii(name) {
+        Ok(ascii) => ascii + port,
+        Err(_) => host.to_owned(),
+    }
+}
+
+/// Converts `host` (which may have a port) to the form to show in the UI:
+/// the Unicode form if it looks legitimate, otherwise the Punycode form.
+pub fn host_to_display(host: &str) -> String {
+    let (name, port) = split_port(host);
+    if !name.split('.').any(|label| label.starts_with("xn--")) {
+        return host.to_owned();
+    }
+    match idna::domain_to_unicode(name) {
+        (ref unicode, Ok(())) if is_safe_host(unicode) => {
+            format!("{}{}", unicode, port)
+        }
+        _ => host.to_owned(),
+    }
+}
+
+/// Returns `url` with its host as returned by `host_to_display`, for the UI.
+/// This is only for showing to the user; it's not necessarily a valid URL.
+pub fn display_url(url: &Url) -> String {
+    let host = match url.host_str() {
+        Some(host) => host,
+        None => return url.as_str().to_owned(),
+    };
+    let display_host = host_to_display(host);
+    if display_host == host {
+        return url.as_str().to_owned();
+    }
+    format!("{}{}{}", &url[..Position::BeforeHost], display_host, &url[Position::AfterHost..])
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Other,
+}
+
+fn script(c: char) -> Option<Script> {
+    match c as u32 {
+        0x30..=0x39 | 0x2d => None, // Digits and '-' go with anything.
+        0x61..=0x7a | 0xdf..=0x24f => Some(Script::Latin),
+        0x370..=0x3ff | 0x1f00..=0x1fff => Some(Script::Greek),
+        0x400..=0x52f => Some(Script::Cyrillic),
+        _ => Some(Script::Other),
+    }
+}
+
+// The script of the Latin, Greek, or Cyrillic letters in `label`, if it has
+// any, or `Err(())` if it mixes them (which is how most spoofs work), or has
+// anything but letters, digits, and '-' (so no lookalike slashes or dots).
+fn label_script(label: &str) -> Result<Option<Script>, ()> {
+    if !label.chars().all(|c| c.is_alphanumeric() || c == '-') {
+        return Err(());
+    }
+    let mut seen = None;
+    for script in label.chars().filter_map(script) {
+        if script == Script::Other {
+            continue;
+        }
+        match seen {
+            Some(s) if s != script => return Err(()),
+            _ => seen = Some(script),
+        }
+    }
+    Ok(seen)
+}
+
+// A deliberately simple version of Desktop's "moderately restrictive"
+// policy. Labels can't mix scripts (see `label_script`), and since a whole
+// label can be spelled in Cyrillic (or Greek) letters that look like Latin
+// ones ("аррӏе.com"), those are only allowed under a TLD in the same script,
+// where they're expected ("пример.рф").
+fn is_safe_host(host: &str) -> bool {
+    let tld_script = match host.rsplit('.').next().map(label_script) {
+        Some(Ok(script)) => script,
+        _ => return false,
+    };
+    host.split('.').all(|label| match label_script(label) {
+        Ok(Some(script)) if script != Script::Latin => Some(script) == tld_script,
+        Ok(_) => true,
+        Err(()) => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_to_ascii() {
+        assert_eq!(host_to_ascii("example.com"), "example.com");
+        assert_eq!(host_to_ascii("EXAMPLE.com:8080"), "example.com:8080");
+        assert_eq!(host_to_ascii("münchen.de"), "xn--mnchen-3ya.de");
+        assert_eq!(host_to_ascii("MÜNCHEN.de:8080"), "xn--mnchen-3ya.de:8080");
+    }
+
+    #[test]
+    fn test_host_to_display() {
+        assert_eq!(host_to_display("example.com"), "example.com");
+        assert_eq!(host_to_display("xn--mnchen-3ya.de"), "münchen.de");
+        assert_eq!(host_to_display("xn--mnchen-3ya.de:8080"), "münchen.de:8080");
+        assert_eq!(host_to_display("xn--e1afmkfd.xn--p1ai"), "пример.рф");
+        // Mixed Latin and Cyrillic ("аpple", with a Cyrillic "а").
+        assert_eq!(host_to_display("xn--pple-43d.com"), "xn--pple-43d.com");
+        // All Cyrillic ("аррӏе"), but under a Latin TLD.
+        assert_eq!(host_to_display("xn--80ak6aa92e.com"), "xn--80ak6aa92e.com");
+        assert_eq!(host_to_display("xn--80ak6aa92e.xn--p1ai"), "аррӏе.рф");
+    }
+
+    #[test]
+    fn test_display_url() {
+        let url = Url::parse("https://user@münchen.de:8080/straße?q=1").unwrap();
+        assert_eq!(url.host_str(), Some("xn--mnchen-3ya.de"));
+        assert_eq!(display_url(&url), "https://user@münchen.de:8080/stra%C3%9Fe?q=1");
+        let url = Url::parse("https://example.com/").unwrap();
+        assert_eq!(display_url(&url), "https://example.com/");
+    }
+}
@@ -10,6 +10,9 @@ extern crate log;
 #[cfg(test)]
 extern crate env_logger;
 
+#[cfg(test)]
+extern crate tempfile;
+
 extern crate failure;
 
 extern crate unicode_segmentation;
@@ -18,6 +21,10 @@ extern crate unicode_segmentation;
 extern crate failure_derive;
 
 extern crate url;
+extern crate idna;
+
+#[macro_use]
+extern crate lazy_static;
 
 extern crate rusqlite;
 
@@ -30,6 +37,8 @@ extern crate serde_derive;
 extern crate caseless;
 extern crate unicode_normalization;
 extern crate sql_support;
+extern crate ffi_support;
+extern crate sync_guid;
 
 pub mod api;
 pub mod error;
@@ -40,6 +49,10 @@ pub mod storage;
 pub mod hash;
 pub mod frecency;
 pub mod observation;
+pub mod ffi;
+pub mod history_sync;
+pub mod bookmark_sync;
+pub mod idn;
 
 pub use error::*;
 pub use types::*;
@@ -2,6 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use error::*;
+use serde_json::{self, Map, Value};
 use types::*;
 use url::{Url};
 
@@ -18,7 +20,11 @@ use url::{Url};
 /// It exposes a "builder api", but for convenience, that API allows Options too.
 /// So, eg, `.with_title(None)` or `with_is_error(None)` is allowed but records
 /// no observation.
-#[derive(Debug)]
+///
+/// Observations can also be deserialized from JSON (with camelCase keys), which
+/// is how they're passed over the FFI.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct VisitObservation {
     pub url: Url,
     pub title: Option<String>,
@@ -29,6 +35,17 @@ pub struct VisitObservation {
     pub at: Option<Timestamp>,
     pub referrer: Option<Url>,
     pub is_remote: Option<bool>,
+    /// For remote visits, the guid of the history record they came from. A
+    /// page first seen through such a visit takes this guid, so that it
+    /// matches the record that's already on the server.
+    pub guid: Option<SyncGuid>,
+    /// The terms that were searched for, if the page is a search engine
+    /// results page. The caller is responsible for recognizing these, since
+    /// it knows which search engines are installed.
+    pub search_term: Option<String>,
+    /// The id of the container (contextual identity) the page was visited
+    /// in, if any. This is only recorded for local visits, and isn't synced.
+    pub container_id: Option<String>,
 }
 
 impl VisitObservation {
@@ -42,10 +59,31 @@ impl VisitObservation {
             is_permanent_redirect_source: None,
             at: None,
             referrer: None,
-            is_remote: None
+            is_remote: None,
+            guid: None,
+            search_term: None,
+            container_id: None,
         }
     }
 
+    /// Parses an observation from JSON, as passed over the FFI. Fails with
+    /// `ErrorKind::InvalidObservation`, naming the field at fault if there's
+    /// just one.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(json).map_err(|e| {
+            debug!("Observation isn't valid JSON: {}", e);
+            ErrorKind::InvalidObservation(None)
+        })?;
+        serde_json::from_value(value.clone()).map_err(|e| {
+            debug!("Invalid observation: {}", e);
+            let field = match value {
+                Value::Object(obj) => find_invalid_field(&obj),
+                _ => None,
+            };
+            ErrorKind::InvalidObservation(field).into()
+        })
+    }
+
     // A "builder" API to sanely build an observation. Note that this can be
     // called with Option<String> (and if None will effectively be a no-op)
     // or directly with a string.
@@ -84,11 +122,26 @@ impl VisitObservation {
         self
     }
 
+    pub fn with_guid(mut self, v: impl Into<Option<SyncGuid>>) -> Self {
+        self.guid = v.into();
+        self
+    }
+
     pub fn with_referrer(mut self, v: impl Into<Option<Url>>) -> Self {
         self.referrer = v.into();
         self
     }
 
+    pub fn with_search_term(mut self, v: impl Into<Option<String>>) -> Self {
+        self.search_term = v.into();
+        self
+    }
+
+    pub fn with_container_id(mut self, v: impl Into<Option<String>>) -> Self {
+        self.container_id = v.into();
+        self
+    }
+
     // Other helpers which can be derived.
     pub fn get_redirect_frecency_boost(&self) -> bool {
         self.is_redirect_source.is_some() &&
@@ -109,3 +162,60 @@ impl VisitObservation {
         }
     }
 }
+
+/// Finds the field that stops `obj` from deserializing, by deserializing the
+/// url on its own, and then each of the other fields along with it. serde's
+/// errors don't say which field they're about, and this only runs when
+/// parsing has already failed, so the extra work doesn't matter.
+fn find_invalid_field(obj: &Map<String, Value>) -> Option<String> {
+    let url = match obj.get("url") {
+        Some(url) => url,
+        None => return Some("url".into()),
+    };
+    let parses = |extra: Option<(&String, &Value)>| {
+        let mut fields = Map::new();
+        fields.insert("url".into(), url.clone());
+        if let Some((key, value)) = extra {
+            fields.insert(key.clone(), value.clone());
+        }
+        serde_json::from_value::<VisitObservation>(Value::Object(fields)).is_ok()
+    };
+    if !parses(None) {
+        return Some("url".into());
+    }
+    let mut invalid = obj.iter().filter(|&(key, value)| key != "url" && !parses(Some((key, value))));
+    match (invalid.next(), invalid.next()) {
+        (Some((key, _)), None) => Some(key.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn invalid_field(json: &str) -> Option<String> {
+        match VisitObservation::from_json(json).unwrap_err().category() {
+            ErrorCategory::InvalidObservation { field } => field,
+            category => panic!("Unexpected error category {:?}", category),
+        }
+    }
+
+    #[test]
+    fn test_from_json() {
+        let obs = VisitObservation::from_json(
+            r#"{"url": "https://example.com", "visitType": 1, "isRemote": true}"#).unwrap();
+        assert_eq!(obs.url.as_str(), "https://example.com/");
+        assert_eq!(obs.visit_type, Some(VisitTransition::Link));
+        assert_eq!(obs.is_remote, Some(true));
+
+        assert_eq!(invalid_field("not json"), None);
+        assert_eq!(invalid_field("[]"), None);
+        assert_eq!(invalid_field(r#"{"title": "no url"}"#), Some("url".into()));
+        assert_eq!(invalid_field(r#"{"url": "not a url", "isRemote": 1}"#), Some("url".into()));
+        assert_eq!(invalid_field(r#"{"url": "https://example.com", "visitType": 99}"#),
+                   Some("visitType".into()));
+        assert_eq!(invalid_field(r#"{"url": "https://example.com", "isRemote": "yes", "at": "now"}"#),
+                   None);
+    }
+}
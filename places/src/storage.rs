@@ -7,9 +7,10 @@
 // This should probably be a sub-directory
 
 use std::{fmt, cmp};
+use std::time::Duration;
 use url::{Url};
-use types::{SyncGuid, Timestamp, VisitTransition};
-use error::{Result};
+use types::{SyncGuid, SyncStatus, Timestamp, VisitTransition};
+use error::{Error, Result};
 use observation::{VisitObservation};
 use frecency;
 
@@ -91,14 +92,15 @@ struct FetchedPageInfo {
     page: PageInfo,
     // XXX - not clear what this is used for yet, and whether it should be local, remote or either?
     // The sql below isn't quite sure either :)
-    last_visit_id: RowId,
+    // None if all visits to the page have been removed.
+    last_visit_id: Option<RowId>,
 }
 
 impl FetchedPageInfo {
     pub fn from_row(row: &Row) -> Result<Self> {
         Ok(Self {
             page: PageInfo::from_row(row)?,
-            last_visit_id: row.get_checked::<_, Option<RowId>>("last_visit_id")?.expect("No visit id!"),
+            last_visit_id: row.get_checked::<_, Option<RowId>>("last_visit_id")?,
         })
     }
 }
@@ -119,27 +121,81 @@ fn fetch_page_info(db: &impl ConnExt, url: &Url) -> Result<Option<FetchedPageInf
 }
 
 pub fn apply_observation(db: &mut PlacesDb, visit_ob: VisitObservation) -> Result<()> {
+    if !db.history_recording_enabled() && !visit_ob.is_remote.unwrap_or(false) {
+        debug!("Ignoring local observation, since history recording is disabled");
+        return Ok(());
+    }
+    let debounce = db.visit_debounce();
     let tx = db.db.transaction()?;
-    apply_observation_direct(tx.conn(), visit_ob)?;
+    apply_observation_impl(tx.conn(), visit_ob, debounce)?;
     tx.commit()?;
     Ok(())
 }
 
+/// Like `apply_observation`, but without a transaction, without debouncing
+/// visits, and regardless of `PlacesDb::history_recording_enabled`. This is
+/// intended for bulk imports, where every visit should be recorded as-is.
 pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) -> Result<()> {
-    let mut page_info = match fetch_page_info(db, &visit_ob.url)? {
-        Some(info) => info.page,
-        None => new_page_info(db, &visit_ob.url)?,
+    apply_observation_impl(db, visit_ob, None)
+}
+
+fn apply_observation_impl(db: &Connection, visit_ob: VisitObservation, debounce: Option<Duration>) -> Result<()> {
+    let is_remote = visit_ob.is_remote.unwrap_or(false);
+    let mut page_info = match (fetch_page_info(db, &visit_ob.url)?, &visit_ob.guid) {
+        (Some(info), _) => info.page,
+        // A page we only know about from the server is already there, under
+        // the guid of the record the visit came from, so there's nothing to
+        // upload. Without that guid, we can't tell which record it is, so we
+        // upload the page as a new one.
+        (None, Some(guid)) if is_remote => new_page_info(db, &visit_ob.url, Some(guid.clone()), SyncStatus::Normal)?,
+        (None, _) => new_page_info(db, &visit_ob.url, None, SyncStatus::New)?,
     };
+    // Search terms are only useful for showing search history, so we don't
+    // bother storing empty ones.
+    let search_term = visit_ob.search_term.as_ref().map(|t| t.trim()).filter(|t| !t.is_empty());
     let mut updates: Vec<(&str, &str, &ToSql)> = Vec::new();
     if let Some(ref title) = visit_ob.title {
         page_info.title = title.clone();
         updates.push(("title", ":title", &page_info.title));
     }
+    if let Some(ref term) = search_term {
+        updates.push(("search_term", ":search_term", term));
+    }
 
     let mut update_frecency = false;
 
+    let at = visit_ob.at.unwrap_or_else(|| Timestamp::now());
+    // A visit we deleted locally must not be brought back by sync, but a local
+    // visit at the same time is a new visit, which resurrects it.
+    let visit_type = match visit_ob.visit_type {
+        Some(_) if is_remote && is_visit_tombstoned(db, page_info.row_id, at)? => {
+            debug!("Ignoring remote visit at {} as it was deleted locally", at);
+            None
+        }
+        Some(visit_type) => {
+            if !is_remote {
+                remove_visit_tombstone(db, page_info.row_id, at)?;
+            }
+            Some(visit_type)
+        }
+        None => None,
+    };
+
+    // Rapid reloads of a page shouldn't each boost its frecency. We still
+    // record these visits, with their transition, but mark them so that
+    // frecency gives them no bonus. Remote visits were already debounced by
+    // the device that made them.
+    let is_debounced = match (visit_type, debounce) {
+        (Some(t), Some(window)) if !is_remote && t != VisitTransition::Reload =>
+            has_recent_local_visit(db, page_info.row_id, t, at, window)?,
+        _ => false,
+    };
+    if is_debounced {
+        debug!("Not counting visit at {} towards frecency, as it repeats a recent visit", at);
+    }
+
     // There's a new visit, so update everything that implies
-    if let Some(visit_type) = visit_ob.visit_type {
+    if let Some(visit_type) = visit_type {
         // A single non-hidden visit makes the place non-hidden.
         if !visit_ob.get_is_hidden() {
             updates.push(("hidden", ":hidden", &false));
@@ -149,9 +205,9 @@ pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) ->
             updates.push(("typed", ":typed", &page_info.typed));
         }
 
-        let at = visit_ob.at.unwrap_or_else(|| Timestamp::now());
-        let is_remote = visit_ob.is_remote.unwrap_or(false);
-        add_visit(db, &page_info.row_id, &None, &at, &visit_type, &is_remote)?;
+        // Containers are a local concept, so remote visits never have one.
+        let container_id = if is_remote { None } else { visit_ob.container_id.as_ref() };
+        add_visit(db, &page_info.row_id, &None, &at, &visit_type, &!is_remote, &container_id, &is_debounced)?;
         if is_remote {
             page_info.visit_count_remote += 1;
             updates.push(("visit_count_remote", ":visit_count_remote", &page_info.visit_count_remote));
@@ -163,8 +219,12 @@ pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) ->
             page_info.last_visit_date_local = cmp::max(at, page_info.last_visit_date_local);
             updates.push(("last_visit_date_local", ":last_visit_date_local", &page_info.last_visit_date_local));
         }
+        // Only local visits need to be uploaded.
+        if !is_remote {
+            bump_change_counter(db, page_info.row_id)?;
+        }
         // a new visit implies new frecency except in error cases.
-        if !visit_ob.is_error.unwrap_or(false) {
+        if !visit_ob.is_error.unwrap_or(false) && !is_debounced {
             update_frecency = true;
         }
     }
@@ -200,17 +260,25 @@ pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) ->
     Ok(())
 }
 
-fn new_page_info(db: &impl ConnExt, url: &Url) -> Result<PageInfo> {
-    let guid = super::sync::util::random_guid().expect("according to logins-sql, this is fine :)");
-    let sql = "INSERT INTO moz_places (guid, url, url_hash)
-               VALUES (:guid, :url, hash(:url))";
+fn new_page_info(db: &impl ConnExt, url: &Url, guid: Option<SyncGuid>, sync_status: SyncStatus) -> Result<PageInfo> {
+    let guid = match guid {
+        Some(guid) => guid,
+        None => SyncGuid(super::sync::util::random_guid().expect("according to logins-sql, this is fine :)")),
+    };
+    // New pages need to be uploaded, but ones which are already on the
+    // server don't.
+    let sync_change_counter = if sync_status == SyncStatus::New { 1 } else { 0 };
+    let sql = "INSERT INTO moz_places (guid, url, url_hash, sync_status, sync_change_counter)
+               VALUES (:guid, :url, hash(:url), :sync_status, :sync_change_counter)";
     db.execute_named_cached(sql, &[
-        (":guid", &guid),
+        (":guid", &guid as &ToSql),
         (":url", &url.clone().into_string()),
+        (":sync_status", &sync_status),
+        (":sync_change_counter", &sync_change_counter),
     ])?;
     Ok(PageInfo {
         url: url.clone(),
-        guid: SyncGuid(guid),
+        guid,
         row_id: RowId(db.conn().last_insert_rowid()),
         title: "".into(),
         hidden: true, // will be set to false as soon as a non-hidden visit appears.
@@ -223,6 +291,16 @@ fn new_page_info(db: &impl ConnExt, url: &Url) -> Result<PageInfo> {
     })
 }
 
+/// Returns the id of the page for `url`, adding it (without any visits) if
+/// it isn't in the database yet. Bookmarking a page we haven't visited needs
+/// this.
+pub(crate) fn fetch_or_insert_page_id(db: &impl ConnExt, url: &Url) -> Result<RowId> {
+    Ok(match fetch_page_info(db, url)? {
+        Some(info) => info.page.row_id,
+        None => new_page_info(db, url, None, SyncStatus::New)?.row_id,
+    })
+}
+
 // Add a single visit - you must know the page rowid. Does not update the
 // page info - if you are calling this, you will also need to update the
 // parent page with the new visit count, frecency, etc.
@@ -231,22 +309,420 @@ fn add_visit(db: &impl ConnExt,
              from_visit: &Option<RowId>,
              visit_date: &Timestamp,
              visit_type: &VisitTransition,
-             is_local: &bool) -> Result<RowId> {
+             is_local: &bool,
+             container_id: &Option<&String>,
+             is_debounced: &bool) -> Result<RowId> {
     let sql =
         "INSERT INTO moz_historyvisits
-            (from_visit, place_id, visit_date, visit_type, is_local)
-        VALUES (:from_visit, :page_id, :visit_date, :visit_type, :is_local)";
+            (from_visit, place_id, visit_date, visit_type, is_local, container_id, is_debounced)
+        VALUES (:from_visit, :page_id, :visit_date, :visit_type, :is_local, :container_id, :is_debounced)";
     db.execute_named_cached(sql, &[
         (":from_visit", from_visit),
         (":page_id", page_id),
         (":visit_date", visit_date),
         (":visit_type", visit_type),
         (":is_local", is_local),
+        (":container_id", container_id),
+        (":is_debounced", is_debounced),
     ])?;
     let rid = db.conn().last_insert_rowid();
     Ok(RowId(rid))
 }
 
+// Returns true if there's a local visit to the page with the given transition
+// in the `window` before `at`, which wasn't itself debounced.
+fn has_recent_local_visit(db: &impl ConnExt,
+                          page_id: RowId,
+                          visit_type: VisitTransition,
+                          at: Timestamp,
+                          window: Duration) -> Result<bool> {
+    let window_ms = window.as_secs() * 1000 + u64::from(window.subsec_nanos()) / 1_000_000;
+    let since = Timestamp(at.0.saturating_sub(window_ms));
+    Ok(db.try_query_row(
+        "SELECT 1 FROM moz_historyvisits
+         WHERE place_id = :page_id
+           AND visit_type = :visit_type
+           AND is_local
+           AND NOT is_debounced
+           AND visit_date BETWEEN :since AND :at
+         LIMIT 1",
+        &[(":page_id", &page_id as &ToSql),
+          (":visit_type", &visit_type as &ToSql),
+          (":since", &since as &ToSql),
+          (":at", &at as &ToSql)],
+        |row| row.get_checked::<_, i64>(0),
+        true)?.is_some())
+}
+
+fn bump_change_counter(db: &impl ConnExt, page_id: RowId) -> Result<()> {
+    db.execute_named_cached(
+        "UPDATE moz_places SET sync_change_counter = sync_change_counter + 1
+         WHERE id = :page_id",
+        &[(":page_id", &page_id)])?;
+    Ok(())
+}
+
+fn is_visit_tombstoned(db: &impl ConnExt, page_id: RowId, visit_date: Timestamp) -> Result<bool> {
+    Ok(db.try_query_row(
+        "SELECT 1 FROM moz_historyvisit_tombstones
+         WHERE place_id = :page_id AND visit_date = :visit_date",
+        &[(":page_id", &page_id as &ToSql), (":visit_date", &visit_date as &ToSql)],
+        |row| row.get_checked::<_, i64>(0),
+        true)?.is_some())
+}
+
+fn remove_visit_tombstone(db: &impl ConnExt, page_id: RowId, visit_date: Timestamp) -> Result<()> {
+    db.execute_named_cached(
+        "DELETE FROM moz_historyvisit_tombstones
+         WHERE place_id = :page_id AND visit_date = :visit_date",
+        &[(":page_id", &page_id as &ToSql), (":visit_date", &visit_date as &ToSql)])?;
+    Ok(())
+}
+
+// Recompute the visit counts, last visit dates and frecency for a page after
+// some of its visits have been removed.
+fn update_page_after_visit_removal(db: &Connection, page_id: RowId) -> Result<()> {
+    db.execute_named_cached("
+        UPDATE moz_places SET
+          visit_count_local = (SELECT count(*) FROM moz_historyvisits
+                               WHERE place_id = :page_id AND is_local),
+          visit_count_remote = (SELECT count(*) FROM moz_historyvisits
+                                WHERE place_id = :page_id AND NOT is_local),
+          last_visit_date_local = (SELECT max(visit_date) FROM moz_historyvisits
+                                   WHERE place_id = :page_id AND is_local),
+          last_visit_date_remote = (SELECT max(visit_date) FROM moz_historyvisits
+                                    WHERE place_id = :page_id AND NOT is_local)
+        WHERE id = :page_id",
+        &[(":page_id", &page_id)])?;
+    let frecency = frecency::calculate_frecency(db,
+        &frecency::DEFAULT_FRECENCY_SETTINGS,
+        page_id.0,
+        None)?;
+    db.execute_named_cached(
+        "UPDATE moz_places SET frecency = :frecency WHERE id = :page_id",
+        &[(":frecency", &frecency), (":page_id", &page_id)])?;
+    Ok(())
+}
+
+fn fetch_page_id_and_status(db: &impl ConnExt, guid: &SyncGuid) -> Result<Option<(RowId, SyncStatus, i64)>> {
+    Ok(db.try_query_row(
+        "SELECT id, sync_status, sync_change_counter FROM moz_places WHERE guid = :guid",
+        &[(":guid", guid as &ToSql)],
+        |row| -> Result<_> {
+            Ok((row.get_checked("id")?,
+                row.get_checked("sync_status")?,
+                row.get_checked("sync_change_counter")?))
+        },
+        true)?)
+}
+
+// Removes the page and everything hanging off it. Doesn't write tombstones.
+fn remove_page(db: &impl ConnExt, page_id: RowId) -> Result<()> {
+    for sql in &[
+        "DELETE FROM moz_historyvisits WHERE place_id = :page_id",
+        "DELETE FROM moz_historyvisit_tombstones WHERE place_id = :page_id",
+        "DELETE FROM moz_inputhistory WHERE place_id = :page_id",
+        "DELETE FROM moz_places WHERE id = :page_id",
+    ] {
+        db.execute_named_cached(sql, &[(":page_id", &page_id)])?;
+    }
+    Ok(())
+}
+
+/// Delete a single visit, leaving the page (and any other visits to it) in
+/// place. If the page has already been synced, the deletion is recorded so
+/// that the page is re-uploaded without the visit, and so the visit isn't
+/// re-added by a later incoming record.
+pub fn delete_visit(db: &Connection, visit_id: RowId) -> Result<()> {
+    let visit = db.try_query_row("
+        SELECT v.place_id, v.visit_date, h.sync_status
+        FROM moz_historyvisits v
+        JOIN moz_places h ON h.id = v.place_id
+        WHERE v.id = :visit_id",
+        &[(":visit_id", &visit_id as &ToSql)],
+        |row| -> Result<(RowId, Timestamp, SyncStatus)> {
+            Ok((row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?))
+        },
+        true)?;
+    let (page_id, visit_date, status) = match visit {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    db.execute_named_cached("DELETE FROM moz_historyvisits WHERE id = :visit_id",
+                            &[(":visit_id", &visit_id)])?;
+    if status != SyncStatus::New {
+        db.execute_named_cached(
+            "INSERT OR IGNORE INTO moz_historyvisit_tombstones(place_id, visit_date)
+             VALUES(:page_id, :visit_date)",
+            &[(":page_id", &page_id as &ToSql), (":visit_date", &visit_date as &ToSql)])?;
+    }
+    bump_change_counter(db, page_id)?;
+    update_page_after_visit_removal(db, page_id)
+}
+
+/// Forget a page entirely, including all of its visits. If the page had been
+/// synced, a tombstone is written so that the deletion is uploaded.
+pub fn delete_place_by_guid(db: &Connection, guid: &SyncGuid) -> Result<()> {
+    let (page_id, status, _) = match fetch_page_id_and_status(db, guid)? {
+        Some(info) => info,
+        None => return Ok(()),
+    };
+    remove_page(db, page_id)?;
+    if status != SyncStatus::New {
+        db.execute_named_cached(
+            "INSERT OR IGNORE INTO moz_places_tombstones(guid) VALUES(:guid)",
+            &[(":guid", guid)])?;
+    }
+    Ok(())
+}
+
+/// How many pages and visits a deletion removed or, for a dry run, would
+/// remove.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DeletionCounts {
+    /// Pages which were removed entirely.
+    pub pages: usize,
+    /// Visits which were removed, including those to removed pages.
+    pub visits: usize,
+}
+
+// The pages on `:host` or any of its subdomains, whatever their scheme.
+// `rev_host` includes the port, so pages on other ports aren't included.
+// The deletion and its dry run both select with this (and the conditions
+// below), so that they can't disagree about what's affected.
+const HOST_PAGES_SQL: &str = "
+    SELECT h.id FROM moz_places h
+    JOIN moz_origins o ON o.id = h.origin_id
+    WHERE substr(o.rev_host, 1, length(reverse_host(:host))) = reverse_host(:host)";
+
+// Bookmarked pages are kept when their history is deleted, without visits.
+const REMOVABLE_PAGE_CONDITION: &str = "foreign_count = 0";
+
+const VISITS_BETWEEN_CONDITION: &str = "visit_date BETWEEN :start AND :end";
+
+// Unbookmarked pages which only have visits between `:start` and `:end`, and
+// so are removed along with those visits.
+const PAGES_ONLY_VISITED_BETWEEN_SQL: &str = "
+    SELECT h.id FROM moz_places h
+    WHERE h.foreign_count = 0
+      AND EXISTS(SELECT 1 FROM moz_historyvisits
+                 WHERE place_id = h.id AND visit_date BETWEEN :start AND :end)
+      AND NOT EXISTS(SELECT 1 FROM moz_historyvisits
+                     WHERE place_id = h.id AND visit_date NOT BETWEEN :start AND :end)";
+
+fn fetch_ids(db: &impl ConnExt, sql: &str, params: &[(&str, &ToSql)]) -> Result<Vec<RowId>> {
+    let mut stmt = db.conn().prepare_cached(sql)?;
+    let ids = stmt.query_and_then_named(params, |row| row.get_checked::<_, RowId>(0))?
+                  .collect::<RusqliteResult<Vec<_>>>()?;
+    Ok(ids)
+}
+
+// Removes pages as `delete_place_by_guid` does.
+fn delete_pages(db: &Connection, page_ids: &[RowId]) -> Result<()> {
+    for &page_id in page_ids {
+        let guid = db.query_row_and_then_named(
+            "SELECT guid FROM moz_places WHERE id = :page_id",
+            &[(":page_id", &page_id)],
+            |row| row.get_checked::<_, SyncGuid>(0),
+            true)?;
+        delete_place_by_guid(db, &guid)?;
+    }
+    Ok(())
+}
+
+/// Returns what `delete_everything_for_host` would remove, without removing
+/// anything.
+pub fn delete_everything_for_host_dry_run(db: &impl ConnExt, host: &str) -> Result<DeletionCounts> {
+    let params: &[(&str, &ToSql)] = &[(":host", &host)];
+    let pages = db.query_row_and_then_named(
+        &format!("SELECT count(*) FROM moz_places WHERE id IN ({}) AND {}",
+                 HOST_PAGES_SQL, REMOVABLE_PAGE_CONDITION),
+        params,
+        |row| row.get_checked::<_, i64>(0),
+        true)?;
+    let visits = db.query_row_and_then_named(
+        &format!("SELECT count(*) FROM moz_historyvisits WHERE place_id IN ({})",
+                 HOST_PAGES_SQL),
+        params,
+        |row| row.get_checked::<_, i64>(0),
+        true)?;
+    Ok(DeletionCounts { pages: pages as usize, visits: visits as usize })
+}
+
+/// Forget all history for pages on `host` (which must be Punycoded) and its
+/// subdomains. Unbookmarked pages are removed, as `delete_place_by_guid`
+/// does; bookmarked pages are kept, but all of their visits are removed, as
+/// `delete_visit` does.
+pub fn delete_everything_for_host(db: &mut PlacesDb, host: &str) -> Result<DeletionCounts> {
+    let tx = db.db.transaction()?;
+    let counts = delete_everything_for_host_impl(&tx, host)?;
+    tx.commit()?;
+    Ok(counts)
+}
+
+fn delete_everything_for_host_impl(db: &Connection, host: &str) -> Result<DeletionCounts> {
+    let counts = delete_everything_for_host_dry_run(db, host)?;
+    let params: &[(&str, &ToSql)] = &[(":host", &host)];
+    let removable = fetch_ids(db, &format!(
+        "SELECT id FROM moz_places WHERE id IN ({}) AND {}",
+        HOST_PAGES_SQL, REMOVABLE_PAGE_CONDITION), params)?;
+    delete_pages(db, &removable)?;
+    let visits = fetch_ids(db, &format!(
+        "SELECT id FROM moz_historyvisits WHERE place_id IN ({})",
+        HOST_PAGES_SQL), params)?;
+    for visit_id in visits {
+        delete_visit(db, visit_id)?;
+    }
+    Ok(counts)
+}
+
+/// Returns what `delete_visits_between` would remove, without removing
+/// anything.
+pub fn delete_visits_between_dry_run(db: &impl ConnExt, start: Timestamp, end: Timestamp) -> Result<DeletionCounts> {
+    let params: &[(&str, &ToSql)] = &[(":start", &start), (":end", &end)];
+    let pages = db.query_row_and_then_named(
+        &format!("SELECT count(*) FROM ({})", PAGES_ONLY_VISITED_BETWEEN_SQL),
+        params,
+        |row| row.get_checked::<_, i64>(0),
+        true)?;
+    let visits = db.query_row_and_then_named(
+        &format!("SELECT count(*) FROM moz_historyvisits WHERE {}", VISITS_BETWEEN_CONDITION),
+        params,
+        |row| row.get_checked::<_, i64>(0),
+        true)?;
+    Ok(DeletionCounts { pages: pages as usize, visits: visits as usize })
+}
+
+/// Delete the visits made between `start` and `end` (inclusive), as
+/// `delete_visit` does. Unbookmarked pages left without any visits are
+/// removed, as `delete_place_by_guid` does.
+pub fn delete_visits_between(db: &mut PlacesDb, start: Timestamp, end: Timestamp) -> Result<DeletionCounts> {
+    let tx = db.db.transaction()?;
+    let counts = delete_visits_between_impl(&tx, start, end)?;
+    tx.commit()?;
+    Ok(counts)
+}
+
+fn delete_visits_between_impl(db: &Connection, start: Timestamp, end: Timestamp) -> Result<DeletionCounts> {
+    let counts = delete_visits_between_dry_run(db, start, end)?;
+    let params: &[(&str, &ToSql)] = &[(":start", &start), (":end", &end)];
+    let pages = fetch_ids(db, PAGES_ONLY_VISITED_BETWEEN_SQL, params)?;
+    delete_pages(db, &pages)?;
+    let visits = fetch_ids(db, &format!(
+        "SELECT id FROM moz_historyvisits WHERE {}", VISITS_BETWEEN_CONDITION), params)?;
+    for visit_id in visits {
+        delete_visit(db, visit_id)?;
+    }
+    Ok(counts)
+}
+
+/// Apply a visit deletion which arrived via sync. Only the single visit is
+/// removed, and nothing is recorded for upload.
+pub fn apply_remote_visit_deletion(db: &Connection, guid: &SyncGuid, visit_date: Timestamp) -> Result<()> {
+    let page_id = match fetch_page_id_and_status(db, guid)? {
+        Some((page_id, ..)) => page_id,
+        None => return Ok(()),
+    };
+    let changes = db.execute_named_cached(
+        "DELETE FROM moz_historyvisits
+         WHERE place_id = :page_id AND visit_date = :visit_date",
+        &[(":page_id", &page_id as &ToSql), (":visit_date", &visit_date as &ToSql)])?;
+    if changes > 0 {
+        update_page_after_visit_removal(db, page_id)?;
+    }
+    Ok(())
+}
+
+/// Apply a page deletion which arrived via sync.
+///
+/// If the page has local changes which haven't been uploaded yet, those
+/// changes win: the page is kept, and is marked as `New` so that it's uploaded
+/// in full (resurrecting it on the server). Otherwise the page and all of its
+/// visits are removed.
+pub fn apply_remote_page_deletion(db: &Connection, guid: &SyncGuid) -> Result<()> {
+    db.execute_named_cached("DELETE FROM moz_places_tombstones WHERE guid = :guid",
+                            &[(":guid", guid)])?;
+    let (page_id, status, change_counter) = match fetch_page_id_and_status(db, guid)? {
+        Some(info) => info,
+        None => return Ok(()),
+    };
+    if change_counter > 0 {
+        debug!("Incoming tombstone for {:?} (status {:?}) has local changes - resurrecting it",
+               guid, status);
+        db.execute_named_cached(
+            "UPDATE moz_places SET sync_status = :status WHERE id = :page_id",
+            &[(":status", &SyncStatus::New as &ToSql), (":page_id", &page_id as &ToSql)])?;
+        db.execute_named_cached(
+            "DELETE FROM moz_historyvisit_tombstones WHERE place_id = :page_id",
+            &[(":page_id", &page_id)])?;
+        return Ok(());
+    }
+    remove_page(db, page_id)
+}
+
+/// Deletions which need to be uploaded.
+#[derive(Debug, Default, PartialEq)]
+pub struct PendingDeletions {
+    /// Pages which were forgotten entirely.
+    pub pages: Vec<SyncGuid>,
+    /// Individual visits (page guid and visit date) which were removed from
+    /// pages which still exist.
+    pub visits: Vec<(SyncGuid, Timestamp)>,
+}
+
+pub fn fetch_pending_deletions(db: &impl ConnExt) -> Result<PendingDeletions> {
+    let mut stmt = db.conn().prepare_cached("SELECT guid FROM moz_places_tombstones")?;
+    let pages = stmt.query_and_then(&[], |row| row.get_checked::<_, SyncGuid>(0))?
+                    .collect::<RusqliteResult<Vec<_>>>()?;
+    let mut stmt = db.conn().prepare_cached("
+        SELECT h.guid, t.visit_date
+        FROM moz_historyvisit_tombstones t
+        JOIN moz_places h ON h.id = t.place_id")?;
+    let visits = stmt.query_and_then(&[], |row| -> RusqliteResult<_> {
+        Ok((row.get_checked::<_, SyncGuid>(0)?, row.get_checked::<_, Timestamp>(1)?))
+    })?.collect::<RusqliteResult<Vec<_>>>()?;
+    Ok(PendingDeletions { pages, visits })
+}
+
+/// Called once the page deletions in `guids` have been uploaded. Pages
+/// deleted since they were fetched are left for the next sync.
+pub fn mark_page_deletions_synced(db: &impl ConnExt, guids: &[SyncGuid]) -> Result<()> {
+    for guid in guids {
+        db.execute_named_cached("DELETE FROM moz_places_tombstones WHERE guid = :guid",
+                                &[(":guid", guid)])?;
+    }
+    Ok(())
+}
+
+/// Called once the pages in `visits` have been uploaded without the deleted
+/// visits, so the server's records no longer mention them. Visits deleted
+/// since they were fetched are left for the next sync.
+pub fn mark_visit_deletions_synced(db: &impl ConnExt, visits: &[(SyncGuid, Timestamp)]) -> Result<()> {
+    for (guid, visit_date) in visits {
+        db.execute_named_cached(
+            "DELETE FROM moz_historyvisit_tombstones
+             WHERE place_id = (SELECT id FROM moz_places WHERE guid = :guid)
+               AND visit_date = :visit_date",
+            &[(":guid", guid as &ToSql), (":visit_date", visit_date as &ToSql)])?;
+    }
+    Ok(())
+}
+
+pub(crate) fn put_meta(db: &impl ConnExt, key: &str, value: &ToSql) -> Result<()> {
+    db.execute_named_cached(
+        "REPLACE INTO moz_meta (key, value) VALUES (:key, :value)",
+        &[(":key", &key as &ToSql), (":value", value)])?;
+    Ok(())
+}
+
+pub(crate) fn get_meta<T: FromSql>(db: &impl ConnExt, key: &str) -> Result<Option<T>> {
+    Ok(db.try_query_row(
+        "SELECT value FROM moz_meta WHERE key = :key",
+        &[(":key", &key as &ToSql)],
+        |row| Ok::<_, Error>(row.get_checked(0)?),
+        true)?)
+}
+
 // Currently not used - we update the frecency as we update the page info.
 pub fn update_frecency(db: &mut PlacesDb, id: RowId, redirect: Option<bool>) -> Result<()> {
     let score = frecency::calculate_frecency(db.conn(),
@@ -268,6 +744,7 @@ pub fn update_frecency(db: &mut PlacesDb, id: RowId, redirect: Option<bool>) ->
 // moz_origins fits in TBH :/
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     struct Origin {
         prefix: String,
@@ -296,4 +773,332 @@ mod tests {
         assert_eq!(o.rev_host(), "moc.oof");
     }
 
+    fn visit(db: &mut PlacesDb, url: &str, at: u64, is_remote: bool) {
+        let ob = VisitObservation::new(Url::parse(url).unwrap())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Timestamp(at))
+            .with_is_remote(is_remote);
+        apply_observation(db, ob).expect("should apply");
+    }
+
+    fn page(db: &PlacesDb, url: &str) -> Option<PageInfo> {
+        fetch_page_info(db, &Url::parse(url).unwrap()).unwrap().map(|p| p.page)
+    }
+
+    fn visit_ids(db: &PlacesDb, page_id: RowId) -> Vec<RowId> {
+        let mut stmt = db.prepare("SELECT id FROM moz_historyvisits
+                                   WHERE place_id = :page_id ORDER BY visit_date").unwrap();
+        stmt.query_map_named(&[(":page_id", &page_id)], |row| row.get(0)).unwrap()
+            .map(|r| r.unwrap()).collect()
+    }
+
+    // Pretend we uploaded everything.
+    fn mark_all_synced(db: &PlacesDb) {
+        db.execute_all(&["UPDATE moz_places SET sync_status = 2, sync_change_counter = 0"]).unwrap();
+    }
+
+    #[test]
+    fn test_delete_visit() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let url = "https://www.example.com/";
+        visit(&mut db, url, 1000, false);
+        visit(&mut db, url, 2000, false);
+        let p = page(&db, url).unwrap();
+        let visits = visit_ids(&db, p.row_id);
+
+        // Deleting a visit to a page we've never uploaded doesn't need a tombstone.
+        delete_visit(&db, visits[0]).unwrap();
+        assert_eq!(fetch_pending_deletions(&db).unwrap(), PendingDeletions::default());
+        let p = page(&db, url).unwrap();
+        assert_eq!(p.visit_count_local, 1);
+
+        mark_all_synced(&db);
+        delete_visit(&db, visits[1]).unwrap();
+        let pending = fetch_pending_deletions(&db).unwrap();
+        assert!(pending.pages.is_empty());
+        assert_eq!(pending.visits, vec![(p.guid.clone(), Timestamp(2000))]);
+        // The page still exists, but needs to be uploaded again.
+        let counter: i64 = db.query_one("SELECT sync_change_counter FROM moz_places").unwrap();
+        assert_eq!(counter, 1);
+        assert_eq!(page(&db, url).unwrap().visit_count_local, 0);
+
+        // An incoming record mentioning the deleted visit doesn't bring it back...
+        visit(&mut db, url, 2000, true);
+        assert!(visit_ids(&db, p.row_id).is_empty());
+        // ...but visiting again locally at that time does.
+        visit(&mut db, url, 2000, false);
+        assert_eq!(visit_ids(&db, p.row_id).len(), 1);
+        assert!(fetch_pending_deletions(&db).unwrap().visits.is_empty());
+    }
+
+    #[test]
+    fn test_visit_deletions_synced() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let url = "https://www.example.com/";
+        visit(&mut db, url, 1000, false);
+        visit(&mut db, url, 2000, false);
+        let p = page(&db, url).unwrap();
+        let visits = visit_ids(&db, p.row_id);
+        mark_all_synced(&db);
+
+        delete_visit(&db, visits[0]).unwrap();
+        let pending = fetch_pending_deletions(&db).unwrap();
+        assert_eq!(pending.visits, vec![(p.guid.clone(), Timestamp(1000))]);
+
+        // A visit deleted while we upload is left for the next sync.
+        delete_visit(&db, visits[1]).unwrap();
+        mark_visit_deletions_synced(&db, &pending.visits).unwrap();
+        assert_eq!(fetch_pending_deletions(&db).unwrap().visits,
+                   vec![(p.guid.clone(), Timestamp(2000))]);
+        let count: i64 = db.query_one("SELECT count(*) FROM moz_historyvisit_tombstones").unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_delete_page_and_resurrect_locally() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let url = "https://www.example.com/";
+        visit(&mut db, url, 1000, false);
+        let old = page(&db, url).unwrap();
+        mark_all_synced(&db);
+
+        delete_place_by_guid(&db, &old.guid).unwrap();
+        assert!(page(&db, url).is_none());
+        assert_eq!(fetch_pending_deletions(&db).unwrap().pages, vec![old.guid.clone()]);
+
+        // Visiting again creates a brand new page, but we still need to tell
+        // the server about the old one.
+        visit(&mut db, url, 5000, false);
+        let new = page(&db, url).unwrap();
+        assert_ne!(new.guid, old.guid);
+        assert_eq!(new.visit_count_local, 1);
+        let pending = fetch_pending_deletions(&db).unwrap();
+        assert_eq!(pending.pages, vec![old.guid.clone()]);
+
+        // Deleting it again while we upload leaves a tombstone for the next
+        // sync.
+        mark_all_synced(&db);
+        delete_place_by_guid(&db, &new.guid).unwrap();
+        mark_page_deletions_synced(&db, &pending.pages).unwrap();
+        assert_eq!(fetch_pending_deletions(&db).unwrap().pages, vec![new.guid.clone()]);
+    }
+
+    #[test]
+    fn test_delete_everything_for_host() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        visit(&mut db, "https://example.com/", 1000, false);
+        visit(&mut db, "http://www.example.com/a", 2000, false);
+        visit(&mut db, "http://www.example.com/a", 3000, true);
+        visit(&mut db, "https://www.example.com/bookmarked", 4000, false);
+        visit(&mut db, "https://notexample.com/", 5000, false);
+        visit(&mut db, "https://example.com:8080/", 6000, false);
+        db.execute_all(&["UPDATE moz_places SET foreign_count = 1
+                          WHERE url = 'https://www.example.com/bookmarked'"]).unwrap();
+        mark_all_synced(&db);
+        let a = page(&db, "http://www.example.com/a").unwrap();
+
+        let expected = DeletionCounts { pages: 2, visits: 4 };
+        assert_eq!(delete_everything_for_host_dry_run(&db, "example.com").unwrap(), expected);
+        assert!(page(&db, "https://example.com/").is_some());
+        assert_eq!(fetch_pending_deletions(&db).unwrap(), PendingDeletions::default());
+
+        assert_eq!(delete_everything_for_host(&mut db, "example.com").unwrap(), expected);
+        assert!(page(&db, "https://example.com/").is_none());
+        assert!(page(&db, "http://www.example.com/a").is_none());
+        let bookmarked = page(&db, "https://www.example.com/bookmarked").unwrap();
+        assert_eq!(bookmarked.visit_count_local, 0);
+        assert!(page(&db, "https://notexample.com/").is_some());
+        assert!(page(&db, "https://example.com:8080/").is_some());
+        assert!(fetch_pending_deletions(&db).unwrap().pages.contains(&a.guid));
+
+        assert_eq!(delete_everything_for_host_dry_run(&db, "example.com").unwrap(),
+                   DeletionCounts::default());
+    }
+
+    #[test]
+    fn test_delete_visits_between() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        visit(&mut db, "https://www.example.com/old", 1000, false);
+        visit(&mut db, "https://www.example.com/both", 1000, false);
+        visit(&mut db, "https://www.example.com/both", 5000, false);
+        visit(&mut db, "https://www.example.com/new", 5000, false);
+        visit(&mut db, "https://www.example.com/after", 9000, false);
+
+        let expected = DeletionCounts { pages: 1, visits: 2 };
+        let dry_run = delete_visits_between_dry_run(&db, Timestamp(4000), Timestamp(6000)).unwrap();
+        assert_eq!(dry_run, expected);
+        assert_eq!(page(&db, "https://www.example.com/both").unwrap().visit_count_local, 2);
+
+        assert_eq!(delete_visits_between(&mut db, Timestamp(4000), Timestamp(6000)).unwrap(), expected);
+        assert!(page(&db, "https://www.example.com/new").is_none());
+        let both = page(&db, "https://www.example.com/both").unwrap();
+        assert_eq!(both.visit_count_local, 1);
+        assert_eq!(both.last_visit_date_local, Timestamp(1000));
+        assert!(page(&db, "https://www.example.com/old").is_some());
+        assert!(page(&db, "https://www.example.com/after").is_some());
+    }
+
+    fn sync_state(db: &PlacesDb, url: &str) -> (SyncStatus, i64) {
+        db.query_row_named(
+            "SELECT sync_status, sync_change_counter FROM moz_places WHERE url = :url",
+            &[(":url", &url)],
+            |row| (row.get(0), row.get(1))).unwrap()
+    }
+
+    #[test]
+    fn test_remote_visits_dont_need_upload() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let url = "https://www.example.com/";
+        let guid = SyncGuid("remoteguid01".into());
+        let ob = VisitObservation::new(Url::parse(url).unwrap())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Timestamp(1000))
+            .with_is_remote(true)
+            .with_guid(guid.clone());
+        apply_observation(&mut db, ob).unwrap();
+        assert_eq!(sync_state(&db, url), (SyncStatus::Normal, 0));
+        // The page matches the record it came from.
+        let p = page(&db, url).unwrap();
+        assert_eq!(p.guid, guid);
+
+        // So a remote tombstone removes it.
+        apply_remote_page_deletion(&db, &p.guid).unwrap();
+        assert!(page(&db, url).is_none());
+
+        // Without the record's guid, we don't know which record the page is,
+        // so it's uploaded like a local one.
+        visit(&mut db, url, 2000, true);
+        assert_eq!(sync_state(&db, url), (SyncStatus::New, 1));
+        assert_ne!(page(&db, url).unwrap().guid, guid);
+    }
+
+    #[test]
+    fn test_remote_deletions() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let url = "https://www.example.com/";
+        let other_url = "https://www.example.com/other";
+        visit(&mut db, url, 1000, true);
+        visit(&mut db, url, 2000, true);
+        visit(&mut db, other_url, 3000, false);
+        mark_all_synced(&db);
+        let p = page(&db, url).unwrap();
+
+        // A remote visit deletion removes just that visit.
+        apply_remote_visit_deletion(&db, &p.guid, Timestamp(1000)).unwrap();
+        let p = page(&db, url).unwrap();
+        assert_eq!(p.visit_count_remote, 1);
+        assert_eq!(p.last_visit_date_remote, Timestamp(2000));
+
+        // A remote page deletion removes the page and its visits, and nothing
+        // needs to be uploaded.
+        apply_remote_page_deletion(&db, &p.guid).unwrap();
+        assert!(page(&db, url).is_none());
+        assert!(visit_ids(&db, p.row_id).is_empty());
+        assert_eq!(fetch_pending_deletions(&db).unwrap(), PendingDeletions::default());
+
+        // But if we have local changes we haven't uploaded, the page survives
+        // and is re-uploaded in full.
+        visit(&mut db, other_url, 4000, false);
+        let other = page(&db, other_url).unwrap();
+        apply_remote_page_deletion(&db, &other.guid).unwrap();
+        let other = page(&db, other_url).unwrap();
+        assert_eq!(other.visit_count_local, 2);
+        let status: SyncStatus = db.query_one("SELECT sync_status FROM moz_places").unwrap();
+        assert_eq!(status, SyncStatus::New);
+    }
+
+    fn visit_types(db: &PlacesDb, page_id: RowId) -> Vec<VisitTransition> {
+        let mut stmt = db.prepare("SELECT visit_type FROM moz_historyvisits
+                                   WHERE place_id = :page_id ORDER BY visit_date").unwrap();
+        stmt.query_map_named(&[(":page_id", &page_id)], |row| row.get(0)).unwrap()
+            .map(|r| VisitTransition::from_primitive(r.unwrap()).unwrap()).collect()
+    }
+
+    fn debounced_visits(db: &PlacesDb, page_id: RowId) -> Vec<bool> {
+        let mut stmt = db.prepare("SELECT is_debounced FROM moz_historyvisits
+                                   WHERE place_id = :page_id ORDER BY visit_date").unwrap();
+        stmt.query_map_named(&[(":page_id", &page_id)], |row| row.get(0)).unwrap()
+            .map(|r| r.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_visit_debounce() {
+        use db::db::DEFAULT_VISIT_DEBOUNCE_SECS;
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let url = "https://www.example.com/";
+        let window = DEFAULT_VISIT_DEBOUNCE_SECS * 1000;
+        visit(&mut db, url, 1000, false);
+        let first = page(&db, url).unwrap();
+
+        // Reloading shortly after is recorded, but doesn't change frecency.
+        visit(&mut db, url, 2000, false);
+        let p = page(&db, url).unwrap();
+        assert_eq!(p.visit_count_local, 2);
+        assert_eq!(p.last_visit_date_local, Timestamp(2000));
+        assert_eq!(p.frecency, first.frecency);
+
+        // Nor does it count when frecency is calculated again later: it's
+        // worth as much as an actual reload.
+        let reloaded_url = "https://www.example.com/reloaded";
+        visit(&mut db, reloaded_url, 1000, false);
+        let ob = VisitObservation::new(Url::parse(reloaded_url).unwrap())
+            .with_visit_type(VisitTransition::Reload)
+            .with_at(Timestamp(2000));
+        apply_observation(&mut db, ob).unwrap();
+        let reloaded = page(&db, reloaded_url).unwrap();
+        update_frecency(&mut db, p.row_id, None).unwrap();
+        update_frecency(&mut db, reloaded.row_id, None).unwrap();
+        assert_eq!(page(&db, url).unwrap().frecency, page(&db, reloaded_url).unwrap().frecency);
+
+        // A different transition isn't a repeat.
+        let ob = VisitObservation::new(Url::parse(url).unwrap())
+            .with_visit_type(VisitTransition::Typed)
+            .with_at(Timestamp(3000));
+        apply_observation(&mut db, ob).unwrap();
+        assert_eq!(page(&db, url).unwrap().typed, 1);
+
+        // Neither are remote visits, or local ones once the window has passed.
+        visit(&mut db, url, 4000, true);
+        visit(&mut db, url, 1000 + window + 1, false);
+        // The repeat keeps its transition, so it's uploaded as it happened.
+        assert_eq!(visit_types(&db, p.row_id), vec![
+            VisitTransition::Link,
+            VisitTransition::Link,
+            VisitTransition::Typed,
+            VisitTransition::Link,
+            VisitTransition::Link,
+        ]);
+        assert_eq!(debounced_visits(&db, p.row_id), vec![false, true, false, false, false]);
+
+        db.set_visit_debounce(None);
+        visit(&mut db, url, 2000 + window, false);
+        assert_eq!(debounced_visits(&db, p.row_id).last(), Some(&false));
+    }
+
+    #[test]
+    fn test_history_recording_disabled() {
+        let mut db = PlacesDb::open_in_memory(None).unwrap();
+        let url = "https://www.example.com/";
+        visit(&mut db, url, 1000, false);
+
+        db.set_history_recording_enabled(false);
+        assert!(!db.history_recording_enabled());
+        visit(&mut db, url, 2000, false);
+        let ob = VisitObservation::new(Url::parse(url).unwrap())
+            .with_title(Some("Private".to_string()));
+        apply_observation(&mut db, ob).unwrap();
+        visit(&mut db, "https://www.example.com/private", 3000, false);
+        let p = page(&db, url).unwrap();
+        assert_eq!(p.visit_count_local, 1);
+        assert_eq!(p.title, "");
+        assert!(page(&db, "https://www.example.com/private").is_none());
+
+        // Remote visits still come in.
+        visit(&mut db, url, 4000, true);
+        assert_eq!(page(&db, url).unwrap().visit_count_remote, 1);
+
+        db.set_history_recording_enabled(true);
+        visit(&mut db, url, 5000, false);
+        assert_eq!(page(&db, url).unwrap().visit_count_local, 2);
+    }
 }
@@ -5,8 +5,10 @@
 use std::{fmt};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use rusqlite::{types::{ToSql, FromSql, ToSqlOutput, FromSqlResult, ValueRef}};
+use rusqlite::{types::{ToSql, FromSql, FromSqlError, ToSqlOutput, FromSqlResult, ValueRef}};
 use rusqlite::Result as RusqliteResult;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
 
 // XXX - copied from logins - surprised it's not in `sync`
 #[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
@@ -133,6 +135,92 @@ impl VisitTransition {
     }
 }
 
+// Deserialized from the numeric value, like we store it.
+impl<'de> Deserialize<'de> for VisitTransition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = u32::deserialize(deserializer)?;
+        VisitTransition::from_primitive(value).ok_or_else(||
+            de::Error::custom(format!("invalid visit transition: {}", value)))
+    }
+}
+
+impl Serialize for VisitTransition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+// Like desktop's `PlacesUtils.history.SYNC_STATUS`. A page is `New` until it
+// has been uploaded, at which point it becomes `Normal`. Deleting a `New` page
+// (or visits to it) never needs to be communicated to the server.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyncStatus {
+    Unknown = 0,
+    New = 1,
+    Normal = 2,
+}
+
+impl SyncStatus {
+    #[inline]
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => SyncStatus::New,
+            2 => SyncStatus::Normal,
+            _ => SyncStatus::Unknown,
+        }
+    }
+}
+
+impl ToSql for SyncStatus {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for SyncStatus {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        value.as_i64().map(|v| SyncStatus::from_u8(v as u8))
+    }
+}
+
+// The `type` of a `moz_bookmarks` row. These are the values desktop uses.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BookmarkType {
+    Bookmark = 1,
+    Folder = 2,
+    Separator = 3,
+}
+
+impl BookmarkType {
+    #[inline]
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(BookmarkType::Bookmark),
+            2 => Some(BookmarkType::Folder),
+            3 => Some(BookmarkType::Separator),
+            _ => None,
+        }
+    }
+}
+
+impl ToSql for BookmarkType {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for BookmarkType {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        let v = value.as_i64()?;
+        if v < 0 || v > i64::from(u8::max_value()) {
+            return Err(FromSqlError::OutOfRange(v));
+        }
+        BookmarkType::from_u8(v as u8).ok_or(FromSqlError::OutOfRange(v))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -142,4 +230,21 @@ mod tests {
         assert_eq!(Some(VisitTransition::Link), VisitTransition::from_primitive(1));
         assert_eq!(None, VisitTransition::from_primitive(99));
     }
+
+    #[test]
+    fn test_deserialize_transition() {
+        use serde_json;
+        assert_eq!(serde_json::from_str::<VisitTransition>("2").unwrap(), VisitTransition::Typed);
+        assert!(serde_json::from_str::<VisitTransition>("99").is_err());
+        assert!(serde_json::from_str::<VisitTransition>("\"link\"").is_err());
+    }
+
+    #[test]
+    fn test_bookmark_type_from_sql() {
+        assert_eq!(BookmarkType::column_result(ValueRef::Integer(2)).unwrap(), BookmarkType::Folder);
+        assert!(BookmarkType::column_result(ValueRef::Integer(4)).is_err());
+        // These would be `Bookmark` if we truncated them to a u8.
+        assert!(BookmarkType::column_result(ValueRef::Integer(257)).is_err());
+        assert!(BookmarkType::column_result(ValueRef::Integer(-255)).is_err());
+    }
 }
@@ -96,9 +96,6 @@ class FxAView: UIViewController, WKNavigationDelegate {
         self.fxa!.completeOAuthFlow(code: dic["code"]!, state: dic["state"]!) { result, error in
             guard let oauthInfo = result else { return }
             print("access_token: " + oauthInfo.accessToken)
-            if let keys = oauthInfo.keys {
-                print("keysJWE: " + keys)
-            }
             print("obtained scopes: " + oauthInfo.scopes.joined(separator: " "))
             self.fxa!.getProfile() { result, error in
                 guard let profile = result else {
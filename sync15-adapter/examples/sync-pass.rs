@@ -2,7 +2,6 @@
 extern crate sync15_adapter as sync;
 extern crate url;
 extern crate base64;
-extern crate reqwest;
 #[macro_use]
 extern crate prettytable;
 
@@ -25,7 +24,7 @@ use std::collections::HashMap;
 use std::borrow::Cow;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use fxa_client::{FirefoxAccount, Config, OAuthInfo};
+use fxa_client::{FirefoxAccount, Config, AccessTokenInfo};
 use sync::{ServerTimestamp, OutgoingChangeset, Payload, Store};
 
 const CLIENT_ID: &str = "3c8bd3fe92e1ddf1";
@@ -33,14 +32,6 @@ const REDIRECT_URI: &str = "http://localhost:13131/oauth/complete";
 const SYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
 
 
-#[derive(Debug, Deserialize)]
-struct ScopedKeyData {
-    k: String,
-    kty: String,
-    kid: String,
-    scope: String,
-}
-
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PasswordRecord {
@@ -534,22 +525,21 @@ fn main() -> Result<(), failure::Error> {
     let tokenserver_url = cfg.token_server_endpoint_url()?;
 
     let mut acct = load_or_create_fxa_creds(cfg.clone())?;
-    let token: OAuthInfo;
-    match acct.get_oauth_token(&[SYNC_SCOPE])? {
+    let token: AccessTokenInfo;
+    match acct.get_access_token(SYNC_SCOPE)? {
         Some(t) => token = t,
         None => {
             // The cached credentials did not have appropriate scope, sign in again.
             println!("Credentials do not have appropriate scope, launching OAuth flow.");
             acct = create_fxa_creds(cfg.clone())?;
-            token = acct.get_oauth_token(&[SYNC_SCOPE])?.unwrap();
+            token = acct.get_access_token(SYNC_SCOPE)?.unwrap();
         }
     }
-    let keys: HashMap<String, ScopedKeyData> = serde_json::from_str(&token.keys.unwrap())?;
-    let key = keys.get(SYNC_SCOPE).unwrap();
+    let key = token.key.expect("Sync token should come with a key");
 
     let client = sync::Sync15StorageClient::new(sync::Sync15StorageClientInit {
         key_id: key.kid.clone(),
-        access_token: token.access_token.clone(),
+        access_token: token.token.clone(),
         tokenserver_url,
     })?;
     let mut state = sync::GlobalState::default();
@@ -0,0 +1,21 @@
+#!/usr/bin/env bash
+# Starts the self-hosted Sync server, runs the integration tests against it,
+# and shuts it down again. Extra arguments are passed to the test binary, e.g.
+# `./run.sh test_tombstones`.
+
+set -euo pipefail
+
+cd "$(dirname "$0")"
+docker-compose up -d --build
+trap "docker-compose down" EXIT
+
+echo "Waiting for the tokenserver and storage node..."
+for _ in $(seq 60); do
+    if curl -s -o /dev/null http://localhost:5000/ && curl -s -o /dev/null http://localhost:8000/; then
+        break
+    fi
+    sleep 1
+done
+
+cd ..
+cargo test --test docker_sync -- --ignored --test-threads=1 "$@"
@@ -0,0 +1,67 @@
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+"""A tokenserver that trusts any OAuth bearer token.
+
+The real tokenserver verifies the token with FxA. This one derives a user id
+from a hash of the token instead, so tests can create as many fresh accounts
+as they like by making up new tokens, and never need a real Firefox Account.
+Tokens are signed with the secret syncstorage is configured with, so storage
+requests are authenticated the same way they are in production.
+"""
+
+import hashlib
+import json
+import os
+import time
+from wsgiref.simple_server import make_server
+
+import tokenlib
+
+SECRET = os.environ["SHARED_SECRET"]
+SYNC_NODE = os.environ["SYNC_NODE"].rstrip("/")
+DURATION = 3600
+
+
+def respond(start_response, status, body):
+    start_response(status, [
+        ("Content-Type", "application/json"),
+        ("X-Timestamp", str(int(time.time()))),
+    ])
+    return [json.dumps(body).encode("utf-8")]
+
+
+def app(environ, start_response):
+    auth = environ.get("HTTP_AUTHORIZATION", "")
+    if not auth.startswith("Bearer ") or not environ.get("HTTP_X_KEYID"):
+        return respond(start_response, "401 Unauthorized", {
+            "status": "invalid-credentials",
+            "errors": [{"location": "header", "name": "Authorization"}],
+        })
+
+    fxa_uid = hashlib.sha256(auth[len("Bearer "):].encode("utf-8")).hexdigest()[:32]
+    uid = int(fxa_uid[:12], 16)
+    hashed_fxa_uid = hashlib.sha256(fxa_uid.encode("utf-8")).hexdigest()[:32]
+    token = tokenlib.make_token({
+        "uid": uid,
+        "node": SYNC_NODE,
+        "expires": int(time.time()) + DURATION,
+        "fxa_uid": fxa_uid,
+        "fxa_kid": environ["HTTP_X_KEYID"],
+        "hashed_fxa_uid": hashed_fxa_uid,
+        "hashed_device_id": hashed_fxa_uid,
+    }, secret=SECRET)
+    return respond(start_response, "200 OK", {
+        "id": token,
+        "key": tokenlib.get_derived_secret(token, secret=SECRET),
+        "uid": uid,
+        "hashed_fxa_uid": hashed_fxa_uid,
+        "api_endpoint": "{}/1.5/{}".format(SYNC_NODE, uid),
+        "duration": DURATION,
+        "hashalg": "sha256",
+    })
+
+
+if __name__ == "__main__":
+    make_server("0.0.0.0", 5000, app).serve_forever()
@@ -180,7 +180,7 @@ impl Payload {
         CleartextBso {
             id,
             collection,
-            modified: 0.0.into(), // Doesn't matter.
+            modified: ServerTimestamp::now_estimate(), // Not sent, but keep it sane.
             sortindex: None, // Should we let consumer's set this?
             ttl: None, // Should we let consumer's set this?
             payload: self,
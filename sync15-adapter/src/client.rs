@@ -2,22 +2,20 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::cell::Cell;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
 
-use hyper::{Method};
-use reqwest::{Client, Request, Response, Url, header::{self, HeaderValue, ACCEPT, AUTHORIZATION}};
 use serde;
-use serde_json;
+use viaduct::{status_codes, Method, Request, Response, Url};
 
 use bso_record::{BsoRecord, EncryptedBso};
 use error::{self, ErrorKind};
 use record_types::MetaGlobalRecord;
 use request::{BatchPoster, CollectionRequest, InfoConfiguration, PostQueue, PostResponse,
-              PostResponseHandler, X_IF_UNMODIFIED_SINCE, X_WEAVE_TIMESTAMP, InfoCollections};
+              PostResponseHandler, X_IF_UNMODIFIED_SINCE, X_WEAVE_TIMESTAMP, InfoCollections,
+              InfoQuota, OVER_QUOTA_RESPONSE, X_WEAVE_QUOTA_REMAINING};
 use std::str::FromStr;
 use token;
-use util::ServerTimestamp;
+use util::{self, ServerTimestamp};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Sync15StorageClientInit {
@@ -32,6 +30,7 @@ pub struct Sync15StorageClientInit {
 pub trait SetupStorageClient {
     fn fetch_info_configuration(&self) -> error::Result<InfoConfiguration>;
     fn fetch_info_collections(&self) -> error::Result<InfoCollections>;
+    fn fetch_info_quota(&self) -> error::Result<InfoQuota>;
     fn fetch_meta_global(&self) -> error::Result<BsoRecord<MetaGlobalRecord>>;
     fn put_meta_global(&self, global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()>;
     fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso>;
@@ -39,12 +38,16 @@ pub trait SetupStorageClient {
     fn wipe_all_remote(&self) -> error::Result<()>;
 }
 
-#[derive(Debug)]
+/// A client for the storage server. It's safe to use from several threads at
+/// once, and clones share the same token (and so the same backoff), so
+/// cloning one is the way to make requests in parallel.
+#[derive(Debug, Clone)]
 pub struct Sync15StorageClient {
-    http_client: Client,
     // We update this when we make requests
-    timestamp: Cell<ServerTimestamp>,
-    tsc: token::TokenProvider,
+    timestamp: Arc<Mutex<ServerTimestamp>>,
+    // The `X-Weave-Quota-Remaining` from the last response that had one.
+    quota_remaining: Arc<Mutex<Option<f64>>>,
+    tsc: Arc<token::TokenProvider>,
 }
 
 impl SetupStorageClient for Sync15StorageClient {
@@ -58,8 +61,13 @@ impl SetupStorageClient for Sync15StorageClient {
         Ok(collections)
     }
 
+    fn fetch_info_quota(&self) -> error::Result<InfoQuota> {
+        let quota = self.fetch_info::<InfoQuota>("info/quota")?;
+        Ok(quota)
+    }
+
     fn fetch_meta_global(&self) -> error::Result<BsoRecord<MetaGlobalRecord>> {
-        let mut resp = match self.relative_storage_request(Method::GET, "storage/meta/global") {
+        let resp = match self.relative_storage_request(Method::Get, "storage/meta/global") {
             Ok(r) => Ok(r),
             Err(ref e) if e.is_not_found() => Err(ErrorKind::NoMetaGlobal.into()),
             Err(e) => Err(e)
@@ -75,7 +83,11 @@ impl SetupStorageClient for Sync15StorageClient {
     }
 
     fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso> {
-        let mut keys_resp = self.relative_storage_request(Method::GET, "storage/crypto/keys")?;
+        let keys_resp = match self.relative_storage_request(Method::Get, "storage/crypto/keys") {
+            Ok(r) => Ok(r),
+            Err(ref e) if e.is_not_found() => Err(ErrorKind::NoCryptoKeys.into()),
+            Err(e) => Err(e)
+        }?;
         let keys: EncryptedBso = keys_resp.json()?;
         Ok(keys)
     }
@@ -85,11 +97,10 @@ impl SetupStorageClient for Sync15StorageClient {
     }
 
     fn wipe_all_remote(&self) -> error::Result<()> {
-        let s = self.tsc.api_endpoint(&self.http_client)?;
+        let s = self.tsc.api_endpoint()?;
         let url = Url::parse(&s)?;
 
-        let req = self.build_request(Method::DELETE, url)?;
-        match self.exec_request(req, true) {
+        match self.exec_request(|| self.build_request(Method::Delete, url.clone()), true) {
             Ok(_) => Ok(()),
             Err(ref e) if e.is_not_found() => Ok(()),
             Err(e) => Err(e)
@@ -99,23 +110,37 @@ impl SetupStorageClient for Sync15StorageClient {
 
 impl Sync15StorageClient {
     pub fn new(init_params: Sync15StorageClientInit) -> error::Result<Sync15StorageClient> {
-        let client = Client::builder().timeout(Duration::from_secs(30)).build()?;
-        let tsc = token::TokenProvider::new(
+        let tsc = Arc::new(token::TokenProvider::new(
             init_params.tokenserver_url,
             init_params.access_token,
             init_params.key_id,
-        );
+        ));
         let timestamp = ServerTimestamp(0f64);
         Ok(Sync15StorageClient {
-            http_client: client,
-            timestamp: Cell::new(timestamp),
+            timestamp: Arc::new(Mutex::new(timestamp)),
+            quota_remaining: Arc::new(Mutex::new(None)),
             tsc,
         })
     }
 
+    /// Fetches a new token if the current one is about to expire, so that it
+    /// doesn't expire partway through a sync. Call this before starting one.
+    pub fn refresh_token_if_expiring(&self) -> error::Result<()> {
+        self.tsc.refresh_if_expiring()
+    }
+
     #[inline]
     pub fn last_server_time(&self) -> ServerTimestamp {
-        return self.timestamp.get();
+        return *self.timestamp.lock().unwrap();
+    }
+
+    /// The kilobytes left in the account's storage quota, if the server told
+    /// us. The server only sends this with responses to writes, once the
+    /// account is getting close to its quota, so `None` usually means
+    /// there's plenty of space left.
+    #[inline]
+    pub fn quota_remaining_kb(&self) -> Option<f64> {
+        *self.quota_remaining.lock().unwrap()
     }
 
     pub fn get_encrypted_records(
@@ -123,27 +148,23 @@ impl Sync15StorageClient {
         collection: &str,
         since: ServerTimestamp,
     ) -> error::Result<Vec<EncryptedBso>> {
-        let mut resp = self.collection_request(
-            Method::GET,
+        let resp = self.collection_request(
+            Method::Get,
             CollectionRequest::new(collection).full().newer_than(since),
         )?;
         Ok(resp.json()?)
     }
 
     #[inline]
-    fn authorized(&self, mut req: Request) -> error::Result<Request> {
-        let hawk_header_value = self.tsc.authorization(&self.http_client, &req)?;
-        req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_str(&hawk_header_value)?);
-        Ok(req)
+    fn authorized(&self, req: Request) -> error::Result<Request> {
+        let hawk_header_value = self.tsc.authorization(&req)?;
+        Ok(req.header("authorization", hawk_header_value)?)
     }
 
     // TODO: probably want a builder-like API to do collection requests (e.g. something
     // that occupies roughly the same conceptual role as the Collection class in desktop)
     fn build_request(&self, method: Method, url: Url) -> error::Result<Request> {
-        self.authorized(self.http_client
-            .request(method, url)
-            .header(ACCEPT, "application/json")
-            .build()?)
+        self.authorized(Request::new(method, url).header("accept", "application/json")?)
     }
 
     fn relative_storage_request<T>(
@@ -154,37 +175,49 @@ impl Sync15StorageClient {
     where
         T: AsRef<str>,
     {
-        let s = self.tsc.api_endpoint(&self.http_client)? + "/";
+        let s = self.tsc.api_endpoint()? + "/";
         let url = Url::parse(&s)?.join(relative_path.as_ref())?;
         Ok(self.make_storage_request(method, url)?)
     }
 
     fn make_storage_request(&self, method: Method, url: Url) -> error::Result<Response> {
-        // I'm shocked that method isn't Copy...
-        Ok(self.exec_request(self.build_request(method.clone(), url)?, true)?)
+        Ok(self.exec_request(|| self.build_request(method, url.clone()), true)?)
     }
 
-    fn exec_request(&self, req: Request, require_success: bool) -> error::Result<Response> {
-        let resp = self.http_client.execute(req)?;
+    // Builds the request with `build_request`, which should authorize it, and
+    // sends it. If the storage server rejects our token, which can happen if
+    // it expired since we checked, we send it again once with a new one.
+    fn exec_request<F>(&self, build_request: F, require_success: bool) -> error::Result<Response>
+    where
+        F: Fn() -> error::Result<Request>,
+    {
+        let mut resp = build_request()?.send()?;
+        if resp.status == status_codes::UNAUTHORIZED {
+            warn!("Storage server rejected our token, retrying with a new one");
+            self.tsc.drop_token();
+            resp = build_request()?.send()?;
+        }
 
-        self.update_timestamp(resp.headers());
+        self.update_timestamp(&resp);
+        self.update_quota_remaining(&resp);
 
-        if require_success && !resp.status().is_success() {
+        if require_success && !resp.is_success() {
+            if resp.status == status_codes::FORBIDDEN {
+                return Err(forbidden_error(&resp));
+            }
             error!(
-                "HTTP error {} ({}) during storage request to {}",
-                resp.status().as_u16(),
-                resp.status(),
-                resp.url().path()
+                "HTTP error {} during storage request to {}",
+                resp.status,
+                resp.url.path()
             );
             return Err(ErrorKind::StorageHttpError {
-                code: resp.status().as_u16(),
-                route: resp.url().path().into(),
+                code: resp.status,
+                route: resp.url.path().into(),
             }.into());
         }
 
         // TODO:
         // - handle backoff
-        // - x-weave-quota?
         // - ... almost certainly other things too...
 
         Ok(resp)
@@ -192,8 +225,8 @@ impl Sync15StorageClient {
 
     fn collection_request(&self, method: Method, r: &CollectionRequest) -> error::Result<Response> {
         self.make_storage_request(
-            method.clone(),
-            r.build_url(Url::parse(&self.tsc.api_endpoint(&self.http_client)?)?)?,
+            method,
+            r.build_url(Url::parse(&self.tsc.api_endpoint()?)?)?,
         )
     }
 
@@ -201,20 +234,28 @@ impl Sync15StorageClient {
     where
         for<'a> T: serde::de::Deserialize<'a>,
     {
-        let mut resp = self.relative_storage_request(Method::GET, path)?;
+        let resp = self.relative_storage_request(Method::Get, path)?;
         let result: T = resp.json()?;
         Ok(result)
     }
 
-    fn update_timestamp(&self, hm: &header::HeaderMap) {
-        if let Some(ts) = hm.get(X_WEAVE_TIMESTAMP).and_then(|v| v.to_str().ok()).and_then(|s| ServerTimestamp::from_str(s).ok()) {
-            self.timestamp.set(ts);
+    fn update_timestamp(&self, resp: &Response) {
+        if let Some(ts) = resp.header(X_WEAVE_TIMESTAMP).and_then(|s| ServerTimestamp::from_str(s).ok()) {
+            *self.timestamp.lock().unwrap() = ts;
+            util::record_server_time(ts);
         } else {
             // Should we complain more here?
             warn!("No X-Weave-Timestamp from storage server!");
         }
     }
 
+    fn update_quota_remaining(&self, resp: &Response) {
+        if let Some(remaining) = resp.header(X_WEAVE_QUOTA_REMAINING).and_then(|s| f64::from_str(s).ok()) {
+            warn!("Storage server says the account has {}KB left in its quota", remaining);
+            *self.quota_remaining.lock().unwrap() = Some(remaining);
+        }
+    }
+
     pub fn new_post_queue<'a, F: PostResponseHandler>(
         &'a self,
         coll: &str,
@@ -239,23 +280,34 @@ impl Sync15StorageClient {
         P: AsRef<str>,
         B: serde::ser::Serialize,
     {
-        let s = self.tsc.api_endpoint(&self.http_client)? + "/";
+        let s = self.tsc.api_endpoint()? + "/";
         let url = Url::parse(&s)?.join(relative_path.as_ref())?;
 
-        let bytes = serde_json::to_vec(body)?;
-
-        let mut req = self.build_request(Method::PUT, url)?;
-        req.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        if let Some(ts) = xius {
-            req.headers_mut().insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", ts))?);
-        }
-        *req.body_mut() = Some(bytes.into());
-        let _ = self.exec_request(req, true)?;
+        let _ = self.exec_request(|| {
+            let mut req = self.build_request(Method::Put, url.clone())?.json(body)?;
+            if let Some(ts) = xius {
+                req = req.header(X_IF_UNMODIFIED_SINCE, format!("{}", ts))?;
+            }
+            Ok(req)
+        }, true)?;
 
         Ok(())
     }
 }
 
+// The server refuses writes that would put the account over its quota with a
+// 403, and a body of just the "over quota" error code.
+fn forbidden_error(resp: &Response) -> error::Error {
+    if resp.text().trim() == OVER_QUOTA_RESPONSE {
+        error!("Storage server refused a request to {}: over quota", resp.url.path());
+        return ErrorKind::OverQuota.into();
+    }
+    ErrorKind::StorageHttpError {
+        code: resp.status,
+        route: resp.url.path().into(),
+    }.into()
+}
+
 pub struct PostWrapper<'a> {
     client: &'a Sync15StorageClient,
     coll: String,
@@ -273,17 +325,21 @@ impl<'a> BatchPoster for PostWrapper<'a> {
         let url = CollectionRequest::new(self.coll.clone())
             .batch(batch)
             .commit(commit)
-            .build_url(Url::parse(&self.client
-                .tsc
-                .api_endpoint(&self.client.http_client)?)?)?;
-
-        let mut req = self.client.build_request(Method::POST, url)?;
-        req.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        req.headers_mut().insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", xius))?);
-        // It's very annoying that we need to copy the body here, the request
-        // shouldn't need to take ownership of it...
-        *req.body_mut() = Some(Vec::from(bytes).into());
-        let mut resp = self.client.exec_request(req, false)?;
-        Ok(PostResponse::from_response(&mut resp)?)
+            .build_url(Url::parse(&self.client.tsc.api_endpoint()?)?)?;
+
+        let resp = self.client.exec_request(|| {
+            // It's very annoying that we need to copy the body here, the request
+            // shouldn't need to take ownership of it...
+            Ok(self.client.build_request(Method::Post, url.clone())?
+                .header("content-type", "application/json")?
+                .header(X_IF_UNMODIFIED_SINCE, format!("{}", xius))?
+                .body(bytes))
+        }, false)?;
+        // Other failures have an upload result for the post queue to handle,
+        // but a 403 doesn't.
+        if resp.status == status_codes::FORBIDDEN {
+            return Err(forbidden_error(&resp));
+        }
+        Ok(PostResponse::from_response(&resp)?)
     }
 }
@@ -0,0 +1,250 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::borrow::{Borrow, Cow};
+use std::fmt;
+use std::ops::Deref;
+
+use client::Sync15StorageClient;
+use error;
+use state::GlobalState;
+use sync::{self, CollectionSync, Store};
+use util::ServerTimestamp;
+
+/// The name of a collection on the sync server, like `"passwords"`. This is
+/// also the name of the engine that syncs it, in `meta/global`. Names of the
+/// collections we know about are constants, like `CollectionName::PASSWORDS`,
+/// but any name is allowed, since other clients may sync collections we
+/// don't.
+///
+/// Serializes as a string, and can be compared with (and used to look up maps
+/// keyed by it with) a `&str`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CollectionName(Cow<'static, str>);
+
+impl CollectionName {
+    pub const ADDONS: CollectionName = CollectionName(Cow::Borrowed("addons"));
+    pub const ADDRESSES: CollectionName = CollectionName(Cow::Borrowed("addresses"));
+    pub const BOOKMARKS: CollectionName = CollectionName(Cow::Borrowed("bookmarks"));
+    pub const CLIENTS: CollectionName = CollectionName(Cow::Borrowed("clients"));
+    pub const CREDITCARDS: CollectionName = CollectionName(Cow::Borrowed("creditcards"));
+    pub const FORMS: CollectionName = CollectionName(Cow::Borrowed("forms"));
+    pub const HISTORY: CollectionName = CollectionName(Cow::Borrowed("history"));
+    pub const PASSWORDS: CollectionName = CollectionName(Cow::Borrowed("passwords"));
+    pub const PREFS: CollectionName = CollectionName(Cow::Borrowed("prefs"));
+    pub const TABS: CollectionName = CollectionName(Cow::Borrowed("tabs"));
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    #[inline]
+    pub fn into_string(self) -> String {
+        self.0.into_owned()
+    }
+}
+
+impl Deref for CollectionName {
+    type Target = str;
+    #[inline]
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for CollectionName {
+    #[inline]
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+// `Cow<str>` hashes and compares like the `str` it holds, so this is
+// consistent with the derived `Hash`, `Eq` and `Ord`.
+impl Borrow<str> for CollectionName {
+    #[inline]
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for CollectionName {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<String> for CollectionName {
+    #[inline]
+    fn from(name: String) -> Self {
+        CollectionName(Cow::Owned(name))
+    }
+}
+
+impl<'a> From<&'a str> for CollectionName {
+    #[inline]
+    fn from(name: &'a str) -> Self {
+        CollectionName(Cow::Owned(name.to_owned()))
+    }
+}
+
+impl From<CollectionName> for String {
+    #[inline]
+    fn from(name: CollectionName) -> Self {
+        name.into_string()
+    }
+}
+
+impl PartialEq<str> for CollectionName {
+    #[inline]
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for CollectionName {
+    #[inline]
+    fn eq(&self, other: &&'a str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+/// The stores an application syncs, by collection. Register each store along
+/// with the time of its last sync, and `sync` syncs the ones that are enabled
+/// in `meta/global`, with `sync_multiple`.
+pub struct EngineRegistry<'a, E: 'a> {
+    engines: Vec<CollectionSync<'a, E>>,
+}
+
+impl<'a, E> Default for EngineRegistry<'a, E> {
+    fn default() -> Self {
+        EngineRegistry { engines: Vec::new() }
+    }
+}
+
+impl<'a, E> EngineRegistry<'a, E> {
+    pub fn new() -> Self {
+        EngineRegistry::default()
+    }
+
+    /// Registers `store` for `collection`, replacing any store that was
+    /// registered for it already. Stores sync in the order they're
+    /// registered, except for their `sync_dependencies`.
+    pub fn register(&mut self,
+                    collection: CollectionName,
+                    store: &'a mut Store<Error=E>,
+                    timestamp: ServerTimestamp) {
+        match self.engines.iter().position(|c| c.collection == collection) {
+            Some(index) => {
+                self.engines[index].store = store;
+                self.engines[index].timestamp = timestamp;
+            }
+            None => self.engines.push(CollectionSync { store, collection, timestamp }),
+        }
+    }
+
+    /// Returns the store registered for `collection`.
+    pub fn get_mut(&mut self, collection: &str) -> Option<&mut CollectionSync<'a, E>> {
+        self.engines.iter_mut().find(|c| c.collection == collection)
+    }
+
+    /// The collections with registered stores, in the order they were
+    /// registered.
+    pub fn collections(&self) -> Vec<&CollectionName> {
+        self.engines.iter().map(|c| &c.collection).collect()
+    }
+
+    /// The registered collections that are enabled in `state` (see
+    /// `GlobalState::is_engine_enabled`), and so should sync.
+    pub fn enabled_collections(&self, state: &GlobalState) -> Vec<&CollectionName> {
+        self.engines.iter()
+            .map(|c| &c.collection)
+            .filter(|collection| state.is_engine_enabled(collection))
+            .collect()
+    }
+
+    /// Syncs the registered stores that are enabled in `state`, with
+    /// `sync_multiple`. Stores for declined engines, or engines that aren't in
+    /// `meta/global`, are skipped.
+    pub fn sync(&mut self,
+                client: &Sync15StorageClient,
+                state: &GlobalState,
+                max_parallel_downloads: usize,
+                fully_atomic: bool) -> Result<(), E>
+    where E: From<error::Error>
+    {
+        let mut enabled = Vec::with_capacity(self.engines.len());
+        for c in &mut self.engines {
+            if state.is_engine_enabled(&c.collection) {
+                enabled.push(CollectionSync {
+                    store: &mut *c.store,
+                    collection: c.collection.clone(),
+                    timestamp: c.timestamp,
+                });
+            } else {
+                info!("Not syncing {}, which is declined or disabled", c.collection);
+            }
+        }
+        sync::sync_multiple(client, state, &mut enabled, max_parallel_downloads, fully_atomic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use serde_json;
+
+    #[test]
+    fn test_collection_name() {
+        let owned = CollectionName::from("passwords".to_owned());
+        assert_eq!(owned, CollectionName::PASSWORDS);
+        assert_eq!(CollectionName::PASSWORDS, "passwords");
+        assert_eq!(CollectionName::from("history").to_string(), "history");
+
+        let mut map = HashMap::new();
+        map.insert(owned, 1);
+        assert_eq!(map.get("passwords"), Some(&1));
+        assert_eq!(map.get(&CollectionName::PASSWORDS), Some(&1));
+
+        assert_eq!(serde_json::to_string(&CollectionName::TABS).unwrap(), "\"tabs\"");
+        let name: CollectionName = serde_json::from_str("\"tabs\"").unwrap();
+        assert_eq!(name, CollectionName::TABS);
+    }
+
+    // Stores nothing, and has nothing to upload.
+    struct NullStore;
+
+    impl Store for NullStore {
+        type Error = error::Error;
+
+        fn apply_incoming(&mut self, inbound: ::IncomingChangeset) -> error::Result<::OutgoingChangeset> {
+            Ok(::OutgoingChangeset::new(inbound.collection, inbound.timestamp))
+        }
+
+        fn sync_finished(&mut self, _: ServerTimestamp, _: &[String]) -> error::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut passwords = NullStore;
+        let mut history = NullStore;
+        let mut other = NullStore;
+        let mut registry = EngineRegistry::new();
+        registry.register(CollectionName::PASSWORDS, &mut passwords, ServerTimestamp(1.0));
+        registry.register(CollectionName::HISTORY, &mut history, ServerTimestamp(2.0));
+        registry.register(CollectionName::PASSWORDS, &mut other, ServerTimestamp(3.0));
+        assert_eq!(registry.collections(), vec![&CollectionName::PASSWORDS, &CollectionName::HISTORY]);
+        assert_eq!(registry.get_mut("passwords").unwrap().timestamp, ServerTimestamp(3.0));
+        assert!(registry.get_mut("bookmarks").is_none());
+
+        // Without `meta/global`, nothing is enabled but clients.
+        assert!(registry.enabled_collections(&GlobalState::default()).is_empty());
+    }
+}
@@ -3,7 +3,6 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::time::SystemTime;
-use reqwest;
 use failure::{Fail, Context, Backtrace, SyncFailure};
 use std::{fmt, result, string};
 use std::boxed::Box;
@@ -11,6 +10,8 @@ use openssl;
 use base64;
 use serde_json;
 use hawk;
+use url;
+use viaduct;
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -78,6 +79,11 @@ pub enum ErrorKind {
     #[fail(display = "HTTP status {} during a storage request to \"{}\"", code, route)]
     StorageHttpError { code: u16, route: String },
 
+    /// The server refused a write because the account is over its storage
+    /// quota. Syncing won't succeed until some data is removed.
+    #[fail(display = "The account is over its storage quota")]
+    OverQuota,
+
     #[fail(display = "Server requested backoff. Retry after {:?}", _0)]
     BackoffError(SystemTime),
 
@@ -120,6 +126,15 @@ pub enum ErrorKind {
     #[fail(display = "Setup state machine disallowed state {}", _0)]
     DisallowedStateError(&'static str),
 
+    #[fail(display = "Another instance ({}) is already syncing", _0)]
+    AlreadySyncing(String),
+
+    #[fail(display = "Another instance took the sync lock while we were syncing")]
+    SyncLockLost,
+
+    #[fail(display = "A download thread exited before finishing its collection")]
+    DownloadThreadFailed,
+
     // Basically reimplement error_chain's foreign_links. (Ugh, this sucks)
 
     #[fail(display = "OpenSSL error: {}", _0)]
@@ -135,16 +150,13 @@ pub enum ErrorKind {
     BadCleartextUtf8(#[fail(cause)] string::FromUtf8Error),
 
     #[fail(display = "Network error: {}", _0)]
-    RequestError(#[fail(cause)] reqwest::Error),
+    RequestError(#[fail(cause)] viaduct::Error),
 
     #[fail(display = "HAWK error: {}", _0)]
     HawkError(#[fail(cause)] SyncFailure<hawk::Error>),
 
     #[fail(display = "Malformed URL error: {}", _0)]
-    MalformedUrl(#[fail(cause)] reqwest::UrlError),
-
-    #[fail(display = "Malformed header error: {}", _0)]
-    MalformedHeader(#[fail(cause)] reqwest::header::InvalidHeaderValue),
+    MalformedUrl(#[fail(cause)] url::ParseError),
 }
 
 macro_rules! impl_from_error {
@@ -170,9 +182,8 @@ impl_from_error! {
     (Base64Decode, ::base64::DecodeError),
     (JsonError, ::serde_json::Error),
     (BadCleartextUtf8, ::std::string::FromUtf8Error),
-    (RequestError, ::reqwest::Error),
-    (MalformedUrl, ::reqwest::UrlError),
-    (MalformedHeader, ::reqwest::header::InvalidHeaderValue)
+    (RequestError, ::viaduct::Error),
+    (MalformedUrl, ::url::ParseError)
 }
 
 // ::hawk::Error uses error_chain, and so it's not trivially compatible with failure.
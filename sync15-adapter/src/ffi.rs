@@ -0,0 +1,72 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// We don't have an FFI of our own, but our errors end up being reported over
+// the FFI of the components that sync with us, which use `error_chain` to
+// find out what went wrong.
+
+use error::{Error, ErrorKind};
+use ffi_support::{ErrorChain, ErrorCode};
+
+/// The domain of our links in an `ErrorChain`.
+pub const ERROR_DOMAIN: &str = "sync15";
+
+/// The error codes used in our links in an `ErrorChain`.
+pub mod error_codes {
+    /// Anything not covered below.
+    pub const UNEXPECTED: i32 = 1;
+
+    /// The token server rejected our FxA credentials, which should be
+    /// refreshed.
+    pub const AUTH_INVALID: i32 = 2;
+
+    /// A request to the token server or storage server failed.
+    pub const NETWORK: i32 = 3;
+
+    /// The server asked us to back off.
+    pub const BACKOFF: i32 = 4;
+
+    /// The storage server returned an error status.
+    pub const STORAGE_HTTP: i32 = 5;
+
+    /// The server was reset (or we were reassigned to another node), and
+    /// we need to resync from scratch.
+    pub const STORAGE_RESET: i32 = 6;
+
+    /// The data on the server is from a newer client than us.
+    pub const CLIENT_UPGRADE_REQUIRED: i32 = 7;
+
+    /// Another instance is syncing the same database, or took over from us
+    /// while we were syncing. The sync should be retried later.
+    pub const ALREADY_SYNCING: i32 = 8;
+
+    /// The account is over its storage quota, so the server refused our
+    /// uploads. Apps should ask the user to free up space.
+    pub const OVER_QUOTA: i32 = 9;
+}
+
+fn get_code(err: &Error) -> ErrorCode {
+    ErrorCode::new(match err.kind() {
+        ErrorKind::TokenserverHttpError(401) => error_codes::AUTH_INVALID,
+        // We only report this once a new token was rejected too.
+        ErrorKind::StorageHttpError { code: 401, .. } => error_codes::AUTH_INVALID,
+        ErrorKind::RequestError(_) => error_codes::NETWORK,
+        ErrorKind::BackoffError(_) => error_codes::BACKOFF,
+        ErrorKind::StorageHttpError { .. } => error_codes::STORAGE_HTTP,
+        ErrorKind::OverQuota => error_codes::OVER_QUOTA,
+        ErrorKind::StorageResetError => error_codes::STORAGE_RESET,
+        ErrorKind::ClientUpgradeRequired => error_codes::CLIENT_UPGRADE_REQUIRED,
+        ErrorKind::AlreadySyncing(_) | ErrorKind::SyncLockLost => error_codes::ALREADY_SYNCING,
+        _ => error_codes::UNEXPECTED,
+    })
+}
+
+impl Error {
+    /// Describe this error for reporting over the FFI of another component.
+    /// Callers will generally want to append this to their own chain with
+    /// `ErrorChain::with_cause`.
+    pub fn error_chain(&self) -> ErrorChain {
+        ErrorChain::new(ERROR_DOMAIN, get_code(self), self.to_string())
+    }
+}
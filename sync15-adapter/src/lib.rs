@@ -5,9 +5,7 @@
 extern crate serde;
 extern crate base64;
 extern crate openssl;
-extern crate reqwest;
 extern crate hawk;
-extern crate hyper;
 
 extern crate failure;
 
@@ -28,6 +26,8 @@ extern crate serde_json;
 
 extern crate url;
 extern crate base16;
+extern crate ffi_support;
+extern crate viaduct;
 
 // TODO: Some of these don't need to be pub...
 pub mod key_bundle;
@@ -35,6 +35,7 @@ pub mod error;
 pub mod bso_record;
 pub mod record_types;
 pub mod token;
+pub mod collection;
 pub mod collection_keys;
 pub mod util;
 pub mod request;
@@ -42,13 +43,21 @@ pub mod changeset;
 pub mod sync;
 pub mod client;
 pub mod state;
+pub mod ffi;
+pub mod trace;
+pub mod sync_lock;
+pub mod validation;
 
 // Re-export some of the types callers are likely to want for convenience.
 pub use bso_record::{BsoRecord, EncryptedBso, Payload, CleartextBso};
 pub use changeset::{RecordChangeset, IncomingChangeset, OutgoingChangeset};
+pub use collection::{CollectionName, EngineRegistry};
 pub use error::{Result, Error, ErrorKind};
-pub use sync::{synchronize, Store};
+pub use sync::{synchronize, sync_multiple, CollectionSync, Store, StoreCommand};
 pub use util::{ServerTimestamp, SERVER_EPOCH};
 pub use key_bundle::KeyBundle;
+pub use trace::{trace_reconcile, ReconcileWinner};
 pub use client::{Sync15StorageClientInit, Sync15StorageClient};
-pub use state::{GlobalState, SetupStateMachine};
+pub use sync_lock::SyncLock;
+pub use state::{GlobalState, SetupStateMachine, Transition, TransitionReason};
+pub use validation::{validate, LocalRecords, ValidatableStore, ValidationReport};
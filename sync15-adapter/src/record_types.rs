@@ -4,6 +4,8 @@
 
 use std::collections::HashMap;
 
+use collection::CollectionName;
+
 // Known record formats.
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -19,8 +21,8 @@ pub struct MetaGlobalRecord {
     pub sync_id: String,
     #[serde(rename = "storageVersion")]
     pub storage_version: usize,
-    pub engines: HashMap<String, MetaGlobalEngine>,
-    pub declined: Vec<String>,
+    pub engines: HashMap<CollectionName, MetaGlobalEngine>,
+    pub declined: Vec<CollectionName>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
@@ -5,24 +5,30 @@
 use util::ServerTimestamp;
 use bso_record::{EncryptedBso};
 
+use serde::{self, Deserialize, Serialize};
 use serde_json;
 use std::fmt;
 use std::collections::HashMap;
 use std::default::Default;
 use std::ops::Deref;
+use std::result;
 use std::str::FromStr;
 use url::{Url, UrlQuery, form_urlencoded::Serializer};
 use error::{self, Result, ErrorKind};
-use hyper::{StatusCode};
-use reqwest::Response;
+use viaduct::{status_codes, Response};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum RequestOrder { Oldest, Newest, Index }
 
 pub const X_IF_UNMODIFIED_SINCE: &str = "X-If-Unmodified-Since";
 pub const X_WEAVE_TIMESTAMP: &str = "X-Weave-Timestamp";
+pub const X_WEAVE_QUOTA_REMAINING: &str = "X-Weave-Quota-Remaining";
 const X_LAST_MODIFIED: &str = "X-Last-Modified";
 
+/// The body of a 403 response to a write that would put the user over their
+/// storage quota.
+pub const OVER_QUOTA_RESPONSE: &str = "14";
+
 impl fmt::Display for RequestOrder {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -265,6 +271,52 @@ impl Deref for InfoCollections {
     }
 }
 
+/// How much the user is storing, and how much they're allowed to store, from
+/// `info/quota`. The server returns these in kilobytes, as a two element
+/// array, with `null` for the quota if the user doesn't have one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InfoQuota {
+    pub usage_kb: f64,
+    pub quota_kb: Option<f64>,
+}
+
+/// The fraction of the quota the user can use before we warn them that
+/// they're close to it.
+pub const QUOTA_WARNING_FRACTION: f64 = 0.9;
+
+impl InfoQuota {
+    /// The fraction of the quota in use, or `None` if there isn't a quota.
+    pub fn fraction_used(&self) -> Option<f64> {
+        match self.quota_kb {
+            Some(quota_kb) if quota_kb > 0.0 => Some(self.usage_kb / quota_kb),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the user has used at least `QUOTA_WARNING_FRACTION`
+    /// of their quota, so apps can warn them before uploads start failing.
+    pub fn is_near_limit(&self) -> bool {
+        self.fraction_used()
+            .map(|fraction| fraction >= QUOTA_WARNING_FRACTION)
+            .unwrap_or(false)
+    }
+}
+
+// We (de)serialize as the same array the server sends, so that we can
+// persist it with the rest of the global state.
+impl Serialize for InfoQuota {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        (self.usage_kb, self.quota_kb).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for InfoQuota {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> result::Result<InfoQuota, D::Error> {
+        let (usage_kb, quota_kb) = <(f64, Option<f64>)>::deserialize(deserializer)?;
+        Ok(InfoQuota { usage_kb, quota_kb })
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct UploadResult {
     batch: Option<String>,
@@ -279,20 +331,25 @@ pub struct UploadResult {
 // Easier to fake during tests
 #[derive(Debug, Clone)]
 pub struct PostResponse {
-    pub status: StatusCode,
+    pub status: u16,
     pub result: UploadResult, // This is lazy...
     pub last_modified: ServerTimestamp,
 }
 
 impl PostResponse {
-    pub fn from_response(r: &mut Response) -> Result<PostResponse> {
+    pub fn from_response(r: &Response) -> Result<PostResponse> {
         let result: UploadResult = r.json()?;
         // TODO Can this happen in error cases?
-        let last_modified = r.headers().get(X_LAST_MODIFIED).and_then(|v| v.to_str().ok()).and_then(|s| ServerTimestamp::from_str(s).ok()).ok_or_else(||
+        let last_modified = r.header(X_LAST_MODIFIED).and_then(|s| ServerTimestamp::from_str(s).ok()).ok_or_else(||
             ErrorKind::MissingServerTimestamp)?;
-        let status = r.status();
+        let status = r.status;
         Ok(PostResponse { status, result, last_modified })
     }
+
+    #[inline]
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
 }
 
 
@@ -358,13 +415,13 @@ impl NormalResponseHandler {
 
 impl PostResponseHandler for NormalResponseHandler {
     fn handle_response(&mut self, r: PostResponse, mid_batch: bool) -> error::Result<()> {
-        if !r.status.is_success() {
+        if !r.is_success() {
             warn!("Got failure status from server while posting: {}", r.status);
-            if r.status == StatusCode::PRECONDITION_FAILED {
+            if r.status == status_codes::PRECONDITION_FAILED {
                 return Err(ErrorKind::BatchInterrupted.into());
             } else {
                 return Err(ErrorKind::StorageHttpError {
-                    code: r.status.as_u16(),
+                    code: r.status,
                     route: "collection storage (TODO: record route somewhere)".into()
                 }.into());
             }
@@ -519,8 +576,8 @@ where
 
         let resp = resp_or_error?;
 
-        if !resp.status.is_success() {
-            let code = resp.status.as_u16();
+        if !resp.is_success() {
+            let code = resp.status;
             self.on_response.handle_response(resp, !want_commit)?;
             error!("Bug: expected OnResponse to have bailed out!");
             // Should we assert here instead?
@@ -538,7 +595,7 @@ where
             return Ok(());
         }
 
-        if resp.status != StatusCode::ACCEPTED {
+        if resp.status != status_codes::ACCEPTED {
             if self.in_batch() {
                 return Err(ErrorKind::ServerBatchProblem(
                     "Server responded non-202 success code while a batch was in progress").into());
@@ -798,7 +855,7 @@ mod test {
         (pq, tester)
     }
 
-    fn fake_response<'a, T: Into<Option<&'a str>>>(status: StatusCode, lm: f64, batch: T) -> PostResponse {
+    fn fake_response<'a, T: Into<Option<&'a str>>>(status: u16, lm: f64, batch: T) -> PostResponse {
         PostResponse {
             status,
             last_modified: ServerTimestamp(lm),
@@ -876,7 +933,7 @@ mod test {
         };
         let time = 11111111.0;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::OK, time + 100.0, None),
+            fake_response(status_codes::OK, time + 100.0, None),
         ]);
 
         pq.enqueue(&make_record(100)).unwrap();
@@ -901,8 +958,8 @@ mod test {
         };
         let time = 11111111.0;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::OK, time + 100.0, None),
-            fake_response(StatusCode::OK, time + 200.0, None),
+            fake_response(status_codes::OK, time + 100.0, None),
+            fake_response(status_codes::OK, time + 200.0, None),
         ]);
 
         // Note that the total record overhead is around 85 bytes
@@ -942,8 +999,8 @@ mod test {
         };
         let time = 11111111.0;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::OK, time + 100.0, None),
-            fake_response(StatusCode::OK, time + 200.0, None),
+            fake_response(status_codes::OK, time + 100.0, None),
+            fake_response(status_codes::OK, time + 200.0, None),
         ]);
 
         // Note that the total record overhead is around 85 bytes
@@ -970,7 +1027,7 @@ mod test {
         let cfg = InfoConfiguration::default();
         let time = 11111111.0;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::ACCEPTED, time + 100.0, Some("1234")),
+            fake_response(status_codes::ACCEPTED, time + 100.0, Some("1234")),
         ]);
 
         let payload_size = 100 - *NON_PAYLOAD_OVERHEAD;
@@ -1000,8 +1057,8 @@ mod test {
         };
         let time = 11111111.0;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::ACCEPTED, time, Some("1234")),
-            fake_response(StatusCode::ACCEPTED, time + 100.0, Some("1234")),
+            fake_response(status_codes::ACCEPTED, time, Some("1234")),
+            fake_response(status_codes::ACCEPTED, time + 100.0, Some("1234")),
         ]);
 
         pq.enqueue(&make_record(100)).unwrap();
@@ -1042,9 +1099,9 @@ mod test {
         };
         let time = 11111111.0;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::ACCEPTED, time, Some("1234")),
-            fake_response(StatusCode::ACCEPTED, time, Some("1234")),
-            fake_response(StatusCode::ACCEPTED, time + 100.0, Some("1234")),
+            fake_response(status_codes::ACCEPTED, time, Some("1234")),
+            fake_response(status_codes::ACCEPTED, time, Some("1234")),
+            fake_response(status_codes::ACCEPTED, time + 100.0, Some("1234")),
         ]);
 
         pq.enqueue(&make_record(100)).unwrap();
@@ -1097,10 +1154,10 @@ mod test {
         };
         let time = 11111111.0;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::ACCEPTED, time, Some("1234")),
-            fake_response(StatusCode::ACCEPTED, time + 100.0, Some("1234")),
-            fake_response(StatusCode::ACCEPTED, time + 100.0, Some("abcd")),
-            fake_response(StatusCode::ACCEPTED, time + 200.0, Some("abcd")),
+            fake_response(status_codes::ACCEPTED, time, Some("1234")),
+            fake_response(status_codes::ACCEPTED, time + 100.0, Some("1234")),
+            fake_response(status_codes::ACCEPTED, time + 100.0, Some("abcd")),
+            fake_response(status_codes::ACCEPTED, time + 200.0, Some("abcd")),
         ]);
 
         pq.enqueue(&make_record(100)).unwrap();
@@ -1169,10 +1226,10 @@ mod test {
         };
         let time = 11111111.0;
         let (mut pq, tester) = pq_test_setup(cfg, time, vec![
-            fake_response(StatusCode::ACCEPTED, time, Some("1234")),
-            fake_response(StatusCode::ACCEPTED, time + 100.0, Some("1234")), // should commit
-            fake_response(StatusCode::ACCEPTED, time + 100.0, Some("abcd")),
-            fake_response(StatusCode::ACCEPTED, time + 200.0, Some("abcd")), // should commit
+            fake_response(status_codes::ACCEPTED, time, Some("1234")),
+            fake_response(status_codes::ACCEPTED, time + 100.0, Some("1234")), // should commit
+            fake_response(status_codes::ACCEPTED, time + 100.0, Some("abcd")),
+            fake_response(status_codes::ACCEPTED, time + 200.0, Some("abcd")), // should commit
         ]);
 
         pq.enqueue(&make_record(100)).unwrap();
@@ -1237,6 +1294,24 @@ mod test {
                    request_bytes_for_payloads(&[100]));
     }
 
+    #[test]
+    fn test_info_quota() {
+        let quota: InfoQuota = serde_json::from_str("[1843.5, 2048]").unwrap();
+        assert_eq!(quota, InfoQuota { usage_kb: 1843.5, quota_kb: Some(2048.0) });
+        assert!(quota.is_near_limit());
+        assert_eq!(serde_json::to_string(&quota).unwrap(), "[1843.5,2048.0]");
+
+        let quota: InfoQuota = serde_json::from_str("[1024, null]").unwrap();
+        assert_eq!(quota.fraction_used(), None);
+        assert!(!quota.is_near_limit());
+
+        let quota = InfoQuota { usage_kb: 1024.0, quota_kb: Some(2048.0) };
+        assert_eq!(quota.fraction_used(), Some(0.5));
+        assert!(!quota.is_near_limit());
+
+        assert!(serde_json::from_str::<InfoQuota>("{\"usage\": 1}").is_err());
+    }
+
     // TODO: Test
     //
     // - error cases!!! We don't test our handling of server errors at all!
@@ -6,11 +6,12 @@ use std::collections::{HashMap, HashSet};
 
 use bso_record::BsoRecord;
 use client::SetupStorageClient;
+use collection::CollectionName;
 use collection_keys::CollectionKeys;
 use error::{self, ErrorKind};
 use key_bundle::KeyBundle;
 use record_types::{MetaGlobalEngine, MetaGlobalRecord};
-use request::{InfoCollections, InfoConfiguration};
+use request::{InfoCollections, InfoConfiguration, InfoQuota};
 use util::{random_guid, ServerTimestamp, SERVER_EPOCH};
 use serde_json;
 
@@ -18,26 +19,31 @@ use self::SetupState::*;
 
 const STORAGE_VERSION: usize = 5;
 
+/// How often we fetch `info/quota` while the account isn't close to its
+/// quota. The server has to add up everything the account is storing to
+/// answer it, so we don't ask on every sync.
+const QUOTA_REFRESH_INTERVAL_SECS: u64 = 60 * 60 * 24;
+
 lazy_static! {
     /// Maps names to storage versions for engines to include in a fresh
     /// `meta/global` record. We include engines that we don't implement
     /// because they'll be disabled on other clients if we omit them
     /// (bug 1479929).
-    static ref DEFAULT_ENGINES: Vec<(&'static str, usize)> = vec![
-        ("passwords", 1),
-        ("clients", 1),
-        ("addons", 1),
-        ("addresses", 1),
-        ("bookmarks", 2),
-        ("creditcards", 1),
-        ("forms", 1),
-        ("history", 1),
-        ("prefs", 2),
-        ("tabs", 1),
+    static ref DEFAULT_ENGINES: Vec<(CollectionName, usize)> = vec![
+        (CollectionName::PASSWORDS, 1),
+        (CollectionName::CLIENTS, 1),
+        (CollectionName::ADDONS, 1),
+        (CollectionName::ADDRESSES, 1),
+        (CollectionName::BOOKMARKS, 2),
+        (CollectionName::CREDITCARDS, 1),
+        (CollectionName::FORMS, 1),
+        (CollectionName::HISTORY, 1),
+        (CollectionName::PREFS, 2),
+        (CollectionName::TABS, 1),
     ];
 
     // Declined engines to include in a fresh `meta/global` record.
-    static ref DEFAULT_DECLINED: Vec<&'static str> = vec![];
+    static ref DEFAULT_DECLINED: Vec<CollectionName> = vec![];
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -56,6 +62,13 @@ pub struct GlobalState {
     pub global: Option<BsoRecord<MetaGlobalRecord>>,
     pub keys: Option<CollectionKeys>,
     pub engine_state_changes: Vec<EngineStateChange>,
+    /// The account's storage usage and quota, from the last time we fetched
+    /// `info/quota`, if we ever have.
+    #[serde(default)]
+    pub quota: Option<InfoQuota>,
+    /// When we last fetched `info/quota`.
+    #[serde(default)]
+    pub quota_fetched: ServerTimestamp,
 }
 
 impl GlobalState {
@@ -82,33 +95,58 @@ impl GlobalState {
         self.collections.get(coll).cloned().unwrap_or(SERVER_EPOCH)
     }
 
-    /// Returns a set of all engine names that should be reset locally.
-    pub fn engines_that_need_local_reset(&self) -> HashSet<String> {
+    /// Returns true if the user declined syncing `engine` (on any client),
+    /// according to `meta/global`.
+    pub fn is_engine_declined(&self, engine: &str) -> bool {
+        self.global
+            .as_ref()
+            .map(|global| global.declined.iter().any(|name| name == engine))
+            .unwrap_or(false)
+    }
+
+    /// Returns true if `engine` should sync: it's in `meta/global`, and not
+    /// declined. The clients engine is always enabled, since other clients
+    /// rely on it to send us commands.
+    pub fn is_engine_enabled(&self, engine: &str) -> bool {
+        if engine == CollectionName::CLIENTS.as_str() {
+            return true;
+        }
+        self.global
+            .as_ref()
+            .map(|global| global.engines.contains_key(engine))
+            .unwrap_or(false) && !self.is_engine_declined(engine)
+    }
+
+    /// Returns a set of all engine names that should be reset locally. After
+    /// we upload a fresh `meta/global` (for example, to set up a new
+    /// account), this is every engine, so that they upload everything they
+    /// have.
+    pub fn engines_that_need_local_reset(&self) -> HashSet<CollectionName> {
         let all_engines = self.global
             .as_ref()
             .map(|global| {
                 global
                     .engines
                     .keys()
-                    .map(|key| key.to_string())
-                    .collect::<HashSet<String>>()
+                    .cloned()
+                    .collect::<HashSet<CollectionName>>()
             })
             .unwrap_or_default();
         let mut engines_to_reset = HashSet::new();
         for change in &self.engine_state_changes {
             match change {
                 EngineStateChange::Reset(name) => {
-                    engines_to_reset.insert(name.to_string());
+                    engines_to_reset.insert(name.clone());
                 }
                 EngineStateChange::ResetAll => {
                     engines_to_reset.reserve(all_engines.len());
                     for name in all_engines.iter() {
-                        engines_to_reset.insert(name.to_string());
+                        engines_to_reset.insert(name.clone());
                     }
                 }
                 EngineStateChange::ResetAllExcept(except) => {
                     for name in all_engines.difference(except) {
-                        engines_to_reset.insert(name.to_string());
+                        engines_to_reset.insert(name.clone());
                     }
                 }
                 _ => {}
@@ -146,6 +184,8 @@ fn resolve_global(
         global: Some(new_global),
         keys: previous_keys,
         engine_state_changes: changes,
+        quota: previous_state.quota,
+        quota_fetched: previous_state.quota_fetched,
     }
 }
 
@@ -155,19 +195,32 @@ fn engine_state_changes_from_new_global(
 ) -> Vec<EngineStateChange> {
     let mut changes = Vec::new();
 
-    let previous_engine_names = previous_global.engines.keys().collect::<HashSet<&String>>();
-    let new_engine_names = new_global.engines.keys().collect::<HashSet<&String>>();
+    let previous_engine_names = previous_global.engines.keys().collect::<HashSet<&CollectionName>>();
+    let new_engine_names = new_global.engines.keys().collect::<HashSet<&CollectionName>>();
 
     // Disable any local engines that aren't mentioned
     // in the new `meta/global`.
     for name in previous_engine_names.difference(&new_engine_names) {
-        changes.push(EngineStateChange::Disable(name.to_string()));
+        changes.push(EngineStateChange::Disable((*name).clone()));
     }
 
     // Enable any new engines that aren't mentioned in
     // the locally cached `meta/global`.
     for name in new_engine_names.difference(&previous_engine_names) {
-        changes.push(EngineStateChange::Enable(name.to_string()));
+        changes.push(EngineStateChange::Enable((*name).clone()));
+    }
+
+    // Disable engines that were declined since, and enable ones that aren't
+    // declined anymore (if they're still in `meta/global`).
+    for name in &new_global.declined {
+        if !previous_global.declined.contains(name) && previous_global.engines.contains_key(name) {
+            changes.push(EngineStateChange::Disable(name.clone()));
+        }
+    }
+    for name in &previous_global.declined {
+        if !new_global.declined.contains(name) && new_global.engines.contains_key(name) {
+            changes.push(EngineStateChange::Enable(name.clone()));
+        }
     }
 
     // Reset engines with sync ID changes.
@@ -175,7 +228,7 @@ fn engine_state_changes_from_new_global(
         let previous_engine = previous_global.engines.get(*name).unwrap();
         let new_engine = new_global.engines.get(*name).unwrap();
         if previous_engine.sync_id != new_engine.sync_id {
-            changes.push(EngineStateChange::Reset(name.to_string()));
+            changes.push(EngineStateChange::Reset((*name).clone()));
         }
     }
 
@@ -191,12 +244,12 @@ fn resolve_keys(previous_state: GlobalState, new_keys: CollectionKeys) -> Global
                 // engines with different collection-specific keys.
                 for (collection, key_bundle) in &previous_global.collections {
                     if key_bundle != new_keys.key_for_collection(collection) {
-                        changes.push(EngineStateChange::Reset(collection.to_string()));
+                        changes.push(EngineStateChange::Reset(collection.clone().into()));
                     }
                 }
                 for (collection, key_bundle) in &new_keys.collections {
                     if key_bundle != previous_global.key_for_collection(collection) {
-                        changes.push(EngineStateChange::Reset(collection.to_string()));
+                        changes.push(EngineStateChange::Reset(collection.clone().into()));
                     }
                 }
             } else {
@@ -206,12 +259,12 @@ fn resolve_keys(previous_state: GlobalState, new_keys: CollectionKeys) -> Global
                 let mut except = HashSet::new();
                 for (collection, key_bundle) in &previous_global.collections {
                     if key_bundle == new_keys.key_for_collection(collection) {
-                        except.insert(collection.to_string());
+                        except.insert(collection.clone().into());
                     }
                 }
                 for (collection, key_bundle) in &new_keys.collections {
                     if key_bundle != previous_global.key_for_collection(collection) {
-                        except.insert(collection.to_string());
+                        except.insert(collection.clone().into());
                     }
                 }
                 changes.push(EngineStateChange::ResetAllExcept(except));
@@ -225,20 +278,30 @@ fn resolve_keys(previous_state: GlobalState, new_keys: CollectionKeys) -> Global
         global: previous_state.global,
         keys: Some(new_keys),
         engine_state_changes: changes,
+        quota: previous_state.quota,
+        quota_fetched: previous_state.quota_fetched,
     }
 }
 
 /// Creates a fresh `meta/global` record, using the default engine selections,
-/// and declined engines from the previous record.
+/// and declined engines from the previous record. Declined engines are left
+/// out of `engines`.
 fn new_global_from_previous(
     previous_global: Option<BsoRecord<MetaGlobalRecord>>,
 ) -> error::Result<MetaGlobalRecord> {
     let sync_id = random_guid()?;
-    let mut engines: HashMap<String, _> = HashMap::new();
+    let declined = previous_global
+        .as_ref()
+        .map(|global| global.declined.clone())
+        .unwrap_or_else(|| DEFAULT_DECLINED.clone());
+    let mut engines = HashMap::new();
     for (name, version) in DEFAULT_ENGINES.iter() {
+        if declined.contains(name) {
+            continue;
+        }
         let sync_id = random_guid()?;
         engines.insert(
-            name.to_string(),
+            name.clone(),
             MetaGlobalEngine {
                 version: *version,
                 sync_id,
@@ -249,23 +312,30 @@ fn new_global_from_previous(
         sync_id,
         storage_version: STORAGE_VERSION,
         engines,
-        declined: previous_global
-            .as_ref()
-            .map(|global| global.declined.clone())
-            .unwrap_or_else(|| {
-                DEFAULT_DECLINED
-                    .iter()
-                    .map(|name| name.to_string())
-                    .collect()
-            }),
+        declined,
     })
 }
 
+/// Returns true if we should fetch `info/quota`: if we never have, if the
+/// account was close to its quota last time (so apps can tell as soon as
+/// the user frees up space), or if it's been a while.
+fn should_fetch_quota(state: &GlobalState, now: ServerTimestamp) -> bool {
+    match state.quota {
+        None => true,
+        Some(quota) if quota.is_near_limit() => true,
+        Some(_) => now
+            .duration_since(state.quota_fetched)
+            .map(|elapsed| elapsed.as_secs() >= QUOTA_REFRESH_INTERVAL_SECS)
+            .unwrap_or(false),
+    }
+}
+
 pub struct SetupStateMachine<'client, 'keys> {
     client: &'client SetupStorageClient,
     root_key: &'keys KeyBundle,
     allowed_states: Vec<&'static str>,
     sequence: Vec<&'static str>,
+    transitions: Vec<Transition>,
 }
 
 impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
@@ -347,36 +417,65 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
             client,
             root_key,
             sequence: Vec::new(),
+            transitions: Vec::new(),
             allowed_states,
         }
     }
 
-    fn advance(&self, from: SetupState) -> error::Result<SetupState> {
+    /// Every transition taken by the last call to `to_ready`, in order.
+    /// Useful for logging, telemetry, and tests.
+    pub fn transitions(&self) -> &[Transition] {
+        &self.transitions
+    }
+
+    fn advance(&self, from: SetupState) -> error::Result<(SetupState, TransitionReason)> {
         match from {
             // Fetch `info/configuration` with current server limits, and
             // `info/collections` with collection last modified times.
             InitialWithLiveToken(state) => {
-                let config = self.client
-                    .fetch_info_configuration()
-                    .unwrap_or(state.config);
-                Ok(InitialWithLiveTokenAndConfig(GlobalState {
+                let (config, reason) = match self.client.fetch_info_configuration() {
+                    Ok(config) => (config, TransitionReason::FetchedConfig),
+                    Err(_) => (state.config, TransitionReason::UsingCachedConfig),
+                };
+                Ok((InitialWithLiveTokenAndConfig(GlobalState {
                     config,
                     collections: state.collections,
                     global: state.global,
                     keys: state.keys,
                     engine_state_changes: Vec::new(),
-                }))
+                    quota: state.quota,
+                    quota_fetched: state.quota_fetched,
+                }), reason))
             }
 
             InitialWithLiveTokenAndConfig(state) => {
                 let collections = self.client.fetch_info_collections()?;
-                Ok(InitialWithLiveTokenAndInfo(GlobalState {
+                // Usage and quota are only informational, so we keep the
+                // cached ones if we can't fetch them.
+                let now = ServerTimestamp::now_estimate();
+                let (quota, quota_fetched) = if should_fetch_quota(&state, now) {
+                    match self.client.fetch_info_quota() {
+                        Ok(quota) => (Some(quota), now),
+                        Err(e) => {
+                            warn!("Failed to fetch info/quota: {}", e);
+                            (state.quota, state.quota_fetched)
+                        }
+                    }
+                } else {
+                    (state.quota, state.quota_fetched)
+                };
+                if quota.map(|quota| quota.is_near_limit()).unwrap_or(false) {
+                    warn!("Account is close to its storage quota: {:?}", quota);
+                }
+                Ok((InitialWithLiveTokenAndInfo(GlobalState {
                     config: state.config,
                     collections,
                     global: state.global,
                     keys: state.keys,
                     engine_state_changes: state.engine_state_changes,
-                }))
+                    quota,
+                    quota_fetched,
+                }), TransitionReason::FetchedCollections))
             }
 
             // Compare local and remote `meta/global` timestamps to determine
@@ -390,10 +489,10 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 Ok(match action {
                     // Hooray, we don't need to fetch `meta/global`. Skip to
                     // the next state.
-                    FetchAction::Skip => HasMetaGlobal(state),
+                    FetchAction::Skip => (HasMetaGlobal(state), TransitionReason::MetaGlobalUpToDate),
                     // Our `meta/global` is out of date, or isn't cached
                     // locally, so we need to fetch it from the server.
-                    FetchAction::Fetch => NeedsFreshMetaGlobal(state),
+                    FetchAction::Fetch => (NeedsFreshMetaGlobal(state), TransitionReason::MetaGlobalStale),
                     // We have a `meta/global` record in our cache, but not on
                     // the server. This likely means we're the first client to
                     // sync after a node reassignment. Invalidate our cached
@@ -401,21 +500,30 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     // `meta/global` from the server anyway. If another client
                     // wins the race, we'll fetch its `meta/global`; if not,
                     // we'll fail and upload our own.
-                    FetchAction::InvalidateThenUpload => NeedsFreshMetaGlobal(GlobalState {
+                    FetchAction::InvalidateThenUpload => (NeedsFreshMetaGlobal(GlobalState {
                         config: state.config,
                         collections: state.collections,
                         global: None,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
-                    }),
+                        quota: state.quota,
+                        quota_fetched: state.quota_fetched,
+                    }), TransitionReason::MetaGlobalMissingRemotely),
                 })
             }
 
             // Fetch `meta/global` from the server.
             NeedsFreshMetaGlobal(state) => match self.client.fetch_meta_global() {
-                Ok(new_global) => Ok(ResolveMetaGlobal(state, new_global)),
+                Ok(new_global) => Ok((ResolveMetaGlobal(state, new_global),
+                                      TransitionReason::FetchedMetaGlobal)),
                 Err(err) => match err.kind() {
-                    ErrorKind::NoMetaGlobal { .. } => Ok(FreshStartRequired(state)),
+                    // A new account (or a new node, after a reassignment)
+                    // has nothing at all, so we need to set it up.
+                    ErrorKind::NoMetaGlobal { .. } if state.collections.is_empty() => {
+                        Ok((FreshStartRequired(state), TransitionReason::EmptyServer))
+                    }
+                    ErrorKind::NoMetaGlobal { .. } => Ok((FreshStartRequired(state),
+                                                          TransitionReason::NoMetaGlobal)),
                     _ => Err(err),
                 },
             },
@@ -432,11 +540,11 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 // If the server has an older storage version, wipe and
                 // reupload.
                 if new_global.payload.storage_version < STORAGE_VERSION {
-                    return Ok(FreshStartRequired(state));
+                    return Ok((FreshStartRequired(state), TransitionReason::StorageVersionTooOld));
                 }
 
                 let new_state = resolve_global(state, new_global);
-                Ok(HasMetaGlobal(new_state))
+                Ok((HasMetaGlobal(new_state), TransitionReason::ResolvedMetaGlobal))
             }
 
             // Check if our locally cached `crypto/keys` collection is
@@ -451,19 +559,21 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 };
                 Ok(match action {
                     // If `crypto/keys` is up-to-date, we're ready to go!
-                    FetchAction::Skip => Ready(state),
+                    FetchAction::Skip => (Ready(state), TransitionReason::CryptoKeysUpToDate),
                     // We need to fetch and cache new keys.
-                    FetchAction::Fetch => NeedsFreshCryptoKeys(state),
+                    FetchAction::Fetch => (NeedsFreshCryptoKeys(state), TransitionReason::CryptoKeysStale),
                     // We need to invalidate our locally cached `crypto/keys`,
                     // then try to fetch new keys, and reupload if fetching
                     // fails.
-                    FetchAction::InvalidateThenUpload => NeedsFreshCryptoKeys(GlobalState {
+                    FetchAction::InvalidateThenUpload => (NeedsFreshCryptoKeys(GlobalState {
                         config: state.config,
                         collections: state.collections,
                         global: state.global,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
-                    }),
+                        quota: state.quota,
+                        quota_fetched: state.quota_fetched,
+                    }), TransitionReason::CryptoKeysMissingRemotely),
                 })
             }
 
@@ -473,22 +583,27 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         let new_keys =
                             CollectionKeys::from_encrypted_bso(encrypted_bso, self.root_key)?;
                         let new_state = resolve_keys(state, new_keys);
-                        Ok(Ready(new_state))
+                        Ok((Ready(new_state), TransitionReason::FetchedCryptoKeys))
                     }
                     Err(err) => match err.kind() {
                         // If the server doesn't have a `crypto/keys`, start over
                         // and reupload our `meta/global` and `crypto/keys`.
-                        ErrorKind::NoCryptoKeys { .. } => Ok(FreshStartRequired(state)),
+                        ErrorKind::NoCryptoKeys { .. } => Ok((FreshStartRequired(state),
+                                                              TransitionReason::NoCryptoKeys)),
                         _ => Err(err),
                     },
                 }
             }
 
-            Ready(state) => Ok(Ready(state)),
+            Ready(state) => Ok((Ready(state), TransitionReason::AlreadyReady)),
 
             FreshStartRequired(state) => {
-                // Wipe the server.
-                self.client.wipe_all_remote()?;
+                // Wipe the server, unless there's nothing to wipe.
+                if state.collections.is_empty() {
+                    info!("Setting up an empty server");
+                } else {
+                    self.client.wipe_all_remote()?;
+                }
 
                 // Upload a fresh `meta/global`...
                 let new_global = BsoRecord::new_record(
@@ -507,19 +622,23 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 // TODO(lina): Can we pass along server timestamps from the PUTs
                 // above, and avoid re-fetching the `m/g` and `c/k` we just
                 // uploaded?
-                Ok(InitialWithLiveTokenAndConfig(GlobalState {
+                Ok((InitialWithLiveTokenAndConfig(GlobalState {
                     config: state.config,
                     collections: InfoCollections::default(),
                     global: None,
                     keys: None,
                     engine_state_changes: vec![EngineStateChange::ResetAll],
-                }))
+                    quota: state.quota,
+                    quota_fetched: state.quota_fetched,
+                }), TransitionReason::UploadedFreshStart))
             }
         }
     }
 
     /// Runs through the state machine to the ready state.
     pub fn to_ready(&mut self, state: GlobalState) -> error::Result<GlobalState> {
+        self.sequence.clear();
+        self.transitions.clear();
         let mut s = InitialWithLiveToken(state);
         loop {
             let label = &s.label();
@@ -541,7 +660,14 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         return Err(ErrorKind::DisallowedStateError(&label).into());
                     }
                     self.sequence.push(label);
-                    s = self.advance(previous_s)?;
+                    let (next, reason) = self.advance(previous_s)?;
+                    debug!("Setup state {} -> {} ({:?})", label, next.label(), reason);
+                    self.transitions.push(Transition {
+                        from: *label,
+                        to: next.label(),
+                        reason,
+                    });
+                    s = next;
                 }
             }
         }
@@ -579,6 +705,53 @@ impl SetupState {
     }
 }
 
+/// Why the state machine moved from one state to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionReason {
+    /// Fetched fresh server limits from `info/configuration`.
+    FetchedConfig,
+    /// Couldn't fetch `info/configuration`, so kept the cached limits.
+    UsingCachedConfig,
+    FetchedCollections,
+    /// Our cached `meta/global` is at least as new as the server's.
+    MetaGlobalUpToDate,
+    /// Our cached `meta/global` is older than the server's, or missing.
+    MetaGlobalStale,
+    /// We have a cached `meta/global` but the server doesn't, most likely
+    /// because we were reassigned to a new storage node.
+    MetaGlobalMissingRemotely,
+    FetchedMetaGlobal,
+    /// The server has no `meta/global`, but has other collections.
+    NoMetaGlobal,
+    /// The server has nothing at all, because this is a new account, or we
+    /// were reassigned to a new node. We upload a fresh `meta/global` and
+    /// `crypto/keys` without wiping anything first.
+    EmptyServer,
+    /// The server's `meta/global` has an older storage version than ours.
+    StorageVersionTooOld,
+    ResolvedMetaGlobal,
+    CryptoKeysUpToDate,
+    CryptoKeysStale,
+    /// We have cached keys but the server doesn't (see
+    /// `MetaGlobalMissingRemotely`).
+    CryptoKeysMissingRemotely,
+    FetchedCryptoKeys,
+    /// The server has no `crypto/keys`.
+    NoCryptoKeys,
+    /// We wiped the server and uploaded a fresh `meta/global` and
+    /// `crypto/keys`.
+    UploadedFreshStart,
+    AlreadyReady,
+}
+
+/// A single step taken by the setup state machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    pub from: &'static str,
+    pub to: &'static str,
+    pub reason: TransitionReason,
+}
+
 /// Whether we should skip fetching `meta/global` or `crypto/keys` from the
 /// server because our locally cached copy is up-to-date, fetch a fresh copy
 /// from the server, or invalidate our locally cached state, then upload a
@@ -609,24 +782,27 @@ impl FetchAction {
 }
 
 /// Flags an engine for enablement or disablement.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum EngineStateChange {
     ResetAll,
-    ResetAllExcept(HashSet<String>),
-    Enable(String),
-    Disable(String),
-    Reset(String),
+    ResetAllExcept(HashSet<CollectionName>),
+    Enable(CollectionName),
+    Disable(CollectionName),
+    Reset(CollectionName),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::cell::{Cell, RefCell};
+
     use bso_record::{BsoRecord, EncryptedBso, EncryptedPayload};
 
     struct InMemoryClient {
         info_configuration: error::Result<InfoConfiguration>,
         info_collections: error::Result<InfoCollections>,
+        info_quota: error::Result<InfoQuota>,
         meta_global: error::Result<BsoRecord<MetaGlobalRecord>>,
         crypto_keys: error::Result<BsoRecord<EncryptedPayload>>,
     }
@@ -652,15 +828,28 @@ mod tests {
             }
         }
 
+        fn fetch_info_quota(&self) -> error::Result<InfoQuota> {
+            match &self.info_quota {
+                Ok(quota) => Ok(*quota),
+                Err(_) => Err(ErrorKind::StorageHttpError {
+                    code: 500,
+                    route: "info/quota".to_string(),
+                }.into()),
+            }
+        }
+
         fn fetch_meta_global(&self) -> error::Result<BsoRecord<MetaGlobalRecord>> {
             match &self.meta_global {
                 Ok(global) => Ok(global.clone()),
                 // TODO(lina): Special handling for 404s, we want to ensure we
                 // handle missing keys and other server errors correctly.
-                Err(_) => Err(ErrorKind::StorageHttpError {
-                    code: 500,
-                    route: "meta/global".to_string(),
-                }.into()),
+                Err(err) => match err.kind() {
+                    ErrorKind::NoMetaGlobal => Err(ErrorKind::NoMetaGlobal.into()),
+                    _ => Err(ErrorKind::StorageHttpError {
+                        code: 500,
+                        route: "meta/global".to_string(),
+                    }.into()),
+                },
             }
         }
 
@@ -675,10 +864,13 @@ mod tests {
             match &self.crypto_keys {
                 Ok(keys) => Ok(keys.clone()),
                 // TODO(lina): Same as above, for 404s.
-                Err(_) => Err(ErrorKind::StorageHttpError {
-                    code: 500,
-                    route: "crypto/keys".to_string(),
-                }.into()),
+                Err(err) => match err.kind() {
+                    ErrorKind::NoCryptoKeys => Err(ErrorKind::NoCryptoKeys.into()),
+                    _ => Err(ErrorKind::StorageHttpError {
+                        code: 500,
+                        route: "crypto/keys".to_string(),
+                    }.into()),
+                },
             }
         }
 
@@ -694,47 +886,137 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_state_machine_ready_from_empty() {
-        let root_key = KeyBundle::new_random().unwrap();
+    /// A storage server that accepts uploads, and starts out empty, like the
+    /// one a new account gets.
+    #[derive(Default)]
+    struct FakeServer {
+        meta_global: RefCell<Option<BsoRecord<MetaGlobalRecord>>>,
+        crypto_keys: RefCell<Option<EncryptedBso>>,
+        wipes: Cell<usize>,
+        last_modified: Cell<f64>,
+    }
+
+    impl FakeServer {
+        fn next_modified(&self) -> ServerTimestamp {
+            self.last_modified.set(self.last_modified.get() + 1.0);
+            ServerTimestamp(self.last_modified.get())
+        }
+    }
+
+    impl SetupStorageClient for FakeServer {
+        fn fetch_info_configuration(&self) -> error::Result<InfoConfiguration> {
+            Ok(InfoConfiguration::default())
+        }
+
+        fn fetch_info_collections(&self) -> error::Result<InfoCollections> {
+            let mut collections = HashMap::new();
+            if let Some(global) = &*self.meta_global.borrow() {
+                collections.insert("meta".to_owned(), global.modified);
+            }
+            if let Some(keys) = &*self.crypto_keys.borrow() {
+                collections.insert("crypto".to_owned(), keys.modified);
+            }
+            Ok(InfoCollections::new(collections))
+        }
+
+        fn fetch_info_quota(&self) -> error::Result<InfoQuota> {
+            Ok(InfoQuota { usage_kb: 0.0, quota_kb: None })
+        }
+
+        fn fetch_meta_global(&self) -> error::Result<BsoRecord<MetaGlobalRecord>> {
+            self.meta_global.borrow().clone().ok_or_else(|| ErrorKind::NoMetaGlobal.into())
+        }
+
+        fn put_meta_global(&self, global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()> {
+            let mut global = global.clone();
+            global.modified = self.next_modified();
+            *self.meta_global.borrow_mut() = Some(global);
+            Ok(())
+        }
+
+        fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso> {
+            self.crypto_keys.borrow().clone().ok_or_else(|| ErrorKind::NoCryptoKeys.into())
+        }
+
+        fn put_crypto_keys(&self, keys: &EncryptedBso) -> error::Result<()> {
+            let mut keys = keys.clone();
+            keys.modified = self.next_modified();
+            *self.crypto_keys.borrow_mut() = Some(keys);
+            Ok(())
+        }
+
+        fn wipe_all_remote(&self) -> error::Result<()> {
+            self.wipes.set(self.wipes.get() + 1);
+            *self.meta_global.borrow_mut() = None;
+            *self.crypto_keys.borrow_mut() = None;
+            Ok(())
+        }
+    }
+
+    fn mocked_global(storage_version: usize) -> BsoRecord<MetaGlobalRecord> {
+        BsoRecord {
+            id: "global".into(),
+            modified: ServerTimestamp(999.0),
+            collection: "meta".into(),
+            sortindex: None,
+            ttl: None,
+            payload: MetaGlobalRecord {
+                sync_id: "syncIDAAAAAA".to_owned(),
+                storage_version,
+                engines: vec![
+                    (
+                        "bookmarks",
+                        MetaGlobalEngine {
+                            version: 1usize,
+                            sync_id: "syncIDBBBBBB".to_owned(),
+                        },
+                    ),
+                ].into_iter()
+                    .map(|(key, value)| (key.into(), value.into()))
+                    .collect(),
+                declined: vec![],
+            },
+        }
+    }
+
+    fn mocked_collections(entries: Vec<(&str, f64)>) -> InfoCollections {
+        InfoCollections::new(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value.into()))
+                .collect(),
+        )
+    }
+
+    fn mocked_client(root_key: &KeyBundle) -> InMemoryClient {
         let keys = CollectionKeys {
             timestamp: 123.4.into(),
             default: KeyBundle::new_random().unwrap(),
             collections: HashMap::new(),
         };
-        let client = InMemoryClient {
+        let mut crypto_keys = keys.to_encrypted_bso(root_key).unwrap();
+        crypto_keys.modified = ServerTimestamp(145.0);
+        InMemoryClient {
             info_configuration: Ok(InfoConfiguration::default()),
-            info_collections: Ok(InfoCollections::new(
-                vec![("meta", 123.456), ("crypto", 145.0)]
-                    .into_iter()
-                    .map(|(key, value)| (key.to_owned(), value.into()))
-                    .collect(),
-            )),
-            meta_global: Ok(BsoRecord {
-                id: "global".into(),
-                modified: ServerTimestamp(999.0),
-                collection: "meta".into(),
-                sortindex: None,
-                ttl: None,
-                payload: MetaGlobalRecord {
-                    sync_id: "syncIDAAAAAA".to_owned(),
-                    storage_version: 5usize,
-                    engines: vec![
-                        (
-                            "bookmarks",
-                            MetaGlobalEngine {
-                                version: 1usize,
-                                sync_id: "syncIDBBBBBB".to_owned(),
-                            },
-                        ),
-                    ].into_iter()
-                        .map(|(key, value)| (key.to_owned(), value.into()))
-                        .collect(),
-                    declined: vec![],
-                },
-            }),
-            crypto_keys: keys.to_encrypted_bso(&root_key),
-        };
+            info_collections: Ok(mocked_collections(vec![("meta", 123.456), ("crypto", 145.0)])),
+            info_quota: Ok(InfoQuota { usage_kb: 512.0, quota_kb: Some(2048.0) }),
+            meta_global: Ok(mocked_global(5usize)),
+            crypto_keys: Ok(crypto_keys),
+        }
+    }
+
+    fn reasons(state_machine: &SetupStateMachine) -> Vec<TransitionReason> {
+        state_machine
+            .transitions()
+            .iter()
+            .map(|t| t.reason)
+            .collect()
+    }
+
+    #[test]
+    fn test_state_machine_ready_from_empty() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = mocked_client(&root_key);
 
         let state = GlobalState::default();
         let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
@@ -756,5 +1038,319 @@ mod tests {
             ],
             "Should cycle through all states"
         );
+        assert_eq!(
+            reasons(&state_machine),
+            vec![
+                TransitionReason::FetchedConfig,
+                TransitionReason::FetchedCollections,
+                TransitionReason::MetaGlobalStale,
+                TransitionReason::FetchedMetaGlobal,
+                TransitionReason::ResolvedMetaGlobal,
+                TransitionReason::CryptoKeysStale,
+                TransitionReason::FetchedCryptoKeys,
+            ]
+        );
+        let last = state_machine.transitions().last().unwrap();
+        assert_eq!(last.from, "NeedsFreshCryptoKeys");
+        assert_eq!(last.to, "Ready");
+    }
+
+    #[test]
+    fn test_state_machine_ready_from_cache() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = mocked_client(&root_key);
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        let state = state_machine.to_ready(GlobalState::default()).unwrap();
+
+        // Nothing changed on the server, so a second sync shouldn't need to
+        // fetch `meta/global` or `crypto/keys`.
+        let mut state_machine = SetupStateMachine::for_fast_sync(&client, &root_key);
+        assert!(state_machine.to_ready(state).is_ok());
+        assert_eq!(
+            reasons(&state_machine),
+            vec![
+                TransitionReason::FetchedConfig,
+                TransitionReason::FetchedCollections,
+                TransitionReason::MetaGlobalUpToDate,
+                TransitionReason::CryptoKeysUpToDate,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_state_machine_cached_config() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let mut client = mocked_client(&root_key);
+        client.info_configuration = Err(ErrorKind::StorageHttpError {
+            code: 503,
+            route: "info/configuration".to_string(),
+        }.into());
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        assert!(state_machine.to_ready(GlobalState::default()).is_ok());
+        assert_eq!(
+            state_machine.transitions()[0].reason,
+            TransitionReason::UsingCachedConfig
+        );
+    }
+
+    #[test]
+    fn test_state_machine_fresh_server() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let mut client = mocked_client(&root_key);
+        client.info_collections = Ok(mocked_collections(vec![]));
+        client.meta_global = Err(ErrorKind::NoMetaGlobal.into());
+
+        // Our in-memory client refuses uploads, so we should stop right after
+        // deciding to start fresh.
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        assert!(state_machine.to_ready(GlobalState::default()).is_err());
+        assert_eq!(
+            reasons(&state_machine),
+            vec![
+                TransitionReason::FetchedConfig,
+                TransitionReason::FetchedCollections,
+                TransitionReason::MetaGlobalStale,
+                TransitionReason::EmptyServer,
+            ]
+        );
+        assert_eq!(state_machine.sequence.last(), Some(&"FreshStartRequired"));
+    }
+
+    #[test]
+    fn test_state_machine_empty_server() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let server = FakeServer::default();
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&server, &root_key);
+        let state = state_machine.to_ready(GlobalState::default())
+            .expect("Should set up an empty server");
+        assert_eq!(
+            reasons(&state_machine),
+            vec![
+                TransitionReason::FetchedConfig,
+                TransitionReason::FetchedCollections,
+                TransitionReason::MetaGlobalStale,
+                TransitionReason::EmptyServer,
+                TransitionReason::UploadedFreshStart,
+                TransitionReason::FetchedCollections,
+                TransitionReason::MetaGlobalStale,
+                TransitionReason::FetchedMetaGlobal,
+                TransitionReason::ResolvedMetaGlobal,
+                TransitionReason::CryptoKeysStale,
+                TransitionReason::FetchedCryptoKeys,
+            ]
+        );
+        assert_eq!(server.wipes.get(), 0, "Shouldn't wipe an empty server");
+        assert!(state.is_engine_enabled("passwords"));
+        // This is every engine's first sync, so they should upload everything.
+        let reset = state.engines_that_need_local_reset();
+        assert!(reset.contains(&CollectionName::PASSWORDS));
+        assert!(reset.contains(&CollectionName::BOOKMARKS));
+
+        // The next sync finds the records we uploaded.
+        let mut state_machine = SetupStateMachine::for_full_sync(&server, &root_key);
+        let state = state_machine.to_ready(state).unwrap();
+        assert_eq!(
+            reasons(&state_machine),
+            vec![
+                TransitionReason::FetchedConfig,
+                TransitionReason::FetchedCollections,
+                TransitionReason::MetaGlobalUpToDate,
+                TransitionReason::CryptoKeysUpToDate,
+            ]
+        );
+        assert!(state.engines_that_need_local_reset().is_empty());
+    }
+
+    #[test]
+    fn test_state_machine_missing_keys() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let server = FakeServer::default();
+        server.put_meta_global(&mocked_global(STORAGE_VERSION)).unwrap();
+
+        // The server has a `meta/global`, but no keys to go with it, so we
+        // need to start over.
+        let mut state_machine = SetupStateMachine::for_full_sync(&server, &root_key);
+        let state = state_machine.to_ready(GlobalState::default())
+            .expect("Should replace the server's records");
+        let reasons = reasons(&state_machine);
+        assert!(reasons.contains(&TransitionReason::NoCryptoKeys));
+        assert!(!reasons.contains(&TransitionReason::EmptyServer));
+        assert_eq!(server.wipes.get(), 1);
+        assert_ne!(state.global.unwrap().sync_id, "syncIDAAAAAA");
+        assert!(server.crypto_keys.borrow().is_some());
+    }
+
+    #[test]
+    fn test_state_machine_old_storage_version() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let mut client = mocked_client(&root_key);
+        client.meta_global = Ok(mocked_global(4usize));
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        assert!(state_machine.to_ready(GlobalState::default()).is_err());
+        assert_eq!(
+            reasons(&state_machine).last(),
+            Some(&TransitionReason::StorageVersionTooOld)
+        );
+    }
+
+    #[test]
+    fn test_state_machine_node_reassigned() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = mocked_client(&root_key);
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        let state = state_machine.to_ready(GlobalState::default()).unwrap();
+
+        // After a node reassignment, the new node is empty, but we still have
+        // the old node's `meta/global` cached.
+        let mut client = mocked_client(&root_key);
+        client.info_collections = Ok(mocked_collections(vec![]));
+        client.meta_global = Err(ErrorKind::NoMetaGlobal.into());
+        client.crypto_keys = Err(ErrorKind::NoCryptoKeys.into());
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        assert!(state_machine.to_ready(state).is_err());
+        assert_eq!(
+            reasons(&state_machine),
+            vec![
+                TransitionReason::FetchedConfig,
+                TransitionReason::FetchedCollections,
+                TransitionReason::MetaGlobalMissingRemotely,
+                TransitionReason::EmptyServer,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_state_machine_stale_keys() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = mocked_client(&root_key);
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        let state = state_machine.to_ready(GlobalState::default()).unwrap();
+
+        // Another client uploaded new keys since our last sync.
+        let mut client = mocked_client(&root_key);
+        client.info_collections = Ok(mocked_collections(vec![("meta", 123.456), ("crypto", 200.0)]));
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        assert!(state_machine.to_ready(state).is_ok());
+        assert_eq!(
+            reasons(&state_machine),
+            vec![
+                TransitionReason::FetchedConfig,
+                TransitionReason::FetchedCollections,
+                TransitionReason::MetaGlobalUpToDate,
+                TransitionReason::CryptoKeysStale,
+                TransitionReason::FetchedCryptoKeys,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_state_machine_disallowed_state() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let mut client = mocked_client(&root_key);
+        client.info_collections = Ok(mocked_collections(vec![]));
+        client.meta_global = Err(ErrorKind::NoMetaGlobal.into());
+
+        // Read-only syncs must never wipe the server.
+        let mut state_machine = SetupStateMachine::for_readonly_sync(&client, &root_key);
+        let err = state_machine.to_ready(GlobalState::default()).unwrap_err();
+        match err.kind() {
+            ErrorKind::DisallowedStateError("FreshStartRequired") => {}
+            _ => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_state_machine_quota() {
+        fn to_ready(client: &InMemoryClient, root_key: &KeyBundle, state: GlobalState) -> GlobalState {
+            SetupStateMachine::for_full_sync(client, root_key).to_ready(state).unwrap()
+        }
+
+        let root_key = KeyBundle::new_random().unwrap();
+        let mut client = mocked_client(&root_key);
+        let state = to_ready(&client, &root_key, GlobalState::default());
+        let quota = state.quota.expect("Should fetch info/quota on the first sync");
+        assert_eq!(quota.usage_kb, 512.0);
+        assert!(!quota.is_near_limit());
+        assert!(state.quota_fetched > SERVER_EPOCH);
+
+        // We fetched it recently, so we shouldn't ask again, even if we
+        // persisted the state in between.
+        let state = GlobalState::from_persisted_string(&state.to_persistable_string()).unwrap();
+        client.info_quota = Ok(InfoQuota { usage_kb: 1900.0, quota_kb: Some(2048.0) });
+        let mut state = to_ready(&client, &root_key, state);
+        assert_eq!(state.quota, Some(quota));
+
+        // ...But we should once it's old.
+        state.quota_fetched = SERVER_EPOCH;
+        let state = to_ready(&client, &root_key, state);
+        assert!(state.quota.unwrap().is_near_limit());
+
+        // Close to the quota, we fetch it on every sync, and keep the cached
+        // one if that fails.
+        client.info_quota = Ok(InfoQuota { usage_kb: 100.0, quota_kb: Some(2048.0) });
+        let mut state = to_ready(&client, &root_key, state);
+        assert_eq!(state.quota.unwrap().usage_kb, 100.0);
+
+        client.info_quota = Err(ErrorKind::OverQuota.into());
+        state.quota = Some(InfoQuota { usage_kb: 2000.0, quota_kb: Some(2048.0) });
+        let state = to_ready(&client, &root_key, state);
+        assert_eq!(state.quota.unwrap().usage_kb, 2000.0);
+    }
+
+    #[test]
+    fn test_persisted_state_without_quota() {
+        let state = GlobalState::from_persisted_string(r#"{
+            "schema_version": "V1",
+            "config": {},
+            "collections": {},
+            "global": null,
+            "keys": null,
+            "engine_state_changes": []
+        }"#).unwrap();
+        assert_eq!(state.quota, None);
+        assert_eq!(state.quota_fetched, SERVER_EPOCH);
+    }
+
+    #[test]
+    fn test_declined_engines() {
+        let mut global = mocked_global(STORAGE_VERSION);
+        global.payload.engines.insert(CollectionName::HISTORY, MetaGlobalEngine {
+            version: 1,
+            sync_id: "syncIDCCCCCC".to_owned(),
+        });
+        global.payload.declined = vec![CollectionName::HISTORY, CollectionName::PASSWORDS];
+        let state = GlobalState {
+            global: Some(global.clone()),
+            ..GlobalState::default()
+        };
+        assert!(state.is_engine_enabled("bookmarks"));
+        assert!(state.is_engine_enabled("clients"));
+        assert!(state.is_engine_declined("history"));
+        assert!(!state.is_engine_enabled("history"));
+        assert!(!state.is_engine_enabled("passwords"));
+        assert!(!state.is_engine_enabled("tabs"));
+
+        // A fresh `meta/global` keeps the declined engines, and leaves them
+        // out of `engines`.
+        let fresh = new_global_from_previous(Some(global.clone())).unwrap();
+        assert_eq!(fresh.declined, vec![CollectionName::HISTORY, CollectionName::PASSWORDS]);
+        assert!(fresh.engines.contains_key("bookmarks"));
+        assert!(!fresh.engines.contains_key("history"));
+        assert!(!fresh.engines.contains_key("passwords"));
+
+        // Declining an engine that's in `meta/global` disables it, and
+        // undeclining it enables it again.
+        let mut new_global = global.payload.clone();
+        new_global.declined = vec![CollectionName::BOOKMARKS, CollectionName::PASSWORDS];
+        assert_eq!(engine_state_changes_from_new_global(&global.payload, &new_global), vec![
+            EngineStateChange::Disable(CollectionName::BOOKMARKS),
+            EngineStateChange::Enable(CollectionName::HISTORY),
+        ]);
     }
 }
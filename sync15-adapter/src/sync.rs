@@ -2,12 +2,23 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::collections::VecDeque;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
 use changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset};
 use client::Sync15StorageClient;
+use collection::CollectionName;
 use error;
 use state::GlobalState;
 use util::ServerTimestamp;
 
+/// How many collections `sync_multiple` downloads at once by default. Each
+/// download is a single request, so this mostly bounds how much we ask of the
+/// server (and how many records we hold in memory) at a time.
+pub const DEFAULT_MAX_PARALLEL_DOWNLOADS: usize = 4;
+
 /// Low-level store functionality. Stores that need custom reconciliation logic should use this.
 ///
 /// Different stores will produce errors of different types.  To accommodate this, we can either
@@ -28,19 +39,142 @@ pub trait Store {
         new_timestamp: ServerTimestamp,
         records_synced: &[String],
     ) -> Result<(), Self::Error>;
+
+    /// The collections that must sync before this store's when they're part
+    /// of the same `sync_multiple` call. Data stores should return
+    /// `CollectionName::CLIENTS`, so that commands sent by other clients are
+    /// applied first.
+    fn sync_dependencies(&self) -> Vec<CollectionName> {
+        Vec::new()
+    }
+
+    /// Takes the commands for other stores that this store received while
+    /// syncing, as (collection, command) pairs. Only the clients store has
+    /// any; `sync_multiple` passes them to the targets' `handle_command`
+    /// before they sync.
+    fn take_commands(&mut self) -> Vec<(CollectionName, StoreCommand)> {
+        Vec::new()
+    }
+
+    /// Applies a command sent by another client. The default implementation
+    /// ignores it.
+    fn handle_command(&mut self, command: StoreCommand) -> Result<(), Self::Error> {
+        warn!("Ignoring unsupported command {:?}", command);
+        Ok(())
+    }
+}
+
+/// A command from another client, delivered by the clients store to the
+/// store for a collection. Both make the store's next sync a first sync, so
+/// `sync_multiple` downloads everything for it afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreCommand {
+    /// Delete all of the store's local data, without uploading tombstones
+    /// (`wipeEngine`), and reset it.
+    Wipe,
+    /// Forget what we've synced, so that everything is merged again
+    /// (`resetEngine`).
+    Reset,
 }
 
 pub fn synchronize<E>(client: &Sync15StorageClient,
                    state: &GlobalState,
                    store: &mut Store<Error=E>,
-                   collection: String,
+                   collection: CollectionName,
                    timestamp: ServerTimestamp,
                    fully_atomic: bool) -> Result<(), E>
 where E: From<error::Error>
 {
 
     info!("Syncing collection {}", collection);
-    let incoming_changes = IncomingChangeset::fetch(client, state, collection.clone(), timestamp)?;
+    let incoming_changes = IncomingChangeset::fetch(client, state, collection.into_string(), timestamp)?;
+    apply_and_upload(client, state, store, incoming_changes, fully_atomic)
+}
+
+/// A collection to sync with `sync_multiple`, along with the store that
+/// records for it are applied to, and the time of its last sync.
+pub struct CollectionSync<'a, E: 'a> {
+    pub store: &'a mut Store<Error=E>,
+    pub collection: CollectionName,
+    pub timestamp: ServerTimestamp,
+}
+
+/// Syncs several collections, like calling `synchronize` for each in turn,
+/// but downloading up to `max_parallel_downloads` of them at once. On
+/// high-latency connections the downloads dominate the time a sync takes.
+///
+/// Stores still apply incoming records and upload one at a time, with each
+/// starting as soon as its collection has downloaded. They sync in the order
+/// given, except that a store always syncs after the collections named by its
+/// `sync_dependencies`. As with calling `synchronize` in a loop, we stop at
+/// the first error, and collections before it stay synced.
+///
+/// Commands that a store receives for other collections (see
+/// `Store::take_commands`) are handled by the target stores before they sync.
+/// Since the commands reset the target, we download its collection again from
+/// the start, rather than using the download we started earlier.
+pub fn sync_multiple<E>(client: &Sync15StorageClient,
+                        state: &GlobalState,
+                        collections: &mut [CollectionSync<E>],
+                        max_parallel_downloads: usize,
+                        fully_atomic: bool) -> Result<(), E>
+where E: From<error::Error>
+{
+    let started = Instant::now();
+    let order = sync_order(&collections.iter()
+        .map(|c| (c.collection.clone(), c.store.sync_dependencies()))
+        .collect::<Vec<_>>());
+    let requests = order.iter()
+        .map(|&index| (collections[index].collection.clone(), collections[index].timestamp))
+        .collect();
+    let mut downloads = ParallelDownloads::start(client, state, requests, max_parallel_downloads);
+    let mut reset = vec![false; collections.len()];
+    for (position, &index) in order.iter().enumerate() {
+        let commands = {
+            let c = &mut collections[index];
+            info!("Syncing collection {}", c.collection);
+            let incoming_changes = if reset[index] {
+                info!("Downloading all of {} again, since it was reset", c.collection);
+                IncomingChangeset::fetch(client, state, c.collection.to_string(), ServerTimestamp(0.0))?
+            } else {
+                downloads.wait_for(position)?
+            };
+            apply_and_upload(client, state, &mut *c.store, incoming_changes, fully_atomic)?;
+            c.store.take_commands()
+        };
+        for (collection, command) in commands {
+            let target = match collections.iter().position(|c| c.collection == collection) {
+                Some(target) => target,
+                None => {
+                    info!("Ignoring {:?} command for {}, which isn't syncing", command, collection);
+                    continue;
+                }
+            };
+            if order[..=position].contains(&target) {
+                // Either a store sent itself a command, or a dependency is
+                // missing. Either way, the target has already synced, so the
+                // command will take effect next time.
+                warn!("Handling {:?} command for {}, which already synced", command, collection);
+            } else {
+                reset[target] = true;
+            }
+            info!("Handling {:?} command for {}", command, collection);
+            collections[target].store.handle_command(command)?;
+        }
+    }
+    let elapsed = started.elapsed();
+    info!("Synced {} collections in {}.{:03}s", collections.len(),
+          elapsed.as_secs(), elapsed.subsec_millis());
+    Ok(())
+}
+
+fn apply_and_upload<E>(client: &Sync15StorageClient,
+                       state: &GlobalState,
+                       store: &mut Store<Error=E>,
+                       incoming_changes: IncomingChangeset,
+                       fully_atomic: bool) -> Result<(), E>
+where E: From<error::Error>
+{
     let last_changed_remote = incoming_changes.timestamp;
 
     info!("Downloaded {} remote changes", incoming_changes.changes.len());
@@ -61,3 +195,163 @@ where E: From<error::Error>
     info!("Sync finished!");
     Ok(())
 }
+
+/// Returns the order to sync collections in: the order given, except that
+/// each collection comes after its dependencies. Dependencies that aren't in
+/// `collections` are ignored, and if there's a cycle, the collections in it
+/// keep their order.
+fn sync_order(collections: &[(CollectionName, Vec<CollectionName>)]) -> Vec<usize> {
+    let mut order = Vec::with_capacity(collections.len());
+    let mut done = vec![false; collections.len()];
+    while order.len() < collections.len() {
+        // Take the first collection whose dependencies have all synced.
+        let ready = (0..collections.len()).find(|&index| {
+            !done[index] && collections[index].1.iter().all(|dependency| {
+                collections.iter()
+                    .enumerate()
+                    .all(|(other, c)| done[other] || other == index || c.0 != *dependency)
+            })
+        });
+        let next = match ready {
+            Some(next) => next,
+            None => {
+                let next = done.iter().position(|&d| !d).unwrap();
+                warn!("Collection {} has cyclic dependencies", collections[next].0);
+                next
+            }
+        };
+        done[next] = true;
+        order.push(next);
+    }
+    order
+}
+
+type DownloadRequest = (usize, CollectionName, ServerTimestamp);
+type DownloadResult = (usize, error::Result<IncomingChangeset>);
+
+// Downloads collections on a pool of threads, which take requests off a
+// shared queue. Each thread has its own clone of the client, but they share
+// its token, so we still only fetch one.
+struct ParallelDownloads {
+    queue: Arc<Mutex<VecDeque<DownloadRequest>>>,
+    receiver: mpsc::Receiver<DownloadResult>,
+    // Downloads that finished before we were ready for them, by index.
+    finished: Vec<Option<error::Result<IncomingChangeset>>>,
+}
+
+impl ParallelDownloads {
+    fn start(client: &Sync15StorageClient,
+             state: &GlobalState,
+             requests: Vec<(CollectionName, ServerTimestamp)>,
+             max_parallel: usize) -> Self {
+        let count = requests.len();
+        let queue: VecDeque<DownloadRequest> = requests.into_iter()
+            .enumerate()
+            .map(|(index, (collection, since))| (index, collection, since))
+            .collect();
+        let queue = Arc::new(Mutex::new(queue));
+        let state = Arc::new(state.clone());
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..max_parallel.max(1).min(count) {
+            let client = client.clone();
+            let state = state.clone();
+            let queue = queue.clone();
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().pop_front();
+                let (index, collection, since) = match next {
+                    Some(request) => request,
+                    None => break,
+                };
+                let result = IncomingChangeset::fetch(&client, &state, collection.into_string(), since);
+                if sender.send((index, result)).is_err() {
+                    // We've stopped waiting for downloads.
+                    break;
+                }
+            });
+        }
+        ParallelDownloads {
+            queue,
+            receiver,
+            finished: (0..count).map(|_| None).collect(),
+        }
+    }
+
+    fn wait_for(&mut self, index: usize) -> error::Result<IncomingChangeset> {
+        loop {
+            if let Some(result) = self.finished[index].take() {
+                return result;
+            }
+            // `recv` only fails once every thread has dropped its sender,
+            // which means one of them panicked before sending our result.
+            let (finished_index, result) = self.receiver.recv()
+                .map_err(|_| error::ErrorKind::DownloadThreadFailed)?;
+            self.finished[finished_index] = Some(result);
+        }
+    }
+}
+
+impl Drop for ParallelDownloads {
+    fn drop(&mut self) {
+        // If we stopped early, don't start downloading anything else. Any
+        // downloads in flight finish in the background.
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collections(deps: &[(&str, &[&str])]) -> Vec<(CollectionName, Vec<CollectionName>)> {
+        deps.iter()
+            .map(|&(name, deps)| (name.into(), deps.iter().map(|&d| d.into()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_sync_order() {
+        let c = collections(&[("passwords", &["clients"]), ("history", &["clients"]), ("clients", &[])]);
+        assert_eq!(sync_order(&c), vec![2, 0, 1]);
+
+        // Without dependencies, the order is unchanged.
+        let c = collections(&[("passwords", &[]), ("history", &[]), ("clients", &[])]);
+        assert_eq!(sync_order(&c), vec![0, 1, 2]);
+
+        // Dependencies that aren't syncing don't matter.
+        let c = collections(&[("passwords", &["clients"]), ("history", &[])]);
+        assert_eq!(sync_order(&c), vec![0, 1]);
+
+        // Chains of dependencies.
+        let c = collections(&[("a", &["b"]), ("b", &["c"]), ("c", &[]), ("d", &[])]);
+        assert_eq!(sync_order(&c), vec![2, 1, 0, 3]);
+    }
+
+    #[test]
+    fn test_sync_order_cycle() {
+        let c = collections(&[("a", &["b"]), ("b", &["a"]), ("c", &["a"])]);
+        assert_eq!(sync_order(&c), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_wait_for_failed_thread() {
+        // Dropping the sender without a result is what a panicking download
+        // thread looks like from here.
+        let (sender, receiver) = mpsc::channel::<DownloadResult>();
+        drop(sender);
+        let mut downloads = ParallelDownloads {
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+            receiver,
+            finished: vec![None],
+        };
+        match downloads.wait_for(0) {
+            Err(e) => match e.kind() {
+                error::ErrorKind::DownloadThreadFailed => {}
+                kind => panic!("Unexpected error {:?}", kind),
+            },
+            Ok(_) => panic!("Should fail once every thread is gone"),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Stopping two instances (say, the app and a background worker) from
+//! syncing the same database at once, and interleaving their writes.
+//!
+//! Stores keep a `SyncLock` in their own database, naming the instance
+//! that's syncing, and when it last showed signs of life. An instance takes
+//! the lock with `acquire` before it syncs, `renew`s it in the same write
+//! transaction as each write it makes while syncing, and removes it when it's
+//! done. Taking the lock needs a write transaction too, so that two instances
+//! can't take it at once.
+//!
+//! If an instance dies while it holds the lock, it goes stale after
+//! `SYNC_LOCK_TIMEOUT_MS`, and another can take it. If the first instance
+//! was only stuck, its next `renew` fails, so it stops before writing
+//! anything else.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use error::{ErrorKind, Result};
+use util::random_guid;
+
+/// A lock that hasn't been renewed for this long is assumed to belong to an
+/// instance that died while syncing.
+pub const SYNC_LOCK_TIMEOUT_MS: i64 = 10 * 60 * 1000;
+
+/// Who's syncing, and when they last renewed the lock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncLock {
+    /// The instance ID of the syncing instance, from `new_instance_id`.
+    pub owner: String,
+    /// When the lock was last acquired or renewed, in milliseconds since
+    /// the epoch.
+    pub heartbeat: i64,
+}
+
+/// Makes a random ID for this instance. Instances should make one when they
+/// open their database, and use it for every sync.
+pub fn new_instance_id() -> Result<String> {
+    Ok(random_guid()?)
+}
+
+fn ms_since_epoch(time: SystemTime) -> i64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() as i64 * 1000 + i64::from(since_epoch.subsec_millis())
+}
+
+impl SyncLock {
+    fn new(owner: &str, now: SystemTime) -> SyncLock {
+        SyncLock {
+            owner: owner.to_string(),
+            heartbeat: ms_since_epoch(now),
+        }
+    }
+
+    /// Returns true if the lock hasn't been renewed for longer than
+    /// `SYNC_LOCK_TIMEOUT_MS`. A heartbeat in the future (if the clock went
+    /// back) isn't stale.
+    pub fn is_stale(&self, now: SystemTime) -> bool {
+        ms_since_epoch(now) - self.heartbeat > SYNC_LOCK_TIMEOUT_MS
+    }
+}
+
+/// Returns the lock to store for `owner` to start syncing, given the lock
+/// that's stored now, if any. Fails with `ErrorKind::AlreadySyncing` if
+/// another instance holds a lock that isn't stale.
+pub fn acquire(current: Option<&SyncLock>, owner: &str, now: SystemTime) -> Result<SyncLock> {
+    if let Some(current) = current {
+        if current.owner != owner {
+            if !current.is_stale(now) {
+                return Err(ErrorKind::AlreadySyncing(current.owner.clone()).into());
+            }
+            warn!("Taking stale sync lock from {}", current.owner);
+        }
+    }
+    Ok(SyncLock::new(owner, now))
+}
+
+/// Returns the lock to store for `owner` to keep syncing, given the lock
+/// that's stored now. Fails with `ErrorKind::SyncLockLost` if `owner`
+/// doesn't hold the lock any more, because another instance took it after
+/// it went stale.
+pub fn renew(current: Option<&SyncLock>, owner: &str, now: SystemTime) -> Result<SyncLock> {
+    match current {
+        Some(current) if current.owner == owner => Ok(SyncLock::new(owner, now)),
+        _ => Err(ErrorKind::SyncLockLost.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn lock(owner: &str, heartbeat: i64) -> SyncLock {
+        SyncLock { owner: owner.to_string(), heartbeat }
+    }
+
+    fn at(ms: i64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms as u64)
+    }
+
+    fn is_already_syncing(result: Result<SyncLock>, owner: &str) -> bool {
+        match result.unwrap_err().kind() {
+            ErrorKind::AlreadySyncing(o) => o == owner,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_acquire() {
+        let now = 1_000_000_000;
+        assert_eq!(acquire(None, "a", at(now)).unwrap(), lock("a", now));
+        assert_eq!(acquire(Some(&lock("a", 5)), "a", at(now)).unwrap(), lock("a", now));
+        assert!(is_already_syncing(acquire(Some(&lock("b", now - 1000)), "a", at(now)), "b"));
+        // A lock from the future isn't stale.
+        assert!(is_already_syncing(acquire(Some(&lock("b", now + 1000)), "a", at(now)), "b"));
+        let stale = now - SYNC_LOCK_TIMEOUT_MS - 1;
+        assert_eq!(acquire(Some(&lock("b", stale)), "a", at(now)).unwrap(), lock("a", now));
+    }
+
+    #[test]
+    fn test_renew() {
+        let now = 1_000_000_000;
+        assert_eq!(renew(Some(&lock("a", 5)), "a", at(now)).unwrap(), lock("a", now));
+        for current in &[None, Some(lock("b", now))] {
+            match renew(current.as_ref(), "a", at(now)).unwrap_err().kind() {
+                ErrorKind::SyncLockLost => {}
+                kind => panic!("Unexpected error {:?}", kind),
+            }
+        }
+    }
+}
@@ -4,14 +4,13 @@
 
 use hawk;
 
-use reqwest::{Client, Request, Url};
-use hyper::header::AUTHORIZATION;
+use viaduct::{Request, Url};
 use error::{self, Result, ErrorKind};
 use std::fmt;
 use std::borrow::{Borrow, Cow};
 use std::str::FromStr;
 use std::time::{SystemTime, Duration};
-use std::cell::{RefCell};
+use std::sync::Mutex;
 use util::ServerTimestamp;
 
 /// Tokenserver's timestamp is X-Timestamp and not X-Weave-Timestamp.
@@ -25,8 +24,14 @@ const X_KEY_ID: &str = "X-KeyID";
 
 const RETRY_AFTER_DEFAULT_MS: u64 = 10000;
 
+/// `refresh_if_expiring` fetches a new token if the current one expires
+/// within this long (or within half its lifetime, if that's shorter), so that
+/// it doesn't expire partway through a sync.
+const TOKEN_REFRESH_THRESHOLD_SECS: u64 = 5 * 60;
+
 // The TokenserverToken is the token as received directly from the token server
-// and deserialized from JSON.
+// and deserialized from JSON. `duration` is how long it's valid for, in
+// seconds from when it was issued.
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 struct TokenserverToken {
     id: String,
@@ -47,7 +52,7 @@ struct TokenFetchResult {
 // The trait for fetching tokens - we'll provide a "real" implementation but
 // tests will re-implement it.
 trait TokenFetcher {
-    fn fetch_token(&self, request_client: &Client) -> super::Result<TokenFetchResult>;
+    fn fetch_token(&self) -> super::Result<TokenFetchResult>;
     // We allow the trait to tell us what the time is so tests can get funky.
     fn now(&self) -> SystemTime;
 }
@@ -69,33 +74,30 @@ impl TokenServerFetcher {
 }
 
 impl TokenFetcher for TokenServerFetcher {
-    fn fetch_token(&self, request_client: &Client) -> Result<TokenFetchResult> {
-        let mut resp = request_client.get(self.server_url.clone())
-                                     .header(AUTHORIZATION, format!("Bearer {}", self.access_token))
-                                     .header(X_KEY_ID, self.key_id.clone())
-                                     .send()?;
-
-        if !resp.status().is_success() {
-            warn!("Non-success status when fetching token: {}", resp.status());
+    fn fetch_token(&self) -> Result<TokenFetchResult> {
+        let resp = Request::get(self.server_url.clone())
+                           .header("authorization", format!("Bearer {}", self.access_token))?
+                           .header(X_KEY_ID, self.key_id.clone())?
+                           .send()?;
+
+        if !resp.is_success() {
+            warn!("Non-success status when fetching token: {}", resp.status);
             // TODO: the body should be JSON and contain a status parameter we might need?
-            debug!("  Response body {}", resp.text().unwrap_or_else(|_| "???".into()));
+            debug!("  Response body {}", resp.text());
             // XXX - shouldn't we "chain" these errors - ie, a BackoffError could
             // have a TokenserverHttpError as its cause?
-            if let Some(header) = resp.headers().get(RETRY_AFTER) {
+            if let Some(header) = resp.header(RETRY_AFTER) {
                 // XXX - We are silently dropping parsing errors here.
-                let ms = header.to_str().ok().and_then(|s| s.parse::<f64>().ok())
+                let ms = header.parse::<f64>().ok()
                     .map_or(RETRY_AFTER_DEFAULT_MS, |f| (f * 1000f64) as u64);
                 let when = self.now() + Duration::from_millis(ms);
                 return Err(ErrorKind::BackoffError(when).into());
             }
-            let status = resp.status().as_u16();
-            return Err(ErrorKind::TokenserverHttpError(status).into());
+            return Err(ErrorKind::TokenserverHttpError(resp.status).into());
         }
 
         let token: TokenserverToken = resp.json()?;
-        let server_timestamp = resp.headers()
-                    .get(X_TIMESTAMP)
-                    .and_then(|v| v.to_str().ok())
+        let server_timestamp = resp.header(X_TIMESTAMP)
                     .and_then(|s| ServerTimestamp::from_str(s).ok())
                     .ok_or_else(|| ErrorKind::MissingServerTimestamp)?;
         Ok(TokenFetchResult { token, server_timestamp })
@@ -134,17 +136,25 @@ impl TokenContext {
     }
 
     fn is_valid(&self, now: SystemTime) -> bool {
-        // We could consider making the duration a little shorter - if it
-        // only has 1 second validity there seems a reasonable chance it will
-        // have expired by the time it gets presented to the remote that wants
-        // it.
-        // Either way though, we will eventually need to handle a token being
-        // rejected as a non-fatal error and recover, so maybe we don't care?
+        // A token that's about to expire may still be rejected by the time
+        // it's presented, but syncs call `refresh_if_expiring` before they
+        // start, and requests rejected with a 401 are retried with a new
+        // token, so this only needs to catch the ones that already expired.
         now < self.valid_until
     }
 
+    // Whether the token will expire within `TOKEN_REFRESH_THRESHOLD_SECS`
+    // (or half its lifetime, for short-lived tokens) of `now`.
+    fn expires_soon(&self, now: SystemTime) -> bool {
+        let threshold = Duration::from_secs(TOKEN_REFRESH_THRESHOLD_SECS.min(self.token.duration / 2));
+        match self.valid_until.duration_since(now) {
+            Ok(remaining) => remaining < threshold,
+            Err(_) => true,
+        }
+    }
+
     fn authorization(&self, req: &Request) -> Result<String> {
-        let url = req.url();
+        let url = &req.url;
 
         let path_and_query = match url.query() {
             None => Cow::from(url.path()),
@@ -160,7 +170,7 @@ impl TokenContext {
                 "Storage URL has no port and no default port is known for the protocol".into()))?;
 
         let header = hawk::RequestBuilder::new(
-            req.method().as_ref(),
+            req.method.as_str(),
             host,
             port,
             path_and_query.borrow()
@@ -188,6 +198,9 @@ enum TokenState {
     // api_endpoint changed - we are never going to get a token nor move out
     // of this state.
     NodeReassigned,
+    // We had a token, but it's about to expire, or the storage server
+    // rejected it, so we need a new one. Holds the token's api_endpoint.
+    Expired(String),
 }
 
 /// The generic TokenProvider implementation - long lived and fetches tokens
@@ -195,24 +208,27 @@ enum TokenState {
 #[derive(Debug)]
 struct TokenProviderImpl<TF: TokenFetcher> {
     fetcher: TF,
-    // Our token state (ie, whether we have a token, and if not, why not)
-    current_state: RefCell<TokenState>,
+    // Our token state (ie, whether we have a token, and if not, why not).
+    // This is behind a mutex so that a storage client can be shared between
+    // threads: only one of them fetches a new token, and they all respect
+    // the same backoff.
+    current_state: Mutex<TokenState>,
 }
 
 impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     fn new(fetcher: TF) -> Self {
         TokenProviderImpl {
             fetcher,
-            current_state: RefCell::new(TokenState::NoToken),
+            current_state: Mutex::new(TokenState::NoToken),
         }
     }
 
     // Uses our fetcher to grab a new token and if successfull, derives other
     // info from that token into a usable TokenContext.
-    fn fetch_context(&self, request_client: &Client) -> Result<TokenContext> {
-        let result = self.fetcher.fetch_token(request_client)?;
+    fn fetch_context(&self) -> Result<TokenContext> {
+        let result = self.fetcher.fetch_token()?;
         let token = result.token;
-        let valid_until = SystemTime::now() + Duration::from_secs(token.duration);
+        let valid_until = self.fetcher.now() + Duration::from_secs(token.duration);
 
         let credentials = hawk::Credentials {
             id: token.id.clone(),
@@ -225,8 +241,8 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     // Attempt to fetch a new token and return a new state reflecting that
     // operation. If it worked a TokenState will be returned, but errors may
     // cause other states.
-    fn fetch_token(&self, request_client: &Client, previous_endpoint: Option<&str>) -> TokenState {
-        match self.fetch_context(request_client) {
+    fn fetch_token(&self, previous_endpoint: Option<&str>) -> TokenState {
+        match self.fetch_context() {
             Ok(tc) => {
                 // We got a new token - check that the endpoint is the same
                 // as a previous endpoint we saw (if any)
@@ -259,19 +275,19 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     // Returns None if the current state should be used (eg, if we are
     // holding a token that remains valid) or Some() if the state has changed
     // (which may have changed to a state with a token or an error state)
-    fn advance_state(&self, request_client: &Client, state: &TokenState) -> Option<TokenState> {
+    fn advance_state(&self, state: &TokenState) -> Option<TokenState> {
         match state {
             TokenState::NoToken => {
-                Some(self.fetch_token(request_client, None))
+                Some(self.fetch_token(None))
             },
             TokenState::Failed(_, existing_endpoint) => {
-                Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
+                Some(self.fetch_token(existing_endpoint.as_ref().map(|e| e.as_str())))
             },
             TokenState::Token(existing_context) => {
                 if existing_context.is_valid(self.fetcher.now()) {
                     None
                 } else {
-                    Some(self.fetch_token(request_client, Some(existing_context.token.api_endpoint.as_str())))
+                    Some(self.fetch_token(Some(existing_context.token.api_endpoint.as_str())))
                 }
             },
             TokenState::Backoff(ref until, ref existing_endpoint) => {
@@ -280,23 +296,29 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
                     None
                 } else {
                     // backoff period is over
-                    Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
+                    Some(self.fetch_token(existing_endpoint.as_ref().map(|e| e.as_str())))
                 }
             },
             TokenState::NodeReassigned => {
                 // We never leave this state.
                 None
             }
+            TokenState::Expired(ref existing_endpoint) => {
+                Some(self.fetch_token(Some(existing_endpoint.as_str())))
+            }
         }
     }
 
-    fn with_token<T, F>(&self, request_client: &Client, func: F) -> Result<T>
+    fn with_token<T, F>(&self, func: F) -> Result<T>
             where F: FnOnce(&TokenContext) -> Result<T> {
 
         // first get a mutable ref to our existing state, advance to the
-        // state we will use, then re-stash that state for next time.
-        let state: &mut TokenState = &mut self.current_state.borrow_mut();
-        match self.advance_state(request_client, state) {
+        // state we will use, then re-stash that state for next time. We hold
+        // the lock for the whole call, so other threads wait for us to fetch
+        // a token rather than fetching their own.
+        let mut guard = self.current_state.lock().unwrap();
+        let state: &mut TokenState = &mut guard;
+        match self.advance_state(state) {
             Some(new_state) => *state = new_state,
             None => ()
         }
@@ -304,9 +326,9 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         // Now re-fetch the state we should use for this call - if it's
         // anything other than TokenState::Token we will fail.
         match state {
-            TokenState::NoToken => {
+            TokenState::NoToken | TokenState::Expired(_) => {
                 // it should be impossible to get here.
-                panic!("Can't be in NoToken state after advancing");
+                panic!("Can't be in NoToken or Expired state after advancing");
             }
             TokenState::Token(ref token_context) => {
                 // make the call.
@@ -326,16 +348,39 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         }
     }
 
-    fn authorization(&self, http_client: &Client, req: &Request) -> Result<String> {
-        self.with_token(http_client, |ctx| ctx.authorization(req))
+    fn authorization(&self, req: &Request) -> Result<String> {
+        self.with_token(|ctx| ctx.authorization(req))
+    }
+
+    fn api_endpoint(&self) -> Result<String> {
+        self.with_token(|ctx| Ok(ctx.token.api_endpoint.clone()))
+    }
+
+    // Forgets the current token, if we have one, so that the next request
+    // fetches a new one.
+    fn drop_token(&self) {
+        let mut guard = self.current_state.lock().unwrap();
+        let endpoint = match *guard {
+            TokenState::Token(ref ctx) => ctx.token.api_endpoint.clone(),
+            _ => return,
+        };
+        *guard = TokenState::Expired(endpoint);
     }
 
-    fn api_endpoint(&self, http_client: &Client) -> Result<String> {
-        self.with_token(http_client, |ctx| Ok(ctx.token.api_endpoint.clone()))
+    fn refresh_if_expiring(&self) -> Result<()> {
+        {
+            let mut guard = self.current_state.lock().unwrap();
+            let endpoint = match *guard {
+                TokenState::Token(ref ctx) if ctx.expires_soon(self.fetcher.now()) => {
+                    ctx.token.api_endpoint.clone()
+                }
+                _ => return Ok(()),
+            };
+            info!("Token expires soon, fetching a new one");
+            *guard = TokenState::Expired(endpoint);
+        }
+        self.with_token(|_| Ok(()))
     }
-    // TODO: we probably want a "drop_token/context" type method so that when
-    // using a token with some validity fails the caller can force a new one
-    // (in which case the new token request will probably fail with a 401)
 }
 
 // The public concrete object exposed by this module
@@ -352,12 +397,25 @@ impl TokenProvider {
         }
     }
 
-    pub fn authorization(&self, http_client: &Client, req: &Request) -> Result<String> {
-        self.imp.authorization(http_client, req)
+    pub fn authorization(&self, req: &Request) -> Result<String> {
+        self.imp.authorization(req)
+    }
+
+    pub fn api_endpoint(&self) -> Result<String> {
+        self.imp.api_endpoint()
+    }
+
+    /// Forgets the current token, for when the storage server rejects it.
+    /// The next request fetches a new one.
+    pub fn drop_token(&self) {
+        self.imp.drop_token()
     }
 
-    pub fn api_endpoint(&self, http_client: &Client) -> Result<String> {
-        self.imp.api_endpoint(http_client)
+    /// Fetches a new token if we have one that's about to expire. Doesn't do
+    /// anything if we don't have a token yet, since the first request will
+    /// fetch one anyway.
+    pub fn refresh_if_expiring(&self) -> Result<()> {
+        self.imp.refresh_if_expiring()
     }
 }
 
@@ -365,11 +423,6 @@ impl TokenProvider {
 mod tests {
     use super::*;
     use std::cell::Cell;
-    use reqwest::Client;
-
-    fn make_client() -> Client {
-        Client::builder().timeout(Duration::from_secs(30)).build().expect("can't build client")
-    }
 
     struct TestFetcher<FF, FN>
         where FF: Fn() -> Result<TokenFetchResult>,
@@ -380,7 +433,7 @@ mod tests {
     impl<FF, FN> TokenFetcher for TestFetcher<FF, FN>
         where FF: Fn() -> Result<TokenFetchResult>,
               FN: Fn() -> SystemTime {
-        fn fetch_token(&self, _: &Client) -> Result<TokenFetchResult> {
+        fn fetch_token(&self) -> Result<TokenFetchResult> {
             (self.fetch)()
         }
         fn now(&self) -> SystemTime {
@@ -419,11 +472,11 @@ mod tests {
 
         let tsc = make_tsc(fetch, || {SystemTime::now()});
 
-        let e = tsc.api_endpoint(&make_client()).expect("should work");
+        let e = tsc.api_endpoint().expect("should work");
         assert_eq!(e, "api_endpoint".to_string());
         assert_eq!(counter.get(), 1);
 
-        let e2 = tsc.api_endpoint(&make_client()).expect("should work");
+        let e2 = tsc.api_endpoint().expect("should work");
         assert_eq!(e2, "api_endpoint".to_string());
         // should not have re-fetched.
         assert_eq!(counter.get(), 1);
@@ -440,12 +493,12 @@ mod tests {
         let now: Cell<SystemTime> = Cell::new(SystemTime::now());
         let tsc = make_tsc(fetch, || {now.get()});
 
-        tsc.api_endpoint(&make_client()).expect_err("should bail");
+        tsc.api_endpoint().expect_err("should bail");
         // XXX - check error type.
         assert_eq!(counter.get(), 1);
         // try and get another token - should not re-fetch as backoff is still
         // in progress.
-        tsc.api_endpoint(&make_client()).expect_err("should bail");
+        tsc.api_endpoint().expect_err("should bail");
         assert_eq!(counter.get(), 1);
 
         // Advance the clock.
@@ -453,7 +506,7 @@ mod tests {
 
         // Our token fetch mock is still returning a backoff error, so we
         // still fail, but should have re-hit the fetch function.
-        tsc.api_endpoint(&make_client()).expect_err("should bail");
+        tsc.api_endpoint().expect_err("should bail");
         assert_eq!(counter.get(), 2);
     }
 
@@ -477,19 +530,65 @@ mod tests {
         let now: Cell<SystemTime> = Cell::new(SystemTime::now());
         let tsc = make_tsc(fetch, || {now.get()});
 
-        tsc.api_endpoint(&make_client()).expect("should get a valid token");
+        tsc.api_endpoint().expect("should get a valid token");
         assert_eq!(counter.get(), 1);
 
         // try and get another token - should not re-fetch as the old one
         // remains valid.
-        tsc.api_endpoint(&make_client()).expect("should reuse existing token");
+        tsc.api_endpoint().expect("should reuse existing token");
         assert_eq!(counter.get(), 1);
 
         // Advance the clock.
         now.set(now.get() + Duration::new(20, 0));
 
         // We should discard our token and fetch a new one.
-        tsc.api_endpoint(&make_client()).expect("should re-fetch");
+        tsc.api_endpoint().expect("should re-fetch");
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test]
+    fn test_refresh() {
+        let counter: Cell<u32> = Cell::new(0);
+        let fetch = || {
+            counter.set(counter.get() + 1);
+            Ok(TokenFetchResult {
+                token: TokenserverToken {
+                    id: "id".to_string(),
+                    key: "key".to_string(),
+                    api_endpoint: "api_endpoint".to_string(),
+                    uid: 1,
+                    duration: 3600,
+                    hashed_fxa_uid: "hash".to_string(),
+                },
+                server_timestamp: ServerTimestamp(0f64),
+            })
+        };
+        let now: Cell<SystemTime> = Cell::new(SystemTime::now());
+        let tsc = make_tsc(fetch, || {now.get()});
+
+        // Nothing to refresh before we have a token.
+        tsc.refresh_if_expiring().expect("should do nothing");
+        assert_eq!(counter.get(), 0);
+
+        tsc.api_endpoint().expect("should get a valid token");
+        assert_eq!(counter.get(), 1);
+        tsc.refresh_if_expiring().expect("should keep a fresh token");
+        assert_eq!(counter.get(), 1);
+
+        // With less than 5 minutes left, the token is still valid, but we
+        // fetch a new one before starting a sync.
+        now.set(now.get() + Duration::from_secs(3600 - 60));
+        tsc.api_endpoint().expect("should still be valid");
+        assert_eq!(counter.get(), 1);
+        tsc.refresh_if_expiring().expect("should refresh");
+        assert_eq!(counter.get(), 2);
+        tsc.api_endpoint().expect("should use the new token");
         assert_eq!(counter.get(), 2);
+
+        // Dropping the token, as we do when the storage server rejects it,
+        // fetches a new one for the next request.
+        tsc.drop_token();
+        tsc.api_endpoint().expect("should re-fetch");
+        assert_eq!(counter.get(), 3);
     }
 }
@@ -0,0 +1,200 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An opt-in trace of the decisions stores make when reconciling incoming
+//! records with local ones, for debugging reports like "my password
+//! reverted", which the logs we normally keep don't help with.
+//!
+//! When the trace is enabled (see `enable_reconcile_trace`), stores call
+//! `trace_reconcile` for each record they reconcile, and the most recent
+//! decisions are kept in memory until the application asks for them with
+//! `reconcile_trace`, typically to attach to a bug report.
+//!
+//! The trace is designed so that it can't leak anything sensitive:
+//! `trace_reconcile` never sees record contents, only the id, timestamps and
+//! a reason code, and ids are stored as a truncated hash. The same id always
+//! hashes the same way, so decisions about one record can still be correlated
+//! across syncs.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base16;
+use openssl::sha::sha256;
+
+use util::ServerTimestamp;
+
+/// Which side's data ended up in the local store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReconcileWinner {
+    /// The local record was kept, and will be uploaded if it changed.
+    Local,
+    /// The incoming record replaced the local one (or was applied as is,
+    /// when there wasn't one).
+    Remote,
+    /// Changes from both sides were merged.
+    Merged,
+}
+
+/// One reconciliation decision, as recorded by `trace_reconcile`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileDecision {
+    pub collection: String,
+    /// The first 16 hex digits of the SHA-256 of the record id.
+    pub redacted_id: String,
+    pub winner: ReconcileWinner,
+    /// A short, store-specific code saying why, like `"three_way_merge"`.
+    pub reason: &'static str,
+    /// When the local record was last changed, in milliseconds since the
+    /// epoch, if the store knows.
+    pub local_modified_ms: Option<u64>,
+    /// The server's modification time for the incoming record, in
+    /// milliseconds.
+    pub remote_modified_ms: Option<u64>,
+    /// When the decision was made, in milliseconds since the epoch.
+    pub traced_at_ms: u64,
+}
+
+struct TraceBuffer {
+    capacity: usize,
+    decisions: VecDeque<ReconcileDecision>,
+}
+
+// Checked before taking the lock, so that tracing costs next to nothing while
+// it's disabled, which is almost always.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref TRACE: Mutex<TraceBuffer> = Mutex::new(TraceBuffer {
+        capacity: 0,
+        decisions: VecDeque::new(),
+    });
+}
+
+/// Starts tracing reconciliation decisions, keeping the most recent
+/// `capacity` of them. If the trace is already enabled, this changes its
+/// capacity, dropping the oldest decisions if there are too many. A capacity
+/// of zero disables it.
+pub fn enable_reconcile_trace(capacity: usize) {
+    let mut trace = TRACE.lock().unwrap();
+    trace.capacity = capacity;
+    while trace.decisions.len() > capacity {
+        trace.decisions.pop_front();
+    }
+    ENABLED.store(capacity > 0, Ordering::SeqCst);
+}
+
+/// Stops tracing, and discards any decisions traced so far.
+pub fn disable_reconcile_trace() {
+    let mut trace = TRACE.lock().unwrap();
+    ENABLED.store(false, Ordering::SeqCst);
+    trace.capacity = 0;
+    trace.decisions.clear();
+}
+
+#[inline]
+pub fn is_reconcile_trace_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Records a reconciliation decision, if tracing is enabled. There's
+/// deliberately no way to pass the record's contents.
+pub fn trace_reconcile(
+    collection: &str,
+    id: &str,
+    winner: ReconcileWinner,
+    reason: &'static str,
+    local_modified: Option<SystemTime>,
+    remote_modified: Option<ServerTimestamp>,
+) {
+    if !is_reconcile_trace_enabled() {
+        return;
+    }
+    let decision = ReconcileDecision {
+        collection: collection.to_string(),
+        redacted_id: redact_id(id),
+        winner,
+        reason,
+        local_modified_ms: local_modified.map(system_time_ms),
+        remote_modified_ms: remote_modified.map(ServerTimestamp::as_millis),
+        traced_at_ms: system_time_ms(SystemTime::now()),
+    };
+    let mut trace = TRACE.lock().unwrap();
+    // Check again, in case it was disabled while we weren't holding the lock.
+    if trace.capacity == 0 {
+        return;
+    }
+    if trace.decisions.len() == trace.capacity {
+        trace.decisions.pop_front();
+    }
+    trace.decisions.push_back(decision);
+}
+
+/// Returns the traced decisions, oldest first. They're kept until the trace
+/// is disabled, or they're pushed out by newer ones.
+pub fn reconcile_trace() -> Vec<ReconcileDecision> {
+    TRACE.lock().unwrap().decisions.iter().cloned().collect()
+}
+
+fn redact_id(id: &str) -> String {
+    base16::encode_lower(&sha256(id.as_bytes())[..8])
+}
+
+fn system_time_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The trace is global, so this is all one test to keep the test harness's
+    // threads from racing each other.
+    #[test]
+    fn test_reconcile_trace() {
+        trace_reconcile("passwords", "aaaaaaaaaaaa", ReconcileWinner::Remote, "remote_deletion",
+                        None, None);
+        assert!(reconcile_trace().is_empty());
+
+        enable_reconcile_trace(2);
+        trace_reconcile("passwords", "aaaaaaaaaaaa", ReconcileWinner::Local, "excluded",
+                        Some(UNIX_EPOCH), Some(ServerTimestamp(1.5)));
+        let trace = reconcile_trace();
+        assert_eq!(trace.len(), 1);
+        assert_eq!(trace[0].collection, "passwords");
+        assert_eq!(trace[0].redacted_id.len(), 16);
+        assert!(!trace[0].redacted_id.contains("aaaa"));
+        assert_eq!(trace[0].winner, ReconcileWinner::Local);
+        assert_eq!(trace[0].local_modified_ms, Some(0));
+        assert_eq!(trace[0].remote_modified_ms, Some(1500));
+
+        // The same id is redacted the same way, and only the newest
+        // decisions are kept.
+        trace_reconcile("passwords", "bbbbbbbbbbbb", ReconcileWinner::Merged, "three_way_merge",
+                        None, None);
+        trace_reconcile("passwords", "aaaaaaaaaaaa", ReconcileWinner::Remote, "newer_remote",
+                        None, None);
+        let newer = reconcile_trace();
+        assert_eq!(newer.len(), 2);
+        assert_eq!(newer[0].reason, "three_way_merge");
+        assert_eq!(newer[1].redacted_id, trace[0].redacted_id);
+
+        let json = ::serde_json::to_string(&newer).unwrap();
+        assert!(json.contains("\"winner\":\"merged\""));
+        assert!(json.contains("\"redactedId\""));
+
+        enable_reconcile_trace(1);
+        assert_eq!(reconcile_trace()[0].reason, "newer_remote");
+
+        disable_reconcile_trace();
+        assert!(reconcile_trace().is_empty());
+        assert!(!is_reconcile_trace_enabled());
+    }
+}
@@ -3,7 +3,8 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::convert::From;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fmt, num};
 use std::str::FromStr;
 use openssl;
@@ -39,7 +40,6 @@ impl From<f64> for ServerTimestamp {
     }
 }
 
-// This lets us use these in hyper header! blocks.
 impl FromStr for ServerTimestamp {
     type Err = num::ParseFloatError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -78,6 +78,69 @@ impl ServerTimestamp {
     pub fn as_millis(self) -> u64 {
         (self.0 * 1000.0).floor() as u64
     }
+
+    /// Our best guess at what the server's clock currently reads. This is the
+    /// local clock, corrected by the skew we observed on the most recent
+    /// storage server response. Before we've seen any response, it's just the
+    /// local clock.
+    ///
+    /// Prefer this over `SystemTime::now()` whenever the result is compared
+    /// against (or sent to the server alongside) server timestamps.
+    pub fn now_estimate() -> ServerTimestamp {
+        CLOCK_SKEW.lock().unwrap().estimate(SystemTime::now())
+    }
+}
+
+lazy_static! {
+    static ref CLOCK_SKEW: Mutex<ClockSkew> = Mutex::new(ClockSkew::default());
+}
+
+/// Records the `X-Weave-Timestamp` from a server response, updating the skew
+/// used by `ServerTimestamp::now_estimate`.
+pub(crate) fn record_server_time(server_time: ServerTimestamp) {
+    let mut skew = CLOCK_SKEW.lock().unwrap();
+    skew.record(server_time, SystemTime::now());
+    if skew.secs.abs() >= 60.0 {
+        // Not a problem as such, but worth knowing about if we see weird
+        // behavior from `newer` queries or TTLs.
+        info!("Local clock is {:.2}s off from the storage server", skew.secs);
+    }
+}
+
+/// The difference between the server's clock and ours, in seconds. Positive
+/// if the server is ahead of us.
+///
+/// Note that this includes the time it took for the response to reach us, so
+/// it will be off by a little, but it's far better than trusting a local clock
+/// which may be wrong by hours.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct ClockSkew {
+    secs: f64,
+}
+
+impl ClockSkew {
+    fn record(&mut self, server_time: ServerTimestamp, local_time: SystemTime) {
+        self.secs = server_time.0 - system_time_secs(local_time);
+    }
+
+    fn estimate(&self, local_time: SystemTime) -> ServerTimestamp {
+        let secs = system_time_secs(local_time) + self.secs;
+        // The server formats its timestamps to the hundredths place, so we
+        // do too. This keeps them sensible when used in `newer` queries.
+        ServerTimestamp(((secs * 100.0).round() / 100.0).max(0.0))
+    }
+}
+
+fn system_time_secs(t: SystemTime) -> f64 {
+    // A local clock that's before the epoch is *very* skewed, but that's
+    // exactly the case we're trying to correct for, so don't panic.
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0,
+        Err(e) => {
+            let d = e.duration();
+            -(d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0)
+        }
+    }
 }
 
 #[cfg(test)]
@@ -96,6 +159,32 @@ mod test {
         assert_eq!(dur.subsec_nanos(), 100_000_000);
     }
 
+    #[test]
+    fn test_clock_skew() {
+        let local = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut skew = ClockSkew::default();
+        assert_eq!(skew.estimate(local), ServerTimestamp(1_000_000.0));
+
+        // Server is ahead of us.
+        skew.record(ServerTimestamp(1_000_100.25), local);
+        assert_eq!(skew.estimate(local), ServerTimestamp(1_000_100.25));
+        let later = local + Duration::from_millis(1500);
+        assert_eq!(skew.estimate(later), ServerTimestamp(1_000_101.75));
+
+        // Server is behind us, and the latest observation wins.
+        skew.record(ServerTimestamp(999_000.0), later);
+        assert_eq!(skew.estimate(later + Duration::from_secs(10)), ServerTimestamp(999_010.0));
+    }
+
+    #[test]
+    fn test_clock_skew_before_epoch() {
+        let local = UNIX_EPOCH - Duration::from_secs(500);
+        let mut skew = ClockSkew::default();
+        assert_eq!(skew.estimate(local), ServerTimestamp(0.0));
+        skew.record(ServerTimestamp(1000.0), local);
+        assert_eq!(skew.estimate(local + Duration::from_secs(5)), ServerTimestamp(1005.0));
+    }
+
     #[test]
     fn test_gen_guid() {
         let mut set = HashSet::new();
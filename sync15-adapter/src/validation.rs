@@ -0,0 +1,296 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Comparing a store's local records with the ones on the server, to measure
+//! how consistent synced data is, like desktop's sync validators. Nothing is
+//! changed on either side; the result is a `ValidationReport` for telemetry.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use serde_json::Value as JsonValue;
+
+use bso_record::Payload;
+use changeset::IncomingChangeset;
+use client::Sync15StorageClient;
+use collection::CollectionName;
+use error;
+use state::GlobalState;
+use util::ServerTimestamp;
+
+/// The records a store has locally, for `validate` to compare with the
+/// server's.
+#[derive(Debug, Clone, Default)]
+pub struct LocalRecords {
+    /// The live records that should be on the server, as we'd upload them.
+    pub records: Vec<Payload>,
+    /// Ids whose local and server records are expected to differ, and so
+    /// aren't compared: records with changes we haven't uploaded yet
+    /// (including deletions), records that aren't synced, and so on.
+    pub skip_ids: HashSet<String>,
+}
+
+/// A store that can be checked with `validate`. This is separate from
+/// `Store`, since most stores don't support it yet.
+pub trait ValidatableStore {
+    type Error;
+
+    fn local_records_for_validation(&self) -> Result<LocalRecords, Self::Error>;
+
+    /// Converts a record from the server into the form we'd upload it in, so
+    /// that it can be compared with ours. Stores that fix up incoming records
+    /// (canonicalizing URLs, say) should do the same here, since otherwise
+    /// every record they fixed up would differ. If this fails, the record is
+    /// counted as invalid. The default implementation returns it unchanged.
+    fn normalize_for_validation(&self, payload: Payload) -> Result<Payload, Self::Error> {
+        Ok(payload)
+    }
+}
+
+/// A record that's both local and on the server, but with different
+/// contents.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordDifference {
+    pub id: String,
+    /// The names of the fields that differ, sorted. A field that's null on
+    /// one side and missing on the other doesn't count.
+    pub fields: Vec<String>,
+}
+
+/// The count of one kind of problem, for the `problems` array of the
+/// validation section of the sync ping.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ValidationProblem {
+    pub name: &'static str,
+    pub count: usize,
+}
+
+/// What `validate` found. The lists of ids are sorted. They identify the
+/// user's records, so only `problems` should be sent in telemetry.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationReport {
+    pub collection: String,
+    /// The number of records we compared, on either side.
+    pub checked: usize,
+    /// The number of ids we didn't compare (see `LocalRecords::skip_ids`).
+    pub skipped: usize,
+    /// Records on the server that we don't have locally.
+    pub client_missing: Vec<String>,
+    /// Local records that aren't on the server.
+    pub server_missing: Vec<String>,
+    /// Local records that have a tombstone on the server.
+    pub server_deleted: Vec<String>,
+    /// Server records that `ValidatableStore::normalize_for_validation`
+    /// couldn't handle.
+    pub server_invalid: Vec<String>,
+    pub differences: Vec<RecordDifference>,
+    /// How long validating took, in milliseconds, including the download.
+    pub took_ms: u64,
+}
+
+impl ValidationReport {
+    /// True if local and server records all match.
+    pub fn is_ok(&self) -> bool {
+        self.problems().is_empty()
+    }
+
+    /// The number of each kind of problem found, leaving out kinds with
+    /// none. The names match desktop's, where it has the same kind.
+    pub fn problems(&self) -> Vec<ValidationProblem> {
+        let counts = [
+            ("clientMissing", self.client_missing.len()),
+            ("serverMissing", self.server_missing.len()),
+            ("serverDeleted", self.server_deleted.len()),
+            ("serverInvalid", self.server_invalid.len()),
+            ("differences", self.differences.len()),
+        ];
+        counts.iter()
+            .filter(|&&(_, count)| count > 0)
+            .map(|&(name, count)| ValidationProblem { name, count })
+            .collect()
+    }
+}
+
+/// Downloads all of `collection` and compares it with `store`'s local
+/// records. This is most useful right after a successful sync, when the two
+/// should match.
+pub fn validate<E>(client: &Sync15StorageClient,
+                   state: &GlobalState,
+                   store: &ValidatableStore<Error=E>,
+                   collection: CollectionName) -> Result<ValidationReport, E>
+where E: From<error::Error>
+{
+    let started = Instant::now();
+    info!("Validating collection {}", collection);
+    let server = IncomingChangeset::fetch(client, state, collection.to_string(), ServerTimestamp(0.0))?;
+    let local = store.local_records_for_validation()?;
+    let mut report = compare(store, collection, server.changes.into_iter().map(|(p, _)| p), local);
+    let elapsed = started.elapsed();
+    report.took_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+    info!("Validated {} records in {}: {:?}", report.checked, report.collection, report.problems());
+    Ok(report)
+}
+
+fn compare<E, I>(store: &ValidatableStore<Error=E>,
+                 collection: CollectionName,
+                 server: I,
+                 local: LocalRecords) -> ValidationReport
+where I: Iterator<Item=Payload>
+{
+    let mut report = ValidationReport {
+        collection: collection.into_string(),
+        .. ValidationReport::default()
+    };
+    let skip_ids = local.skip_ids;
+    let mut local_by_id: HashMap<String, Payload> = local.records.into_iter()
+        .filter(|p| !skip_ids.contains(&p.id))
+        .map(|p| (p.id.clone(), p))
+        .collect();
+    report.checked = local_by_id.len();
+    report.skipped = skip_ids.len();
+
+    for payload in server {
+        if skip_ids.contains(&payload.id) {
+            continue;
+        }
+        if payload.is_tombstone() {
+            if local_by_id.remove(&payload.id).is_some() {
+                report.server_deleted.push(payload.id);
+            }
+            continue;
+        }
+        let id = payload.id.clone();
+        let local = local_by_id.remove(&id);
+        if local.is_none() {
+            report.checked += 1;
+        }
+        let server = match store.normalize_for_validation(payload) {
+            Ok(server) => server,
+            Err(_) => {
+                warn!("Couldn't normalize server record {} for validation", id);
+                report.server_invalid.push(id);
+                continue;
+            }
+        };
+        match local {
+            None => report.client_missing.push(id),
+            Some(local) => {
+                let fields = differing_fields(&local, &server);
+                if !fields.is_empty() {
+                    report.differences.push(RecordDifference { id, fields });
+                }
+            }
+        }
+    }
+    report.server_missing.extend(local_by_id.into_iter().map(|(id, _)| id));
+
+    report.client_missing.sort();
+    report.server_missing.sort();
+    report.server_deleted.sort();
+    report.server_invalid.sort();
+    report.differences.sort_by(|a, b| a.id.cmp(&b.id));
+    report
+}
+
+fn differing_fields(local: &Payload, server: &Payload) -> Vec<String> {
+    let mut fields: Vec<String> = local.data.keys()
+        .chain(server.data.keys().filter(|key| !local.data.contains_key(*key)))
+        .filter(|key| {
+            let local_value = local.data.get(*key).unwrap_or(&JsonValue::Null);
+            let server_value = server.data.get(*key).unwrap_or(&JsonValue::Null);
+            local_value != server_value
+        })
+        .cloned()
+        .collect();
+    fields.sort();
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestStore;
+
+    impl ValidatableStore for TestStore {
+        type Error = String;
+
+        fn local_records_for_validation(&self) -> Result<LocalRecords, String> {
+            unreachable!()
+        }
+
+        fn normalize_for_validation(&self, payload: Payload) -> Result<Payload, String> {
+            if payload.data.contains_key("bad") {
+                return Err("bad record".into());
+            }
+            Ok(payload)
+        }
+    }
+
+    fn payload(value: JsonValue) -> Payload {
+        Payload::from_json(value).unwrap()
+    }
+
+    #[test]
+    fn test_compare() {
+        let local = LocalRecords {
+            records: vec![
+                payload(json!({"id": "same", "a": 1, "b": null})),
+                payload(json!({"id": "changed", "a": 1, "b": "x", "c": true})),
+                payload(json!({"id": "localonly", "a": 1})),
+                payload(json!({"id": "deleted", "a": 1})),
+                payload(json!({"id": "pending", "a": 1})),
+            ],
+            skip_ids: vec!["pending".to_string(), "remapped".to_string()].into_iter().collect(),
+        };
+        let server = vec![
+            payload(json!({"id": "same", "a": 1})),
+            payload(json!({"id": "changed", "a": 2, "c": true, "d": 4})),
+            payload(json!({"id": "serveronly", "a": 1})),
+            payload(json!({"id": "deleted", "deleted": true})),
+            payload(json!({"id": "gone", "deleted": true})),
+            payload(json!({"id": "pending", "a": 2})),
+            payload(json!({"id": "remapped", "a": 2})),
+            payload(json!({"id": "invalid", "bad": true})),
+        ];
+        let report = compare(&TestStore, CollectionName::PASSWORDS, server.into_iter(), local);
+        assert_eq!(report, ValidationReport {
+            collection: "passwords".into(),
+            checked: 6,
+            skipped: 2,
+            client_missing: vec!["serveronly".into()],
+            server_missing: vec!["localonly".into()],
+            server_deleted: vec!["deleted".into()],
+            server_invalid: vec!["invalid".into()],
+            differences: vec![RecordDifference {
+                id: "changed".into(),
+                fields: vec!["a".into(), "b".into(), "d".into()],
+            }],
+            took_ms: 0,
+        });
+        assert!(!report.is_ok());
+        assert_eq!(report.problems(), vec![
+            ValidationProblem { name: "clientMissing", count: 1 },
+            ValidationProblem { name: "serverMissing", count: 1 },
+            ValidationProblem { name: "serverDeleted", count: 1 },
+            ValidationProblem { name: "serverInvalid", count: 1 },
+            ValidationProblem { name: "differences", count: 1 },
+        ]);
+    }
+
+    #[test]
+    fn test_compare_matching() {
+        let local = LocalRecords {
+            records: vec![payload(json!({"id": "aaaa", "a": [1, 2]}))],
+            skip_ids: HashSet::new(),
+        };
+        let server = vec![payload(json!({"id": "aaaa", "a": [1, 2]}))];
+        let report = compare(&TestStore, CollectionName::PASSWORDS, server.into_iter(), local);
+        assert!(report.is_ok());
+        assert_eq!(report.checked, 1);
+        assert!(report.problems().is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! End-to-end tests that sync against a self-hosted server. These need the
+//! server in `integration/` to be running, so they're ignored by default; see
+//! `integration/README.md` for how to run them.
+
+extern crate sync15_adapter as sync;
+extern crate url;
+#[macro_use]
+extern crate serde_json;
+extern crate env_logger;
+
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::mem;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sync::{
+    CollectionSync, GlobalState, IncomingChangeset, KeyBundle, OutgoingChangeset, Payload,
+    ServerTimestamp, SetupStateMachine, Store, Sync15StorageClient, Sync15StorageClientInit,
+};
+use url::Url;
+
+const DEFAULT_TOKENSERVER_URL: &str = "http://localhost:5000/";
+const COLLECTION: &str = "passwords";
+
+/// A store that keeps `{"id": ..., "value": ...}` records in memory. Local
+/// changes always win over incoming ones.
+struct MemoryStore {
+    records: HashMap<String, Payload>,
+    // Ids of records changed or deleted since the last sync.
+    changed: HashSet<String>,
+    last_sync: ServerTimestamp,
+}
+
+impl MemoryStore {
+    fn new() -> Self {
+        MemoryStore {
+            records: HashMap::new(),
+            changed: HashSet::new(),
+            last_sync: ServerTimestamp(0.0),
+        }
+    }
+
+    fn insert(&mut self, id: &str, value: JsonValue) {
+        let payload = Payload::from_json(json!({ "id": id, "value": value })).unwrap();
+        self.records.insert(id.to_string(), payload);
+        self.changed.insert(id.to_string());
+    }
+
+    fn delete(&mut self, id: &str) {
+        self.records.remove(id);
+        self.changed.insert(id.to_string());
+    }
+
+    fn values(&self) -> BTreeMap<String, JsonValue> {
+        self.records
+            .iter()
+            .map(|(id, payload)| (id.clone(), payload.data["value"].clone()))
+            .collect()
+    }
+}
+
+impl Store for MemoryStore {
+    type Error = sync::Error;
+
+    fn apply_incoming(&mut self, inbound: IncomingChangeset) -> sync::Result<OutgoingChangeset> {
+        for (payload, _) in inbound.changes {
+            if self.changed.contains(payload.id()) {
+                continue;
+            }
+            if payload.is_tombstone() {
+                self.records.remove(payload.id());
+            } else {
+                self.records.insert(payload.id().to_string(), payload);
+            }
+        }
+        let mut outgoing = OutgoingChangeset::new(inbound.collection, inbound.timestamp);
+        outgoing.changes = self
+            .changed
+            .iter()
+            .map(|id| match self.records.get(id) {
+                Some(payload) => payload.clone(),
+                None => Payload::new_tombstone(id.clone()),
+            })
+            .collect();
+        Ok(outgoing)
+    }
+
+    fn sync_finished(
+        &mut self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> sync::Result<()> {
+        for id in records_synced {
+            self.changed.remove(id);
+        }
+        self.last_sync = new_timestamp;
+        Ok(())
+    }
+}
+
+/// A fresh account on the test server. The fake tokenserver gives each
+/// access token its own user, so we make one up.
+struct TestAccount {
+    init: Sync15StorageClientInit,
+    root_key: KeyBundle,
+}
+
+impl TestAccount {
+    fn new(name: &str) -> Self {
+        let _ = env_logger::try_init();
+        let tokenserver_url = env::var("SYNC15_TEST_TOKENSERVER_URL")
+            .unwrap_or_else(|_| DEFAULT_TOKENSERVER_URL.to_string());
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        TestAccount {
+            init: Sync15StorageClientInit {
+                key_id: "1234-test".into(),
+                access_token: format!(
+                    "{}-{}.{}",
+                    name,
+                    since_epoch.as_secs(),
+                    since_epoch.subsec_nanos()
+                ),
+                tokenserver_url: Url::parse(&tokenserver_url).unwrap(),
+            },
+            root_key: KeyBundle::new_random().unwrap(),
+        }
+    }
+
+    /// Returns a new client (as if on another device) for this account.
+    fn client(&self) -> TestClient {
+        TestClient {
+            client: Sync15StorageClient::new(self.init.clone()).unwrap(),
+            root_key: self.root_key.clone(),
+            state: GlobalState::default(),
+            store: MemoryStore::new(),
+        }
+    }
+}
+
+struct TestClient {
+    client: Sync15StorageClient,
+    root_key: KeyBundle,
+    state: GlobalState,
+    store: MemoryStore,
+}
+
+impl TestClient {
+    fn ready(&mut self) {
+        let state = mem::replace(&mut self.state, GlobalState::default());
+        let mut machine = SetupStateMachine::for_full_sync(&self.client, &self.root_key);
+        self.state = machine.to_ready(state).expect("Should reach the ready state");
+    }
+
+    fn sync(&mut self) {
+        self.ready();
+        let last_sync = self.store.last_sync;
+        sync::synchronize(
+            &self.client,
+            &self.state,
+            &mut self.store,
+            COLLECTION.into(),
+            last_sync,
+            true,
+        ).expect("Sync should succeed");
+    }
+
+    /// Syncs each of `stores` with the matching collection in `collections`,
+    /// returning how long it took.
+    fn sync_multiple(
+        &mut self,
+        collections: &[&str],
+        stores: &mut [MemoryStore],
+        max_parallel_downloads: usize,
+    ) -> Duration {
+        self.ready();
+        let mut to_sync: Vec<CollectionSync<sync::Error>> = stores
+            .iter_mut()
+            .zip(collections)
+            .map(|(store, collection)| CollectionSync {
+                timestamp: store.last_sync,
+                collection: (*collection).into(),
+                store,
+            })
+            .collect();
+        let started = Instant::now();
+        sync::sync_multiple(
+            &self.client,
+            &self.state,
+            &mut to_sync,
+            max_parallel_downloads,
+            true,
+        ).expect("Sync should succeed");
+        started.elapsed()
+    }
+}
+
+#[test]
+#[ignore]
+fn test_sync_between_clients() {
+    let account = TestAccount::new("between-clients");
+    let mut a = account.client();
+    let mut b = account.client();
+
+    a.store.insert("record-aaaa", json!("from a"));
+    a.store.insert("record-bbbb", json!({"nested": [1, 2, 3]}));
+    a.sync();
+    b.sync();
+    assert_eq!(b.store.values(), a.store.values());
+    assert!(b.store.changed.is_empty());
+
+    b.store.insert("record-cccc", json!("from b"));
+    b.store.insert("record-aaaa", json!("changed by b"));
+    b.sync();
+    a.sync();
+    assert_eq!(a.store.values().len(), 3);
+    assert_eq!(a.store.values()["record-aaaa"], "changed by b");
+    assert_eq!(a.store.values(), b.store.values());
+}
+
+#[test]
+#[ignore]
+fn test_tombstones() {
+    let account = TestAccount::new("tombstones");
+    let mut a = account.client();
+    let mut b = account.client();
+
+    a.store.insert("record-aaaa", json!(1));
+    a.store.insert("record-bbbb", json!(2));
+    a.sync();
+    b.sync();
+    assert_eq!(b.store.values().len(), 2);
+
+    a.store.delete("record-aaaa");
+    a.sync();
+    b.sync();
+    assert_eq!(b.store.values().keys().collect::<Vec<_>>(), vec!["record-bbbb"]);
+}
+
+#[test]
+#[ignore]
+fn test_accounts_are_isolated() {
+    let mut a = TestAccount::new("isolated-a").client();
+    let mut b = TestAccount::new("isolated-b").client();
+
+    a.store.insert("record-aaaa", json!("only in a"));
+    a.sync();
+    b.sync();
+    assert!(b.store.values().is_empty());
+}
+
+#[test]
+#[ignore]
+fn test_sync_multiple() {
+    // Run with `--nocapture` to see the timings. Against a local server the
+    // difference is small; it's latency to the server that parallel downloads
+    // help with.
+    let collections = ["bookmarks", "history", "forms", "prefs", "tabs", "addons"];
+    let account = TestAccount::new("sync-multiple");
+    let mut a = account.client();
+    let mut a_stores: Vec<MemoryStore> = collections.iter().map(|_| MemoryStore::new()).collect();
+    for (i, store) in a_stores.iter_mut().enumerate() {
+        for j in 0..20 {
+            store.insert(&format!("record-{}-{}", i, j), json!(j));
+        }
+    }
+    a.sync_multiple(&collections, &mut a_stores, 1);
+
+    let mut sequential = account.client();
+    let mut sequential_stores: Vec<MemoryStore> =
+        collections.iter().map(|_| MemoryStore::new()).collect();
+    let sequential_time = sequential.sync_multiple(&collections, &mut sequential_stores, 1);
+
+    let mut parallel = account.client();
+    let mut parallel_stores: Vec<MemoryStore> =
+        collections.iter().map(|_| MemoryStore::new()).collect();
+    let parallel_time = parallel.sync_multiple(
+        &collections,
+        &mut parallel_stores,
+        sync::sync::DEFAULT_MAX_PARALLEL_DOWNLOADS,
+    );
+
+    println!(
+        "Downloaded {} collections in {:?} sequentially, {:?} in parallel",
+        collections.len(),
+        sequential_time,
+        parallel_time
+    );
+    for ((original, s), p) in a_stores.iter().zip(&sequential_stores).zip(&parallel_stores) {
+        assert_eq!(original.values().len(), 20);
+        assert_eq!(s.values(), original.values());
+        assert_eq!(p.values(), original.values());
+    }
+}